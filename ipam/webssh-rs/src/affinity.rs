@@ -0,0 +1,124 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::settings::ServerSettings;
+use crate::AppState;
+
+/// Environment variable overriding the configured node ID
+const NODE_ID_ENV: &str = "WEBSSH_NODE_ID";
+
+/// Identifies this instance so load balancers can route a session's traffic back to it
+///
+/// Sessions live in the memory of the instance that created them, so in
+/// multi-replica deployments WebSocket and status calls must reach that instance.
+pub struct NodeIdentity {
+    pub id: String,
+    header_name: HeaderName,
+    header_value: HeaderValue,
+}
+
+impl NodeIdentity {
+    /// Resolves the node ID from `WEBSSH_NODE_ID`, the settings or the hostname
+    ///
+    /// # Returns
+    /// * `Result<Self, String>` - The identity, or a description of the configuration problem
+    pub fn from_settings(settings: &ServerSettings) -> Result<Self, String> {
+        let id = std::env::var(NODE_ID_ENV).ok()
+            .or_else(|| settings.node_id.clone())
+            .or_else(hostname)
+            .filter(|id| !id.trim().is_empty())
+            .unwrap_or_else(|| "webssh".to_string());
+
+        let header_name = HeaderName::from_bytes(settings.node_header.as_bytes())
+            .map_err(|_| format!("Invalid node header name '{}'", settings.node_header))?;
+        let header_value = HeaderValue::from_str(id.trim())
+            .map_err(|_| format!("Node ID '{}' is not a valid header value", id))?;
+
+        Ok(Self { id: id.trim().to_string(), header_name, header_value })
+    }
+
+    pub fn header_name(&self) -> &HeaderName {
+        &self.header_name
+    }
+}
+
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+}
+
+/// Middleware stamping every response with the node header
+pub async fn add_node_header(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(state.node.header_name.clone(), state.node.header_value.clone());
+    response
+}
+
+/// The session a WebSocket or session API path is about
+fn session_in_path(path: &str) -> Option<String> {
+    let rest = path.strip_prefix("/api/session/")
+        .or_else(|| path.strip_prefix("/ws/").filter(|rest| !rest.starts_with("view/")))?;
+    let session_id = rest.split('/').next().filter(|session_id| !session_id.is_empty())?;
+    urlencoding::decode(session_id).ok().map(|session_id| session_id.into_owned())
+}
+
+/// Middleware sending requests for another instance's sessions to that instance
+///
+/// With the session registry in Redis, a WebSocket or session API call that
+/// lands on the wrong instance gets a `307` to the owner's advertised URL.
+/// Clients that cannot follow it, such as browsers opening a WebSocket, find
+/// the owner's node ID in the body for the load balancer's affinity.
+pub async fn route_to_owner(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(directory) = state.directory.clone() else {
+        return next.run(request).await;
+    };
+    let Some(session_id) = session_in_path(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    if state.session_registry.lock().await.is_local(&session_id) {
+        return next.run(request).await;
+    }
+    let owner = match directory.owner(&session_id).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return next.run(request).await,
+        Err(e) => {
+            warn!("Cannot look up the owner of session {}: {}", session_id, e);
+            return next.run(request).await;
+        }
+    };
+    let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let location = format!("{}{}", owner.url, path);
+    debug!("Session {} is on node {}; redirecting to {}", session_id, owner.node_id, location);
+    let mut response = (StatusCode::TEMPORARY_REDIRECT, Json(json!({
+        "error": "wrong_node",
+        "message": format!("Session '{}' is on node '{}'", session_id, owner.node_id),
+        "node_id": owner.node_id,
+        "location": location,
+    }))).into_response();
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_in_path() {
+        assert_eq!(session_in_path("/ws/s-1").as_deref(), Some("s-1"));
+        assert_eq!(session_in_path("/api/session/fe80%3A%3A1/status").as_deref(), Some("fe80::1"));
+        assert_eq!(session_in_path("/ws/view/wss_token"), None);
+        assert_eq!(session_in_path("/api/sessions/history"), None);
+        assert_eq!(session_in_path("/api/session/"), None);
+    }
+}
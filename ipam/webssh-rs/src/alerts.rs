@@ -0,0 +1,252 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::error;
+
+use crate::error_code::ErrorCode;
+use crate::settings::{AlertSettings, WebhookEventType};
+use crate::store::EndReason;
+use crate::webhooks::Webhooks;
+use crate::AppState;
+
+/// The window the alert thresholds are counted over
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Alerts kept for `/api/alerts`
+const RECENT_ALERTS: usize = 50;
+
+/// What an alert is raised for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// Sessions ending because their connection stalled or was lost, across the gateway
+    AbnormalTerminations,
+    /// WebSockets resuming sessions, across the gateway
+    ReconnectStorm,
+    /// Connections to one device failing
+    HandshakeFailures,
+}
+
+/// An alert raised, as logged, posted and listed
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    /// The device, for handshake failures
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Events in the last minute
+    pub count: usize,
+    pub threshold: u32,
+    pub raised_at: DateTime<Utc>,
+}
+
+/// Times of the events in the last minute, and a count of all since startup
+#[derive(Default)]
+struct Rate {
+    events: VecDeque<Instant>,
+    total: u64,
+}
+
+impl Rate {
+    /// Counts an event, returning the events in the last minute
+    fn add(&mut self, now: Instant) -> usize {
+        self.events.push_back(now);
+        self.total += 1;
+        self.current(now)
+    }
+
+    fn current(&mut self, now: Instant) -> usize {
+        while self.events.front().is_some_and(|at| now.duration_since(*at) >= WINDOW) {
+            self.events.pop_front();
+        }
+        self.events.len()
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    abnormal_terminations: Rate,
+    reconnects: Rate,
+    handshake_failures: HashMap<String, Rate>,
+    handshake_failures_total: u64,
+    // When each alert was last raised, to hold off repeats for the cooldown
+    raised: HashMap<(AlertKind, Option<String>), Instant>,
+    recent: VecDeque<Alert>,
+}
+
+/// Counts abnormal session ends, reconnects and failed connections, raising
+/// an alert when one passes its threshold within a minute
+///
+/// Alerts are logged as `ALERT` lines and, with `alerts.webhook`, posted to
+/// the webhooks as `alert` events. An alert is not raised again for the same
+/// thing, e.g. the same device, until `alerts.cooldown_seconds` have passed.
+pub struct Alerts {
+    settings: AlertSettings,
+    webhooks: Option<Arc<Webhooks>>,
+    counters: Mutex<Counters>,
+}
+
+impl Alerts {
+    pub fn new(settings: &AlertSettings, webhooks: Option<Arc<Webhooks>>) -> Self {
+        Self { settings: settings.clone(), webhooks, counters: Mutex::default() }
+    }
+
+    /// Notes a session ending, counting it if its connection stalled or was lost
+    pub fn session_ended(&self, reason: EndReason) {
+        if !matches!(reason, EndReason::Stalled | EndReason::ConnectionLost) {
+            return;
+        }
+        let now = Instant::now();
+        let mut counters = self.lock();
+        let count = counters.abnormal_terminations.add(now);
+        self.check(&mut counters, AlertKind::AbnormalTerminations, None, count, self.settings.abnormal_terminations_per_minute, now);
+    }
+
+    /// Notes a WebSocket resuming a session it or another had been attached to
+    pub fn reconnected(&self) {
+        let now = Instant::now();
+        let mut counters = self.lock();
+        let count = counters.reconnects.add(now);
+        self.check(&mut counters, AlertKind::ReconnectStorm, None, count, self.settings.reconnects_per_minute, now);
+    }
+
+    /// Notes a connection to a device failing, unless it was refused before reaching the device
+    pub fn connect_failed(&self, device_id: &str, error_code: ErrorCode) {
+        if !matches!(error_code, ErrorCode::ConnectionFailed | ErrorCode::Timeout | ErrorCode::AuthFailed | ErrorCode::UnknownError) {
+            return;
+        }
+        let now = Instant::now();
+        let mut counters = self.lock();
+        counters.handshake_failures_total += 1;
+        let count = counters.handshake_failures.entry(device_id.to_string()).or_default().add(now);
+        counters.handshake_failures.retain(|_, rate| rate.current(now) > 0);
+        self.check(&mut counters, AlertKind::HandshakeFailures, Some(device_id), count,
+                   self.settings.handshake_failures_per_device_per_minute, now);
+    }
+
+    /// Raises an alert if the count has reached its threshold and it was not raised within the cooldown
+    fn check(&self, counters: &mut Counters, kind: AlertKind, device_id: Option<&str>, count: usize, threshold: u32, now: Instant) {
+        if !self.settings.enabled || threshold == 0 || count < threshold as usize {
+            return;
+        }
+        let key = (kind, device_id.map(str::to_string));
+        let cooldown = Duration::from_secs(self.settings.cooldown_seconds);
+        if counters.raised.get(&key).is_some_and(|at| now.duration_since(*at) < cooldown) {
+            return;
+        }
+        counters.raised.insert(key, now);
+
+        let alert = Alert { kind, device_id: device_id.map(str::to_string), count, threshold, raised_at: Utc::now() };
+        match device_id {
+            Some(device_id) => error!("ALERT: {} connections to device {} failed in the last minute (threshold {})", count, device_id, threshold),
+            None => error!("ALERT: {:?}: {} in the last minute (threshold {})", kind, count, threshold),
+        }
+        if let (true, Some(webhooks)) = (self.settings.webhook, &self.webhooks) {
+            webhooks.send(WebhookEventType::Alert, serde_json::to_value(&alert).unwrap_or_default());
+        }
+        if counters.recent.len() == RECENT_ALERTS {
+            counters.recent.pop_front();
+        }
+        counters.recent.push_back(alert);
+    }
+
+    /// The counts and alerts, as reported by `/api/alerts`
+    pub fn status(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let mut counters = self.lock();
+        let devices: BTreeMap<String, usize> = counters.handshake_failures.iter_mut()
+            .map(|(device_id, rate)| (device_id.clone(), rate.current(now)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        let abnormal_terminations = counters.abnormal_terminations.current(now);
+        let reconnects = counters.reconnects.current(now);
+        json!({
+            "enabled": self.settings.enabled,
+            "abnormal_terminations": {
+                "last_minute": abnormal_terminations,
+                "total": counters.abnormal_terminations.total,
+                "threshold": self.settings.abnormal_terminations_per_minute,
+            },
+            "reconnects": {
+                "last_minute": reconnects,
+                "total": counters.reconnects.total,
+                "threshold": self.settings.reconnects_per_minute,
+            },
+            "handshake_failures": {
+                "devices": devices,
+                "total": counters.handshake_failures_total,
+                "threshold": self.settings.handshake_failures_per_device_per_minute,
+            },
+            "recent_alerts": counters.recent.iter().rev().collect::<Vec<_>>(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Abnormal session ends, reconnects and failed connections in the last minute, and the alerts raised
+pub async fn status_handler(State(state): State<AppState>) -> Response {
+    Json(state.alerts.status()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerts() -> Alerts {
+        Alerts::new(&AlertSettings {
+            abnormal_terminations_per_minute: 2,
+            handshake_failures_per_device_per_minute: 3,
+            ..AlertSettings::default()
+        }, None)
+    }
+
+    fn raised(alerts: &Alerts) -> Vec<Alert> {
+        alerts.lock().recent.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_threshold_raises_once_within_cooldown() {
+        let alerts = alerts();
+        alerts.session_ended(EndReason::ShellClosed);
+        alerts.session_ended(EndReason::Stalled);
+        assert!(raised(&alerts).is_empty());
+
+        alerts.session_ended(EndReason::ConnectionLost);
+        alerts.session_ended(EndReason::ConnectionLost);
+        let raised = raised(&alerts);
+        assert_eq!(raised.len(), 1);
+        assert_eq!((raised[0].kind, raised[0].count), (AlertKind::AbnormalTerminations, 2));
+    }
+
+    #[test]
+    fn test_handshake_failures_are_counted_per_device() {
+        let alerts = alerts();
+        for _ in 0..2 {
+            alerts.connect_failed("router1", ErrorCode::Timeout);
+            alerts.connect_failed("router2", ErrorCode::ConnectionFailed);
+        }
+        // Refused before reaching the device
+        alerts.connect_failed("router1", ErrorCode::AccessDenied);
+        assert!(raised(&alerts).is_empty());
+
+        alerts.connect_failed("router1", ErrorCode::AuthFailed);
+        let raised = raised(&alerts);
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].device_id.as_deref(), Some("router1"));
+
+        let status = alerts.status();
+        assert_eq!(status["handshake_failures"]["devices"]["router2"], 2);
+        assert_eq!(status["handshake_failures"]["total"], 5);
+    }
+}
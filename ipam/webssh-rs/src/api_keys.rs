@@ -0,0 +1,425 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use tracing::{error, info, warn};
+
+use crate::authz;
+use crate::jwt::AuthenticatedUser;
+use crate::settings::{ApiKeySettings, Role};
+use crate::AppState;
+
+/// Header carrying the API key on requests from backend services
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Environment variable holding a bootstrap admin key that is never persisted
+const BOOTSTRAP_KEY_ENV: &str = "WEBSSH_ADMIN_API_KEY";
+
+/// Permission granted to an API key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// May create sessions and work with their files
+    Connect,
+    /// May only read session status
+    ReadStatus,
+    /// May do everything, including terminating sessions and managing keys
+    Admin,
+}
+
+impl ApiKeyScope {
+    /// Returns true if a key holding this scope may access a route requiring `required`
+    pub fn allows(self, required: ApiKeyScope) -> bool {
+        self == ApiKeyScope::Admin || self == required
+    }
+}
+
+/// A stored API key; only the SHA-256 hash of the secret is kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyRecord {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub key_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKeyRecord {
+    fn allows(&self, required: ApiKeyScope) -> bool {
+        self.scopes.iter().any(|scope| scope.allows(required))
+    }
+}
+
+/// Public view of a key, without its hash
+#[derive(Debug, Serialize)]
+pub struct ApiKeySummary {
+    id: String,
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+    created_at: DateTime<Utc>,
+    rotated_at: Option<DateTime<Utc>>,
+}
+
+impl From<&ApiKeyRecord> for ApiKeySummary {
+    fn from(record: &ApiKeyRecord) -> Self {
+        Self {
+            id: record.id.clone(),
+            name: record.name.clone(),
+            scopes: record.scopes.clone(),
+            created_at: record.created_at,
+            rotated_at: record.rotated_at,
+        }
+    }
+}
+
+/// Response returned when a key is created or rotated; the only time the secret is shown
+#[derive(Debug, Serialize)]
+pub struct ApiKeySecretResponse {
+    #[serde(flatten)]
+    summary: ApiKeySummary,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+}
+
+/// Hashed API keys persisted to a JSON file
+pub struct ApiKeyStore {
+    path: PathBuf,
+    keys: HashMap<String, ApiKeyRecord>,
+    bootstrap_hash: Option<String>,
+}
+
+impl ApiKeyStore {
+    /// Loads the key store from the file configured in settings
+    pub fn load(settings: &ApiKeySettings) -> Self {
+        let path = PathBuf::from(&settings.store_file);
+        let keys = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<Vec<ApiKeyRecord>>(&contents) {
+                Ok(records) => records.into_iter().map(|record| (record.id.clone(), record)).collect(),
+                Err(e) => {
+                    error!("Failed to parse API key store {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        let bootstrap_hash = std::env::var(BOOTSTRAP_KEY_ENV)
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(|key| hash_key(&key));
+
+        if settings.enabled {
+            info!("Loaded {} API keys from {}", keys.len(), path.display());
+            if keys.is_empty() && bootstrap_hash.is_none() {
+                warn!("API keys are enabled but none exist; set {} to bootstrap an admin key", BOOTSTRAP_KEY_ENV);
+            }
+        }
+
+        Self { path, keys, bootstrap_hash }
+    }
+
+    /// Returns true if the presented key exists and grants the required scope
+    pub fn authorize(&self, presented: &str, required: ApiKeyScope) -> Result<(), StatusCode> {
+        let hash = hash_key(presented);
+        if self.bootstrap_hash.as_deref().is_some_and(|bootstrap| hash_matches(bootstrap, &hash)) {
+            return Ok(());
+        }
+        // Every record is compared, so the time taken does not tell which one matched
+        let mut matched = None;
+        for record in self.keys.values() {
+            if hash_matches(&record.key_hash, &hash) {
+                matched = Some(record);
+            }
+        }
+        match matched {
+            Some(record) if record.allows(required) => Ok(()),
+            Some(_) => Err(StatusCode::FORBIDDEN),
+            None => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    /// Returns true if the presented key exists, whatever its scopes
+    pub fn authenticate(&self, presented: &str) -> bool {
+        self.authorize(presented, ApiKeyScope::Admin) != Err(StatusCode::UNAUTHORIZED)
+    }
+
+    pub fn list(&self) -> Vec<ApiKeySummary> {
+        let mut summaries: Vec<ApiKeySummary> = self.keys.values().map(ApiKeySummary::from).collect();
+        summaries.sort_by_key(|summary| summary.created_at);
+        summaries
+    }
+
+    /// Creates a new key and returns its plaintext secret
+    pub fn create(&mut self, name: &str, scopes: Vec<ApiKeyScope>) -> std::io::Result<ApiKeySecretResponse> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let key = generate_key();
+        let record = ApiKeyRecord {
+            id: id.clone(),
+            name: name.to_string(),
+            scopes,
+            key_hash: hash_key(&key),
+            created_at: Utc::now(),
+            rotated_at: None,
+        };
+        let summary = ApiKeySummary::from(&record);
+        self.keys.insert(id, record);
+        self.save()?;
+        Ok(ApiKeySecretResponse { summary, key })
+    }
+
+    /// Replaces the secret of an existing key, invalidating the old one
+    pub fn rotate(&mut self, id: &str) -> std::io::Result<Option<ApiKeySecretResponse>> {
+        let key = generate_key();
+        let summary = match self.keys.get_mut(id) {
+            Some(record) => {
+                record.key_hash = hash_key(&key);
+                record.rotated_at = Some(Utc::now());
+                ApiKeySummary::from(&*record)
+            }
+            None => return Ok(None),
+        };
+        self.save()?;
+        Ok(Some(ApiKeySecretResponse { summary, key }))
+    }
+
+    pub fn revoke(&mut self, id: &str) -> std::io::Result<bool> {
+        if self.keys.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let mut records: Vec<&ApiKeyRecord> = self.keys.values().collect();
+        records.sort_by_key(|record| record.created_at);
+        let contents = serde_json::to_string_pretty(&records)?;
+
+        // Write to a temporary file first so a crash never leaves a truncated store
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn generate_key() -> String {
+    format!(
+        "wsk_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Compares key hashes in constant time
+fn hash_matches(stored: &str, presented: &str) -> bool {
+    stored.as_bytes().ct_eq(presented.as_bytes()).into()
+}
+
+fn auth_error(status: StatusCode, message: &str) -> Response {
+    let error = if status == StatusCode::FORBIDDEN { "forbidden" } else { "unauthorized" };
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// The API key a request carries, if any
+pub fn presented_key(request: &Request) -> Option<&str> {
+    request.headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+async fn authorize(state: AppState, request: Request, next: Next, required: ApiKeyScope) -> Response {
+    if !state.settings.api_keys.enabled {
+        return next.run(request).await;
+    }
+
+    let Some(presented) = presented_key(&request) else {
+        // Users authenticated by JWT may use everything but the admin routes,
+        // unless authorization gives them the admin role
+        if let Some(user) = request.extensions().get::<AuthenticatedUser>() {
+            let authorization = &state.policy.settings().authorization;
+            if required != ApiKeyScope::Admin
+                || (authorization.enabled && authz::role(authorization, user) == Some(Role::Admin)) {
+                return next.run(request).await;
+            }
+        }
+        return auth_error(StatusCode::UNAUTHORIZED, "Missing API key");
+    };
+
+    let result = state.api_keys.lock().await.authorize(presented, required);
+    match result {
+        Ok(()) => next.run(request).await,
+        Err(StatusCode::FORBIDDEN) => {
+            warn!("API key rejected for {}: missing {:?} scope", request.uri().path(), required);
+            auth_error(StatusCode::FORBIDDEN, "API key does not grant access to this endpoint")
+        }
+        Err(status) => {
+            warn!("Invalid API key presented for {}", request.uri().path());
+            auth_error(status, "Invalid API key")
+        }
+    }
+}
+
+/// Middleware refusing requests without an API key, for the control plane listener
+///
+/// Requests authenticated by JWT alone are refused there; the routes'
+/// own middleware checks the key's scope.
+pub async fn require_key(request: Request, next: Next) -> Response {
+    if !request.headers().contains_key(API_KEY_HEADER) {
+        return auth_error(StatusCode::UNAUTHORIZED, "This listener requires an API key");
+    }
+    next.run(request).await
+}
+
+/// Middleware for routes that create sessions or act on them
+pub async fn require_connect(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    authorize(state, request, next, ApiKeyScope::Connect).await
+}
+
+/// Middleware for read-only status routes
+pub async fn require_read_status(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    authorize(state, request, next, ApiKeyScope::ReadStatus).await
+}
+
+/// Middleware for administrative routes
+pub async fn require_admin(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    authorize(state, request, next, ApiKeyScope::Admin).await
+}
+
+fn store_error(e: std::io::Error) -> Response {
+    error!("Failed to persist API key store: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+        "error": "store_error",
+        "message": format!("Failed to persist API keys: {}", e),
+    }))).into_response()
+}
+
+/// Handler for listing API keys
+pub async fn list_handler(State(state): State<AppState>) -> Json<Vec<ApiKeySummary>> {
+    Json(state.api_keys.lock().await.list())
+}
+
+/// Handler for creating an API key
+pub async fn create_handler(
+    State(state): State<AppState>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Response {
+    if request.name.trim().is_empty() || request.scopes.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "error": "invalid_request",
+            "message": "A key needs a name and at least one scope",
+        }))).into_response();
+    }
+
+    match state.api_keys.lock().await.create(request.name.trim(), request.scopes) {
+        Ok(created) => {
+            info!("Created API key {} ({}) with scopes {:?}", created.summary.id, created.summary.name, created.summary.scopes);
+            (StatusCode::CREATED, Json(created)).into_response()
+        }
+        Err(e) => store_error(e),
+    }
+}
+
+/// Handler for rotating the secret of an API key
+pub async fn rotate_handler(
+    Path(key_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.api_keys.lock().await.rotate(&key_id) {
+        Ok(Some(rotated)) => {
+            info!("Rotated API key {}", key_id);
+            Json(rotated).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "error": "key_not_found",
+            "message": format!("API key '{}' not found", key_id),
+        }))).into_response(),
+        Err(e) => store_error(e),
+    }
+}
+
+/// Handler for revoking an API key
+pub async fn revoke_handler(
+    Path(key_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.api_keys.lock().await.revoke(&key_id) {
+        Ok(true) => {
+            info!("Revoked API key {}", key_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, Json(json!({
+            "error": "key_not_found",
+            "message": format!("API key '{}' not found", key_id),
+        }))).into_response(),
+        Err(e) => store_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> ApiKeyStore {
+        ApiKeyStore {
+            path: std::env::temp_dir().join(format!("webssh-api-keys-{}.json", uuid::Uuid::new_v4())),
+            keys: HashMap::new(),
+            bootstrap_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_scope_enforcement() {
+        let mut store = store();
+        let monitor = store.create("monitoring", vec![ApiKeyScope::ReadStatus]).unwrap();
+
+        assert_eq!(store.authorize(&monitor.key, ApiKeyScope::ReadStatus), Ok(()));
+        assert_eq!(store.authorize(&monitor.key, ApiKeyScope::Connect), Err(StatusCode::FORBIDDEN));
+        assert_eq!(store.authorize(&monitor.key, ApiKeyScope::Admin), Err(StatusCode::FORBIDDEN));
+        assert_eq!(store.authorize("wsk_bogus", ApiKeyScope::ReadStatus), Err(StatusCode::UNAUTHORIZED));
+
+        let admin = store.create("ops", vec![ApiKeyScope::Admin]).unwrap();
+        assert_eq!(store.authorize(&admin.key, ApiKeyScope::Connect), Ok(()));
+
+        let _ = fs::remove_file(&store.path);
+    }
+
+    #[test]
+    fn test_rotation_invalidates_old_key() {
+        let mut store = store();
+        let created = store.create("portal", vec![ApiKeyScope::Connect]).unwrap();
+        let rotated = store.rotate(&created.summary.id).unwrap().unwrap();
+
+        assert_eq!(store.authorize(&created.key, ApiKeyScope::Connect), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(store.authorize(&rotated.key, ApiKeyScope::Connect), Ok(()));
+
+        let _ = fs::remove_file(&store.path);
+    }
+}
@@ -0,0 +1,319 @@
+//! `webssh-rs attach`: a shell on a device through a running gateway, in the local terminal
+//!
+//! Opens a session to an inventory device over `/api/connect` and attaches to
+//! it over the WebSocket, as the web interface does. A host key to confirm,
+//! the device's password and other prompts are answered in the terminal
+//! before the shell starts. Ctrl-] detaches, leaving the session to the
+//! gateway's detach timeout.
+//!
+//! Run without a terminal, e.g. as PuTTY's local proxy command, the input and
+//! output are passed through as they are and the gateway's default size is kept.
+
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::api_keys::API_KEY_HEADER;
+use crate::cli::{default_url, AttachArgs, SettingsSource};
+use crate::http_client::{Connection, HttpClient};
+
+/// Ctrl-], as telnet uses it to get back to the local prompt
+const DETACH_KEY: u8 = 0x1d;
+
+/// Standard input, the terminal when attached from one
+const STDIN: libc::c_int = 0;
+
+type Socket = WebSocketStream<Box<dyn Connection>>;
+
+/// The gateway, reached with the caller's API key or token
+struct Gateway {
+    client: HttpClient,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Gateway {
+    async fn call(&self, path: &str, body: &Value) -> Result<(u16, Value), String> {
+        let headers: Vec<(&str, &str)> = self.headers.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        let path = format!("{}{}", self.client.endpoint.path.trim_end_matches('/'), path);
+        let (status, response) = self.client.request("POST", &path, &headers, Some(&body.to_string())).await
+            .map_err(|e| format!("Cannot reach the gateway: {}", e))?;
+        Ok((status, serde_json::from_slice(&response).unwrap_or(Value::Null)))
+    }
+
+    async fn websocket(&self, session_id: &str, ws_token: &str) -> Result<Socket, String> {
+        let url = self.client.endpoint.websocket_url(&format!("/ws/{}?ws_token={}", session_id, ws_token));
+        let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+        for (name, value) in &self.headers {
+            let value = HeaderValue::from_str(value).map_err(|_| format!("Invalid value of header '{}'", name))?;
+            request.headers_mut().insert(*name, value);
+        }
+        let connection = self.client.connect().await.map_err(|e| format!("Cannot reach the gateway: {}", e))?;
+        let (socket, _) = tokio_tungstenite::client_async(request, connection).await.map_err(|e| e.to_string())?;
+        Ok(socket)
+    }
+}
+
+/// The terminal's settings, put back when dropped
+struct TerminalMode {
+    original: libc::termios,
+}
+
+impl TerminalMode {
+    /// Changes the terminal's settings, if standard input is a terminal
+    fn change(apply: impl FnOnce(&mut libc::termios)) -> Option<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr before it is read
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(STDIN, &mut termios) != 0 {
+                return None;
+            }
+            let original = termios;
+            apply(&mut termios);
+            (libc::tcsetattr(STDIN, libc::TCSANOW, &termios) == 0).then_some(Self { original })
+        }
+    }
+
+    /// Keystrokes go to the device as typed, neither echoed nor interpreted locally
+    fn raw() -> Option<Self> {
+        // SAFETY: cfmakeraw only changes the flags of the termios it is given
+        Self::change(|termios| unsafe { libc::cfmakeraw(termios) })
+    }
+
+    fn no_echo() -> Option<Self> {
+        Self::change(|termios| termios.c_lflag &= !libc::ECHO)
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        // SAFETY: restores settings read from the same terminal
+        unsafe {
+            libc::tcsetattr(STDIN, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// The terminal's size in characters, if standard input is a terminal
+fn terminal_size() -> Option<(u16, u16)> {
+    // SAFETY: TIOCGWINSZ fills in the winsize it is given
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        (libc::ioctl(STDIN, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0)
+            .then_some((size.ws_col, size.ws_row))
+    }
+}
+
+/// Asks a question on the terminal and reads the answer, unechoed if it is a secret
+async fn ask(question: String, echo: bool) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        eprint!("{}", question);
+        let _ = std::io::stderr().flush();
+        let quiet = if echo { None } else { TerminalMode::no_echo() };
+        let mut answer = String::new();
+        let read = std::io::stdin().lock().read_line(&mut answer);
+        if quiet.is_some() {
+            eprintln!();
+        }
+        read.map_err(|e| e.to_string())?;
+        Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+    }).await.map_err(|e| e.to_string())?
+}
+
+/// Takes the text at the start of the input, leaving a character split across reads for the next
+fn take_text(pending: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        // A sequence cut off at the end is completed by the next read; invalid bytes are replaced
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..valid]).into_owned();
+    pending.drain(..valid);
+    text
+}
+
+/// Opens the session, confirming the device's host key if the gateway asks to
+async fn open(gateway: &Gateway, args: &AttachArgs) -> Result<Value, String> {
+    let size = terminal_size();
+    let mut request = json!({
+        "device_ref": args.device,
+        "username": args.username.clone().unwrap_or_default(),
+        "term": std::env::var("TERM").ok().filter(|term| !term.is_empty()),
+        "cols": size.map(|(cols, _)| cols),
+        "rows": size.map(|(_, rows)| rows),
+    });
+    let (_, mut response) = gateway.call("/api/connect", &request).await?;
+    if response["error_code"] == "HOSTKEY_CONFIRMATION_REQUIRED" {
+        let key = &response["host_key"];
+        let question = format!(
+            "The host key of {} is not known yet.\r\n{} key fingerprint is {}.\r\nAre you sure you want to continue connecting (yes/no)? ",
+            args.device, key["key_type"].as_str().unwrap_or("Its"), key["fingerprint"].as_str().unwrap_or("unknown"));
+        if ask(question, true).await? != "yes" {
+            return Err("Host key not confirmed".to_string());
+        }
+        request["host_key_fingerprint"] = key["fingerprint"].clone();
+        response = gateway.call("/api/connect/confirm", &request).await?.1;
+    }
+    match response["success"].as_bool() {
+        Some(true) => Ok(response),
+        _ => Err(response["message"].as_str().or(response["error"].as_str()).unwrap_or("Connect refused").to_string()),
+    }
+}
+
+/// Answers the prompts relayed while the session authenticates
+///
+/// # Returns
+/// * `Result<Option<Vec<u8>>, String>` - Output that arrived with the end of authentication, if any
+async fn authenticate(socket: &mut Socket) -> Result<Option<Vec<u8>>, String> {
+    while let Some(message) = socket.next().await {
+        let event: Value = match message.map_err(|e| e.to_string())? {
+            Message::Text(text) => serde_json::from_str(&text).unwrap_or(Value::Null),
+            // Output means there was nothing left to answer
+            Message::Binary(data) => return Ok(Some(data)),
+            Message::Close(frame) => return Err(closed(frame)),
+            _ => continue,
+        };
+        let reply = match event["type"].as_str() {
+            Some("auth_prompt") => {
+                if let Some(instructions) = event["instructions"].as_str().filter(|text| !text.is_empty()) {
+                    eprintln!("{}", instructions);
+                }
+                let mut responses = Vec::new();
+                for prompt in event["prompts"].as_array().into_iter().flatten() {
+                    let question = prompt["prompt"].as_str().unwrap_or("").to_string();
+                    responses.push(ask(question, prompt["echo"].as_bool().unwrap_or(false)).await?);
+                }
+                json!({ "type": "auth_response", "responses": responses })
+            }
+            Some("secret_prompt") => {
+                let answer = ask(event["prompt"].as_str().unwrap_or("Secret: ").to_string(), false).await?;
+                json!({ "type": "secret_input", "data": answer })
+            }
+            Some("auth_success") => return Ok(None),
+            Some("auth_failed") => return Err(event["message"].as_str().unwrap_or("Authentication failed").to_string()),
+            _ => continue,
+        };
+        socket.send(Message::Text(reply.to_string())).await.map_err(|e| e.to_string())?;
+    }
+    Err("The gateway closed the connection".to_string())
+}
+
+fn closed(frame: Option<tokio_tungstenite::tungstenite::protocol::CloseFrame>) -> String {
+    frame.map(|frame| frame.reason.to_string())
+        .filter(|reason| !reason.is_empty())
+        .unwrap_or_else(|| "Connection closed by the gateway".to_string())
+}
+
+/// Passes keystrokes to the shell and its output to the terminal until either side ends
+///
+/// # Returns
+/// * `Result<String, String>` - Why the shell was left
+async fn relay(socket: &mut Socket, first_output: Option<Vec<u8>>) -> Result<String, String> {
+    let mut stdout = tokio::io::stdout();
+    if let Some(data) = first_output {
+        stdout.write_all(&data).await.map_err(|e| e.to_string())?;
+        stdout.flush().await.map_err(|e| e.to_string())?;
+    }
+    let _raw = TerminalMode::raw();
+    let mut stdin = tokio::io::stdin();
+    let mut resized = signal(SignalKind::window_change()).map_err(|e| e.to_string())?;
+    let mut buffer = [0u8; 4096];
+    let mut pending = Vec::new();
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Binary(data))) => {
+                    stdout.write_all(&data).await.map_err(|e| e.to_string())?;
+                    stdout.flush().await.map_err(|e| e.to_string())?;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    let event: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+                    if event["type"] == "error" {
+                        return Err(event["message"].as_str().unwrap_or("Session error").to_string());
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => return Ok(closed(frame)),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.to_string()),
+                None => return Ok("Connection closed by the gateway".to_string()),
+            },
+            read = stdin.read(&mut buffer) => {
+                let read = read.map_err(|e| e.to_string())?;
+                let typed = &buffer[..read];
+                let detach = typed.iter().position(|&byte| byte == DETACH_KEY);
+                pending.extend_from_slice(&typed[..detach.unwrap_or(read)]);
+                let data = take_text(&mut pending);
+                if !data.is_empty() {
+                    socket.send(Message::Text(json!({ "type": "input", "data": data }).to_string())).await
+                        .map_err(|e| e.to_string())?;
+                }
+                if detach.is_some() {
+                    let _ = socket.close(None).await;
+                    return Ok("Detached; the session is kept until the gateway's detach timeout".to_string());
+                }
+                if read == 0 {
+                    let _ = socket.close(None).await;
+                    return Ok("Input closed".to_string());
+                }
+            }
+            _ = resized.recv() => {
+                if let Some((cols, rows)) = terminal_size() {
+                    socket.send(Message::Text(json!({ "type": "resize", "cols": cols, "rows": rows }).to_string())).await
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+    }
+}
+
+/// `webssh-rs attach`: opens a shell on an inventory device through a running gateway
+pub async fn run_cli(source: &SettingsSource, args: &AttachArgs) -> Result<(), String> {
+    let url = match &args.url {
+        Some(url) => url.clone(),
+        None => default_url(&source.load().0),
+    };
+    let mut headers = Vec::new();
+    if let Some(api_key) = &args.api_key {
+        headers.push((API_KEY_HEADER, api_key.clone()));
+    }
+    if let Some(token) = &args.token {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+    let gateway = Gateway { client: HttpClient::new(&url, args.ca_file.as_deref(), "gateway")?, headers };
+
+    let response = open(&gateway, args).await?;
+    let session_id = response["session_id"].as_str().ok_or("The gateway returned no session")?;
+    let ws_token = response["ws_token"].as_str().ok_or("The gateway returned no WebSocket token")?;
+    let mut socket = gateway.websocket(session_id, ws_token).await?;
+    let first_output = match response["auth_pending"].as_bool() {
+        Some(true) => authenticate(&mut socket).await?,
+        _ => None,
+    };
+    let reason = relay(&mut socket, first_output).await?;
+    eprintln!("\r\n{}", reason);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_characters_split_across_reads_wait_for_the_rest() {
+        let mut pending = "show ver".as_bytes().to_vec();
+        pending.extend_from_slice(&"é".as_bytes()[..1]);
+        assert_eq!(take_text(&mut pending), "show ver");
+        pending.extend_from_slice(&"é".as_bytes()[1..]);
+        assert_eq!(take_text(&mut pending), "é");
+        assert!(pending.is_empty());
+
+        // Bytes that can never be text are replaced rather than held back
+        pending.extend_from_slice(b"a\xffb");
+        assert_eq!(take_text(&mut pending), "a\u{fffd}b");
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,385 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+use crate::settings::AuditSettings;
+use crate::AppState;
+
+/// Longest command line kept; anything typed beyond it is dropped
+const MAX_LINE_CHARS: usize = 4096;
+
+/// Default and largest number of entries returned by one query
+const DEFAULT_QUERY_LIMIT: usize = 1000;
+const MAX_QUERY_LIMIT: usize = 10_000;
+
+/// A command line typed in a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub session_id: String,
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+    pub command: String,
+    /// The line was edited with keys whose effect depends on the device, e.g.
+    /// history recall or tab completion, so the command text may differ from
+    /// what the device ran
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub uncertain: bool,
+    /// The command policy refused the line, so it never reached the device
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub blocked: bool,
+}
+
+/// The append-only command audit log, one JSON entry per line
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(settings: &AuditSettings) -> io::Result<Self> {
+        let path = PathBuf::from(&settings.path);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        info!("Auditing commands to {}", path.display());
+        Ok(Self { path, file: Mutex::new(file) })
+    }
+
+    pub fn append(&self, entry: &AuditEntry) {
+        let result = serde_json::to_string(entry).map_err(io::Error::from).and_then(|line| {
+            let mut file = self.file.lock().map_err(|_| io::Error::other("audit log lock poisoned"))?;
+            writeln!(file, "{}", line)
+        });
+        if let Err(e) = result {
+            error!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Who a shell's commands are attributed to in the audit log
+#[derive(Clone)]
+pub struct AuditContext {
+    pub log: Arc<AuditLog>,
+    pub session_id: String,
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+}
+
+/// Audits the commands typed into one shell
+pub struct CommandAudit {
+    context: AuditContext,
+    editor: LineEditor,
+}
+
+/// A command audit shared between the WebSocket tasks of a shell
+pub type SharedAudit = Arc<Mutex<CommandAudit>>;
+
+impl CommandAudit {
+    pub fn new(context: AuditContext) -> SharedAudit {
+        Arc::new(Mutex::new(Self { context, editor: LineEditor::default() }))
+    }
+
+    /// Follows typed input, logging each command line it completes
+    pub fn input(&mut self, data: &[u8]) {
+        for (command, uncertain) in self.editor.feed(&String::from_utf8_lossy(data)) {
+            self.append(command, uncertain, false);
+        }
+    }
+
+    /// Logs a command line the command policy kept from the device
+    pub fn blocked(&mut self, command: &str) {
+        self.append(command.to_string(), false, true);
+    }
+
+    fn append(&self, command: String, uncertain: bool, blocked: bool) {
+        self.context.log.append(&AuditEntry {
+            timestamp: Utc::now(),
+            session_id: self.context.session_id.clone(),
+            portal_user_id: self.context.portal_user_id.clone(),
+            device_id: self.context.device_id.clone(),
+            ssh_username: self.context.ssh_username.clone(),
+            command,
+            uncertain,
+            blocked,
+        });
+    }
+}
+
+/// Applies typed input to the shell's audit, if commands are audited
+pub fn audit(audit: &Option<SharedAudit>, data: &[u8]) {
+    if let Some(audit) = audit {
+        if let Ok(mut audit) = audit.lock() {
+            audit.input(data);
+        }
+    }
+}
+
+/// Logs a refused command line in the shell's audit, if commands are audited
+pub fn audit_blocked(audit: &Option<SharedAudit>, command: &str) {
+    if let Some(audit) = audit {
+        if let Ok(mut audit) = audit.lock() {
+            audit.blocked(command);
+        }
+    }
+}
+
+/// Rebuilds command lines from keystrokes, as a shell's line editor would
+///
+/// Covers the editing keys common to bash, Cisco IOS and similar CLIs:
+/// backspace, delete, cursor movement, Ctrl-A/E/K/U/W and Ctrl-C. Keys whose
+/// effect only the device knows (history recall, completion, word motion)
+/// mark the line as uncertain rather than guessing.
+#[derive(Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    // Escape sequence read so far, including the ESC
+    escape: Option<String>,
+    uncertain: bool,
+}
+
+impl LineEditor {
+    /// Applies input, returning the non-empty lines it completed
+    pub fn feed(&mut self, input: &str) -> Vec<(String, bool)> {
+        let mut completed = Vec::new();
+        for c in input.chars() {
+            if let Some(mut sequence) = self.escape.take() {
+                // A line end still ends the line, though the device may read ESC Enter differently
+                if matches!(c, '\r' | '\n') {
+                    self.uncertain = true;
+                } else {
+                    sequence.push(c);
+                    if escape_complete(&sequence) {
+                        self.apply_escape(&sequence);
+                    } else {
+                        self.escape = Some(sequence);
+                    }
+                    continue;
+                }
+            }
+
+            match c {
+                '\r' | '\n' => {
+                    let command: String = self.line.iter().collect();
+                    if !command.trim().is_empty() {
+                        completed.push((command.trim().to_string(), self.uncertain));
+                    }
+                    self.clear();
+                }
+                '\x1b' => self.escape = Some(c.to_string()),
+                '\x7f' | '\x08' => self.backspace(),
+                '\x01' => self.cursor = 0,
+                '\x02' => self.cursor = self.cursor.saturating_sub(1),
+                '\x03' => self.clear(),
+                '\x04' => self.delete(),
+                '\x05' => self.cursor = self.line.len(),
+                '\x06' => self.cursor = (self.cursor + 1).min(self.line.len()),
+                '\x0b' => self.line.truncate(self.cursor),
+                '\x15' => {
+                    self.line.drain(..self.cursor);
+                    self.cursor = 0;
+                }
+                '\x17' => self.delete_word(),
+                // Tab completion, and history recall with Ctrl-P/N
+                '\t' | '\x10' | '\x0e' => self.uncertain = true,
+                c if c.is_control() => {}
+                c => self.insert(c),
+            }
+        }
+        completed
+    }
+
+    fn apply_escape(&mut self, sequence: &str) {
+        // CSI (ESC [) and SS3 (ESC O) sequences, e.g. arrows and Home/End
+        let body = sequence.strip_prefix("\x1b[").or_else(|| sequence.strip_prefix("\x1bO"));
+        match body {
+            Some("D") => self.cursor = self.cursor.saturating_sub(1),
+            Some("C") => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Some("H" | "1~" | "7~") => self.cursor = 0,
+            Some("F" | "4~" | "8~") => self.cursor = self.line.len(),
+            Some("3~") => self.delete(),
+            // Bracketed paste markers; the pasted text is typed as usual
+            Some("200~" | "201~") => {}
+            // History recall and anything else that may change the line
+            _ => self.uncertain = true,
+        }
+    }
+
+    fn insert(&mut self, c: char) {
+        if self.line.len() >= MAX_LINE_CHARS {
+            self.uncertain = true;
+            return;
+        }
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+        }
+    }
+
+    fn delete_word(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.line[start - 1] == ' ' {
+            start -= 1;
+        }
+        while start > 0 && self.line[start - 1] != ' ' {
+            start -= 1;
+        }
+        self.line.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    fn clear(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.uncertain = false;
+    }
+}
+
+/// Whether an escape sequence has been read to its final character
+fn escape_complete(sequence: &str) -> bool {
+    let mut chars = sequence.chars().skip(1);
+    match chars.next() {
+        None => false,
+        // CSI ends with a character from '@' to '~'
+        Some('[') => chars.last().is_some_and(|c| ('@'..='~').contains(&c)),
+        // SS3 is followed by a single character
+        Some('O') => chars.next().is_some(),
+        // Alt-modified key, e.g. ESC b
+        Some(_) => true,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub portal_user_id: Option<String>,
+    pub device_id: Option<String>,
+    pub session_id: Option<String>,
+    /// Only commands at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only commands before this time
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.portal_user_id.as_ref().is_none_or(|id| *id == entry.portal_user_id)
+            && self.device_id.as_ref().is_none_or(|id| *id == entry.device_id)
+            && self.session_id.as_ref().is_none_or(|id| *id == entry.session_id)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+    }
+}
+
+/// Reads the matching entries, newest first
+fn search(path: &PathBuf, query: &AuditQuery, limit: usize) -> io::Result<Vec<AuditEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries: Vec<AuditEntry> = BufReader::new(file).lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .filter(|entry| query.matches(entry))
+        .collect();
+    entries.reverse();
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Reads the commands typed in a session, oldest first
+pub fn session_commands(path: &PathBuf, session_id: &str) -> io::Result<Vec<AuditEntry>> {
+    let query = AuditQuery {
+        portal_user_id: None,
+        device_id: None,
+        session_id: Some(session_id.to_string()),
+        from: None,
+        to: None,
+        limit: None,
+    };
+    let mut entries = search(path, &query, usize::MAX)?;
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Lists audited commands, newest first, filtered by user, device, session and time
+pub async fn query_handler(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    let path = PathBuf::from(&state.settings.audit.path);
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT).clamp(1, MAX_QUERY_LIMIT);
+    match tokio::task::spawn_blocking(move || search(&path, &query, limit)).await {
+        Ok(Ok(entries)) => Json(json!({ "entries": entries })).into_response(),
+        Ok(Err(e)) => {
+            error!("Failed to read audit log: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "error": "audit_unavailable",
+                "message": format!("Failed to read audit log: {}", e),
+            }))).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+            "error": "audit_unavailable",
+            "message": e.to_string(),
+        }))).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(input: &str) -> Vec<(String, bool)> {
+        LineEditor::default().feed(input)
+    }
+
+    #[test]
+    fn test_line_editor_applies_editing_keys() {
+        assert_eq!(commands("show versoin\x7f\x7f\x7fion\r"), vec![("show version".to_string(), false)]);
+        // Left arrow twice, insert, End, append
+        assert_eq!(commands("sh ip rout\x1b[D\x1b[Dx\x1b[F e\r")[0].0, "sh ip roxut e");
+        // Ctrl-A, delete the first character, Ctrl-U on a second line
+        assert_eq!(commands("xls -l\x01\x1b[3~\r"), vec![("ls -l".to_string(), false)]);
+        assert_eq!(commands("rm -rf /\x15echo ok\r\n"), vec![("echo ok".to_string(), false)]);
+        assert_eq!(commands("copy run start\x17\x17conf t\r")[0].0, "copy conf t");
+        assert!(commands("reload\x03\r").is_empty());
+        assert_eq!(commands("reload\x1b\r"), vec![("reload".to_string(), true)]);
+
+        // History recall may change the line in ways only the device knows
+        assert_eq!(commands("\x1b[A\r"), Vec::<(String, bool)>::new());
+        assert_eq!(commands("ping \x1b[A1.1.1.1\r"), vec![("ping 1.1.1.1".to_string(), true)]);
+
+        // Escape sequences split across inputs
+        let mut editor = LineEditor::default();
+        assert!(editor.feed("abc\x1b").is_empty());
+        assert!(editor.feed("[").is_empty());
+        assert_eq!(editor.feed("Dx\rnext\r"), vec![("abxc".to_string(), false), ("next".to_string(), false)]);
+    }
+}
@@ -0,0 +1,169 @@
+use tracing::warn;
+
+use crate::jwt::AuthenticatedUser;
+use crate::settings::{AuthorizationSettings, DeviceAcl, Role};
+use crate::ssh::source::HostMatcher;
+
+/// What a request does with a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAccess {
+    /// Type into it, or use its files, forwards and other features
+    Use,
+    /// Watch its output read-only
+    View,
+    /// Close it
+    Terminate,
+}
+
+/// Checks the ACLs, so mistakes are reported before they are enforced
+pub fn validate(settings: &AuthorizationSettings) -> Result<(), String> {
+    for acl in &settings.acls {
+        if acl.name.trim().is_empty() {
+            return Err("Every authorization ACL needs a name".to_string());
+        }
+        HostMatcher::new(&acl.hosts, &format!("ACL '{}'", acl.name))?;
+    }
+    Ok(())
+}
+
+/// The role of a user: the highest their token's roles grant, or the default role
+pub fn role(settings: &AuthorizationSettings, user: &AuthenticatedUser) -> Option<Role> {
+    let grants = |names: &[String]| user.roles.iter().any(|role| names.iter().any(|name| name.eq_ignore_ascii_case(role)));
+    if grants(&settings.admin_roles) {
+        Some(Role::Admin)
+    } else if grants(&settings.operator_roles) {
+        Some(Role::Operator)
+    } else if grants(&settings.viewer_roles) {
+        Some(Role::Viewer)
+    } else {
+        settings.default_role
+    }
+}
+
+/// Whether an ACL is for the user
+fn grants(acl: &DeviceAcl, user: &AuthenticatedUser) -> bool {
+    (acl.subjects.is_empty() && acl.roles.is_empty())
+        || acl.subjects.contains(&user.subject)
+        || acl.roles.iter().any(|wanted| user.roles.iter().any(|role| role.eq_ignore_ascii_case(wanted)))
+}
+
+/// Whether an ACL covers a device, by its address or its inventory tags
+fn covers(acl: &DeviceAcl, hostname: &str, tags: &[String]) -> bool {
+    if acl.hosts.is_empty() && acl.tags.is_empty() {
+        return true;
+    }
+    let host_matches = !acl.hosts.is_empty() && match HostMatcher::new(&acl.hosts, &format!("ACL '{}'", acl.name)) {
+        Ok(matcher) => matcher.matches(hostname),
+        Err(e) => {
+            warn!("Ignoring the hosts of ACL '{}': {}", acl.name, e);
+            false
+        }
+    };
+    host_matches || acl.tags.iter().any(|wanted| tags.iter().any(|tag| tag.eq_ignore_ascii_case(wanted)))
+}
+
+/// Decides whether a user may open a connection to a device
+///
+/// # Arguments
+/// * `hostname` - The address the gateway connects to
+/// * `tags` - The tags of the inventory device the request named, if any
+///
+/// # Returns
+/// * `Result<(), String>` - Why the connection is refused
+pub fn check_connect(
+    settings: &AuthorizationSettings,
+    user: &AuthenticatedUser,
+    hostname: &str,
+    tags: &[String],
+) -> Result<(), String> {
+    if !settings.enabled {
+        return Ok(());
+    }
+    match role(settings, user) {
+        Some(Role::Admin) => Ok(()),
+        Some(Role::Operator) => {
+            if settings.acls.iter().any(|acl| grants(acl, user) && covers(acl, hostname, tags)) {
+                Ok(())
+            } else {
+                Err(format!("No ACL allows {} to connect to {}", user.subject, hostname))
+            }
+        }
+        Some(Role::Viewer) => Err("Viewers may only watch sessions shared with them".to_string()),
+        None => Err(format!("{} has no role that allows connecting to devices", user.subject)),
+    }
+}
+
+/// Decides whether a user may act on a session owned by `portal_user_id`
+///
+/// Owners may do anything with their sessions; with authorization enabled,
+/// admins may also watch and terminate those of others.
+pub fn may_access(settings: &AuthorizationSettings, user: &AuthenticatedUser, portal_user_id: &str, access: SessionAccess) -> bool {
+    user.owns(portal_user_id)
+        || (settings.enabled && access != SessionAccess::Use && role(settings, user) == Some(Role::Admin))
+}
+
+/// Decides whether a user may type into sessions, rather than only watch them
+pub fn may_interact(settings: &AuthorizationSettings, user: &AuthenticatedUser) -> bool {
+    !settings.enabled || role(settings, user) >= Some(Role::Operator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_and_device_acls() {
+        let user = |subject: &str, roles: &[&str]| AuthenticatedUser {
+            subject: subject.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        };
+        let settings = AuthorizationSettings {
+            enabled: true,
+            acls: vec![
+                DeviceAcl {
+                    name: "core".to_string(),
+                    roles: vec!["core-team".to_string()],
+                    hosts: vec!["10.0.0.0/24".to_string(), "*.core.example.net".to_string()],
+                    ..DeviceAcl::default()
+                },
+                DeviceAcl {
+                    name: "alice-lab".to_string(),
+                    subjects: vec!["alice".to_string()],
+                    tags: vec!["lab".to_string()],
+                    ..DeviceAcl::default()
+                },
+            ],
+            ..AuthorizationSettings::default()
+        };
+
+        let admin = user("root", &["operator", "Admin"]);
+        let core = user("bob", &["operator", "core-team"]);
+        let alice = user("alice", &["operator"]);
+        let viewer = user("carol", &["viewer"]);
+        let nobody = user("dave", &[]);
+        assert_eq!(role(&settings, &admin), Some(Role::Admin));
+        assert_eq!(role(&settings, &nobody), None);
+
+        assert!(check_connect(&settings, &admin, "192.0.2.1", &[]).is_ok());
+        assert!(check_connect(&settings, &core, "10.0.0.7", &[]).is_ok());
+        assert!(check_connect(&settings, &core, "PE1.core.example.net", &[]).is_ok());
+        assert!(check_connect(&settings, &core, "10.0.1.7", &["lab".to_string()]).is_err());
+        assert!(check_connect(&settings, &alice, "10.0.1.7", &["Lab".to_string()]).is_ok());
+        assert!(check_connect(&settings, &alice, "10.0.0.7", &[]).is_err());
+        assert!(check_connect(&settings, &viewer, "10.0.0.7", &[]).is_err());
+        assert!(check_connect(&settings, &nobody, "10.0.0.7", &[]).is_err());
+        let defaulted = AuthorizationSettings { default_role: Some(Role::Admin), ..settings.clone() };
+        assert!(check_connect(&defaulted, &nobody, "10.0.0.7", &[]).is_ok());
+
+        assert!(may_access(&settings, &core, "bob", SessionAccess::Use));
+        assert!(!may_access(&settings, &core, "alice", SessionAccess::View));
+        assert!(may_access(&settings, &admin, "alice", SessionAccess::Terminate));
+        assert!(!may_access(&settings, &admin, "alice", SessionAccess::Use));
+        assert!(may_interact(&settings, &core));
+        assert!(!may_interact(&settings, &viewer));
+
+        let disabled = AuthorizationSettings::default();
+        assert!(check_connect(&disabled, &nobody, "10.0.0.7", &[]).is_ok());
+        assert!(!may_access(&disabled, &admin, "alice", SessionAccess::Terminate));
+    }
+}
@@ -0,0 +1,446 @@
+//! Commands run on many devices at once
+//!
+//! A bulk exec job runs the same commands on every device it names, each as
+//! `/api/exec` would run them, on up to `concurrency` devices at a time. The
+//! request returns as soon as the job starts; its results are polled from
+//! `/api/bulk/exec/{job_id}`, or followed device by device as server-sent
+//! events, until `bulk_exec.retention_seconds` after it finishes.
+
+use axum::{
+    extract::{Path, RawQuery, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::error_code::ErrorCode;
+use crate::exec::{self, ExecPlan, ExecResponse};
+use crate::jwt::AuthenticatedUser;
+use crate::{AppState, SSHCredentials};
+
+/// A device a job runs on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum BulkDevice {
+    /// An inventory device, by ID or name
+    Inventory(String),
+    /// A device by address
+    Address {
+        hostname: String,
+        #[serde(default)]
+        port: u16,
+        device_type: Option<String>,
+    },
+}
+
+impl BulkDevice {
+    /// The device as shown in the job's results
+    fn label(&self) -> String {
+        match self {
+            BulkDevice::Inventory(device_ref) => device_ref.clone(),
+            BulkDevice::Address { hostname, port: 0, .. } => hostname.clone(),
+            BulkDevice::Address { hostname, port, .. } => format!("{}:{}", hostname, port),
+        }
+    }
+
+    /// The request for this device, with the job's login
+    fn credentials(&self, login: &SSHCredentials) -> SSHCredentials {
+        let mut credentials = login.clone();
+        match self {
+            BulkDevice::Inventory(device_ref) => credentials.device_ref = Some(device_ref.clone()),
+            BulkDevice::Address { hostname, port, device_type } => {
+                credentials.hostname = hostname.clone();
+                credentials.port = *port;
+                credentials.device_type = device_type.clone().or(credentials.device_type);
+            }
+        }
+        credentials
+    }
+}
+
+/// Body of a request to run commands on many devices
+#[derive(Debug, Deserialize)]
+pub struct BulkExecRequest {
+    /// The login used on every device; inventory devices without one use their own credentials
+    #[serde(flatten)]
+    credentials: SSHCredentials,
+    #[serde(default)]
+    devices: Vec<BulkDevice>,
+    /// Inventory devices with this tag, after those in `devices`
+    tag: Option<String>,
+    command: Option<String>,
+    #[serde(default)]
+    commands: Vec<String>,
+    /// Time allowed for all the commands on each device
+    timeout_seconds: Option<u64>,
+    max_output_bytes: Option<usize>,
+    #[serde(default)]
+    parse: bool,
+    /// Devices run on at once; defaults to `bulk_exec.default_concurrency`
+    concurrency: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// How the commands went on one device
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceRun {
+    pub device: String,
+    pub status: RunStatus,
+    pub started_at: Option<DateTime<Utc>>,
+    /// From connecting to the device to its last command ending
+    pub duration_ms: Option<u64>,
+    /// As `/api/exec` answers, once the device is done
+    pub result: Option<ExecResponse>,
+}
+
+struct JobState {
+    runs: Vec<DeviceRun>,
+    /// Indexes of the devices done, in the order they were done
+    done: Vec<usize>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+/// A bulk exec job, running or finished
+pub struct BulkJob {
+    id: String,
+    /// The JWT subject who started the job, the only one who may see it
+    owner: Option<String>,
+    created_at: DateTime<Utc>,
+    commands: Vec<String>,
+    concurrency: usize,
+    state: Mutex<JobState>,
+    /// Count of devices done, for those following the job
+    progress: watch::Sender<usize>,
+}
+
+impl BulkJob {
+    fn new(owner: Option<String>, devices: &[BulkDevice], plan: &ExecPlan, concurrency: usize) -> Self {
+        let runs = devices.iter()
+            .map(|device| DeviceRun {
+                device: device.label(),
+                status: RunStatus::Pending,
+                started_at: None,
+                duration_ms: None,
+                result: None,
+            })
+            .collect();
+        Self {
+            id: format!("bulk-{}", uuid::Uuid::new_v4()),
+            owner,
+            created_at: Utc::now(),
+            commands: plan.commands.clone(),
+            concurrency,
+            state: Mutex::new(JobState { runs, done: Vec::new(), finished_at: None }),
+            progress: watch::channel(0).0,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JobState> {
+        self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn start(&self, index: usize) {
+        let run = &mut self.lock().runs[index];
+        run.status = RunStatus::Running;
+        run.started_at = Some(Utc::now());
+    }
+
+    fn complete(&self, index: usize, result: ExecResponse, duration: Duration) {
+        let done = {
+            let mut state = self.lock();
+            let run = &mut state.runs[index];
+            run.status = if result.success { RunStatus::Succeeded } else { RunStatus::Failed };
+            run.duration_ms = Some(duration.as_millis() as u64);
+            run.result = Some(result);
+            state.done.push(index);
+            if state.done.len() == state.runs.len() {
+                state.finished_at = Some(Utc::now());
+            }
+            state.done.len()
+        };
+        self.progress.send_replace(done);
+    }
+
+    fn finished_at(&self) -> Option<DateTime<Utc>> {
+        self.lock().finished_at
+    }
+
+    /// The job's status, with the devices' runs if asked for
+    fn summary(&self, with_devices: bool) -> Value {
+        let state = self.lock();
+        let count = |status| state.runs.iter().filter(|run| run.status == status).count();
+        let mut summary = json!({
+            "job_id": self.id,
+            "status": if state.finished_at.is_some() { "finished" } else { "running" },
+            "created_at": self.created_at,
+            "finished_at": state.finished_at,
+            "commands": self.commands,
+            "concurrency": self.concurrency,
+            "total": state.runs.len(),
+            "pending": count(RunStatus::Pending),
+            "running": count(RunStatus::Running),
+            "succeeded": count(RunStatus::Succeeded),
+            "failed": count(RunStatus::Failed),
+        });
+        if with_devices {
+            summary["devices"] = json!(state.runs);
+        }
+        summary
+    }
+}
+
+/// Bulk exec jobs, kept in memory until they have been finished for the retention time
+#[derive(Default)]
+pub struct BulkJobs {
+    jobs: Mutex<HashMap<String, Arc<BulkJob>>>,
+}
+
+impl BulkJobs {
+    /// Adds a job, dropping those finished longer ago than `retention`
+    fn insert(&self, job: Arc<BulkJob>, retention: Duration) {
+        let cutoff = Utc::now() - chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+        let mut jobs = self.lock();
+        jobs.retain(|_, job| job.finished_at().is_none_or(|finished_at| finished_at > cutoff));
+        jobs.insert(job.id.clone(), job);
+    }
+
+    /// The job, if it exists and the user may see it
+    fn get(&self, job_id: &str, user: Option<&AuthenticatedUser>) -> Option<Arc<BulkJob>> {
+        self.lock().get(job_id)
+            .filter(|job| job.owner.as_deref().is_none_or(|owner| user.is_some_and(|user| user.owns(owner))))
+            .cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<BulkJob>>> {
+        self.jobs.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn job_not_found(job_id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "job_not_found", format!("No bulk exec job {}", job_id))
+}
+
+/// Starts running commands on many devices, answering with the job's ID
+pub async fn start_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    RawQuery(query): RawQuery,
+    Json(request): Json<BulkExecRequest>,
+) -> Response {
+    let policy_settings = state.policy.settings();
+    let settings = &policy_settings.bulk_exec;
+    if !settings.enabled {
+        return error_response(StatusCode::CONFLICT, "bulk_exec_disabled",
+                              "Bulk command execution is not enabled on this instance".to_string());
+    }
+    let plan = match ExecPlan::new(&state, &policy_settings.exec, request.command, request.commands,
+                                   request.timeout_seconds, request.max_output_bytes, request.parse) {
+        Ok(plan) => plan,
+        Err((status, error, message)) => return error_response(status, error, message),
+    };
+    if !request.credentials.hostname.is_empty() || request.credentials.device_ref.is_some() {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request",
+                              "Name the devices in devices or tag, not with hostname or device_ref".to_string());
+    }
+    let concurrency = request.concurrency.unwrap_or(settings.default_concurrency);
+    if concurrency == 0 || concurrency > settings.max_concurrency {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_concurrency",
+                              format!("concurrency must be between 1 and {}", settings.max_concurrency));
+    }
+
+    let mut devices = request.devices;
+    if let Some(tag) = &request.tag {
+        let Some(inventory) = &state.inventory else {
+            return error_response(StatusCode::CONFLICT, "inventory_disabled",
+                                  "The device inventory is not enabled on this instance".to_string());
+        };
+        match inventory.list(Some(tag)) {
+            Ok(tagged) => devices.extend(tagged.into_iter().map(|device| BulkDevice::Inventory(device.name))),
+            Err(e) => return crate::inventory::inventory_error(e),
+        }
+    }
+    // A device named twice, or both by name and through its tag, is run on once
+    let mut seen = Vec::new();
+    devices.retain(|device| {
+        let label = device.label();
+        let first = !seen.contains(&label);
+        seen.push(label);
+        first
+    });
+    if devices.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request",
+                              "Give the devices to run on in devices or tag".to_string());
+    }
+    if devices.len() > settings.max_devices {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request",
+                              format!("At most {} devices may be run on per job", settings.max_devices));
+    }
+
+    let user = user.map(|Extension(user)| user);
+    let job = Arc::new(BulkJob::new(user.as_ref().map(|user| user.subject.clone()), &devices, &plan, concurrency));
+    state.bulk_jobs.insert(job.clone(), Duration::from_secs(settings.retention_seconds));
+    info!("[{}] Running {} command(s) on {} devices, {} at a time",
+          job.id, plan.commands.len(), devices.len(), concurrency);
+
+    let response = json!({
+        "job_id": job.id,
+        "devices": devices.len(),
+        "status_url": format!("/api/bulk/exec/{}", job.id),
+        "events_url": format!("/api/bulk/exec/{}/events", job.id),
+    });
+    let login = request.credentials;
+    tokio::spawn(async move {
+        let started = Instant::now();
+        futures::stream::iter(devices.into_iter().enumerate())
+            .for_each_concurrent(concurrency, |(index, device)| {
+                let (state, policy_settings, job) = (&state, &policy_settings, &job);
+                let (user, query, plan, login) = (user.as_ref(), query.as_deref(), &plan, &login);
+                async move {
+                    job.start(index);
+                    let started = Instant::now();
+                    let exec_id = format!("{}-{}", job.id, index);
+                    let result = exec::exec_on_device(state, policy_settings, user, query, device.credentials(login), plan, &exec_id)
+                        .await
+                        .unwrap_or_else(|e| {
+                            error!("[{}] Exec task failed: {}", exec_id, e);
+                            ExecResponse::failed("Exec task failed".to_string(), ErrorCode::UnknownError, Vec::new(), Vec::new())
+                        });
+                    job.complete(index, result, started.elapsed());
+                }
+            })
+            .await;
+        let summary = job.summary(false);
+        info!("[{}] Bulk exec finished in {}s: {} succeeded, {} failed",
+              job.id, started.elapsed().as_secs(), summary["succeeded"], summary["failed"]);
+    });
+
+    (StatusCode::ACCEPTED, Json(response)).into_response()
+}
+
+/// The job's status and each device's results so far
+pub async fn status_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Path(job_id): Path<String>,
+) -> Response {
+    match state.bulk_jobs.get(&job_id, user.as_ref().map(|Extension(user)| user)) {
+        Some(job) => Json(job.summary(true)).into_response(),
+        None => job_not_found(&job_id),
+    }
+}
+
+/// Follows the job as server-sent events: a `device` event as each device is
+/// done, those done already first, then a `finished` event
+pub async fn events_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Path(job_id): Path<String>,
+) -> Response {
+    let Some(job) = state.bulk_jobs.get(&job_id, user.as_ref().map(|Extension(user)| user)) else {
+        return job_not_found(&job_id);
+    };
+    // Subscribed before anything is read, so no device done in between is missed
+    let progress = job.progress.subscribe();
+    let events = futures::stream::unfold((job, progress, 0, false), |(job, mut progress, sent, ended)| async move {
+        if ended {
+            return None;
+        }
+        loop {
+            let (run, finished) = {
+                let state = job.lock();
+                (state.done.get(sent).map(|&index| json!(state.runs[index])), state.finished_at.is_some())
+            };
+            if let Some(run) = run {
+                let event = Event::default().event("device").data(run.to_string());
+                return Some((Ok::<_, Infallible>(event), (job, progress, sent + 1, false)));
+            }
+            if finished {
+                let event = Event::default().event("finished").data(job.summary(false).to_string());
+                return Some((Ok(event), (job, progress, sent, true)));
+            }
+            // The job holds the sender, so this waits for the next device to be done
+            let _ = progress.changed().await;
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devices_are_named_by_inventory_reference_or_address() {
+        let devices: Vec<BulkDevice> = serde_json::from_value(json!([
+            "core-1",
+            {"hostname": "10.0.0.1"},
+            {"hostname": "10.0.0.2", "port": 2222, "device_type": "cisco"},
+        ])).unwrap();
+        let labels: Vec<_> = devices.iter().map(BulkDevice::label).collect();
+        assert_eq!(labels, ["core-1", "10.0.0.1", "10.0.0.2:2222"]);
+
+        let login = SSHCredentials { username: "netops".to_string(), ..SSHCredentials::default() };
+        assert_eq!(devices[0].credentials(&login).device_ref.as_deref(), Some("core-1"));
+        let credentials = devices[2].credentials(&login);
+        assert_eq!((credentials.hostname.as_str(), credentials.port), ("10.0.0.2", 2222));
+        assert_eq!(credentials.device_type.as_deref(), Some("cisco"));
+        assert_eq!(credentials.username, "netops");
+    }
+
+    #[test]
+    fn test_jobs_count_their_devices_as_they_finish() {
+        let plan = ExecPlan { commands: vec!["show version".to_string()], timeout_seconds: 30, max_output_bytes: 1024, parse: false };
+        let devices = [BulkDevice::Inventory("core-1".to_string()), BulkDevice::Inventory("core-2".to_string())];
+        let job = BulkJob::new(Some("alice".to_string()), &devices, &plan, 2);
+        let succeeded = ExecResponse {
+            success: true,
+            message: "Ran 1 command(s)".to_string(),
+            error_code: None,
+            results: Vec::new(),
+            shared_connection: false,
+            warnings: Vec::new(),
+        };
+
+        job.start(1);
+        job.complete(1, succeeded, Duration::from_millis(40));
+        let summary = job.summary(true);
+        assert_eq!((summary["status"].as_str(), summary["succeeded"].as_u64(), summary["pending"].as_u64()),
+                   (Some("running"), Some(1), Some(1)));
+        assert_eq!(summary["devices"][1]["status"], "succeeded");
+
+        job.start(0);
+        job.complete(0, ExecResponse::failed("refused".to_string(), ErrorCode::AuthFailed, Vec::new(), Vec::new()), Duration::ZERO);
+        let summary = job.summary(false);
+        assert_eq!((summary["status"].as_str(), summary["failed"].as_u64()), (Some("finished"), Some(1)));
+        assert_eq!(job.lock().done, [1, 0]);
+        assert_eq!(*job.progress.borrow(), 2);
+    }
+}
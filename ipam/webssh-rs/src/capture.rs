@@ -0,0 +1,330 @@
+use axum::{
+    body::Body,
+    extract::{ws::Message, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, warn};
+
+use crate::protocol::BinaryMessage;
+use crate::settings::CaptureSettings;
+use crate::AppState;
+
+/// A session's frame capture, shared by the registry and its WebSocket tasks
+///
+/// The slot exists for the life of the session so a capture can be started
+/// and stopped while a WebSocket is attached.
+pub type CaptureSlot = Arc<Mutex<Option<FrameCapture>>>;
+
+/// How much of each frame's payload goes into a capture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadMode {
+    /// Sizes and timings only
+    None,
+    /// Payloads of outgoing frames; the data of what the user types is left out
+    #[default]
+    Redacted,
+    /// Every payload, including keystrokes (and so passwords typed at prompts)
+    Full,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    /// From the client
+    In,
+    /// To the client
+    Out,
+}
+
+/// What is reported about a capture when it stops
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureInfo {
+    pub capture_id: String,
+    pub session_id: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub payloads: PayloadMode,
+    pub frames: u64,
+    pub size_bytes: u64,
+    /// The capture reached `capture.max_bytes` and later frames were not written
+    pub truncated: bool,
+}
+
+/// Writes every WebSocket frame of a session to a JSON Lines file
+///
+/// The first line describes the capture; each following line is one frame:
+/// `{"t": seconds since start, "dir": "in"|"out", "kind", "size", "payload"}`.
+/// Binary payloads are base64 encoded.
+pub struct FrameCapture {
+    writer: BufWriter<File>,
+    info: CaptureInfo,
+    started: Instant,
+    max_bytes: u64,
+}
+
+impl FrameCapture {
+    /// Creates the capture file in the configured directory
+    pub fn start(settings: &CaptureSettings, session_id: &str, payloads: PayloadMode) -> std::io::Result<Self> {
+        let directory = PathBuf::from(&settings.directory);
+        fs::create_dir_all(&directory)?;
+
+        let info = CaptureInfo {
+            capture_id: uuid::Uuid::new_v4().to_string(),
+            session_id: session_id.to_string(),
+            started_at: Utc::now(),
+            ended_at: None,
+            payloads,
+            frames: 0,
+            size_bytes: 0,
+            truncated: false,
+        };
+        let path = directory.join(format!("{}.jsonl", info.capture_id));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let header = serde_json::to_string(&info)?;
+        writeln!(writer, "{}", header)?;
+        writer.flush()?;
+        info!("Capturing WebSocket frames of session {} to {}", session_id, path.display());
+
+        Ok(Self {
+            writer,
+            info: CaptureInfo { size_bytes: header.len() as u64 + 1, ..info },
+            started: Instant::now(),
+            max_bytes: settings.max_bytes,
+        })
+    }
+
+    pub fn info(&self) -> &CaptureInfo {
+        &self.info
+    }
+
+    /// Writes a frame sent or received on the session's WebSocket
+    pub fn record(&mut self, direction: Direction, msg: &Message) {
+        if self.info.truncated {
+            return;
+        }
+        let (kind, size, payload) = match msg {
+            Message::Text(text) => ("text", text.len(), Some(Value::String(text.clone()))),
+            Message::Binary(data) => ("binary", data.len(), Some(Value::String(base64::encode(data)))),
+            Message::Ping(data) => ("ping", data.len(), None),
+            Message::Pong(data) => ("pong", data.len(), None),
+            Message::Close(frame) => ("close", 0, frame.as_ref().map(|frame| json!({
+                "code": frame.code,
+                "reason": frame.reason,
+            }))),
+        };
+        let payload = match (self.info.payloads, direction) {
+            (PayloadMode::None, _) => None,
+            (_, Direction::In) if is_secret(msg) => redact(kind, payload),
+            (PayloadMode::Redacted, Direction::In) => redact(kind, payload),
+            _ => payload,
+        };
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let mut frame = json!({
+            "t": (elapsed * 1_000_000.0).round() / 1_000_000.0,
+            "dir": match direction { Direction::In => "in", Direction::Out => "out" },
+            "kind": kind,
+            "size": size,
+        });
+        if let Some(payload) = payload {
+            frame["payload"] = payload;
+        }
+
+        let line = frame.to_string();
+        if self.info.size_bytes + line.len() as u64 + 1 > self.max_bytes {
+            warn!("Capture {} reached its size limit; further frames are not written", self.info.capture_id);
+            self.info.truncated = true;
+            return;
+        }
+        match writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()) {
+            Ok(()) => {
+                self.info.frames += 1;
+                self.info.size_bytes += line.len() as u64 + 1;
+            }
+            Err(e) => error!("Failed to write capture {}: {}", self.info.capture_id, e),
+        }
+    }
+
+    /// Flushes the file and stamps the end time
+    pub fn finish(mut self) -> CaptureInfo {
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush capture {}: {}", self.info.capture_id, e);
+        }
+        self.info.ended_at = Some(Utc::now());
+        info!("Finished capture {} of session {} ({} frames)", self.info.capture_id, self.info.session_id, self.info.frames);
+        self.info.clone()
+    }
+}
+
+/// Whether a client frame answers a password prompt, which is never written out in full
+fn is_secret(msg: &Message) -> bool {
+    let text = match msg {
+        Message::Text(text) => Some(text.clone()),
+        Message::Binary(data) => match BinaryMessage::from_binary(data) {
+            Ok(BinaryMessage::Event { json }) => Some(json),
+            _ => None,
+        },
+        _ => None,
+    };
+    text.and_then(|text| serde_json::from_str::<Value>(&text).ok())
+        .is_some_and(|command| command["type"] == "secret_input")
+}
+
+/// Leaves the typed data out of a client frame, keeping what kind of command it was
+fn redact(kind: &str, payload: Option<Value>) -> Option<Value> {
+    if kind != "text" {
+        return None;
+    }
+    let Value::String(text) = payload? else {
+        return None;
+    };
+    let mut command: Value = serde_json::from_str(&text).ok()?;
+    if let Some(data) = command.get_mut("data") {
+        *data = Value::String("[redacted]".to_string());
+    }
+    Some(Value::String(command.to_string()))
+}
+
+/// Writes a frame to the session's capture, if one is running
+pub fn capture(slot: &CaptureSlot, direction: Direction, msg: &Message) {
+    if let Ok(mut capture) = slot.lock() {
+        if let Some(capture) = capture.as_mut() {
+            capture.record(direction, msg);
+        }
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({ "error": error, "message": message }))).into_response()
+}
+
+fn session_not_found(session_id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct StartCaptureRequest {
+    pub payloads: PayloadMode,
+}
+
+/// Starts capturing the WebSocket frames of a session
+pub async fn start_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    request: Option<Json<StartCaptureRequest>>,
+) -> Response {
+    let session_id = session_id.trim();
+    let payloads = request.map(|Json(request)| request.payloads).unwrap_or_default();
+    let slot = match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => session_info.capture.clone(),
+        None => return session_not_found(session_id),
+    };
+
+    let mut capture = match slot.lock() {
+        Ok(capture) => capture,
+        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "capture_failed", "Capture state is unavailable".to_string()),
+    };
+    if let Some(running) = capture.as_ref() {
+        return error_response(StatusCode::CONFLICT, "capture_running",
+            format!("Session '{}' is already being captured as '{}'", session_id, running.info().capture_id));
+    }
+    match FrameCapture::start(&state.settings.capture, session_id, payloads) {
+        Ok(started) => {
+            let info = started.info().clone();
+            *capture = Some(started);
+            Json(info).into_response()
+        }
+        Err(e) => {
+            error!("Failed to start capture for session {}: {}", session_id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "capture_failed", format!("Failed to start capture: {}", e))
+        }
+    }
+}
+
+/// Stops a session's capture, reporting what was written
+pub async fn stop_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    let slot = match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => session_info.capture.clone(),
+        None => return session_not_found(session_id),
+    };
+    let stopped = slot.lock().ok().and_then(|mut capture| capture.take());
+    match stopped {
+        Some(capture) => Json(capture.finish()).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "capture_not_running",
+            format!("Session '{}' is not being captured", session_id)),
+    }
+}
+
+/// Downloads a capture as JSON Lines, whether or not it has stopped
+pub async fn download_handler(
+    State(state): State<AppState>,
+    Path(capture_id): Path<String>,
+) -> Response {
+    let not_found = || error_response(StatusCode::NOT_FOUND, "capture_not_found", format!("Capture '{}' not found", capture_id));
+    if uuid::Uuid::parse_str(&capture_id).is_err() {
+        return not_found();
+    }
+    let path = PathBuf::from(&state.settings.capture.directory).join(format!("{}.jsonl", capture_id));
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return not_found(),
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/jsonl".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.jsonl\"", capture_id)),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_redacts_client_input() {
+        let directory = std::env::temp_dir().join(format!("webssh-capture-{}", uuid::Uuid::new_v4()));
+        let settings = CaptureSettings {
+            directory: directory.to_string_lossy().into_owned(),
+            max_bytes: 1024,
+        };
+
+        let mut capture = FrameCapture::start(&settings, "session", PayloadMode::Redacted).unwrap();
+        capture.record(Direction::In, &Message::Text(r#"{"type":"input","data":"secret\r"}"#.to_string()));
+        capture.record(Direction::In, &Message::Binary(b"secret".to_vec()));
+        capture.record(Direction::Out, &Message::Binary(b"\x1b[2J$ ".to_vec()));
+        capture.record(Direction::Out, &Message::Binary(vec![b'x'; 1024]));
+        let info = capture.finish();
+        assert_eq!(info.frames, 3);
+        assert!(info.truncated);
+
+        let contents = fs::read_to_string(directory.join(format!("{}.jsonl", info.capture_id))).unwrap();
+        assert!(!contents.contains("secret"));
+        let frames: Vec<Value> = contents.lines().skip(1).map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(frames[0]["payload"], r#"{"data":"[redacted]","type":"input"}"#);
+        assert_eq!(frames[1]["size"], 6);
+        assert!(frames[1].get("payload").is_none());
+        assert_eq!(frames[2]["dir"], "out");
+        assert_eq!(frames[2]["payload"], base64::encode(b"\x1b[2J$ "));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
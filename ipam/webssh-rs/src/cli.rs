@@ -0,0 +1,428 @@
+use clap::{Args, Parser, Subcommand};
+use serde_json::{json, Value};
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use tracing::{warn, Level, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::FmtSubscriber;
+
+use crate::affinity::NodeIdentity;
+use crate::api_keys::API_KEY_HEADER;
+use crate::http::HttpPolicy;
+use crate::http_client::HttpClient;
+use crate::jwt::JwtValidator;
+use crate::settings::{LogFormat, LoggingSettings, Settings, SettingsOrigin, SETTINGS_FILE};
+use crate::{authz, command_policy, protocol, ssh, terminal, tls, ws_deflate};
+
+/// A web-based SSH gateway to network devices; without a subcommand it serves
+///
+/// Each setting is taken from the first of: its flag, the flag's environment
+/// variable, the settings file, and the built-in default.
+#[derive(Debug, Parser)]
+#[command(name = "webssh-rs", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub source: SettingsSource,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the gateway
+    Serve,
+    /// Checks the settings file with the flags applied, reports every problem found and exits
+    CheckConfig,
+    /// Lists the live sessions of a running gateway through its API
+    ListSessions(ListSessionsArgs),
+    /// Opens a shell on an inventory device through a running gateway, in this terminal; Ctrl-] detaches
+    Attach(AttachArgs),
+    /// Prints the version and the optional features built in
+    Version,
+    /// Exports or imports policy bundles: `policy export [--version N] [--out FILE]`, `policy import FILE [--force] [--dry-run]`
+    Policy {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Drives synthetic sessions through a running gateway: `soak --url URL --request FILE [--sessions N] [--duration 4h]`
+    #[cfg(feature = "fault-injection")]
+    Soak {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
+/// Where the settings are read from, and what the command line changes in them
+///
+/// Kept for the life of the server, so that a reload reads the same file and
+/// keeps the same overrides.
+#[derive(Debug, Clone, Args)]
+pub struct SettingsSource {
+    /// The settings file
+    #[arg(long, short = 'c', global = true, env = "WEBSSH_CONFIG", value_name = "FILE", default_value = SETTINGS_FILE)]
+    pub config: PathBuf,
+    #[command(flatten)]
+    pub overrides: Overrides,
+}
+
+/// Settings given on the command line or in the environment, in place of the file's
+#[derive(Debug, Clone, Default, Args)]
+pub struct Overrides {
+    /// Address to listen on, in place of `server.address`
+    #[arg(long = "bind", alias = "address", global = true, env = "WEBSSH_SERVER_ADDRESS", value_name = "ADDRESS")]
+    pub address: Option<String>,
+    /// Port to listen on, in place of `server.port`
+    #[arg(long, global = true, env = "WEBSSH_SERVER_PORT")]
+    pub port: Option<u16>,
+    /// Most verbose level logged, in place of `logging.level`
+    #[arg(long, global = true, env = "RUST_LOG", value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// How log lines are written, in place of `logging.format`
+    #[arg(long, global = true, env = "WEBSSH_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Serve HTTPS and WSS, turning on `server.tls_enabled`
+    #[arg(long, global = true, conflicts_with = "no_tls")]
+    pub tls: bool,
+    /// Serve plain HTTP and WS, turning off `server.tls_enabled`
+    #[arg(long, global = true)]
+    pub no_tls: bool,
+    /// PEM certificate chain, in place of `server.cert_file`
+    #[arg(long, alias = "cert", global = true, env = "WEBSSH_TLS_CERT", value_name = "FILE")]
+    pub tls_cert: Option<String>,
+    /// PEM private key, in place of `server.key_file`
+    #[arg(long, alias = "key", global = true, env = "WEBSSH_TLS_KEY", value_name = "FILE")]
+    pub tls_key: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(address) = &self.address {
+            settings.server.address = address.clone();
+        }
+        if let Some(port) = self.port {
+            settings.server.port = port;
+        }
+        if let Some(level) = &self.log_level {
+            settings.logging.level = level.clone();
+        }
+        if let Some(format) = self.log_format {
+            settings.logging.format = format;
+        }
+        if self.tls {
+            settings.server.tls_enabled = true;
+        }
+        if self.no_tls {
+            settings.server.tls_enabled = false;
+        }
+        if let Some(cert_file) = &self.tls_cert {
+            settings.server.cert_file = Some(cert_file.clone());
+        }
+        if let Some(key_file) = &self.tls_key {
+            settings.server.key_file = Some(key_file.clone());
+        }
+    }
+}
+
+impl SettingsSource {
+    /// The settings in force at startup: the file, or the defaults without one, with the overrides
+    pub fn load(&self) -> (Settings, SettingsOrigin) {
+        let (mut settings, origin) = Settings::load(&self.config);
+        self.overrides.apply(&mut settings);
+        (settings, origin)
+    }
+
+    /// Reads the file again, failing rather than falling back to the defaults, with the overrides
+    pub fn reload(&self) -> Result<Settings, String> {
+        let mut settings = Settings::load_from_file(&self.config)
+            .map_err(|e| format!("Cannot load {}: {}", self.config.display(), e))?;
+        self.overrides.apply(&mut settings);
+        Ok(settings)
+    }
+}
+
+/// Sets up the log output
+///
+/// A level that is not a plain level, e.g. a `RUST_LOG` filter, is reported
+/// and `info` is used instead.
+pub fn init_logging(settings: &LoggingSettings) {
+    subscriber(settings, std::io::stdout).init();
+    if settings.level.parse::<Level>().is_err() {
+        warn!("Unknown log level '{}'; logging at info", settings.level);
+    }
+}
+
+/// The subscriber `init_logging` installs, writing to the given writer
+///
+/// JSON lines carry the fields of the span they were logged in, e.g. the
+/// session's, under `span`.
+pub fn subscriber<W>(settings: &LoggingSettings, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = FmtSubscriber::builder()
+        .with_max_level(settings.level.parse::<Level>().unwrap_or(Level::INFO))
+        .with_thread_ids(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_writer(writer);
+    match settings.format {
+        LogFormat::Compact => Box::new(builder
+            .with_level(false)  // Hide log levels in production
+            .with_target(false)  // Hide targets in production
+            .compact()
+            .finish()),
+        LogFormat::Pretty => Box::new(builder.pretty().finish()),
+        LogFormat::Json => Box::new(builder.json().with_current_span(true).with_span_list(false).finish()),
+    }
+}
+
+/// `webssh-rs check-config`: every problem the server would refuse to start with
+///
+/// The TLS certificate and key are read; nothing is written and no connection is made.
+pub async fn check_config(source: &SettingsSource) -> Result<(), String> {
+    let settings = source.reload()?;
+
+    let problems = check(&settings).await;
+    if !problems.is_empty() {
+        return Err(format!("{} has {} problem(s):\n  {}", source.config.display(), problems.len(), problems.join("\n  ")));
+    }
+    let scheme = if settings.server.tls_enabled { "https" } else { "http" };
+    println!("{} is valid; the server would listen on {}://{}:{}",
+             source.config.display(), scheme, settings.server.address, settings.server.port);
+    Ok(())
+}
+
+async fn check(settings: &Settings) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut problem = |what: &str, result: Result<(), String>| {
+        if let Err(e) = result {
+            problems.push(format!("{}: {}", what, e));
+        }
+    };
+
+    let listen = format!("{}:{}", settings.server.address, settings.server.port);
+    problem("server.address", listen.to_socket_addrs().map(drop).map_err(|e| format!("cannot listen on {}: {}", listen, e)));
+    if settings.server.tls_enabled {
+        problem("TLS", tls::load(&settings.server).await.map(drop));
+    }
+    problem("server.node_id", NodeIdentity::from_settings(&settings.server).map(drop));
+    if settings.jwt.enabled {
+        problem("jwt", JwtValidator::from_settings(&settings.jwt).map(drop));
+    }
+    problem("http", HttpPolicy::from_settings(&settings.http).map(drop));
+    problem("ssh.outbound", ssh::source::validate(&settings.ssh.outbound));
+    problem("ssh.terminal.pty_modes", terminal::validate(&settings.ssh.terminal));
+    problem("ssh.gssapi", ssh::gssapi::validate(&settings.ssh.gssapi));
+    problem("command_policy", command_policy::validate(&settings.command_policy));
+    problem("authorization", authz::validate(&settings.authorization));
+    if settings.server.control_plane.require_api_key && !settings.api_keys.enabled {
+        problem("server.control_plane.require_api_key", Err("needs api_keys.enabled".to_string()));
+    }
+    problem("forwarding.socks", ssh::socks::DestinationFilter::new(&settings.forwarding.socks).map(drop));
+    problem("binary_protocol", protocol::validate(&settings.binary_protocol));
+    problem("websocket", ws_deflate::validate(&settings.websocket));
+    if settings.config_backup.enabled && !settings.inventory.enabled {
+        problem("config_backup", Err("needs the device inventory; enable inventory or turn config_backup off".to_string()));
+    }
+    if settings.logging.level.parse::<Level>().is_err() {
+        problem("logging.level", Err(format!("unknown level '{}'", settings.logging.level)));
+    }
+    problems
+}
+
+/// `webssh-rs list-sessions`
+#[derive(Debug, Args)]
+pub struct ListSessionsArgs {
+    /// Base URL of the gateway; defaults to the control plane listener if enabled, else the server's
+    #[arg(long, env = "WEBSSH_URL")]
+    pub url: Option<String>,
+    /// API key with the `read_status` scope
+    #[arg(long, env = "WEBSSH_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// JWT bearer token, when API keys are not enabled
+    #[arg(long, env = "WEBSSH_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// Only the sessions of this portal user
+    #[arg(long)]
+    pub portal_user: Option<String>,
+    /// PEM bundle of roots trusted for an HTTPS gateway; defaults to the system bundle
+    #[arg(long, value_name = "FILE")]
+    pub ca_file: Option<String>,
+    /// Print the API's response as is
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct AttachArgs {
+    /// The inventory device, by ID or name
+    pub device: String,
+    /// Base URL of the gateway; defaults to the control plane listener if enabled, else the server's
+    #[arg(long, env = "WEBSSH_URL")]
+    pub url: Option<String>,
+    /// API key with the `connect` scope
+    #[arg(long, env = "WEBSSH_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// JWT bearer token, when JWT authentication is enabled
+    #[arg(long, env = "WEBSSH_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// Username on the device, in place of the one in its default credentials
+    #[arg(long, short = 'l')]
+    pub username: Option<String>,
+    /// PEM bundle of roots trusted for an HTTPS gateway; defaults to the system bundle
+    #[arg(long, value_name = "FILE")]
+    pub ca_file: Option<String>,
+}
+
+/// `webssh-rs list-sessions`: asks a running gateway for its sessions over `POST /api/sessions`
+pub async fn list_sessions(source: &SettingsSource, args: &ListSessionsArgs) -> Result<(), String> {
+    let url = match &args.url {
+        Some(url) => url.clone(),
+        None => {
+            let (settings, _) = source.load();
+            default_url(&settings)
+        }
+    };
+    let client = HttpClient::new(&url, args.ca_file.as_deref(), "gateway")?;
+    let bearer = args.token.as_ref().map(|token| format!("Bearer {}", token));
+    let mut headers = Vec::new();
+    if let Some(api_key) = &args.api_key {
+        headers.push((API_KEY_HEADER, api_key.as_str()));
+    }
+    if let Some(bearer) = &bearer {
+        headers.push(("Authorization", bearer.as_str()));
+    }
+    let path = format!("{}/api/sessions", client.endpoint.path.trim_end_matches('/'));
+    let body = json!({ "portal_user_id": args.portal_user }).to_string();
+
+    let (status, response) = client.request("POST", &path, &headers, Some(&body)).await
+        .map_err(|e| format!("Cannot reach the gateway at {}: {}", url, e))?;
+    let response: Value = serde_json::from_slice(&response)
+        .map_err(|e| format!("Unexpected response from {} (HTTP {}): {}", url, status, e))?;
+    if status != 200 {
+        let message = response["message"].as_str().or(response["error"].as_str()).unwrap_or("request refused");
+        return Err(format!("{} answered HTTP {}: {}", url, status, message));
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&response).map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    print!("{}", session_table(&response));
+    Ok(())
+}
+
+/// Where the gateway described by the settings serves its API
+pub(crate) fn default_url(settings: &Settings) -> String {
+    let scheme = if settings.server.tls_enabled { "https" } else { "http" };
+    let control_plane = &settings.server.control_plane;
+    let (address, port) = match control_plane.enabled {
+        true => (control_plane.address.as_str(), control_plane.port),
+        false => (settings.server.address.as_str(), settings.server.port),
+    };
+    // A wildcard listener is reached locally
+    let address = match address {
+        "0.0.0.0" => "127.0.0.1",
+        "::" | "[::]" => "[::1]",
+        address => address,
+    };
+    format!("{}://{}:{}", scheme, address, port)
+}
+
+/// The sessions of a `/api/sessions` response, one per line under a header
+fn session_table(response: &Value) -> String {
+    let text = |value: &Value| match value {
+        Value::String(text) => text.clone(),
+        Value::Null => "-".to_string(),
+        value => value.to_string(),
+    };
+    let mut rows = vec![["SESSION", "DEVICE", "USER", "PORTAL USER", "PROTOCOL", "HEALTH", "LAST OUTPUT"].map(String::from).to_vec()];
+    for session in response["sessions"].as_array().into_iter().flatten() {
+        rows.push(vec![
+            text(&session["session_id"]),
+            text(&session["device_id"]),
+            text(&session["ssh_username"]),
+            text(&session["portal_user_id"]),
+            text(&session["protocol"]),
+            text(&session["health"]["state"]),
+            text(&session["last_output_at"]),
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    let mut table = String::new();
+    for row in &rows {
+        let line: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+    table.push_str(&format!("{} session(s) on node {}\n", rows.len() - 1, text(&response["node_id"])));
+    table
+}
+
+/// `webssh-rs version`
+pub fn print_version() {
+    let features: Vec<&str> = [
+        ("reactor-io", cfg!(feature = "reactor-io")),
+        ("fault-injection", cfg!(feature = "fault-injection")),
+        ("os-keyring", cfg!(feature = "os-keyring")),
+    ].into_iter().filter(|(_, enabled)| *enabled).map(|(feature, _)| feature).collect();
+    println!("webssh-rs {}", env!("CARGO_PKG_VERSION"));
+    println!("features: {}", if features.is_empty() { "none".to_string() } else { features.join(", ") });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("webssh-rs").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_flags_override_the_file() {
+        let cli = parse(&["serve", "--bind", "127.0.0.1", "--port", "9000", "--log-format", "json", "--tls",
+                          "--tls-cert", "cert.pem", "-c", "other.json"]);
+        assert!(matches!(cli.command, Some(Command::Serve)));
+        assert_eq!(cli.source.config, PathBuf::from("other.json"));
+
+        let mut settings = Settings::default();
+        cli.source.overrides.apply(&mut settings);
+        assert_eq!((settings.server.address.as_str(), settings.server.port), ("127.0.0.1", 9000));
+        assert_eq!(settings.logging.format, LogFormat::Json);
+        assert!(settings.server.tls_enabled);
+        assert_eq!(settings.server.cert_file.as_deref(), Some("cert.pem"));
+        // Unset flags leave the file's values
+        assert_eq!(settings.server.key_file, Settings::default().server.key_file);
+        assert_eq!(settings.logging.level, "info");
+    }
+
+    #[test]
+    fn test_policy_arguments_are_passed_through() {
+        let cli = parse(&["policy", "export", "--version", "3", "--out", "bundle.json"]);
+        match cli.command {
+            Some(Command::Policy { args }) => assert_eq!(args, ["export", "--version", "3", "--out", "bundle.json"]),
+            command => panic!("parsed as {:?}", command),
+        }
+        assert!(Cli::try_parse_from(["webssh-rs", "--tls", "--no-tls"]).is_err());
+    }
+
+    #[test]
+    fn test_session_table() {
+        let response = json!({
+            "node_id": "gw1",
+            "sessions": [{
+                "session_id": "abc", "device_id": "router1", "ssh_username": "admin", "portal_user_id": "alice",
+                "protocol": "ssh", "health": { "state": "healthy" }, "last_output_at": "2026-01-01T00:00:00Z",
+            }],
+        });
+        let table = session_table(&response);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("SESSION  DEVICE   USER   PORTAL USER"));
+        assert!(lines[1].starts_with("abc      router1  admin  alice"));
+        assert_eq!(lines[2], "1 session(s) on node gw1");
+    }
+}
@@ -0,0 +1,237 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::redis_client::{RedisClient, Reply};
+use crate::settings::ClusterSettings;
+use crate::store::SessionEvent;
+
+/// A session as registered for the other instances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedSession {
+    pub session_id: String,
+    /// The instance holding the session's shell
+    pub node_id: String,
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+    pub hostname: String,
+    pub port: u16,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An instance, as it announces itself with every heartbeat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeRecord {
+    pub node_id: String,
+    /// Base URL the instance is reached at directly
+    pub url: String,
+    pub heartbeat_at: DateTime<Utc>,
+}
+
+/// Names of the keys kept in Redis
+#[derive(Clone)]
+struct Keys {
+    prefix: String,
+}
+
+impl Keys {
+    fn session(&self, session_id: &str) -> String {
+        format!("{}:session:{}", self.prefix, session_id)
+    }
+
+    fn node(&self, node_id: &str) -> String {
+        format!("{}:node:{}", self.prefix, node_id)
+    }
+
+    /// The set of the IDs of an instance's sessions
+    fn node_sessions(&self, node_id: &str) -> String {
+        format!("{}:node-sessions:{}", self.prefix, node_id)
+    }
+}
+
+enum Change {
+    Created(OwnedSession),
+    Ended(String),
+}
+
+/// The sessions of every instance behind a load balancer, kept in Redis
+///
+/// Each instance registers the sessions it creates and removes them when
+/// they end, from a background task so the session registry never waits on
+/// Redis. A heartbeat keeps the instance's own key alive; the sessions of an
+/// instance whose key has expired are taken as gone. After Redis has been
+/// unreachable, the instance registers its live sessions again.
+pub struct SessionDirectory {
+    client: Arc<RedisClient>,
+    keys: Keys,
+    node_id: String,
+    changes: mpsc::UnboundedSender<Change>,
+}
+
+impl SessionDirectory {
+    /// Checks the settings and starts registering this instance's sessions
+    ///
+    /// Redis need not be reachable yet; the heartbeat keeps trying.
+    pub fn start(settings: &ClusterSettings, node_id: &str) -> Result<Self, String> {
+        let client = Arc::new(RedisClient::new(&settings.redis_url)?);
+        let url = settings.advertise_url.as_deref().map(str::trim).filter(|url| !url.is_empty())
+            .ok_or("cluster.advertise_url must be set when the session registry is in Redis")?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("cluster.advertise_url '{}' must be an http:// or https:// URL", url));
+        }
+        let keys = Keys { prefix: settings.key_prefix.clone() };
+        let node = NodeRecord { node_id: node_id.to_string(), url: url.trim_end_matches('/').to_string(), heartbeat_at: Utc::now() };
+        let (changes, changes_rx) = mpsc::unbounded_channel();
+        tokio::spawn(sync(client.clone(), keys.clone(), node, settings.node_ttl_seconds.max(3), changes_rx));
+        Ok(Self { client, keys, node_id: node_id.to_string(), changes })
+    }
+
+    /// Notes a lifecycle event of one of this instance's sessions
+    pub fn record(&self, event: &SessionEvent) {
+        let change = match event {
+            SessionEvent::Created(record) => Change::Created(OwnedSession {
+                session_id: record.session_id.clone(),
+                node_id: self.node_id.clone(),
+                portal_user_id: record.portal_user_id.clone(),
+                device_id: record.device_id.clone(),
+                ssh_username: record.ssh_username.clone(),
+                hostname: record.hostname.clone(),
+                port: record.port,
+                created_at: record.created_at,
+            }),
+            SessionEvent::Ended { session_id, .. } => Change::Ended(session_id.to_string()),
+            _ => return,
+        };
+        let _ = self.changes.send(change);
+    }
+
+    /// Finds the live instance holding a session, if it is not this one
+    pub async fn owner(&self, session_id: &str) -> Result<Option<NodeRecord>, String> {
+        let Some(session) = get::<OwnedSession>(&self.client, &self.keys.session(session_id)).await? else {
+            return Ok(None);
+        };
+        if session.node_id == self.node_id {
+            return Ok(None);
+        }
+        match get::<NodeRecord>(&self.client, &self.keys.node(&session.node_id)).await? {
+            Some(node) => Ok(Some(node)),
+            None => {
+                // The owner stopped without removing its sessions; they died with it
+                info!("Session {} belonged to node {}, which is gone", session_id, session.node_id);
+                let _ = run(&self.client, &["DEL", &self.keys.session(session_id)]).await;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Runs a command, taking error replies as failures
+async fn run(client: &RedisClient, args: &[&str]) -> Result<Reply, String> {
+    match client.command(args).await {
+        Ok(Reply::Error(e)) => Err(e),
+        Ok(reply) => Ok(reply),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Reads a JSON value, or `None` if the key does not exist
+async fn get<T: serde::de::DeserializeOwned>(client: &RedisClient, key: &str) -> Result<Option<T>, String> {
+    match run(client, &["GET", key]).await?.text() {
+        Some(text) => serde_json::from_str(&text).map(Some).map_err(|e| format!("Malformed value of {}: {}", key, e)),
+        None => Ok(None),
+    }
+}
+
+async fn register(client: &RedisClient, keys: &Keys, session: &OwnedSession) -> Result<(), String> {
+    let value = serde_json::to_string(session).map_err(|e| e.to_string())?;
+    run(client, &["SET", &keys.session(&session.session_id), &value]).await?;
+    run(client, &["SADD", &keys.node_sessions(&session.node_id), &session.session_id]).await?;
+    Ok(())
+}
+
+async fn unregister(client: &RedisClient, keys: &Keys, node_id: &str, session_id: &str) -> Result<(), String> {
+    run(client, &["DEL", &keys.session(session_id)]).await?;
+    run(client, &["SREM", &keys.node_sessions(node_id), session_id]).await?;
+    Ok(())
+}
+
+/// Makes Redis hold exactly the live sessions of this instance
+///
+/// Sessions registered by an earlier run, or that ended while Redis was
+/// unreachable, are removed.
+async fn republish(client: &RedisClient, keys: &Keys, node_id: &str, live: &HashMap<String, OwnedSession>) -> Result<(), String> {
+    if let Reply::Array(registered) = run(client, &["SMEMBERS", &keys.node_sessions(node_id)]).await? {
+        for session_id in registered.iter().filter_map(Reply::text).filter(|session_id| !live.contains_key(session_id)) {
+            unregister(client, keys, node_id, &session_id).await?;
+        }
+    }
+    for session in live.values() {
+        register(client, keys, session).await?;
+    }
+    Ok(())
+}
+
+/// Applies this instance's session changes to Redis and sends its heartbeats
+async fn sync(
+    client: Arc<RedisClient>,
+    keys: Keys,
+    mut node: NodeRecord,
+    ttl_seconds: u64,
+    mut changes: mpsc::UnboundedReceiver<Change>,
+) {
+    let mut live: HashMap<String, OwnedSession> = HashMap::new();
+    // Whether Redis holds every live session; until the first heartbeat, it may hold stale ones
+    let mut synced = false;
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(ttl_seconds / 3));
+    loop {
+        tokio::select! {
+            change = changes.recv() => match change {
+                Some(Change::Created(session)) => {
+                    if synced {
+                        if let Err(e) = register(&client, &keys, &session).await {
+                            warn!("Failed to register session {} in Redis: {}", session.session_id, e);
+                            synced = false;
+                        }
+                    }
+                    live.insert(session.session_id.clone(), session);
+                }
+                Some(Change::Ended(session_id)) => {
+                    live.remove(&session_id);
+                    if synced {
+                        if let Err(e) = unregister(&client, &keys, &node.node_id, &session_id).await {
+                            warn!("Failed to remove session {} from Redis: {}", session_id, e);
+                            synced = false;
+                        }
+                    }
+                }
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                node.heartbeat_at = Utc::now();
+                let value = serde_json::to_string(&node).unwrap_or_default();
+                let mut result = run(&client, &["SET", &keys.node(&node.node_id), &value, "EX", &ttl_seconds.to_string()]).await.map(|_| ());
+                if result.is_ok() && !synced {
+                    result = republish(&client, &keys, &node.node_id, &live).await;
+                }
+                match result {
+                    Ok(()) if !synced => {
+                        info!("Session registry in Redis is in sync: {} session(s) of node {}", live.len(), node.node_id);
+                        synced = true;
+                    }
+                    Ok(()) => {}
+                    Err(e) if synced => {
+                        warn!("Lost the session registry in Redis: {}", e);
+                        synced = false;
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
@@ -0,0 +1,147 @@
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tracing::{debug, Instrument};
+
+use crate::settings::InputCoalescingSettings;
+
+/// Counts of the input writes a shell received and the writes made to its channel
+#[derive(Debug, Default)]
+pub struct InputStats {
+    chunks: AtomicU64,
+    writes: AtomicU64,
+    bytes: AtomicU64,
+    largest_write: AtomicU64,
+    delayed_writes: AtomicU64,
+}
+
+/// A copy of [`InputStats`] for the API
+#[derive(Debug, Clone, Serialize)]
+pub struct InputStatsSnapshot {
+    /// Writes received from WebSockets and replays
+    pub chunks_received: u64,
+    /// Writes made to the device channel
+    pub writes: u64,
+    pub bytes: u64,
+    pub largest_write_bytes: u64,
+    /// Writes held back for more input to join them
+    pub delayed_writes: u64,
+    pub chunks_per_write: f64,
+}
+
+impl InputStats {
+    pub fn snapshot(&self) -> InputStatsSnapshot {
+        let chunks = self.chunks.load(Ordering::Relaxed);
+        let writes = self.writes.load(Ordering::Relaxed);
+        InputStatsSnapshot {
+            chunks_received: chunks,
+            writes,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            largest_write_bytes: self.largest_write.load(Ordering::Relaxed),
+            delayed_writes: self.delayed_writes.load(Ordering::Relaxed),
+            chunks_per_write: if writes == 0 { 0.0 } else { chunks as f64 / writes as f64 },
+        }
+    }
+
+    fn record(&self, chunks: u64, bytes: usize, delayed: bool) {
+        self.chunks.fetch_add(chunks, Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.largest_write.fetch_max(bytes as u64, Ordering::Relaxed);
+        if delayed {
+            self.delayed_writes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Merges bursts of input into larger writes on their way to the shell
+///
+/// Whatever is queued when a write arrives joins it, and while more keeps
+/// coming the batch waits up to `max_latency_ms` for it, until it reaches
+/// `max_bytes`. A write arriving alone is passed on at once.
+///
+/// # Arguments
+/// * `input_rx` - Input as received
+/// * `settings` - The coalescing settings
+/// * `stats` - Counts updated as batches are passed on
+/// * `session_id` - The session, for logging
+///
+/// # Returns
+/// * `mpsc::Receiver<Bytes>` - The merged input, for the shell's I/O pump
+pub fn start(
+    mut input_rx: mpsc::Receiver<Bytes>,
+    settings: &InputCoalescingSettings,
+    stats: Arc<InputStats>,
+    session_id: &str,
+) -> mpsc::Receiver<Bytes> {
+    let (batch_tx, batch_rx) = mpsc::channel::<Bytes>(32);
+    let max_bytes = settings.max_bytes.max(1);
+    let max_latency = Duration::from_millis(settings.max_latency_ms);
+    let session_id = session_id.to_string();
+    tokio::spawn(async move {
+        while let Some(first) = input_rx.recv().await {
+            let mut batch = BytesMut::from(&first[..]);
+            let mut chunks = 1;
+            let mut deadline = None;
+            while batch.len() < max_bytes {
+                let data = match input_rx.try_recv() {
+                    Ok(data) => data,
+                    Err(TryRecvError::Empty) if chunks > 1 && !max_latency.is_zero() => {
+                        let deadline = *deadline.get_or_insert_with(|| tokio::time::Instant::now() + max_latency);
+                        match tokio::time::timeout_at(deadline, input_rx.recv()).await {
+                            Ok(Some(data)) => data,
+                            // Closed, or the time is up
+                            Ok(None) | Err(_) => break,
+                        }
+                    }
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+                };
+                batch.extend_from_slice(&data);
+                chunks += 1;
+            }
+            stats.record(chunks, batch.len(), deadline.is_some());
+            if batch_tx.send(batch.freeze()).await.is_err() {
+                break;
+            }
+        }
+        let stats = stats.snapshot();
+        debug!("[Session {}] Input ended: {} writes coalesced into {}", session_id, stats.chunks_received, stats.writes);
+    }.in_current_span());
+    batch_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursts_are_merged() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            let settings = InputCoalescingSettings { enabled: true, max_bytes: 8, max_latency_ms: 50 };
+            let stats = Arc::new(InputStats::default());
+            let (input_tx, input_rx) = mpsc::channel(32);
+            let mut batches = start(input_rx, &settings, stats.clone(), "test");
+
+            // A lone keystroke is passed on as it is
+            input_tx.send(Bytes::from_static(b"a")).await.unwrap();
+            assert_eq!(batches.recv().await.unwrap(), Bytes::from_static(b"a"));
+
+            // A burst is written in batches of about max_bytes
+            for chunk in [b"conf", b"ig t", b"\rint", b"er 1"] {
+                input_tx.send(Bytes::from_static(chunk)).await.unwrap();
+            }
+            assert_eq!(batches.recv().await.unwrap(), Bytes::from_static(b"config t"));
+            assert_eq!(batches.recv().await.unwrap(), Bytes::from_static(b"\rinter 1"));
+
+            drop(input_tx);
+            assert!(batches.recv().await.is_none());
+            let stats = stats.snapshot();
+            assert_eq!((stats.chunks_received, stats.writes, stats.largest_write_bytes), (5, 3, 8));
+        });
+    }
+}
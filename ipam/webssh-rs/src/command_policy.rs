@@ -0,0 +1,582 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::audit::LineEditor;
+use crate::jwt::AuthenticatedUser;
+use crate::parsing::expand_abbreviations;
+use crate::policy::PolicyStore;
+use crate::settings::{CommandPolicySettings, CommandRule, GuardrailSettings, RuleAction, WebhookEventType};
+use crate::webhooks::Webhooks;
+use crate::AppState;
+
+/// Sent to the device in place of the Enter of a refused line: Ctrl-E and
+/// Ctrl-U, which move to the end of the line and erase it in bash, IOS,
+/// EOS and Junos alike
+const CLEAR_LINE: &[u8] = b"\x05\x15";
+
+/// A command line the policy refused
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Blocked {
+    pub command: String,
+    /// The deny rule that matched, or none when no allow rule did
+    pub rule: Option<String>,
+    pub reason: String,
+    /// The line is off the guardrail's allow-list, so it may be sent for approval
+    pub guardrail: bool,
+}
+
+/// Checks every pattern of the rules, so mistakes are reported before they are enforced
+pub fn validate(settings: &CommandPolicySettings) -> Result<(), String> {
+    for rule in &settings.rules {
+        if rule.name.trim().is_empty() {
+            return Err("Every command policy rule needs a name".to_string());
+        }
+        if rule.commands.is_empty() {
+            return Err(format!("Command policy rule '{}' lists no commands", rule.name));
+        }
+        for pattern in &rule.commands {
+            command_regex(pattern)
+                .map_err(|e| format!("Invalid command '{}' in rule '{}': {}", pattern, rule.name, e))?;
+        }
+    }
+    for pattern in &settings.guardrail.commands {
+        command_regex(pattern).map_err(|e| format!("Invalid command '{}' in the guardrail: {}", pattern, e))?;
+    }
+    Ok(())
+}
+
+/// Matches a pattern against the start of a command, up to a word boundary
+fn command_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(&format!(r"^(?:{})(?:\s|$)", expand_abbreviations(pattern.trim())))
+        .case_insensitive(true)
+        .build()
+}
+
+/// Whether any of the patterns matches; `owner` names where they come from in warnings
+fn patterns_match(patterns: &[String], owner: &str, command: &str) -> bool {
+    patterns.iter().any(|pattern| match command_regex(pattern) {
+        Ok(regex) => regex.is_match(command),
+        Err(e) => {
+            warn!("Ignoring invalid command '{}' in {}: {}", pattern, owner, e);
+            false
+        }
+    })
+}
+
+fn rule_matches(rule: &CommandRule, command: &str) -> bool {
+    patterns_match(&rule.commands, &format!("rule '{}'", rule.name), command)
+}
+
+/// Whether a user with the given roles is held to the guardrail's allow-list
+pub fn guarded(guardrail: &GuardrailSettings, roles: &[String]) -> bool {
+    guardrail.roles.iter().any(|wanted| roles.iter().any(|role| role.eq_ignore_ascii_case(wanted)))
+}
+
+/// Whether a rule covers a device type and a user with the given roles
+fn applies(rule: &CommandRule, device_type: Option<&str>, roles: &[String]) -> bool {
+    let has_role = |wanted: &String| roles.iter().any(|role| role.eq_ignore_ascii_case(wanted));
+    let type_matches = rule.device_types.is_empty()
+        || device_type.is_some_and(|device_type| rule.device_types.iter().any(|wanted| wanted.eq_ignore_ascii_case(device_type)));
+    type_matches
+        && (rule.roles.is_empty() || rule.roles.iter().any(has_role))
+        && !rule.except_roles.iter().any(has_role)
+}
+
+/// Decides whether a command line may be sent to a device
+///
+/// A matching deny rule refuses the line. Otherwise, if any allow rules apply,
+/// the line must match one of them. Users held to the guardrail may send only
+/// lines it or an allow rule lists, and never lines they cannot be checked on.
+///
+/// # Arguments
+/// * `device_type`, `roles` - The device, and the roles of the user typing
+/// * `uncertain` - The line was edited in ways only the device can follow
+pub fn check(
+    settings: &CommandPolicySettings,
+    device_type: Option<&str>,
+    roles: &[String],
+    command: &str,
+    uncertain: bool,
+) -> Result<(), Blocked> {
+    let command = command.split_whitespace().collect::<Vec<_>>().join(" ");
+    let rules: Vec<&CommandRule> = settings.rules.iter().filter(|rule| applies(rule, device_type, roles)).collect();
+    let guarded = guarded(&settings.guardrail, roles);
+    if rules.is_empty() && !guarded {
+        return Ok(());
+    }
+    let blocked = |rule: Option<&str>, reason: String, guardrail: bool| Err(Blocked {
+        command: command.clone(),
+        rule: rule.map(str::to_string),
+        reason,
+        guardrail,
+    });
+    if uncertain && (settings.block_uncertain || guarded) {
+        return blocked(None, "Command blocked: it was edited with history recall or completion, so it cannot be checked".to_string(), false);
+    }
+    if let Some(rule) = rules.iter().find(|rule| rule.action == RuleAction::Deny && rule_matches(rule, &command)) {
+        return blocked(Some(&rule.name), format!("Command blocked by policy '{}': {}", rule.name, command), false);
+    }
+    let mut allow_rules = rules.iter().filter(|rule| rule.action == RuleAction::Allow).peekable();
+    if guarded {
+        if !patterns_match(&settings.guardrail.commands, "the guardrail", &command) && !allow_rules.any(|rule| rule_matches(rule, &command)) {
+            return blocked(None, format!("Command not on the allow-list for your role: {}", command), true);
+        }
+        return Ok(());
+    }
+    if allow_rules.peek().is_some() && !allow_rules.any(|rule| rule_matches(rule, &command)) {
+        return blocked(None, format!("Command not allowed by policy: {}", command), false);
+    }
+    Ok(())
+}
+
+/// Where a command sent for approval stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalStatus {
+    Pending,
+    /// May be entered once, until the request expires
+    Approved,
+    /// Refused; the request is dropped
+    Denied,
+}
+
+/// A command refused by the guardrail and sent for approval
+#[derive(Debug, Clone, Serialize)]
+pub struct ApprovalRequest {
+    pub id: String,
+    pub command: String,
+    pub requested_by: String,
+    pub status: ApprovalStatus,
+    pub requested_at: DateTime<Utc>,
+    /// When a pending request lapses, or an approved command can no longer be entered
+    pub expires_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decided_by: Option<String>,
+}
+
+/// The commands of a session sent for approval, forgotten with it
+#[derive(Debug, Default)]
+pub struct CommandApprovals {
+    requests: Vec<ApprovalRequest>,
+}
+
+pub type SharedApprovals = Arc<Mutex<CommandApprovals>>;
+
+impl CommandApprovals {
+    fn prune(&mut self) {
+        let now = Utc::now();
+        self.requests.retain(|request| request.expires_at > now);
+    }
+
+    /// Sends a command for approval, unless it is already waiting
+    ///
+    /// # Returns
+    /// * `(ApprovalRequest, bool)` - The request, and whether it is new
+    pub fn request(&mut self, command: &str, requested_by: &str, ttl: chrono::Duration) -> (ApprovalRequest, bool) {
+        self.prune();
+        if let Some(request) = self.requests.iter().find(|request| request.status == ApprovalStatus::Pending && request.command == command) {
+            return (request.clone(), false);
+        }
+        let now = Utc::now();
+        let request = ApprovalRequest {
+            id: uuid::Uuid::new_v4().to_string(),
+            command: command.to_string(),
+            requested_by: requested_by.to_string(),
+            status: ApprovalStatus::Pending,
+            requested_at: now,
+            expires_at: now + ttl,
+            decided_by: None,
+        };
+        self.requests.push(request.clone());
+        (request, true)
+    }
+
+    /// Uses up the approval of a command, if it has one
+    pub fn take_approved(&mut self, command: &str) -> Option<ApprovalRequest> {
+        self.prune();
+        let index = self.requests.iter().position(|request| request.status == ApprovalStatus::Approved && request.command == command)?;
+        Some(self.requests.remove(index))
+    }
+
+    /// Approves or denies a pending request; a denied one is dropped
+    pub fn decide(&mut self, id: &str, approve: bool, decided_by: Option<&str>, ttl: chrono::Duration) -> Option<ApprovalRequest> {
+        self.prune();
+        let index = self.requests.iter().position(|request| request.id == id && request.status == ApprovalStatus::Pending)?;
+        if !approve {
+            let mut request = self.requests.remove(index);
+            request.status = ApprovalStatus::Denied;
+            request.decided_by = decided_by.map(str::to_string);
+            return Some(request);
+        }
+        let request = &mut self.requests[index];
+        request.status = ApprovalStatus::Approved;
+        request.expires_at = Utc::now() + ttl;
+        request.decided_by = decided_by.map(str::to_string);
+        Some(request.clone())
+    }
+
+    /// Gets the requests waiting for a decision, oldest first
+    pub fn pending(&mut self) -> Vec<ApprovalRequest> {
+        self.prune();
+        self.requests.iter().filter(|request| request.status == ApprovalStatus::Pending).cloned().collect()
+    }
+}
+
+/// The input to send to the device, the lines held back from it, and what
+/// the client is told of them
+#[derive(Debug, Default)]
+pub struct Filtered {
+    pub forward: Vec<u8>,
+    pub blocked: Vec<Blocked>,
+    /// Lines for the terminal after the reasons of the refused lines
+    pub notices: Vec<String>,
+}
+
+/// Where a filter sends the commands the guardrail refuses
+struct Approvals {
+    requests: SharedApprovals,
+    requested_by: String,
+    notifications: broadcast::Sender<serde_json::Value>,
+}
+
+/// Where a filter reports the lines it refuses
+struct Reporting {
+    webhooks: Arc<Webhooks>,
+    session_id: String,
+    portal_user_id: String,
+}
+
+/// Follows the input of one WebSocket, holding back the Enter of refused lines
+///
+/// The policy in force is read for every line, so changes apply to open
+/// sessions at once.
+pub struct CommandFilter {
+    policy: Arc<PolicyStore>,
+    device_type: Option<String>,
+    roles: Vec<String>,
+    editor: LineEditor,
+    approvals: Option<Approvals>,
+    reporting: Option<Reporting>,
+}
+
+impl CommandFilter {
+    pub fn new(policy: Arc<PolicyStore>, device_type: Option<String>, roles: Vec<String>) -> Self {
+        Self { policy, device_type, roles, editor: LineEditor::default(), approvals: None, reporting: None }
+    }
+
+    /// Lets commands the guardrail refuses be sent for approval, when the policy allows
+    pub fn with_approvals(
+        mut self,
+        requests: SharedApprovals,
+        requested_by: String,
+        notifications: broadcast::Sender<serde_json::Value>,
+    ) -> Self {
+        self.approvals = Some(Approvals { requests, requested_by, notifications });
+        self
+    }
+
+    /// Posts the lines this filter refuses to the webhooks
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>, session_id: String, portal_user_id: String) -> Self {
+        self.reporting = Some(Reporting { webhooks, session_id, portal_user_id });
+        self
+    }
+
+    fn report(&self, blocked: &Blocked) {
+        if let Some(reporting) = &self.reporting {
+            reporting.webhooks.send(WebhookEventType::CommandBlocked, json!({
+                "session_id": reporting.session_id,
+                "portal_user_id": reporting.portal_user_id,
+                "device_type": self.device_type,
+                "command": blocked.command,
+                "rule": blocked.rule,
+                "reason": blocked.reason,
+                "guardrail": blocked.guardrail,
+            }));
+        }
+    }
+
+    /// Lets an approved command through, or sends a refused one for approval
+    ///
+    /// # Returns
+    /// * `bool` - true if the command was approved and may be sent
+    fn approval(&self, guardrail: &GuardrailSettings, blocked: &Blocked, notices: &mut Vec<String>) -> bool {
+        let Some(approvals) = &self.approvals else {
+            return false;
+        };
+        let mut requests = approvals.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(request) = requests.take_approved(&blocked.command) {
+            info!("Approved command sent for {}: {}", approvals.requested_by, request.command);
+            return true;
+        }
+        if !guardrail.approval {
+            return false;
+        }
+        let ttl = chrono::Duration::seconds(guardrail.approval_ttl_seconds.min(i64::MAX as u64) as i64);
+        let (request, new) = requests.request(&blocked.command, &approvals.requested_by, ttl);
+        if new {
+            info!("Command of {} sent for approval as {}: {}", approvals.requested_by, request.id, request.command);
+            let _ = approvals.notifications.send(json!({ "type": "command_approval", "request": request }));
+            notices.push(format!("Sent for approval as {}. Enter the command again once it is approved.", request.id));
+        } else {
+            notices.push(format!("Already waiting for approval as {}.", request.id));
+        }
+        false
+    }
+
+    pub fn input(&mut self, data: &[u8]) -> Filtered {
+        let settings = self.policy.settings();
+        let mut filtered = Filtered::default();
+        // Keystrokes between line ends go through as they are, split only where a line ends
+        for chunk in data.split_inclusive(|byte| matches!(byte, b'\r' | b'\n')) {
+            let (text, end) = match chunk.split_last() {
+                Some((end, text)) if matches!(end, b'\r' | b'\n') => (text, Some(*end)),
+                _ => (chunk, None),
+            };
+            self.editor.feed(&String::from_utf8_lossy(text));
+            filtered.forward.extend_from_slice(text);
+            let Some(end) = end else {
+                continue;
+            };
+            let completed = self.editor.feed(&char::from(end).to_string());
+            let policy = &settings.command_policy;
+            let refused = match policy.enabled {
+                true => completed.into_iter().find_map(|(command, uncertain)| {
+                    check(policy, self.device_type.as_deref(), &self.roles, &command, uncertain).err()
+                }),
+                false => None,
+            };
+            let refused = refused.filter(|blocked| {
+                let mut notices = Vec::new();
+                let approved = blocked.guardrail && self.approval(&policy.guardrail, blocked, &mut notices);
+                if !approved && blocked.guardrail && !policy.guardrail.message.is_empty() {
+                    filtered.notices.push(policy.guardrail.message.clone());
+                }
+                filtered.notices.extend(notices);
+                !approved
+            });
+            match refused {
+                Some(blocked) => {
+                    self.report(&blocked);
+                    filtered.forward.extend_from_slice(CLEAR_LINE);
+                    filtered.blocked.push(blocked);
+                }
+                None => filtered.forward.push(end),
+            }
+        }
+        filtered
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Returns the command policy in force
+pub async fn get_handler(State(state): State<AppState>) -> Response {
+    Json(state.policy.settings().command_policy.clone()).into_response()
+}
+
+/// Replaces the command policy in force
+pub async fn update_handler(State(state): State<AppState>, Json(settings): Json<CommandPolicySettings>) -> Response {
+    if let Err(e) = validate(&settings) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_command_policy", e);
+    }
+    info!("Command policy updated: {} with {} rule(s)",
+          if settings.enabled { "enabled" } else { "disabled" }, settings.rules.len());
+    state.policy.set_command_policy(settings.clone());
+    Json(settings).into_response()
+}
+
+/// Body of a request to try a command against the policy
+#[derive(Debug, Deserialize)]
+pub struct CheckRequest {
+    pub command: String,
+    pub device_type: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// Reports whether the policy in force would let a command through
+pub async fn check_handler(State(state): State<AppState>, Json(request): Json<CheckRequest>) -> Response {
+    let settings = state.policy.settings();
+    if !settings.command_policy.enabled {
+        return Json(json!({ "allowed": true, "enabled": false })).into_response();
+    }
+    match check(&settings.command_policy, request.device_type.as_deref(), &request.roles, &request.command, false) {
+        Ok(()) => Json(json!({ "allowed": true, "enabled": true })).into_response(),
+        Err(blocked) => Json(json!({
+            "allowed": false,
+            "enabled": true,
+            "rule": blocked.rule,
+            "reason": blocked.reason,
+            "guardrail": blocked.guardrail,
+        })).into_response(),
+    }
+}
+
+/// A command waiting for approval, with the session it was typed in
+#[derive(Debug, Serialize)]
+struct PendingApproval {
+    session_id: String,
+    device_id: String,
+    #[serde(flatten)]
+    request: ApprovalRequest,
+}
+
+/// Lists the commands waiting for approval, across sessions
+pub async fn approvals_handler(State(state): State<AppState>) -> Response {
+    let registry = state.session_registry.lock().await;
+    let mut pending: Vec<PendingApproval> = registry.sessions.iter()
+        .flat_map(|(session_id, session_info)| {
+            let requests = session_info.command_approvals.lock().unwrap_or_else(PoisonError::into_inner).pending();
+            requests.into_iter().map(|request| PendingApproval {
+                session_id: session_id.clone(),
+                device_id: session_info.device_id.clone(),
+                request,
+            })
+        })
+        .collect();
+    pending.sort_by_key(|approval| approval.request.requested_at);
+    Json(json!({ "requests": pending })).into_response()
+}
+
+/// Approves or denies a command, telling the session's clients
+async fn decide(state: AppState, session_id: String, approval_id: String, user: Option<AuthenticatedUser>, approve: bool) -> Response {
+    let settings = state.policy.settings();
+    let decided_by = user.as_ref().map(|user| user.subject.as_str());
+    let registry = state.session_registry.lock().await;
+    let session_id = session_id.trim();
+    let Some(session_info) = registry.sessions.get(session_id) else {
+        return error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id));
+    };
+    let mut approvals = session_info.command_approvals.lock().unwrap_or_else(PoisonError::into_inner);
+    let not_found = || error_response(StatusCode::NOT_FOUND, "approval_not_found",
+                                      format!("No command of session '{}' is waiting for approval as '{}'", session_id, approval_id));
+    let Some(request) = approvals.pending().into_iter().find(|request| request.id == approval_id) else {
+        return not_found();
+    };
+    // Supervision means someone else looks at the command
+    if user.as_ref().is_some_and(|user| user.owns(&request.requested_by)) {
+        return error_response(StatusCode::FORBIDDEN, "self_approval", "Commands cannot be approved or denied by whoever typed them".to_string());
+    }
+    let ttl = chrono::Duration::seconds(settings.command_policy.guardrail.approval_ttl_seconds.min(i64::MAX as u64) as i64);
+    let Some(request) = approvals.decide(&approval_id, approve, decided_by, ttl) else {
+        return not_found();
+    };
+    info!("Command of {} in session {} {} by {}: {}", request.requested_by, session_id,
+          if approve { "approved" } else { "denied" }, decided_by.unwrap_or("an API key"), request.command);
+    let _ = session_info.notifications.send(json!({ "type": "command_approval", "request": request }));
+    Json(request).into_response()
+}
+
+/// Approves a command the guardrail refused, so it may be entered once
+pub async fn approve_handler(
+    State(state): State<AppState>,
+    Path((session_id, approval_id)): Path<(String, String)>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Response {
+    decide(state, session_id, approval_id, user.map(|Extension(user)| user), true).await
+}
+
+/// Denies a command sent for approval
+pub async fn deny_handler(
+    State(state): State<AppState>,
+    Path((session_id, approval_id)): Path<(String, String)>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Response {
+    decide(state, session_id, approval_id, user.map(|Extension(user)| user), false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_by_device_type_and_role() {
+        let mut settings = CommandPolicySettings { enabled: true, ..CommandPolicySettings::default() };
+        settings.rules.push(CommandRule {
+            name: "operators".to_string(),
+            action: RuleAction::Allow,
+            commands: vec!["sh[[ow]]".to_string(), "ping".to_string()],
+            roles: vec!["operator".to_string()],
+            ..CommandRule::default()
+        });
+        assert!(validate(&settings).is_ok());
+        let ios = Some("cisco_ios");
+        let none: &[String] = &[];
+        let operator = &["Operator".to_string()];
+        let admin = &["network-admin".to_string()];
+
+        assert_eq!(check(&settings, ios, none, "  relo  in 5", false).unwrap_err().rule.as_deref(), Some("network-destructive"));
+        assert!(check(&settings, ios, none, "reloader", false).is_ok());
+        assert!(check(&settings, ios, none, "write erase", false).is_err());
+        assert!(check(&settings, ios, admin, "reload", false).is_ok());
+        assert!(check(&settings, Some("linux"), none, "sudo rm -rf /", false).is_err());
+        assert!(check(&settings, Some("linux"), none, "rm -rf /tmp/build", false).is_ok());
+        assert!(check(&settings, None, none, "reload", false).is_ok());
+
+        assert!(check(&settings, ios, operator, "sh ip int brief", false).is_ok());
+        assert_eq!(check(&settings, ios, operator, "conf t", false).unwrap_err().rule, None);
+        assert!(check(&settings, ios, operator, "conf t", true).is_err());
+        settings.block_uncertain = true;
+        assert!(check(&settings, ios, none, "show version", true).is_err());
+
+        settings.rules[0].commands.push("(unclosed".to_string());
+        assert!(validate(&settings).is_err());
+    }
+
+    #[test]
+    fn test_guardrail_and_approvals() {
+        let mut settings = CommandPolicySettings { enabled: true, ..CommandPolicySettings::default() };
+        settings.guardrail.roles = vec!["new-hire".to_string()];
+        let ios = Some("cisco_ios");
+        let junior = &["New-Hire".to_string()];
+
+        assert!(check(&settings, ios, junior, "sh run", false).is_ok());
+        let blocked = check(&settings, ios, junior, "conf t", false).unwrap_err();
+        assert!(blocked.guardrail);
+        assert!(!check(&settings, ios, junior, "reload", false).unwrap_err().guardrail);
+        assert!(!check(&settings, ios, junior, "show version", true).unwrap_err().guardrail);
+        assert!(check(&settings, ios, &[], "conf t", false).is_ok());
+        settings.rules.push(CommandRule {
+            name: "juniors-on-linux".to_string(),
+            action: RuleAction::Allow,
+            commands: vec!["ls".to_string()],
+            device_types: vec!["linux".to_string()],
+            roles: vec!["new-hire".to_string()],
+            ..CommandRule::default()
+        });
+        assert!(check(&settings, Some("linux"), junior, "ls -l", false).is_ok());
+        assert!(check(&settings, ios, junior, "ls -l", false).is_err());
+
+        let ttl = chrono::Duration::minutes(15);
+        let mut approvals = CommandApprovals::default();
+        let (request, new) = approvals.request(&blocked.command, "alice", ttl);
+        assert!(new);
+        assert!(!approvals.request(&blocked.command, "alice", ttl).1);
+        assert!(approvals.take_approved(&blocked.command).is_none());
+        assert_eq!(approvals.decide(&request.id, true, Some("bob"), ttl).unwrap().status, ApprovalStatus::Approved);
+        assert!(approvals.decide(&request.id, false, Some("bob"), ttl).is_none());
+        assert!(approvals.pending().is_empty());
+        assert!(approvals.take_approved("conf t").is_some());
+        assert!(approvals.take_approved("conf t").is_none());
+
+        let (request, _) = approvals.request("reload", "alice", ttl);
+        assert_eq!(approvals.decide(&request.id, false, None, ttl).unwrap().status, ApprovalStatus::Denied);
+        assert!(approvals.take_approved("reload").is_none());
+    }
+}
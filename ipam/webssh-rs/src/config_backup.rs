@@ -0,0 +1,611 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::exec;
+use crate::inventory::{inventory_error, Device};
+use crate::settings::ConfigBackupSettings;
+use crate::tasks::RestartPolicy;
+use crate::{connection_target, resolve_request, AppState, SSHCredentials};
+
+/// Version IDs are the times the configurations were taken, and their file names
+const VERSION_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+const EXTENSION: &str = ".cfg";
+const GZIP_EXTENSION: &str = ".cfg.gz";
+
+/// Who the backup connections are made for, in logs and shared connection accounting
+const OWNER: &str = "config-backup";
+
+/// Unchanged lines shown around each change in a diff
+const DIFF_CONTEXT: usize = 3;
+
+/// Most line comparisons a diff makes; past it, a changed region is shown removed and added whole
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// A stored configuration of a device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigVersion {
+    pub id: String,
+    pub taken_at: DateTime<Utc>,
+    /// Size of the stored file, compressed or not
+    pub stored_bytes: u64,
+    pub compressed: bool,
+}
+
+/// Configurations kept as files, `<directory>/<device id>/<version id>.cfg[.gz]`
+pub struct ConfigStore {
+    directory: PathBuf,
+    gzip: bool,
+    keep_versions: usize,
+}
+
+impl ConfigStore {
+    pub fn new(settings: &ConfigBackupSettings) -> Self {
+        Self {
+            directory: PathBuf::from(&settings.directory),
+            gzip: settings.gzip,
+            keep_versions: settings.keep_versions,
+        }
+    }
+
+    /// Lists a device's stored configurations, newest first
+    pub fn list(&self, device_id: &str) -> io::Result<Vec<ConfigVersion>> {
+        let entries = match std::fs::read_dir(self.directory.join(device_id)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut versions = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let (id, compressed) = match name.strip_suffix(GZIP_EXTENSION) {
+                Some(id) => (id, true),
+                None => match name.strip_suffix(EXTENSION) {
+                    Some(id) => (id, false),
+                    None => continue,
+                },
+            };
+            let Some(taken_at) = parse_version(id) else {
+                continue;
+            };
+            versions.push(ConfigVersion {
+                id: id.to_string(),
+                taken_at,
+                stored_bytes: entry.metadata()?.len(),
+                compressed,
+            });
+        }
+        versions.sort_by_key(|version| std::cmp::Reverse(version.taken_at));
+        Ok(versions)
+    }
+
+    /// Reads a stored configuration
+    pub fn read(&self, device_id: &str, version_id: &str) -> io::Result<Option<String>> {
+        // Only a well-formed ID names a file, so none can reach outside the device's directory
+        if parse_version(version_id).is_none() {
+            return Ok(None);
+        }
+        let directory = self.directory.join(device_id);
+        match std::fs::read(directory.join(format!("{}{}", version_id, GZIP_EXTENSION))) {
+            Ok(compressed) => {
+                let mut config = String::new();
+                GzDecoder::new(compressed.as_slice()).read_to_string(&mut config)?;
+                return Ok(Some(config));
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        match std::fs::read_to_string(directory.join(format!("{}{}", version_id, EXTENSION))) {
+            Ok(config) => Ok(Some(config)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores a device's configuration, unless it is the same as the latest one
+    ///
+    /// Versions past `keep_versions` are removed, oldest first.
+    ///
+    /// # Returns
+    /// * `Option<ConfigVersion>` - The new version, or None if the configuration has not changed
+    pub fn save(&self, device_id: &str, config: &str, taken_at: DateTime<Utc>) -> io::Result<Option<ConfigVersion>> {
+        let versions = self.list(device_id)?;
+        if let Some(latest) = versions.first() {
+            if self.read(device_id, &latest.id)?.as_deref() == Some(config) {
+                return Ok(None);
+            }
+        }
+
+        let directory = self.directory.join(device_id);
+        std::fs::create_dir_all(&directory)?;
+        let id = taken_at.format(VERSION_FORMAT).to_string();
+        let contents = if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(config.as_bytes())?;
+            encoder.finish()?
+        } else {
+            config.as_bytes().to_vec()
+        };
+        // Written aside and renamed, so a half-written file is never listed
+        let path = directory.join(format!("{}{}", id, if self.gzip { GZIP_EXTENSION } else { EXTENSION }));
+        let partial = directory.join(format!("{}.partial", id));
+        std::fs::write(&partial, &contents)?;
+        std::fs::rename(&partial, &path)?;
+
+        if self.keep_versions > 0 {
+            for old in versions.iter().skip(self.keep_versions - 1) {
+                let extension = if old.compressed { GZIP_EXTENSION } else { EXTENSION };
+                if let Err(e) = std::fs::remove_file(directory.join(format!("{}{}", old.id, extension))) {
+                    warn!("Failed to remove configuration {} of device {}: {}", old.id, device_id, e);
+                }
+            }
+        }
+        Ok(Some(ConfigVersion {
+            id,
+            taken_at,
+            stored_bytes: contents.len() as u64,
+            compressed: self.gzip,
+        }))
+    }
+}
+
+fn parse_version(id: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(id, VERSION_FORMAT).ok().map(|time| time.and_utc())
+}
+
+/// How a device's latest backup went
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupAttempt {
+    pub at: DateTime<Utc>,
+    pub success: bool,
+    /// The version stored, if the configuration had changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Scheduled backups of inventory devices' configurations
+///
+/// Every `config_backup.interval_seconds`, each inventory device with a
+/// command for its type is connected to with its stored credentials, and the
+/// configuration the command prints is stored if it differs from the last
+/// one. Devices are backed up one at a time.
+pub struct ConfigBackups {
+    pub store: ConfigStore,
+    // The latest attempt by device ID; kept in memory only
+    attempts: Mutex<HashMap<String, BackupAttempt>>,
+}
+
+impl ConfigBackups {
+    pub fn new(settings: &ConfigBackupSettings) -> Result<Self, String> {
+        if settings.commands.values().any(|command| command.trim().is_empty()) {
+            return Err("commands must not be empty".to_string());
+        }
+        std::fs::create_dir_all(&settings.directory)
+            .map_err(|e| format!("cannot create directory {}: {}", settings.directory, e))?;
+        Ok(Self {
+            store: ConfigStore::new(settings),
+            attempts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn last_attempt(&self, device_id: &str) -> Option<BackupAttempt> {
+        self.attempts.lock().unwrap_or_else(PoisonError::into_inner).get(device_id).cloned()
+    }
+
+    fn record(&self, device_id: &str, outcome: &Result<Option<ConfigVersion>, String>) {
+        let attempt = BackupAttempt {
+            at: Utc::now(),
+            success: outcome.is_ok(),
+            version_id: outcome.as_ref().ok().and_then(|version| version.as_ref().map(|version| version.id.clone())),
+            error: outcome.as_ref().err().cloned(),
+        };
+        self.attempts.lock().unwrap_or_else(PoisonError::into_inner).insert(device_id.to_string(), attempt);
+    }
+}
+
+/// The command printing a device type's configuration, if it has one
+fn command_for<'a>(settings: &'a ConfigBackupSettings, device_type: Option<&str>) -> Option<&'a str> {
+    settings.commands.get(&device_type?.to_lowercase()).map(String::as_str)
+}
+
+/// Starts backing up every device now and at each interval after
+pub fn start(state: AppState, backups: Arc<ConfigBackups>) {
+    let interval = Duration::from_secs(state.settings.config_backup.interval_seconds.max(60));
+    let tasks = state.tasks.clone();
+    tasks.spawn("config_backup", RestartPolicy::OnPanic, move |task| {
+        let (state, backups) = (state.clone(), backups.clone());
+        async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                backup_all(&state, &backups).await;
+                task.ran();
+            }
+        }
+    });
+}
+
+async fn backup_all(state: &AppState, backups: &ConfigBackups) {
+    let Some(inventory) = &state.inventory else {
+        return;
+    };
+    let settings = &state.settings.config_backup;
+    let devices = match inventory.list(settings.tag.as_deref()) {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Configuration backups skipped: {}", e);
+            return;
+        }
+    };
+    let (mut changed, mut unchanged, mut failed) = (0, 0, 0);
+    for device in &devices {
+        let Some(command) = command_for(settings, device.device_type.as_deref()) else {
+            debug!("No backup command for device {} of type {:?}", device.name, device.device_type);
+            continue;
+        };
+        let outcome = backup(state, backups, device, command).await;
+        match &outcome {
+            Ok(Some(_)) => changed += 1,
+            Ok(None) => unchanged += 1,
+            Err(e) => {
+                warn!("Backup of device {} failed: {}", device.name, e);
+                failed += 1;
+            }
+        }
+    }
+    info!("Configuration backups: {} changed, {} unchanged, {} failed", changed, unchanged, failed);
+}
+
+/// Takes a device's configuration, storing it if it has changed
+async fn backup(state: &AppState, backups: &ConfigBackups, device: &Device, command: &str) -> Result<Option<ConfigVersion>, String> {
+    let outcome = take(state, backups, device, command).await;
+    backups.record(&device.id, &outcome);
+    if let Ok(Some(version)) = &outcome {
+        info!("Configuration of device {} changed; stored as {}", device.name, version.id);
+    }
+    outcome
+}
+
+async fn take(state: &AppState, backups: &ConfigBackups, device: &Device, command: &str) -> Result<Option<ConfigVersion>, String> {
+    let settings = &state.settings.config_backup;
+    let credentials = SSHCredentials { device_ref: Some(device.id.clone()), ..Default::default() };
+    let credentials = resolve_request(state, credentials).await.map_err(|e| e.to_string())?;
+    let target = connection_target(state, &credentials, &state.policy.settings(), OWNER);
+
+    let timeout = Duration::from_secs(settings.timeout_seconds.max(1));
+    let max_output = settings.max_output_bytes;
+    let commands = vec![command.to_string()];
+    let (results, outcome, _) = tokio::task::spawn_blocking(move || exec::run(&target, None, OWNER, &commands, timeout, max_output))
+        .await
+        .map_err(|e| format!("Backup task failed: {}", e))?;
+    outcome.map_err(|e| e.to_string())?;
+
+    let result = results.into_iter().next().ok_or("The command did not run")?;
+    if result.timed_out {
+        return Err(format!("'{}' did not finish within {} seconds", command, timeout.as_secs()));
+    }
+    if result.truncated {
+        return Err(format!("The configuration is longer than {} bytes", max_output));
+    }
+    if let Some(status) = result.exit_status.filter(|status| *status != 0) {
+        return Err(format!("'{}' exited with status {}: {}", command, status, result.stderr.trim()));
+    }
+    if result.stdout.trim().is_empty() {
+        return Err(format!("'{}' printed nothing", command));
+    }
+    let config = result.stdout.replace("\r\n", "\n");
+    backups.store.save(&device.id, &config, Utc::now()).map_err(|e| format!("Failed to store the configuration: {}", e))
+}
+
+/// Whether a line is the same in both texts, or only in the old or new one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Matches up the lines of two texts, by their longest common subsequence
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Edit, &'a str)> {
+    // Configurations change in few places, so only the lines between the
+    // unchanged start and end are compared
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut edits: Vec<(Edit, &str)> = old[..prefix].iter().map(|line| (Edit::Same, *line)).collect();
+    let (mut i, mut j) = (0, 0);
+    if a.len().saturating_mul(b.len()) <= MAX_DIFF_CELLS {
+        // lengths[i * width + j]: the longest common subsequence of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut lengths = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lengths[i * width + j] = if a[i] == b[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+        while i < a.len() && j < b.len() {
+            if a[i] == b[j] {
+                edits.push((Edit::Same, a[i]));
+                i += 1;
+                j += 1;
+            } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+                edits.push((Edit::Removed, a[i]));
+                i += 1;
+            } else {
+                edits.push((Edit::Added, b[j]));
+                j += 1;
+            }
+        }
+    }
+    edits.extend(a[i..].iter().map(|line| (Edit::Removed, *line)));
+    edits.extend(b[j..].iter().map(|line| (Edit::Added, *line)));
+    edits.extend(old[old.len() - suffix..].iter().map(|line| (Edit::Same, *line)));
+    edits
+}
+
+/// Compares two texts line by line, in unified diff format
+///
+/// # Returns
+/// * `String` - The diff, empty if the texts have the same lines
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let edits = diff_lines(&old_lines, &new_lines);
+    let changes: Vec<usize> = edits.iter().enumerate()
+        .filter(|(_, (edit, _))| *edit != Edit::Same)
+        .map(|(index, _)| index)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Lines of each text before each edit, and after the last
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for (edit, _) in &edits {
+        positions.push((old_line, new_line));
+        match edit {
+            Edit::Same => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Edit::Removed => old_line += 1,
+            Edit::Added => new_line += 1,
+        }
+    }
+    positions.push((old_line, new_line));
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_label, new_label);
+    let mut next = 0;
+    while next < changes.len() {
+        let start = changes[next].saturating_sub(DIFF_CONTEXT);
+        let mut last = changes[next];
+        next += 1;
+        // Changes whose context would meet go in the same hunk
+        while next < changes.len() && changes[next] - last <= 2 * DIFF_CONTEXT + 1 {
+            last = changes[next];
+            next += 1;
+        }
+        let end = (last + DIFF_CONTEXT + 1).min(edits.len());
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        diff.push_str(&format!("@@ -{} +{} @@\n", hunk_range(old_start, old_end - old_start), hunk_range(new_start, new_end - new_start)));
+        for (edit, line) in &edits[start..end] {
+            diff.push(match edit {
+                Edit::Same => ' ',
+                Edit::Removed => '-',
+                Edit::Added => '+',
+            });
+            diff.push_str(line);
+            diff.push('\n');
+        }
+    }
+    diff
+}
+
+/// A hunk's lines in one text: the first and how many, or the line before an empty hunk
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn storage_error(device: &Device, e: io::Error) -> Response {
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "config_storage_failed",
+                   format!("Failed to read the configurations of device '{}': {}", device.name, e))
+}
+
+fn version_not_found(version_id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "version_not_found", format!("Configuration version '{}' not found", version_id))
+}
+
+/// The backups and the inventory device a request names
+async fn device(state: &AppState, device_ref: &str) -> Result<(Arc<ConfigBackups>, Device), Response> {
+    let Some(backups) = state.config_backups.clone() else {
+        return Err(error_response(StatusCode::CONFLICT, "config_backup_disabled",
+                                  "Configuration backups are not enabled on this instance".to_string()));
+    };
+    let Some(inventory) = &state.inventory else {
+        return Err(error_response(StatusCode::CONFLICT, "inventory_disabled",
+                                  "The device inventory is not enabled on this instance".to_string()));
+    };
+    let device = inventory.get(device_ref.trim()).map_err(inventory_error)?;
+    Ok((backups, device))
+}
+
+/// Lists a device's stored configurations, newest first, with how its latest backup went
+pub async fn list_handler(State(state): State<AppState>, Path(device_ref): Path<String>) -> Response {
+    let (backups, device) = match device(&state, &device_ref).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    match backups.store.list(&device.id) {
+        Ok(versions) => Json(json!({
+            "device_id": device.id,
+            "device_name": device.name,
+            "versions": versions,
+            "last_attempt": backups.last_attempt(&device.id),
+        })).into_response(),
+        Err(e) => storage_error(&device, e),
+    }
+}
+
+/// Backs up a device now, e.g. before or after a change
+pub async fn backup_handler(State(state): State<AppState>, Path(device_ref): Path<String>) -> Response {
+    let (backups, device) = match device(&state, &device_ref).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let Some(command) = command_for(&state.settings.config_backup, device.device_type.as_deref()) else {
+        return error_response(StatusCode::CONFLICT, "no_backup_command",
+                              format!("No backup command is configured for device type {:?}", device.device_type));
+    };
+    match backup(&state, &backups, &device, command).await {
+        Ok(Some(version)) => (StatusCode::CREATED, Json(json!({ "changed": true, "version": version }))).into_response(),
+        Ok(None) => Json(json!({
+            "changed": false,
+            "version": backups.store.list(&device.id).ok().and_then(|versions| versions.into_iter().next()),
+        })).into_response(),
+        Err(e) => {
+            warn!("Backup of device {} failed: {}", device.name, e);
+            error_response(StatusCode::BAD_GATEWAY, "backup_failed", e)
+        }
+    }
+}
+
+/// Gets a stored configuration as text
+pub async fn get_handler(
+    State(state): State<AppState>,
+    Path((device_ref, version_id)): Path<(String, String)>,
+) -> Response {
+    let (backups, device) = match device(&state, &device_ref).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    match backups.store.read(&device.id, &version_id) {
+        Ok(Some(config)) => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], config).into_response(),
+        Ok(None) => version_not_found(&version_id),
+        Err(e) => storage_error(&device, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    /// The version compared against; the one before by default
+    pub against: Option<String>,
+}
+
+/// Shows what changed in a stored configuration, as a unified diff
+pub async fn diff_handler(
+    State(state): State<AppState>,
+    Path((device_ref, version_id)): Path<(String, String)>,
+    Query(query): Query<DiffQuery>,
+) -> Response {
+    let (backups, device) = match device(&state, &device_ref).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let against = match query.against {
+        Some(against) => against,
+        None => {
+            let versions = match backups.store.list(&device.id) {
+                Ok(versions) => versions,
+                Err(e) => return storage_error(&device, e),
+            };
+            let Some(position) = versions.iter().position(|version| version.id == version_id) else {
+                return version_not_found(&version_id);
+            };
+            match versions.get(position + 1) {
+                Some(previous) => previous.id.clone(),
+                None => return error_response(StatusCode::NOT_FOUND, "version_not_found",
+                                              format!("Configuration version '{}' is the first one stored", version_id)),
+            }
+        }
+    };
+    let read = |id: &str| backups.store.read(&device.id, id);
+    let (old, new) = match (read(&against), read(&version_id)) {
+        (Ok(Some(old)), Ok(Some(new))) => (old, new),
+        (Err(e), _) | (_, Err(e)) => return storage_error(&device, e),
+        (Ok(None), _) => return version_not_found(&against),
+        (_, Ok(None)) => return version_not_found(&version_id),
+    };
+    let diff = unified_diff(&old, &new, &format!("{}/{}", device.name, against), &format!("{}/{}", device.name, version_id));
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], diff).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_configurations_are_kept_and_diffed() {
+        let directory = std::env::temp_dir().join(format!("webssh-config-backup-{}", uuid::Uuid::new_v4()));
+        let settings = ConfigBackupSettings {
+            directory: directory.to_string_lossy().into_owned(),
+            keep_versions: 2,
+            ..Default::default()
+        };
+        let store = ConfigStore::new(&settings);
+        let at = |seconds: i64| DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap();
+        let first = "hostname r1\ninterface Gi1\n shutdown\n!\nend\n";
+        let second = "hostname r1\ninterface Gi1\n no shutdown\n description uplink\n!\nend\n";
+
+        let version = store.save("d1", first, at(0)).unwrap().unwrap();
+        assert_eq!(version.id, "20231114T221320.000Z");
+        assert!(version.compressed);
+        assert!(store.save("d1", first, at(60)).unwrap().is_none());
+        let latest = store.save("d1", second, at(120)).unwrap().unwrap();
+        assert_eq!(store.read("d1", &latest.id).unwrap().as_deref(), Some(second));
+        assert_eq!(store.read("d1", "../d2/20231114T221320.000Z").unwrap(), None);
+
+        store.save("d1", first, at(180)).unwrap().unwrap();
+        let versions = store.list("d1").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[1].id, latest.id);
+
+        assert_eq!(unified_diff(first, second, "a", "b"),
+                   "--- a\n+++ b\n@@ -1,5 +1,6 @@\n hostname r1\n interface Gi1\n- shutdown\n+ no shutdown\n+ description uplink\n !\n end\n");
+        assert_eq!(unified_diff(first, first, "a", "b"), "");
+        let long: String = (0..20).map(|i| format!("line {}\n", i)).collect();
+        let changed = long.replace("line 2\n", "").replace("line 17\n", "line 17b\n");
+        assert_eq!(unified_diff(&long, &changed, "a", "b"),
+                   "--- a\n+++ b\n@@ -1,6 +1,5 @@\n line 0\n line 1\n-line 2\n line 3\n line 4\n line 5\n\
+                    @@ -15,6 +14,6 @@\n line 14\n line 15\n line 16\n-line 17\n+line 17b\n line 18\n line 19\n");
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
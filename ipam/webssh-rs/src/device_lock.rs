@@ -0,0 +1,175 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::jwt::AuthenticatedUser;
+use crate::settings::DeviceLockSettings;
+use crate::AppState;
+
+/// Who holds a device's configuration lock
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceLock {
+    pub device_id: String,
+    pub session_id: String,
+    pub portal_user_id: String,
+    pub acquired_at: DateTime<Utc>,
+}
+
+impl DeviceLock {
+    /// What another user connecting to the device is told
+    pub fn describe(&self) -> String {
+        format!("Device {} is locked for configuration by {} (session {}) since {}",
+                self.device_id, self.portal_user_id, self.session_id, self.acquired_at.format("%Y-%m-%d %H:%M:%S UTC"))
+    }
+}
+
+/// Advisory locks on devices marked for one operator at a time
+///
+/// The first session to such a device takes its lock and holds it until the
+/// session ends or the lock is released. Sessions of the holder's portal user
+/// share the lock; other users are warned or refused, as configured. Nothing
+/// stops a user from connecting to the device some other way.
+#[derive(Default)]
+pub struct DeviceLocks {
+    locks: HashMap<String, DeviceLock>,
+}
+
+impl DeviceLocks {
+    /// Whether sessions to a device with these inventory tags take its lock
+    pub fn applies(settings: &DeviceLockSettings, device_tags: &[String]) -> bool {
+        settings.enabled && device_tags.iter().any(|tag| tag == &settings.tag)
+    }
+
+    /// The lock on a device held by another portal user, if any
+    pub fn held_against(&self, device_id: &str, portal_user_id: &str) -> Option<&DeviceLock> {
+        self.locks.get(device_id).filter(|lock| lock.portal_user_id != portal_user_id)
+    }
+
+    /// Takes a device's lock for a session, unless it is held already
+    ///
+    /// # Returns
+    /// * `bool` - true if the session now holds the lock
+    pub fn acquire(&mut self, device_id: &str, session_id: &str, portal_user_id: &str) -> bool {
+        if self.locks.contains_key(device_id) {
+            return false;
+        }
+        self.locks.insert(device_id.to_string(), DeviceLock {
+            device_id: device_id.to_string(),
+            session_id: session_id.to_string(),
+            portal_user_id: portal_user_id.to_string(),
+            acquired_at: Utc::now(),
+        });
+        true
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&DeviceLock> {
+        self.locks.get(device_id)
+    }
+
+    /// Releases a device's lock, whoever holds it
+    pub fn release(&mut self, device_id: &str) -> Option<DeviceLock> {
+        self.locks.remove(device_id)
+    }
+
+    /// Releases the locks a session holds
+    pub fn release_session(&mut self, session_id: &str) -> Vec<DeviceLock> {
+        let devices: Vec<String> = self.locks.iter()
+            .filter(|(_, lock)| lock.session_id == session_id)
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+        devices.iter().filter_map(|device_id| self.locks.remove(device_id)).collect()
+    }
+
+    /// Gets the locks held, oldest first
+    pub fn list(&self) -> Vec<DeviceLock> {
+        let mut locks: Vec<DeviceLock> = self.locks.values().cloned().collect();
+        locks.sort_by_key(|lock| lock.acquired_at);
+        locks
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Lists the device locks held on this instance
+pub async fn list_handler(State(state): State<AppState>) -> Response {
+    let locks = state.session_registry.lock().await.device_locks.list();
+    Json(json!({ "locks": locks })).into_response()
+}
+
+/// Releases the locks a session holds before it ends, e.g. once its changes are saved
+pub async fn release_session_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    if registry.get_session(session_id).is_none() {
+        return error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id));
+    }
+    let released = registry.device_locks.release_session(session_id);
+    if released.is_empty() {
+        return error_response(StatusCode::NOT_FOUND, "lock_not_held", format!("Session '{}' holds no device lock", session_id));
+    }
+    for lock in &released {
+        info!("Session {} released the lock on device {}", session_id, lock.device_id);
+    }
+    Json(json!({ "released": released })).into_response()
+}
+
+/// Releases a device's lock whoever holds it, e.g. when its holder has left a session open
+pub async fn force_release_handler(
+    State(state): State<AppState>,
+    Path(device_ref): Path<String>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Response {
+    let device_ref = device_ref.trim();
+    // Locks are kept by address; inventory devices may also be named by ID or name
+    let device_id = match &state.inventory {
+        Some(inventory) => inventory.get(device_ref).map(|device| device.hostname).unwrap_or_else(|_| device_ref.to_string()),
+        None => device_ref.to_string(),
+    };
+    let Some(lock) = state.session_registry.lock().await.device_locks.release(&device_id) else {
+        return error_response(StatusCode::NOT_FOUND, "lock_not_held", format!("Device '{}' is not locked", device_ref));
+    };
+    warn!("Lock on device {} held by {} (session {}) released by {}", lock.device_id, lock.portal_user_id, lock.session_id,
+          user.map(|Extension(user)| user.subject).unwrap_or_else(|| "an administrator".to_string()));
+    Json(json!({ "released": [lock] })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_held_until_its_session_ends() {
+        let settings = DeviceLockSettings::default();
+        assert!(DeviceLocks::applies(&settings, &["core".to_string(), "config-locked".to_string()]));
+        assert!(!DeviceLocks::applies(&settings, &["core".to_string()]));
+
+        let mut locks = DeviceLocks::default();
+        assert!(locks.acquire("10.0.0.1", "s1", "alice"));
+        assert!(!locks.acquire("10.0.0.1", "s2", "bob"));
+        assert!(locks.held_against("10.0.0.1", "alice").is_none());
+        assert_eq!(locks.held_against("10.0.0.1", "bob").unwrap().session_id, "s1");
+        assert!(locks.held_against("10.0.0.2", "bob").is_none());
+
+        assert!(locks.release_session("s2").is_empty());
+        assert_eq!(locks.release_session("s1").len(), 1);
+        assert!(locks.get("10.0.0.1").is_none());
+        assert!(locks.acquire("10.0.0.1", "s2", "bob"));
+        assert_eq!(locks.release("10.0.0.1").unwrap().portal_user_id, "bob");
+    }
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Why a connect, exec or credential check failed, as reported in `error_code`
+///
+/// These are the codes clients branch on; other endpoints report failures
+/// with a lowercase `error` string instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    AuthFailed,
+    ConnectionFailed,
+    UnknownError,
+    CredentialsInQuery,
+    WeakPrivateKey,
+    InvalidPrivateKey,
+    PassphraseRequired,
+    DefaultCredentials,
+    SessionLimitExceeded,
+    CertificateUnavailable,
+    Timeout,
+    DeviceNotFound,
+    CredentialsUnavailable,
+    CredentialsNotFound,
+    InvalidCredentialRef,
+    InventoryUnavailable,
+    AgentUnavailable,
+    UnsupportedProtocol,
+    InvalidAddress,
+    CommandBlocked,
+    AccessDenied,
+    Maintenance,
+    DeviceLocked,
+    PassthroughRefused,
+    HsmUnavailable,
+    GssapiUnavailable,
+    KerberosTicketMissing,
+    KerberosTicketExpired,
+    HostkeyConfirmationRequired,
+    HostKeyChanged,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 30] = [
+        ErrorCode::AuthFailed,
+        ErrorCode::ConnectionFailed,
+        ErrorCode::UnknownError,
+        ErrorCode::CredentialsInQuery,
+        ErrorCode::WeakPrivateKey,
+        ErrorCode::InvalidPrivateKey,
+        ErrorCode::PassphraseRequired,
+        ErrorCode::DefaultCredentials,
+        ErrorCode::SessionLimitExceeded,
+        ErrorCode::CertificateUnavailable,
+        ErrorCode::Timeout,
+        ErrorCode::DeviceNotFound,
+        ErrorCode::CredentialsUnavailable,
+        ErrorCode::CredentialsNotFound,
+        ErrorCode::InvalidCredentialRef,
+        ErrorCode::InventoryUnavailable,
+        ErrorCode::AgentUnavailable,
+        ErrorCode::UnsupportedProtocol,
+        ErrorCode::InvalidAddress,
+        ErrorCode::CommandBlocked,
+        ErrorCode::AccessDenied,
+        ErrorCode::Maintenance,
+        ErrorCode::DeviceLocked,
+        ErrorCode::PassthroughRefused,
+        ErrorCode::HsmUnavailable,
+        ErrorCode::GssapiUnavailable,
+        ErrorCode::KerberosTicketMissing,
+        ErrorCode::KerberosTicketExpired,
+        ErrorCode::HostkeyConfirmationRequired,
+        ErrorCode::HostKeyChanged,
+    ];
+
+    /// The code as sent to clients, e.g. `AUTH_FAILED`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::AuthFailed => "AUTH_FAILED",
+            ErrorCode::ConnectionFailed => "CONNECTION_FAILED",
+            ErrorCode::UnknownError => "UNKNOWN_ERROR",
+            ErrorCode::CredentialsInQuery => "CREDENTIALS_IN_QUERY",
+            ErrorCode::WeakPrivateKey => "WEAK_PRIVATE_KEY",
+            ErrorCode::InvalidPrivateKey => "INVALID_PRIVATE_KEY",
+            ErrorCode::PassphraseRequired => "PASSPHRASE_REQUIRED",
+            ErrorCode::DefaultCredentials => "DEFAULT_CREDENTIALS",
+            ErrorCode::SessionLimitExceeded => "SESSION_LIMIT_EXCEEDED",
+            ErrorCode::CertificateUnavailable => "CERTIFICATE_UNAVAILABLE",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::DeviceNotFound => "DEVICE_NOT_FOUND",
+            ErrorCode::CredentialsUnavailable => "CREDENTIALS_UNAVAILABLE",
+            ErrorCode::CredentialsNotFound => "CREDENTIALS_NOT_FOUND",
+            ErrorCode::InvalidCredentialRef => "INVALID_CREDENTIAL_REF",
+            ErrorCode::InventoryUnavailable => "INVENTORY_UNAVAILABLE",
+            ErrorCode::AgentUnavailable => "AGENT_UNAVAILABLE",
+            ErrorCode::UnsupportedProtocol => "UNSUPPORTED_PROTOCOL",
+            ErrorCode::InvalidAddress => "INVALID_ADDRESS",
+            ErrorCode::CommandBlocked => "COMMAND_BLOCKED",
+            ErrorCode::AccessDenied => "ACCESS_DENIED",
+            ErrorCode::Maintenance => "MAINTENANCE",
+            ErrorCode::DeviceLocked => "DEVICE_LOCKED",
+            ErrorCode::PassthroughRefused => "PASSTHROUGH_REFUSED",
+            ErrorCode::HsmUnavailable => "HSM_UNAVAILABLE",
+            ErrorCode::GssapiUnavailable => "GSSAPI_UNAVAILABLE",
+            ErrorCode::KerberosTicketMissing => "KERBEROS_TICKET_MISSING",
+            ErrorCode::KerberosTicketExpired => "KERBEROS_TICKET_EXPIRED",
+            ErrorCode::HostkeyConfirmationRequired => "HOSTKEY_CONFIRMATION_REQUIRED",
+            ErrorCode::HostKeyChanged => "HOST_KEY_CHANGED",
+        }
+    }
+
+    /// What the code means, for the API documentation
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::AuthFailed => "Authentication failed (invalid username/password or private key)",
+            ErrorCode::ConnectionFailed => "Failed to connect to the device (host unreachable, port closed, etc.)",
+            ErrorCode::UnknownError => "An unknown error occurred",
+            ErrorCode::CredentialsInQuery => "Credentials were passed in the query string",
+            ErrorCode::WeakPrivateKey => "The private key is below the required strength, or is a DSA key",
+            ErrorCode::InvalidPrivateKey => "The private key could not be parsed, or is in an unsupported format",
+            ErrorCode::PassphraseRequired => "The private key is encrypted and no private_key_passphrase was given",
+            ErrorCode::DefaultCredentials => "Known default credentials were refused by policy",
+            ErrorCode::SessionLimitExceeded => "A session limit is reached and no idle session could be evicted",
+            ErrorCode::CertificateUnavailable => {
+                "Certificate authentication is disabled, the user is not authenticated, or the SSH CA did not issue a certificate"
+            }
+            ErrorCode::Timeout => "Commands run through /api/exec did not finish in time",
+            ErrorCode::DeviceNotFound => "The device_ref is not in the device inventory",
+            ErrorCode::CredentialsUnavailable => {
+                "The inventory device's credentials_ref, or the request's credential_ref, could not be read"
+            }
+            ErrorCode::CredentialsNotFound => "The credential_ref names a Vault secret or keyring entry that does not exist",
+            ErrorCode::InvalidCredentialRef => "The credential_ref is malformed or not allowed",
+            ErrorCode::InventoryUnavailable => {
+                "A device_ref was given but the device inventory is disabled or cannot be read"
+            }
+            ErrorCode::AgentUnavailable => {
+                "Agent authentication is disabled, the gateway's ssh-agent cannot be reached, or it holds no allowed identity"
+            }
+            ErrorCode::UnsupportedProtocol => {
+                "Telnet is disabled, or the request needs something telnet does not offer"
+            }
+            ErrorCode::InvalidAddress => {
+                "The hostname is malformed, e.g. an interface scope on an address that is not link-local IPv6"
+            }
+            ErrorCode::CommandBlocked => "A command was refused by the command policy; none of the commands were run",
+            ErrorCode::AccessDenied => "The user's role, or the device ACLs, do not allow connecting to the device",
+            ErrorCode::Maintenance => "The gateway is in maintenance mode and takes no new connections",
+            ErrorCode::DeviceLocked => "Another user holds the device's configuration lock and the policy refuses other sessions",
+            ErrorCode::PassthroughRefused => "Pass-through mode is not enabled, or not allowed for the user's roles or authentication type",
+            ErrorCode::HsmUnavailable => {
+                "PKCS#11 authentication is disabled, the module is not allowed or cannot be loaded, or the token or key was not found"
+            }
+            ErrorCode::GssapiUnavailable => {
+                "Kerberos authentication is disabled, or the OpenSSH client or the GSSAPI library cannot be used"
+            }
+            ErrorCode::KerberosTicketMissing => "The gateway holds no Kerberos ticket and has no keytab to get one from",
+            ErrorCode::KerberosTicketExpired => "The gateway's Kerberos ticket has expired; renew it with kinit or configure a keytab",
+            ErrorCode::HostkeyConfirmationRequired => {
+                "The device's host key is not pinned yet; confirm the fingerprint in host_key through /api/connect/confirm"
+            }
+            ErrorCode::HostKeyChanged => "The device presented a different host key from the one pinned for it",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,527 @@
+use axum::{
+    extract::{RawQuery, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use ssh2::{Channel, Session};
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::audit::AuditEntry;
+use crate::authz;
+use crate::command_policy;
+use crate::error_code::ErrorCode;
+use crate::jwt::AuthenticatedUser;
+use crate::parsing;
+use crate::settings::{ExecSettings, Settings, WebhookEventType};
+use crate::ssh::{error::SSHError, ChannelKind, ConnectionTarget, SharedConnection};
+use crate::{connection_target, credential_policy, resolve_request, use_certificate, AppState, SSHCredentials, CERTIFICATE};
+
+/// How long to wait for more output before polling the channel again
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time allowed to close a channel once its command has finished or been abandoned
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// libssh2's LIBSSH2_ERROR_EAGAIN, returned by non-blocking calls with nothing to do
+const ERROR_EAGAIN: i32 = -37;
+
+/// libssh2 errors for a channel the device would not open or start a command on;
+/// the command has not run, so it can be retried on another connection
+const ERROR_CHANNEL_FAILURE: i32 = -21;
+const ERROR_CHANNEL_REQUEST_DENIED: i32 = -22;
+
+/// Body of a request to run commands on a device
+#[derive(Debug, Deserialize)]
+pub struct ExecRequest {
+    #[serde(flatten)]
+    credentials: SSHCredentials,
+    /// A single command; `commands` runs several in order
+    command: Option<String>,
+    #[serde(default)]
+    commands: Vec<String>,
+    /// Time allowed for all the commands; defaults to `exec.default_timeout_seconds`
+    timeout_seconds: Option<u64>,
+    /// Output kept per command; defaults to `exec.default_max_output_bytes`
+    max_output_bytes: Option<usize>,
+    /// Parse each command's output into rows with the template for it
+    #[serde(default)]
+    parse: bool,
+}
+
+/// What one command printed and how it ended
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandResult {
+    pub command: String,
+    pub stdout: String,
+    pub stderr: String,
+    /// Not set when the command was abandoned, or ended by a signal
+    pub exit_status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_signal: Option<String>,
+    /// The output limit was reached; the command was abandoned there
+    pub truncated: bool,
+    /// The request's time ran out; the command was abandoned there
+    pub timed_out: bool,
+    pub duration_ms: u64,
+    /// Rows parsed from stdout, when the request asked for parsing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parsed: Option<Vec<Map<String, Value>>>,
+    /// Why stdout could not be parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecResponse {
+    pub success: bool,
+    pub message: String,
+    pub error_code: Option<ErrorCode>,
+    /// Commands that were run, in order; those after a timeout are not run
+    pub results: Vec<CommandResult>,
+    /// The commands ran over the connection of a live session to the device
+    pub shared_connection: bool,
+    // Credential policy findings that did not block the connection
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl ExecResponse {
+    pub(crate) fn failed(message: String, error_code: ErrorCode, results: Vec<CommandResult>, warnings: Vec<String>) -> Self {
+        Self {
+            success: false,
+            message,
+            error_code: Some(error_code),
+            results,
+            shared_connection: false,
+            warnings,
+        }
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Commands of a request and the limits they run within, checked against the settings
+#[derive(Debug, Clone)]
+pub(crate) struct ExecPlan {
+    pub commands: Vec<String>,
+    pub timeout_seconds: u64,
+    pub max_output_bytes: usize,
+    pub parse: bool,
+}
+
+impl ExecPlan {
+    /// Checks the commands and limits a request gives, filling in the defaults
+    ///
+    /// A refusal is given as the status, error and message to answer with.
+    pub(crate) fn new(
+        state: &AppState,
+        settings: &ExecSettings,
+        command: Option<String>,
+        commands: Vec<String>,
+        timeout_seconds: Option<u64>,
+        max_output_bytes: Option<usize>,
+        parse: bool,
+    ) -> Result<Self, (StatusCode, &'static str, String)> {
+        if !settings.enabled {
+            return Err((StatusCode::CONFLICT, "exec_disabled",
+                        "Command execution is not enabled on this instance".to_string()));
+        }
+        let commands: Vec<String> = command.into_iter().chain(commands).collect();
+        if commands.is_empty() || commands.iter().any(|command| command.trim().is_empty()) {
+            return Err((StatusCode::BAD_REQUEST, "invalid_request",
+                        "Give a non-empty command or commands".to_string()));
+        }
+        if commands.len() > settings.max_commands {
+            return Err((StatusCode::BAD_REQUEST, "invalid_request",
+                        format!("At most {} commands may be run per request", settings.max_commands)));
+        }
+        let timeout_seconds = timeout_seconds.unwrap_or(settings.default_timeout_seconds);
+        if timeout_seconds == 0 || timeout_seconds > settings.max_timeout_seconds {
+            return Err((StatusCode::BAD_REQUEST, "invalid_timeout",
+                        format!("timeout_seconds must be between 1 and {}", settings.max_timeout_seconds)));
+        }
+        let max_output_bytes = max_output_bytes.unwrap_or(settings.default_max_output_bytes);
+        if max_output_bytes == 0 || max_output_bytes > settings.max_output_bytes {
+            return Err((StatusCode::BAD_REQUEST, "invalid_output_limit",
+                        format!("max_output_bytes must be between 1 and {}", settings.max_output_bytes)));
+        }
+        if parse && state.templates.is_none() {
+            return Err((StatusCode::CONFLICT, "parsing_disabled",
+                        "Output parsing is not enabled on this instance".to_string()));
+        }
+        Ok(Self { commands, timeout_seconds, max_output_bytes, parse })
+    }
+}
+
+/// Runs commands on a device over exec channels, without a terminal or a session
+///
+/// Each command gets its own channel, so state such as the working directory
+/// does not carry over. The channels are opened on the connection of a live
+/// session logged in to the device the same way, when there is one; otherwise
+/// the device is connected to for the request only.
+pub async fn exec_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    RawQuery(query): RawQuery,
+    Json(request): Json<ExecRequest>,
+) -> Response {
+    let policy_settings = state.policy.settings();
+    let plan = match ExecPlan::new(&state, &policy_settings.exec, request.command, request.commands,
+                                   request.timeout_seconds, request.max_output_bytes, request.parse) {
+        Ok(plan) => plan,
+        Err((status, error, message)) => return error_response(status, error, message),
+    };
+    let exec_id = format!("exec-{}", uuid::Uuid::new_v4());
+    let user = user.map(|Extension(user)| user);
+    match exec_on_device(&state, &policy_settings, user.as_ref(), query.as_deref(), request.credentials, &plan, &exec_id).await {
+        Ok(response) => Json(response).into_response(),
+        Err(e) => {
+            error!("[{}] Exec task failed: {}", exec_id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "exec_failed", "Exec task failed".to_string())
+        }
+    }
+}
+
+/// Checks a request for one device against the policies and runs its commands there
+///
+/// Refusals and failures are reported in the response; an error means the
+/// task running the commands failed.
+pub(crate) async fn exec_on_device(
+    state: &AppState,
+    policy_settings: &Settings,
+    user: Option<&AuthenticatedUser>,
+    query: Option<&str>,
+    credentials: SSHCredentials,
+    plan: &ExecPlan,
+    exec_id: &str,
+) -> Result<ExecResponse, String> {
+    let settings = &policy_settings.exec;
+    if let Some(message) = state.maintenance.refusal() {
+        info!("Exec on {} refused: in maintenance", credentials.hostname);
+        return Ok(ExecResponse::failed(message, ErrorCode::Maintenance, Vec::new(), Vec::new()));
+    }
+
+    let credentials = match resolve_request(state, credentials).await {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            warn!("Exec refused: {}", e);
+            return Ok(ExecResponse::failed(e.to_string(), e.error_code(), Vec::new(), Vec::new()));
+        }
+    };
+    if let Some(user) = user {
+        if let Err(message) = authz::check_connect(&policy_settings.authorization, user, &credentials.hostname, &credentials.device_tags) {
+            warn!("Exec on {} refused for {}: {}", credentials.hostname, user.subject, message);
+            return Ok(ExecResponse::failed(message, ErrorCode::AccessDenied, Vec::new(), Vec::new()));
+        }
+    }
+    let identity = user.map(|user| user.subject.clone());
+    let portal_user_id = identity.clone()
+        .or(credentials.portal_user_id.clone())
+        .unwrap_or_else(|| "anonymous".to_string());
+    let mut target = connection_target(state, &credentials, policy_settings, &portal_user_id);
+    if target.port == 0 {
+        target.port = 22;
+    }
+
+    let warnings = match credential_policy::check(&policy_settings.credential_policy, &target, query) {
+        Ok(warnings) => warnings,
+        Err(violation) => {
+            warn!("Exec on {} as {} refused by credential policy: {}", target.hostname, target.username, violation);
+            return Ok(ExecResponse::failed(violation.to_string(), violation.error_code(), Vec::new(), Vec::new()));
+        }
+    };
+
+    // Commands are refused before anything reaches the device, as they would be in a terminal
+    if policy_settings.command_policy.enabled {
+        let roles = user.map(|user| user.roles.as_slice()).unwrap_or_default();
+        for command in &plan.commands {
+            if let Err(blocked) = command_policy::check(&policy_settings.command_policy, target.device_type.as_deref(), roles, command, false) {
+                warn!("Exec on {} refused: {}", target.hostname, blocked.reason);
+                if let Some(webhooks) = &state.webhooks {
+                    webhooks.send(WebhookEventType::CommandBlocked, json!({
+                        "session_id": null,
+                        "portal_user_id": user.map(|user| user.subject.as_str()),
+                        "hostname": target.hostname,
+                        "device_type": target.device_type,
+                        "command": blocked.command,
+                        "rule": blocked.rule,
+                        "reason": blocked.reason,
+                        "guardrail": blocked.guardrail,
+                    }));
+                }
+                return Ok(ExecResponse::failed(blocked.reason, ErrorCode::CommandBlocked, Vec::new(), warnings));
+            }
+        }
+    }
+
+    if credentials.auth_type.as_deref() == Some(CERTIFICATE) {
+        if let Err(e) = use_certificate(state, identity.as_deref(), &mut target).await {
+            warn!("No certificate for portal user {} to device {}: {}", portal_user_id, target.hostname, e);
+            return Ok(ExecResponse::failed(e.to_string(), e.error_code(), Vec::new(), warnings));
+        }
+    }
+
+    info!("[{}] Running {} command(s) on {} as {} for portal user {}",
+          exec_id, plan.commands.len(), target.hostname, target.username, portal_user_id);
+
+    let shared = if settings.share_connections {
+        state.session_registry.lock().await.shared_connection(&target)
+    } else {
+        None
+    };
+    if shared.is_some() {
+        debug!("[{}] Sharing the connection of a live session to {}", exec_id, target.hostname);
+    }
+
+    let device_id = target.hostname.clone();
+    let device_type = target.device_type.clone();
+    let ssh_username = target.username.clone();
+    let timeout_seconds = plan.timeout_seconds;
+    let timeout = Duration::from_secs(timeout_seconds);
+    let max_output_bytes = plan.max_output_bytes;
+    let commands = plan.commands.clone();
+    let owner = portal_user_id.clone();
+    let ran = tokio::task::spawn_blocking(move || run(&target, shared.as_ref(), &owner, &commands, timeout, max_output_bytes)).await;
+    let (mut results, outcome, shared_connection) = ran.map_err(|e| e.to_string())?;
+
+    // Commands run this way are audited like those typed in a terminal
+    if let Some(audit) = &state.audit {
+        for result in &results {
+            audit.append(&AuditEntry {
+                timestamp: Utc::now(),
+                session_id: exec_id.to_string(),
+                portal_user_id: portal_user_id.clone(),
+                device_id: device_id.clone(),
+                ssh_username: ssh_username.clone(),
+                command: result.command.clone(),
+                uncertain: false,
+                blocked: false,
+            });
+        }
+    }
+
+    if let Some(templates) = state.templates.as_deref().filter(|_| plan.parse) {
+        for result in &mut results {
+            // Cut-off output would parse into misleading rows
+            let parsed = if result.truncated || result.timed_out {
+                Err("The command did not finish, so its output was not parsed".to_string())
+            } else {
+                parsing::parse_output(templates, device_type.as_deref(), &device_id, &result.command, &result.stdout)
+            };
+            match parsed {
+                Ok(rows) => result.parsed = Some(rows),
+                Err(e) => {
+                    debug!("[{}] Output of '{}' not parsed: {}", exec_id, result.command, e);
+                    result.parse_error = Some(e);
+                }
+            }
+        }
+    }
+
+    let mut response = match outcome {
+        Err(e) => {
+            error!("[{}] Exec on {} failed: {}", exec_id, device_id, e);
+            ExecResponse::failed(format!("Failed to run commands: {}", e), e.error_code(), results, warnings)
+        }
+        Ok(()) if results.last().is_some_and(|result| result.timed_out) => {
+            warn!("[{}] Exec on {} timed out after {}s", exec_id, device_id, timeout_seconds);
+            ExecResponse::failed(format!("Commands did not finish within {} seconds", timeout_seconds), ErrorCode::Timeout, results, warnings)
+        }
+        Ok(()) => {
+            info!("[{}] Ran {} command(s) on {}", exec_id, results.len(), device_id);
+            ExecResponse {
+                success: true,
+                message: format!("Ran {} command(s)", results.len()),
+                error_code: None,
+                results,
+                shared_connection: false,
+                warnings,
+            }
+        }
+    };
+    response.shared_connection = shared_connection;
+    Ok(response)
+}
+
+/// Runs the commands in order, stopping at the first timeout or error
+///
+/// A shared connection is used if the device opens channels on it; otherwise
+/// the device is connected to for these commands only.
+///
+/// # Returns
+/// * `(Vec<CommandResult>, Result<(), SSHError>, bool)` - The commands run so far, the error that
+///   stopped them, and whether they ran over the shared connection
+pub(crate) fn run(
+    target: &ConnectionTarget,
+    shared: Option<&SharedConnection>,
+    owner: &str,
+    commands: &[String],
+    timeout: Duration,
+    max_output: usize,
+) -> (Vec<CommandResult>, Result<(), SSHError>, bool) {
+    let deadline = Instant::now() + timeout;
+    if let Some(connection) = shared {
+        let mut results = Vec::new();
+        match run_commands(connection.session(), Some((connection, owner)), commands, deadline, max_output, &mut results) {
+            Err(e) if results.is_empty() && is_refused(&e) => {
+                info!("{} refused a channel on the shared connection ({}); connecting separately", target.hostname, e);
+            }
+            outcome => return (results, outcome, true),
+        }
+    }
+
+    let session = match target.connect() {
+        Ok(session) => session,
+        Err(e) => return (Vec::new(), Err(e), false),
+    };
+    session.set_blocking(false);
+    let mut results = Vec::new();
+    let outcome = run_commands(&session, None, commands, deadline, max_output, &mut results);
+    session.set_blocking(true);
+    session.set_timeout(timeout_ms(CLOSE_TIMEOUT));
+    let _ = session.disconnect(None, "Commands complete", None);
+    (results, outcome, false)
+}
+
+/// Runs the commands over a non-blocking session, collecting their results
+///
+/// Channels on a shared connection are accounted to the owner while they are open.
+fn run_commands(
+    session: &Session,
+    shared: Option<(&SharedConnection, &str)>,
+    commands: &[String],
+    deadline: Instant,
+    max_output: usize,
+    results: &mut Vec<CommandResult>,
+) -> Result<(), SSHError> {
+    for command in commands {
+        let _lease = shared.map(|(connection, owner)| connection.lease(ChannelKind::Exec, owner));
+        let result = run_command(session, command, deadline, max_output)?;
+        let timed_out = result.timed_out;
+        results.push(result);
+        if timed_out {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Runs one command, reading stdout and stderr together until it exits
+///
+/// The session stays in non-blocking mode throughout: a shared connection
+/// is also pumping a shell's I/O.
+fn run_command(session: &Session, command: &str, deadline: Instant, max_output: usize) -> Result<CommandResult, SSHError> {
+    let started = Instant::now();
+    debug!("Running command: {}", command);
+    let mut channel = retry(deadline, || session.channel_session())?;
+    retry(deadline, || channel.exec(command))?;
+
+    // Both streams are polled, so a command filling one cannot stall on the other
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut buffer = [0u8; 16 * 1024];
+    let mut truncated = false;
+    let mut timed_out = false;
+    loop {
+        let mut progressed = false;
+        for (stream_id, output) in [(0, &mut stdout), (1, &mut stderr)] {
+            let read = channel.stream(stream_id).read(&mut buffer);
+            match read {
+                Ok(0) => {}
+                Ok(n) => {
+                    progressed = true;
+                    output.extend_from_slice(&buffer[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if stdout.len() + stderr.len() > max_output {
+            truncated = true;
+            break;
+        }
+        if !progressed {
+            if channel.eof() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                timed_out = true;
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    let (exit_status, exit_signal) = close(&mut channel, truncated || timed_out)?;
+
+    if truncated {
+        // Keep stdout in preference to stderr, up to the limit
+        stdout.truncate(max_output);
+        stderr.truncate(max_output - stdout.len());
+    }
+    Ok(CommandResult {
+        command: command.to_string(),
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        exit_status,
+        exit_signal,
+        truncated,
+        timed_out,
+        duration_ms: started.elapsed().as_millis() as u64,
+        parsed: None,
+        parse_error: None,
+    })
+}
+
+/// Closes a command's channel and reads how the command ended
+///
+/// An abandoned command is left to the device; closing the channel usually ends it.
+fn close(channel: &mut Channel, abandoned: bool) -> Result<(Option<i32>, Option<String>), SSHError> {
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    if abandoned {
+        let _ = retry(deadline, || channel.close());
+        return Ok((None, None));
+    }
+    retry(deadline, || channel.close())?;
+    retry(deadline, || channel.wait_close())?;
+    let exit_signal = channel.exit_signal().ok().and_then(|signal| signal.exit_signal);
+    let exit_status = if exit_signal.is_some() { None } else { channel.exit_status().ok() };
+    Ok((exit_status, exit_signal))
+}
+
+/// Repeats a non-blocking libssh2 call until it stops asking to be retried
+pub(crate) fn retry<T>(deadline: Instant, mut call: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, SSHError> {
+    loop {
+        match call() {
+            Err(e) if e.code() == ssh2::ErrorCode::Session(ERROR_EAGAIN) => {
+                if Instant::now() >= deadline {
+                    return Err(SSHError::Connection(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the device")));
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            result => return result.map_err(Into::into),
+        }
+    }
+}
+
+/// Whether the device refused to open a channel or start the command on it
+fn is_refused(error: &SSHError) -> bool {
+    matches!(error, SSHError::Ssh(e) if matches!(e.code(),
+        ssh2::ErrorCode::Session(ERROR_CHANNEL_FAILURE) | ssh2::ErrorCode::Session(ERROR_CHANNEL_REQUEST_DENIED)))
+}
+
+/// libssh2 timeout in milliseconds; 0 would mean no timeout at all
+fn timeout_ms(duration: Duration) -> u32 {
+    duration.as_millis().clamp(1, u32::MAX as u128) as u32
+}
@@ -0,0 +1,262 @@
+//! Session files for terminal programs, opening inventory devices through the gateway
+//!
+//! Each exported session runs `webssh-rs attach` for its device, so engineers
+//! working in tmux or PuTTY go through the gateway as the web interface does:
+//! with its authentication, policies, recording and audit. The files hold no
+//! credentials; `attach` reads the API key or token from its environment.
+//!
+//! SecureCRT sessions cannot run a local command in place of a connection,
+//! so there is no exporter for them.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::authz;
+use crate::inventory::{inventory_error, Device};
+use crate::jwt::AuthenticatedUser;
+use crate::AppState;
+
+/// The terminal programs sessions are exported for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// A tmuxinator project with a window per device
+    Tmuxinator,
+    /// A registry file of PuTTY sessions, each running `attach` as its local proxy command
+    Putty,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tmuxinator" => Some(Format::Tmuxinator),
+            "putty" => Some(Format::Putty),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// The inventory devices with this tag
+    tag: Option<String>,
+    /// Inventory devices by ID or name, separated by commas
+    devices: Option<String>,
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Quotes an argument for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:@%+=,".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Quotes an argument for a Windows command line
+fn windows_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"') {
+        arg.to_string()
+    } else {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    }
+}
+
+/// Escapes a session name as PuTTY does for its registry keys
+fn putty_session_key(name: &str) -> String {
+    let mut key = String::new();
+    for (i, byte) in name.bytes().enumerate() {
+        if matches!(byte, b' ' | b'\\' | b'*' | b'?' | b'%') || !(b' '..=b'~').contains(&byte) || (byte == b'.' && i == 0) {
+            key.push_str(&format!("%{:02X}", byte));
+        } else {
+            key.push(byte as char);
+        }
+    }
+    key
+}
+
+/// Escapes a string value of a registry file
+fn reg_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', r"\\").replace('"', "\\\""))
+}
+
+/// A tmuxinator project opening each device in a window of its own
+///
+/// Names and commands are written as double-quoted YAML scalars, which JSON strings are.
+fn tmuxinator(project: &str, devices: &[Device], attach: impl Fn(&Device) -> String) -> String {
+    let mut file = format!(
+        "# Devices of the webssh-rs gateway; set WEBSSH_API_KEY or WEBSSH_TOKEN before starting\nname: {}\nwindows:\n",
+        json!(project));
+    for device in devices {
+        file.push_str(&format!("  - {}: {}\n", json!(device.name), json!(attach(device))));
+    }
+    file
+}
+
+/// PuTTY sessions, in a registry file to be merged on Windows
+///
+/// Each session passes a raw connection through its local proxy command,
+/// `attach`, with local echo and line editing off so that keystrokes reach the
+/// device as typed. PuTTY reads `\` and `%` in the command as escapes.
+fn putty(devices: &[Device], attach: impl Fn(&Device) -> String) -> Vec<u8> {
+    let mut file = String::from("Windows Registry Editor Version 5.00\r\n");
+    for device in devices {
+        let command = attach(device).replace('\\', r"\\").replace('%', "%%");
+        file.push_str(&format!(
+            "\r\n[HKEY_CURRENT_USER\\Software\\SimonTatham\\PuTTY\\Sessions\\{}]\r\n\
+             \"HostName\"={}\r\n\
+             \"Protocol\"=\"raw\"\r\n\
+             \"ProxyMethod\"=dword:00000005\r\n\
+             \"ProxyTelnetCommand\"={}\r\n\
+             \"LocalEcho\"=dword:00000001\r\n\
+             \"LocalEdit\"=dword:00000001\r\n",
+            putty_session_key(&format!("webssh/{}", device.name)), reg_string(&device.name), reg_string(&command)));
+    }
+    // regedit reads version 5 files as UTF-16
+    std::iter::once('\u{feff}').collect::<String>().encode_utf16().chain(file.encode_utf16())
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// Exports inventory devices as sessions of a terminal program
+///
+/// The devices are those with `tag`, those named in `devices`, or all of
+/// them, leaving out those the user may not connect to.
+pub async fn export_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    headers: HeaderMap,
+    Path(format): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let policy_settings = state.policy.settings();
+    let settings = &policy_settings.export;
+    if !settings.enabled {
+        return error_response(StatusCode::CONFLICT, "export_disabled",
+                              "Session export is not enabled on this instance".to_string());
+    }
+    let Some(format) = Format::parse(&format) else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_format",
+                              format!("Unknown export format '{}'; use tmuxinator or putty", format));
+    };
+    let Some(inventory) = &state.inventory else {
+        return error_response(StatusCode::CONFLICT, "inventory_disabled",
+                              "The device inventory is not enabled on this instance".to_string());
+    };
+
+    let named: Vec<&str> = query.devices.as_deref().unwrap_or_default()
+        .split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+    let mut devices = Vec::new();
+    if query.tag.is_some() || named.is_empty() {
+        match inventory.list(query.tag.as_deref()) {
+            Ok(tagged) => devices = tagged,
+            Err(e) => return inventory_error(e),
+        }
+    }
+    for device_ref in named {
+        match inventory.get(device_ref) {
+            Ok(device) if !devices.iter().any(|listed| listed.id == device.id) => devices.push(device),
+            Ok(_) => {}
+            Err(e) => return inventory_error(e),
+        }
+    }
+    if let Some(Extension(user)) = &user {
+        devices.retain(|device| authz::check_connect(&policy_settings.authorization, user, &device.hostname, &device.tags).is_ok());
+    }
+    if devices.is_empty() {
+        return error_response(StatusCode::NOT_FOUND, "no_devices", "No devices to export".to_string());
+    }
+
+    let gateway_url = settings.gateway_url.clone().unwrap_or_else(|| {
+        let scheme = if state.settings.server.tls_enabled { "https" } else { "http" };
+        let host = headers.get(header::HOST).and_then(|host| host.to_str().ok());
+        let default_host = format!("{}:{}", state.settings.server.address, state.settings.server.port);
+        format!("{}://{}", scheme, host.unwrap_or(&default_host))
+    });
+    // Kept to letters, digits and dashes, as tmux session names and file names allow
+    let group: String = query.tag.as_deref().unwrap_or("devices").chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+        .collect();
+    let project = format!("webssh-{}", group);
+    info!("Exporting {} devices as {:?} sessions through {}", devices.len(), format, gateway_url);
+
+    let (file, content_type, extension) = match format {
+        Format::Tmuxinator => {
+            let attach = |device: &Device| format!("{} attach --url {} {}",
+                settings.attach_command, shell_quote(&gateway_url), shell_quote(&device.name));
+            (tmuxinator(&project, &devices, attach).into_bytes(), "application/yaml", "yml")
+        }
+        Format::Putty => {
+            let attach = |device: &Device| format!("{} attach --url {} {}",
+                settings.attach_command, windows_quote(&gateway_url), windows_quote(&device.name));
+            (putty(&devices, attach), "application/octet-stream", "reg")
+        }
+    };
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", project, extension)),
+        ],
+        file,
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn device(name: &str) -> Device {
+        Device {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            hostname: "10.0.0.1".to_string(),
+            port: 22,
+            device_type: None,
+            credentials_ref: None,
+            tags: Vec::new(),
+            lab_twin: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_tmuxinator_projects_quote_names_and_commands() {
+        let devices = [device("core-1"), device("edge's")];
+        let attach = |device: &Device| format!("webssh-rs attach --url http://gw:8888 {}", shell_quote(&device.name));
+        assert_eq!(tmuxinator("webssh-site", &devices, attach),
+                   "# Devices of the webssh-rs gateway; set WEBSSH_API_KEY or WEBSSH_TOKEN before starting\n\
+                    name: \"webssh-site\"\nwindows:\n\
+                    \x20 - \"core-1\": \"webssh-rs attach --url http://gw:8888 core-1\"\n\
+                    \x20 - \"edge's\": \"webssh-rs attach --url http://gw:8888 'edge'\\\\''s'\"\n");
+    }
+
+    #[test]
+    fn test_putty_sessions_escape_keys_and_commands() {
+        assert_eq!(putty_session_key("webssh/core 1%"), "webssh/core%201%25");
+        assert_eq!(putty_session_key(".hidden"), "%2Ehidden");
+        assert_eq!(windows_quote("core 1"), "\"core 1\"");
+
+        let file = putty(&[device("core-1")], |_| r"C:\Tools\webssh-rs.exe attach --url https://gw 100%".to_string());
+        let text = String::from_utf16(&file.chunks(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect::<Vec<_>>()).unwrap();
+        assert!(text.starts_with("\u{feff}Windows Registry Editor Version 5.00\r\n"));
+        assert!(text.contains(r"[HKEY_CURRENT_USER\Software\SimonTatham\PuTTY\Sessions\webssh/core-1]"));
+        // Escaped for PuTTY, then for the registry file
+        assert!(text.contains(r#""ProxyTelnetCommand"="C:\\\\Tools\\\\webssh-rs.exe attach --url https://gw 100%%""#));
+    }
+}
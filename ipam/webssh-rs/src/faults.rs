@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "fault-injection")]
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+#[cfg(feature = "fault-injection")]
+use bytes::Bytes;
+#[cfg(feature = "fault-injection")]
+use serde_json::json;
+#[cfg(feature = "fault-injection")]
+use tokio::sync::mpsc;
+#[cfg(feature = "fault-injection")]
+use tracing::info;
+
+#[cfg(feature = "fault-injection")]
+use crate::AppState;
+
+/// Longest delay a profile may add to a chunk or frame
+#[cfg(any(test, feature = "fault-injection"))]
+const MAX_DELAY_MS: u64 = 60_000;
+
+/// The faults injected into a shell, shared by its I/O tasks and WebSockets
+///
+/// Always empty unless the gateway is built with the `fault-injection` feature.
+pub type FaultSlot = Arc<RwLock<Option<FaultProfile>>>;
+
+/// A leg of a session's traffic, in both directions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultPath {
+    /// Between the gateway and the device
+    Ssh,
+    /// Between the gateway and the client
+    Websocket,
+}
+
+/// Latency, jitter and loss injected into a session's traffic
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultProfile {
+    /// Delay added to every chunk of shell I/O or WebSocket frame
+    pub latency_ms: u64,
+    /// Up to this much more delay, drawn at random each time
+    pub jitter_ms: u64,
+    /// Share of chunks and frames thrown away, from 0 to 1; they are not retransmitted
+    pub drop_rate: f64,
+    /// The legs affected; all of them when empty
+    pub paths: Vec<FaultPath>,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl FaultProfile {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.drop_rate) {
+            return Err(format!("drop_rate must be between 0 and 1, not {}", self.drop_rate));
+        }
+        if self.latency_ms.saturating_add(self.jitter_ms) > MAX_DELAY_MS {
+            return Err(format!("latency_ms and jitter_ms may add up to at most {} ms", MAX_DELAY_MS));
+        }
+        Ok(())
+    }
+
+    fn covers(&self, path: FaultPath) -> bool {
+        self.paths.is_empty() || self.paths.contains(&path)
+    }
+}
+
+/// Holds a chunk or frame back for the latency set on its path
+///
+/// # Returns
+/// * `bool` - true if the chunk or frame is to be dropped
+#[cfg(feature = "fault-injection")]
+pub async fn inject(slot: &FaultSlot, path: FaultPath) -> bool {
+    use rand::Rng;
+
+    let profile = slot.read().ok().and_then(|profile| profile.clone());
+    let Some(profile) = profile.filter(|profile| profile.covers(path)) else {
+        return false;
+    };
+    let (delay, dropped) = {
+        let mut rng = rand::thread_rng();
+        (profile.latency_ms + rng.gen_range(0..=profile.jitter_ms), rng.gen_bool(profile.drop_rate))
+    };
+    if delay > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+    }
+    dropped
+}
+
+#[cfg(not(feature = "fault-injection"))]
+pub async fn inject(_slot: &FaultSlot, _path: FaultPath) -> bool {
+    false
+}
+
+/// Passes a shell's input through the faults of its SSH leg
+#[cfg(feature = "fault-injection")]
+pub fn relay(mut input_rx: mpsc::Receiver<Bytes>, slot: FaultSlot) -> mpsc::Receiver<Bytes> {
+    let (input_tx, relayed) = mpsc::channel::<Bytes>(32);
+    tokio::spawn(async move {
+        while let Some(data) = input_rx.recv().await {
+            if inject(&slot, FaultPath::Ssh).await {
+                continue;
+            }
+            if input_tx.send(data).await.is_err() {
+                break;
+            }
+        }
+    });
+    relayed
+}
+
+#[cfg(feature = "fault-injection")]
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// The faults slot of a session's shell, which exists once a WebSocket has attached
+#[cfg(feature = "fault-injection")]
+async fn session_slot(state: &AppState, session_id: &str) -> Result<FaultSlot, Response> {
+    match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => match &session_info.stream {
+            Some(stream) => Ok(stream.faults()),
+            None => Err(error_response(StatusCode::CONFLICT, "no_shell",
+                format!("Session '{}' has no shell yet; attach a WebSocket first", session_id))),
+        },
+        None => Err(error_response(StatusCode::NOT_FOUND, "session_not_found",
+            format!("Session '{}' not found", session_id))),
+    }
+}
+
+/// Lists the sessions with faults injected
+#[cfg(feature = "fault-injection")]
+pub async fn list_handler(State(state): State<AppState>) -> Response {
+    let mut registry = state.session_registry.lock().await;
+    let mut sessions = Vec::new();
+    for session_id in registry.get_all_sessions() {
+        let profile = registry.get_session(&session_id)
+            .and_then(|session_info| session_info.stream.as_ref())
+            .and_then(|stream| stream.faults().read().ok().and_then(|profile| profile.clone()));
+        if let Some(profile) = profile {
+            sessions.push(json!({ "session_id": session_id, "faults": profile }));
+        }
+    }
+    Json(json!({ "sessions": sessions })).into_response()
+}
+
+/// Injects faults into a session, replacing any set before
+#[cfg(feature = "fault-injection")]
+pub async fn set_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Json(profile): Json<FaultProfile>,
+) -> Response {
+    let session_id = session_id.trim();
+    if let Err(e) = profile.validate() {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_fault_profile", e);
+    }
+    let slot = match session_slot(&state, session_id).await {
+        Ok(slot) => slot,
+        Err(response) => return response,
+    };
+    info!("[Session {}] Injecting faults: {} ms latency, {} ms jitter, {} drop rate",
+          session_id, profile.latency_ms, profile.jitter_ms, profile.drop_rate);
+    if let Ok(mut faults) = slot.write() {
+        *faults = Some(profile.clone());
+    }
+    Json(json!({ "session_id": session_id, "faults": profile })).into_response()
+}
+
+/// Stops injecting faults into a session
+#[cfg(feature = "fault-injection")]
+pub async fn clear_handler(State(state): State<AppState>, Path(session_id): Path<String>) -> Response {
+    let session_id = session_id.trim();
+    let slot = match session_slot(&state, session_id).await {
+        Ok(slot) => slot,
+        Err(response) => return response,
+    };
+    let cleared = slot.write().ok().and_then(|mut faults| faults.take()).is_some();
+    if cleared {
+        info!("[Session {}] Stopped injecting faults", session_id);
+    }
+    Json(json!({ "session_id": session_id, "cleared": cleared })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_limits_and_paths() {
+        let profile: FaultProfile = serde_json::from_str(r#"{"latency_ms": 200, "paths": ["ssh"]}"#).unwrap();
+        assert!(profile.validate().is_ok());
+        assert!(profile.covers(FaultPath::Ssh));
+        assert!(!profile.covers(FaultPath::Websocket));
+        assert!(FaultProfile::default().covers(FaultPath::Websocket));
+
+        assert!(FaultProfile { drop_rate: 1.5, ..FaultProfile::default() }.validate().is_err());
+        assert!(FaultProfile { drop_rate: f64::NAN, ..FaultProfile::default() }.validate().is_err());
+        assert!(FaultProfile { latency_ms: 50_000, jitter_ms: 20_000, ..FaultProfile::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use bytes::Bytes;
+use futures::stream;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path as FsPath, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{broadcast, Mutex};
+use tracing::{error, info, warn};
+
+use crate::session::SessionRegistry;
+use crate::settings::FileServerSettings;
+use crate::tftp;
+use crate::AppState;
+
+/// Size of the chunks read from files served over HTTP
+const CHUNK_SIZE: usize = 32 * 1024;
+
+#[derive(Debug, Error)]
+pub enum FileServerError {
+    #[error("File not found")]
+    NotFound,
+    #[error("Access denied")]
+    AccessDenied,
+}
+
+/// A device allowed to fetch files for as long as a session lasts
+#[derive(Debug, Clone)]
+pub struct DeviceAccess {
+    pub addresses: Vec<IpAddr>,
+    pub path_prefix: Option<PathBuf>,
+}
+
+/// Serves the staging area to devices over TFTP and HTTP
+///
+/// A device may fetch a file if a configured ACL entry covers its address, or
+/// if a session to it has enabled the file server. Transfers for a session's
+/// device are reported on that session's WebSocket.
+pub struct FileServer {
+    root: PathBuf,
+    acl: Vec<(IpNet, Option<PathBuf>)>,
+    registry: Arc<Mutex<SessionRegistry>>,
+    pub settings: FileServerSettings,
+}
+
+/// A file a device may fetch
+pub struct Grant {
+    pub path: PathBuf,
+    pub size: u64,
+    pub transfer: Transfer,
+}
+
+impl FileServer {
+    /// Prepares the staging area and parses the ACL
+    ///
+    /// # Returns
+    /// * `Result<Self, String>` - The server, or a description of the configuration problem
+    pub fn new(settings: &FileServerSettings, registry: Arc<Mutex<SessionRegistry>>) -> Result<Self, String> {
+        std::fs::create_dir_all(&settings.directory)
+            .map_err(|e| format!("Cannot create staging area {}: {}", settings.directory, e))?;
+        let root = std::fs::canonicalize(&settings.directory)
+            .map_err(|e| format!("Cannot resolve staging area {}: {}", settings.directory, e))?;
+
+        let acl = settings.acl.iter()
+            .map(|entry| {
+                let source = parse_source(&entry.source)
+                    .ok_or_else(|| format!("Invalid file server ACL source '{}'", entry.source))?;
+                let prefix = entry.path_prefix.as_deref().map(|prefix| sanitize(prefix)
+                    .ok_or_else(|| format!("Invalid file server ACL path prefix '{}'", prefix)))
+                    .transpose()?;
+                Ok((source, prefix))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self { root, acl, registry, settings: settings.clone() })
+    }
+
+    /// Checks that the client may fetch the requested file and locates it
+    pub async fn authorize(&self, protocol: &'static str, client: IpAddr, requested: &str) -> Result<Grant, FileServerError> {
+        let Some(relative) = sanitize(requested) else {
+            warn!("{} request from {} for invalid path '{}'", protocol, client, requested);
+            return Err(FileServerError::AccessDenied);
+        };
+
+        let allowed_by_acl = self.acl.iter()
+            .any(|(source, prefix)| source.contains(&client) && covers(prefix, &relative));
+        let sessions: Vec<(String, broadcast::Sender<serde_json::Value>)> = self.registry.lock().await
+            .sessions
+            .iter()
+            .filter(|(_, session_info)| session_info.file_access.as_ref().is_some_and(|access|
+                access.addresses.contains(&client) && covers(&access.path_prefix, &relative)))
+            .map(|(session_id, session_info)| (session_id.clone(), session_info.notifications.clone()))
+            .collect();
+
+        if !allowed_by_acl && sessions.is_empty() {
+            warn!("{} request from {} for {} denied", protocol, client, relative.display());
+            return Err(FileServerError::AccessDenied);
+        }
+
+        // Symlinks must not lead out of the staging area
+        let path = tokio::fs::canonicalize(self.root.join(&relative)).await
+            .map_err(|_| FileServerError::NotFound)?;
+        if !path.starts_with(&self.root) {
+            warn!("{} request from {} for {} leaves the staging area", protocol, client, relative.display());
+            return Err(FileServerError::AccessDenied);
+        }
+        let metadata = tokio::fs::metadata(&path).await.map_err(|_| FileServerError::NotFound)?;
+        if !metadata.is_file() {
+            return Err(FileServerError::NotFound);
+        }
+
+        let session_ids: Vec<&str> = sessions.iter().map(|(session_id, _)| session_id.as_str()).collect();
+        info!("{} transfer of {} to {} started (sessions: {})", protocol, relative.display(), client,
+              if session_ids.is_empty() { "none".to_string() } else { session_ids.join(", ") });
+
+        Ok(Grant {
+            path,
+            size: metadata.len(),
+            transfer: Transfer {
+                protocol,
+                path: relative.to_string_lossy().into_owned(),
+                client,
+                notifications: sessions.into_iter().map(|(_, notifications)| notifications).collect(),
+                bytes_transferred: 0,
+                finished: false,
+            },
+        })
+    }
+}
+
+/// A file being sent to a device, reported when it ends
+pub struct Transfer {
+    protocol: &'static str,
+    path: String,
+    client: IpAddr,
+    notifications: Vec<broadcast::Sender<serde_json::Value>>,
+    bytes_transferred: u64,
+    finished: bool,
+}
+
+impl Transfer {
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes_transferred += bytes;
+    }
+
+    /// Logs the outcome and publishes it to the sessions of the device
+    pub fn finish(&mut self, result: Result<(), String>) {
+        self.finished = true;
+        match &result {
+            Ok(()) => info!("{} transfer of {} to {} completed ({} bytes)",
+                            self.protocol, self.path, self.client, self.bytes_transferred),
+            Err(e) => error!("{} transfer of {} to {} failed after {} bytes: {}",
+                             self.protocol, self.path, self.client, self.bytes_transferred, e),
+        }
+        for notifications in &self.notifications {
+            let _ = notifications.send(json!({
+                "type": "file_server_transfer",
+                "protocol": self.protocol,
+                "path": self.path,
+                "client": self.client,
+                "bytes_transferred": self.bytes_transferred,
+                "complete": result.is_ok(),
+                "error": result.as_ref().err(),
+            }));
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish(Err("Transfer aborted".to_string()));
+        }
+    }
+}
+
+/// Normalizes a requested path to one relative to the staging area
+///
+/// Leading slashes are ignored, as devices often send them; anything
+/// climbing out of the staging area is rejected.
+pub fn sanitize(requested: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in FsPath::new(requested.trim()).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+fn covers(prefix: &Option<PathBuf>, path: &FsPath) -> bool {
+    match prefix {
+        Some(prefix) => path.starts_with(prefix),
+        None => true,
+    }
+}
+
+fn parse_source(source: &str) -> Option<IpNet> {
+    source.parse::<IpNet>().ok()
+        .or_else(|| source.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Starts the TFTP and HTTP listeners in the background
+pub async fn start(server: Arc<FileServer>) {
+    let settings = &server.settings;
+    info!("Serving staging area {} to devices", server.root.display());
+
+    let tftp_addr = format!("{}:{}", settings.address, settings.tftp_port);
+    match tokio::net::UdpSocket::bind(&tftp_addr).await {
+        Ok(socket) => {
+            info!("TFTP server listening on {}", tftp_addr);
+            tokio::spawn(tftp::serve(socket, server.clone()));
+        }
+        Err(e) => error!("Failed to bind TFTP server to {}: {}", tftp_addr, e),
+    }
+
+    let http_addr = format!("{}:{}", settings.address, settings.http_port);
+    match tokio::net::TcpListener::bind(&http_addr).await {
+        Ok(listener) => {
+            info!("HTTP file server listening on {}", http_addr);
+            let app = Router::new()
+                .route("/*path", get(http_handler))
+                .with_state(server.clone());
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+                    error!("HTTP file server failed: {}", e);
+                }
+            });
+        }
+        Err(e) => error!("Failed to bind HTTP file server to {}: {}", http_addr, e),
+    }
+}
+
+/// Serves a file from the staging area to a device
+async fn http_handler(
+    State(server): State<Arc<FileServer>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+) -> Response {
+    let grant = match server.authorize("HTTP", client.ip(), &path).await {
+        Ok(grant) => grant,
+        Err(FileServerError::NotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(FileServerError::AccessDenied) => return StatusCode::FORBIDDEN.into_response(),
+    };
+    let file = match tokio::fs::File::open(&grant.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open {}: {}", grant.path.display(), e);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+
+    let size = grant.size;
+    let body = stream::unfold(Some((file, grant.transfer)), |state| async move {
+        let (mut file, mut transfer) = state?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => {
+                transfer.finish(Ok(()));
+                None
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                transfer.add(n as u64);
+                Some((Ok(Bytes::from(buf)), Some((file, transfer))))
+            }
+            Err(e) => {
+                transfer.finish(Err(e.to_string()));
+                Some((Err(e), None))
+            }
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        Body::from_stream(body),
+    ).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EnableRequest {
+    /// Limits the device to files under this path of the staging area
+    pub path_prefix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileServerStatus {
+    pub enabled: bool,
+    /// Device addresses allowed to fetch files
+    pub addresses: Vec<IpAddr>,
+    pub path_prefix: Option<String>,
+    pub tftp_port: u16,
+    pub http_port: u16,
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Lets the session's device fetch files from the staging area while the session lasts
+pub async fn enable_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    request: Option<Json<EnableRequest>>,
+) -> Response {
+    let settings = &state.settings.file_server;
+    if !settings.enabled {
+        return error_response(StatusCode::CONFLICT, "file_server_disabled",
+                              "The file server is not enabled on this instance".to_string());
+    }
+    let Json(request) = request.unwrap_or_default();
+    let path_prefix = match request.path_prefix.as_deref().map(|prefix| sanitize(prefix).ok_or(prefix)).transpose() {
+        Ok(prefix) => prefix,
+        Err(prefix) => return error_response(StatusCode::BAD_REQUEST, "invalid_path",
+                                             format!("Invalid path prefix '{}'", prefix)),
+    };
+
+    let session_id = session_id.trim();
+    let target = match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => session_info.ssh_session.target().clone(),
+        None => return error_response(StatusCode::NOT_FOUND, "session_not_found",
+                                      format!("Session '{}' not found", session_id)),
+    };
+
+    // The device fetches from its own address, which is what the session connected to
+    let addresses: Vec<IpAddr> = match crate::ssh::host::lookup(&target.hostname, target.port).await {
+        Ok(addresses) => addresses.iter().map(|address| address.ip()).collect(),
+        Err(e) => return error_response(StatusCode::BAD_GATEWAY, "resolve_failed",
+                                        format!("Cannot resolve {}: {}", target.hostname, e)),
+    };
+
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        return error_response(StatusCode::NOT_FOUND, "session_not_found",
+                              format!("Session '{}' not found", session_id));
+    };
+    info!("File server enabled for session {} (device addresses: {:?})", session_id, addresses);
+    session_info.file_access = Some(DeviceAccess { addresses: addresses.clone(), path_prefix: path_prefix.clone() });
+
+    Json(FileServerStatus {
+        enabled: true,
+        addresses,
+        path_prefix: path_prefix.map(|prefix| prefix.to_string_lossy().into_owned()),
+        tftp_port: settings.tftp_port,
+        http_port: settings.http_port,
+    }).into_response()
+}
+
+/// Withdraws the session's device access to the staging area
+pub async fn disable_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        return error_response(StatusCode::NOT_FOUND, "session_not_found",
+                              format!("Session '{}' not found", session_id));
+    };
+    if session_info.file_access.take().is_some() {
+        info!("File server disabled for session {}", session_id);
+    }
+
+    let settings = &state.settings.file_server;
+    Json(FileServerStatus {
+        enabled: false,
+        addresses: Vec::new(),
+        path_prefix: None,
+        tftp_port: settings.tftp_port,
+        http_port: settings.http_port,
+    }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_stays_in_staging_area() {
+        assert_eq!(sanitize("/images/ios.bin"), Some(PathBuf::from("images/ios.bin")));
+        assert_eq!(sanitize("./ios.bin"), Some(PathBuf::from("ios.bin")));
+        assert_eq!(sanitize("../etc/passwd"), None);
+        assert_eq!(sanitize("images/../../etc/passwd"), None);
+        assert_eq!(sanitize("/"), None);
+    }
+
+    #[test]
+    fn test_acl_prefix_matches_whole_components() {
+        let prefix = Some(PathBuf::from("cisco"));
+        assert!(covers(&prefix, FsPath::new("cisco/ios.bin")));
+        assert!(!covers(&prefix, FsPath::new("cisco-old/ios.bin")));
+        assert!(covers(&None, FsPath::new("anything.bin")));
+
+        assert!(parse_source("10.0.0.0/8").unwrap().contains(&"10.1.2.3".parse::<IpAddr>().unwrap()));
+        assert!(parse_source("192.0.2.7").unwrap().contains(&"192.0.2.7".parse::<IpAddr>().unwrap()));
+        assert!(parse_source("not-an-address").is_none());
+    }
+}
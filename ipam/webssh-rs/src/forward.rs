@@ -0,0 +1,316 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::ssh::forward::{self, ForwardDirection, ForwardSpec, ForwardStats};
+use crate::ssh::socks::DestinationFilter;
+use crate::AppState;
+
+/// A port forward running over a session
+pub struct Forward {
+    spec: ForwardSpec,
+    created_at: DateTime<Utc>,
+    stats: Arc<ForwardStats>,
+    // Cancelled to close the forward; cancelled by the pump too once it has stopped
+    shutdown: CancellationToken,
+}
+
+/// State of a forward, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct ForwardStatus {
+    pub id: String,
+    pub direction: ForwardDirection,
+    pub bind_host: String,
+    pub bind_port: u16,
+    /// Missing for SOCKS proxies, whose clients choose the target of each connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_port: Option<u16>,
+    pub created_at: DateTime<Utc>,
+    pub active: bool,
+    pub connections: u64,
+    pub bytes_to_device: u64,
+    pub bytes_from_device: u64,
+}
+
+/// The port forwards of a session, closed together with it
+#[derive(Default)]
+pub struct ForwardRegistry {
+    forwards: HashMap<String, Forward>,
+}
+
+impl ForwardRegistry {
+    /// Number of forwards still running
+    pub fn active(&self) -> usize {
+        self.forwards.values().filter(|forward| !forward.shutdown.is_cancelled()).count()
+    }
+
+    /// Adds a forward, forgetting those that have stopped
+    pub fn insert(&mut self, id: &str, forward: Forward) {
+        self.forwards.retain(|_, forward| !forward.shutdown.is_cancelled());
+        self.forwards.insert(id.to_string(), forward);
+    }
+
+    /// Closes and removes a forward
+    ///
+    /// # Returns
+    /// * `bool` - true if the forward existed
+    pub fn remove(&mut self, id: &str) -> bool {
+        match self.forwards.remove(id) {
+            Some(forward) => {
+                forward.shutdown.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes every forward
+    pub fn close_all(&mut self) {
+        for (id, forward) in self.forwards.drain() {
+            if !forward.shutdown.is_cancelled() {
+                info!("Closing port forward {}", id);
+                forward.shutdown.cancel();
+            }
+        }
+    }
+
+    /// Gets the state of all forwards, oldest first
+    pub fn list(&self) -> Vec<ForwardStatus> {
+        let mut forwards: Vec<ForwardStatus> = self.forwards.iter()
+            .map(|(id, forward)| forward.status(id))
+            .collect();
+        forwards.sort_by_key(|forward| forward.created_at);
+        forwards
+    }
+
+    /// Gets the state of the SOCKS proxies, oldest first
+    pub fn list_socks(&self) -> Vec<ForwardStatus> {
+        self.list().into_iter().filter(|forward| forward.direction == ForwardDirection::Dynamic).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<ForwardStatus> {
+        self.forwards.get(id).map(|forward| forward.status(id))
+    }
+}
+
+impl Forward {
+    fn status(&self, id: &str) -> ForwardStatus {
+        ForwardStatus {
+            id: id.to_string(),
+            direction: self.spec.direction,
+            bind_host: self.spec.bind_host.clone(),
+            bind_port: self.spec.bind_port,
+            target_host: Some(self.spec.target_host.clone()).filter(|host| !host.is_empty()),
+            target_port: Some(self.spec.target_port).filter(|port| *port != 0),
+            created_at: self.created_at,
+            active: !self.shutdown.is_cancelled(),
+            connections: self.stats.connections.load(Ordering::Relaxed),
+            bytes_to_device: self.stats.bytes_to_device.load(Ordering::Relaxed),
+            bytes_from_device: self.stats.bytes_from_device.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Body of a request to open a forward
+#[derive(Debug, Deserialize)]
+pub struct ForwardRequest {
+    pub direction: ForwardDirection,
+    /// Remote forwards only: address the device listens on; defaults to localhost
+    pub bind_host: Option<String>,
+    /// Port to listen on; 0 or absent lets the listening side choose
+    #[serde(default)]
+    pub bind_port: u16,
+    pub target_host: String,
+    pub target_port: u16,
+}
+
+/// Body of a request to open a SOCKS proxy
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SocksRequest {
+    /// Port to listen on; 0 or absent lets the gateway choose
+    pub bind_port: u16,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForwardListResponse {
+    forwards: Vec<ForwardStatus>,
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn session_not_found(session_id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id))
+}
+
+/// Opens a port forward over the session's device connection
+pub async fn create_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Json(request): Json<ForwardRequest>,
+) -> Response {
+    let policy_settings = state.policy.settings();
+    let settings = &policy_settings.forwarding;
+    if !settings.enabled {
+        return error_response(StatusCode::CONFLICT, "forwarding_disabled",
+                              "Port forwarding is not enabled on this instance".to_string());
+    }
+    if request.direction == ForwardDirection::Remote && !settings.allow_remote {
+        return error_response(StatusCode::FORBIDDEN, "remote_forwarding_disabled",
+                              "Remote port forwarding is not allowed on this instance".to_string());
+    }
+    if request.direction == ForwardDirection::Dynamic {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_direction",
+                              format!("SOCKS proxies are opened with POST /api/session/{}/socks", session_id.trim()));
+    }
+    if request.target_host.trim().is_empty() || request.target_port == 0 {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_target",
+                              "A target host and port are required".to_string());
+    }
+
+    // Local forwards always listen where the configuration allows, never wider
+    let bind_host = match request.direction {
+        ForwardDirection::Remote => request.bind_host.unwrap_or_else(|| "localhost".to_string()),
+        _ => settings.bind_address.clone(),
+    };
+    let spec = ForwardSpec {
+        direction: request.direction,
+        bind_host,
+        bind_port: request.bind_port,
+        target_host: request.target_host.trim().to_string(),
+        target_port: request.target_port,
+        socks: None,
+    };
+
+    open(&state, session_id.trim(), spec, settings.max_per_session).await
+}
+
+/// Opens a SOCKS5 proxy whose connections are made from the session's device
+pub async fn socks_create_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    request: Option<Json<SocksRequest>>,
+) -> Response {
+    let policy_settings = state.policy.settings();
+    let settings = &policy_settings.forwarding;
+    if !settings.enabled || !settings.socks.enabled {
+        return error_response(StatusCode::CONFLICT, "socks_disabled",
+                              "SOCKS proxies are not enabled on this instance".to_string());
+    }
+    if let Err(e) = DestinationFilter::new(&settings.socks) {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "invalid_configuration", e);
+    }
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let spec = ForwardSpec {
+        direction: ForwardDirection::Dynamic,
+        bind_host: settings.bind_address.clone(),
+        bind_port: request.bind_port,
+        target_host: String::new(),
+        target_port: 0,
+        socks: Some(settings.socks.clone()),
+    };
+    open(&state, session_id.trim(), spec, settings.max_per_session).await
+}
+
+/// Lists the session's SOCKS proxies
+pub async fn socks_list_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => Json(ForwardListResponse { forwards: session_info.forwards.list_socks() }).into_response(),
+        None => session_not_found(session_id),
+    }
+}
+
+/// Starts a forward over a connection of its own to the session's device and registers it
+async fn open(state: &AppState, session_id: &str, mut spec: ForwardSpec, max_per_session: usize) -> Response {
+    let (target, shutdown) = match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) if session_info.forwards.active() >= max_per_session => {
+            return error_response(StatusCode::CONFLICT, "too_many_forwards",
+                                  format!("Sessions may have at most {} port forwards", max_per_session));
+        }
+        Some(session_info) => (
+            session_info.ssh_session.target().clone(),
+            session_info.ssh_session.shutdown_token().child_token(),
+        ),
+        None => return session_not_found(session_id),
+    };
+
+    let stats = Arc::new(ForwardStats::default());
+    let open_spec = spec.clone();
+    let open_stats = stats.clone();
+    let open_shutdown = shutdown.clone();
+    let opened = tokio::task::spawn_blocking(move || {
+        forward::open(&target, &open_spec, open_stats, open_shutdown)
+    }).await;
+    spec.bind_port = match opened {
+        Ok(Ok(port)) => port,
+        Ok(Err(e)) => {
+            error!("Port forward for session {} failed: {}", session_id, e);
+            return error_response(StatusCode::BAD_GATEWAY, "forward_failed", e.to_string());
+        }
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "forward_failed", e.to_string()),
+    };
+
+    // The session may have closed while the forward was being opened
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        shutdown.cancel();
+        return session_not_found(session_id);
+    };
+    let id = uuid::Uuid::new_v4().to_string();
+    info!("Port forward {} opened for session {}", id, session_id);
+    session_info.forwards.insert(&id, Forward { spec, created_at: Utc::now(), stats, shutdown });
+
+    (StatusCode::CREATED, Json(session_info.forwards.get(&id))).into_response()
+}
+
+/// Lists the session's port forwards
+pub async fn list_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => Json(ForwardListResponse { forwards: session_info.forwards.list() }).into_response(),
+        None => session_not_found(session_id),
+    }
+}
+
+/// Closes one of the session's port forwards
+pub async fn close_handler(
+    State(state): State<AppState>,
+    Path((session_id, forward_id)): Path<(String, String)>,
+) -> Response {
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        return session_not_found(session_id);
+    };
+    if session_info.forwards.remove(&forward_id) {
+        info!("Port forward {} closed for session {}", forward_id, session_id);
+        Json(json!({ "success": true, "message": format!("Port forward '{}' closed", forward_id) })).into_response()
+    } else {
+        error_response(StatusCode::NOT_FOUND, "forward_not_found", format!("Port forward '{}' not found", forward_id))
+    }
+}
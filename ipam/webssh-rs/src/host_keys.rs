@@ -0,0 +1,299 @@
+//! Trust on first use for devices' SSH host keys
+//!
+//! A device whose host key is not pinned is not connected to until the user
+//! has seen the key's fingerprint and confirmed it through
+//! `/api/connect/confirm`, which pins it. From then on the device must present
+//! the same key; a changed key is refused until an admin removes the pin.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ssh2::Session;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+use crate::settings::HostKeySettings;
+use crate::ssh::agent::fingerprint;
+use crate::ssh::error::SSHError;
+use crate::AppState;
+
+/// A host key as presented by a device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresentedKey {
+    /// e.g. `ssh-ed25519` or `ecdsa-sha2-nistp256`
+    pub key_type: String,
+    /// As OpenSSH shows it, e.g. `SHA256:H+TE7...`
+    pub fingerprint: String,
+    /// `host:port` of the jump host that presented the key, when it was not the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jump_host: Option<String>,
+}
+
+impl PresentedKey {
+    /// Reads the key type from a public key blob, which starts with it
+    pub fn from_blob(blob: &[u8]) -> Self {
+        let key_type = blob.get(..4)
+            .and_then(|len| Some(u32::from_be_bytes(len.try_into().ok()?) as usize))
+            .and_then(|len| blob.get(4..4 + len))
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .unwrap_or_else(|| "unknown".to_string());
+        Self { key_type, fingerprint: fingerprint(blob), jump_host: None }
+    }
+
+    /// Who presented the key, for messages
+    pub fn presenter(&self) -> String {
+        match &self.jump_host {
+            Some(jump_host) => format!("jump host {}", jump_host),
+            None => "the device".to_string(),
+        }
+    }
+}
+
+/// A device's host key, as confirmed by a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedKey {
+    pub hostname: String,
+    pub port: u16,
+    pub key_type: String,
+    pub fingerprint: String,
+    pub pinned_at: DateTime<Utc>,
+    /// The portal user who confirmed the key
+    pub pinned_by: String,
+}
+
+/// Pinned host keys persisted to a JSON file
+pub struct HostKeys {
+    path: PathBuf,
+    pins: Mutex<Vec<PinnedKey>>,
+}
+
+impl HostKeys {
+    /// Loads the pins from the file configured in settings
+    ///
+    /// A missing file has no pins yet. One that cannot be read or parsed is
+    /// an error rather than an empty trust store, which the next pin would
+    /// save over every other.
+    pub fn load(settings: &HostKeySettings) -> std::io::Result<Self> {
+        let path = PathBuf::from(&settings.pins_file);
+        let pins = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<Vec<PinnedKey>>(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        info!("Loaded {} pinned host keys from {}", pins.len(), path.display());
+        Ok(Self { path, pins: Mutex::new(pins) })
+    }
+
+    pub fn list(&self) -> Vec<PinnedKey> {
+        self.lock().clone()
+    }
+
+    /// The key pinned for a device, if any
+    pub fn pinned(&self, hostname: &str, port: u16) -> Option<PinnedKey> {
+        self.lock().iter().find(|pin| pin.hostname == device(hostname) && pin.port == port).cloned()
+    }
+
+    /// Pins a device's key, replacing any pinned before
+    pub fn pin(&self, hostname: &str, port: u16, key: &PresentedKey, pinned_by: &str) -> std::io::Result<()> {
+        let mut pins = self.lock();
+        pins.retain(|pin| !(pin.hostname == device(hostname) && pin.port == port));
+        pins.push(PinnedKey {
+            hostname: device(hostname),
+            port,
+            key_type: key.key_type.clone(),
+            fingerprint: key.fingerprint.clone(),
+            pinned_at: Utc::now(),
+            pinned_by: pinned_by.to_string(),
+        });
+        self.save(&pins)
+    }
+
+    /// Removes a device's pin, so that its next key is confirmed anew
+    pub fn remove(&self, hostname: &str, port: u16) -> std::io::Result<bool> {
+        let mut pins = self.lock();
+        let count = pins.len();
+        pins.retain(|pin| !(pin.hostname == device(hostname) && pin.port == port));
+        if pins.len() == count {
+            return Ok(false);
+        }
+        self.save(&pins)?;
+        Ok(true)
+    }
+
+    fn save(&self, pins: &[PinnedKey]) -> std::io::Result<()> {
+        let contents = serde_json::to_string_pretty(pins)?;
+        // Write to a temporary file first so a crash never leaves a truncated file
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<PinnedKey>> {
+        self.pins.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Devices are pinned by hostname as written, in lowercase
+fn device(hostname: &str) -> String {
+    hostname.trim().to_ascii_lowercase()
+}
+
+/// Checks the host key a device presents during the handshake against its pin
+#[derive(Clone)]
+pub struct HostKeyCheck {
+    pub keys: Arc<HostKeys>,
+    /// The fingerprint the user confirmed, pinned if the device presents that key
+    pub confirmed: Option<String>,
+    /// The portal user connecting, recorded with a key they confirm
+    pub portal_user_id: String,
+}
+
+impl std::fmt::Debug for HostKeyCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostKeyCheck")
+            .field("confirmed", &self.confirmed)
+            .field("portal_user_id", &self.portal_user_id)
+            .finish()
+    }
+}
+
+impl HostKeyCheck {
+    /// Accepts a pinned key, or an unpinned one the user has confirmed, which is pinned
+    pub fn verify(&self, hostname: &str, port: u16, session: &Session) -> Result<(), SSHError> {
+        let Some((blob, _)) = session.host_key() else {
+            return Err(SSHError::Connection(std::io::Error::other("The device presented no host key")));
+        };
+        self.verify_key(hostname, port, PresentedKey::from_blob(blob))
+    }
+
+    /// Accepts or refuses the key a device presented during the handshake
+    pub fn verify_key(&self, hostname: &str, port: u16, presented: PresentedKey) -> Result<(), SSHError> {
+        match self.keys.pinned(hostname, port) {
+            Some(pin) if pin.fingerprint == presented.fingerprint => Ok(()),
+            Some(pin) => {
+                warn!("Host key of {}:{} has changed: presented {} {}, pinned {}",
+                      hostname, port, presented.key_type, presented.fingerprint, pin.fingerprint);
+                Err(SSHError::HostKeyChanged { presented, pinned: pin.fingerprint })
+            }
+            None if self.confirmed.as_deref().map(normalize) == Some(presented.fingerprint.clone()) => {
+                info!("Portal user {} confirmed host key {} {} of {}:{}",
+                      self.portal_user_id, presented.key_type, presented.fingerprint, hostname, port);
+                if let Err(e) = self.keys.pin(hostname, port, &presented, &self.portal_user_id) {
+                    error!("Failed to save the pinned host key of {}:{}: {}", hostname, port, e);
+                }
+                Ok(())
+            }
+            None => {
+                info!("Host key {} {} of {}:{} is not pinned; waiting for confirmation",
+                      presented.key_type, presented.fingerprint, hostname, port);
+                Err(SSHError::HostKeyUnconfirmed(presented))
+            }
+        }
+    }
+}
+
+/// Puts a fingerprint given with or without its `SHA256:` prefix in the form it is compared in
+fn normalize(fingerprint: &str) -> String {
+    let fingerprint = fingerprint.trim();
+    match fingerprint.strip_prefix("SHA256:") {
+        Some(_) => fingerprint.to_string(),
+        None => format!("SHA256:{}", fingerprint),
+    }
+}
+
+/// The pinned host keys
+pub async fn list_handler(State(state): State<AppState>) -> Response {
+    match &state.host_keys {
+        Some(keys) => Json(keys.list()).into_response(),
+        None => disabled(),
+    }
+}
+
+/// Removes a device's pinned key, e.g. after the device was replaced
+pub async fn remove_handler(
+    State(state): State<AppState>,
+    Path((hostname, port)): Path<(String, u16)>,
+) -> Response {
+    let Some(keys) = &state.host_keys else {
+        return disabled();
+    };
+    match keys.remove(&hostname, port) {
+        Ok(true) => {
+            info!("Removed the pinned host key of {}:{}", hostname, port);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, Json(json!({
+            "error": "host_key_not_found",
+            "message": format!("No host key is pinned for {}:{}", hostname, port),
+        }))).into_response(),
+        Err(e) => {
+            error!("Failed to save host key pins: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "error": "host_keys_unavailable",
+                "message": e.to_string(),
+            }))).into_response()
+        }
+    }
+}
+
+fn disabled() -> Response {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "error": "host_keys_disabled",
+        "message": "Host key confirmation is not enabled",
+    }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pins_are_kept_per_device_and_port() {
+        let path = std::env::temp_dir().join(format!("webssh-host-keys-{}.json", uuid::Uuid::new_v4()));
+        let settings = HostKeySettings { enabled: true, pins_file: path.display().to_string() };
+        let keys = HostKeys::load(&settings).unwrap();
+        let key = PresentedKey::from_blob(b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert!(key.fingerprint.starts_with("SHA256:"));
+
+        keys.pin("Router1", 22, &key, "alice").unwrap();
+        assert_eq!(keys.pinned("router1", 22).unwrap().fingerprint, key.fingerprint);
+        assert!(keys.pinned("router1", 2222).is_none());
+
+        // Pins outlive a restart
+        let reloaded = HostKeys::load(&settings).unwrap();
+        assert_eq!(reloaded.pinned("router1", 22).unwrap().pinned_by, "alice");
+        assert!(reloaded.remove("router1", 22).unwrap());
+        assert!(!reloaded.remove("router1", 22).unwrap());
+        assert!(HostKeys::load(&settings).unwrap().list().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_corrupt_pins_file_is_not_loaded() {
+        let path = std::env::temp_dir().join(format!("webssh-host-keys-{}.json", uuid::Uuid::new_v4()));
+        let settings = HostKeySettings { enabled: true, pins_file: path.display().to_string() };
+        fs::write(&path, "[{\"hostname\": \"router1\",").unwrap();
+
+        let e = HostKeys::load(&settings).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        // The file is left for an admin to repair
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[{\"hostname\": \"router1\",");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_confirmed_fingerprints_may_leave_out_the_prefix() {
+        assert_eq!(normalize(" H+TE7BOY0Thf "), "SHA256:H+TE7BOY0Thf");
+        assert_eq!(normalize("SHA256:H+TE7BOY0Thf"), "SHA256:H+TE7BOY0Thf");
+    }
+}
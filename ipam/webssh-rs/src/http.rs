@@ -0,0 +1,182 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::settings::HttpSettings;
+use crate::AppState;
+
+/// Address of the client behind a request, after any trusted proxies
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The `http` settings, checked and parsed at startup
+pub struct HttpPolicy {
+    origins: Vec<HeaderValue>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age: Duration,
+    trusted_proxies: Vec<IpNet>,
+    forwarded_header: HeaderName,
+    pub max_body_bytes: usize,
+    pub max_upload_bytes: usize,
+}
+
+impl HttpPolicy {
+    /// Parses the origins, methods, headers and proxy ranges
+    ///
+    /// # Returns
+    /// * `Result<Self, String>` - The policy, or a description of the configuration problem
+    pub fn from_settings(settings: &HttpSettings) -> Result<Self, String> {
+        let any_origin = settings.cors.allowed_origins.iter().any(|origin| origin == "*");
+        if any_origin && settings.cors.allowed_origins.len() > 1 {
+            return Err("http.cors.allowed_origins cannot list origins alongside \"*\"".to_string());
+        }
+        let origins = settings.cors.allowed_origins.iter()
+            .filter(|origin| *origin != "*")
+            .map(|origin| {
+                let valid = (origin.starts_with("http://") || origin.starts_with("https://"))
+                    && !origin.ends_with('/');
+                match HeaderValue::from_str(origin) {
+                    Ok(value) if valid => Ok(value),
+                    _ => Err(format!("Invalid CORS origin '{}'; expected scheme://host[:port]", origin)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let methods = settings.cors.allowed_methods.iter()
+            .map(|method| Method::from_bytes(method.to_uppercase().as_bytes())
+                .map_err(|_| format!("Invalid CORS method '{}'", method)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = settings.cors.allowed_headers.iter()
+            .map(|header| HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format!("Invalid CORS header '{}'", header)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let trusted_proxies = settings.trusted_proxies.iter()
+            .map(|proxy| proxy.parse::<IpNet>()
+                .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid trusted proxy '{}'; expected an address or CIDR range", proxy)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let forwarded_header = HeaderName::from_bytes(settings.forwarded_header.as_bytes())
+            .map_err(|_| format!("Invalid forwarded header name '{}'", settings.forwarded_header))?;
+
+        Ok(Self {
+            origins,
+            any_origin,
+            methods,
+            headers,
+            max_age: Duration::from_secs(settings.cors.max_age_seconds),
+            trusted_proxies,
+            forwarded_header,
+            max_body_bytes: settings.max_body_bytes,
+            max_upload_bytes: settings.max_upload_bytes,
+        })
+    }
+
+    /// CORS for the configured origins; with none, cross-origin requests get no CORS headers
+    pub fn cors(&self, expose: HeaderName) -> CorsLayer {
+        let origin = if self.any_origin {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(self.origins.clone())
+        };
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(self.methods.clone())
+            .allow_headers(self.headers.clone())
+            .expose_headers([expose])
+            .max_age(self.max_age)
+    }
+
+    /// Finds the client behind a connection, trusting the forwarded header only from trusted proxies
+    ///
+    /// The header is read from the right, as each proxy appends the address it
+    /// received the request from; the first untrusted address is the client.
+    pub fn client_ip(&self, peer: IpAddr, forwarded: Option<&str>) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let Some(forwarded) = forwarded else {
+            return peer;
+        };
+        let mut client = peer;
+        for hop in forwarded.rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Middleware resolving the client address for the handlers and logs
+pub async fn resolve_client_ip(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let forwarded = request.headers().get(&state.http.forwarded_header)
+        .and_then(|value| value.to_str().ok());
+    let client = ClientIp(state.http.client_ip(peer.ip(), forwarded));
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip_trusts_only_configured_proxies() {
+        let settings = HttpSettings {
+            trusted_proxies: vec!["10.0.0.0/8".to_string(), "192.168.1.5".to_string()],
+            ..HttpSettings::default()
+        };
+        let policy = HttpPolicy::from_settings(&settings).unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // A direct client cannot claim another address
+        assert_eq!(policy.client_ip(ip("203.0.113.7"), Some("198.51.100.1")), ip("203.0.113.7"));
+        // Behind two trusted proxies, the spoofed leftmost entry is ignored
+        assert_eq!(policy.client_ip(ip("10.1.1.1"), Some("198.51.100.1, 203.0.113.7, 192.168.1.5")), ip("203.0.113.7"));
+        assert_eq!(policy.client_ip(ip("10.1.1.1"), None), ip("10.1.1.1"));
+        assert_eq!(policy.client_ip(ip("::ffff:10.1.1.1"), Some("garbage, 203.0.113.7")), ip("203.0.113.7"));
+
+        let mut invalid = HttpSettings::default();
+        invalid.cors.allowed_origins = vec!["*".to_string(), "https://ipam.example.com".to_string()];
+        assert!(HttpPolicy::from_settings(&invalid).is_err());
+        invalid.cors.allowed_origins = vec!["https://ipam.example.com/".to_string()];
+        assert!(HttpPolicy::from_settings(&invalid).is_err());
+        invalid.cors.allowed_origins = Vec::new();
+        invalid.trusted_proxies = vec!["10.0.0.0/33".to_string()];
+        assert!(HttpPolicy::from_settings(&invalid).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use rustls::pki_types::ServerName;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+
+/// Root bundle used for an HTTPS service when no CA file is set
+const SYSTEM_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Largest response read
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// Client of the HTTP services the gateway calls out to, such as Vault
+///
+/// Each request is made on a new connection, closed by the server once it
+/// has answered.
+pub struct HttpClient {
+    pub endpoint: Endpoint,
+    tls: Option<TlsConnector>,
+}
+
+pub struct Endpoint {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl HttpClient {
+    /// Parses the service's URL and loads the roots trusted for an HTTPS service
+    ///
+    /// # Arguments
+    /// * `url` - The service's URL; its path is kept as `endpoint.path`
+    /// * `ca_file` - PEM bundle of trusted roots; defaults to the system bundle
+    /// * `service` - What the service is, for error messages, e.g. "SSH CA"
+    pub fn new(url: &str, ca_file: Option<&str>, service: &str) -> Result<Self, String> {
+        let endpoint = Endpoint::parse(url).ok_or_else(|| format!("Invalid {} URL '{}'", service, url))?;
+        let tls = if endpoint.https {
+            Some(tls_connector(ca_file.unwrap_or(SYSTEM_CA_BUNDLE))?)
+        } else {
+            warn!("{} at {} is reached over plain HTTP", service, url);
+            None
+        };
+        Ok(Self { endpoint, tls })
+    }
+
+    /// Sends a request and reads the whole response
+    ///
+    /// # Arguments
+    /// * `method` - e.g. "GET"
+    /// * `path` - The request path, query included
+    /// * `headers` - Extra headers
+    /// * `body` - A JSON body, if any
+    ///
+    /// # Returns
+    /// * `std::io::Result<(u16, Vec<u8>)>` - The status and body of the response
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> std::io::Result<(u16, Vec<u8>)> {
+        let endpoint = &self.endpoint;
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, endpoint.host);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(body) = body {
+            request.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body.unwrap_or(""));
+
+        let response = exchange(self.connect().await?, request.as_bytes()).await?;
+        parse_response(&response)
+    }
+
+    /// Opens a connection to the service, over TLS for an HTTPS service
+    pub async fn connect(&self) -> std::io::Result<Box<dyn Connection>> {
+        let endpoint = &self.endpoint;
+        let stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?;
+        match &self.tls {
+            Some(tls) => {
+                let name = ServerName::try_from(endpoint.host.clone())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                Ok(Box::new(tls.connect(name, stream).await?))
+            }
+            None => Ok(Box::new(stream)),
+        }
+    }
+}
+
+/// A connection to a service, plain or over TLS
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Connection for S {}
+
+impl Endpoint {
+    fn parse(url: &str) -> Option<Self> {
+        let (https, rest) = match url.split_once("://")? {
+            ("https", rest) => (true, rest),
+            ("http", rest) => (false, rest),
+            _ => return None,
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        // An IPv6 address is bracketed, so its colons are not taken for the port
+        let port_separator = match authority.rfind(']') {
+            Some(end) => authority[end..].find(':').map(|index| end + index),
+            None => authority.rfind(':'),
+        };
+        let (host, port) = match port_separator {
+            Some(index) => (&authority[..index], authority[index + 1..].parse::<u16>().ok()?),
+            None => (authority, if https { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self { https, host: host.to_string(), port, path: path.to_string() })
+    }
+
+    /// The WebSocket URL of a path under the service's, e.g. `/ws/{session_id}?ws_token=...`
+    pub fn websocket_url(&self, path: &str) -> String {
+        format!("{}://{}:{}{}{}",
+            if self.https { "wss" } else { "ws" },
+            if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() },
+            self.port, self.path.trim_end_matches('/'), path)
+    }
+}
+
+/// The messages of a Vault error response's `errors` array
+pub fn vault_errors(response: &Value) -> String {
+    response["errors"].as_array()
+        .map(|errors| errors.iter().filter_map(Value::as_str).collect::<Vec<_>>().join("; "))
+        .filter(|message| !message.is_empty())
+        .unwrap_or_else(|| "no details".to_string())
+}
+
+fn tls_connector(bundle: &str) -> Result<TlsConnector, String> {
+    let pem = std::fs::read(bundle).map_err(|e| format!("Cannot read CA bundle {}: {}", bundle, e))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        let cert = cert.map_err(|e| format!("Invalid certificate in {}: {}", bundle, e))?;
+        roots.add(cert).map_err(|e| format!("Invalid certificate in {}: {}", bundle, e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("No certificates found in {}", bundle));
+    }
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Writes a request and reads the response until the server closes the connection
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_BYTES).read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+/// Splits an HTTP/1.1 response into its status and body, undoing chunked encoding
+fn parse_response(response: &[u8]) -> std::io::Result<(u16, Vec<u8>)> {
+    let malformed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed HTTP response");
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(malformed)?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+
+    let mut lines = head.lines();
+    let status = lines.next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(malformed)?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding") && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+    if !chunked {
+        return Ok((status, body.to_vec()));
+    }
+
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+        let line_end = rest.windows(2).position(|window| window == b"\r\n").ok_or_else(malformed)?;
+        let size = String::from_utf8_lossy(&rest[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16).map_err(|_| malformed())?;
+        if size == 0 {
+            break;
+        }
+        let chunk = rest.get(line_end + 2..line_end + 2 + size).ok_or_else(malformed)?;
+        decoded.extend_from_slice(chunk);
+        rest = rest.get(line_end + 4 + size..).ok_or_else(malformed)?;
+    }
+    Ok((status, decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n{\"a\":\r\n2\r\n1}\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response).unwrap(), (200, b"{\"a\":1}".to_vec()));
+        let response = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(response).unwrap(), (403, b"{}".to_vec()));
+    }
+}
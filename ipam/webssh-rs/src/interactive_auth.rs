@@ -0,0 +1,183 @@
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ssh2::{KeyboardInteractivePrompt, Prompt};
+use std::sync::mpsc as std_mpsc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::protocol::Framing;
+use crate::settings::BinaryProtocolSettings;
+
+/// A prompt shown to the user, e.g. "Verification code:"
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthPrompt {
+    pub prompt: String,
+    /// Whether the answer may be displayed while typed
+    pub echo: bool,
+}
+
+/// Progress of a keyboard-interactive authentication, as seen by the WebSocket
+#[derive(Debug)]
+pub enum AuthEvent {
+    Challenge { instructions: String, prompts: Vec<AuthPrompt> },
+    /// A secret the gateway needs to authenticate, e.g. a token's PIN; never echoed
+    SecretPrompt { prompt: String, purpose: &'static str },
+    Succeeded,
+    Failed(String),
+}
+
+/// The WebSocket end of a pending keyboard-interactive authentication
+pub struct AuthExchange {
+    events: mpsc::Receiver<AuthEvent>,
+    responses: std_mpsc::Sender<Vec<String>>,
+}
+
+/// Answers the server's prompts by relaying them to the attached WebSocket
+///
+/// Runs on the blocking thread performing the SSH connection; gives up with
+/// empty answers when the user does not respond in time.
+pub struct RelayPrompter {
+    events: mpsc::Sender<AuthEvent>,
+    responses: std_mpsc::Receiver<Vec<String>>,
+    timeout: Duration,
+}
+
+/// Creates the two ends of a keyboard-interactive relay
+pub fn relay_channel(timeout: Duration) -> (RelayPrompter, AuthExchange) {
+    let (events_tx, events_rx) = mpsc::channel(8);
+    let (responses_tx, responses_rx) = std_mpsc::channel();
+    (
+        RelayPrompter { events: events_tx, responses: responses_rx, timeout },
+        AuthExchange { events: events_rx, responses: responses_tx },
+    )
+}
+
+impl RelayPrompter {
+    /// Reports the outcome of the authentication to the WebSocket
+    pub fn finish(self, outcome: AuthEvent) {
+        let _ = self.events.blocking_send(outcome);
+    }
+
+    /// Asks the user for a secret, e.g. the PIN of the token holding their key
+    ///
+    /// # Returns
+    /// * `Option<String>` - The secret, or None if the user did not answer in time
+    pub fn secret(&mut self, prompt: &str, purpose: &'static str) -> Option<String> {
+        self.events.blocking_send(AuthEvent::SecretPrompt { prompt: prompt.to_string(), purpose }).ok()?;
+        match self.responses.recv_timeout(self.timeout) {
+            Ok(responses) => responses.into_iter().next(),
+            Err(_) => {
+                warn!("No answer to the {} prompt within {:?}", purpose, self.timeout);
+                None
+            }
+        }
+    }
+}
+
+impl KeyboardInteractivePrompt for RelayPrompter {
+    fn prompt<'a>(&mut self, _username: &str, instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        let challenge = AuthEvent::Challenge {
+            instructions: instructions.to_string(),
+            prompts: prompts.iter()
+                .map(|prompt| AuthPrompt { prompt: prompt.text.to_string(), echo: prompt.echo })
+                .collect(),
+        };
+        if self.events.blocking_send(challenge).is_err() {
+            return Vec::new();
+        }
+        // Informational rounds carry no prompts and expect no answers
+        if prompts.is_empty() {
+            return Vec::new();
+        }
+
+        match self.responses.recv_timeout(self.timeout) {
+            Ok(mut responses) => {
+                responses.resize(prompts.len(), String::new());
+                responses
+            }
+            Err(_) => {
+                warn!("No answer to keyboard-interactive prompts within {:?}", self.timeout);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// An answer from the client: `{"type": "auth_response", "responses": [...]}`,
+/// or `{"type": "secret_input", "data": "..."}` to a secret prompt
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum AuthCommand {
+    #[serde(rename = "auth_response")]
+    AuthResponse { responses: Vec<String> },
+    #[serde(rename = "secret_input")]
+    SecretInput { data: String },
+}
+
+/// Relays prompts to the WebSocket and answers back until authentication completes
+///
+/// Prompts are sent as `auth_prompt` messages, or `secret_prompt` for a secret
+/// such as a token's PIN; the outcome as `auth_success` or `auth_failed`.
+///
+/// # Returns
+/// * `bool` - true if the session was authenticated and can be attached
+pub async fn relay(socket: &mut WebSocket, mut exchange: AuthExchange, session_id: &str, binary_protocol: &BinaryProtocolSettings) -> bool {
+    info!("[Session {}] Relaying keyboard-interactive authentication", session_id);
+    let framing = Framing::negotiated(socket, binary_protocol);
+    loop {
+        tokio::select! {
+            event = exchange.events.recv() => {
+                let message = match event {
+                    Some(AuthEvent::Challenge { instructions, prompts }) => json!({
+                        "type": "auth_prompt",
+                        "instructions": instructions,
+                        "prompts": prompts,
+                    }),
+                    Some(AuthEvent::SecretPrompt { prompt, purpose }) => json!({
+                        "type": "secret_prompt",
+                        "prompt": prompt,
+                        "purpose": purpose,
+                    }),
+                    Some(AuthEvent::Succeeded) => {
+                        info!("[Session {}] Keyboard-interactive authentication succeeded", session_id);
+                        let _ = socket.send(framing.event(json!({ "type": "auth_success" }))).await;
+                        return true;
+                    }
+                    Some(AuthEvent::Failed(reason)) => {
+                        info!("[Session {}] Keyboard-interactive authentication failed: {}", session_id, reason);
+                        let _ = socket.send(framing.event(json!({
+                            "type": "auth_failed",
+                            "message": reason,
+                        }))).await;
+                        return false;
+                    }
+                    None => return false,
+                };
+                if socket.send(framing.event(message)).await.is_err() {
+                    return false;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        info!("[Session {}] WebSocket closed during authentication", session_id);
+                        return false;
+                    }
+                    Some(Ok(message)) => match framing.event_text(message).and_then(|text| serde_json::from_str::<AuthCommand>(&text).ok()) {
+                        Some(AuthCommand::AuthResponse { responses }) => {
+                            debug!("[Session {}] Received {} prompt answers", session_id, responses.len());
+                            let _ = exchange.responses.send(responses);
+                        }
+                        Some(AuthCommand::SecretInput { data }) => {
+                            debug!("[Session {}] Received a secret", session_id);
+                            let _ = exchange.responses.send(vec![data]);
+                        }
+                        None => debug!("[Session {}] Ignoring message during authentication", session_id),
+                    },
+                }
+            }
+        }
+    }
+}
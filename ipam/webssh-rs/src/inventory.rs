@@ -0,0 +1,494 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use thiserror::Error;
+use tracing::info;
+
+use crate::credentials::{is_contained, read_local, StoredCredentials};
+use crate::error_code::ErrorCode;
+use crate::settings::InventorySettings;
+use crate::{AppState, SSHCredentials};
+
+#[derive(Debug, Error)]
+pub enum InventoryError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Inventory unavailable: {0}")]
+    Unavailable(String),
+    #[error("Device '{0}' is not in the inventory")]
+    NotFound(String),
+    #[error("A device named '{0}' is already in the inventory")]
+    Exists(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Credentials '{0}' cannot be read: {1}")]
+    Credentials(String, String),
+}
+
+impl InventoryError {
+    /// Error code reported to clients connecting by `device_ref`
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            InventoryError::NotFound(_) => ErrorCode::DeviceNotFound,
+            InventoryError::Credentials(..) => ErrorCode::CredentialsUnavailable,
+            _ => ErrorCode::InventoryUnavailable,
+        }
+    }
+}
+
+/// A device registered in the inventory, with how to connect to it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub hostname: String,
+    pub port: u16,
+    pub device_type: Option<String>,
+    /// Where the device's default credentials are kept, e.g. "env:CORE_SSH" or "file:core.json"
+    pub credentials_ref: Option<String>,
+    pub tags: Vec<String>,
+    /// The lab device changes to this one are rehearsed on, by ID or name
+    pub lab_twin: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Body of a request to register or update a device
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceRequest {
+    pub name: String,
+    pub hostname: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub device_type: Option<String>,
+    pub credentials_ref: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub lab_twin: Option<String>,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+/// Registered devices in an SQLite database
+pub struct Inventory {
+    connection: Mutex<Connection>,
+    credentials_dir: PathBuf,
+}
+
+impl Inventory {
+    /// Opens or creates the inventory database
+    pub fn open(settings: &InventorySettings) -> Result<Self, InventoryError> {
+        let path = PathBuf::from(&settings.path);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| InventoryError::Unavailable(e.to_string()))?;
+        }
+        let connection = Connection::open(&path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS devices (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                hostname TEXT NOT NULL,
+                port INTEGER NOT NULL,
+                device_type TEXT,
+                credentials_ref TEXT,
+                tags TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                lab_twin TEXT
+            );",
+        )?;
+        // Inventories created before lab twins existed lack the column
+        let has_lab_twin = connection.prepare("SELECT lab_twin FROM devices LIMIT 0").is_ok();
+        if !has_lab_twin {
+            connection.execute_batch("ALTER TABLE devices ADD COLUMN lab_twin TEXT;")?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+            credentials_dir: PathBuf::from(&settings.credentials_dir),
+        })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>, InventoryError> {
+        self.connection.lock().map_err(|_| InventoryError::Unavailable("connection lock poisoned".to_string()))
+    }
+
+    /// Lists the devices, by name, optionally only those with a tag
+    pub fn list(&self, tag: Option<&str>) -> Result<Vec<Device>, InventoryError> {
+        let connection = self.connection()?;
+        let devices: Vec<Device> = connection
+            .prepare(&format!("SELECT {} FROM devices ORDER BY name", COLUMNS))?
+            .query_map([], device_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(devices.into_iter()
+            .filter(|device| tag.is_none_or(|tag| device.tags.iter().any(|t| t == tag)))
+            .collect())
+    }
+
+    /// Finds a device by ID or name
+    pub fn get(&self, device_ref: &str) -> Result<Device, InventoryError> {
+        self.connection()?
+            .query_row(
+                &format!("SELECT {} FROM devices WHERE id = ?1 OR name = ?1", COLUMNS),
+                params![device_ref],
+                device_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| InventoryError::NotFound(device_ref.to_string()))
+    }
+
+    /// Finds the device at an address, as a session connected to it records
+    pub fn find_by_address(&self, hostname: &str, port: u16) -> Result<Option<Device>, InventoryError> {
+        Ok(self.connection()?
+            .query_row(
+                &format!("SELECT {} FROM devices WHERE lower(hostname) = lower(?1) AND port = ?2 ORDER BY name LIMIT 1", COLUMNS),
+                params![hostname, port],
+                device_from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn create(&self, request: DeviceRequest) -> Result<Device, InventoryError> {
+        let request = validate(request)?;
+        let now = Utc::now();
+        let device = Device {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: request.name,
+            hostname: request.hostname,
+            port: request.port,
+            device_type: request.device_type,
+            credentials_ref: request.credentials_ref,
+            tags: request.tags,
+            lab_twin: request.lab_twin,
+            created_at: now,
+            updated_at: now,
+        };
+        let connection = self.connection()?;
+        if name_taken(&connection, &device.name, None)? {
+            return Err(InventoryError::Exists(device.name));
+        }
+        connection.execute(
+            &format!("INSERT INTO devices ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", COLUMNS),
+            params![device.id, device.name, device.hostname, device.port, device.device_type,
+                    device.credentials_ref, tags_json(&device.tags), device.created_at, device.updated_at,
+                    device.lab_twin],
+        )?;
+        Ok(device)
+    }
+
+    /// Replaces a device's profile, keeping its ID
+    pub fn update(&self, device_ref: &str, request: DeviceRequest) -> Result<Device, InventoryError> {
+        let request = validate(request)?;
+        let existing = self.get(device_ref)?;
+        let device = Device {
+            name: request.name,
+            hostname: request.hostname,
+            port: request.port,
+            device_type: request.device_type,
+            credentials_ref: request.credentials_ref,
+            tags: request.tags,
+            lab_twin: request.lab_twin,
+            updated_at: Utc::now(),
+            ..existing
+        };
+        let connection = self.connection()?;
+        if name_taken(&connection, &device.name, Some(&device.id))? {
+            return Err(InventoryError::Exists(device.name));
+        }
+        connection.execute(
+            "UPDATE devices SET name = ?2, hostname = ?3, port = ?4, device_type = ?5, credentials_ref = ?6,
+                                tags = ?7, updated_at = ?8, lab_twin = ?9
+             WHERE id = ?1",
+            params![device.id, device.name, device.hostname, device.port, device.device_type,
+                    device.credentials_ref, tags_json(&device.tags), device.updated_at, device.lab_twin],
+        )?;
+        Ok(device)
+    }
+
+    pub fn delete(&self, device_ref: &str) -> Result<Device, InventoryError> {
+        let device = self.get(device_ref)?;
+        self.connection()?.execute("DELETE FROM devices WHERE id = ?1", params![device.id])?;
+        Ok(device)
+    }
+
+    /// Reads the credentials a device's `credentials_ref` points to
+    ///
+    /// `env:NAME` reads a JSON object from an environment variable; `file:NAME`
+    /// reads one from a file in `inventory.credentials_dir`. Both are read on
+    /// every connection, so credentials can be rotated in place.
+    pub fn credentials(&self, credentials_ref: &str) -> Result<StoredCredentials, InventoryError> {
+        let (provider, name) = credentials_ref.split_once(':').unwrap_or(("", credentials_ref));
+        read_local(provider, name, &self.credentials_dir)
+            .map_err(|reason| InventoryError::Credentials(credentials_ref.to_string(), reason))
+    }
+
+    /// Fills in a connect request from the inventory when it names a `device_ref`
+    ///
+    /// The device's address, type and tags replace the request's. Credentials given
+    /// in the request are used as they are; otherwise the device's default
+    /// credentials are, with the request's username if it has one.
+    pub fn resolve(&self, mut credentials: SSHCredentials) -> Result<SSHCredentials, InventoryError> {
+        let Some(device_ref) = credentials.device_ref.take() else {
+            return Ok(credentials);
+        };
+        let device = self.get(device_ref.trim())?;
+        credentials.hostname = device.hostname;
+        credentials.port = device.port;
+        credentials.device_type = device.device_type.or(credentials.device_type);
+        credentials.device_name = credentials.device_name.or(Some(device.name));
+        credentials.device_tags = device.tags;
+
+        let has_credentials = credentials.password.is_some() || credentials.private_key.is_some();
+        if let (false, Some(credentials_ref)) = (has_credentials, &device.credentials_ref) {
+            let stored = self.credentials(credentials_ref)?;
+            if credentials.username.is_empty() {
+                credentials.username = stored.username.unwrap_or_default();
+            }
+            credentials.password = stored.password;
+            credentials.private_key = stored.private_key;
+            credentials.private_key_passphrase = stored.private_key_passphrase;
+            credentials.auth_type = credentials.auth_type.or(stored.auth_type);
+            credentials.enable_password = credentials.enable_password.or(stored.enable_password);
+        }
+        Ok(credentials)
+    }
+}
+
+const COLUMNS: &str = "id, name, hostname, port, device_type, credentials_ref, tags, created_at, updated_at, lab_twin";
+
+fn device_from_row(row: &Row) -> rusqlite::Result<Device> {
+    let tags: String = row.get(6)?;
+    Ok(Device {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        hostname: row.get(2)?,
+        port: row.get(3)?,
+        device_type: row.get(4)?,
+        credentials_ref: row.get(5)?,
+        tags: serde_json::from_str(&tags).unwrap_or_default(),
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+        lab_twin: row.get(9)?,
+    })
+}
+
+fn tags_json(tags: &[String]) -> String {
+    serde_json::to_string(tags).unwrap_or_else(|_| "[]".to_string())
+}
+
+fn name_taken(connection: &Connection, name: &str, except_id: Option<&str>) -> Result<bool, InventoryError> {
+    let id: Option<String> = connection
+        .query_row("SELECT id FROM devices WHERE name = ?1", params![name], |row| row.get(0))
+        .optional()?;
+    Ok(id.is_some_and(|id| Some(id.as_str()) != except_id))
+}
+
+fn validate(mut request: DeviceRequest) -> Result<DeviceRequest, InventoryError> {
+    request.name = request.name.trim().to_string();
+    request.hostname = crate::ssh::host::normalize(&request.hostname);
+    if request.name.is_empty() || request.hostname.is_empty() {
+        return Err(InventoryError::Invalid("name and hostname are required".to_string()));
+    }
+    crate::ssh::host::validate(&request.hostname).map_err(InventoryError::Invalid)?;
+    if request.port == 0 {
+        return Err(InventoryError::Invalid("port must be between 1 and 65535".to_string()));
+    }
+    request.device_type = request.device_type.map(|device_type| device_type.to_lowercase());
+    if let Some(credentials_ref) = &request.credentials_ref {
+        let valid = match credentials_ref.split_once(':') {
+            Some(("env", name)) => !name.is_empty(),
+            // Files are kept to the credentials directory
+            Some(("file", name)) => is_contained(name),
+            _ => false,
+        };
+        if !valid {
+            return Err(InventoryError::Invalid(format!(
+                "Invalid credentials_ref '{}'; expected env:NAME or file:NAME within the credentials directory",
+                credentials_ref
+            )));
+        }
+    }
+    request.lab_twin = request.lab_twin
+        .map(|lab_twin| lab_twin.trim().to_string())
+        .filter(|lab_twin| !lab_twin.is_empty());
+    if request.lab_twin.as_ref() == Some(&request.name) {
+        return Err(InventoryError::Invalid("A device cannot be its own lab twin".to_string()));
+    }
+    request.tags.retain(|tag| !tag.trim().is_empty());
+    request.tags.sort();
+    request.tags.dedup();
+    Ok(request)
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+pub(crate) fn inventory_error(e: InventoryError) -> Response {
+    match e {
+        InventoryError::NotFound(_) => error_response(StatusCode::NOT_FOUND, "device_not_found", e.to_string()),
+        InventoryError::Exists(_) => error_response(StatusCode::CONFLICT, "device_exists", e.to_string()),
+        InventoryError::Invalid(_) => error_response(StatusCode::BAD_REQUEST, "invalid_device", e.to_string()),
+        _ => error_response(StatusCode::INTERNAL_SERVER_ERROR, "inventory_unavailable", e.to_string()),
+    }
+}
+
+fn inventory_disabled() -> Response {
+    error_response(StatusCode::CONFLICT, "inventory_disabled",
+                   "The device inventory is not enabled on this instance".to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub tag: Option<String>,
+}
+
+/// Lists the registered devices, optionally those with a tag
+pub async fn list_handler(State(state): State<AppState>, Query(query): Query<ListQuery>) -> Response {
+    let Some(inventory) = &state.inventory else {
+        return inventory_disabled();
+    };
+    match inventory.list(query.tag.as_deref()) {
+        Ok(devices) => Json(json!({ "devices": devices })).into_response(),
+        Err(e) => inventory_error(e),
+    }
+}
+
+/// Gets a device by ID or name
+pub async fn get_handler(State(state): State<AppState>, Path(device_ref): Path<String>) -> Response {
+    let Some(inventory) = &state.inventory else {
+        return inventory_disabled();
+    };
+    match inventory.get(&device_ref) {
+        Ok(device) => {
+            // Locks are kept by the address sessions connect to
+            let lock = state.session_registry.lock().await.device_locks.get(&device.hostname).cloned();
+            let mut device = json!(device);
+            device["lock"] = json!(lock);
+            Json(device).into_response()
+        }
+        Err(e) => inventory_error(e),
+    }
+}
+
+/// Registers a device
+pub async fn create_handler(State(state): State<AppState>, Json(request): Json<DeviceRequest>) -> Response {
+    let Some(inventory) = &state.inventory else {
+        return inventory_disabled();
+    };
+    match inventory.create(request) {
+        Ok(device) => {
+            info!("Device {} ({}) added to the inventory", device.name, device.id);
+            (StatusCode::CREATED, Json(device)).into_response()
+        }
+        Err(e) => inventory_error(e),
+    }
+}
+
+/// Replaces a device's profile
+pub async fn update_handler(
+    State(state): State<AppState>,
+    Path(device_ref): Path<String>,
+    Json(request): Json<DeviceRequest>,
+) -> Response {
+    let Some(inventory) = &state.inventory else {
+        return inventory_disabled();
+    };
+    match inventory.update(&device_ref, request) {
+        Ok(device) => {
+            info!("Device {} ({}) updated in the inventory", device.name, device.id);
+            Json(device).into_response()
+        }
+        Err(e) => inventory_error(e),
+    }
+}
+
+/// Removes a device; sessions already connected to it are left open
+pub async fn delete_handler(State(state): State<AppState>, Path(device_ref): Path<String>) -> Response {
+    let Some(inventory) = &state.inventory else {
+        return inventory_disabled();
+    };
+    match inventory.delete(&device_ref) {
+        Ok(device) => {
+            info!("Device {} ({}) removed from the inventory", device.name, device.id);
+            Json(json!({ "success": true, "message": format!("Device '{}' removed", device.name) })).into_response()
+        }
+        Err(e) => inventory_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str) -> DeviceRequest {
+        DeviceRequest {
+            name: name.to_string(),
+            hostname: "10.0.0.1".to_string(),
+            port: 22,
+            device_type: Some("Cisco".to_string()),
+            credentials_ref: Some("env:WEBSSH_TEST_INVENTORY_CREDENTIALS".to_string()),
+            tags: vec!["core".to_string(), "core".to_string()],
+            lab_twin: None,
+        }
+    }
+
+    #[test]
+    fn test_inventory_crud_and_resolve() {
+        let inventory = Inventory::open(&InventorySettings {
+            path: ":memory:".to_string(),
+            ..InventorySettings::default()
+        }).unwrap();
+        let device = inventory.create(request("core-1")).unwrap();
+        assert_eq!(device.device_type.as_deref(), Some("cisco"));
+        assert_eq!(device.tags, vec!["core".to_string()]);
+        assert!(matches!(inventory.create(request("core-1")), Err(InventoryError::Exists(_))));
+        assert_eq!(inventory.get(&device.id).unwrap(), inventory.get("core-1").unwrap());
+        assert_eq!(inventory.list(Some("core")).unwrap().len(), 1);
+        assert!(inventory.list(Some("edge")).unwrap().is_empty());
+
+        let mut invalid = request("core-2");
+        invalid.credentials_ref = Some("file:../../etc/shadow".to_string());
+        assert!(matches!(inventory.create(invalid), Err(InventoryError::Invalid(_))));
+
+        let mut moved = request("core-1");
+        moved.hostname = "10.0.0.2".to_string();
+        moved.port = 2222;
+        inventory.update("core-1", moved).unwrap();
+        assert_eq!(inventory.find_by_address("10.0.0.2", 2222).unwrap().map(|device| device.name), Some("core-1".to_string()));
+        assert!(inventory.find_by_address("10.0.0.2", 22).unwrap().is_none());
+
+        std::env::set_var("WEBSSH_TEST_INVENTORY_CREDENTIALS", r#"{"username": "netops", "password": "secret"}"#);
+        let connect: SSHCredentials = serde_json::from_value(json!({ "device_ref": "core-1" })).unwrap();
+        let resolved = inventory.resolve(connect).unwrap();
+        assert_eq!((resolved.hostname.as_str(), resolved.port), ("10.0.0.2", 2222));
+        assert_eq!(resolved.username, "netops");
+        assert_eq!(resolved.password.as_deref(), Some("secret"));
+        assert_eq!(resolved.device_name.as_deref(), Some("core-1"));
+
+        // Credentials in the request are kept
+        let connect: SSHCredentials = serde_json::from_value(json!({
+            "device_ref": "core-1", "username": "admin", "password": "other",
+        })).unwrap();
+        let resolved = inventory.resolve(connect).unwrap();
+        assert_eq!((resolved.username.as_str(), resolved.password.as_deref()), ("admin", Some("other")));
+
+        inventory.delete(&device.id).unwrap();
+        assert!(matches!(inventory.resolve(serde_json::from_value(json!({ "device_ref": "core-1" })).unwrap()),
+                         Err(InventoryError::NotFound(_))));
+    }
+}
@@ -0,0 +1,241 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::api_keys::presented_key;
+use crate::authz::{self, SessionAccess};
+use crate::settings::JwtSettings;
+use crate::AppState;
+
+/// Environment variable consulted when no HS256 secret is configured
+const SECRET_ENV: &str = "WEBSSH_JWT_SECRET";
+
+/// Claims the gateway reads from a token; other claims are ignored
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    /// Roles the command policy applies rules by
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+/// The identity established by a valid JWT, available to handlers as an extension
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub subject: String,
+    pub roles: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    /// Returns true if this user may act on a session owned by `portal_user_id`
+    pub fn owns(&self, portal_user_id: &str) -> bool {
+        self.subject == portal_user_id
+    }
+}
+
+/// Validates tokens according to the configured algorithm and key
+pub struct JwtValidator {
+    decoding_key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtValidator {
+    /// Builds the validator from settings
+    ///
+    /// # Returns
+    /// * `Result<Self, String>` - The validator, or a description of the configuration problem
+    pub fn from_settings(settings: &JwtSettings) -> Result<Self, String> {
+        let (algorithm, decoding_key) = match settings.algorithm.to_uppercase().as_str() {
+            "HS256" => {
+                let secret = settings.secret.clone()
+                    .or_else(|| std::env::var(SECRET_ENV).ok())
+                    .filter(|secret| !secret.is_empty())
+                    .ok_or("jwt.secret (or WEBSSH_JWT_SECRET) is required for HS256")?;
+                (Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes()))
+            }
+            "RS256" => {
+                let path = settings.public_key_file.as_deref()
+                    .ok_or("jwt.public_key_file is required for RS256")?;
+                let pem = std::fs::read(path)
+                    .map_err(|e| format!("Failed to read JWT public key {}: {}", path, e))?;
+                let key = DecodingKey::from_rsa_pem(&pem)
+                    .map_err(|e| format!("Invalid JWT public key {}: {}", path, e))?;
+                (Algorithm::RS256, key)
+            }
+            other => return Err(format!("Unsupported JWT algorithm '{}'; use HS256 or RS256", other)),
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = settings.leeway_seconds;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &settings.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &settings.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Ok(Self { decoding_key, validation })
+    }
+
+    /// Validates a token and returns the authenticated subject
+    pub fn validate(&self, token: &str) -> Result<AuthenticatedUser, jsonwebtoken::errors::Error> {
+        let data = decode::<Claims>(token, &self.decoding_key, &self.validation)?;
+        Ok(AuthenticatedUser { subject: data.claims.sub, roles: data.claims.roles })
+    }
+}
+
+fn query_param(request: &Request, name: &str) -> Option<String> {
+    request.uri().query().and_then(|query| {
+        query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| urlencoding::decode(value).ok())
+            .map(|value| value.into_owned())
+    })
+}
+
+/// Extracts a bearer token from the Authorization header or the `token` query parameter
+///
+/// Browsers cannot set headers on WebSocket upgrades, so the query parameter is
+/// accepted as well.
+fn extract_token(request: &Request) -> Option<String> {
+    let from_header = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    from_header.or_else(|| query_param(request, "token"))
+}
+
+/// What a request on a per-session route does with the session
+fn session_access(request: &Request) -> SessionAccess {
+    let path = request.uri().path();
+    if path.starts_with("/ws/") && query_param(request, "view").as_deref() == Some("true") {
+        SessionAccess::View
+    } else if path.ends_with("/terminate") {
+        SessionAccess::Terminate
+    } else {
+        SessionAccess::Use
+    }
+}
+
+/// Pulls the session ID out of `/ws/:session_id` and `/api/session/:session_id/...` paths
+fn session_id_from_path(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/ws/")
+        .or_else(|| path.strip_prefix("/api/session/"))?;
+    rest.split('/').next().filter(|id| !id.is_empty())
+}
+
+fn auth_error(status: StatusCode, message: &str) -> Response {
+    let error = if status == StatusCode::FORBIDDEN { "forbidden" } else { "unauthorized" };
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Middleware validating JWTs and binding per-session routes to the token subject
+///
+/// Requests carrying a valid API key skip JWT validation when API keys are
+/// enabled; the API key middleware checks the key's scope where the route
+/// has it. An unknown key is refused here, as routes without that middleware,
+/// like the WebSocket, would otherwise be open to it.
+pub async fn require_jwt(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let Some(validator) = state.jwt.as_deref() else {
+        return next.run(request).await;
+    };
+
+    if state.settings.api_keys.enabled {
+        if let Some(presented) = presented_key(&request) {
+            if !state.api_keys.lock().await.authenticate(presented) {
+                warn!("Invalid API key presented for {}", request.uri().path());
+                return auth_error(StatusCode::UNAUTHORIZED, "Invalid API key");
+            }
+            return next.run(request).await;
+        }
+    }
+
+    let Some(token) = extract_token(&request) else {
+        return auth_error(StatusCode::UNAUTHORIZED, "Missing bearer token");
+    };
+
+    let user = match validator.validate(&token) {
+        Ok(user) => user,
+        Err(e) => {
+            warn!("Rejected JWT for {}: {}", request.uri().path(), e);
+            return auth_error(StatusCode::UNAUTHORIZED, "Invalid or expired token");
+        }
+    };
+
+    // Sessions belong to the subject that created them; admins may watch and terminate others'
+    if let Some(session_id) = session_id_from_path(request.uri().path()) {
+        let session_id = urlencoding::decode(session_id)
+            .map(|id| id.trim().to_string())
+            .unwrap_or_default();
+        let owner = state.session_registry.lock().await
+            .get_session(&session_id)
+            .map(|session_info| session_info.portal_user_id.clone());
+        if let Some(owner) = owner {
+            let settings = state.policy.settings();
+            if !authz::may_access(&settings.authorization, &user, &owner, session_access(&request)) {
+                warn!("Subject {} denied access to session {} owned by {}", user.subject, session_id, owner);
+                return auth_error(StatusCode::FORBIDDEN, "Session belongs to another user");
+            }
+        }
+    }
+
+    debug!("Authenticated subject {} for {}", user.subject, request.uri().path());
+    request.extensions_mut().insert(user);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn settings() -> JwtSettings {
+        JwtSettings {
+            enabled: true,
+            algorithm: "HS256".to_string(),
+            secret: Some("test-secret".to_string()),
+            ..JwtSettings::default()
+        }
+    }
+
+    fn token(secret: &str, sub: &str, exp_offset: i64) -> String {
+        let claims = json!({
+            "sub": sub,
+            "exp": chrono::Utc::now().timestamp() + exp_offset,
+        });
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_validates_hs256_tokens() {
+        let validator = JwtValidator::from_settings(&settings()).unwrap();
+
+        let user = validator.validate(&token("test-secret", "alice", 300)).unwrap();
+        assert_eq!(user.subject, "alice");
+        assert!(validator.validate(&token("other-secret", "alice", 300)).is_err());
+        assert!(validator.validate(&token("test-secret", "alice", -300)).is_err());
+    }
+
+    #[test]
+    fn test_session_id_from_path() {
+        assert_eq!(session_id_from_path("/ws/abc"), Some("abc"));
+        assert_eq!(session_id_from_path("/api/session/abc/status"), Some("abc"));
+        assert_eq!(session_id_from_path("/api/sessions"), None);
+    }
+}
@@ -0,0 +1,155 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::policy::PolicyStore;
+use crate::session::SessionRegistry;
+use crate::settings::ConnectionSettings;
+use crate::ssh::heartbeat::KeepaliveCounts;
+use crate::store::EndReason;
+use crate::tasks::{RestartPolicy, TaskContext, TaskSupervisor};
+
+/// How a session's connection to its device is holding up, by its keepalives
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    #[default]
+    Healthy,
+    /// Keepalives are failing or going unanswered, but not yet for long
+    Degraded,
+    /// Too many keepalives failed or went unanswered; the session is being closed
+    Dead,
+}
+
+/// A session's connection health, as reported in the session status
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ConnectionHealth {
+    pub state: HealthState,
+    /// Keepalives in a row that failed to send
+    pub keepalive_failures: u32,
+    /// Keepalive intervals in a row without anything from the device
+    pub missed_replies: u32,
+    /// Since when the connection has not been healthy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl ConnectionHealth {
+    /// Judges the counts of a session's connections against the limits
+    pub fn assess(counts: KeepaliveCounts, settings: &ConnectionSettings) -> HealthState {
+        if counts.failures >= settings.keepalive_max_failures.max(1)
+            || counts.missed_replies >= settings.keepalive_max_missed_replies.max(1) {
+            HealthState::Dead
+        } else if counts.failures > 0 || counts.missed_replies > 0 {
+            HealthState::Degraded
+        } else {
+            HealthState::Healthy
+        }
+    }
+}
+
+/// Starts watching the keepalives of every session's connections
+///
+/// A session whose keepalives start failing or going unanswered is marked
+/// degraded and its clients are told; one that passes the limits in
+/// `ssh.connection` is marked dead, its clients are sent `connection_lost`,
+/// and it is closed. The limits are read on every pass, as they may be reloaded.
+pub fn start(tasks: &TaskSupervisor, registry: Arc<tokio::sync::Mutex<SessionRegistry>>, policy: Arc<PolicyStore>) {
+    tasks.spawn("keepalive_health", RestartPolicy::OnPanic, move |task| run(registry.clone(), policy.clone(), task));
+}
+
+async fn run(registry: Arc<tokio::sync::Mutex<SessionRegistry>>, policy: Arc<PolicyStore>, task: TaskContext) {
+    loop {
+        let settings = policy.settings();
+        // Counts change at most once per keepalive, so checking as often catches every change
+        tokio::time::sleep(Duration::from_secs(settings.ssh.connection.keepalive_seconds.max(1))).await;
+        check(&mut *registry.lock().await, &settings.ssh.connection);
+        task.ran();
+    }
+}
+
+fn check(registry: &mut SessionRegistry, settings: &ConnectionSettings) {
+    let mut lost = Vec::new();
+
+    for (session_id, session_info) in registry.sessions.iter_mut() {
+        let counts = session_info.ssh_session.heartbeats().iter()
+            .map(|heartbeat| heartbeat.keepalive())
+            .fold(KeepaliveCounts::default(), KeepaliveCounts::worst);
+        let state = ConnectionHealth::assess(counts, settings);
+        let previous = session_info.health;
+        session_info.health = ConnectionHealth {
+            state,
+            keepalive_failures: counts.failures,
+            missed_replies: counts.missed_replies,
+            since: match state {
+                HealthState::Healthy => None,
+                _ => previous.since.or(Some(Utc::now())),
+            },
+        };
+        if state == previous.state {
+            continue;
+        }
+
+        match state {
+            HealthState::Healthy => {
+                info!("Session {} connection to {} recovered", session_id, session_info.device_id);
+                let _ = session_info.notifications.send(json!({ "type": "connection_recovered" }));
+            }
+            HealthState::Degraded => {
+                warn!("Session {} connection to {} degraded: {} keepalive(s) failed, {} unanswered",
+                      session_id, session_info.device_id, counts.failures, counts.missed_replies);
+                let _ = session_info.notifications.send(json!({
+                    "type": "connection_degraded",
+                    "message": "The device is slow to answer; the connection may be failing",
+                    "keepalive_failures": counts.failures,
+                    "missed_replies": counts.missed_replies,
+                }));
+            }
+            HealthState::Dead => {
+                let reason = if counts.failures >= settings.keepalive_max_failures.max(1) {
+                    "keepalive_failures"
+                } else {
+                    "keepalive_timeout"
+                };
+                error!("Session {} connection to {} lost ({}: {} keepalive(s) failed, {} unanswered); closing it",
+                       session_id, session_info.device_id, reason, counts.failures, counts.missed_replies);
+                let _ = session_info.notifications.send(json!({
+                    "type": "connection_lost",
+                    "reason": reason,
+                    "message": "The connection to the device was lost and the session is being closed",
+                    "keepalive_failures": counts.failures,
+                    "missed_replies": counts.missed_replies,
+                }));
+                lost.push(session_id.clone());
+            }
+        }
+    }
+
+    for session_id in lost {
+        if let Some(session_info) = registry.sessions.get(&session_id) {
+            session_info.ssh_session.shutdown();
+        }
+        registry.remove_session(&session_id, EndReason::ConnectionLost);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_assess_against_the_limits() {
+        let settings = Settings::default().ssh.connection;
+        let counts = |failures, missed_replies| KeepaliveCounts { failures, missed_replies };
+
+        assert_eq!(ConnectionHealth::assess(counts(0, 0), &settings), HealthState::Healthy);
+        assert_eq!(ConnectionHealth::assess(counts(0, 1), &settings), HealthState::Degraded);
+        assert_eq!(ConnectionHealth::assess(counts(2, 2), &settings), HealthState::Degraded);
+        assert_eq!(ConnectionHealth::assess(counts(3, 0), &settings), HealthState::Dead);
+        assert_eq!(ConnectionHealth::assess(counts(0, 3), &settings), HealthState::Dead);
+    }
+}
@@ -0,0 +1,437 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::audit::{audit, session_commands};
+use crate::authz;
+use crate::command_policy::{self, Blocked};
+use crate::credential_policy;
+use crate::inventory::{Device, Inventory, InventoryError};
+use crate::jwt::AuthenticatedUser;
+use crate::recording::record;
+use crate::settings::{Settings, WebhookEventType};
+use crate::ssh::{ConnectionTarget, Shell};
+use crate::{AppState, AGENT};
+
+/// Body of a request to clone a session to a lab device
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CloneRequest {
+    /// The lab device, by ID or name; defaults to the lab twin of the session's device
+    pub lab_device_ref: Option<String>,
+    /// Replay the commands typed in the session on the lab device
+    pub dry_run: bool,
+    /// The fingerprint of the lab device's host key the user confirmed, if it is not pinned yet
+    pub host_key_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CloneResponse {
+    pub success: bool,
+    pub session_id: String,
+    pub websocket_url: String,
+    /// Lets one WebSocket attach to the lab session; also in `websocket_url`
+    pub ws_token: String,
+    pub cloned_from: String,
+    pub lab_device: LabDevice,
+    /// Commands being replayed on the lab device, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub replayed: Vec<String>,
+    /// Commands left out of the replay, as their text may not be what the device ran
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LabDevice {
+    pub id: String,
+    pub name: String,
+    pub hostname: String,
+    pub port: u16,
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+/// Why a session's lab device could not be found
+#[derive(Debug, Error)]
+enum LabError {
+    #[error("{0}:{1} is not in the inventory; name the lab device with lab_device_ref")]
+    NotInInventory(String, u16),
+    #[error("Device '{0}' has no lab twin")]
+    NoTwin(String),
+    #[error(transparent)]
+    Inventory(#[from] InventoryError),
+}
+
+impl IntoResponse for LabError {
+    fn into_response(self) -> Response {
+        match self {
+            LabError::NotInInventory(..) | LabError::NoTwin(_) =>
+                error_response(StatusCode::NOT_FOUND, "lab_twin_not_found", self.to_string()),
+            LabError::Inventory(InventoryError::NotFound(_)) =>
+                error_response(StatusCode::NOT_FOUND, "device_not_found", self.to_string()),
+            LabError::Inventory(_) =>
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "inventory_unavailable", self.to_string()),
+        }
+    }
+}
+
+/// Finds the lab device a session's target is rehearsed on
+fn lab_device(inventory: &Inventory, target: &ConnectionTarget, lab_device_ref: Option<&str>) -> Result<Device, LabError> {
+    let lab_twin = match lab_device_ref {
+        Some(lab_device_ref) => lab_device_ref.trim().to_string(),
+        None => {
+            let device = inventory.find_by_address(&target.hostname, target.port)?
+                .ok_or_else(|| LabError::NotInInventory(target.hostname.clone(), target.port))?;
+            device.lab_twin.ok_or(LabError::NoTwin(device.name))?
+        }
+    };
+    Ok(inventory.get(&lab_twin)?)
+}
+
+/// Why a user may not clone a session to a lab device
+#[derive(Debug)]
+enum Refusal {
+    /// No ACL lets the user connect to the lab device
+    Access(String),
+    /// The command policy refuses a command the dry run would replay
+    Command(Blocked),
+}
+
+/// Checks a clone the way connecting to the lab device and running the replayed commands there would be
+fn check_clone(
+    settings: &Settings,
+    user: Option<&AuthenticatedUser>,
+    device: &Device,
+    device_type: Option<&str>,
+    replayed: &[String],
+) -> Result<(), Refusal> {
+    if let Some(user) = user {
+        authz::check_connect(&settings.authorization, user, &device.hostname, &device.tags).map_err(Refusal::Access)?;
+    }
+    if settings.command_policy.enabled {
+        let roles = user.map(|user| user.roles.as_slice()).unwrap_or_default();
+        for command in replayed {
+            command_policy::check(&settings.command_policy, device_type, roles, command, false)
+                .map_err(Refusal::Command)?;
+        }
+    }
+    Ok(())
+}
+
+/// Points a copy of the session's target at the lab device
+///
+/// The lab device's own credentials are used if it has any; otherwise the
+/// session's login is tried there too. Its host key, and its jump host's,
+/// are checked against their own pins, never with the key confirmed for
+/// the session's device.
+fn lab_target(
+    state: &AppState,
+    inventory: &Inventory,
+    target: &ConnectionTarget,
+    device: &Device,
+    confirmed: Option<String>,
+    portal_user_id: &str,
+) -> Result<ConnectionTarget, InventoryError> {
+    let mut lab = target.clone();
+    lab.hostname = device.hostname.clone();
+    lab.port = device.port;
+    crate::check_host_keys(state, &mut lab, confirmed, portal_user_id);
+    lab.device_type = device.device_type.clone().or(lab.device_type);
+    if let Some(credentials_ref) = &device.credentials_ref {
+        let stored = inventory.credentials(credentials_ref)?;
+        lab.username = stored.username.unwrap_or(lab.username);
+        lab.password = stored.password;
+        lab.private_key = stored.private_key;
+        lab.private_key_passphrase = stored.private_key_passphrase;
+        lab.certificate = None;
+        lab.agent = stored.auth_type.as_deref() == Some(AGENT);
+        lab.keyboard_interactive = false;
+    }
+    Ok(lab)
+}
+
+/// Opens a session to the lab twin of a session's device, for rehearsing changes
+///
+/// With `dry_run`, the commands typed in the session so far, as recorded by
+/// the command audit, are replayed on the lab device once it is connected.
+pub async fn clone_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    user: Option<Extension<AuthenticatedUser>>,
+    request: Option<Json<CloneRequest>>,
+) -> Response {
+    if !state.settings.lab.enabled {
+        return error_response(StatusCode::CONFLICT, "lab_disabled",
+                              "Cloning sessions to lab devices is not enabled on this instance".to_string());
+    }
+    if let Some(message) = state.maintenance.refusal() {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "maintenance", message);
+    }
+    let Some(inventory) = state.inventory.clone() else {
+        return error_response(StatusCode::CONFLICT, "inventory_disabled",
+                              "Lab twins are kept in the device inventory, which is not enabled on this instance".to_string());
+    };
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    if request.dry_run && state.audit.is_none() {
+        return error_response(StatusCode::CONFLICT, "audit_disabled",
+                              "A dry run replays the audited commands, and command auditing is not enabled".to_string());
+    }
+
+    let session_id = session_id.trim().to_string();
+    let source = state.session_registry.lock().await.get_session(&session_id)
+        .map(|session_info| (session_info.ssh_session.target().clone(), session_info.portal_user_id.clone()));
+    let Some((target, portal_user_id)) = source else {
+        return error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id));
+    };
+
+    let device = match lab_device(&inventory, &target, request.lab_device_ref.as_deref()) {
+        Ok(device) => device,
+        Err(e) => return e.into_response(),
+    };
+    if device.hostname.eq_ignore_ascii_case(&target.hostname) && device.port == target.port {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_lab_device",
+                              format!("Device '{}' is the session's own device", device.name));
+    }
+    let lab = match lab_target(&state, &inventory, &target, &device, request.host_key_fingerprint.clone(), &portal_user_id) {
+        Ok(lab) => lab,
+        Err(e) => return error_response(StatusCode::BAD_GATEWAY, "credentials_unavailable", e.to_string()),
+    };
+    let policy_settings = state.policy.settings();
+    if let Err(violation) = credential_policy::check(&policy_settings.credential_policy, &lab, None) {
+        return error_response(StatusCode::FORBIDDEN, "credential_policy", violation.to_string());
+    }
+
+    // Read the commands before connecting, so a failed read costs no session
+    let mut replayed = Vec::new();
+    let mut skipped = Vec::new();
+    if request.dry_run {
+        let path = PathBuf::from(&state.settings.audit.path);
+        let audited_session = session_id.clone();
+        let entries = match tokio::task::spawn_blocking(move || session_commands(&path, &audited_session)).await {
+            Ok(Ok(entries)) => entries,
+            Ok(Err(e)) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "audit_unavailable", e.to_string()),
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "audit_unavailable", e.to_string()),
+        };
+        // Refused commands never ran on the device
+        for entry in entries.into_iter().filter(|entry| !entry.blocked) {
+            if entry.uncertain {
+                skipped.push(entry.command);
+            } else {
+                replayed.push(entry.command);
+            }
+        }
+        let max = state.settings.lab.max_replay_commands;
+        if replayed.len() > max {
+            return error_response(StatusCode::BAD_REQUEST, "too_many_commands",
+                                  format!("The session has {} commands to replay; at most {} are", replayed.len(), max));
+        }
+    }
+
+    let user = user.map(|Extension(user)| user);
+    match check_clone(&policy_settings, user.as_ref(), &device, lab.device_type.as_deref(), &replayed) {
+        Ok(()) => {}
+        Err(Refusal::Access(message)) => {
+            warn!("Clone of session {} to lab device {} refused: {}", session_id, device.name, message);
+            return error_response(StatusCode::FORBIDDEN, "forbidden", message);
+        }
+        Err(Refusal::Command(blocked)) => {
+            warn!("Dry run of session {} on lab device {} refused: {}", session_id, device.name, blocked.reason);
+            if let Some(webhooks) = &state.webhooks {
+                webhooks.send(WebhookEventType::CommandBlocked, json!({
+                    "session_id": session_id,
+                    "portal_user_id": portal_user_id,
+                    "hostname": lab.hostname,
+                    "device_type": lab.device_type,
+                    "command": blocked.command,
+                    "rule": blocked.rule,
+                    "reason": blocked.reason,
+                    "guardrail": blocked.guardrail,
+                }));
+            }
+            return error_response(StatusCode::FORBIDDEN, "command_blocked", blocked.reason);
+        }
+    }
+
+    let device_id = lab.hostname.clone();
+    let ssh_username = lab.username.clone();
+    if let Err(e) = state.session_registry.lock().await.check_limits(&portal_user_id, &device_id) {
+        return error_response(StatusCode::TOO_MANY_REQUESTS, "session_limit_exceeded", e.to_string());
+    }
+
+    info!("Cloning session {} to lab device {} ({}:{})", session_id, device.name, lab.hostname, lab.port);
+    let shell = match tokio::task::spawn_blocking(move || Shell::open(lab)).await {
+        Ok(Ok(shell)) => shell,
+        Ok(Err(e)) => {
+            error!("Failed to connect to lab device {}: {}", device.name, e);
+            // As with a connect, a key to confirm is sent back for the user to check
+            return (StatusCode::BAD_GATEWAY, Json(json!({
+                "error": "lab_connection_failed",
+                "message": format!("Failed to connect to lab device '{}': {}", device.name, e),
+                "error_code": e.error_code(),
+                "host_key": e.host_key(),
+            }))).into_response();
+        }
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "lab_connection_failed", e.to_string()),
+    };
+
+    let (lab_session_id, stream, ws_token) = {
+        let mut registry = state.session_registry.lock().await;
+        let lab_session_id = match registry.add_session(&portal_user_id, &device_id, &ssh_username, shell) {
+            Ok(lab_session_id) => lab_session_id,
+            Err(e) => return error_response(StatusCode::TOO_MANY_REQUESTS, "session_limit_exceeded", e.to_string()),
+        };
+        crate::start_recording(&mut registry, &state.settings, &lab_session_id);
+        crate::start_audit(&mut registry, &state, &lab_session_id);
+        crate::follow_notifications(&mut registry, &lab_session_id);
+        crate::watch_terminal(&mut registry, &state, &lab_session_id);
+        crate::build_output_pipeline(&mut registry, &state, &lab_session_id);
+        // Replayed output is buffered for the WebSocket attaching later
+        let stream = if replayed.is_empty() { None } else { registry.stream(&lab_session_id, &state.scrollback) };
+        let ws_token = registry.ws_tokens.issue(&lab_session_id, crate::ws_token::ttl(&state.settings.ws_tokens));
+        (lab_session_id, stream, ws_token)
+    };
+    info!("Session {} cloned to {} on lab device {}", session_id, lab_session_id, device.name);
+
+    if let Some(stream) = stream {
+        let commands = replayed.clone();
+        let delay = Duration::from_millis(state.settings.lab.replay_delay_ms);
+        let replay_session_id = lab_session_id.clone();
+        tokio::spawn(async move {
+            let input_tx = stream.input_sender();
+            let (recorder, input_audit) = (stream.recorder(), stream.audit());
+            for command in commands {
+                tokio::time::sleep(delay).await;
+                let line = format!("{}\r", command);
+                record(&recorder, |recorder| recorder.record_input(line.as_bytes()));
+                audit(&input_audit, line.as_bytes());
+                if input_tx.send(Bytes::from(line)).await.is_err() {
+                    warn!("Lab session {} closed during the dry run", replay_session_id);
+                    return;
+                }
+            }
+            info!("Dry run on lab session {} finished", replay_session_id);
+        });
+    }
+
+    (StatusCode::CREATED, Json(CloneResponse {
+        success: true,
+        websocket_url: crate::websocket_url(&state.settings, &lab_session_id, &ws_token),
+        ws_token,
+        session_id: lab_session_id,
+        cloned_from: session_id,
+        lab_device: LabDevice {
+            id: device.id,
+            name: device.name,
+            hostname: device.hostname,
+            port: device.port,
+        },
+        replayed,
+        skipped,
+    })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_code::ErrorCode;
+    use crate::host_keys::{HostKeys, PresentedKey};
+    use crate::settings::{CommandRule, DeviceAcl, InventorySettings};
+    use std::sync::Arc;
+
+    fn device(name: &str, hostname: &str, tags: &[&str]) -> Device {
+        Device {
+            id: name.to_string(),
+            name: name.to_string(),
+            hostname: hostname.to_string(),
+            port: 22,
+            device_type: Some("cisco_ios".to_string()),
+            credentials_ref: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            lab_twin: None,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_clone_is_checked_against_the_lab_device() {
+        let mut settings = Settings::default();
+        settings.authorization.enabled = true;
+        settings.authorization.acls = vec![DeviceAcl {
+            name: "lab".to_string(),
+            subjects: vec!["alice".to_string()],
+            tags: vec!["lab".to_string()],
+            ..DeviceAcl::default()
+        }];
+        let alice = AuthenticatedUser { subject: "alice".to_string(), roles: vec!["operator".to_string()] };
+        let bob = AuthenticatedUser { subject: "bob".to_string(), roles: vec!["operator".to_string()] };
+        let lab_device = device("lab-core-1", "10.9.0.1", &["lab"]);
+        let device_type = lab_device.device_type.as_deref();
+        let replayed = vec!["configure terminal".to_string(), "reload".to_string()];
+
+        assert!(check_clone(&settings, Some(&alice), &lab_device, device_type, &replayed).is_ok());
+        // Bob may use the source session but has no ACL for the lab device
+        assert!(matches!(check_clone(&settings, Some(&bob), &lab_device, device_type, &replayed), Err(Refusal::Access(_))));
+
+        settings.command_policy.enabled = true;
+        settings.command_policy.rules = vec![CommandRule {
+            name: "no-reload".to_string(),
+            commands: vec!["rel[[oad]]".to_string()],
+            ..CommandRule::default()
+        }];
+        match check_clone(&settings, Some(&alice), &lab_device, device_type, &replayed) {
+            Err(Refusal::Command(blocked)) => assert_eq!(blocked.rule.as_deref(), Some("no-reload")),
+            other => panic!("replay of reload not refused: {:?}", other),
+        }
+        assert!(check_clone(&settings, Some(&alice), &lab_device, device_type, &replayed[..1]).is_ok());
+    }
+
+    #[test]
+    fn test_lab_host_key_is_checked_against_its_own_pin() {
+        let mut settings = Settings::default();
+        settings.host_keys.enabled = true;
+        settings.host_keys.pins_file = std::env::temp_dir().join(format!("webssh-host-keys-{}.json", uuid::Uuid::new_v4())).display().to_string();
+        let mut state = crate::test_state(settings.clone());
+        let keys = Arc::new(HostKeys::load(&settings.host_keys).unwrap());
+        state.host_keys = Some(keys.clone());
+        let inventory = Inventory::open(&InventorySettings { path: ":memory:".to_string(), ..InventorySettings::default() }).unwrap();
+        let key = PresentedKey::from_blob(b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+
+        // The session's device was connected to after confirming its key
+        let credentials = crate::SSHCredentials {
+            hostname: "10.0.0.1".to_string(),
+            username: "admin".to_string(),
+            host_key_fingerprint: Some(key.fingerprint.clone()),
+            ..Default::default()
+        };
+        let target = crate::connection_target(&state, &credentials, &settings, "alice");
+        let lab_device = device("lab-core-1", "10.9.0.1", &["lab"]);
+
+        let lab = lab_target(&state, &inventory, &target, &lab_device, None, "alice").unwrap();
+        let refused = lab.host_key_check.unwrap().verify_key(&lab.hostname, lab.port, key.clone()).unwrap_err();
+        assert_eq!(refused.error_code(), ErrorCode::HostkeyConfirmationRequired);
+        assert!(keys.pinned("10.9.0.1", 22).is_none());
+
+        // Confirmed in the clone request, it is pinned for the lab device
+        let lab = lab_target(&state, &inventory, &target, &lab_device, Some(key.fingerprint.clone()), "alice").unwrap();
+        assert!(lab.host_key_check.unwrap().verify_key(&lab.hostname, lab.port, key).is_ok());
+        assert_eq!(keys.pinned("10.9.0.1", 22).unwrap().pinned_by, "alice");
+        let _ = std::fs::remove_file(&settings.host_keys.pins_file);
+    }
+}
@@ -0,0 +1,467 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::jwt::AuthenticatedUser;
+use crate::session::{SessionInfo, SessionRegistry};
+use crate::settings::{LifetimeClass, SessionLifetimeSettings};
+use crate::store::EndReason;
+use crate::tasks::{RestartPolicy, TaskContext};
+use crate::AppState;
+
+/// The lifetime limit of a session, decided from its class when it is first checked
+#[derive(Debug, Clone)]
+pub struct SessionLifetime {
+    pub class: String,
+    /// None for a class without a limit
+    pub expires_at: Option<DateTime<Utc>>,
+    pub extensions: u32,
+    /// The last warning sent, in seconds before the end
+    warned_before: Option<u64>,
+    pub pending: Option<ExtensionRequest>,
+}
+
+/// A request for more time, waiting for an administrator
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtensionRequest {
+    pub id: String,
+    pub seconds: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The JWT subject of the requester, when JWT authentication is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// What came of a request for more time
+pub enum Extension {
+    Granted(DateTime<Utc>),
+    Pending(ExtensionRequest),
+}
+
+/// A request for more time that cannot be made
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("The session has no lifetime limit")]
+    Unlimited,
+    #[error("The session has had its {0} extensions")]
+    LimitReached(u32),
+    #[error("An extension is already waiting for approval")]
+    Pending,
+    #[error("seconds must be between 1 and {0}")]
+    InvalidDuration(u64),
+}
+
+impl ExtensionError {
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            ExtensionError::Unlimited => "lifetime_unlimited",
+            ExtensionError::LimitReached(_) => "extension_limit_reached",
+            ExtensionError::Pending => "extension_pending",
+            ExtensionError::InvalidDuration(_) => "invalid_duration",
+        }
+    }
+}
+
+/// The class of a session by its SSH username, as a name and its limits
+///
+/// Of the classes listing the username, the one with the shortest lifetime
+/// applies; sessions matching none are in the default class.
+pub fn classify<'a>(settings: &'a SessionLifetimeSettings, ssh_username: &str) -> Option<(&'a str, &'a LifetimeClass)> {
+    settings.classes.iter()
+        .filter(|(_, class)| class.usernames.iter().any(|username| username.eq_ignore_ascii_case(ssh_username)))
+        .min_by_key(|(name, class)| (lifetime_seconds(class), *name))
+        .or_else(|| settings.classes.get_key_value(&settings.default_class))
+        .map(|(name, class)| (name.as_str(), class))
+}
+
+fn lifetime_seconds(class: &LifetimeClass) -> u64 {
+    match class.max_lifetime_seconds {
+        0 => u64::MAX,
+        seconds => seconds,
+    }
+}
+
+impl SessionLifetime {
+    /// Decides the lifetime of a session created at `created_at`
+    ///
+    /// A session already past its limit, e.g. when limits are first enabled,
+    /// is still given the last warning before it is closed.
+    pub fn assign(settings: &SessionLifetimeSettings, created_at: DateTime<Utc>, ssh_username: &str, now: DateTime<Utc>) -> Option<Self> {
+        let (name, class) = classify(settings, ssh_username)?;
+        let expires_at = (class.max_lifetime_seconds > 0).then(|| {
+            let last_warning = settings.warn_before_seconds.iter().copied().min().unwrap_or(0);
+            (created_at + seconds(class.max_lifetime_seconds)).max(now + seconds(last_warning))
+        });
+        Some(Self { class: name.to_string(), expires_at, extensions: 0, warned_before: None, pending: None })
+    }
+
+    /// The warning due at `now`, if it has not been sent, in seconds before the end
+    ///
+    /// Only the closest warning is due; those already passed are skipped.
+    fn due_warning(&mut self, warn_before_seconds: &[u64], now: DateTime<Utc>) -> Option<u64> {
+        let remaining = (self.expires_at? - now).num_seconds().max(0) as u64;
+        let warning = warn_before_seconds.iter().copied()
+            .filter(|warning| remaining <= *warning)
+            .min()
+            .filter(|warning| self.warned_before.is_none_or(|warned| *warning < warned))?;
+        self.warned_before = Some(warning);
+        Some(warning)
+    }
+
+    /// Asks for more time, granted at once unless the class requires approval
+    pub fn request(
+        &mut self,
+        class: Option<&LifetimeClass>,
+        seconds: Option<u64>,
+        reason: Option<String>,
+        requested_by: Option<String>,
+    ) -> Result<Extension, ExtensionError> {
+        if self.expires_at.is_none() {
+            return Err(ExtensionError::Unlimited);
+        }
+        let Some(class) = class.filter(|class| self.extensions < class.max_extensions) else {
+            return Err(ExtensionError::LimitReached(self.extensions));
+        };
+        if self.pending.is_some() {
+            return Err(ExtensionError::Pending);
+        }
+        let seconds = seconds.unwrap_or(class.extension_seconds);
+        if seconds == 0 || seconds > class.extension_seconds {
+            return Err(ExtensionError::InvalidDuration(class.extension_seconds));
+        }
+        if !class.require_approval {
+            return Ok(Extension::Granted(self.extend(seconds)));
+        }
+        let request = ExtensionRequest {
+            id: Uuid::new_v4().to_string(),
+            seconds,
+            reason,
+            requested_by,
+            requested_at: Utc::now(),
+        };
+        self.pending = Some(request.clone());
+        Ok(Extension::Pending(request))
+    }
+
+    /// Grants the pending request, returning the new end of the session
+    pub fn approve(&mut self) -> Option<DateTime<Utc>> {
+        let request = self.pending.take()?;
+        Some(self.extend(request.seconds))
+    }
+
+    fn extend(&mut self, extension: u64) -> DateTime<Utc> {
+        let expires_at = self.expires_at.unwrap_or_else(Utc::now) + seconds(extension);
+        self.expires_at = Some(expires_at);
+        self.extensions += 1;
+        // The warnings start over for the new end
+        self.warned_before = None;
+        self.pending = None;
+        expires_at
+    }
+}
+
+fn seconds(seconds: u64) -> chrono::Duration {
+    chrono::Duration::seconds(seconds.min(i64::MAX as u64 / 1000) as i64)
+}
+
+/// Gives a session its lifetime if it does not have one yet
+fn ensure<'a>(session_info: &'a mut SessionInfo, settings: &SessionLifetimeSettings, now: DateTime<Utc>) -> Option<&'a mut SessionLifetime> {
+    if session_info.lifetime.is_none() {
+        session_info.lifetime = SessionLifetime::assign(settings, session_info.created_at, &session_info.ssh_username, now);
+    }
+    session_info.lifetime.as_mut()
+}
+
+/// Starts checking sessions against their lifetime limits
+///
+/// The settings are read on every pass, as they may be reloaded; while
+/// limits are disabled, nothing is checked.
+pub fn start(state: AppState) {
+    let tasks = state.tasks.clone();
+    tasks.spawn("session_lifetime", RestartPolicy::OnPanic, move |task| run(state.clone(), task));
+}
+
+async fn run(state: AppState, task: TaskContext) {
+    let mut interval_seconds = state.settings.session_lifetime.check_interval_seconds.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+    loop {
+        interval.tick().await;
+        let settings = state.policy.settings();
+        let lifetime = &settings.session_lifetime;
+        if lifetime.check_interval_seconds.max(1) != interval_seconds {
+            interval_seconds = lifetime.check_interval_seconds.max(1);
+            interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + Duration::from_secs(interval_seconds),
+                Duration::from_secs(interval_seconds),
+            );
+        }
+        if lifetime.enabled {
+            check(&mut *state.session_registry.lock().await, lifetime, Utc::now());
+        }
+        task.ran();
+    }
+}
+
+/// Warns the sessions nearing their end and closes those past it
+fn check(registry: &mut SessionRegistry, settings: &SessionLifetimeSettings, now: DateTime<Utc>) {
+    let mut expired = Vec::new();
+    for (session_id, session_info) in registry.sessions.iter_mut() {
+        let notifications = session_info.notifications.clone();
+        let Some(lifetime) = ensure(session_info, settings, now) else {
+            continue;
+        };
+        let Some(expires_at) = lifetime.expires_at else {
+            continue;
+        };
+        if expires_at <= now {
+            let _ = notifications.send(json!({
+                "type": "session_expired",
+                "message": format!("The session reached the end of its {} lifetime and is being closed", lifetime.class),
+                "class": lifetime.class,
+            }));
+            expired.push(session_id.clone());
+        } else if let Some(warning) = lifetime.due_warning(&settings.warn_before_seconds, now) {
+            info!("Session {} ends in {} seconds ({} lifetime)", session_id, (expires_at - now).num_seconds(), lifetime.class);
+            let _ = notifications.send(json!({
+                "type": "session_expiring",
+                "message": format!("The session will be closed in {} minute(s)", warning.div_ceil(60)),
+                "class": lifetime.class,
+                "expires_at": expires_at,
+                "remaining_seconds": (expires_at - now).num_seconds(),
+                "extensions_left": settings.classes.get(&lifetime.class)
+                    .map(|class| class.max_extensions.saturating_sub(lifetime.extensions))
+                    .unwrap_or(0),
+            }));
+        }
+    }
+    for session_id in &expired {
+        if registry.remove_session(session_id, EndReason::LifetimeExceeded) {
+            info!("Closed session {} at the end of its lifetime", session_id);
+        }
+    }
+}
+
+/// A session's lifetime, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct LifetimeStatus {
+    pub session_id: String,
+    pub enabled: bool,
+    pub class: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub remaining_seconds: Option<i64>,
+    pub extensions_used: u32,
+    pub max_extensions: u32,
+    pub pending_extension: Option<ExtensionRequest>,
+}
+
+fn status(session_id: &str, session_info: &mut SessionInfo, settings: &SessionLifetimeSettings) -> LifetimeStatus {
+    let now = Utc::now();
+    let created_at = session_info.created_at;
+    let lifetime = if settings.enabled { ensure(session_info, settings, now).cloned() } else { None };
+    let max_extensions = lifetime.as_ref()
+        .and_then(|lifetime| settings.classes.get(&lifetime.class))
+        .map(|class| class.max_extensions)
+        .unwrap_or(0);
+    let expires_at = lifetime.as_ref().and_then(|lifetime| lifetime.expires_at);
+    LifetimeStatus {
+        session_id: session_id.to_string(),
+        enabled: settings.enabled,
+        class: lifetime.as_ref().map(|lifetime| lifetime.class.clone()),
+        created_at,
+        expires_at,
+        remaining_seconds: expires_at.map(|expires_at| (expires_at - now).num_seconds().max(0)),
+        extensions_used: lifetime.as_ref().map(|lifetime| lifetime.extensions).unwrap_or(0),
+        max_extensions,
+        pending_extension: lifetime.and_then(|lifetime| lifetime.pending),
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn session_not_found(session_id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id))
+}
+
+fn lifetime_disabled() -> Response {
+    error_response(StatusCode::CONFLICT, "lifetime_disabled", "Session lifetime limits are not enabled".to_string())
+}
+
+/// Finds a session the caller may act on; authenticated users only ever see their own
+fn find_session<'a>(
+    registry: &'a mut SessionRegistry,
+    session_id: &str,
+    user: &Option<axum::Extension<AuthenticatedUser>>,
+) -> Option<&'a mut SessionInfo> {
+    registry.sessions.get_mut(session_id).filter(|session_info| match user {
+        Some(axum::Extension(user)) => user.owns(&session_info.portal_user_id),
+        None => true,
+    })
+}
+
+/// Reports a session's lifetime class, end and extensions
+pub async fn status_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    user: Option<axum::Extension<AuthenticatedUser>>,
+) -> Response {
+    let settings = state.policy.settings();
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = find_session(&mut registry, session_id, &user) else {
+        return session_not_found(session_id);
+    };
+    Json(status(session_id, session_info, &settings.session_lifetime)).into_response()
+}
+
+/// Body of a request for more time
+#[derive(Debug, Default, Deserialize)]
+pub struct ExtendRequest {
+    /// Defaults to the class's `extension_seconds`
+    pub seconds: Option<u64>,
+    pub reason: Option<String>,
+}
+
+/// Asks for a session to be kept open for longer
+pub async fn extend_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    user: Option<axum::Extension<AuthenticatedUser>>,
+    request: Option<Json<ExtendRequest>>,
+) -> Response {
+    let settings = state.policy.settings();
+    let settings = &settings.session_lifetime;
+    if !settings.enabled {
+        return lifetime_disabled();
+    }
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let requested_by = user.as_ref().map(|axum::Extension(user)| user.subject.clone());
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = find_session(&mut registry, session_id, &user) else {
+        return session_not_found(session_id);
+    };
+    let notifications = session_info.notifications.clone();
+    let Some(lifetime) = ensure(session_info, settings, Utc::now()) else {
+        return error_response(StatusCode::CONFLICT, ExtensionError::Unlimited.error_code(), ExtensionError::Unlimited.to_string());
+    };
+    let class = settings.classes.get(&lifetime.class);
+    match lifetime.request(class, request.seconds, request.reason, requested_by) {
+        Ok(Extension::Granted(expires_at)) => {
+            info!("Session {} extended until {} ({} lifetime)", session_id, expires_at, lifetime.class);
+            let _ = notifications.send(json!({ "type": "session_extended", "expires_at": expires_at }));
+            Json(status(session_id, session_info, settings)).into_response()
+        }
+        Ok(Extension::Pending(request)) => {
+            info!("Session {} asks for {} more seconds ({} lifetime), awaiting approval", session_id, request.seconds, lifetime.class);
+            (StatusCode::ACCEPTED, Json(status(session_id, session_info, settings))).into_response()
+        }
+        Err(e @ ExtensionError::InvalidDuration(_)) => error_response(StatusCode::BAD_REQUEST, e.error_code(), e.to_string()),
+        Err(e) => error_response(StatusCode::CONFLICT, e.error_code(), e.to_string()),
+    }
+}
+
+/// Grants or refuses a session's pending extension
+async fn decide(state: AppState, session_id: String, approve: bool) -> Response {
+    let settings = state.policy.settings();
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.sessions.get_mut(session_id) else {
+        return session_not_found(session_id);
+    };
+    let notifications = session_info.notifications.clone();
+    let Some(lifetime) = session_info.lifetime.as_mut().filter(|lifetime| lifetime.pending.is_some()) else {
+        return error_response(StatusCode::NOT_FOUND, "no_pending_extension",
+                              format!("Session '{}' has no extension waiting for approval", session_id));
+    };
+    if approve {
+        if let Some(expires_at) = lifetime.approve() {
+            info!("Extension of session {} approved, until {}", session_id, expires_at);
+            let _ = notifications.send(json!({ "type": "session_extended", "expires_at": expires_at }));
+        }
+    } else {
+        lifetime.pending = None;
+        info!("Extension of session {} denied", session_id);
+        let _ = notifications.send(json!({
+            "type": "session_extension_denied",
+            "message": "The request for more time was denied",
+        }));
+    }
+    Json(status(session_id, session_info, &settings.session_lifetime)).into_response()
+}
+
+/// Approves a session's pending extension
+pub async fn approve_handler(State(state): State<AppState>, Path(session_id): Path<String>) -> Response {
+    decide(state, session_id, true).await
+}
+
+/// Denies a session's pending extension
+pub async fn deny_handler(State(state): State<AppState>, Path(session_id): Path<String>) -> Response {
+    decide(state, session_id, false).await
+}
+
+/// Lists the extensions waiting for approval
+pub async fn pending_handler(State(state): State<AppState>) -> Response {
+    let settings = state.policy.settings();
+    let mut registry = state.session_registry.lock().await;
+    let mut pending: Vec<LifetimeStatus> = registry.sessions.iter_mut()
+        .filter(|(_, session_info)| session_info.lifetime.as_ref().is_some_and(|lifetime| lifetime.pending.is_some()))
+        .map(|(session_id, session_info)| status(session_id, session_info, &settings.session_lifetime))
+        .collect();
+    pending.sort_by_key(|status| status.pending_extension.as_ref().map(|request| request.requested_at));
+    Json(json!({ "requests": pending })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classes_warnings_and_extensions() {
+        let settings = SessionLifetimeSettings::default();
+        assert_eq!(classify(&settings, "ROOT").unwrap().0, "privileged");
+        assert_eq!(classify(&settings, "netops").unwrap().0, "standard");
+
+        let created_at = Utc::now();
+        let mut lifetime = SessionLifetime::assign(&settings, created_at, "admin", created_at).unwrap();
+        let expires_at = created_at + chrono::Duration::hours(1);
+        assert_eq!(lifetime.expires_at, Some(expires_at));
+
+        // Warnings cascade once each; one already passed is skipped
+        let warnings = &settings.warn_before_seconds;
+        assert_eq!(lifetime.due_warning(warnings, expires_at - chrono::Duration::minutes(20)), None);
+        assert_eq!(lifetime.due_warning(warnings, expires_at - chrono::Duration::minutes(14)), Some(900));
+        assert_eq!(lifetime.due_warning(warnings, expires_at - chrono::Duration::minutes(13)), None);
+        assert_eq!(lifetime.due_warning(warnings, expires_at - chrono::Duration::seconds(30)), Some(60));
+
+        // Privileged sessions wait for approval, once
+        let class = settings.classes.get("privileged");
+        assert!(matches!(lifetime.request(class, Some(7200), None, None), Err(ExtensionError::InvalidDuration(1800))));
+        assert!(matches!(lifetime.request(class, None, None, None), Ok(Extension::Pending(_))));
+        assert!(matches!(lifetime.request(class, None, None, None), Err(ExtensionError::Pending)));
+        assert_eq!(lifetime.approve(), Some(expires_at + chrono::Duration::minutes(30)));
+        assert_eq!(lifetime.due_warning(warnings, expires_at + chrono::Duration::minutes(20)), Some(900));
+        assert!(matches!(lifetime.request(class, None, None, None), Err(ExtensionError::LimitReached(1))));
+
+        // A session found past its end still gets the last warning
+        let late = SessionLifetime::assign(&settings, created_at - chrono::Duration::hours(9), "netops", created_at).unwrap();
+        assert_eq!(late.expires_at, Some(created_at + chrono::Duration::minutes(1)));
+    }
+}
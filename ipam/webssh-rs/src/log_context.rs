@@ -0,0 +1,72 @@
+//! Who a log event is about, as structured fields rather than words in its message
+//!
+//! Everything done for a session runs inside its span, so each event carries
+//! the session, portal user and device it belongs to. JSON logs put them under
+//! `span`, where a log collector can index them; compact logs print them
+//! after the message, and pretty logs on the line below it.
+
+use tracing::{field, info_span, Span};
+
+/// The span a session's events are logged in
+///
+/// A connection still being made has no session ID yet; `identify` adds it.
+pub fn session_span(session_id: Option<&str>, portal_user_id: &str, device_id: &str) -> Span {
+    let span = info_span!("session", session_id = field::Empty, portal_user_id, device_id);
+    if let Some(session_id) = session_id {
+        identify(&span, session_id);
+    }
+    span
+}
+
+/// Names the session a connection's span belongs to, once it has an ID
+pub fn identify(span: &Span, session_id: &str) {
+    span.record("session_id", session_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+    use crate::settings::{LogFormat, LoggingSettings};
+    use std::sync::{Arc, Mutex};
+    use tracing::Instrument;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_events_carry_the_session() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let settings = LoggingSettings { level: "info".to_string(), format: LogFormat::Json };
+        let subscriber = cli::subscriber(&settings, move || writer.clone());
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let span = session_span(None, "alice", "router1");
+        async { tracing::info!("connecting") }.instrument(span.clone()).await;
+        identify(&span, "s1");
+        span.in_scope(|| tracing::warn!("connected"));
+        tracing::info!("outside");
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["span"]["portal_user_id"], "alice");
+        assert_eq!(lines[0]["span"]["device_id"], "router1");
+        assert!(lines[0]["span"].get("session_id").is_none());
+        assert_eq!(lines[1]["span"]["session_id"], "s1");
+        assert_eq!(lines[1]["fields"]["message"], "connected");
+        assert!(lines[2].get("span").is_none());
+    }
+}
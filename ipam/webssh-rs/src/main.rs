@@ -4,47 +4,428 @@ mod ssh;
 // - ssh/error.rs: Error types
 // - ssh/channel.rs: Channel setup functions
 // - ssh/session.rs: SSHSession implementation
+// - ssh/target.rs: Connection parameters and authentication
+// - ssh/backend.rs: Shell I/O backend trait
+// - ssh/tunnel.rs: Jump host tunnels
+// - ssh/pool.rs: Connections shared with exec requests
+// - ssh/telnet.rs: Telnet sessions for legacy devices
 mod websocket;
 mod settings;
 mod session;
 mod protocol;
+#[cfg(test)]
+mod protocol_tests;
+mod sftp;
+mod api_keys;
+mod recording;
+mod jwt;
+mod affinity;
+mod interactive_auth;
+mod validate;
+mod replay;
+mod file_server;
+mod tftp;
+mod store;
+mod credential_policy;
+mod forward;
+mod watchdog;
+mod policy;
+mod tls;
+mod scrollback;
+mod capture;
+mod runs;
+mod share;
+mod audit;
+mod http;
+mod http_client;
+mod ssh_ca;
+mod exec;
+mod bulk;
+mod inventory;
+mod textfsm;
+mod parsing;
+mod presence;
+mod terminal;
+mod rate_limit;
+mod lab;
+mod coalesce;
+mod credentials;
+mod error_code;
+mod reload;
+mod openapi;
+mod lifetime;
+mod command_policy;
+mod redis_client;
+mod cluster;
+mod faults;
+mod authz;
+mod webhooks;
+mod password_change;
+mod maintenance;
+mod ws_token;
+mod transform;
+mod device_lock;
+mod config_backup;
+mod keepalive;
+mod alerts;
+mod cli;
+mod reachability;
+mod ws_deflate;
+mod screen_feed;
+mod log_context;
+mod timeline;
+mod host_keys;
+mod attach;
+mod export;
+#[cfg(feature = "fault-injection")]
+mod soak;
+mod tasks;
 
 use axum::{
     extract::{
-        ws::{WebSocket, WebSocketUpgrade},
-        State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Query, RawQuery, State,
     },
+    http::{header, HeaderMap},
+    middleware,
     response::{Html, IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
-    http::Method,
+    Extension, Json, Router,
 };
-use tower_http::cors::{CorsLayer, Any};
-use bytes::Bytes;
+use tower_http::limit::RequestBodyLimitLayer;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 // Collections removed - not used in current implementation
 use std::time::Duration;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
 use tower_http::services::ServeDir;
-use tracing::{error, info, debug, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{error, info, debug, warn, Instrument};
+use clap::Parser;
 
-use crate::{settings::Settings, ssh::SSHSession, websocket::WebSocketHandler, session::SessionRegistry};
+use crate::{settings::{CompressionMode, DeviceLockPolicy, PassthroughSettings, PtyModeValue, RegistryBackend, Settings, WebSocketSettings, WebhookEventType}, ssh::{ChannelOwner, ConnectionInfo, ConnectionTarget, JumpHost, Protocol, PtyRequest, SSHSession, SharedConnection, Shell}, websocket::WebSocketHandler, session::{Attachment, Lifecycle, LifecycleState, PendingAuth, SessionLimitExceeded, SessionRegistry, StaleSession}};
+use crate::api_keys::ApiKeyStore;
+use crate::replay::{OutputWatches, ShellStream};
+use crate::jwt::{AuthenticatedUser, JwtValidator};
+use crate::authz::SessionAccess;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::accept::DefaultAcceptor;
+use crate::affinity::NodeIdentity;
+use crate::cluster::SessionDirectory;
+use crate::webhooks::Webhooks;
+use crate::password_change::{PasswordChangeRules, WriteBack};
+use crate::maintenance::Maintenance;
+use crate::ws_token::{Redemption, WsTokenError};
+use crate::openapi::Access;
+use crate::transform::{OutputPipeline, OutputStages, StageContext};
+use crate::device_lock::DeviceLocks;
+use crate::config_backup::ConfigBackups;
+use crate::keepalive::ConnectionHealth;
+use crate::alerts::Alerts;
+use crate::reachability::Reachability;
+use crate::interactive_auth::AuthEvent;
+use crate::store::{EndReason, SessionRecord, SqliteStore};
+use crate::watchdog::{WatchdogMetrics, WatchdogStatus};
+use crate::policy::PolicyStore;
+use crate::scrollback::ScrollbackStore;
+use crate::runs::RunTracker;
+use crate::share::ShareRole;
+use crate::audit::{AuditContext, AuditLog, CommandAudit};
+use crate::command_policy::CommandFilter;
+use crate::http::{ClientIp, HttpPolicy};
+use crate::ssh_ca::{CertificateError, SshCa};
+use crate::ssh::error::SSHError;
+use crate::ssh::pkcs11::{Pkcs11Key, Pkcs11Login};
+use crate::protocol::PerformanceStats;
+use crate::inventory::{Inventory, InventoryError};
+use crate::parsing::TemplateLibrary;
+use crate::presence::{Participant, PresenceRole};
+use crate::terminal::{RequestedTerminal, TerminalTypes};
+use crate::rate_limit::ConnectLimiter;
+use crate::tasks::{RestartPolicy, TaskContext, TaskSupervisor};
+use crate::error_code::ErrorCode;
+use crate::credentials::{CredentialError, CredentialStore};
+use crate::coalesce::InputStatsSnapshot;
+use crate::ws_deflate::DeflateAcceptor;
+use crate::host_keys::{HostKeyCheck, HostKeys, PresentedKey};
+use crate::bulk::BulkJobs;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct SSHCredentials {
+    // May be left out when the device is named with `device_ref`
+    #[serde(default)]
     hostname: String,
+    #[serde(default)]
     port: u16,
+    #[serde(default)]
     username: String,
     password: Option<String>,
     private_key: Option<String>,
+    private_key_passphrase: Option<String>, // Passphrase of an encrypted private key
     device_type: Option<String>, // Optional field to explicitly specify device type
-    auth_type: Option<String>,   // Optional field to specify auth type (password or private-key)
+    auth_type: Option<String>,   // Optional field to specify auth type (password, private-key or keyboard-interactive)
     portal_user_id: Option<String>, // Added field for portal user identification
     enable_password: Option<String>, // Added field for enable password for network devices
     device_name: Option<String>, // Added field for friendly device name display
     session_id: Option<String>,  // Added field for session ID from backend
+    compression: Option<CompressionMode>, // Overrides the device profile and global compression setting
+    jump_host: Option<JumpHost>, // Bastion to tunnel the connection through
+    #[serde(default)]
+    device_ref: Option<String>, // Inventory device to connect to, by ID or name
+    #[serde(default)]
+    credential_ref: Option<String>, // Where to read the credentials, e.g. "vault:secret/network/router1"
+    #[serde(default)]
+    protocol: Option<Protocol>, // ssh (the default) or telnet
+    #[serde(default)]
+    term: Option<String>, // TERM for the shell, e.g. "xterm-256color" or "vt100"
+    #[serde(default)]
+    cols: Option<u32>, // Initial terminal size, so the first screen fits the client's window
+    #[serde(default)]
+    rows: Option<u32>,
+    #[serde(default)]
+    width_px: Option<u32>, // Initial terminal size in pixels, for curses applications that need it
+    #[serde(default)]
+    height_px: Option<u32>,
+    #[serde(default)]
+    pty_modes: BTreeMap<String, PtyModeValue>, // PTY modes by termios name, e.g. {"ECHO": false}
+    #[serde(default)]
+    env: BTreeMap<String, String>, // Environment variables for the shell, e.g. LANG; only allowed names are sent
+    #[serde(default)]
+    pkcs11: Option<Pkcs11Key>, // Key in an HSM or smartcard, with "auth_type": "pkcs11"
+    #[serde(default)]
+    passthrough: bool, // Send output straight to the WebSocket, neither recorded nor inspected
+    #[serde(skip)]
+    device_tags: Vec<String>, // Tags of the inventory device named by device_ref, for the device ACLs
+    #[serde(skip)]
+    host_key_fingerprint: Option<String>, // Host key the user confirmed through /api/connect/confirm
+}
+
+/// A connect request sent again with the host key fingerprint the user confirmed
+#[derive(Debug, Deserialize)]
+struct ConfirmRequest {
+    #[serde(flatten)]
+    credentials: SSHCredentials,
+    host_key_fingerprint: String,
+}
+
+/// `auth_type` requesting keyboard-interactive authentication (e.g. OTP challenges)
+const KEYBOARD_INTERACTIVE: &str = "keyboard-interactive";
+
+/// `auth_type` requesting a short-lived certificate from the gateway's SSH CA
+const CERTIFICATE: &str = "certificate";
+
+/// `auth_type` requesting authentication with the gateway host's ssh-agent
+const AGENT: &str = "agent";
+
+/// `auth_type` requesting authentication with a key held in an HSM or smartcard
+const PKCS11: &str = "pkcs11";
+
+/// `auth_type` requesting authentication with the gateway's Kerberos ticket
+const GSSAPI: &str = "gssapi";
+
+/// Fills in a request naming an inventory device with the device's profile
+fn resolve_device(state: &AppState, credentials: SSHCredentials) -> Result<SSHCredentials, InventoryError> {
+    match &state.inventory {
+        Some(inventory) => inventory.resolve(credentials),
+        None if credentials.device_ref.is_some() => {
+            Err(InventoryError::Unavailable("the device inventory is not enabled".to_string()))
+        }
+        None => Ok(credentials),
+    }
+}
+
+/// Why the device or credentials a connect request names could not be looked up
+#[derive(Debug, thiserror::Error)]
+enum LookupError {
+    #[error(transparent)]
+    Device(#[from] InventoryError),
+    #[error(transparent)]
+    Credentials(#[from] CredentialError),
+    #[error("Invalid hostname: {0}")]
+    Address(String),
+}
+
+impl LookupError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            LookupError::Device(e) => e.error_code(),
+            LookupError::Credentials(e) => e.error_code(),
+            LookupError::Address(_) => ErrorCode::InvalidAddress,
+        }
+    }
+}
+
+/// Fills in a request from the inventory device and the stored credentials it names
+///
+/// The hostname is normalized, e.g. `[fe80::1%25mgmt0]` to `fe80::1%mgmt0`, and checked.
+async fn resolve_request(state: &AppState, credentials: SSHCredentials) -> Result<SSHCredentials, LookupError> {
+    let credentials = fetch_credentials(state, credentials).await?;
+    let mut credentials = resolve_device(state, credentials)?;
+    credentials.hostname = ssh::host::normalize(&credentials.hostname);
+    ssh::host::validate(&credentials.hostname).map_err(LookupError::Address)?;
+    if let Some(jump_host) = credentials.jump_host.as_mut() {
+        jump_host.hostname = ssh::host::normalize(&jump_host.hostname);
+        ssh::host::validate(&jump_host.hostname).map_err(LookupError::Address)?;
+    }
+    Ok(credentials)
+}
+
+/// Fills in a request's credentials from the provider its `credential_ref` names
+///
+/// A request naming its credentials this way cannot also carry a password
+/// or key; the stored username is used unless the request has one.
+async fn fetch_credentials(state: &AppState, mut credentials: SSHCredentials) -> Result<SSHCredentials, CredentialError> {
+    let Some(reference) = credentials.credential_ref.clone() else {
+        return Ok(credentials);
+    };
+    let invalid = |reason: &str| CredentialError::Invalid(reference.clone(), reason.to_string());
+    let Some(store) = &state.credentials else {
+        return Err(invalid("credential references are not enabled on this instance"));
+    };
+    if credentials.password.is_some() || credentials.private_key.is_some() {
+        return Err(invalid("the request carries credentials of its own"));
+    }
+    let stored = store.fetch(reference.trim()).await?;
+    if credentials.username.is_empty() {
+        credentials.username = stored.username.unwrap_or_default();
+    }
+    credentials.password = stored.password;
+    credentials.private_key = stored.private_key;
+    credentials.private_key_passphrase = stored.private_key_passphrase;
+    credentials.auth_type = credentials.auth_type.or(stored.auth_type);
+    credentials.enable_password = credentials.enable_password.or(stored.enable_password);
+    Ok(credentials)
+}
+
+/// Drops cached credentials a device refused, so the next attempt reads them afresh
+fn forget_refused(state: &AppState, credential_ref: Option<&str>, error_code: Option<ErrorCode>) {
+    if let (Some(store), Some(reference), Some(ErrorCode::AuthFailed)) = (&state.credentials, credential_ref, error_code) {
+        store.forget(reference.trim());
+    }
+}
+
+/// Counts a failed connection towards the device's alert threshold, and tells
+/// the webhooks if the device refused the credentials
+fn report_connect_failure(state: &AppState, error_code: ErrorCode, portal_user_id: &str, device_id: &str, ssh_username: &str, message: &str) {
+    state.alerts.connect_failed(device_id, error_code);
+    if let (Some(webhooks), ErrorCode::AuthFailed) = (&state.webhooks, error_code) {
+        webhooks.send(WebhookEventType::AuthFailed, serde_json::json!({
+            "portal_user_id": portal_user_id,
+            "device_id": device_id,
+            "ssh_username": ssh_username,
+            "message": message,
+        }));
+    }
+}
+
+/// Checks that a telnet connect request asks only for what telnet offers
+///
+/// The device is logged in to in the terminal, so only a username and
+/// password can be passed on, and there is no transport to tunnel.
+fn check_telnet(settings: &Settings, credentials: &SSHCredentials) -> Result<(), &'static str> {
+    if credentials.protocol != Some(Protocol::Telnet) {
+        return Ok(());
+    }
+    if !settings.telnet.enabled {
+        return Err("Telnet is not enabled on this instance");
+    }
+    if credentials.private_key.is_some() || credentials.auth_type.as_deref().is_some_and(|auth_type| auth_type != "password") {
+        return Err("Telnet devices can only be logged in to with a username and password");
+    }
+    if credentials.jump_host.is_some() {
+        return Err("Telnet connections cannot go through a jump host");
+    }
+    Ok(())
+}
+
+/// Checks that a connect request asking for pass-through mode may have it
+///
+/// Keyboard-interactive prompts and token PINs are relayed by inspecting the
+/// WebSocket traffic, so such logins cannot be passed through.
+fn check_passthrough(settings: &PassthroughSettings, user: Option<&AuthenticatedUser>, credentials: &SSHCredentials) -> Result<(), &'static str> {
+    if !credentials.passthrough {
+        return Ok(());
+    }
+    if !settings.enabled {
+        return Err("Pass-through mode is not enabled on this instance");
+    }
+    if credentials.auth_type.as_deref() == Some(KEYBOARD_INTERACTIVE) {
+        return Err("Pass-through sessions cannot use keyboard-interactive authentication");
+    }
+    if credentials.auth_type.as_deref() == Some(PKCS11) {
+        return Err("Pass-through sessions cannot use PKCS#11 keys");
+    }
+    let allowed = settings.roles.is_empty()
+        || user.is_some_and(|user| user.roles.iter().any(|role| settings.roles.contains(role)));
+    if !allowed {
+        return Err("Pass-through mode is not allowed for your roles");
+    }
+    Ok(())
+}
+
+/// Whether the device's password is asked of the user over the WebSocket
+///
+/// Only a request that brings no credentials and names no other way to log in
+/// is prompted for, and only over SSH; telnet devices prompt in the terminal anyway.
+fn prompts_for_password(settings: &Settings, credentials: &SSHCredentials) -> bool {
+    settings.ssh.connection.prompt_for_password
+        && credentials.password.is_none()
+        && credentials.private_key.is_none()
+        && credentials.auth_type.as_deref().is_none_or(|auth_type| auth_type == "password")
+        && credentials.protocol.unwrap_or_default() == Protocol::Ssh
+        && !credentials.passthrough
+}
+
+/// Builds the connection parameters for a connect request
+///
+/// With host key confirmation enabled, an SSH device must present its
+/// pinned key, or the one whose fingerprint the request confirms.
+fn connection_target(state: &AppState, credentials: &SSHCredentials, settings: &Settings, portal_user_id: &str) -> ConnectionTarget {
+    let device_type = credentials.device_type.as_ref().map(|hint| hint.to_lowercase());
+    let protocol = credentials.protocol.unwrap_or_default();
+    let mut target = ConnectionTarget {
+        hostname: credentials.hostname.clone(),
+        port: if credentials.port == 0 { protocol.default_port() } else { credentials.port },
+        username: credentials.username.clone(),
+        password: credentials.password.clone(),
+        private_key: credentials.private_key.clone(),
+        private_key_passphrase: credentials.private_key_passphrase.clone(),
+        certificate: None,
+        compression: settings.compression_mode(credentials.compression, device_type.as_deref()),
+        terminal_type: settings.profile(device_type.as_deref()).and_then(|profile| profile.terminal_type.clone()),
+        terminal_size: None,
+        environment: Vec::new(),
+        pty: PtyRequest::default(),
+        host_key_check: None,
+        quirks: settings.profile(device_type.as_deref()).map(|profile| profile.quirks).unwrap_or_default(),
+        device_type,
+        jump_host: credentials.jump_host.as_ref().map(|jump_host| Box::new(jump_host.to_target(&settings.ssh))),
+        keyboard_interactive: credentials.auth_type.as_deref() == Some(KEYBOARD_INTERACTIVE),
+        agent: credentials.auth_type.as_deref() == Some(AGENT),
+        pkcs11: (credentials.auth_type.as_deref() == Some(PKCS11))
+            .then(|| Pkcs11Login { key: credentials.pkcs11.clone().unwrap_or_default(), pin: None }),
+        gssapi: credentials.auth_type.as_deref() == Some(GSSAPI),
+        protocol,
+        settings: settings.ssh.clone(),
+    };
+    check_host_keys(state, &mut target, credentials.host_key_fingerprint.clone(), portal_user_id);
+    target
+}
+
+/// Checks the host keys of a device and its jump host against their pins, if host key confirmation is enabled
+///
+/// The bastion is pinned by its own host and port. A key not pinned yet is
+/// pinned if its fingerprint is `confirmed`, on behalf of the portal user;
+/// the device and its bastion are confirmed one after the other.
+fn check_host_keys(state: &AppState, target: &mut ConnectionTarget, confirmed: Option<String>, portal_user_id: &str) {
+    let check = |target: &ConnectionTarget| state.host_keys.clone()
+        .filter(|_| target.protocol == Protocol::Ssh && !target.gssapi)
+        .map(|keys| HostKeyCheck { keys, confirmed: confirmed.clone(), portal_user_id: portal_user_id.to_string() });
+    if let Some(jump_host) = target.jump_host.as_mut() {
+        jump_host.host_key_check = check(jump_host);
+    }
+    target.host_key_check = check(target);
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,218 +434,1502 @@ struct ConnectResponse {
     message: String,
     session_id: Option<String>,
     websocket_url: Option<String>,
-    error_code: Option<String>,
+    // Lets one WebSocket attach to the session; also in websocket_url
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ws_token: Option<String>,
+    error_code: Option<ErrorCode>,
+    node_id: String,
+    // The client must answer keyboard-interactive prompts over the WebSocket
+    auth_pending: bool,
+    // The device type given, or else the one recognised from the device's banner and prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_type: Option<String>,
+    // Credential policy findings that did not block the connection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    // The device's host key, when it has to be confirmed or has changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_key: Option<PresentedKey>,
 }
 
 #[derive(Clone)]
 struct AppState {
     session_registry: Arc<Mutex<SessionRegistry>>,
     settings: Arc<Settings>,
+    api_keys: Arc<Mutex<ApiKeyStore>>,
+    jwt: Option<Arc<JwtValidator>>,
+    node: Arc<NodeIdentity>,
+    // Where the instances behind a load balancer find each other's sessions, if in Redis
+    directory: Option<Arc<SessionDirectory>>,
+    watchdog: Arc<WatchdogMetrics>,
+    policy: Arc<PolicyStore>,
+    scrollback: Arc<ScrollbackStore>,
+    audit: Option<Arc<AuditLog>>,
+    http: Arc<HttpPolicy>,
+    ssh_ca: Option<Arc<SshCa>>,
+    inventory: Option<Arc<Inventory>>,
+    credentials: Option<Arc<CredentialStore>>,
+    templates: Option<Arc<TemplateLibrary>>,
+    terminal_types: Arc<TerminalTypes>,
+    connect_limiter: Arc<ConnectLimiter>,
+    // Where session lifecycle and security events are posted, if anywhere
+    webhooks: Option<Arc<Webhooks>>,
+    // Prompts of forced password changes relayed as secret frames, if enabled
+    password_change: Option<Arc<PasswordChangeRules>>,
+    // Refuses new connections while the instance is drained
+    maintenance: Arc<Maintenance>,
+    // What sessions' output can pass through on its way to the clients
+    output_stages: Arc<OutputStages>,
+    // Scheduled backups of inventory devices' configurations, if enabled
+    config_backups: Option<Arc<ConfigBackups>>,
+    // The background loops, stopped at shutdown
+    tasks: Arc<TaskSupervisor>,
+    // Counts of abnormal session ends, reconnects and failed connections, checked against the alert thresholds
+    alerts: Arc<Alerts>,
+    // The settings file and the command line's overrides, read again on reload
+    settings_source: Arc<cli::SettingsSource>,
+    // Latest probes of the inventory devices, if enabled
+    reachability: Option<Arc<Reachability>>,
+    // Pinned device host keys, if new keys wait for the user's confirmation
+    host_keys: Option<Arc<HostKeys>>,
+    // Bulk exec jobs, running or kept for their results
+    bulk_jobs: Arc<BulkJobs>,
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging with production-ready configuration
-    let log_level = std::env::var("RUST_LOG")
-        .unwrap_or_else(|_| "info".to_string())
-        .parse::<Level>()
-        .unwrap_or(Level::INFO);
-
-    FmtSubscriber::builder()
-        .with_max_level(log_level)
-        .with_level(false)  // Hide log levels in production
-        .with_thread_ids(false)  // Hide thread IDs in production
-        .with_target(false)  // Hide targets in production
-        .with_file(false)  // Hide file names in production
-        .with_line_number(false)  // Hide line numbers in production
-        .compact()  // Use compact format for production
-        .init();
-
-    // Load settings
-    let settings = Arc::new(Settings::load());
+    let cli = cli::Cli::parse();
+    match &cli.command {
+        None | Some(cli::Command::Serve) => {}
+        Some(cli::Command::CheckConfig) => exit_with(cli::check_config(&cli.source).await),
+        Some(cli::Command::ListSessions(args)) => exit_with(cli::list_sessions(&cli.source, args).await),
+        Some(cli::Command::Attach(args)) => exit_with(attach::run_cli(&cli.source, args).await),
+        Some(cli::Command::Version) => return cli::print_version(),
+        // `webssh-rs policy ...` works on the local policy file and exits
+        Some(cli::Command::Policy { args }) => exit_with(policy::run_cli(cli.source.load().0, args)),
+        // `webssh-rs soak ...` drives synthetic sessions through a running gateway
+        #[cfg(feature = "fault-injection")]
+        Some(cli::Command::Soak { args }) => exit_with(soak::run_cli(args).await),
+    }
+
+    // Settings come from the flags, then the environment, then the settings file, then the defaults
+    let settings_source = Arc::new(cli.source);
+    let (loaded, origin) = settings_source.load();
+    cli::init_logging(&loaded.logging);
+    origin.log(&settings_source.config);
+
+    // Load settings, with the last imported policy bundle applied
+    let policy = Arc::new(PolicyStore::load(loaded));
+    let settings = policy.settings();
     info!("Settings loaded");
 
-    // Initialize session registry
-    let session_registry = Arc::new(Mutex::new(SessionRegistry::new()));
+    // Initialize session registry, with its history and the run history if storage is enabled
+    let (registry, runs) = if settings.storage.enabled {
+        match SqliteStore::open(&settings.storage.path) {
+            Ok(store) => {
+                info!("Session history stored in {}", settings.storage.path);
+                let store = Arc::new(store);
+                let retention = chrono::Duration::days(settings.storage.retention_days.into());
+                let runs = RunTracker::start(store.clone(), retention);
+                let registry = SessionRegistry::with_store(store, retention);
+                if let Some(runs) = &runs {
+                    runs.report(&registry);
+                }
+                (registry, runs.map(Arc::new))
+            }
+            Err(e) => {
+                error!("Failed to open session store {}: {}; history will not be kept", settings.storage.path, e);
+                (SessionRegistry::new(), None)
+            }
+        }
+    } else {
+        (SessionRegistry::new(), None)
+    };
+    let session_registry = Arc::new(Mutex::new(registry.with_limits(settings.session_limits.clone()).with_timeline(settings.timeline.clone())));
+    if let Some(runs) = &runs {
+        runs.watch(session_registry.clone());
+    }
+    
+    // Load API keys
+    let api_keys = Arc::new(Mutex::new(ApiKeyStore::load(&settings.api_keys)));
+    
+    // Build the JWT validator; a broken auth configuration must not start an open server
+    let jwt = if settings.jwt.enabled {
+        match JwtValidator::from_settings(&settings.jwt) {
+            Ok(validator) => {
+                info!("JWT authentication enabled ({})", settings.jwt.algorithm);
+                Some(Arc::new(validator))
+            }
+            Err(e) => {
+                error!("Invalid JWT configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let http = match HttpPolicy::from_settings(&settings.http) {
+        Ok(http) => Arc::new(http),
+        Err(e) => {
+            error!("Invalid HTTP configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    if let Err(e) = ssh::source::validate(&settings.ssh.outbound) {
+        error!("Invalid outbound connection configuration: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = terminal::validate(&settings.ssh.terminal) {
+        error!("Invalid terminal configuration: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = ssh::gssapi::validate(&settings.ssh.gssapi) {
+        error!("Invalid Kerberos configuration: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = command_policy::validate(&settings.command_policy) {
+        error!("Invalid command policy: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = authz::validate(&settings.authorization) {
+        error!("Invalid authorization settings: {}", e);
+        std::process::exit(1);
+    }
+    if settings.server.control_plane.require_api_key && !settings.api_keys.enabled {
+        error!("server.control_plane.require_api_key needs api_keys.enabled");
+        std::process::exit(1);
+    }
+    if settings.authorization.enabled && !settings.jwt.enabled {
+        warn!("Authorization is enabled but JWT authentication is not; no request will carry a role");
+    }
+
+    if let Err(e) = ssh::socks::DestinationFilter::new(&settings.forwarding.socks) {
+        error!("Invalid SOCKS configuration: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = protocol::validate(&settings.binary_protocol) {
+        error!("Invalid binary protocol settings: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = ws_deflate::validate(&settings.websocket) {
+        error!("Invalid WebSocket settings: {}", e);
+        std::process::exit(1);
+    }
+    
+    let inventory = if settings.inventory.enabled {
+        match Inventory::open(&settings.inventory) {
+            Ok(inventory) => {
+                info!("Device inventory stored in {}", settings.inventory.path);
+                Some(Arc::new(inventory))
+            }
+            Err(e) => {
+                error!("Failed to open device inventory {}: {}", settings.inventory.path, e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let config_backups = match settings.config_backup.enabled {
+        false => None,
+        true if inventory.is_none() => {
+            error!("Configuration backups need the device inventory; enable inventory or turn config_backup off");
+            std::process::exit(1);
+        }
+        true => match ConfigBackups::new(&settings.config_backup) {
+            Ok(backups) => {
+                info!("Device configurations backed up to {} every {}s", settings.config_backup.directory,
+                      settings.config_backup.interval_seconds.max(60));
+                Some(Arc::new(backups))
+            }
+            Err(e) => {
+                error!("Invalid configuration backup settings: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    
+    let reachability = match settings.reachability.enabled {
+        false => None,
+        true if inventory.is_none() => {
+            error!("Reachability probes need the device inventory; enable inventory or turn reachability off");
+            std::process::exit(1);
+        }
+        true => {
+            info!("Inventory devices probed for reachability every {}s", settings.reachability.interval_seconds.max(10));
+            Some(Arc::new(Reachability::new()))
+        }
+    };
+    
+    let templates = if settings.parsing.enabled {
+        match TemplateLibrary::load(&settings.parsing) {
+            Ok(templates) => Some(Arc::new(templates)),
+            Err(e) => {
+                error!("Failed to load output templates: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let host_keys = if settings.host_keys.enabled {
+        match HostKeys::load(&settings.host_keys) {
+            Ok(host_keys) => Some(Arc::new(host_keys)),
+            Err(e) => {
+                error!("Failed to load host key pins {}: {}", settings.host_keys.pins_file, e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let ssh_ca = if settings.ssh_ca.enabled {
+        match SshCa::from_settings(&settings.ssh_ca) {
+            Ok(ca) => {
+                info!("SSH certificate authentication enabled ({})", settings.ssh_ca.url);
+                Some(Arc::new(ca))
+            }
+            Err(e) => {
+                error!("Invalid SSH CA configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let credentials = if settings.credentials.enabled {
+        match CredentialStore::from_settings(&settings.credentials) {
+            Ok(store) => {
+                if settings.credentials.vault.enabled {
+                    info!("Vault credential provider enabled ({})", settings.credentials.vault.url);
+                }
+                let store = Arc::new(store);
+                store.start_renewal();
+                Some(store)
+            }
+            Err(e) => {
+                error!("Invalid credential provider configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let node = match NodeIdentity::from_settings(&settings.server) {
+        Ok(node) => {
+            info!("Node ID: {} (header {})", node.id, node.header_name());
+            Arc::new(node)
+        }
+        Err(e) => {
+            error!("Invalid node configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let directory = match settings.cluster.registry {
+        RegistryBackend::Memory => None,
+        RegistryBackend::Redis => match SessionDirectory::start(&settings.cluster, &node.id) {
+            Ok(directory) => {
+                info!("Sessions registered in Redis at {} for the other instances", settings.cluster.redis_url);
+                let directory = Arc::new(directory);
+                session_registry.lock().await.set_directory(directory.clone());
+                Some(directory)
+            }
+            Err(e) => {
+                error!("Invalid cluster configuration: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    
+    let password_change = match settings.password_change.enabled.then(|| PasswordChangeRules::new(&settings.password_change)) {
+        None => None,
+        Some(Ok(rules)) => Some(Arc::new(rules)),
+        Some(Err(e)) => {
+            error!("Invalid password change settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    // Stages of other builds are registered here, after the built-in ones
+    let output_stages = match OutputStages::new(&settings.output_pipeline)
+        .and_then(|stages| stages.validate(&settings).map(|()| stages)) {
+        Ok(stages) => Arc::new(stages),
+        Err(e) => {
+            error!("Invalid output pipeline settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let maintenance = Arc::new(Maintenance::new(&settings.maintenance));
+    if settings.maintenance.enabled {
+        warn!("Starting in maintenance mode: new connections are refused until it is turned off");
+    }
+    
+    let webhooks = if settings.webhooks.enabled {
+        match Webhooks::start(&settings.webhooks, &node.id) {
+            Ok(webhooks) => {
+                let webhooks = Arc::new(webhooks);
+                session_registry.lock().await.set_webhooks(webhooks.clone());
+                Some(webhooks)
+            }
+            Err(e) => {
+                error!("Invalid webhook configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    
+    let alerts = Arc::new(Alerts::new(&settings.alerts, webhooks.clone()));
+    session_registry.lock().await.set_alerts(alerts.clone());
+    
+    if settings.file_server.enabled {
+        match file_server::FileServer::new(&settings.file_server, session_registry.clone()) {
+            Ok(server) => file_server::start(Arc::new(server)).await,
+            Err(e) => {
+                error!("Invalid file server configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    let scrollback = Arc::new(ScrollbackStore::new(&settings, &node.id));
+    
+    let tasks = Arc::new(TaskSupervisor::new(&settings.background_tasks));
+    let watchdog = Arc::new(WatchdogMetrics::default());
+    watchdog::start(&tasks, session_registry.clone(), &settings, watchdog.clone());
+    keepalive::start(&tasks, session_registry.clone(), policy.clone());
+    
+    let audit = if settings.audit.enabled {
+        match AuditLog::open(&settings.audit) {
+            Ok(log) => Some(Arc::new(log)),
+            Err(e) => {
+                error!("Failed to open audit log {}: {}", settings.audit.path, e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
     
     let state = AppState {
         session_registry: session_registry.clone(),
         settings: settings.clone(),
+        api_keys,
+        jwt,
+        node: node.clone(),
+        directory,
+        watchdog,
+        policy,
+        scrollback,
+        audit,
+        http: http.clone(),
+        ssh_ca,
+        inventory,
+        credentials,
+        templates,
+        terminal_types: Arc::new(TerminalTypes::new(settings.ssh.terminal.downgrade.clone())),
+        connect_limiter: Arc::new(ConnectLimiter::default()),
+        webhooks,
+        password_change,
+        maintenance,
+        output_stages,
+        config_backups: config_backups.clone(),
+        tasks: tasks.clone(),
+        alerts,
+        settings_source,
+        reachability: reachability.clone(),
+        host_keys,
+        bulk_jobs: Arc::new(BulkJobs::default()),
     };
 
-    // Start session cleanup task
     let cleanup_state = state.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(300)); // 5 minutes
-        
-        loop {
-            interval.tick().await;
-            
-            let mut registry = cleanup_state.session_registry.lock().await;
-            let count = registry.cleanup_stale_sessions(Duration::from_secs(3600)); // 1 hour
-            
-            if count > 0 {
-                info!("Cleaned up {} stale sessions", count);
+    tasks.spawn("session_cleanup", RestartPolicy::OnPanic, move |task| clean_up_sessions(cleanup_state.clone(), task));
+
+    lifetime::start(state.clone());
+    if let Some(backups) = config_backups {
+        config_backup::start(state.clone(), backups);
+    }
+    if let Some(reachability) = reachability {
+        reachability::start(state.clone(), reachability);
+    }
+    reload::watch(state.clone(), settings.server.settings_reload_seconds);
+
+    let (app, control_app) = router(state);
+    let control_plane = &settings.server.control_plane;
+
+    // `--bind` and `--port`, or their environment variables, are already applied
+    let addr = format!("{0}:{1}", settings.server.address, settings.server.port);
+    // Refuse to start rather than fall back to plain HTTP when TLS cannot be set up
+    let tls_config = if settings.server.tls_enabled {
+        match tls::load(&settings.server).await {
+            Ok(tls_config) => Some(tls_config),
+            Err(e) => {
+                error!("TLS is enabled but cannot be started: {}", e);
+                std::process::exit(1);
             }
-            
-            // Log session statistics
-            info!("Session statistics: {} total sessions, {} portal users, {} devices",
-                  registry.total_sessions(),
-                  registry.total_portal_users(),
-                  registry.total_devices());
         }
+    } else {
+        None
+    };
+    info!("Starting {} server on {}", if tls_config.is_some() { "HTTPS" } else { "HTTP" }, addr);
+    let control_addr = format!("{0}:{1}", control_plane.address, control_plane.port);
+    if control_app.is_some() {
+        info!("Serving /api/* on the control plane listener {}{}", control_addr,
+              if control_plane.require_api_key { ", API keys only" } else { "" });
+    }
+    
+    // Log the available routes
+    info!("Available routes:");
+    info!("  GET  / - HTML interface");
+    info!("  GET  /healthz - Health check for load balancers; 503 in maintenance");
+    info!("  GET  /api/openapi.json - OpenAPI document of this API");
+    info!("  GET  /ws/:session_id?ws_token= - WebSocket endpoint, with a single-use token");
+    info!("  GET  /ws/view/:token - Read-only WebSocket through a share link");
+    info!("  POST /connect - Connect endpoint");
+    info!("  POST /api/connect - API connect endpoint");
+    info!("  POST /api/connect/confirm - Connect again, pinning the device's confirmed host key");
+    info!("  POST /api/validate-credentials - Check device credentials without opening a session");
+    info!("  POST /api/exec - Run one-off commands on a device without a terminal");
+    info!("  POST /api/bulk/exec - Run commands on many devices at once");
+    info!("  GET  /api/bulk/exec/:job_id - Status and results of a bulk exec job");
+    info!("  GET  /api/bulk/exec/:job_id/events - Follow a bulk exec job as server-sent events");
+    info!("  GET  /api/export/:format - Inventory devices as tmuxinator or PuTTY sessions");
+    info!("  POST /api/session/:session_id/terminate - Terminate session endpoint");
+    info!("  GET  /api/sessions/history - Lifecycle history of live and ended sessions");
+    info!("  GET  /api/sessions/stale - Sessions idle past the cleanup threshold");
+    info!("  POST /api/sessions/purge - Remove stale sessions ahead of the cleanup");
+    info!("  GET  /api/session/:session_id/stats - Traffic and latency of a session's WebSockets");
+    info!("  GET  /api/session/:session_id/sftp/list - SFTP directory listing");
+    info!("  POST /api/session/:session_id/sftp/upload - SFTP file upload");
+    info!("  GET  /api/session/:session_id/sftp/download - SFTP file download");
+    info!("  POST/DELETE /api/session/:session_id/file-server - Let the session's device fetch from the staging area");
+    info!("  GET/POST /api/session/:session_id/forward - List and open port forwards");
+    info!("  DELETE /api/session/:session_id/forward/:forward_id - Close port forward");
+    info!("  GET/POST /api/session/:session_id/socks - List and open SOCKS proxies");
+    info!("  DELETE /api/session/:session_id/socks/:forward_id - Close SOCKS proxy");
+    info!("  GET  /api/session/:session_id/scrollback - Session output history, including spilled output");
+    info!("  GET  /api/session/:session_id/screen/subscribe - WebSocket following a region or scraped fields of the screen");
+    info!("  GET/POST /api/session/:session_id/share - List or create read-only share links");
+    info!("  DELETE /api/session/:session_id/share/:share_id - Revoke a share link");
+    info!("  POST /api/session/:session_id/embed-token - Mint a read-only token for embedding in another tool");
+    info!("  POST /api/session/:session_id/clone-to-lab - Open a session to the lab twin of the device, optionally replaying its commands");
+    info!("  POST /api/session/:session_id/extend - Ask for a session to be kept open past its lifetime");
+    info!("  POST /api/session/:session_id/ws-token - Mint a single-use token for a WebSocket to the session");
+    info!("  POST /api/session/:session_id/transfer - Let go of the session's WebSocket and mint a token to attach elsewhere");
+    info!("  DELETE /api/session/:session_id/device-lock - Release the device locks a session holds");
+    info!("  GET  /api/session/:session_id/lifetime - Lifetime class, end and extensions of a session");
+    info!("  GET  /api/device-locks - Device configuration locks held");
+    info!("  GET  /api/alerts - Abnormal session ends, reconnects and failed connections, and the alerts raised");
+    info!("  GET  /api/reachability - Reachability and latency of the inventory devices from this gateway");
+    info!("  GET  /api/sessions/extension-requests - Extensions waiting for approval");
+    info!("  POST /api/session/:session_id/extend/approve - Approve a pending extension");
+    info!("  POST /api/session/:session_id/extend/deny - Deny a pending extension");
+    info!("  GET/PUT /api/command-policy - Get or replace the command policy");
+    info!("  POST /api/command-policy/check - Try a command against the command policy");
+    info!("  GET  /api/sessions/command-approvals - Commands refused by the guardrail, waiting for approval");
+    info!("  POST /api/session/:session_id/command-approvals/:approval_id/approve - Approve a command to be entered once");
+    info!("  POST /api/session/:session_id/command-approvals/:approval_id/deny - Deny a command sent for approval");
+    #[cfg(feature = "fault-injection")]
+    {
+        info!("  GET  /api/faults - List the sessions with faults injected");
+        info!("  PUT/DELETE /api/session/:session_id/faults - Inject or stop injecting faults into a session");
+    }
+    info!("  GET  /api/recordings - List session recordings");
+    info!("  GET  /api/recordings/:recording_id/download - Download asciicast recording");
+    info!("  POST/DELETE /api/session/:session_id/capture - Start or stop capturing WebSocket frames");
+    info!("  GET  /api/captures/:capture_id/download - Download a frame capture");
+    info!("  GET  /api/audit - Search the command audit log");
+    info!("  GET  /api/session/:session_id/timeline - Events of a session in the order they happened");
+    info!("  GET/POST /api/keys - List and create API keys");
+    info!("  DELETE /api/keys/:key_id - Revoke API key");
+    info!("  POST /api/keys/:key_id/rotate - Rotate API key");
+    info!("  GET  /api/host-keys - Pinned device host keys");
+    info!("  DELETE /api/host-keys/:hostname/:port - Remove a device's pinned host key");
+    info!("  GET /api/policy - Active policy version");
+    info!("  GET /api/policy/export - Export signed policy bundle");
+    info!("  POST /api/policy/import - Import signed policy bundle");
+    info!("  GET/POST /api/inventory - List and register devices");
+    info!("  GET/PUT/DELETE /api/inventory/:device_ref - Get, update or remove a device");
+    info!("  DELETE /api/inventory/:device_ref/lock - Release a device's configuration lock");
+    info!("  GET/POST /api/inventory/:device_ref/configs - List or take a device's configuration backups");
+    info!("  GET  /api/inventory/:device_ref/configs/:version_id - Get a stored configuration");
+    info!("  GET  /api/inventory/:device_ref/configs/:version_id/diff - Diff a stored configuration against an earlier one");
+    info!("  GET /api/templates - Output parsing templates");
+    info!("  POST /api/templates/reload - Reload output parsing templates");
+    info!("  POST /api/admin/reload - Reload settings.json and report what changed");
+    info!("  GET/POST /api/admin/maintenance - Get or set maintenance mode");
+    info!("  GET /api/admin/tasks - Background tasks with their last run and status");
+    if settings.api_keys.enabled {
+        info!("API key authorization is enabled");
+    }
+    
+    // On SIGINT or SIGTERM, close the sessions and stop accepting connections
+    let shutdown = CancellationToken::new();
+    let signal = tokio::spawn({
+        let shutdown = shutdown.clone();
+        let session_registry = session_registry.clone();
+        async move {
+            let signal = shutdown_signal().await;
+            info!("Received {}, shutting down", signal);
+            let _ = tokio::task::spawn_blocking(move || {
+                session_registry.blocking_lock().remove_all(EndReason::ServiceRestart);
+            }).await;
+            shutdown.cancel();
+            signal
+        }
+    });
+    
+    if let Some(tls_config) = &tls_config {
+        tls::watch(tls_config.clone(), &settings.server);
+    }
+    let control = control_app.map(|control_app| {
+        tokio::spawn(serve(control_app, control_addr, tls_config.clone(), settings.websocket.clone(), shutdown.clone()))
     });
+    serve(app, addr, tls_config, settings.websocket.clone(), shutdown).await;
+    if let Some(control) = control {
+        let _ = control.await;
+    }
+    tasks.shutdown().await;
+    
+    let signal = signal.await.unwrap_or("unknown");
+    if let Some(runs) = runs {
+        runs.finish(&session_registry, signal).await;
+    }
+}
+
+/// The routers of the main listener and, if enabled, the control plane listener
+fn router(state: AppState) -> (Router, Option<Router>) {
+    let http = state.http.clone();
 
     // Configure CORS
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers(Any);
-
-    // Create router
-    let app = Router::new()
-        .route("/", get(index_handler))
-        .route("/ws/:session_id", get(ws_handler))
-        .route("/connect", post(connect_handler))
-        .route("/api/connect", post(api_connect_handler))
-        .route("/api/sessions", post(session_status_handler))
-        .route("/api/session/:session_id/status", get(session_status_single_handler))
-        .route("/api/session/:session_id/terminate", post(session_terminate_handler))
+    let cors = http.cors(state.node.header_name().clone());
+    let upload_limit = match http.max_upload_bytes {
+        0 => usize::MAX,
+        max => max,
+    };
+
+    // Routes are grouped by the API key scope they require, and documented as they are routed
+    let mut api = openapi::Api::default();
+    let limit_connect = middleware::from_fn_with_state(state.clone(), rate_limit::limit_connect);
+    let page_connect_routes = openapi::Routes::new(Access::Connect)
+        .route("/connect", openapi::post(connect_handler, "Open a session for the web interface").connect("ConnectResponse").layer(limit_connect.clone()))
+        .finish(&mut api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_connect))
+        .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+
+    let connect_routes = openapi::Routes::new(Access::Connect)
+        .route("/api/connect", openapi::post(api_connect_handler, "Open a session for a backend integration").connect("ConnectResponse").layer(limit_connect.clone()))
+        .route("/api/connect/confirm", openapi::post(connect_confirm_handler, "Connect again, pinning the host key whose fingerprint the user confirmed").connect("ConnectResponse").layer(limit_connect))
+        .route("/api/validate-credentials", openapi::post(validate::validate_credentials_handler, "Check device credentials without opening a session").connect("ValidationResponse"))
+        .route("/api/exec", openapi::post(exec::exec_handler, "Run one-off commands on a device without a terminal"))
+        .route("/api/bulk/exec", openapi::post(bulk::start_handler, "Run commands on many devices at once, as a job"))
+        .route("/api/bulk/exec/:job_id", openapi::get(bulk::status_handler, "Status and per-device results of a bulk exec job"))
+        .route("/api/bulk/exec/:job_id/events", openapi::get(bulk::events_handler, "Follow a bulk exec job as server-sent events"))
+        .route("/api/export/:format", openapi::get(export::export_handler, "Inventory devices as tmuxinator or PuTTY session files opened through webssh-rs attach"))
+        .route("/api/session/:session_id/sftp/list", openapi::get(sftp::list_handler, "SFTP directory listing"))
+        .route("/api/session/:session_id/sftp/upload", openapi::post(sftp::upload_handler, "File upload over SFTP, or SCP without it").layer(RequestBodyLimitLayer::new(upload_limit)))
+        .route("/api/session/:session_id/sftp/download", openapi::get(sftp::download_handler, "File download over SFTP, or SCP without it"))
+        .route("/api/session/:session_id/file-server", openapi::post(file_server::enable_handler, "Let the session's device fetch from the staging area").delete(file_server::disable_handler, "Stop serving the staging area to the device"))
+        .route("/api/session/:session_id/forward", openapi::get(forward::list_handler, "List port forwards").post(forward::create_handler, "Open a port forward"))
+        .route("/api/session/:session_id/forward/:forward_id", openapi::delete(forward::close_handler, "Close a port forward"))
+        .route("/api/session/:session_id/socks", openapi::get(forward::socks_list_handler, "List SOCKS proxies").post(forward::socks_create_handler, "Open a SOCKS proxy"))
+        .route("/api/session/:session_id/socks/:forward_id", openapi::delete(forward::close_handler, "Close a SOCKS proxy"))
+        .route("/api/session/:session_id/scrollback", openapi::get(scrollback::scrollback_handler, "Session output history, including spilled output"))
+        .route("/api/session/:session_id/screen/subscribe", openapi::get(screen_feed::subscribe_handler, "WebSocket following a region or scraped fields of the screen"))
+        .route("/api/session/:session_id/share", openapi::get(share::list_handler, "List share links and viewers").post(share::create_handler, "Create a read-only share link"))
+        .route("/api/session/:session_id/share/:share_id", openapi::delete(share::revoke_handler, "Revoke a share link"))
+        .route("/api/session/:session_id/embed-token", openapi::post(share::embed_handler, "Mint a read-only token for embedding in another tool"))
+        .route("/api/session/:session_id/clone-to-lab", openapi::post(lab::clone_handler, "Open a session to the lab twin of the device"))
+        .route("/api/session/:session_id/extend", openapi::post(lifetime::extend_handler, "Ask for a session to be kept open past its lifetime"))
+        .route("/api/session/:session_id/ws-token", openapi::post(ws_token::issue_handler, "Mint a single-use token for a WebSocket to attach to the session"))
+        .route("/api/session/:session_id/transfer", openapi::post(ws_token::transfer_handler, "Let go of the session's WebSocket and mint a token to attach from another tab or machine"))
+        .route("/api/session/:session_id/device-lock", openapi::delete(device_lock::release_session_handler, "Release the device configuration locks a session holds"))
+        .finish(&mut api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_connect))
+        .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+
+    let status_routes = openapi::Routes::new(Access::ReadStatus)
+        .route("/api/sessions", openapi::post(session_status_handler, "Status of all sessions"))
+        .route("/api/session/:session_id/status", openapi::get(session_status_single_handler, "Status of a session"))
+        .route("/api/session/:session_id/stats", openapi::get(session_stats_handler, "Traffic and latency of a session's WebSockets"))
+        .route("/api/sessions/history", openapi::get(session_history_handler, "Lifecycle history of live and ended sessions"))
+        .route("/api/sessions/stale", openapi::get(stale_sessions_handler, "Sessions idle past the cleanup threshold"))
+        .route("/api/session/:session_id/lifetime", openapi::get(lifetime::status_handler, "Lifetime class, end and extensions of a session"))
+        .route("/api/device-locks", openapi::get(device_lock::list_handler, "List the device configuration locks held"))
+        .route("/api/alerts", openapi::get(alerts::status_handler, "Abnormal session ends, reconnects and failed connections in the last minute, and the alerts raised"))
+        .route("/api/reachability", openapi::get(reachability::reachability_handler, "Reachability and latency of the inventory devices from this gateway"))
+        .finish(&mut api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_read_status))
+        .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+
+    let admin_routes = openapi::Routes::new(Access::Admin)
+        .route("/api/session/:session_id/terminate", openapi::post(session_terminate_handler, "Terminate a session"))
+        .route("/api/sessions/purge", openapi::post(purge_sessions_handler, "Remove stale sessions ahead of the cleanup"))
+        .route("/api/recordings", openapi::get(recording::list_handler, "List session recordings"))
+        .route("/api/recordings/:recording_id/download", openapi::get(recording::download_handler, "Download an asciicast recording"))
+        .route("/api/session/:session_id/capture", openapi::post(capture::start_handler, "Start capturing WebSocket frames").delete(capture::stop_handler, "Stop capturing WebSocket frames"))
+        .route("/api/captures/:capture_id/download", openapi::get(capture::download_handler, "Download a frame capture"))
+        .route("/api/audit", openapi::get(audit::query_handler, "Search the command audit log"))
+        .route("/api/session/:session_id/timeline", openapi::get(timeline::timeline_handler, "Events of a live or ended session in the order they happened"))
+        .route("/api/keys", openapi::get(api_keys::list_handler, "List API keys").post(api_keys::create_handler, "Create an API key"))
+        .route("/api/keys/:key_id", openapi::delete(api_keys::revoke_handler, "Revoke an API key"))
+        .route("/api/keys/:key_id/rotate", openapi::post(api_keys::rotate_handler, "Rotate an API key"))
+        .route("/api/host-keys", openapi::get(host_keys::list_handler, "Pinned device host keys"))
+        .route("/api/host-keys/:hostname/:port", openapi::delete(host_keys::remove_handler, "Remove a device's pinned host key, so that its next key is confirmed anew"))
+        .route("/api/policy", openapi::get(policy::status_handler, "Version of the policy in force"))
+        .route("/api/policy/export", openapi::get(policy::export_handler, "Export a signed policy bundle"))
+        .route("/api/policy/import", openapi::post(policy::import_handler, "Import a signed policy bundle"))
+        .route("/api/inventory", openapi::get(inventory::list_handler, "List inventory devices").post(inventory::create_handler, "Register a device"))
+        .route("/api/inventory/:device_ref", openapi::get(inventory::get_handler, "Get a device, with its configuration lock").put(inventory::update_handler, "Update a device").delete(inventory::delete_handler, "Remove a device"))
+        .route("/api/inventory/:device_ref/lock", openapi::delete(device_lock::force_release_handler, "Release a device's configuration lock, whoever holds it"))
+        .route("/api/inventory/:device_ref/configs", openapi::get(config_backup::list_handler, "List a device's stored configurations and how its latest backup went").post(config_backup::backup_handler, "Back up a device's configuration now"))
+        .route("/api/inventory/:device_ref/configs/:version_id", openapi::get(config_backup::get_handler, "Get a stored configuration as text"))
+        .route("/api/inventory/:device_ref/configs/:version_id/diff", openapi::get(config_backup::diff_handler, "Diff a stored configuration against an earlier one"))
+        .route("/api/templates", openapi::get(parsing::list_handler, "Output parsing templates"))
+        .route("/api/templates/reload", openapi::post(parsing::reload_handler, "Reload output parsing templates"))
+        .route("/api/admin/reload", openapi::post(reload::reload_handler, "Reload settings.json and report what changed"))
+        .route("/api/admin/maintenance", openapi::get(maintenance::status_handler, "Whether this instance is in maintenance mode").post(maintenance::update_handler, "Turn maintenance mode on or off"))
+        .route("/api/admin/tasks", openapi::get(tasks::list_handler, "Background tasks with their last run, restarts and status"))
+        .route("/api/sessions/extension-requests", openapi::get(lifetime::pending_handler, "Extensions waiting for approval"))
+        .route("/api/session/:session_id/extend/approve", openapi::post(lifetime::approve_handler, "Approve a pending extension"))
+        .route("/api/session/:session_id/extend/deny", openapi::post(lifetime::deny_handler, "Deny a pending extension"))
+        .route("/api/command-policy", openapi::get(command_policy::get_handler, "The command policy in force").put(command_policy::update_handler, "Replace the command policy"))
+        .route("/api/command-policy/check", openapi::post(command_policy::check_handler, "Try a command against the command policy"))
+        .route("/api/sessions/command-approvals", openapi::get(command_policy::approvals_handler, "Commands refused by the guardrail, waiting for approval"))
+        .route("/api/session/:session_id/command-approvals/:approval_id/approve", openapi::post(command_policy::approve_handler, "Approve a command to be entered once"))
+        .route("/api/session/:session_id/command-approvals/:approval_id/deny", openapi::post(command_policy::deny_handler, "Deny a command sent for approval"));
+    // Test builds can inject latency and loss into chosen sessions
+    #[cfg(feature = "fault-injection")]
+    let admin_routes = admin_routes
+        .route("/api/faults", openapi::get(faults::list_handler, "Sessions with faults injected"))
+        .route("/api/session/:session_id/faults", openapi::delete(faults::clear_handler, "Stop injecting faults into a session").put(faults::set_handler, "Inject latency, jitter and loss into a session"));
+    let admin_routes = admin_routes
+        .finish(&mut api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_admin))
+        .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+
+    let ws_routes = openapi::Routes::new(Access::Jwt)
+        .route("/ws/:session_id", openapi::get(ws_handler, "WebSocket attached to a session's shell"))
+        .finish(&mut api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+
+    // The share link token is the viewer's credential
+    let view_routes = openapi::Routes::new(Access::ShareToken)
+        .route("/ws/view/:token", openapi::get(ws_view_handler, "Read-only WebSocket through a share link or embed token"))
+        .finish(&mut api);
+
+    // The terminal page and its WebSockets, for operators' browsers
+    let terminal_routes = openapi::Routes::new(Access::Public)
+        .route("/", openapi::get(index_handler, "HTML interface"))
+        .route("/healthz", openapi::get(maintenance::healthz_handler, "Health check for load balancers; 503 in maintenance mode"))
+        .finish(&mut api)
+        .merge(ws_routes)
+        .merge(view_routes)
+        .merge(page_connect_routes);
+
+    // The API the IPAM backend drives the gateway with
+    let control_routes = connect_routes
+        .merge(status_routes)
+        .merge(admin_routes);
+
+    let common_layers = |router: Router<AppState>| router
+        .layer(middleware::from_fn_with_state(state.clone(), affinity::route_to_owner))
+        .layer(middleware::from_fn_with_state(state.clone(), affinity::add_node_header))
+        .layer(middleware::from_fn_with_state(state.clone(), http::resolve_client_ip))
+        .layer(DefaultBodyLimit::max(http.max_body_bytes));
+
+    // With a control plane listener, the main one keeps only what the terminal page calls
+    let control_plane = &state.settings.server.control_plane;
+    let page_routes = control_plane.enabled.then(|| {
+        let page_status_routes = openapi::Routes::new(Access::ReadStatus)
+            .route("/api/session/:session_id/status", openapi::get(session_status_single_handler, "Status of a session"))
+            .finish(&mut api)
+            .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_read_status))
+            .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+        let page_token_routes = openapi::Routes::new(Access::Connect)
+            .route("/api/session/:session_id/ws-token", openapi::post(ws_token::issue_handler, "Mint a single-use token for a WebSocket to attach to the session"))
+            .finish(&mut api)
+            .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_connect))
+            .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+        let page_admin_routes = openapi::Routes::new(Access::Admin)
+            .route("/api/session/:session_id/terminate", openapi::post(session_terminate_handler, "Terminate a session"))
+            .finish(&mut api)
+            .route_layer(middleware::from_fn_with_state(state.clone(), api_keys::require_admin))
+            .route_layer(middleware::from_fn_with_state(state.clone(), jwt::require_jwt));
+        page_status_routes.merge(page_token_routes).merge(page_admin_routes)
+    });
+    let control_routes = api.serve("/api/openapi.json", control_routes);
+    let (terminal_routes, control_app) = if let Some(page_routes) = page_routes {
+        // Backend services call it directly, not browsers, so it has no CORS
+        let mut control_app = common_layers(control_routes);
+        if control_plane.require_api_key {
+            control_app = control_app.layer(middleware::from_fn(api_keys::require_key));
+        }
+        (terminal_routes.merge(page_routes), Some(control_app.with_state(state.clone())))
+    } else {
+        (terminal_routes.merge(control_routes), None)
+    };
+
+    let app = common_layers(terminal_routes
         .nest_service("/static", ServeDir::new("static"))
-        .fallback_service(ServeDir::new("static").append_index_html_on_directories(true))
+        .fallback_service(ServeDir::new("static").append_index_html_on_directories(true)))
         .layer(cors)
+        .layer(middleware::from_fn(ws_deflate::negotiate))
         .with_state(state);
+    (app, control_app)
+}
+
+/// Serves an app on a listener until the shutdown token is cancelled
+async fn serve(app: Router, addr: String, tls_config: Option<RustlsConfig>, websocket: WebSocketSettings, shutdown: CancellationToken) {
+    let listener = std::net::TcpListener::bind(&addr).unwrap();
+    let handle = axum_server::Handle::new();
+    let stopping = handle.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        stopping.graceful_shutdown(Some(Duration::from_secs(10)));
+    });
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    // Connections are wrapped to compress their WebSocket, once negotiated
+    match tls_config {
+        Some(tls_config) => axum_server::from_tcp_rustls(listener, tls_config)
+            .map(|tls| DeflateAcceptor::new(tls, &websocket))
+            .handle(handle)
+            .serve(app)
+            .await
+            .unwrap(),
+        None => axum_server::from_tcp(listener)
+            .acceptor(DeflateAcceptor::new(DefaultAcceptor, &websocket))
+            .handle(handle)
+            .serve(app)
+            .await
+            .unwrap(),
+    }
+}
+
+/// Waits for the signal to shut down, returning its name
+/// Ends a subcommand other than `serve`, with its error on stderr
+fn exit_with(result: Result<(), String>) -> ! {
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "ctrl-c"
+    }
+}
+
+/// Removes stale sessions and logs session statistics
+///
+/// The settings are read on every pass, as they may be reloaded. Besides
+/// every interval, the task wakes when an idle warning or removal is due.
+async fn clean_up_sessions(state: AppState, task: TaskContext) {
+    let mut next_pass = tokio::time::Instant::now()
+        + Duration::from_secs(state.settings.session_cleanup.interval_seconds.max(1));
+    let mut wake = tokio::time::Instant::now();
+
+    loop {
+        tokio::time::sleep_until(wake).await;
+        let settings = state.policy.settings();
+        let cleanup = &settings.session_cleanup;
+
+        let mut registry = state.session_registry.lock().await;
+        let check = registry.cleanup_stale_sessions(cleanup);
+
+        if check.removed > 0 {
+            info!("Cleaned up {} stale sessions", check.removed);
+        }
 
-    // Get server address and port from environment variables if available
-    let address = std::env::var("WEBSSH_SERVER_ADDRESS")
-        .unwrap_or_else(|_| settings.server.address.clone());
-    let port = std::env::var("WEBSSH_SERVER_PORT")
-        .unwrap_or_else(|_| settings.server.port.to_string())
-        .parse::<u16>()
-        .unwrap_or(settings.server.port);
+        let now = tokio::time::Instant::now();
+        if now >= next_pass {
+            next_pass = now + Duration::from_secs(cleanup.interval_seconds.max(1));
+
+            // Log session statistics
+            info!("Session statistics: {} total sessions, {} portal users, {} devices",
+                  registry.total_sessions(),
+                  registry.total_portal_users(),
+                  registry.total_devices());
+        }
+        drop(registry);
+        task.ran();
+
+        // A session opened from now on is due no sooner than its first warning
+        let first_due = cleanup.idle_timeout_seconds.saturating_sub(cleanup.warning_seconds).max(1);
+        wake = next_pass.min(now + Duration::from_secs(first_due));
+        if let Some(due) = check.next_due {
+            wake = wake.min(now + (due - chrono::Utc::now()).to_std().unwrap_or_default().max(Duration::from_secs(1)));
+        }
+    }
+}
+
+async fn index_handler() -> impl IntoResponse {
+    // We're using the static HTML file with client-side JavaScript that will parse URL parameters
+    // The JavaScript in the HTML will handle the session_id and other parameters
+    info!("Serving index.html with client-side parameter handling");
+    Html(include_str!("../static/index.html"))
+}
+
+async fn connect_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Extension(client): Extension<ClientIp>,
+    RawQuery(query): RawQuery,
+    Json(credentials): Json<SSHCredentials>,
+) -> Json<ConnectResponse> {
+    if let Some(message) = state.maintenance.refusal() {
+        info!("Connection request to {} refused: in maintenance", credentials.hostname);
+        return Json(ConnectResponse {
+            success: false,
+            message,
+            session_id: None,
+            websocket_url: None,
+            ws_token: None,
+            error_code: Some(ErrorCode::Maintenance),
+            node_id: state.node.id.clone(),
+            auth_pending: false,
+            device_type: None,
+            warnings: Vec::new(),
+            host_key: None,
+        });
+    }
     
-    let addr = format!("{0}:{1}", address, port);
-    info!("Starting server on {}", addr);
+    let credentials = match resolve_request(&state, credentials).await {
+        Ok(credentials) => credentials,
+        Err(e) => return lookup_failed(&state, e),
+    };
+    
+    // Device profiles and the credential policy may be replaced by a policy import
+    let policy_settings = state.policy.settings();
+    if let Some(Extension(user)) = &user {
+        if let Err(message) = authz::check_connect(&policy_settings.authorization, user, &credentials.hostname, &credentials.device_tags) {
+            warn!("Connection to {} refused for {}: {}", credentials.hostname, user.subject, message);
+            return Json(ConnectResponse {
+                success: false,
+                message,
+                session_id: None,
+                websocket_url: None,
+                ws_token: None,
+                error_code: Some(ErrorCode::AccessDenied),
+                node_id: state.node.id.clone(),
+                auth_pending: false,
+                device_type: None,
+                warnings: Vec::new(),
+                host_key: None,
+            });
+        }
+    }
+
+    // Sessions of authenticated users are bound to the token subject; otherwise
+    // generate a unique portal user ID if not provided
+    let identity = user.as_ref().map(|Extension(user)| user.subject.clone());
+    let roles = user.as_ref().map(|Extension(user)| user.roles.clone()).unwrap_or_default();
+    let portal_user_id = match &identity {
+        Some(subject) => subject.clone(),
+        None => credentials.portal_user_id.clone()
+            .unwrap_or_else(|| format!("anonymous-{}", uuid::Uuid::new_v4())),
+    };
+
+    // A device whose host key is not pinned waits for the user to confirm it
+    let mut target = connection_target(&state, &credentials, &policy_settings, &portal_user_id);
+    // A terminal type the client asks for is used as is; otherwise a device's downgrade sticks
+    let terminal = RequestedTerminal::check(
+        &policy_settings.ssh.terminal,
+        credentials.term.as_deref(),
+        credentials.cols,
+        credentials.rows,
+        &credentials.env,
+    ).with_pty(&credentials.pty_modes, credentials.width_px, credentials.height_px);
+    if let Some(terminal_type) = terminal.terminal_type.or_else(|| state.terminal_types.downgraded(&target.hostname, target.port)) {
+        target.terminal_type = Some(terminal_type);
+    }
+    target.terminal_size = terminal.size;
+    target.environment = terminal.environment;
+    target.pty = terminal.pty;
+    // The user answers the device's password prompt in the terminal instead
+    if prompts_for_password(&policy_settings, &credentials) {
+        target.keyboard_interactive = true;
+    }
+    
+    if let Err(message) = check_telnet(&state.settings, &credentials) {
+        warn!("Telnet connection to {} refused: {}", credentials.hostname, message);
+        return Json(ConnectResponse {
+            success: false,
+            message: message.to_string(),
+            session_id: None,
+            websocket_url: None,
+            ws_token: None,
+            error_code: Some(ErrorCode::UnsupportedProtocol),
+            node_id: state.node.id.clone(),
+            auth_pending: false,
+            device_type: None,
+            warnings: Vec::new(),
+            host_key: None,
+        });
+    }
+    
+    if let Err(message) = check_passthrough(&state.settings.passthrough, user.as_ref().map(|Extension(user)| user), &credentials) {
+        warn!("Pass-through connection to {} refused: {}", credentials.hostname, message);
+        return Json(ConnectResponse {
+            success: false,
+            message: message.to_string(),
+            session_id: None,
+            websocket_url: None,
+            ws_token: None,
+            error_code: Some(ErrorCode::PassthroughRefused),
+            node_id: state.node.id.clone(),
+            auth_pending: false,
+            device_type: None,
+            warnings: Vec::new(),
+            host_key: None,
+        });
+    }
     
-    // For now, we'll just use the non-TLS server
-    // TLS support can be added later with a proper TLS implementation
-    if settings.server.tls_enabled {
-        info!("TLS is enabled in settings, but not implemented in this version");
-        info!("Starting non-TLS server on {}", addr);
+    // Enforce the credential policy before anything reaches the device
+    let mut warnings = match credential_policy::check(&policy_settings.credential_policy, &target, query.as_deref()) {
+        Ok(warnings) => warnings,
+        Err(violation) => {
+            warn!("Connection to {} as {} refused by credential policy: {}",
+                  credentials.hostname, credentials.username, violation);
+            return Json(ConnectResponse {
+                success: false,
+                message: violation.to_string(),
+                session_id: None,
+                websocket_url: None,
+                ws_token: None,
+                error_code: Some(violation.error_code()),
+                node_id: state.node.id.clone(),
+                auth_pending: false,
+                device_type: None,
+                warnings: Vec::new(),
+                host_key: None,
+            });
+        }
+    };
+    for warning in &warnings {
+        warn!("Credential policy: {}", warning);
+    }
+    if target.protocol == Protocol::Telnet && !target.environment.is_empty() {
+        target.environment.clear();
+        warnings.push("Environment variables cannot be passed to telnet devices".to_string());
+    }
+    warnings.extend(terminal.warnings);
+    
+    // Use hostname as device ID for now
+    let device_id = credentials.hostname.clone();
+    
+    // Everything logged from here on carries the portal user and device, and the session once it has an ID
+    let span = log_context::session_span(None, &portal_user_id, &device_id);
+    async move {
+        info!("Connection request from portal user {} ({}) to device {} with SSH user {}",
+              portal_user_id, client, device_id, credentials.username);
+    
+        // Refuse early rather than connect to the device for a session that cannot be kept
+        if let Err(e) = state.session_registry.lock().await.check_limits(&portal_user_id, &device_id) {
+            warn!("Connection for portal user {} to device {} refused: {}", portal_user_id, device_id, e);
+            return limit_exceeded(&state, e, warnings);
+        }
+    
+        // Another user's configuration lock on the device warns or refuses, as configured
+        let locks_device = DeviceLocks::applies(&state.settings.device_locks, &credentials.device_tags);
+        if locks_device {
+            let mut registry = state.session_registry.lock().await;
+            if let Some(lock) = registry.device_locks.held_against(&device_id, &portal_user_id).cloned() {
+                if state.settings.device_locks.policy == DeviceLockPolicy::Block {
+                    warn!("Connection for portal user {} to device {} refused: locked by {}", portal_user_id, device_id, lock.portal_user_id);
+                    return device_locked(&state, lock.describe(), warnings);
+                }
+                info!("Portal user {} is connecting to device {} locked by {}", portal_user_id, device_id, lock.portal_user_id);
+                warnings.push(lock.describe());
+                if let Some(holder) = registry.get_session(&lock.session_id) {
+                    let _ = holder.notifications.send(serde_json::json!({
+                        "type": "device_lock",
+                        "status": "contested",
+                        "portal_user_id": portal_user_id,
+                        "message": format!("{} has connected to device {}, whose configuration lock this session holds", portal_user_id, device_id),
+                    }));
+                }
+            }
+        }
+    
+        // The operator's SSO identity stands in for shared device credentials
+        if credentials.auth_type.as_deref() == Some(CERTIFICATE) {
+            if let Err(e) = use_certificate(&state, identity.as_deref(), &mut target).await {
+                warn!("No certificate for portal user {} to device {}: {}", portal_user_id, device_id, e);
+                return Json(ConnectResponse {
+                    success: false,
+                    message: e.to_string(),
+                    session_id: None,
+                    websocket_url: None,
+                    ws_token: None,
+                    error_code: Some(e.error_code()),
+                    node_id: state.node.id.clone(),
+                    auth_pending: false,
+                    device_type: None,
+                    warnings,
+                    host_key: None,
+                });
+            }
+        }
+    
+        if target.keyboard_interactive || target.pkcs11.is_some() {
+            let mut response = start_interactive_connect(state, target, portal_user_id, device_id, credentials.username, roles, locks_device).await;
+            response.warnings = warnings;
+            return response;
+        }
+    
+        match Shell::open(target) {
+            Ok(session) => {
+                let mut device_type = None;
+                // Add session to registry
+                let added = {
+                    let mut registry = state.session_registry.lock().await;
+                    let added = registry.add_session(
+                        &portal_user_id,
+                        &device_id,
+                        &credentials.username,
+                        session
+                    );
+                
+                    // Start recording before any output can reach a client
+                    if let Ok(session_id) = &added {
+                        if let Some(session_info) = registry.get_session(session_id) {
+                            session_info.roles = roles;
+                            device_type = session_info.device_type.clone();
+                        }
+                        if credentials.passthrough {
+                            pass_through(&mut registry, session_id);
+                        } else {
+                            start_recording(&mut registry, &state.settings, session_id);
+                            watch_terminal(&mut registry, &state, session_id);
+                            assist_password_change(&mut registry, &state, session_id, credentials.credential_ref.as_deref());
+                            build_output_pipeline(&mut registry, &state, session_id);
+                        }
+                        start_audit(&mut registry, &state, session_id);
+                        follow_notifications(&mut registry, session_id);
+                        if locks_device {
+                            lock_device(&mut registry, session_id);
+                        }
+                    }
+                    added
+                };
+                let session_id = match added {
+                    Ok(session_id) => session_id,
+                    Err(e) => return limit_exceeded(&state, e, warnings),
+                };
+                log_context::identify(&tracing::Span::current(), &session_id);
+            
+                let ws_token = state.session_registry.lock().await
+                    .ws_tokens.issue(&session_id, ws_token::ttl(&state.settings.ws_tokens));
+                let websocket_url = websocket_url(&state.settings, &session_id, &ws_token);
+            
+                info!("Created session {} for portal user {}, device {}, SSH user {}",
+                      session_id, portal_user_id, device_id, credentials.username);
+            
+                Json(ConnectResponse {
+                    success: true,
+                    message: "Connected successfully".to_string(),
+                    session_id: Some(session_id),
+                    websocket_url: Some(websocket_url),
+                    ws_token: Some(ws_token),
+                    error_code: None,
+                    node_id: state.node.id.clone(),
+                    auth_pending: false,
+                    device_type,
+                    warnings,
+                    host_key: None,
+                })
+            }
+            Err(e) => {
+                error!("SSH connection error for portal user {}, device {}, SSH user {}: {}",
+                       portal_user_id, device_id, credentials.username, e);
+            
+                let error_code = e.error_code();
+                forget_refused(&state, credentials.credential_ref.as_deref(), Some(error_code));
+                report_connect_failure(&state, error_code, &portal_user_id, &device_id, &credentials.username, &e.to_string());
+            
+                Json(ConnectResponse {
+                    success: false,
+                    message: format!("Failed to connect: {}", e),
+                    session_id: None,
+                    websocket_url: None,
+                    ws_token: None,
+                    error_code: Some(error_code),
+                    node_id: state.node.id.clone(),
+                    auth_pending: false,
+                    device_type: None,
+                    warnings,
+                    host_key: e.host_key().cloned(),
+                })
+            }
+        }
+    }.instrument(span).await
+}
+
+fn lookup_failed(state: &AppState, e: LookupError) -> Json<ConnectResponse> {
+    warn!("Connection refused: {}", e);
+    Json(ConnectResponse {
+        success: false,
+        message: e.to_string(),
+        session_id: None,
+        websocket_url: None,
+        ws_token: None,
+        error_code: Some(e.error_code()),
+        node_id: state.node.id.clone(),
+        auth_pending: false,
+        device_type: None,
+        warnings: Vec::new(),
+        host_key: None,
+    })
+}
+
+/// Replaces the request's credentials with a certificate issued for the operator's SSO identity
+async fn use_certificate(state: &AppState, identity: Option<&str>, target: &mut ConnectionTarget) -> Result<(), CertificateError> {
+    let issued = match (&state.ssh_ca, identity) {
+        (None, _) => Err(CertificateError::Disabled),
+        (Some(_), None) => Err(CertificateError::NoIdentity),
+        (Some(ca), Some(identity)) => ca.issue(identity, target).await,
+    }?;
+    debug!("Authenticating to {} with a certificate for principals {}", target.hostname, issued.principals.join(","));
+    target.password = None;
+    target.private_key = Some(issued.private_key);
+    target.private_key_passphrase = None;
+    target.certificate = Some(issued.certificate);
+    Ok(())
+}
+
+/// Starts a keyboard-interactive or PKCS#11 connection in the background
+///
+/// The session ID is returned at once. The client answers the device's prompts,
+/// or gives the token's PIN, over the WebSocket, and the session is registered
+/// once authentication succeeds.
+async fn start_interactive_connect(
+    state: AppState,
+    mut target: ConnectionTarget,
+    portal_user_id: String,
+    device_id: String,
+    ssh_username: String,
+    roles: Vec<String>,
+    locks_device: bool,
+) -> Json<ConnectResponse> {
+    let session_id = SessionRegistry::new_session_id(&portal_user_id, &device_id, &ssh_username);
+    let message = match &target.pkcs11 {
+        Some(_) => "Waiting for the token PIN",
+        None => "Waiting for keyboard-interactive authentication",
+    };
+    let timeout = Duration::from_secs(state.policy.settings().ssh.connection.auth_prompt_timeout_seconds);
+    let (mut prompter, exchange) = interactive_auth::relay_channel(timeout);
+    state.session_registry.lock().await.add_pending_auth(&session_id, PendingAuth {
+        portal_user_id,
+        device_id,
+        ssh_username,
+        roles,
+        locks_device,
+        exchange: Some(exchange),
+    });
+    
+    let background_state = state.clone();
+    let background_session_id = session_id.clone();
+    let span = tracing::Span::current();
+    log_context::identify(&span, &session_id);
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let (state, session_id) = (background_state, background_session_id);
+        let result = match target.pkcs11.as_mut() {
+            Some(login) => match prompter.secret(&format!("PIN for {}:", login.key), "pkcs11_pin") {
+                Some(pin) => {
+                    login.pin = Some(pin);
+                    SSHSession::open(target)
+                }
+                None => Err(SSHError::Authentication("No PIN was given for the token".into())),
+            },
+            None => SSHSession::open_interactive(target, &mut prompter),
+        };
+        
+        let mut registry = state.session_registry.blocking_lock();
+        let Some(pending) = registry.remove_pending_auth(&session_id) else {
+            return;
+        };
+        match result {
+            Ok(session) => {
+                match registry.insert_session(&session_id, &pending.portal_user_id, &pending.device_id, &pending.ssh_username, session.into()) {
+                    Ok(()) => {
+                        if let Some(session_info) = registry.get_session(&session_id) {
+                            session_info.roles = pending.roles;
+                        }
+                        start_recording(&mut registry, &state.settings, &session_id);
+                        start_audit(&mut registry, &state, &session_id);
+                        follow_notifications(&mut registry, &session_id);
+                        watch_terminal(&mut registry, &state, &session_id);
+                        assist_password_change(&mut registry, &state, &session_id, None);
+                        build_output_pipeline(&mut registry, &state, &session_id);
+                        if pending.locks_device {
+                            lock_device(&mut registry, &session_id);
+                        }
+                        drop(registry);
+                        prompter.finish(AuthEvent::Succeeded);
+                    }
+                    Err(e) => {
+                        registry.record_failed_connect(&session_id, e.error_code(), e.to_string());
+                        drop(registry);
+                        prompter.finish(AuthEvent::Failed(e.to_string()));
+                    }
+                }
+            }
+            Err(e) => {
+                registry.record_failed_connect(&session_id, e.error_code(), e.to_string());
+                drop(registry);
+                report_connect_failure(&state, e.error_code(), &pending.portal_user_id, &pending.device_id, &pending.ssh_username, &e.to_string());
+                error!("Interactive connection for session {} failed: {}", session_id, e);
+                prompter.finish(AuthEvent::Failed(e.to_string()));
+            }
+        }
+    });
+    
+    let ws_token = state.session_registry.lock().await
+        .ws_tokens.issue(&session_id, ws_token::ttl(&state.settings.ws_tokens));
+    Json(ConnectResponse {
+        success: true,
+        message: message.to_string(),
+        websocket_url: Some(websocket_url(&state.settings, &session_id, &ws_token)),
+        ws_token: Some(ws_token),
+        session_id: Some(session_id),
+        error_code: None,
+        node_id: state.node.id.clone(),
+        auth_pending: true,
+        device_type: None,
+        warnings: Vec::new(),
+        host_key: None,
+    })
+}
+
+/// Response for a connection refused by another user's lock on the device
+fn device_locked(state: &AppState, message: String, warnings: Vec<String>) -> Json<ConnectResponse> {
+    Json(ConnectResponse {
+        success: false,
+        message,
+        session_id: None,
+        websocket_url: None,
+        ws_token: None,
+        error_code: Some(ErrorCode::DeviceLocked),
+        node_id: state.node.id.clone(),
+        auth_pending: false,
+        device_type: None,
+        warnings,
+        host_key: None,
+    })
+}
+
+/// Response for a connection refused by the session limits
+fn limit_exceeded(state: &AppState, e: SessionLimitExceeded, warnings: Vec<String>) -> Json<ConnectResponse> {
+    Json(ConnectResponse {
+        success: false,
+        message: e.to_string(),
+        session_id: None,
+        websocket_url: None,
+        ws_token: None,
+        error_code: Some(e.error_code()),
+        node_id: state.node.id.clone(),
+        auth_pending: false,
+        device_type: None,
+        warnings,
+        host_key: None,
+    })
+}
+
+/// Starts recording a session if recording is enabled
+fn start_recording(registry: &mut SessionRegistry, settings: &Settings, session_id: &str) {
+    if !settings.recording.enabled {
+        return;
+    }
+    if let Some(session_info) = registry.get_session(session_id) {
+        session_info.recorder = recording::start_session_recording(
+            settings,
+            session_id,
+            &session_info.portal_user_id,
+            &session_info.device_id,
+            &session_info.ssh_username,
+            session_info.ssh_session.connection_info(),
+        );
+    }
+}
+
+/// Attributes the session's typed commands in the audit log if auditing is enabled
+fn start_audit(registry: &mut SessionRegistry, state: &AppState, session_id: &str) {
+    let Some(log) = &state.audit else {
+        return;
+    };
+    if let Some(session_info) = registry.get_session(session_id) {
+        session_info.audit = Some(AuditContext {
+            log: log.clone(),
+            session_id: session_id.to_string(),
+            portal_user_id: session_info.portal_user_id.clone(),
+            device_id: session_info.device_id.clone(),
+            ssh_username: session_info.ssh_username.clone(),
+        });
+    }
+}
+
+/// Adds the session's notifications to its timeline, e.g. a terminal type downgrade or a lost connection
+fn follow_notifications(registry: &mut SessionRegistry, session_id: &str) {
+    if let Some(session_info) = registry.get_session(session_id) {
+        if let Some(timeline) = &session_info.timeline {
+            timeline.follow(session_info.notifications.subscribe());
+        }
+    }
+}
+
+/// Watches the session's output for a device that does not handle its terminal type
+fn watch_terminal(registry: &mut SessionRegistry, state: &AppState, session_id: &str) {
+    if let Some(session_info) = registry.get_session(session_id) {
+        let target = session_info.ssh_session.target();
+        session_info.terminal_watch = session_info.ssh_session.connection_info().terminal_type.as_deref()
+            .and_then(|terminal_type| state.terminal_types.watch(
+                &target.hostname,
+                target.port,
+                terminal_type,
+                session_info.notifications.clone(),
+            ));
+    }
+}
+
+/// Takes the device's configuration lock for a new session, if no session holds it
+fn lock_device(registry: &mut SessionRegistry, session_id: &str) {
+    let Some((device_id, portal_user_id)) = registry.get_session(session_id)
+        .map(|session_info| (session_info.device_id.clone(), session_info.portal_user_id.clone())) else {
+        return;
+    };
+    if registry.device_locks.acquire(&device_id, session_id, &portal_user_id) {
+        info!("Session {} holds the configuration lock on device {}", session_id, device_id);
+    }
+}
+
+/// Lays out the stages a new session's output passes through, by its device type
+fn build_output_pipeline(registry: &mut SessionRegistry, state: &AppState, session_id: &str) {
+    if let Some(session_info) = registry.get_session(session_id) {
+        let context = StageContext { session_id, device_type: session_info.device_type.as_deref() };
+        session_info.output_pipeline = Some(state.output_stages.pipeline(&state.settings, &context));
+    }
+}
+
+/// Sends a new session's output to its WebSockets as it comes, as its connect request asked
+///
+/// Nothing records, watches or transforms the output, and no screen is
+/// tracked, so slow clients are not sent snapshots.
+fn pass_through(registry: &mut SessionRegistry, session_id: &str) {
+    if let Some(session_info) = registry.get_session(session_id) {
+        session_info.passthrough = true;
+        session_info.output_pipeline = Some(OutputPipeline::passthrough());
+        info!("Session {} is in pass-through mode: its output is neither recorded nor inspected", session_id);
+    }
+}
+
+/// Watches a new session for a password change the device demands after login
+///
+/// With `password_change.write_back`, the new password is stored where the
+/// session's credentials were read from.
+fn assist_password_change(registry: &mut SessionRegistry, state: &AppState, session_id: &str, credential_ref: Option<&str>) {
+    let Some(rules) = &state.password_change else {
+        return;
+    };
+    if let Some(session_info) = registry.get_session(session_id) {
+        let write_back = state.credentials.clone().zip(credential_ref)
+            .map(|(store, reference)| WriteBack { store, reference: reference.trim().to_string() });
+        session_info.password_change = Some(rules.watch(session_info.notifications.clone(), write_back));
     }
-    
-    // Log the available routes
-    info!("Available routes:");
-    info!("  GET  / - HTML interface");
-    info!("  GET  /ws/:session_id - WebSocket endpoint");
-    info!("  POST /connect - Connect endpoint");
-    info!("  POST /api/connect - API connect endpoint");
-    info!("  POST /api/session/:session_id/terminate - Terminate session endpoint");
-    
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
 }
 
-async fn index_handler() -> impl IntoResponse {
-    // We're using the static HTML file with client-side JavaScript that will parse URL parameters
-    // The JavaScript in the HTML will handle the session_id and other parameters
-    info!("Serving index.html with client-side parameter handling");
-    Html(include_str!("../static/index.html"))
+fn websocket_url(settings: &Settings, session_id: &str, ws_token: &str) -> String {
+    let scheme = if settings.server.tls_enabled { "wss" } else { "ws" };
+    format!("{}://{}:{}/ws/{}?ws_token={}", scheme, settings.server.address, settings.server.port, session_id, ws_token)
 }
 
-async fn connect_handler(
-    State(state): State<AppState>,
-    Json(credentials): Json<SSHCredentials>,
+/// The terminal page, opened on a session with a WebSocket token
+fn terminal_url(settings: &Settings, session_id: &str, ws_token: &str) -> String {
+    let scheme = if settings.server.tls_enabled { "https" } else { "http" };
+    format!("{}://{}:{}/?session_id={}&ws_token={}", scheme, settings.server.address, settings.server.port,
+            urlencoding::encode(session_id), ws_token)
+}
+
+fn share_url(settings: &Settings, token: &str) -> String {
+    let scheme = if settings.server.tls_enabled { "wss" } else { "ws" };
+    format!("{}://{}:{}/ws/view/{}", scheme, settings.server.address, settings.server.port, token)
+}
+
+/// Handler for POST /api/connect/confirm
+///
+/// Connects as `/api/connect` does, pinning the device's host key if it is
+/// still the one whose fingerprint the user confirmed.
+async fn connect_confirm_handler(
+    state: State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    client: Extension<ClientIp>,
+    query: RawQuery,
+    Json(request): Json<ConfirmRequest>,
 ) -> Json<ConnectResponse> {
-    // Generate a unique portal user ID if not provided
-    let portal_user_id = credentials.portal_user_id
-        .unwrap_or_else(|| format!("anonymous-{}", uuid::Uuid::new_v4()));
-    
-    // Use hostname as device ID for now
-    let device_id = credentials.hostname.clone();
-    
-    info!("Connection request from portal user {} to device {} with SSH user {}",
-          portal_user_id, device_id, credentials.username);
-    
-    match SSHSession::new(
-        &credentials.hostname,
-        credentials.port,
-        &credentials.username,
-        credentials.password.as_deref(),
-        credentials.private_key.as_deref(),
-        credentials.device_type.as_deref(),
-        &state.settings.ssh,
-    ) {
-        Ok(session) => {
-            // Add session to registry
-            let session_id = {
-                let mut registry = state.session_registry.lock().await;
-                registry.add_session(
-                    &portal_user_id,
-                    &device_id,
-                    &credentials.username,
-                    session
-                )
-            };
-            
-            let websocket_url = format!("ws://{}:{}/ws/{}",
-                                       state.settings.server.address,
-                                       state.settings.server.port,
-                                       session_id);
-            
-            info!("Created session {} for portal user {}, device {}, SSH user {}",
-                  session_id, portal_user_id, device_id, credentials.username);
-            
-            Json(ConnectResponse {
-                success: true,
-                message: "Connected successfully".to_string(),
-                session_id: Some(session_id),
-                websocket_url: Some(websocket_url),
-                error_code: None,
-            })
-        }
-        Err(e) => {
-            error!("SSH connection error for portal user {}, device {}, SSH user {}: {}",
-                   portal_user_id, device_id, credentials.username, e);
-            
-            // Determine error code based on error message
-            let error_code = if e.to_string().contains("Authentication") {
-                "AUTH_FAILED"
-            } else if e.to_string().contains("Connection") || e.to_string().contains("connect") {
-                "CONNECTION_FAILED"
-            } else {
-                "UNKNOWN_ERROR"
-            };
-            
-            Json(ConnectResponse {
-                success: false,
-                message: format!("Failed to connect: {}", e),
-                session_id: None,
-                websocket_url: None,
-                error_code: Some(error_code.to_string()),
-            })
-        }
-    }
+    let mut credentials = request.credentials;
+    credentials.host_key_fingerprint = Some(request.host_key_fingerprint);
+    api_connect_handler(state, user, client, query, Json(credentials)).await
 }
 
 // Enhanced API endpoint for backend integration with improved security
 async fn api_connect_handler(
     State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    client: Extension<ClientIp>,
+    query: RawQuery,
     Json(credentials): Json<SSHCredentials>,
 ) -> Json<ConnectResponse> {
+    let credentials = match resolve_request(&state, credentials).await {
+        Ok(credentials) => credentials,
+        Err(e) => return lookup_failed(&state, e),
+    };
+    
     // Log the connection attempt with limited information (no passwords)
-    info!("API connection request for hostname: {}, username: {}, device_name: {}", 
+    info!("API connection request from {} for hostname: {}, username: {}, device_name: {}", 
+          client.0,
           credentials.hostname, 
           credentials.username, 
           credentials.device_name.as_deref().unwrap_or("Unknown"));
     
     // Set default port if not provided
-    let port = if credentials.port == 0 { 22 } else { credentials.port };
+    let port = if credentials.port == 0 {
+        credentials.protocol.unwrap_or_default().default_port()
+    } else {
+        credentials.port
+    };
     
     // Determine authentication method
-    let (password, private_key) = match credentials.auth_type.as_deref() {
-        Some("private-key") => (None, credentials.private_key.clone()),
-        _ => (credentials.password.clone(), None), // Default to password auth
+    let (password, private_key, private_key_passphrase) = match credentials.auth_type.as_deref() {
+        Some("private-key") => (None, credentials.private_key.clone(), credentials.private_key_passphrase.clone()),
+        _ => (credentials.password.clone(), None, None), // Default to password auth
     };
     
     // Generate a unique session ID if not provided
@@ -284,16 +1949,36 @@ async fn api_connect_handler(
         username: credentials.username.clone(),
         password,
         private_key,
+        private_key_passphrase,
         device_type: credentials.device_type.clone(),
         auth_type: credentials.auth_type.clone(),
         portal_user_id: Some(portal_user_id),
         enable_password: credentials.enable_password.clone(),
         device_name: credentials.device_name.clone(),
         session_id: Some(session_id),
+        compression: credentials.compression,
+        jump_host: credentials.jump_host.clone(),
+        device_ref: None,
+        // Already fetched, into the credentials above
+        credential_ref: None,
+        protocol: credentials.protocol,
+        term: credentials.term.clone(),
+        cols: credentials.cols,
+        rows: credentials.rows,
+        width_px: credentials.width_px,
+        height_px: credentials.height_px,
+        pty_modes: credentials.pty_modes.clone(),
+        env: credentials.env.clone(),
+        pkcs11: credentials.pkcs11.clone(),
+        passthrough: credentials.passthrough,
+        device_tags: credentials.device_tags.clone(),
+        host_key_fingerprint: credentials.host_key_fingerprint.clone(),
     };
     
     // Use the existing connect_handler logic
-    let mut response = connect_handler(State(state), Json(processed_credentials.clone())).await;
+    let forget_state = state.clone();
+    let mut response = connect_handler(State(state), user, client, query, Json(processed_credentials.clone())).await;
+    forget_refused(&forget_state, credentials.credential_ref.as_deref(), response.error_code);
     
     // Enhance the response with additional information for the frontend
     if let Some(websocket_url) = &response.websocket_url {
@@ -311,39 +1996,124 @@ async fn api_connect_handler(
     response
 }
 
+/// Query parameters of the WebSocket endpoint
+#[derive(Debug, Deserialize)]
+struct WsParams {
+    /// Output offset to resume from after a dropped connection
+    resume: Option<u64>,
+    /// Watch the session's shell read-only, next to the WebSocket attached to it
+    #[serde(default)]
+    view: bool,
+    /// Single-use token from the connect response or `/api/session/:session_id/ws-token`
+    ws_token: Option<String>,
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(params): Query<WsParams>,
     State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Extension(client): Extension<ClientIp>,
 ) -> Response {
     // Log the session ID being requested
-    info!("WebSocket connection request for session ID: {}", session_id);
+    info!("WebSocket connection request from {} for session ID: {}", client, session_id);
+    let ws = ws.protocols(protocol::subprotocols(&state.settings.binary_protocol).iter().copied());
+    
+    // Viewers may only watch; the JWT middleware has checked whose session it is
+    if let Some(Extension(user)) = &user {
+        if !params.view && !authz::may_interact(&state.policy.settings().authorization, user) {
+            warn!("Subject {} may only watch session {}", user.subject, session_id);
+            return (axum::http::StatusCode::FORBIDDEN, Json(serde_json::json!({
+                "error": "forbidden",
+                "message": "Your role only allows watching sessions; connect with view=true",
+            }))).into_response();
+        }
+    }
     
     // Trim any whitespace from the session ID
     let clean_session_id = session_id.trim().to_string();
     
     // Check if the session exists in the registry
     let mut registry = state.session_registry.lock().await;
+    
+    // A session ID alone does not attach; each WebSocket spends a token of its own once upgraded
+    let known = registry.get_session(&clean_session_id).is_some() || registry.get_pending_auth(&clean_session_id).is_some();
+    let mut redemption = None;
+    if known && state.settings.ws_tokens.required {
+        match registry.ws_tokens.check(&clean_session_id, params.ws_token.as_deref()) {
+            Ok(checked) => redemption = Some(checked),
+            Err(e) => {
+                let (error, message) = match e {
+                    WsTokenError::Missing => ("ws_token_required", "A WebSocket token is required; get one from /api/session/:session_id/ws-token"),
+                    WsTokenError::Invalid => ("invalid_ws_token", "The WebSocket token is invalid, expired or already used"),
+                };
+                warn!("WebSocket for session {} from {} refused: {}", clean_session_id, client, message);
+                return (axum::http::StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+                    "error": error,
+                    "message": message,
+                }))).into_response();
+            }
+        }
+    }
+    
+    // Connections still authenticating relay the device's prompts before attaching
+    if let Some(pending) = registry.get_pending_auth(&clean_session_id) {
+        if pending.exchange.is_none() {
+            return (axum::http::StatusCode::CONFLICT, Json(serde_json::json!({
+                "error": "auth_in_progress",
+                "message": "Another WebSocket is already answering this session's authentication prompts",
+            }))).into_response();
+        }
+        drop(registry);
+        
+        // The prompts are taken only once upgraded, so that a failed upgrade can be retried
+        return ws.on_upgrade(move |mut socket| async move {
+            if !redeem(redemption, &state, &mut socket).await {
+                return;
+            }
+            let exchange = state.session_registry.lock().await
+                .get_pending_auth(&clean_session_id)
+                .and_then(|pending| pending.exchange.take());
+            let Some(exchange) = exchange else {
+                warn!("WebSocket for session {} closed: another WebSocket is answering its authentication prompts", clean_session_id);
+                close(&mut socket, close_code::POLICY, "Another WebSocket is already answering this session's authentication prompts").await;
+                return;
+            };
+            if !interactive_auth::relay(&mut socket, exchange, &clean_session_id, &state.settings.binary_protocol).await {
+                return;
+            }
+            let mut registry = state.session_registry.lock().await;
+            let attachment = registry.attach(&clean_session_id, false, &state.scrollback);
+            let session_info = registry.get_session(&clean_session_id);
+            if let (Some(attachment), Some(session_info)) = (attachment, session_info) {
+                let notification_rx = session_info.notifications.subscribe();
+                let portal_user_id = session_info.portal_user_id.clone();
+                let span = session_info.span.clone();
+                drop(registry);
+                handle_socket(socket, attachment, None, notification_rx, clean_session_id, portal_user_id, state).instrument(span).await;
+            }
+        });
+    }
+    
     let session_exists = registry.get_session(&clean_session_id).is_some();
     
+    if session_exists && params.view {
+        drop(registry);
+        return observe(ws, clean_session_id, None, redemption, state).await;
+    }
+    
     if session_exists {
-        // Get session info
-        let session_info = registry.get_session(&clean_session_id).unwrap();
-        let portal_user_id = session_info.portal_user_id.clone();
-        let device_id = session_info.device_id.clone();
-        let ssh_username = session_info.ssh_username.clone();
-        
-        // Clone the SSH session for this connection
-        let session = session_info.ssh_session.clone();
-        
-        // Release the lock before upgrading
+        let span = registry.get_session(&clean_session_id).unwrap().span.clone();
         drop(registry);
         
-        info!("Starting WebSocket connection for session {} (portal user: {}, device: {}, SSH user: {})",
-              clean_session_id, portal_user_id, device_id, ssh_username);
-        
-        // Upgrade the connection with the cloned session
-        ws.on_upgrade(move |socket| handle_socket(socket, session, clean_session_id, portal_user_id, state))
+        // Attach only once upgraded, so that a failed upgrade leaves the session as it was
+        let resume = params.resume;
+        ws.on_upgrade(move |mut socket| async move {
+            if redeem(redemption, &state, &mut socket).await {
+                attach_socket(socket, resume, clean_session_id, state).await;
+            }
+        }.instrument(span))
     } else {
         // Log all available sessions for debugging
         let sessions = registry.get_all_sessions();
@@ -363,58 +2133,285 @@ async fn ws_handler(
     }
 }
 
-async fn handle_socket(
-    socket: WebSocket,
-    mut session: SSHSession,
+/// Attaches a read-only viewer through a share link
+async fn ws_view_handler(
+    ws: WebSocketUpgrade,
+    axum::extract::Path(token): axum::extract::Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let share = state.session_registry.lock().await.find_share(token.trim());
+    let Some((session_id, grant)) = share.filter(|(_, grant)| grant.role == ShareRole::Viewer) else {
+        warn!("WebSocket view request with an unknown or expired share link");
+        return (axum::http::StatusCode::NOT_FOUND, Json(serde_json::json!({
+            "error": "share_not_found",
+            "message": "The share link is invalid, expired or revoked",
+        }))).into_response();
+    };
+    // Browsers always send the page's origin with a WebSocket, and scripts cannot change it
+    let origin = headers.get(header::ORIGIN).and_then(|origin| origin.to_str().ok());
+    if !grant.allows_origin(origin) {
+        warn!("Embed token for session {} used from origin {}", session_id, origin.unwrap_or("(none)"));
+        return (axum::http::StatusCode::FORBIDDEN, Json(serde_json::json!({
+            "error": "origin_not_allowed",
+            "message": "The share link cannot be used from this origin",
+        }))).into_response();
+    }
+    observe(ws.protocols(protocol::subprotocols(&state.settings.binary_protocol).iter().copied()), session_id, Some(grant.revoked), None, state).await
+}
+
+/// Upgrades a WebSocket that watches the session's shell without sending input
+///
+/// A viewer that joined through a share link is detached when the link is revoked.
+async fn observe(
+    ws: WebSocketUpgrade,
     session_id: String,
-    portal_user_id: String,
+    revoked: Option<CancellationToken>,
+    redemption: Option<Redemption>,
     state: AppState,
-) {
-    // Create channels for SSH communication
-    let (ssh_input_tx, ssh_input_rx) = mpsc::channel::<Bytes>(32);
-    let (ssh_output_tx, ssh_output_rx) = mpsc::channel::<Bytes>(32);
+) -> Response {
+    let mut registry = state.session_registry.lock().await;
+    let max_viewers = state.settings.sharing.max_viewers;
+    if registry.get_session(&session_id).is_some_and(|session_info| session_info.viewers >= max_viewers) {
+        return (axum::http::StatusCode::CONFLICT, Json(serde_json::json!({
+            "error": "too_many_viewers",
+            "message": format!("Sessions may have at most {} viewers", max_viewers),
+        }))).into_response();
+    }
+    let Some(attachment) = registry.observe(&session_id, &state.scrollback) else {
+        return shell_error(&session_id, "the session's shell is not available".to_string());
+    };
+    let Some(session_info) = registry.get_session(&session_id) else {
+        return shell_error(&session_id, "the session has ended".to_string());
+    };
+    let notification_rx = session_info.notifications.subscribe();
+    let viewer = format!("{} (viewer)", session_info.portal_user_id);
+    let span = session_info.span.clone();
+    drop(registry);
+
+    if let Some(revoked) = revoked {
+        let detach = attachment.detach.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = revoked.cancelled() => detach.cancel(),
+                _ = detach.cancelled() => {}
+            }
+        });
+    }
+    ws.on_upgrade(move |mut socket| async move {
+        if redeem(redemption, &state, &mut socket).await {
+            handle_socket(socket, attachment, None, notification_rx, session_id, viewer, state).await;
+        }
+    }.instrument(span))
+}
+
+/// Uses up the token of a WebSocket that has been upgraded, if it needed one
+/// Attaches an upgraded WebSocket to its session's shell
+///
+/// The first WebSocket, or one resuming after a dropped connection, attaches
+/// to the session's shell; others open a shell of their own.
+async fn attach_socket(mut socket: WebSocket, resume: Option<u64>, session_id: String, state: AppState) {
+    let mut registry = state.session_registry.lock().await;
+    let attachment = registry.attach(&session_id, resume.is_some(), &state.scrollback);
     
-    // Create resize channel
-    let (resize_tx, resize_rx) = mpsc::channel::<(u32, u32)>(8);
+    // Get session info
+    let Some(session_info) = registry.get_session(&session_id) else {
+        drop(registry);
+        warn!("WebSocket for session {} closed: the session ended before it attached", session_id);
+        close(&mut socket, close_code::AWAY, "The session has ended").await;
+        return;
+    };
+    let portal_user_id = session_info.portal_user_id.clone();
+    let device_id = session_info.device_id.clone();
+    let ssh_username = session_info.ssh_username.clone();
+    let handle = session_info.ssh_session.clone();
+    let notification_rx = session_info.notifications.subscribe();
+    let recorder = session_info.recorder.clone();
+    let audit = session_info.audit.clone().map(CommandAudit::new);
+    let activity = session_info.activity.clone();
+    let span = session_info.span.clone();
+    let (buffer, pipeline) = if session_info.passthrough {
+        (state.scrollback.passthrough_buffer(), OutputPipeline::passthrough())
+    } else {
+        let context = StageContext { session_id: &session_id, device_type: session_info.device_type.as_deref() };
+        (state.scrollback.exclusive_buffer(handle.connection_info().terminal_size), state.output_stages.pipeline(&state.settings, &context))
+    };
+    let watches = OutputWatches { pipeline, ..OutputWatches::default() };
     
-    // Set resize channel on SSH session
-    session.set_resize_channel(resize_rx);
-
-    // Clone session_id for use in the closure
-    let session_id_clone = session_id.clone();
+    // Release the lock before opening a shell
+    drop(registry);
     
-    // Start SSH I/O in a separate thread
-    tokio::task::spawn_blocking(move || {
-        if let Err(e) = session.start_io(ssh_input_rx, ssh_output_tx) {
-            error!("SSH I/O error for session {}: {}", session_id_clone, e);
+    let attachment = match attachment {
+        Some(attachment) => attachment,
+        None => {
+            let opened = match tokio::task::spawn_blocking(move || handle.open_shell()).await {
+                Ok(opened) => opened.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match opened {
+                Ok(shell) => Attachment::exclusive(span.in_scope(|| {
+                    ShellStream::start(shell, buffer, recorder, audit, watches, activity, &session_id)
+                })),
+                Err(reason) => {
+                    error!("Failed to open SSH shell for session {}: {}", session_id, reason);
+                    close(&mut socket, close_code::ERROR, "Failed to open SSH shell").await;
+                    return;
+                }
+            }
         }
-    });
+    };
+    
+    info!("Starting WebSocket connection for session {} (portal user: {}, device: {}, SSH user: {})",
+          session_id, portal_user_id, device_id, ssh_username);
+    handle_socket(socket, attachment, resume, notification_rx, session_id, portal_user_id, state).await;
+}
+
+/// Closes an upgraded WebSocket that cannot be served
+async fn close(socket: &mut WebSocket, code: u16, reason: &'static str) {
+    let _ = socket.send(Message::Close(Some(CloseFrame { code, reason: reason.into() }))).await;
+}
+
+async fn redeem(redemption: Option<Redemption>, state: &AppState, socket: &mut WebSocket) -> bool {
+    match redemption {
+        Some(redemption) => redemption.complete(&state.session_registry, socket).await,
+        None => true,
+    }
+}
 
+fn shell_error(session_id: &str, reason: String) -> Response {
+    error!("Failed to open SSH shell for session {}: {}", session_id, reason);
+    (axum::http::StatusCode::BAD_GATEWAY, Json(serde_json::json!({
+        "error": "ssh_connection_failed",
+        "message": format!("Failed to open SSH shell: {}", reason),
+        "session_id": session_id,
+    }))).into_response()
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    attachment: Attachment,
+    resume: Option<u64>,
+    notification_rx: broadcast::Receiver<serde_json::Value>,
+    session_id: String,
+    portal_user_id: String,
+    state: AppState,
+) {
     // Create WebSocket handler with session context
     let mut ws_handler = WebSocketHandler::new(
         socket,
-        ssh_input_tx,
-        ssh_output_rx,
+        attachment.stream.clone(),
+        attachment.detach.clone(),
         session_id.clone(),
         portal_user_id.clone(),
     );
-    
-    // Set resize channel on WebSocket handler
-    ws_handler.set_resize_channel(resize_tx);
+    ws_handler.set_notification_channel(notification_rx);
+    ws_handler.set_detach_notice(attachment.notice.clone());
+    ws_handler.set_binary_protocol(&state.settings.binary_protocol);
+    ws_handler.set_output_batching(&state.settings.websocket);
+    if let Some(session_info) = state.session_registry.lock().await.get_session(&session_id) {
+        ws_handler.set_capture(session_info.capture.clone());
+        ws_handler.set_session_stats(session_info.stats.clone());
+        if let Some(timeline) = &session_info.timeline {
+            ws_handler.set_timeline(timeline.clone());
+        }
+        let mut filter = CommandFilter::new(
+            state.policy.clone(),
+            session_info.device_type.clone(),
+            session_info.roles.clone(),
+        ).with_approvals(
+            session_info.command_approvals.clone(),
+            portal_user_id.clone(),
+            session_info.notifications.clone(),
+        );
+        if let Some(webhooks) = &state.webhooks {
+            filter = filter.with_webhooks(webhooks.clone(), session_id.clone(), portal_user_id.clone());
+        }
+        ws_handler.set_command_filter(filter);
+        if session_info.passthrough {
+            ws_handler.set_passthrough();
+        }
+        // A shell opened for this WebSocket alone is not shared with anyone
+        if state.settings.presence.enabled && (attachment.is_shared() || attachment.is_read_only()) {
+            let role = if attachment.is_read_only() { PresenceRole::Viewer } else { PresenceRole::Writer };
+            ws_handler.set_presence(session_info.presence.join(
+                portal_user_id.clone(),
+                role,
+                &state.settings.presence,
+                session_info.notifications.clone(),
+            ));
+        }
+    }
+    if let Some(banner) = state.maintenance.banner() {
+        ws_handler.set_banner(banner);
+    }
+    if let Some(offset) = resume {
+        ws_handler.set_resume_offset(offset);
+    }
+    ws_handler.set_read_only(attachment.is_read_only());
+    if state.settings.client_stats.enabled {
+        ws_handler.set_stats_interval(Duration::from_secs(state.settings.client_stats.interval_seconds.max(1)));
+    }
+    if state.settings.client_stats.ping_interval_seconds > 0 {
+        ws_handler.set_ping_interval(Duration::from_secs(state.settings.client_stats.ping_interval_seconds));
+    }
+    if state.settings.slow_consumers.summarize {
+        ws_handler.set_slow_consumers(state.settings.slow_consumers.clone());
+    }
+    if state.settings.flow_control.enabled {
+        ws_handler.set_flow_control(state.settings.flow_control.clone());
+    }
     
     // Start WebSocket handler
     ws_handler.handle().await;
     
-    // Clean up the session when the WebSocket connection ends
-    let mut registry = state.session_registry.lock().await;
     info!("WebSocket connection ended for session {} (portal user: {})",
           session_id, portal_user_id);
     
+    // Viewers leave the session as it was
+    if attachment.is_read_only() {
+        state.session_registry.lock().await.stop_observing(&session_id);
+        return;
+    }
+    
+    // A shell opened for this WebSocket alone goes with it
+    if !attachment.is_shared() {
+        attachment.stream.shutdown();
+        return;
+    }
+    
+    let grace = Duration::from_secs(state.policy.settings().reconnect.grace_seconds);
+    let mut registry = state.session_registry.lock().await;
+    
+    // A WebSocket displaced by a resume or a transfer leaves the session to the next one
+    if !attachment.stream.is_shut_down() && registry.is_displaced(&session_id, &attachment) {
+        return;
+    }
+    
+    // Keep the session's shell running for a while so the client can resume
+    if !attachment.stream.is_shut_down() && !grace.is_zero() {
+        if registry.detach(&session_id, &attachment) {
+            info!("Keeping session {} for {:?} for the client to reconnect", session_id, grace);
+            let registry = state.session_registry.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(grace).await;
+                if registry.lock().await.expire_detached(&session_id, grace) {
+                    info!("Session {} closed: no WebSocket reconnected within {:?}", session_id, grace);
+                }
+            });
+        }
+        return;
+    }
+    
     // Log that we're closing the SSH connection due to WebSocket close
-    debug!("Closing SSH connection for session {} because WebSocket close message received", session_id);
+    debug!("Closing SSH connection for session {} because WebSocket connection ended", session_id);
     
     // Remove the session from the registry and close the SSH connection
-    if registry.remove_session(&session_id) {
+    let reason = if attachment.stream.is_shut_down() {
+        EndReason::ShellClosed
+    } else {
+        EndReason::ClientDisconnected
+    };
+    if registry.remove_session(&session_id, reason) {
         info!("SSH session removed and closed for session {}", session_id);
     } else {
         debug!("Session {} not found in registry during cleanup", session_id);
@@ -426,11 +2423,78 @@ struct SessionStatusRequest {
     portal_user_id: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 struct SessionStatusSingleResponse {
     exists: bool,
     ready: bool,
     message: String,
+    node_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<SessionRecord>,
+    #[serde(flatten)]
+    lifecycle: Lifecycle,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionStatsResponse {
+    session_id: String,
+    node_id: String,
+    // Output the device has sent since the shell started; None until a WebSocket starts it
+    device_output_bytes: Option<u64>,
+    // How keyboard and pasted input was merged into device writes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<InputStatsSnapshot>,
+    // Key re-exchanges the device started, over SSH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_exchanges: Option<ssh::rekey::KeyExchangeHistory>,
+    #[serde(flatten)]
+    stats: PerformanceStats,
+}
+
+#[derive(Debug, Deserialize)]
+struct StaleSessionsQuery {
+    // Seconds without activity; defaults to the cleanup's idle timeout
+    threshold: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct StaleSessionsResponse {
+    node_id: String,
+    threshold_seconds: u64,
+    sessions: Vec<StaleSession>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PurgeRequest {
+    threshold: Option<u64>,
+    // Purge only these of the stale sessions; empty purges them all
+    session_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResult {
+    session_id: String,
+    purged: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_seconds: Option<u64>,
+    // Why the session was left alone: not_found or not_stale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResponse {
+    node_id: String,
+    threshold_seconds: u64,
+    purged: usize,
+    results: Vec<PurgeResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionHistoryResponse {
+    node_id: String,
+    sessions: Vec<SessionRecord>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -441,8 +2505,12 @@ struct SessionTerminateResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SessionStatusResponse {
+    node_id: String,
     active_sessions: usize,
     sessions: Vec<SessionInfo>,
+    // Traffic of the sessions listed, added up
+    totals: PerformanceStats,
+    watchdog: WatchdogStatus,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -452,18 +2520,43 @@ struct SessionInfo {
     device_id: String,
     ssh_username: String,
     last_activity: String,
+    // Last input from a user and output from the device, which decide when the session is idle
+    last_input_at: chrono::DateTime<chrono::Utc>,
+    last_output_at: chrono::DateTime<chrono::Utc>,
+    recording_id: Option<String>,
+    // Output goes to the WebSockets uninspected and unrecorded
+    passthrough: bool,
+    connection: ConnectionInfo,
+    protocol: Protocol,
+    // The watchdog found the session's I/O stuck and is tearing it down
+    degraded: bool,
+    // How the connection's keepalives are going
+    health: ConnectionHealth,
+    // Channels open on the session's connection: its shell and any exec requests sharing it
+    channels: Vec<ChannelOwner>,
+    // Who is attached to the session's shell or watching it
+    participants: Vec<Participant>,
+    // Traffic of the session's WebSockets
+    stats: PerformanceStats,
 }
 
 /// Handler for checking the status of all sessions
 async fn session_status_handler(
     State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
     Json(request): Json<SessionStatusRequest>,
 ) -> Json<SessionStatusResponse> {
     let mut registry = state.session_registry.lock().await;
     
     let mut sessions_info = Vec::new();
     
-    if let Some(portal_user_id) = request.portal_user_id.clone() {
+    // Authenticated users only ever see their own sessions
+    let portal_user_filter = match user {
+        Some(Extension(user)) => Some(user.subject),
+        None => request.portal_user_id.clone(),
+    };
+    
+    if let Some(portal_user_id) = portal_user_filter {
         // Get sessions for a specific portal user
         let session_ids = registry.get_portal_user_sessions(&portal_user_id);
         
@@ -475,6 +2568,17 @@ async fn session_status_handler(
                     device_id: session_info.device_id.clone(),
                     ssh_username: session_info.ssh_username.clone(),
                     last_activity: format!("{:?}", session_info.last_activity),
+                    last_input_at: session_info.activity.last_input(),
+                    last_output_at: session_info.activity.last_output(),
+                    recording_id: session_info.recording_id(),
+                    passthrough: session_info.passthrough,
+                    connection: session_info.ssh_session.connection_info().clone(),
+                    protocol: session_info.ssh_session.target().protocol,
+                    degraded: session_info.degraded_since.is_some(),
+                    health: session_info.health,
+                    channels: session_info.ssh_session.connection().map(SharedConnection::channels).unwrap_or_default(),
+                    participants: session_info.presence.participants(),
+                    stats: session_info.traffic(),
                 });
             }
         }
@@ -498,15 +2602,34 @@ async fn session_status_handler(
                         device_id: session_info.device_id.clone(),
                         ssh_username: session_info.ssh_username.clone(),
                         last_activity: format!("{:?}", session_info.last_activity),
+                        last_input_at: session_info.activity.last_input(),
+                        last_output_at: session_info.activity.last_output(),
+                        recording_id: session_info.recording_id(),
+                        passthrough: session_info.passthrough,
+                        connection: session_info.ssh_session.connection_info().clone(),
+                        protocol: session_info.ssh_session.target().protocol,
+                        degraded: session_info.degraded_since.is_some(),
+                        health: session_info.health,
+                        channels: session_info.ssh_session.connection().map(SharedConnection::channels).unwrap_or_default(),
+                        participants: session_info.presence.participants(),
+                        stats: session_info.traffic(),
                     });
                 }
             }
         }
     }
     
+    let degraded_sessions = sessions_info.iter().filter(|session| session.degraded).count();
+    let mut totals = PerformanceStats::default();
+    for session in &sessions_info {
+        totals.accumulate(&session.stats);
+    }
     Json(SessionStatusResponse {
+        node_id: state.node.id.clone(),
         active_sessions: sessions_info.len(),
         sessions: sessions_info,
+        totals,
+        watchdog: state.watchdog.status(degraded_sessions),
     })
 }
 
@@ -514,6 +2637,7 @@ async fn session_status_handler(
 async fn session_terminate_handler(
     axum::extract::Path(session_id): axum::extract::Path<String>,
     State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
 ) -> Json<SessionTerminateResponse> {
     // Log the session ID being terminated
     info!("Terminating session ID: {}", session_id);
@@ -526,12 +2650,27 @@ async fn session_terminate_handler(
     
     // Check if the session exists
     if let Some(session) = registry.get_session(&clean_session_id) {
+        // Only the owner, or an admin, may terminate a session
+        if let Some(Extension(user)) = &user {
+            let settings = state.policy.settings();
+            if !authz::may_access(&settings.authorization, user, &session.portal_user_id, SessionAccess::Terminate) {
+                warn!("Subject {} may not terminate session {} of {}", user.subject, clean_session_id, session.portal_user_id);
+                return Json(SessionTerminateResponse {
+                    success: false,
+                    message: format!("Session '{}' belongs to another user", clean_session_id),
+                });
+            }
+            if !user.owns(&session.portal_user_id) {
+                info!("Admin {} is terminating session {} of {}", user.subject, clean_session_id, session.portal_user_id);
+            }
+        }
+        
         // Log session details before termination
         info!("Terminating session for portal user {}, device {}, SSH user {}", 
               session.portal_user_id, session.device_id, session.ssh_username);
         
         // Remove the session from the registry
-        registry.remove_session(&clean_session_id);
+        registry.remove_session(&clean_session_id, EndReason::Terminated);
         
         info!("Session {} successfully terminated", clean_session_id);
         Json(SessionTerminateResponse {
@@ -552,40 +2691,371 @@ async fn session_terminate_handler(
 async fn session_status_single_handler(
     axum::extract::Path(session_id): axum::extract::Path<String>,
     State(state): State<AppState>,
-) -> Json<SessionStatusSingleResponse> {
+) -> Response {
     // Log the session ID being checked
     info!("Checking status for session ID: {}", session_id);
     
     // Trim any whitespace from the session ID
     let clean_session_id = session_id.trim().to_string();
     
-    // Check if the session exists in the registry
+    let registry = state.session_registry.lock().await;
+    let lifecycle = registry.lifecycle(&clean_session_id);
+    let history = registry.history(&clean_session_id).cloned();
+    drop(registry);
+    
+    let message = match lifecycle.state {
+        LifecycleState::Authenticating => "Waiting for keyboard-interactive authentication".to_string(),
+        LifecycleState::Ready | LifecycleState::Attached | LifecycleState::Detached => "Session is ready for connection".to_string(),
+        LifecycleState::Degraded => "Session is being closed after its connection stopped responding".to_string(),
+        LifecycleState::Failed => format!("Connection for session '{}' failed", clean_session_id),
+        // A session that has ended is reported with how and when it did
+        LifecycleState::Ended => {
+            let reason = history.as_ref().and_then(|record| record.end_reason).map(|reason| reason.as_str()).unwrap_or("unknown");
+            format!("Session '{}' has ended ({})", clean_session_id, reason)
+        }
+        LifecycleState::NotFound => format!("Session '{}' not found. Waiting for it to be created...", clean_session_id),
+    };
+    info!("Session {}: {:?}", clean_session_id, lifecycle.state);
+    
+    let exists = matches!(lifecycle.state, LifecycleState::Ready | LifecycleState::Attached | LifecycleState::Detached | LifecycleState::Degraded);
+    let retry_after = lifecycle.retry_after_seconds;
+    let response = Json(SessionStatusSingleResponse {
+        exists,
+        ready: exists && lifecycle.state != LifecycleState::Degraded,
+        message,
+        node_id: state.node.id.clone(),
+        history,
+        lifecycle,
+    });
+    match retry_after {
+        Some(seconds) => ([(header::RETRY_AFTER, seconds.to_string())], response).into_response(),
+        None => response.into_response(),
+    }
+}
+
+/// Handler for the traffic and latency of a session's WebSockets
+async fn session_stats_handler(
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Response {
+    let session_id = session_id.trim().to_string();
     let mut registry = state.session_registry.lock().await;
-    let session_exists = registry.get_session(&clean_session_id).is_some();
+    // Authenticated users only ever see their own sessions
+    let session_info = registry.get_session(&session_id).filter(|session_info| match &user {
+        Some(Extension(user)) => session_info.portal_user_id == user.subject,
+        None => true,
+    });
+    let Some(session_info) = session_info else {
+        return (axum::http::StatusCode::NOT_FOUND, Json(serde_json::json!({
+            "error": "session_not_found",
+            "message": format!("Session '{}' not found", session_id),
+        }))).into_response();
+    };
+    Json(SessionStatsResponse {
+        device_output_bytes: session_info.stream.as_ref().map(|stream| stream.end_offset()),
+        input: session_info.stream.as_ref().and_then(|stream| stream.input_stats()),
+        key_exchanges: session_info.ssh_session.connection().map(|connection| connection.key_exchanges().history()),
+        stats: session_info.traffic(),
+        session_id,
+        node_id: state.node.id.clone(),
+    }).into_response()
+}
+
+/// The idle threshold of a stale session request, in seconds; `None` if it is 0
+fn stale_threshold(state: &AppState, threshold: Option<u64>) -> Option<u64> {
+    Some(threshold.unwrap_or(state.policy.settings().session_cleanup.idle_timeout_seconds)).filter(|threshold| *threshold > 0)
+}
+
+fn invalid_threshold() -> Response {
+    (axum::http::StatusCode::BAD_REQUEST, Json(serde_json::json!({
+        "error": "invalid_threshold",
+        "message": "threshold must be at least 1 second",
+    }))).into_response()
+}
+
+/// Handler listing sessions idle past the threshold that the cleanup has not removed yet
+async fn stale_sessions_handler(
+    State(state): State<AppState>,
+    Query(query): Query<StaleSessionsQuery>,
+) -> Response {
+    let Some(threshold) = stale_threshold(&state, query.threshold) else {
+        return invalid_threshold();
+    };
+    let output_counts = state.policy.settings().session_cleanup.output_counts_as_activity;
+    let sessions = state.session_registry.lock().await.stale_sessions(Duration::from_secs(threshold), output_counts);
+    Json(StaleSessionsResponse {
+        node_id: state.node.id.clone(),
+        threshold_seconds: threshold,
+        sessions,
+    }).into_response()
+}
+
+/// Handler removing stale sessions now, rather than at the next cleanup
+async fn purge_sessions_handler(
+    State(state): State<AppState>,
+    request: Option<Json<PurgeRequest>>,
+) -> Response {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let Some(threshold) = stale_threshold(&state, request.threshold) else {
+        return invalid_threshold();
+    };
     
-    if session_exists {
-        info!("Session {} exists and is ready", clean_session_id);
-        Json(SessionStatusSingleResponse {
-            exists: true,
-            ready: true,
-            message: "Session is ready for connection".to_string(),
-        })
+    let output_counts = state.policy.settings().session_cleanup.output_counts_as_activity;
+    let mut registry = state.session_registry.lock().await;
+    let stale = registry.stale_sessions(Duration::from_secs(threshold), output_counts);
+    let session_ids: Vec<String> = if request.session_ids.is_empty() {
+        stale.iter().map(|session| session.session_id.clone()).collect()
     } else {
-        // Check if the session ID contains connection information
-        // Format: portal-{portal_user_id}-device-{device_id}-ssh-{ssh_username}-{uuid}
-        let _parts: Vec<&str> = clean_session_id.split('-').collect();
-        
-        // Log all available sessions for debugging
-        let sessions = registry.get_all_sessions();
-        info!("Available sessions: {}", sessions.join(", "));
-        info!("Session {} does not exist", clean_session_id);
-        
-        // For now, just return that the session doesn't exist
-        // The frontend will continue polling until it times out or the session is created
-        Json(SessionStatusSingleResponse {
-            exists: false,
-            ready: false,
-            message: format!("Session '{}' not found. Waiting for it to be created...", clean_session_id),
-        })
+        request.session_ids.iter().map(|session_id| session_id.trim().to_string()).collect()
+    };
+    
+    let mut results = Vec::new();
+    for session_id in session_ids {
+        let idle_seconds = stale.iter().find(|session| session.session_id == session_id).map(|session| session.idle_seconds);
+        let result = match idle_seconds {
+            Some(idle_seconds) => {
+                let purged = registry.remove_session(&session_id, EndReason::Purged);
+                PurgeResult { session_id, purged, idle_seconds: Some(idle_seconds), reason: (!purged).then_some("not_found") }
+            }
+            None => {
+                let live = registry.history(&session_id).is_some_and(|record| record.ended_at.is_none());
+                PurgeResult { session_id, purged: false, idle_seconds: None, reason: Some(if live { "not_stale" } else { "not_found" }) }
+            }
+        };
+        results.push(result);
+    }
+    let purged = results.iter().filter(|result| result.purged).count();
+    info!("Purged {} stale sessions idle for over {}s", purged, threshold);
+    
+    Json(PurgeResponse {
+        node_id: state.node.id.clone(),
+        threshold_seconds: threshold,
+        purged,
+        results,
+    }).into_response()
+}
+
+/// Handler for the lifecycle history of live and ended sessions
+async fn session_history_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Query(request): Query<SessionStatusRequest>,
+) -> Json<SessionHistoryResponse> {
+    // Authenticated users only ever see their own sessions
+    let portal_user_filter = match user {
+        Some(Extension(user)) => Some(user.subject),
+        None => request.portal_user_id,
+    };
+    
+    let registry = state.session_registry.lock().await;
+    Json(SessionHistoryResponse {
+        node_id: state.node.id.clone(),
+        sessions: registry.session_history(portal_user_filter.as_deref()),
+    })
+}
+
+/// Gateway state for tests, with the files `settings` names moved to a fresh temporary directory
+#[cfg(test)]
+fn test_state(mut settings: Settings) -> AppState {
+    let dir = std::env::temp_dir().join(format!("webssh-state-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    settings.api_keys.store_file = dir.join("api_keys.json").display().to_string();
+    settings.policy_bundles.file = dir.join("policy.json").display().to_string();
+    settings.scrollback.spill_enabled = false;
+    let settings = Arc::new(settings);
+    let node = Arc::new(NodeIdentity::from_settings(&settings.server).unwrap());
+    AppState {
+        session_registry: Arc::new(Mutex::new(SessionRegistry::new())),
+        settings: settings.clone(),
+        api_keys: Arc::new(Mutex::new(ApiKeyStore::load(&settings.api_keys))),
+        jwt: settings.jwt.enabled.then(|| Arc::new(JwtValidator::from_settings(&settings.jwt).unwrap())),
+        node: node.clone(),
+        directory: None,
+        watchdog: Arc::new(WatchdogMetrics::default()),
+        policy: Arc::new(PolicyStore::load((*settings).clone())),
+        scrollback: Arc::new(ScrollbackStore::new(&settings, &node.id)),
+        audit: None,
+        http: Arc::new(HttpPolicy::from_settings(&settings.http).unwrap()),
+        ssh_ca: None,
+        inventory: None,
+        credentials: None,
+        templates: None,
+        terminal_types: Arc::new(TerminalTypes::new(settings.ssh.terminal.downgrade.clone())),
+        connect_limiter: Arc::new(ConnectLimiter::default()),
+        webhooks: None,
+        password_change: None,
+        maintenance: Arc::new(Maintenance::new(&settings.maintenance)),
+        output_stages: Arc::new(OutputStages::new(&settings.output_pipeline).unwrap()),
+        config_backups: None,
+        tasks: Arc::new(TaskSupervisor::new(&settings.background_tasks)),
+        alerts: Arc::new(Alerts::new(&settings.alerts, None)),
+        settings_source: Arc::new(cli::SettingsSource {
+            config: dir.join("settings.json"),
+            overrides: cli::Overrides::default(),
+        }),
+        reachability: None,
+        host_keys: None,
+        bulk_jobs: Arc::new(BulkJobs::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::{Request, StatusCode};
+    use tower::Service;
+
+    /// Sends a request to the main listener's router, as from 127.0.0.1
+    pub(crate) async fn send(app: &Router, mut request: Request<Body>) -> Response {
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        app.clone().call(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_refuses_unknown_api_keys() {
+        let mut settings = Settings::default();
+        settings.jwt.enabled = true;
+        settings.jwt.secret = Some("test-secret".to_string());
+        settings.api_keys.enabled = true;
+        let state = test_state(settings);
+        let key = state.api_keys.lock().await.create("backend", vec![api_keys::ApiKeyScope::Connect]).unwrap().key;
+        let (app, _) = router(state);
+
+        for presented in [Some("wsk_junk"), None] {
+            let mut request = Request::get("/ws/abc?ws_token=t");
+            if let Some(presented) = presented {
+                request = request.header(api_keys::API_KEY_HEADER, presented);
+            }
+            let response = send(&app, request.body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "with {:?}", presented);
+        }
+
+        // A real key gets past authentication, to the WebSocket upgrade this request lacks
+        let request = Request::get("/ws/abc?ws_token=t").header(api_keys::API_KEY_HEADER, &key).body(Body::empty()).unwrap();
+        assert_ne!(send(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_connection_targets_check_pinned_host_keys() {
+        let mut settings = Settings::default();
+        settings.host_keys.enabled = true;
+        settings.host_keys.pins_file = std::env::temp_dir().join(format!("webssh-host-keys-{}.json", uuid::Uuid::new_v4())).display().to_string();
+        let mut state = test_state(settings.clone());
+        let keys = Arc::new(HostKeys::load(&settings.host_keys).unwrap());
+        state.host_keys = Some(keys.clone());
+        let pinned = PresentedKey::from_blob(b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let presented = PresentedKey::from_blob(b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+        keys.pin("router1", 22, &pinned, "alice").unwrap();
+
+        // Exec, credential checks and configuration backups build their targets as connects do
+        let credentials = SSHCredentials { hostname: "router1".to_string(), username: "admin".to_string(), ..Default::default() };
+        let check = connection_target(&state, &credentials, &settings, "bob").host_key_check.unwrap();
+        assert!(check.verify_key("router1", 22, pinned.clone()).is_ok());
+        let refused = check.verify_key("router1", 22, presented.clone()).unwrap_err();
+        assert_eq!(refused.error_code(), ErrorCode::HostKeyChanged);
+
+        // A device that is not pinned waits for its key to be confirmed
+        let refused = check.verify_key("router2", 22, presented.clone()).unwrap_err();
+        assert_eq!(refused.error_code(), ErrorCode::HostkeyConfirmationRequired);
+        assert!(keys.pinned("router2", 22).is_none());
+
+        let credentials = SSHCredentials { host_key_fingerprint: Some(presented.fingerprint.clone()), ..credentials };
+        let check = connection_target(&state, &credentials, &settings, "bob").host_key_check.unwrap();
+        assert_eq!(check.verify_key("router1", 22, presented.clone()).unwrap_err().error_code(), ErrorCode::HostKeyChanged);
+        assert!(check.verify_key("router2", 22, presented).is_ok());
+        assert_eq!(keys.pinned("router2", 22).unwrap().pinned_by, "bob");
+
+        let telnet = SSHCredentials { protocol: Some(Protocol::Telnet), ..credentials };
+        assert!(connection_target(&state, &telnet, &settings, "bob").host_key_check.is_none());
+        let _ = std::fs::remove_file(&settings.host_keys.pins_file);
+    }
+
+    #[test]
+    fn test_jump_hosts_are_checked_against_their_own_pins() {
+        let mut settings = Settings::default();
+        settings.host_keys.enabled = true;
+        settings.host_keys.pins_file = std::env::temp_dir().join(format!("webssh-host-keys-{}.json", uuid::Uuid::new_v4())).display().to_string();
+        let mut state = test_state(settings.clone());
+        let keys = Arc::new(HostKeys::load(&settings.host_keys).unwrap());
+        state.host_keys = Some(keys.clone());
+        let pinned = PresentedKey::from_blob(b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let presented = PresentedKey::from_blob(b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+        keys.pin("bastion", 2222, &pinned, "alice").unwrap();
+        keys.pin("router1", 22, &presented, "alice").unwrap();
+
+        let jump_host = JumpHost { hostname: "bastion".to_string(), port: 2222, username: "jump".to_string(), password: None, private_key: None, private_key_passphrase: None };
+        let credentials = SSHCredentials {
+            hostname: "router1".to_string(),
+            username: "admin".to_string(),
+            jump_host: Some(jump_host),
+            host_key_fingerprint: Some(presented.fingerprint.clone()),
+            ..Default::default()
+        };
+        let target = connection_target(&state, &credentials, &settings, "bob");
+        let check = target.jump_host.as_ref().unwrap().host_key_check.as_ref().unwrap();
+        assert!(check.verify_key("bastion", 2222, pinned.clone()).is_ok());
+
+        // A bastion presenting another key is refused, even one confirmed for the device
+        let refused = check.verify_key("bastion", 2222, presented.clone()).unwrap_err().at_jump_host("bastion", 2222);
+        assert_eq!(refused.error_code(), ErrorCode::HostKeyChanged);
+        assert_eq!(refused.host_key().unwrap().jump_host.as_deref(), Some("bastion:2222"));
+        assert!(refused.to_string().contains("jump host bastion:2222 presented"));
+        assert_eq!(keys.pinned("bastion", 2222).unwrap().fingerprint, pinned.fingerprint);
+        assert!(target.host_key_check.unwrap().verify_key("router1", 22, presented).is_ok());
+        let _ = std::fs::remove_file(&settings.host_keys.pins_file);
+    }
+
+    /// A WebSocket handshake whose connection cannot actually be upgraded
+    fn handshake(uri: &str) -> Request<Body> {
+        let mut request = Request::get(uri)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        let upgrade = hyper::upgrade::on(&mut request);
+        request.extensions_mut().insert(upgrade);
+        request
+    }
+
+    #[tokio::test]
+    async fn test_ws_token_is_kept_until_the_upgrade_succeeds() {
+        let state = test_state(Settings::default());
+        let (_, exchange) = interactive_auth::relay_channel(Duration::from_secs(60));
+        let token = {
+            let mut registry = state.session_registry.lock().await;
+            registry.add_pending_auth("s1", PendingAuth {
+                portal_user_id: "alice".to_string(),
+                device_id: "r1".to_string(),
+                ssh_username: "admin".to_string(),
+                roles: Vec::new(),
+                locks_device: false,
+                exchange: Some(exchange),
+            });
+            registry.ws_tokens.issue("s1", chrono::Duration::seconds(60))
+        };
+        let (app, _) = router(state.clone());
+        let uri = format!("/ws/s1?ws_token={}", token);
+
+        // The handshake is answered, but the connection is never handed over
+        let response = send(&app, handshake(&uri)).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Neither the token nor the authentication prompts were taken, so a retry gets them
+        {
+            let mut registry = state.session_registry.lock().await;
+            assert!(registry.ws_tokens.check("s1", Some(&token)).is_ok());
+            assert!(registry.get_pending_auth("s1").unwrap().exchange.is_some());
+        }
+        let response = send(&app, handshake(&uri)).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        let response = send(&app, handshake("/ws/s1?ws_token=wst_unknown")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
 }
@@ -0,0 +1,162 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::{PoisonError, RwLock};
+use tracing::info;
+
+use crate::jwt::AuthenticatedUser;
+use crate::settings::MaintenanceSettings;
+use crate::AppState;
+
+/// Most characters of a refusal message or banner
+const MAX_MESSAGE_CHARS: usize = 1000;
+
+/// The gateway's maintenance mode, while it is on
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceMode {
+    /// What connect requests are refused with
+    pub message: String,
+    /// Shown to every attached client, if set
+    pub banner: Option<String>,
+    pub since: DateTime<Utc>,
+    /// Who turned it on; `None` when started in maintenance by the settings
+    pub started_by: Option<String>,
+}
+
+/// Whether this instance takes new connections
+///
+/// In maintenance, connect and exec requests are refused, open sessions carry
+/// on, and `/healthz` reports the instance unavailable so load balancers send
+/// new connections elsewhere. The mode is kept in memory only.
+pub struct Maintenance {
+    mode: RwLock<Option<MaintenanceMode>>,
+}
+
+impl Maintenance {
+    pub fn new(settings: &MaintenanceSettings) -> Self {
+        let mode = settings.enabled.then(|| MaintenanceMode {
+            message: settings.message.clone(),
+            banner: settings.banner.clone(),
+            since: Utc::now(),
+            started_by: None,
+        });
+        Self { mode: RwLock::new(mode) }
+    }
+
+    pub fn current(&self) -> Option<MaintenanceMode> {
+        self.mode.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// The message new connections are refused with, while in maintenance
+    pub fn refusal(&self) -> Option<String> {
+        self.current().map(|mode| mode.message)
+    }
+
+    /// The banner frame for a client attaching while one is shown
+    pub fn banner(&self) -> Option<Value> {
+        self.current().and_then(|mode| mode.banner).map(|banner| banner_event(Some(&banner)))
+    }
+
+    fn set(&self, mode: Option<MaintenanceMode>) {
+        *self.mode.write().unwrap_or_else(PoisonError::into_inner) = mode;
+    }
+}
+
+/// A banner for clients to show above the terminal, or to take down with no message
+fn banner_event(message: Option<&str>) -> Value {
+    json!({ "type": "banner", "level": "info", "message": message })
+}
+
+/// Body of a request to turn maintenance mode on or off
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+    /// What connect requests are refused with; `maintenance.message` by default
+    pub message: Option<String>,
+    /// Shown to every attached client; none by default
+    pub banner: Option<String>,
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn status(mode: Option<MaintenanceMode>) -> Value {
+    json!({
+        "enabled": mode.is_some(),
+        "maintenance": mode,
+    })
+}
+
+/// Reports whether this instance is in maintenance
+pub async fn status_handler(State(state): State<AppState>) -> Response {
+    Json(status(state.maintenance.current())).into_response()
+}
+
+/// Turns maintenance mode on or off, showing or taking down the banner in every attached client
+pub async fn update_handler(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Json(request): Json<MaintenanceRequest>,
+) -> Response {
+    let too_long = |text: &Option<String>| text.as_ref().is_some_and(|text| text.chars().count() > MAX_MESSAGE_CHARS);
+    if too_long(&request.message) || too_long(&request.banner) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request",
+                              format!("message and banner may be at most {} characters", MAX_MESSAGE_CHARS));
+    }
+    let previous = state.maintenance.current();
+    let mode = request.enabled.then(|| MaintenanceMode {
+        message: request.message.filter(|message| !message.trim().is_empty())
+            .unwrap_or_else(|| state.settings.maintenance.message.clone()),
+        banner: request.banner.filter(|banner| !banner.trim().is_empty()),
+        // Changing the message or banner does not restart the maintenance window
+        since: previous.as_ref().map(|mode| mode.since).unwrap_or_else(Utc::now),
+        started_by: match &previous {
+            Some(previous) => previous.started_by.clone(),
+            None => user.map(|Extension(user)| user.subject),
+        },
+    });
+    state.maintenance.set(mode.clone());
+
+    let banner = mode.as_ref().and_then(|mode| mode.banner.as_deref());
+    let previous_banner = previous.as_ref().and_then(|mode| mode.banner.as_deref());
+    let notified = if banner != previous_banner {
+        state.session_registry.lock().await.notify_all(banner_event(banner))
+    } else {
+        0
+    };
+    match &mode {
+        Some(mode) => info!("Maintenance mode on: refusing new connections with \"{}\"{}", mode.message,
+                            if notified > 0 { format!("; banner sent to {} session(s)", notified) } else { String::new() }),
+        None => info!("Maintenance mode off: taking new connections"),
+    }
+    Json(status(mode)).into_response()
+}
+
+/// Tells load balancers whether to send new connections to this instance
+///
+/// Answers `503` in maintenance, so the instance is drained while its open
+/// sessions carry on.
+pub async fn healthz_handler(State(state): State<AppState>) -> Response {
+    match state.maintenance.current() {
+        None => Json(json!({
+            "status": "ok",
+            "node_id": state.node.id,
+        })).into_response(),
+        Some(mode) => (StatusCode::SERVICE_UNAVAILABLE, Json(json!({
+            "status": "maintenance",
+            "node_id": state.node.id,
+            "message": mode.message,
+            "since": mode.since,
+        }))).into_response(),
+    }
+}
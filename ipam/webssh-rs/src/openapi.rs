@@ -0,0 +1,368 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::{
+    extract::Request,
+    handler::Handler,
+    response::IntoResponse,
+    routing::{self, MethodRouter, Route},
+    Json, Router,
+};
+use serde_json::{json, Map, Value};
+use tower::{Layer, Service};
+
+use crate::error_code::ErrorCode;
+use crate::AppState;
+
+/// What a caller needs to use a route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// An API key with the `connect` scope, or a JWT
+    Connect,
+    /// An API key with the `read_status` scope, or a JWT
+    ReadStatus,
+    /// An API key with the `admin` scope
+    Admin,
+    /// A JWT, when JWT authentication is enabled
+    Jwt,
+    /// The share link token in the path
+    ShareToken,
+    Public,
+}
+
+/// A method of a route, as registered with the router
+#[derive(Clone)]
+struct Endpoint {
+    method: &'static str,
+    summary: &'static str,
+    /// Schemas of the request and success response, if documented beyond a JSON object
+    request: Option<&'static str>,
+    response: Option<&'static str>,
+}
+
+/// A route of the HTTP API
+struct Operation {
+    /// In OpenAPI form, e.g. `/api/session/{session_id}/stats`
+    path: String,
+    access: Access,
+    endpoint: Endpoint,
+}
+
+/// A method router that keeps a summary of each method it routes
+pub(crate) struct Documented {
+    router: MethodRouter<AppState>,
+    endpoints: Vec<Endpoint>,
+}
+
+/// Routes a first method, or further ones, keeping a summary of each
+macro_rules! methods {
+    (first: $($first:ident),*; further: $($further:ident),*) => {
+        $(
+            pub(crate) fn $first<H: Handler<T, AppState>, T: 'static>(handler: H, summary: &'static str) -> Documented {
+                Documented { router: routing::$first(handler), endpoints: vec![Endpoint::new(stringify!($first), summary)] }
+            }
+        )*
+
+        impl Documented {
+            $(
+                pub(crate) fn $further<H: Handler<T, AppState>, T: 'static>(mut self, handler: H, summary: &'static str) -> Self {
+                    self.router = self.router.$further(handler);
+                    self.endpoints.push(Endpoint::new(stringify!($further), summary));
+                    self
+                }
+            )*
+        }
+    };
+}
+
+methods!(first: get, post, delete; further: post, put, delete);
+
+impl Endpoint {
+    fn new(method: &'static str, summary: &'static str) -> Self {
+        Endpoint { method, summary, request: None, response: None }
+    }
+}
+
+impl Documented {
+    /// Describes the request as a `ConnectRequest` and the response with the given schema
+    pub(crate) fn connect(mut self, response: &'static str) -> Self {
+        for endpoint in &mut self.endpoints {
+            endpoint.request = Some("ConnectRequest");
+            endpoint.response = Some(response);
+        }
+        self
+    }
+
+    /// Layers the methods routed so far, as [`MethodRouter::layer`] does
+    pub(crate) fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.router = self.router.layer(layer);
+        self
+    }
+}
+
+/// Routes needing the same access, recording the operations they serve
+pub(crate) struct Routes {
+    access: Access,
+    router: Router<AppState>,
+    operations: Vec<Operation>,
+}
+
+impl Routes {
+    pub(crate) fn new(access: Access) -> Self {
+        Routes { access, router: Router::new(), operations: Vec::new() }
+    }
+
+    /// Routes `path` as [`Router::route`] does, with axum's `:name` parameters
+    pub(crate) fn route(mut self, path: &str, documented: Documented) -> Self {
+        let openapi_path: Vec<String> = path.split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect();
+        let openapi_path = openapi_path.join("/");
+        for endpoint in documented.endpoints {
+            self.operations.push(Operation { path: openapi_path.clone(), access: self.access, endpoint });
+        }
+        self.router = self.router.route(path, documented.router);
+        self
+    }
+
+    /// Adds the operations to the API and hands back the router, for its access layers
+    pub(crate) fn finish(self, api: &mut Api) -> Router<AppState> {
+        api.operations.extend(self.operations);
+        self.router
+    }
+}
+
+/// The operations of the routers built so far
+#[derive(Default)]
+pub(crate) struct Api {
+    operations: Vec<Operation>,
+}
+
+impl Api {
+    /// Serves the OpenAPI document of every operation, including its own, at `path`
+    pub(crate) fn serve(mut self, path: &str, router: Router<AppState>) -> Router<AppState> {
+        self.operations.push(Operation { path: path.to_string(), access: Access::Public, endpoint: Endpoint::new("get", "This OpenAPI document") });
+        let document = Arc::new(document(&self.operations));
+        router.route(path, routing::get(move || async move { Json(document.as_ref().clone()) }))
+    }
+}
+
+/// Builds the OpenAPI 3 document of the HTTP API
+///
+/// Request and response bodies are described in detail for the connect
+/// endpoints; API.md remains the reference for the others.
+fn document(operations: &[Operation]) -> Value {
+    let mut paths = Map::new();
+    for operation in operations {
+        let item = paths.entry(operation.path.clone()).or_insert_with(|| json!({}));
+        item[operation.endpoint.method] = operation.to_value();
+    }
+
+    let error_codes: Vec<&str> = ErrorCode::ALL.iter().map(|code| code.as_str()).collect();
+    let error_code_descriptions: String = ErrorCode::ALL.iter()
+        .map(|code| format!("- `{}`: {}\n", code, code.description()))
+        .collect();
+    // Kept apart from the document, whose size is bound by the json! macro's recursion limit
+    let connect_request = json!({
+        "type": "object",
+        "description": "The device, by hostname or device_ref, and how to authenticate to it",
+        "properties": {
+            "hostname": { "type": "string" },
+            "port": { "type": "integer" },
+            "username": { "type": "string" },
+            "password": { "type": "string" },
+            "private_key": { "type": "string" },
+            "private_key_passphrase": { "type": "string" },
+            "auth_type": { "type": "string", "enum": ["password", "private-key", "keyboard-interactive", "certificate", "agent", "pkcs11", "gssapi"] },
+            "enable_password": { "type": "string" },
+            "device_name": { "type": "string" },
+            "portal_user_id": { "type": "string" },
+            "session_id": { "type": "string" },
+            "compression": { "type": "string" },
+            "jump_host": { "type": "object" },
+            "protocol": { "type": "string", "enum": ["ssh", "telnet"] },
+            "term": { "type": "string", "description": "TERM for the shell, e.g. xterm-256color" },
+            "cols": { "type": "integer" },
+            "rows": { "type": "integer" },
+            "width_px": { "type": "integer", "description": "Initial terminal width in pixels" },
+            "height_px": { "type": "integer", "description": "Initial terminal height in pixels" },
+            "pty_modes": { "type": "object", "description": "PTY modes by termios name, e.g. {\"ECHO\": false}", "additionalProperties": { "type": ["boolean", "integer"] } },
+            "env": { "type": "object", "additionalProperties": { "type": "string" } },
+            "device_type": { "type": "string" },
+            "device_ref": { "type": "string", "description": "Inventory device to connect to, in place of hostname and credentials" },
+            "credential_ref": { "type": "string", "description": "vault:MOUNT/PATH, env:NAME or file:NAME" },
+            "pkcs11": { "type": "object", "description": "module, token, and label or id (hex CKA_ID) of a key in an HSM or smartcard, with auth_type pkcs11" },
+            "passthrough": { "type": "boolean", "description": "Send output straight to the WebSocket, neither recorded nor inspected; needs passthrough.enabled" },
+            "host_key_fingerprint": { "type": "string", "description": "Required by /api/connect/confirm: the fingerprint of the host key the user confirmed, e.g. SHA256:..." },
+        },
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "WebSSH-RS API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Web SSH and telnet gateway to network devices. See API.md for the behaviour of each endpoint.",
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": crate::api_keys::API_KEY_HEADER },
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "description": "Failure of a REST endpoint",
+                    "required": ["error", "message"],
+                    "properties": {
+                        "error": { "type": "string", "description": "Lowercase identifier, e.g. session_not_found" },
+                        "message": { "type": "string" },
+                    },
+                },
+                "ErrorCode": {
+                    "type": "string",
+                    "enum": error_codes,
+                    "description": format!("Why a connect, exec or credential check failed\n\n{}", error_code_descriptions),
+                },
+                "ConnectRequest": connect_request,
+                "ConnectResponse": {
+                    "type": "object",
+                    "required": ["success", "message", "node_id"],
+                    "properties": {
+                        "success": { "type": "boolean" },
+                        "message": { "type": "string" },
+                        "session_id": { "type": "string", "nullable": true },
+                        "websocket_url": { "type": "string", "nullable": true },
+                        "ws_token": { "type": "string", "description": "Single-use token for the session's WebSocket, also in websocket_url" },
+                        "error_code": { "allOf": [{ "$ref": "#/components/schemas/ErrorCode" }], "nullable": true },
+                        "node_id": { "type": "string" },
+                        "auth_pending": { "type": "boolean" },
+                        "device_type": { "type": "string" },
+                        "warnings": { "type": "array", "items": { "type": "string" } },
+                        "host_key": { "type": "object", "description": "The device's host key as key_type and fingerprint, with HOSTKEY_CONFIRMATION_REQUIRED or HOST_KEY_CHANGED" },
+                    },
+                },
+                "ValidationResponse": {
+                    "type": "object",
+                    "required": ["success", "message"],
+                    "properties": {
+                        "success": { "type": "boolean" },
+                        "message": { "type": "string" },
+                        "error_code": { "allOf": [{ "$ref": "#/components/schemas/ErrorCode" }], "nullable": true },
+                        "auth_methods": { "type": "array", "items": { "type": "string" } },
+                        "server_banner": { "type": "string", "nullable": true },
+                    },
+                },
+            },
+        },
+    })
+}
+
+impl Operation {
+    fn to_value(&self) -> Value {
+        let parameters: Vec<Value> = self.path.split('/')
+            .filter_map(|segment| segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')))
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+            .collect();
+        let success = match self.endpoint.response {
+            Some(schema) => json!({
+                "description": "Success, or a failure described by error_code",
+                "content": { "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } } },
+            }),
+            None => json!({ "description": "Success" }),
+        };
+        let mut operation = json!({
+            "summary": self.endpoint.summary,
+            "parameters": parameters,
+            "responses": {
+                "2XX": success,
+                "default": {
+                    "description": "Error",
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } },
+                },
+            },
+        });
+        if let Some(schema) = self.endpoint.request {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } } },
+            });
+        }
+        let (security, scope) = match self.access {
+            Access::Connect => (json!([{ "bearerAuth": [] }, { "apiKey": [] }]), Some("connect")),
+            Access::ReadStatus => (json!([{ "bearerAuth": [] }, { "apiKey": [] }]), Some("read_status")),
+            Access::Admin => (json!([{ "apiKey": [] }]), Some("admin")),
+            Access::Jwt => (json!([{ "bearerAuth": [] }]), None),
+            Access::ShareToken | Access::Public => (json!([]), None),
+        };
+        operation["security"] = security;
+        if let Some(scope) = scope {
+            operation["x-api-key-scope"] = json!(scope);
+        }
+        operation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::http::StatusCode;
+    use crate::settings::Settings;
+    use crate::tests::send;
+
+    async fn fetch_document(app: &Router) -> Value {
+        let response = send(app, Request::get("/api/openapi.json").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_document_describes_the_router() {
+        let mut settings = Settings::default();
+        settings.api_keys.enabled = true;
+        let (app, _) = crate::router(crate::test_state(settings.clone()));
+        let document = fetch_document(&app).await;
+
+        // Every operation documented is routed, behind the API key scope it documents
+        let paths = document["paths"].as_object().unwrap();
+        for (path, item) in paths {
+            let uri = path.replace(['{', '}'], "");
+            for (method, operation) in item.as_object().unwrap() {
+                let request = Request::builder().method(method.to_uppercase().as_str()).uri(&uri).body(Body::empty()).unwrap();
+                let status = send(&app, request).await.status();
+                assert!(status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+                if operation.get("x-api-key-scope").is_some() {
+                    assert_eq!(status, StatusCode::UNAUTHORIZED, "{} {}", method, path);
+                }
+            }
+        }
+        assert_eq!(document["paths"]["/api/connect"]["post"]["x-api-key-scope"], "connect");
+        assert_eq!(document["paths"]["/api/connect"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ConnectRequest");
+        assert_eq!(document["paths"]["/api/session/{session_id}/stats"]["get"]["parameters"][0]["name"], "session_id");
+        assert_eq!(document["paths"]["/api/inventory/{device_ref}"].as_object().unwrap().len(), 3);
+        assert_eq!(document["paths"]["/api/faults"].is_object(), cfg!(feature = "fault-injection"));
+
+        // The control plane listener serves the same document, routes of both listeners included
+        settings.server.control_plane.enabled = true;
+        let (_, control_app) = crate::router(crate::test_state(settings));
+        assert_eq!(fetch_document(&control_app.unwrap()).await["paths"], document["paths"]);
+
+        let codes = document["components"]["schemas"]["ErrorCode"]["enum"].as_array().unwrap();
+        assert_eq!(codes.len(), ErrorCode::ALL.len());
+        for code in ErrorCode::ALL {
+            assert_eq!(serde_json::to_value(code).unwrap(), code.as_str());
+        }
+    }
+}
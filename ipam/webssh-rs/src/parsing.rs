@@ -0,0 +1,298 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::settings::ParsingSettings;
+use crate::textfsm::{Template, TemplateError};
+use crate::AppState;
+
+/// Templates built into the gateway, in the layout of a templates directory
+const BUNDLED_INDEX: &str = include_str!("templates/index");
+const BUNDLED: &[(&str, &str)] = &[
+    ("cisco_show_ip_interface_brief.textfsm", include_str!("templates/cisco_show_ip_interface_brief.textfsm")),
+    ("cisco_show_version.textfsm", include_str!("templates/cisco_show_version.textfsm")),
+    ("linux_df.textfsm", include_str!("templates/linux_df.textfsm")),
+];
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("{file}: {source}")]
+    Template { file: String, source: TemplateError },
+    #[error("{file}: {message}")]
+    Index { file: String, message: String },
+    #[error("failed to read {file}: {source}")]
+    Io { file: String, source: io::Error },
+}
+
+/// Where a template was loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateSource {
+    /// The templates directory; these take precedence
+    Runtime,
+    Bundled,
+}
+
+/// One line of an index: which template parses a command's output on which devices
+struct Entry {
+    file: String,
+    source: TemplateSource,
+    hostname: String,
+    platform: String,
+    command: String,
+    hostname_regex: Regex,
+    platform_regex: Regex,
+    command_regex: Regex,
+    template: Arc<Template>,
+}
+
+/// An index entry, as listed by the API
+#[derive(Debug, Serialize)]
+pub struct TemplateInfo {
+    pub template: String,
+    pub source: TemplateSource,
+    pub hostname: String,
+    pub platform: String,
+    pub command: String,
+}
+
+/// The templates command output is parsed with, selected ntc-templates style
+///
+/// An `index` file lists templates with the hostname, platform and command
+/// they apply to; the platform is matched against the request's device type.
+/// Templates in the templates directory are tried before the bundled ones and
+/// may be reloaded while the gateway runs.
+pub struct TemplateLibrary {
+    dir: PathBuf,
+    entries: RwLock<Arc<Vec<Entry>>>,
+}
+
+impl TemplateLibrary {
+    pub fn load(settings: &ParsingSettings) -> Result<Self, LibraryError> {
+        let library = Self {
+            dir: PathBuf::from(&settings.templates_dir),
+            entries: RwLock::new(Arc::new(Vec::new())),
+        };
+        library.reload()?;
+        Ok(library)
+    }
+
+    /// Reads the templates directory again; the templates in use are kept if any fails to load
+    ///
+    /// # Returns
+    /// * `Result<usize, LibraryError>` - The number of templates now available
+    pub fn reload(&self) -> Result<usize, LibraryError> {
+        let mut entries = if self.dir.join("index").exists() {
+            read_runtime(&self.dir)?
+        } else {
+            Vec::new()
+        };
+        let runtime = entries.len();
+        entries.extend(read_index("bundled index", BUNDLED_INDEX, TemplateSource::Bundled, |file| {
+            BUNDLED.iter()
+                .find(|(name, _)| *name == file)
+                .map(|(_, text)| text.to_string())
+                .ok_or_else(|| LibraryError::Index { file: file.to_string(), message: "not bundled".to_string() })
+        })?);
+        info!("Loaded {} output templates ({} from {})", entries.len(), runtime, self.dir.display());
+        let count = entries.len();
+        *self.entries.write().unwrap() = Arc::new(entries);
+        Ok(count)
+    }
+
+    /// Finds the template for a command run on a device
+    pub fn find(&self, device_type: &str, hostname: &str, command: &str) -> Option<Arc<Template>> {
+        let command = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        let entries = self.entries.read().unwrap().clone();
+        entries.iter()
+            .find(|entry| entry.platform_regex.is_match(device_type)
+                && entry.hostname_regex.is_match(hostname)
+                && entry.command_regex.is_match(&command))
+            .map(|entry| entry.template.clone())
+    }
+
+    pub fn list(&self) -> Vec<TemplateInfo> {
+        self.entries.read().unwrap().iter()
+            .map(|entry| TemplateInfo {
+                template: entry.file.clone(),
+                source: entry.source,
+                hostname: entry.hostname.clone(),
+                platform: entry.platform.clone(),
+                command: entry.command.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Parses a command's output with the template for it
+///
+/// # Returns
+/// * `Result<Vec<Map<String, Value>>, String>` - The rows, or why the output could not be parsed
+pub fn parse_output(
+    library: &TemplateLibrary,
+    device_type: Option<&str>,
+    hostname: &str,
+    command: &str,
+    output: &str,
+) -> Result<Vec<Map<String, Value>>, String> {
+    let device_type = device_type.ok_or("A device_type is needed to choose a template")?;
+    let template = library.find(device_type, hostname, command)
+        .ok_or_else(|| format!("No template for '{}' on {} devices", command, device_type))?;
+    template.run(output).map_err(|e| e.to_string())
+}
+
+fn read_runtime(dir: &Path) -> Result<Vec<Entry>, LibraryError> {
+    let index_path = dir.join("index");
+    let index = fs::read_to_string(&index_path)
+        .map_err(|source| LibraryError::Io { file: index_path.display().to_string(), source })?;
+    read_index(&index_path.display().to_string(), &index, TemplateSource::Runtime, |file| {
+        let path = dir.join(file);
+        fs::read_to_string(&path).map_err(|source| LibraryError::Io { file: path.display().to_string(), source })
+    })
+}
+
+/// Reads an index, loading each template it names with `read`
+fn read_index(
+    name: &str,
+    index: &str,
+    source: TemplateSource,
+    read: impl Fn(&str) -> Result<String, LibraryError>,
+) -> Result<Vec<Entry>, LibraryError> {
+    let index_error = |message: String| LibraryError::Index { file: name.to_string(), message };
+    let mut lines = index.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+    let header: Vec<&str> = lines.next().ok_or_else(|| index_error("no header row".to_string()))?
+        .split(',').map(str::trim).collect();
+    let column = |title: &str| header.iter().position(|column| *column == title);
+    let (Some(template_column), Some(command_column)) = (column("Template"), column("Command")) else {
+        return Err(index_error("the header needs Template and Command columns".to_string()));
+    };
+    let hostname_column = column("Hostname");
+    let platform_column = column("Platform");
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() != header.len() {
+            return Err(index_error(format!("expected {} columns: {}", header.len(), line)));
+        }
+        let field = |column: Option<usize>| column.map(|column| fields[column]).unwrap_or(".*");
+        let anchored = |pattern: &str| Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| index_error(format!("invalid pattern {}: {}", pattern, e)));
+
+        let file = fields[template_column].to_string();
+        let text = read(&file)?;
+        let template = Template::parse(&text)
+            .map_err(|source| LibraryError::Template { file: file.clone(), source })?;
+        // Commands may be given with trailing arguments, as ntc-templates allows
+        let command_regex = Regex::new(&format!("^{}", expand_abbreviations(fields[command_column])))
+            .map_err(|e| index_error(format!("invalid command {}: {}", fields[command_column], e)))?;
+        entries.push(Entry {
+            hostname_regex: anchored(field(hostname_column))?,
+            platform_regex: anchored(field(platform_column))?,
+            command_regex,
+            hostname: field(hostname_column).to_string(),
+            platform: field(platform_column).to_string(),
+            command: fields[command_column].to_string(),
+            file,
+            source,
+            template: Arc::new(template),
+        });
+    }
+    Ok(entries)
+}
+
+/// Turns `sh[[ow]]` into a pattern matching `sh`, `sho` and `show`
+pub fn expand_abbreviations(command: &str) -> String {
+    let mut pattern = String::new();
+    let mut rest = command;
+    while let Some(start) = rest.find("[[") {
+        let Some(end) = rest[start..].find("]]") else {
+            break;
+        };
+        pattern.push_str(&rest[..start]);
+        let optional = &rest[start + 2..start + end];
+        for c in optional.chars() {
+            pattern.push('(');
+            pattern.push_str(&regex::escape(&c.to_string()));
+        }
+        pattern.push_str(&")?".repeat(optional.chars().count()));
+        rest = &rest[start + end + 2..];
+    }
+    pattern.push_str(rest);
+    pattern
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn parsing_disabled() -> Response {
+    error_response(StatusCode::CONFLICT, "parsing_disabled",
+                   "Output parsing is not enabled on this instance".to_string())
+}
+
+/// Lists the templates in use, in the order they are tried
+pub async fn list_handler(State(state): State<AppState>) -> Response {
+    let Some(templates) = &state.templates else {
+        return parsing_disabled();
+    };
+    Json(json!({ "templates": templates.list() })).into_response()
+}
+
+/// Reads the templates directory again
+pub async fn reload_handler(State(state): State<AppState>) -> Response {
+    let Some(templates) = &state.templates else {
+        return parsing_disabled();
+    };
+    match templates.reload() {
+        Ok(count) => Json(json!({ "success": true, "templates": count })).into_response(),
+        Err(e) => {
+            warn!("Template reload refused: {}", e);
+            error_response(StatusCode::BAD_REQUEST, "invalid_template", e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_templates_are_selected() {
+        let library = TemplateLibrary::load(&ParsingSettings {
+            enabled: true,
+            templates_dir: "no-such-templates-dir".to_string(),
+        }).unwrap();
+        assert!(library.find("cisco", "core-1", "show ip interface brief").is_some());
+        assert!(library.find("router", "core-1", "sh  ip int br").is_some());
+        assert!(library.find("linux", "core-1", "show ip interface brief").is_none());
+        assert!(library.find("cisco", "core-1", "show ip route").is_none());
+
+        let output = "Interface              IP-Address      OK? Method Status                Protocol\n\
+                      GigabitEthernet0/0     10.0.0.1        YES NVRAM  up                    up\n\
+                      GigabitEthernet0/1     unassigned      YES unset  administratively down down\n";
+        let rows = parse_output(&library, Some("cisco"), "core-1", "show ip int brief", output).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["interface"], "GigabitEthernet0/0");
+        assert_eq!(rows[1]["status"], "administratively down");
+        assert!(parse_output(&library, None, "core-1", "show ip int brief", output).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::credentials::CredentialStore;
+use crate::settings::PasswordChangeSettings;
+
+/// Most output kept while waiting for a prompt or for the outcome of an answer
+const TAIL_BYTES: usize = 1024;
+
+/// The password change settings, with their patterns compiled
+pub struct PasswordChangeRules {
+    patterns: Vec<Regex>,
+    scan_bytes: usize,
+    prompt: Regex,
+    success: Vec<Regex>,
+    failure: Vec<Regex>,
+    shell_prompt: Regex,
+    timeout: Duration,
+    write_back: bool,
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| format!("invalid pattern '{}': {}", pattern, e))
+}
+
+fn compile_all(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns.iter().map(|pattern| compile(pattern)).collect()
+}
+
+impl PasswordChangeRules {
+    /// Checks the patterns, so mistakes are reported at startup
+    pub fn new(settings: &PasswordChangeSettings) -> Result<Self, String> {
+        Ok(Self {
+            patterns: compile_all(&settings.patterns)?,
+            scan_bytes: settings.scan_bytes,
+            prompt: compile(&settings.prompt_pattern)?,
+            success: compile_all(&settings.success_patterns)?,
+            failure: compile_all(&settings.failure_patterns)?,
+            shell_prompt: compile(&settings.shell_prompt_pattern)?,
+            timeout: Duration::from_secs(settings.timeout_seconds.max(1)),
+            write_back: settings.write_back,
+        })
+    }
+
+    /// Starts watching a new shell's output for a demand to change the password
+    ///
+    /// # Arguments
+    /// * `write_back` - Where the session's credentials came from, if the new
+    ///   password may be stored there
+    pub fn watch(
+        self: &Arc<Self>,
+        notifications: broadcast::Sender<Value>,
+        write_back: Option<WriteBack>,
+    ) -> Arc<PasswordChange> {
+        Arc::new(PasswordChange {
+            rules: self.clone(),
+            notifications,
+            write_back: write_back.filter(|_| self.write_back),
+            stage: Mutex::new(Stage::Watching { seen: String::new() }),
+        })
+    }
+}
+
+/// Where a new password is stored once the device has taken it
+pub struct WriteBack {
+    pub store: Arc<CredentialStore>,
+    pub reference: String,
+}
+
+/// What a password prompt asks for, going by its text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretPurpose {
+    Current,
+    New,
+    /// The new password once more
+    Confirm,
+    Other,
+}
+
+impl SecretPurpose {
+    fn of(prompt: &str) -> Self {
+        let prompt = prompt.to_lowercase();
+        if ["again", "retype", "re-enter", "reenter", "confirm", "verify", "repeat"].iter().any(|word| prompt.contains(word)) {
+            SecretPurpose::Confirm
+        } else if prompt.contains("new") {
+            SecretPurpose::New
+        } else if prompt.contains("old") || prompt.contains("current") {
+            SecretPurpose::Current
+        } else {
+            SecretPurpose::Other
+        }
+    }
+}
+
+enum Stage {
+    /// Looking through the start of the output; what was seen so far
+    Watching { seen: String },
+    Assisting(Flow),
+    Done,
+}
+
+/// A password change under way
+struct Flow {
+    /// When the device or the user last moved the change along
+    updated: Instant,
+    /// Output since the last answer, up to `TAIL_BYTES`
+    tail: String,
+    /// The prompt waiting for an answer, and what it asks for
+    prompt: Option<(String, SecretPurpose)>,
+    /// The user has been shown a prompt
+    prompted: bool,
+    /// A prompt has been answered since the device last refused the new password
+    answered: bool,
+    new_password: Option<String>,
+    /// The new password was entered a second time, alike
+    confirmed: bool,
+}
+
+/// How a password change ended
+enum Outcome {
+    Completed(Option<String>),
+    Failed,
+    Abandoned,
+    /// The device's prompt came back without asking for a password
+    NotAsked,
+}
+
+/// Guides the user of one shell through a forced password change
+///
+/// The shell's output is scanned for a demand for a new password. From
+/// then on, password prompts are sent to the client as `secret_prompt`
+/// events, to be answered with `secret_input` frames that bypass the
+/// recording, the command audit and the frame capture.
+pub struct PasswordChange {
+    rules: Arc<PasswordChangeRules>,
+    notifications: broadcast::Sender<Value>,
+    write_back: Option<WriteBack>,
+    stage: Mutex<Stage>,
+}
+
+impl PasswordChange {
+    /// Looks at more of the shell's output
+    pub fn scan(&self, data: &[u8]) {
+        let mut stage = self.stage.lock().unwrap_or_else(PoisonError::into_inner);
+        match &mut *stage {
+            Stage::Watching { seen } => {
+                let room = self.rules.scan_bytes.saturating_sub(seen.len());
+                seen.push_str(&String::from_utf8_lossy(&data[..data.len().min(room)]));
+                if !self.rules.patterns.iter().any(|pattern| pattern.is_match(seen)) {
+                    if seen.len() >= self.rules.scan_bytes {
+                        *stage = Stage::Done;
+                    }
+                    return;
+                }
+                info!("Device demands a password change; assisting the user");
+                let mut tail = std::mem::take(seen);
+                keep_tail(&mut tail);
+                *stage = Stage::Assisting(Flow {
+                    updated: Instant::now(),
+                    tail,
+                    prompt: None,
+                    prompted: false,
+                    answered: false,
+                    new_password: None,
+                    confirmed: false,
+                });
+            }
+            Stage::Assisting(flow) => {
+                flow.tail.push_str(&String::from_utf8_lossy(data));
+                keep_tail(&mut flow.tail);
+            }
+            Stage::Done => return,
+        }
+        let Stage::Assisting(flow) = &mut *stage else {
+            return;
+        };
+        if let Some(outcome) = self.advance(flow) {
+            let prompted = flow.prompted;
+            *stage = Stage::Done;
+            drop(stage);
+            self.finish(outcome, prompted);
+        }
+    }
+
+    /// Moves the change along after more output; returns its outcome once it has ended
+    fn advance(&self, flow: &mut Flow) -> Option<Outcome> {
+        if flow.updated.elapsed() > self.rules.timeout {
+            return Some(Outcome::Abandoned);
+        }
+        if flow.answered {
+            if let Some(found) = self.rules.failure.iter().find_map(|pattern| pattern.find(&flow.tail)) {
+                let message = line_around(&flow.tail, found.start(), found.end());
+                info!("Device refused the new password: {}", message);
+                self.notify("failed", Some(message));
+                flow.tail.drain(..found.end());
+                flow.answered = false;
+                flow.new_password = None;
+                flow.confirmed = false;
+            }
+        }
+        if self.rules.success.iter().any(|pattern| pattern.is_match(&flow.tail)) {
+            return Some(Outcome::Completed(flow.new_password.take()));
+        }
+        if flow.prompt.is_some() {
+            return None;
+        }
+        if self.rules.prompt.is_match(&flow.tail) {
+            let prompt = flow.tail.rsplit(['\r', '\n']).next().unwrap_or_default().trim().to_string();
+            let purpose = SecretPurpose::of(&prompt);
+            if !flow.prompted {
+                self.notify("started", Some("The device requires a new password".to_string()));
+                flow.prompted = true;
+            }
+            debug!("Relaying password prompt \"{}\" ({:?})", prompt, purpose);
+            let _ = self.notifications.send(prompt_event(&prompt, purpose));
+            flow.prompt = Some((prompt, purpose));
+            flow.updated = Instant::now();
+            return None;
+        }
+        if self.rules.shell_prompt.is_match(&flow.tail) {
+            return Some(match (flow.confirmed, flow.prompted) {
+                (true, _) => Outcome::Completed(flow.new_password.take()),
+                (false, true) => Outcome::Failed,
+                (false, false) => Outcome::NotAsked,
+            });
+        }
+        None
+    }
+
+    /// Sends the answer to the waiting prompt to the shell
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>, &'static str>` - The keystrokes to send, or why there is nothing to answer
+    pub fn answer(&self, secret: &str) -> Result<Vec<u8>, &'static str> {
+        if secret.contains(['\r', '\n']) {
+            return Err("A secret cannot contain line breaks");
+        }
+        let mut stage = self.stage.lock().unwrap_or_else(PoisonError::into_inner);
+        let Stage::Assisting(flow) = &mut *stage else {
+            return Err("No password prompt is waiting for an answer");
+        };
+        if flow.updated.elapsed() > self.rules.timeout {
+            *stage = Stage::Done;
+            drop(stage);
+            self.finish(Outcome::Abandoned, true);
+            return Err("The password change took too long and was abandoned");
+        }
+        let Some((_, purpose)) = flow.prompt.take() else {
+            return Err("No password prompt is waiting for an answer");
+        };
+        match purpose {
+            SecretPurpose::New => {
+                flow.new_password = Some(secret.to_string());
+                flow.confirmed = false;
+            }
+            SecretPurpose::Confirm => flow.confirmed = flow.new_password.as_deref() == Some(secret),
+            SecretPurpose::Current | SecretPurpose::Other => {}
+        }
+        flow.answered = true;
+        flow.tail.clear();
+        flow.updated = Instant::now();
+        let mut keystrokes = secret.as_bytes().to_vec();
+        keystrokes.push(b'\r');
+        Ok(keystrokes)
+    }
+
+    /// The `secret_prompt` event of the prompt waiting for an answer, for a client that has just attached
+    pub fn pending_prompt(&self) -> Option<Value> {
+        match &*self.stage.lock().unwrap_or_else(PoisonError::into_inner) {
+            Stage::Assisting(Flow { prompt: Some((prompt, purpose)), .. }) => Some(prompt_event(prompt, *purpose)),
+            _ => None,
+        }
+    }
+
+    /// Notes input typed in the terminal
+    ///
+    /// Only a demand made before the user has typed anything is acted on, so
+    /// output the user brings about cannot stand in for the device's prompts
+    /// and pass commands off as secrets.
+    pub fn typed(&self) {
+        let mut stage = self.stage.lock().unwrap_or_else(PoisonError::into_inner);
+        if matches!(*stage, Stage::Watching { .. }) {
+            *stage = Stage::Done;
+        }
+    }
+
+    fn finish(&self, outcome: Outcome, prompted: bool) {
+        match outcome {
+            Outcome::Completed(new_password) => {
+                info!("Password change completed");
+                let store = self.write_back.as_ref().zip(new_password);
+                let _ = self.notifications.send(json!({
+                    "type": "password_change",
+                    "status": "completed",
+                    "storing": store.is_some(),
+                }));
+                if let Some((write_back, new_password)) = store {
+                    let (store, reference) = (write_back.store.clone(), write_back.reference.clone());
+                    let notifications = self.notifications.clone();
+                    tokio::spawn(async move {
+                        let event = match store.update_password(&reference, &new_password).await {
+                            Ok(()) => json!({ "type": "password_change", "status": "stored" }),
+                            Err(e) => {
+                                warn!("Cannot store the new password: {}", e);
+                                json!({ "type": "password_change", "status": "store_failed", "message": e.to_string() })
+                            }
+                        };
+                        let _ = notifications.send(event);
+                    });
+                }
+            }
+            Outcome::Failed => {
+                info!("Password change ended without the device taking a new password");
+                self.notify("failed", None);
+            }
+            Outcome::Abandoned if prompted => {
+                info!("Password change abandoned");
+                self.notify("abandoned", None);
+            }
+            Outcome::Abandoned | Outcome::NotAsked => debug!("Device did not ask for a new password"),
+        }
+    }
+
+    fn notify(&self, status: &str, message: Option<String>) {
+        let _ = self.notifications.send(json!({
+            "type": "password_change",
+            "status": status,
+            "message": message,
+        }));
+    }
+}
+
+fn prompt_event(prompt: &str, purpose: SecretPurpose) -> Value {
+    json!({ "type": "secret_prompt", "prompt": prompt, "purpose": purpose })
+}
+
+/// Drops the oldest output beyond `TAIL_BYTES`
+fn keep_tail(tail: &mut String) {
+    if tail.len() > TAIL_BYTES {
+        let mut start = tail.len() - TAIL_BYTES;
+        while !tail.is_char_boundary(start) {
+            start += 1;
+        }
+        tail.drain(..start);
+    }
+}
+
+/// The line of output holding a match
+fn line_around(text: &str, start: usize, end: usize) -> String {
+    let from = text[..start].rfind(['\r', '\n']).map(|i| i + 1).unwrap_or(0);
+    let to = text[end..].find(['\r', '\n']).map(|i| end + i).unwrap_or(text.len());
+    text[from..to].trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forced_change_is_relayed() {
+        let rules = Arc::new(PasswordChangeRules::new(&PasswordChangeSettings::default()).unwrap());
+        let (notifications, mut rx) = broadcast::channel(16);
+        let change = rules.watch(notifications.clone(), None);
+
+        change.scan(b"Last login: Mon Jan  1 12:00:00 2024\r\n$ ");
+        change.typed();
+        change.scan(b"echo 'Your password has expired'; printf 'New password: '\r\nYour password has expired\r\nNew password: ");
+        assert!(change.pending_prompt().is_none());
+        assert_eq!(change.answer("rm -rf /"), Err("No password prompt is waiting for an answer"));
+
+        let change = rules.watch(notifications, None);
+        change.scan(b"WARNING: Your password has expired.\r\nYou must change your password now and login again!\r\n");
+        change.scan(b"Changing password for netops.\r\nCurrent password: ");
+        assert_eq!(rx.try_recv().unwrap()["status"], "started");
+        let prompt = rx.try_recv().unwrap();
+        assert_eq!(prompt["prompt"], "Current password:");
+        assert_eq!(prompt["purpose"], "current");
+        assert_eq!(change.pending_prompt(), Some(prompt));
+        assert_eq!(change.answer("old\r"), Err("A secret cannot contain line breaks"));
+        assert_eq!(change.answer("old").unwrap(), b"old\r");
+
+        change.scan(b"\r\nNew password: ");
+        assert_eq!(rx.try_recv().unwrap()["purpose"], "new");
+        change.answer("n3w-Secret").unwrap();
+        change.scan(b"\r\nRetype new password: ");
+        assert_eq!(rx.try_recv().unwrap()["purpose"], "confirm");
+        change.answer("n3w-Secrte").unwrap();
+        change.scan(b"\r\nSorry, passwords do not match.\r\nNew password: ");
+        assert_eq!(rx.try_recv().unwrap()["message"], "Sorry, passwords do not match.");
+        assert_eq!(rx.try_recv().unwrap()["purpose"], "new");
+        change.answer("n3w-Secret").unwrap();
+        change.scan(b"\r\nRetype new password: ");
+        rx.try_recv().unwrap();
+        change.answer("n3w-Secret").unwrap();
+        change.scan(b"\r\npasswd: all authentication tokens updated successfully.\r\n");
+
+        let completed = rx.try_recv().unwrap();
+        assert_eq!(completed["status"], "completed");
+        assert_eq!(completed["storing"], false);
+        assert!(change.pending_prompt().is_none());
+        assert_eq!(change.answer("n3w-Secret"), Err("No password prompt is waiting for an answer"));
+    }
+}
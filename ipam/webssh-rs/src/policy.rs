@@ -0,0 +1,513 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::affinity::NodeIdentity;
+use crate::reload::{self, ReloadReport};
+use crate::settings::{CommandPolicySettings, CredentialPolicySettings, DeviceProfile, ForwardingSettings, Settings};
+use crate::AppState;
+
+/// Bundle layout written by this build; bundles in other layouts are refused
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The policy sections of the settings, as carried between instances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicySet {
+    pub profiles: BTreeMap<String, DeviceProfile>,
+    pub credential_policy: CredentialPolicySettings,
+    pub forwarding: ForwardingSettings,
+}
+
+impl PolicySet {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            profiles: settings.profiles.iter()
+                .map(|(device_type, profile)| (device_type.clone(), profile.clone()))
+                .collect(),
+            credential_policy: settings.credential_policy.clone(),
+            forwarding: settings.forwarding.clone(),
+        }
+    }
+
+    /// Replaces the policy sections of `settings` with this set
+    fn apply(&self, settings: &mut Settings) {
+        settings.profiles = self.profiles.clone().into_iter().collect();
+        settings.credential_policy = self.credential_policy.clone();
+        settings.forwarding = self.forwarding.clone();
+    }
+
+    /// Checks the set for values the gateway could not enforce
+    ///
+    /// # Returns
+    /// * `Vec<String>` - A description of every problem found; empty if the set is valid
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for device_type in self.profiles.keys() {
+            if device_type.trim().is_empty() || *device_type != device_type.to_lowercase() {
+                problems.push(format!("Profile name '{}' must be non-empty and lowercase", device_type));
+            }
+        }
+        if self.credential_policy.min_rsa_bits < 1024 {
+            problems.push("credential_policy.min_rsa_bits must be at least 1024".to_string());
+        }
+        if self.credential_policy.default_credentials.iter().any(|default| default.username.is_empty()) {
+            problems.push("credential_policy.default_credentials entries need a username".to_string());
+        }
+        if self.forwarding.bind_address.parse::<IpAddr>().is_err() {
+            problems.push(format!("forwarding.bind_address '{}' is not an IP address", self.forwarding.bind_address));
+        }
+        if self.forwarding.enabled && self.forwarding.max_per_session == 0 {
+            problems.push("forwarding.max_per_session must be at least 1 when forwarding is enabled".to_string());
+        }
+        problems
+    }
+}
+
+/// A signed, versioned snapshot of the policy configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyBundle {
+    pub format: u32,
+    /// Increases with every bundle; an instance only imports versions newer than its own
+    pub version: u64,
+    pub created_at: DateTime<Utc>,
+    pub source_node: String,
+    pub policy: PolicySet,
+    /// Hex HMAC-SHA256 of the bundle without this field, in canonical JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// The policy currently in force, as reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyStatus {
+    /// Version of the imported bundle; 0 while the policy comes from settings.json
+    pub version: u64,
+    pub source_node: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Error)]
+pub enum PolicyError {
+    #[error("Policy bundles require policy_bundles.signing_key to be configured")]
+    SigningNotConfigured,
+    #[error("Bundle signature is missing or does not match")]
+    InvalidSignature,
+    #[error("Bundle format {0} is not supported (expected {FORMAT_VERSION})")]
+    UnsupportedFormat(u32),
+    #[error("Bundle version {offered} is not newer than the active version {active}")]
+    StaleVersion { active: u64, offered: u64 },
+    #[error("Policy is invalid: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+    #[error("Malformed bundle: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Failed to write the policy file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl PolicyError {
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            PolicyError::SigningNotConfigured => "policy_signing_not_configured",
+            PolicyError::InvalidSignature => "invalid_signature",
+            PolicyError::UnsupportedFormat(_) => "unsupported_format",
+            PolicyError::StaleVersion { .. } => "stale_version",
+            PolicyError::Invalid(_) => "invalid_policy",
+            PolicyError::Malformed(_) => "invalid_bundle",
+            PolicyError::Io(_) => "policy_write_failed",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            PolicyError::SigningNotConfigured | PolicyError::StaleVersion { .. } => StatusCode::CONFLICT,
+            PolicyError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+struct Active {
+    /// settings.json as loaded at startup, with reloaded changes applied
+    base: Settings,
+    settings: Arc<Settings>,
+    status: PolicyStatus,
+}
+
+/// Holds the settings in force, with the last imported policy bundle applied
+///
+/// settings.json provides everything; an imported bundle overrides its policy
+/// sections and is kept in `policy_bundles.file` so it survives restarts.
+/// Settings reloaded from settings.json replace those of both.
+pub struct PolicyStore {
+    signing_key: Option<Vec<u8>>,
+    path: PathBuf,
+    active: RwLock<Active>,
+}
+
+impl PolicyStore {
+    /// Applies the bundle saved by the last import, if there is one
+    pub fn load(base: Settings) -> Self {
+        let path = PathBuf::from(&base.policy_bundles.file);
+        let signing_key = base.policy_bundles.signing_key.as_ref()
+            .filter(|key| !key.is_empty())
+            .map(|key| key.as_bytes().to_vec());
+        let store = Self {
+            active: RwLock::new(Active {
+                settings: Arc::new(base.clone()),
+                base,
+                status: PolicyStatus { version: 0, source_node: None, created_at: None },
+            }),
+            signing_key,
+            path,
+        };
+
+        if store.path.exists() {
+            match store.read_saved() {
+                Ok(bundle) => {
+                    info!("Applied policy bundle version {} from {}", bundle.version, store.path.display());
+                    store.activate(&bundle);
+                }
+                Err(e) => error!("Ignoring policy file {}: {}", store.path.display(), e),
+            }
+        }
+        store
+    }
+
+    /// The settings in force; callers should fetch them once per request
+    pub fn settings(&self) -> Arc<Settings> {
+        self.active.read().unwrap().settings.clone()
+    }
+
+    pub fn status(&self) -> PolicyStatus {
+        self.active.read().unwrap().status.clone()
+    }
+
+    /// Applies the settings of a reloaded settings.json that can change while running
+    ///
+    /// The settings in force are replaced in one step, so a request never sees
+    /// some of the reloaded values and not others.
+    pub fn reload(&self, loaded: &Settings) -> Result<ReloadReport, String> {
+        let mut active = self.active.write().unwrap();
+        let report = ReloadReport::compare(&active.base, loaded)?;
+        if !report.applied.is_empty() {
+            let base = reload::apply(&active.base, loaded, &report)?;
+            let settings = reload::apply(&active.settings, loaded, &report)?;
+            active.base = base;
+            active.settings = Arc::new(settings);
+        }
+        Ok(report)
+    }
+
+    /// Replaces the command policy in force
+    ///
+    /// The change lasts until a restart, a policy import, or a change to
+    /// `command_policy` in settings.json.
+    pub fn set_command_policy(&self, command_policy: CommandPolicySettings) {
+        let mut active = self.active.write().unwrap();
+        let mut settings = (*active.settings).clone();
+        settings.command_policy = command_policy;
+        active.settings = Arc::new(settings);
+    }
+
+    /// Builds a signed bundle of the policy in force
+    ///
+    /// # Arguments
+    /// * `version` - Version to stamp; defaults to one more than the active version
+    pub fn export(&self, source_node: &str, version: Option<u64>) -> Result<Value, PolicyError> {
+        let key = self.signing_key.as_deref().ok_or(PolicyError::SigningNotConfigured)?;
+        let active = self.active.read().unwrap();
+        let bundle = PolicyBundle {
+            format: FORMAT_VERSION,
+            version: version.unwrap_or(active.status.version + 1),
+            created_at: Utc::now(),
+            source_node: source_node.to_string(),
+            policy: PolicySet::from_settings(&active.settings),
+            signature: None,
+        };
+        let mut value = serde_json::to_value(&bundle)?;
+        value["signature"] = Value::String(sign(key, &value));
+        Ok(value)
+    }
+
+    /// Verifies a bundle and makes it the policy in force
+    ///
+    /// The bundle is saved before it is applied, and concurrent imports are
+    /// serialized, so an instance never runs a policy it would not restart with.
+    ///
+    /// # Arguments
+    /// * `force` - Accept a version that is not newer than the active one
+    /// * `dry_run` - Only verify the bundle
+    pub fn import(&self, value: Value, force: bool, dry_run: bool) -> Result<PolicyStatus, PolicyError> {
+        let bundle = self.verify(&value)?;
+        let mut active = self.active.write().unwrap();
+        if !force && bundle.version <= active.status.version {
+            return Err(PolicyError::StaleVersion { active: active.status.version, offered: bundle.version });
+        }
+        let (settings, status) = resolve(&active.base, &bundle);
+        if dry_run {
+            return Ok(status);
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated bundle
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&value)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        active.settings = Arc::new(settings);
+        active.status = status.clone();
+        Ok(status)
+    }
+
+    /// Checks a bundle's signature, format and contents
+    fn verify(&self, value: &Value) -> Result<PolicyBundle, PolicyError> {
+        let key = self.signing_key.as_deref().ok_or(PolicyError::SigningNotConfigured)?;
+        if !verify_signature(key, value) {
+            return Err(PolicyError::InvalidSignature);
+        }
+        let format = value.get("format").and_then(Value::as_u64).unwrap_or(0);
+        if format != u64::from(FORMAT_VERSION) {
+            return Err(PolicyError::UnsupportedFormat(format as u32));
+        }
+        let bundle: PolicyBundle = serde_json::from_value(value.clone())?;
+        let problems = bundle.policy.validate();
+        if !problems.is_empty() {
+            return Err(PolicyError::Invalid(problems));
+        }
+        Ok(bundle)
+    }
+
+    fn read_saved(&self) -> Result<PolicyBundle, PolicyError> {
+        let value: Value = serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+        if self.signing_key.is_none() {
+            // The file was written by this instance; without a key it cannot be checked
+            warn!("No policy signing key configured; applying {} unverified", self.path.display());
+            let bundle: PolicyBundle = serde_json::from_value(value)?;
+            let problems = bundle.policy.validate();
+            return if problems.is_empty() { Ok(bundle) } else { Err(PolicyError::Invalid(problems)) };
+        }
+        self.verify(&value)
+    }
+
+    fn activate(&self, bundle: &PolicyBundle) {
+        let mut active = self.active.write().unwrap();
+        let (settings, status) = resolve(&active.base, bundle);
+        active.settings = Arc::new(settings);
+        active.status = status;
+    }
+}
+
+/// The base settings with a bundle's policy applied
+fn resolve(base: &Settings, bundle: &PolicyBundle) -> (Settings, PolicyStatus) {
+    let mut settings = base.clone();
+    bundle.policy.apply(&mut settings);
+    let status = PolicyStatus {
+        version: bundle.version,
+        source_node: Some(bundle.source_node.clone()),
+        created_at: Some(bundle.created_at),
+    };
+    (settings, status)
+}
+
+/// JSON of the bundle without its signature; object keys are sorted, so
+/// the bytes do not depend on field order
+fn signed_bytes(value: &Value) -> Vec<u8> {
+    let mut unsigned = value.clone();
+    if let Some(object) = unsigned.as_object_mut() {
+        object.remove("signature");
+    }
+    serde_json::to_vec(&unsigned).unwrap_or_default()
+}
+
+fn mac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+fn sign(key: &[u8], value: &Value) -> String {
+    let mut mac = mac(key);
+    mac.update(&signed_bytes(value));
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn verify_signature(key: &[u8], value: &Value) -> bool {
+    let Some(signature) = value.get("signature").and_then(Value::as_str).and_then(decode_hex) else {
+        return false;
+    };
+    let mut mac = mac(key);
+    mac.update(&signed_bytes(value));
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn policy_error_response(e: PolicyError) -> Response {
+    error_response(e.status(), e.error_code(), e.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Reports the version of the policy in force
+pub async fn status_handler(State(state): State<AppState>) -> Response {
+    Json(state.policy.status()).into_response()
+}
+
+/// Exports the policy in force as a signed bundle
+pub async fn export_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    match state.policy.export(&state.node.id, params.version) {
+        Ok(bundle) => Json(bundle).into_response(),
+        Err(e) => policy_error_response(e),
+    }
+}
+
+/// Verifies a signed bundle and applies it in one step
+pub async fn import_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ImportParams>,
+    Json(bundle): Json<Value>,
+) -> Response {
+    match state.policy.import(bundle, params.force, params.dry_run) {
+        Ok(status) if params.dry_run => {
+            Json(json!({ "success": true, "dry_run": true, "policy": status })).into_response()
+        }
+        Ok(status) => {
+            info!("Imported policy bundle version {} from {}", status.version,
+                  status.source_node.as_deref().unwrap_or("unknown node"));
+            Json(json!({ "success": true, "dry_run": false, "policy": status })).into_response()
+        }
+        Err(e) => {
+            warn!("Policy bundle import refused: {}", e);
+            policy_error_response(e)
+        }
+    }
+}
+
+const CLI_USAGE: &str = "usage: webssh-rs policy export [--version N] [--out FILE]
+       webssh-rs policy import FILE [--force] [--dry-run]";
+
+/// Runs `webssh-rs policy ...` against the local settings and policy file
+///
+/// An import here is saved for the next start; a running instance only
+/// picks up bundles imported through the API.
+pub fn run_cli(settings: Settings, args: &[String]) -> Result<(), String> {
+    let store = PolicyStore::load(settings);
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let option = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1));
+
+    match args.first().map(String::as_str) {
+        Some("export") => {
+            let version = option("--version")
+                .map(|version| version.parse::<u64>().map_err(|_| format!("Invalid version '{}'", version)))
+                .transpose()?;
+            let node = NodeIdentity::from_settings(&store.settings().server)?;
+            let bundle = store.export(&node.id, version).map_err(|e| e.to_string())?;
+            let contents = serde_json::to_string_pretty(&bundle).map_err(|e| e.to_string())?;
+            match option("--out") {
+                Some(path) => fs::write(path, contents).map_err(|e| format!("Cannot write {}: {}", path, e)),
+                None => {
+                    println!("{}", contents);
+                    Ok(())
+                }
+            }
+        }
+        Some("import") => {
+            let path = args.get(1).filter(|path| !path.starts_with("--")).ok_or(CLI_USAGE)?;
+            let contents = fs::read_to_string(Path::new(path)).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            let bundle: Value = serde_json::from_str(&contents).map_err(|e| format!("Malformed bundle: {}", e))?;
+            let dry_run = flag("--dry-run");
+            let status = store.import(bundle, flag("--force"), dry_run).map_err(|e| e.to_string())?;
+            if dry_run {
+                println!("Bundle version {} is valid", status.version);
+            } else {
+                println!("Saved policy bundle version {} to {}; it applies from the next start",
+                         status.version, store.path.display());
+            }
+            Ok(())
+        }
+        _ => Err(CLI_USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(key: &str) -> PolicyStore {
+        let mut settings = Settings::default();
+        settings.policy_bundles.signing_key = Some(key.to_string());
+        settings.policy_bundles.file = std::env::temp_dir()
+            .join(format!("webssh-policy-{}.json", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        PolicyStore::load(settings)
+    }
+
+    #[test]
+    fn test_import_checks_signature_and_version() {
+        let source = store("shared-secret");
+        let target = store("shared-secret");
+        let mut bundle = source.export("staging", None).unwrap();
+        assert_eq!(bundle["version"], 1);
+
+        assert!(matches!(store("other-secret").import(bundle.clone(), false, false), Err(PolicyError::InvalidSignature)));
+        let mut tampered = bundle.clone();
+        tampered["policy"]["forwarding"]["enabled"] = Value::Bool(true);
+        assert!(matches!(target.import(tampered, false, false), Err(PolicyError::InvalidSignature)));
+
+        assert_eq!(target.import(bundle.clone(), false, true).unwrap().version, 1);
+        assert_eq!(target.status().version, 0);
+        assert_eq!(target.import(bundle.clone(), false, false).unwrap().version, 1);
+        assert!(matches!(target.import(bundle.clone(), false, false), Err(PolicyError::StaleVersion { active: 1, offered: 1 })));
+
+        // The saved bundle is applied again on the next start
+        let restarted = PolicyStore::load(target.active.read().unwrap().base.clone());
+        assert_eq!(restarted.status().source_node.as_deref(), Some("staging"));
+
+        bundle["policy"]["credential_policy"]["min_rsa_bits"] = json!(512);
+        bundle["signature"] = Value::String(sign(b"shared-secret", &bundle));
+        assert!(matches!(target.import(bundle, true, false), Err(PolicyError::Invalid(_))));
+        let _ = fs::remove_file(&target.path);
+    }
+}
@@ -0,0 +1,226 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::settings::PresenceSettings;
+
+/// What a participant does in a shared session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceRole {
+    /// Attached to the session's shell, sending its input
+    Writer,
+    /// Watching the output read-only
+    Viewer,
+}
+
+/// A WebSocket taking part in a session, as reported to the others
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Participant {
+    pub id: u64,
+    pub user: String,
+    pub role: PresenceRole,
+    pub joined_at: DateTime<Utc>,
+    pub idle: bool,
+    pub typing: bool,
+}
+
+#[derive(Default)]
+struct Board {
+    participants: Vec<Participant>,
+    joined: u64,
+}
+
+/// Who is taking part in a session, kept by the WebSockets attached to it
+///
+/// Changes are announced on the session's notification channel as `presence`
+/// and `typing` messages, which every WebSocket forwards to its client.
+#[derive(Clone, Default)]
+pub struct PresenceBoard {
+    board: Arc<Mutex<Board>>,
+}
+
+impl PresenceBoard {
+    /// Adds a participant, announcing it to the others
+    ///
+    /// The participant leaves when the returned handle is dropped.
+    pub fn join(
+        &self,
+        user: String,
+        role: PresenceRole,
+        settings: &PresenceSettings,
+        notifications: broadcast::Sender<serde_json::Value>,
+    ) -> Presence {
+        let participant = {
+            let mut board = self.board.lock().unwrap();
+            board.joined += 1;
+            let participant = Participant {
+                id: board.joined,
+                user,
+                role,
+                joined_at: Utc::now(),
+                idle: false,
+                typing: false,
+            };
+            board.participants.push(participant.clone());
+            participant
+        };
+        let presence = Presence {
+            board: self.clone(),
+            id: participant.id,
+            notifications,
+            idle_after: Duration::from_secs(settings.idle_seconds.max(1)),
+            typing_for: settings.typing_indicator.then(|| Duration::from_millis(settings.typing_timeout_ms.max(1))),
+            activity: Mutex::new(Activity { last_active: Instant::now(), last_typed: None }),
+        };
+        presence.announce("joined", &participant);
+        presence
+    }
+
+    /// The participants, in the order they joined
+    pub fn participants(&self) -> Vec<Participant> {
+        self.board.lock().unwrap().participants.clone()
+    }
+
+    /// Updates a participant, returning it if anything changed
+    fn update(&self, id: u64, change: impl FnOnce(&mut Participant)) -> Option<Participant> {
+        let mut board = self.board.lock().unwrap();
+        let participant = board.participants.iter_mut().find(|participant| participant.id == id)?;
+        let before = (participant.idle, participant.typing);
+        change(participant);
+        (before != (participant.idle, participant.typing)).then(|| participant.clone())
+    }
+}
+
+struct Activity {
+    last_active: Instant,
+    // When the participant last typed, while shown as typing
+    last_typed: Option<Instant>,
+}
+
+/// A WebSocket's place on its session's presence board
+pub struct Presence {
+    board: PresenceBoard,
+    id: u64,
+    notifications: broadcast::Sender<serde_json::Value>,
+    idle_after: Duration,
+    // How long a writer is shown typing after a keystroke; None without the typing indicator
+    typing_for: Option<Duration>,
+    activity: Mutex<Activity>,
+}
+
+impl Presence {
+    /// Notes a message from the client, bringing an idle participant back
+    pub fn active(&self) {
+        self.activity.lock().unwrap().last_active = Instant::now();
+        if let Some(participant) = self.board.update(self.id, |participant| participant.idle = false) {
+            self.announce("active", &participant);
+        }
+    }
+
+    /// Notes input from the writer, showing it as typing
+    pub fn typed(&self) {
+        if self.typing_for.is_none() {
+            return;
+        }
+        self.activity.lock().unwrap().last_typed = Some(Instant::now());
+        if let Some(participant) = self.board.update(self.id, |participant| participant.typing = true) {
+            self.typing(&participant);
+        }
+    }
+
+    /// Marks the participant idle, or no longer typing, once enough time has passed
+    pub fn tick(&self) {
+        let (idle, stopped_typing) = {
+            let mut activity = self.activity.lock().unwrap();
+            let idle = activity.last_active.elapsed() >= self.idle_after;
+            let stopped_typing = match (activity.last_typed, self.typing_for) {
+                (Some(last_typed), Some(typing_for)) => last_typed.elapsed() >= typing_for,
+                _ => false,
+            };
+            if stopped_typing {
+                activity.last_typed = None;
+            }
+            (idle, stopped_typing)
+        };
+        if stopped_typing {
+            if let Some(participant) = self.board.update(self.id, |participant| participant.typing = false) {
+                self.typing(&participant);
+            }
+        }
+        if idle {
+            if let Some(participant) = self.board.update(self.id, |participant| participant.idle = true) {
+                self.announce("idle", &participant);
+            }
+        }
+    }
+
+    fn announce(&self, event: &str, participant: &Participant) {
+        debug!("Participant {} ({}) {}", participant.id, participant.user, event);
+        let _ = self.notifications.send(json!({
+            "type": "presence",
+            "event": event,
+            "participant": participant,
+            "participants": self.board.participants(),
+        }));
+    }
+
+    fn typing(&self, participant: &Participant) {
+        let _ = self.notifications.send(json!({
+            "type": "typing",
+            "participant": participant.id,
+            "user": participant.user,
+            "active": participant.typing,
+        }));
+    }
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        let participant = {
+            let mut board = self.board.board.lock().unwrap();
+            let Some(index) = board.participants.iter().position(|participant| participant.id == self.id) else {
+                return;
+            };
+            board.participants.remove(index)
+        };
+        self.announce("left", &participant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence_is_announced() {
+        let settings = PresenceSettings { enabled: true, idle_seconds: 1, typing_indicator: true, typing_timeout_ms: 1 };
+        let (notifications, mut rx) = broadcast::channel(16);
+        let board = PresenceBoard::default();
+
+        let writer = board.join("alice".to_string(), PresenceRole::Writer, &settings, notifications.clone());
+        let viewer = board.join("bob".to_string(), PresenceRole::Viewer, &settings, notifications);
+        assert_eq!(rx.try_recv().unwrap()["event"], "joined");
+        let joined = rx.try_recv().unwrap();
+        assert_eq!(joined["participant"]["user"], "bob");
+        assert_eq!(joined["participants"].as_array().unwrap().len(), 2);
+
+        writer.typed();
+        writer.typed();
+        let typing = rx.try_recv().unwrap();
+        assert_eq!((typing["type"].as_str(), typing["active"].as_bool()), (Some("typing"), Some(true)));
+        assert!(rx.try_recv().is_err());
+        std::thread::sleep(Duration::from_millis(5));
+        writer.tick();
+        assert_eq!(rx.try_recv().unwrap()["active"], false);
+
+        drop(viewer);
+        let left = rx.try_recv().unwrap();
+        assert_eq!((left["event"].as_str(), left["participant"]["role"].as_str()), (Some("left"), Some("viewer")));
+        assert_eq!(board.participants().len(), 1);
+    }
+}
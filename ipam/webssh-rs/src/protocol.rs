@@ -1,10 +1,103 @@
+use axum::extract::ws::{Message, WebSocket};
 use serde::{Deserialize, Serialize};
 use bytes::Bytes;
 use std::io::{Read, Write};
 use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use tracing::error;
+
+use crate::settings::BinaryProtocolSettings;
+
+/// WebSocket subprotocol a client offers to exchange `BinaryMessage` frames
+pub const SUBPROTOCOL: &str = "binary-v1";
+/// The same frames, compressed with zstd instead of gzip
+pub const SUBPROTOCOL_ZSTD: &str = "binary-v1-zstd";
+
+// First byte of a frame: how the message after it is compressed
+const UNCOMPRESSED: u8 = 0;
+const GZIP: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// The subprotocols offered at upgrade, most preferred first
+pub fn subprotocols(settings: &BinaryProtocolSettings) -> &'static [&'static str] {
+    if settings.zstd {
+        &[SUBPROTOCOL_ZSTD, SUBPROTOCOL]
+    } else {
+        &[SUBPROTOCOL]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+/// How a WebSocket's binary frames are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCompression {
+    pub codec: Codec,
+    /// Messages larger than this are compressed, when that makes them smaller
+    pub threshold: usize,
+    pub level: i32,
+}
+
+impl Default for FrameCompression {
+    /// Fast gzip over 1 KB, as `binary-v1` has always had
+    fn default() -> Self {
+        Self { codec: Codec::Gzip, threshold: 1024, level: 1 }
+    }
+}
+
+impl FrameCompression {
+    pub fn new(codec: Codec, settings: &BinaryProtocolSettings) -> Self {
+        let level = match codec {
+            Codec::Gzip => settings.gzip_level as i32,
+            Codec::Zstd => settings.zstd_level,
+        };
+        Self { codec, threshold: settings.compression_threshold_bytes, level }
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.level as u32));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Codec::Zstd => zstd::encode_all(data, self.level),
+        }
+    }
+
+    fn flag(&self) -> u8 {
+        match self.codec {
+            Codec::Gzip => GZIP,
+            Codec::Zstd => ZSTD,
+        }
+    }
+}
+
+/// Checks the compression levels, before any frame is sent with them
+pub fn validate(settings: &BinaryProtocolSettings) -> Result<(), String> {
+    if settings.gzip_level > 9 {
+        return Err(format!("binary_protocol.gzip_level must be 0 to 9, not {}", settings.gzip_level));
+    }
+    let zstd_levels = zstd::compression_level_range();
+    if !zstd_levels.contains(&settings.zstd_level) {
+        return Err(format!("binary_protocol.zstd_level must be {} to {}, not {}",
+                           zstd_levels.start(), zstd_levels.end(), settings.zstd_level));
+    }
+    Ok(())
+}
+
+/// A message encoded for the wire
+pub struct Frame {
+    pub data: Vec<u8>,
+    /// Size of the message before it was compressed, if it was
+    pub compressed_from: Option<usize>,
+}
 
 /// High-performance binary message protocol for WebSocket communication
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BinaryMessage {
     /// Terminal output data (compressed if large)
     TerminalOutput {
@@ -34,51 +127,59 @@ pub enum BinaryMessage {
         code: String,
         message: String,
     },
+    /// Any other message, as the JSON text it has in the legacy framing
+    /// (e.g. `output_offset`, notifications, `auth_prompt`, `auth_response`)
+    Event {
+        json: String,
+    },
 }
 
 impl BinaryMessage {
-    /// Serialize message to binary format with optional compression
+    /// Serialize message to binary format with optional compression, as `binary-v1` does by default
     #[allow(dead_code)]
     pub fn to_binary(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.to_frame(&FrameCompression::default())?.data)
+    }
+
+    /// Serialize message to binary format, compressed as the WebSocket negotiated
+    pub fn to_frame(&self, compression: &FrameCompression) -> Result<Frame, Box<dyn std::error::Error>> {
         let serialized = bincode::serialize(self)?;
         
-        // Compress if message is large (>1KB)
-        if serialized.len() > 1024 {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
-            encoder.write_all(&serialized)?;
-            let compressed = encoder.finish()?;
+        if serialized.len() > compression.threshold {
+            let compressed = compression.compress(&serialized)?;
             
             // Only use compression if it actually reduces size
             if compressed.len() < serialized.len() {
-                let mut result = vec![1u8]; // Compression flag
-                result.extend_from_slice(&compressed);
-                return Ok(result);
+                let mut data = vec![compression.flag()];
+                data.extend_from_slice(&compressed);
+                return Ok(Frame { data, compressed_from: Some(serialized.len()) });
             }
         }
         
         // No compression
-        let mut result = vec![0u8]; // No compression flag
-        result.extend_from_slice(&serialized);
-        Ok(result)
+        let mut data = vec![UNCOMPRESSED];
+        data.extend_from_slice(&serialized);
+        Ok(Frame { data, compressed_from: None })
     }
     
     /// Deserialize message from binary format with decompression
-    #[allow(dead_code)]
     pub fn from_binary(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
         if data.is_empty() {
             return Err("Empty data".into());
         }
         
-        let compressed = data[0] == 1;
         let payload = &data[1..];
         
-        let serialized = if compressed {
-            let mut decoder = GzDecoder::new(payload);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            decompressed
-        } else {
-            payload.to_vec()
+        let serialized = match data[0] {
+            UNCOMPRESSED => payload.to_vec(),
+            GZIP => {
+                let mut decoder = GzDecoder::new(payload);
+                let mut decompressed = Vec::new();
+                decoder.read_to_end(&mut decompressed)?;
+                decompressed
+            }
+            ZSTD => zstd::decode_all(payload)?,
+            flag => return Err(format!("Unknown compression flag {}", flag).into()),
         };
         
         let message = bincode::deserialize(&serialized)?;
@@ -86,7 +187,6 @@ impl BinaryMessage {
     }
     
     /// Create terminal output message with automatic compression
-    #[allow(dead_code)]
     pub fn terminal_output(data: Bytes) -> Self {
         let data_vec = data.to_vec(); // Convert Bytes to Vec<u8>
         let compressed = data_vec.len() > 512; // Auto-compress if >512 bytes
@@ -112,12 +212,91 @@ impl BinaryMessage {
     }
     
     /// Create error message
-    #[allow(dead_code)]
     pub fn error(code: String, message: String) -> Self {
         BinaryMessage::Error { code, message }
     }
 }
 
+/// How messages are framed on a WebSocket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// JSON text messages, with terminal output and raw input in binary frames
+    Json,
+    /// A `BinaryMessage` in every binary frame, for clients that negotiated `binary-v1`
+    /// or `binary-v1-zstd`
+    Binary(FrameCompression),
+}
+
+impl Framing {
+    /// Picks the framing from the subprotocol agreed at upgrade
+    pub fn negotiated(socket: &WebSocket, settings: &BinaryProtocolSettings) -> Self {
+        match socket.protocol() {
+            Some(protocol) if protocol == SUBPROTOCOL => Framing::Binary(FrameCompression::new(Codec::Gzip, settings)),
+            Some(protocol) if protocol == SUBPROTOCOL_ZSTD => Framing::Binary(FrameCompression::new(Codec::Zstd, settings)),
+            _ => Framing::Json,
+        }
+    }
+
+    /// Frames a JSON message, e.g. `{"type": "info", ...}`
+    pub fn event(self, event: serde_json::Value) -> Message {
+        match self {
+            Framing::Json => Message::Text(event.to_string()),
+            Framing::Binary(compression) => encode(BinaryMessage::Event { json: event.to_string() }, &compression).0,
+        }
+    }
+
+    /// Frames terminal output
+    pub fn output(self, data: Vec<u8>) -> Message {
+        self.measured_output(data).0
+    }
+
+    /// Frames terminal output, with its size before compression if it was compressed
+    pub fn measured_output(self, data: Vec<u8>) -> (Message, Option<usize>) {
+        match self {
+            Framing::Json => (Message::Binary(data), None),
+            Framing::Binary(compression) => encode(BinaryMessage::terminal_output(Bytes::from(data)), &compression),
+        }
+    }
+
+    /// Frames an error; the legacy framing has no error codes
+    pub fn error(self, code: &str, message: &str) -> Message {
+        match self {
+            Framing::Json => Message::Text(serde_json::json!({ "type": "error", "message": message }).to_string()),
+            Framing::Binary(compression) => encode(BinaryMessage::error(code.to_string(), message.to_string()), &compression).0,
+        }
+    }
+
+    /// Answers a client ping
+    pub fn pong(self) -> Message {
+        match self {
+            Framing::Json => Message::Text(serde_json::json!({ "type": "pong" }).to_string()),
+            Framing::Binary(compression) => encode(BinaryMessage::Pong, &compression).0,
+        }
+    }
+
+    /// Reads the JSON text of a client message, e.g. an `auth_response`
+    pub fn event_text(self, message: Message) -> Option<String> {
+        match (self, message) {
+            (_, Message::Text(text)) => Some(text),
+            (Framing::Binary(_), Message::Binary(data)) => match BinaryMessage::from_binary(&data) {
+                Ok(BinaryMessage::Event { json }) => Some(json),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn encode(message: BinaryMessage, compression: &FrameCompression) -> (Message, Option<usize>) {
+    match message.to_frame(compression) {
+        Ok(frame) => (Message::Binary(frame.data), frame.compressed_from),
+        Err(e) => {
+            error!("Failed to encode binary message: {}", e);
+            (Message::Binary(Vec::new()), None)
+        }
+    }
+}
+
 /// Performance statistics for monitoring
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PerformanceStats {
@@ -127,6 +306,18 @@ pub struct PerformanceStats {
     pub bytes_received: u64,
     pub compression_ratio: f32,
     pub average_latency_ms: f32,
+    /// Round trips measured with WebSocket pings
+    #[serde(default)]
+    pub latency_samples: u64,
+    #[serde(default)]
+    pub last_latency_ms: Option<f32>,
+    /// Binary frames sent compressed, and their messages' sizes before and after
+    #[serde(default)]
+    pub compressed_messages: u64,
+    #[serde(default)]
+    pub bytes_before_compression: u64,
+    #[serde(default)]
+    pub bytes_after_compression: u64,
 }
 
 impl Default for PerformanceStats {
@@ -138,12 +329,16 @@ impl Default for PerformanceStats {
             bytes_received: 0,
             compression_ratio: 1.0,
             average_latency_ms: 0.0,
+            latency_samples: 0,
+            last_latency_ms: None,
+            compressed_messages: 0,
+            bytes_before_compression: 0,
+            bytes_after_compression: 0,
         }
     }
 }
 
 impl PerformanceStats {
-    #[allow(dead_code)]
     pub fn record_sent(&mut self, original_size: usize, compressed_size: usize) {
         self.messages_sent += 1;
         self.bytes_sent += compressed_size as u64;
@@ -153,16 +348,54 @@ impl PerformanceStats {
         self.compression_ratio = (self.compression_ratio * 0.9) + (new_ratio * 0.1);
     }
     
-    #[allow(dead_code)]
+    /// Counts a frame that was compressed, by its size before and after
+    pub fn record_compression(&mut self, uncompressed_size: usize, frame_size: usize) {
+        self.compressed_messages += 1;
+        self.bytes_before_compression += uncompressed_size as u64;
+        self.bytes_after_compression += frame_size as u64;
+    }
+
+    /// How much smaller compressed frames were than their messages, over all of them
+    pub fn frame_compression_ratio(&self) -> Option<f32> {
+        (self.bytes_after_compression > 0)
+            .then(|| self.bytes_before_compression as f32 / self.bytes_after_compression as f32)
+    }
+
     pub fn record_received(&mut self, size: usize) {
         self.messages_received += 1;
         self.bytes_received += size as u64;
     }
     
-    #[allow(dead_code)]
     pub fn record_latency(&mut self, latency_ms: f32) {
-        // Rolling average of latency
-        self.average_latency_ms = (self.average_latency_ms * 0.9) + (latency_ms * 0.1);
+        // Rolling average of latency, starting from the first sample
+        self.average_latency_ms = match self.latency_samples {
+            0 => latency_ms,
+            _ => (self.average_latency_ms * 0.9) + (latency_ms * 0.1),
+        };
+        self.latency_samples += 1;
+        self.last_latency_ms = Some(latency_ms);
+    }
+
+    /// Adds another's counters to these, for totals over several sessions
+    ///
+    /// The compression ratio is weighted by messages sent and the latency by
+    /// samples taken; the last latency is dropped, as it means nothing in a total.
+    pub fn accumulate(&mut self, other: &PerformanceStats) {
+        let weighted = |ours: f32, our_weight: u64, theirs: f32, their_weight: u64| match our_weight + their_weight {
+            0 => ours,
+            total => (ours * our_weight as f32 + theirs * their_weight as f32) / total as f32,
+        };
+        self.compression_ratio = weighted(self.compression_ratio, self.messages_sent, other.compression_ratio, other.messages_sent);
+        self.average_latency_ms = weighted(self.average_latency_ms, self.latency_samples, other.average_latency_ms, other.latency_samples);
+        self.messages_sent += other.messages_sent;
+        self.messages_received += other.messages_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.latency_samples += other.latency_samples;
+        self.last_latency_ms = None;
+        self.compressed_messages += other.compressed_messages;
+        self.bytes_before_compression += other.bytes_before_compression;
+        self.bytes_after_compression += other.bytes_after_compression;
     }
     
     #[allow(dead_code)]
@@ -208,4 +441,67 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+
+    #[test]
+    fn test_zstd_frames() {
+        let settings = BinaryProtocolSettings { compression_threshold_bytes: 100, ..Default::default() };
+        assert_eq!(subprotocols(&settings), &[SUBPROTOCOL_ZSTD, SUBPROTOCOL]);
+        let compression = FrameCompression::new(Codec::Zstd, &settings);
+
+        let large_data = "show interfaces\r\n".repeat(20);
+        let msg = BinaryMessage::terminal_output(Bytes::from(large_data.clone()));
+        let frame = msg.to_frame(&compression).unwrap();
+        assert_eq!(frame.data[0], ZSTD);
+        let serialized = frame.compressed_from.unwrap();
+        assert!(serialized > large_data.len() && frame.data.len() < serialized);
+        assert_eq!(BinaryMessage::from_binary(&frame.data).unwrap(), msg);
+
+        // Below the threshold, nothing is compressed
+        let frame = BinaryMessage::Pong.to_frame(&compression).unwrap();
+        assert_eq!((frame.data[0], frame.compressed_from), (UNCOMPRESSED, None));
+
+        let mut stats = PerformanceStats::default();
+        assert_eq!(stats.frame_compression_ratio(), None);
+        stats.record_compression(1000, 250);
+        stats.record_compression(1000, 150);
+        assert_eq!(stats.frame_compression_ratio(), Some(5.0));
+
+        assert!(validate(&settings).is_ok());
+        assert!(validate(&BinaryProtocolSettings { zstd_level: 99, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_event_round_trip() {
+        let event = serde_json::json!({ "type": "output_offset", "offset": 42 });
+        let framing = Framing::Binary(FrameCompression::default());
+        let Message::Binary(binary) = framing.event(event.clone()) else {
+            panic!("Expected a binary frame");
+        };
+        let text = framing.event_text(Message::Binary(binary.clone())).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap(), event);
+
+        // The legacy framing sends events as text and never decodes binary frames
+        assert_eq!(Framing::Json.event(event.clone()), Message::Text(event.to_string()));
+        assert!(Framing::Json.event_text(Message::Binary(binary)).is_none());
+    }
+
+    #[test]
+    fn test_stats_totals() {
+        let mut first = PerformanceStats::default();
+        first.record_latency(40.0);
+        first.record_latency(80.0);
+        assert_eq!(first.average_latency_ms, 44.0);
+        first.record_received(10);
+
+        let mut second = PerformanceStats::default();
+        second.record_latency(20.0);
+        second.record_received(5);
+
+        let mut totals = PerformanceStats::default();
+        totals.accumulate(&first);
+        totals.accumulate(&second);
+        assert_eq!((totals.messages_received, totals.bytes_received, totals.latency_samples), (2, 15, 3));
+        assert!((totals.average_latency_ms - 36.0).abs() < 0.01);
+        assert_eq!(totals.last_latency_ms, None);
+    }
 }
@@ -0,0 +1,141 @@
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::protocol::{BinaryMessage, SUBPROTOCOL};
+
+/// Regenerates the fixtures instead of checking against them, e.g. after a deliberate framing change
+const UPDATE_ENV: &str = "UPDATE_PROTOCOL_FIXTURES";
+
+/// Every message type, by the name it is encoded under
+const VARIANTS: &[&str] = &["TerminalOutput", "TerminalInput", "Resize", "Ping", "Pong", "SessionInfo", "Error", "Event"];
+
+fn variant(message: &BinaryMessage) -> &'static str {
+    match message {
+        BinaryMessage::TerminalOutput { .. } => "TerminalOutput",
+        BinaryMessage::TerminalInput { .. } => "TerminalInput",
+        BinaryMessage::Resize { .. } => "Resize",
+        BinaryMessage::Ping => "Ping",
+        BinaryMessage::Pong => "Pong",
+        BinaryMessage::SessionInfo { .. } => "SessionInfo",
+        BinaryMessage::Error { .. } => "Error",
+        BinaryMessage::Event { .. } => "Event",
+    }
+}
+
+/// Where the fixtures of the framing in use are kept; a new framing gets a directory of its own
+fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/protocol").join(SUBPROTOCOL)
+}
+
+/// The messages with a fixture each; those over 1 KB are sent compressed
+fn vectors() -> Vec<(&'static str, BinaryMessage)> {
+    let lines = |line: &str, count: usize| line.repeat(count);
+    vec![
+        ("terminal_output", BinaryMessage::TerminalOutput {
+            data: b"show clock\r\n*10:15:42.123 UTC Mon May 6 2024\r\nRouter#".to_vec(),
+            compressed: false,
+        }),
+        ("terminal_output_raw_bytes", BinaryMessage::TerminalOutput {
+            data: vec![0x00, 0x1b, b'[', b'0', b'm', 0x7f, 0x80, 0xc3, 0x28, 0xff],
+            compressed: false,
+        }),
+        ("terminal_output_gzip", BinaryMessage::TerminalOutput {
+            data: lines("GigabitEthernet0/0/1   10.0.0.1        YES manual up                    up      \r\n", 40).into_bytes(),
+            compressed: true,
+        }),
+        ("terminal_input", BinaryMessage::TerminalInput { data: "show ip interface brief\r".to_string() }),
+        ("terminal_input_utf8", BinaryMessage::TerminalInput { data: "echo héllo ✓ 日本\r".to_string() }),
+        ("terminal_input_gzip", BinaryMessage::TerminalInput { data: lines("interface Loopback0\r description pasted\r", 60) }),
+        ("resize", BinaryMessage::Resize { cols: 132, rows: 43 }),
+        ("ping", BinaryMessage::Ping),
+        ("pong", BinaryMessage::Pong),
+        ("session_info", BinaryMessage::SessionInfo {
+            session_id: "3f2b8c1e-5d4a-4b7e-9c0d-1a2b3c4d5e6f".to_string(),
+            message: "Connected".to_string(),
+        }),
+        ("session_info_gzip", BinaryMessage::SessionInfo {
+            session_id: "3f2b8c1e-5d4a-4b7e-9c0d-1a2b3c4d5e6f".to_string(),
+            message: lines("Unauthorized access is prohibited. ", 40),
+        }),
+        ("error", BinaryMessage::Error { code: "read_only".to_string(), message: "This session is read-only".to_string() }),
+        ("error_gzip", BinaryMessage::Error { code: "ssh_closed".to_string(), message: lines("Connection closed by remote host. ", 40) }),
+        ("event", BinaryMessage::Event { json: json!({ "type": "output_offset", "offset": 42 }).to_string() }),
+        ("event_gzip", BinaryMessage::Event {
+            json: json!({ "type": "info", "message": lines("Session will be closed for inactivity. ", 40) }).to_string(),
+        }),
+    ]
+}
+
+/// The manifest clients read the fixtures from, with each message in its JSON form
+fn manifest(vectors: &[(&str, BinaryMessage, Vec<u8>)]) -> Value {
+    json!({
+        "subprotocol": SUBPROTOCOL,
+        "framing": "One flag byte, 1 if the rest is gzip-compressed and 0 otherwise, then the message in bincode 1 encoding: \
+                    the variant index as a u32, then the fields in order, with byte arrays and strings as a u64 length and \
+                    their bytes, all little-endian",
+        "vectors": vectors.iter().map(|(name, message, frame)| json!({
+            "name": name,
+            "file": format!("{}.bin", name),
+            "variant": variant(message),
+            "compressed": frame[0] == 1,
+            "message": message,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// Writes the manifest with a vector per line, so changes show up line by line in diffs
+fn manifest_text(manifest: &Value) -> String {
+    let vectors: Vec<String> = manifest["vectors"].as_array().unwrap().iter()
+        .map(|vector| format!("    {}", vector))
+        .collect();
+    format!("{{\n  \"subprotocol\": {},\n  \"framing\": {},\n  \"vectors\": [\n{}\n  ]\n}}\n",
+            manifest["subprotocol"], manifest["framing"], vectors.join(",\n"))
+}
+
+#[test]
+fn test_protocol_fixtures() {
+    let dir = fixtures_dir();
+    let vectors: Vec<_> = vectors().into_iter()
+        .map(|(name, message)| {
+            let frame = message.to_binary().unwrap();
+            (name, message, frame)
+        })
+        .collect();
+
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        fs::create_dir_all(&dir).unwrap();
+        for (name, _, frame) in &vectors {
+            fs::write(dir.join(format!("{}.bin", name)), frame).unwrap();
+        }
+        fs::write(dir.join("manifest.json"), manifest_text(&manifest(&vectors))).unwrap();
+    }
+
+    let covered: BTreeSet<&str> = vectors.iter().map(|(_, message, _)| variant(message)).collect();
+    assert_eq!(covered, VARIANTS.iter().copied().collect(), "every message type needs a fixture");
+    let compressed: BTreeSet<&str> = vectors.iter()
+        .filter(|(_, _, frame)| frame[0] == 1)
+        .map(|(_, message, _)| variant(message))
+        .collect();
+    assert_eq!(compressed, ["TerminalOutput", "TerminalInput", "SessionInfo", "Error", "Event"].into_iter().collect());
+
+    let manifest: Value = serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest, self::manifest(&vectors), "manifest.json is out of date; run with {}=1", UPDATE_ENV);
+
+    for (name, message, frame) in &vectors {
+        let fixture = fs::read(dir.join(format!("{}.bin", name))).unwrap();
+        assert_eq!(BinaryMessage::from_binary(&fixture).unwrap(), *message, "{} does not decode", name);
+        if fixture[0] == 0 {
+            assert_eq!(*frame, fixture, "{} is not encoded byte for byte", name);
+        } else {
+            // gzip output differs between implementations; what it holds must not
+            assert_eq!(frame[0], 1, "{} is no longer compressed", name);
+            let mut payload = Vec::new();
+            GzDecoder::new(&fixture[1..]).read_to_end(&mut payload).unwrap();
+            assert_eq!(payload, bincode::serialize(message).unwrap(), "{} is not encoded byte for byte", name);
+        }
+    }
+}
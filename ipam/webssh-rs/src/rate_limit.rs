@@ -0,0 +1,226 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::error_code::ErrorCode;
+use crate::http::ClientIp;
+use crate::settings::RateLimitSettings;
+use crate::AppState;
+
+/// Tracked keys beyond which expired entries are swept on the next request
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Requests counted in the current fixed window
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Failed logins to a device, and how long it is locked out for
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Limits connect attempts by source address and by target device
+///
+/// Each source and target may make a number of attempts per window. A device
+/// refusing the credentials `lockout_after` times in a row is locked out for
+/// `lockout_base_seconds`, doubled on each further failure up to
+/// `lockout_max_seconds`; a successful login clears its failures. The limits
+/// are passed in on each call, so reloaded settings apply at once.
+#[derive(Default)]
+pub struct ConnectLimiter {
+    sources: Mutex<HashMap<IpAddr, Window>>,
+    targets: Mutex<HashMap<String, Window>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl ConnectLimiter {
+    /// Counts an attempt, refusing it if a limit is reached or the target is locked out
+    ///
+    /// # Returns
+    /// * `Result<(), (Duration, String)>` - How long to wait before retrying, and why, if refused
+    fn check(&self, settings: &RateLimitSettings, source: IpAddr, target: Option<&str>, now: Instant) -> Result<(), (Duration, String)> {
+        if let Some(target) = target {
+            if let Some(locked_until) = self.failures.lock().unwrap().get(target).and_then(|failures| failures.locked_until) {
+                if locked_until > now {
+                    return Err((locked_until - now, format!("Too many failed logins to {}", target)));
+                }
+            }
+        }
+        let window = Duration::from_secs(settings.window_seconds.max(1));
+        count(&self.sources, source, settings.per_source, window, now)
+            .map_err(|wait| (wait, format!("Too many connection attempts from {}", source)))?;
+        if let Some(target) = target {
+            count(&self.targets, target.to_string(), settings.per_target, window, now)
+                .map_err(|wait| (wait, format!("Too many connection attempts to {}", target)))?;
+        }
+        Ok(())
+    }
+
+    /// Notes whether a login to the target was refused by the device
+    fn record(&self, settings: &RateLimitSettings, target: &str, auth_failed: bool, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        if !auth_failed {
+            failures.remove(target);
+            return;
+        }
+        let entry = failures.entry(target.to_string()).or_default();
+        entry.count += 1;
+        let threshold = settings.lockout_after.max(1);
+        if entry.count >= threshold {
+            let doublings = (entry.count - threshold).min(16);
+            let lockout = Duration::from_secs(settings.lockout_base_seconds.saturating_mul(1 << doublings))
+                .min(Duration::from_secs(settings.lockout_max_seconds));
+            warn!("Locking out logins to {} for {:?} after {} failures", target, lockout, entry.count);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+}
+
+/// Counts a request against a key's window
+///
+/// # Returns
+/// * `Result<(), Duration>` - The time until the window ends, if the limit is reached
+fn count<K: std::hash::Hash + Eq>(
+    windows: &Mutex<HashMap<K, Window>>,
+    key: K,
+    limit: u32,
+    window: Duration,
+    now: Instant,
+) -> Result<(), Duration> {
+    let mut windows = windows.lock().unwrap();
+    if windows.len() > SWEEP_THRESHOLD {
+        windows.retain(|_, counted| now.duration_since(counted.started) < window);
+    }
+    let counted = windows.entry(key).or_insert(Window { started: now, count: 0 });
+    if now.duration_since(counted.started) >= window {
+        *counted = Window { started: now, count: 0 };
+    }
+    if counted.count >= limit {
+        return Err(window - now.duration_since(counted.started));
+    }
+    counted.count += 1;
+    Ok(())
+}
+
+/// The device a connect request is for: its hostname, or its inventory reference
+fn target_of(body: &[u8]) -> Option<String> {
+    let request: serde_json::Value = serde_json::from_slice(body).ok()?;
+    match request.get("hostname").and_then(|hostname| hostname.as_str()).filter(|hostname| !hostname.is_empty()) {
+        Some(hostname) => Some(hostname.trim().to_lowercase()),
+        None => request.get("device_ref").and_then(|device| device.as_str()).map(|device| format!("device:{}", device)),
+    }
+}
+
+fn rate_limited(wait: Duration, message: String) -> Response {
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(json!({
+        "error": "rate_limited",
+        "message": message,
+    }))).into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after.max(1)));
+    response
+}
+
+/// Middleware limiting connect attempts and locking out devices after failed logins
+pub async fn limit_connect(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let policy_settings = state.policy.settings();
+    let settings = &policy_settings.rate_limit;
+    if !settings.enabled {
+        return next.run(request).await;
+    }
+    let limiter = &state.connect_limiter;
+    let source = request.extensions().get::<ClientIp>().map(|client| client.0);
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, state.http.max_body_bytes).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(json!({
+            "error": "payload_too_large",
+            "message": "The request body is too large",
+        }))).into_response();
+    };
+    let target = target_of(&body);
+
+    if let Some(source) = source {
+        if let Err((wait, message)) = limiter.check(settings, source, target.as_deref(), Instant::now()) {
+            warn!("Refusing connect request from {}: {}", source, message);
+            return rate_limited(wait, message);
+        }
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let Some(target) = target else {
+        return response;
+    };
+
+    // The connect handlers report failures in the body, with a 200 status
+    let (parts, body) = response.into_parts();
+    let Ok(body) = to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let outcome: Option<serde_json::Value> = serde_json::from_slice(&body).ok();
+    if let Some(outcome) = outcome {
+        let succeeded = outcome.get("success").and_then(|success| success.as_bool()) == Some(true);
+        let auth_failed = outcome.get("error_code").and_then(|code| code.as_str()) == Some(ErrorCode::AuthFailed.as_str());
+        if succeeded || auth_failed {
+            limiter.record(settings, &target, auth_failed, Instant::now());
+        }
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_and_lockout() {
+        let limiter = ConnectLimiter::default();
+        let settings = RateLimitSettings {
+            enabled: true,
+            window_seconds: 60,
+            per_source: 3,
+            per_target: 2,
+            lockout_after: 2,
+            lockout_base_seconds: 30,
+            lockout_max_seconds: 100,
+        };
+        let now = Instant::now();
+        let (alice, bob): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        assert!(limiter.check(&settings, alice, Some("core-1"), now).is_ok());
+        assert!(limiter.check(&settings, alice, Some("core-1"), now).is_ok());
+        assert!(limiter.check(&settings, bob, Some("core-1"), now).is_err());
+        assert!(limiter.check(&settings, alice, Some("core-2"), now).is_ok());
+        let (wait, _) = limiter.check(&settings, alice, Some("core-3"), now + Duration::from_secs(20)).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(40));
+        assert!(limiter.check(&settings, alice, Some("core-3"), now + Duration::from_secs(60)).is_ok());
+
+        limiter.record(&settings, "core-4", true, now);
+        assert!(limiter.check(&settings, bob, Some("core-4"), now).is_ok());
+        limiter.record(&settings, "core-4", true, now);
+        let (wait, _) = limiter.check(&settings, bob, Some("core-4"), now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(30));
+        limiter.record(&settings, "core-4", true, now);
+        limiter.record(&settings, "core-4", true, now);
+        assert_eq!(limiter.check(&settings, bob, Some("core-4"), now).unwrap_err().0, Duration::from_secs(100));
+        limiter.record(&settings, "core-4", false, now);
+        assert!(limiter.check(&settings, bob, Some("core-4"), now + Duration::from_secs(60)).is_ok());
+
+        assert_eq!(target_of(br#"{"hostname": " Core-1 ", "port": 22}"#).as_deref(), Some("core-1"));
+        assert_eq!(target_of(br#"{"device_ref": "edge"}"#).as_deref(), Some("device:edge"));
+    }
+}
@@ -0,0 +1,350 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::inventory::Device;
+use crate::settings::{OutboundSettings, ReachabilitySettings};
+use crate::ssh::source;
+use crate::tasks::RestartPolicy;
+use crate::AppState;
+
+/// How often the devices are checked for probes that are due
+const SCHEDULE_TICK: Duration = Duration::from_secs(5);
+
+/// Whether a device accepted the gateway's last probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReachState {
+    /// Not probed yet
+    Unknown,
+    Reachable,
+    Unreachable,
+}
+
+/// A device's reachability from this gateway, as listed by `/api/reachability`
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceReachability {
+    pub device_id: String,
+    pub name: String,
+    pub hostname: String,
+    pub port: u16,
+    pub tags: Vec<String>,
+    pub state: ReachState,
+    /// Time the last successful probe took to connect
+    pub latency_ms: Option<u64>,
+    /// The address the device was last reached from
+    pub source_address: Option<String>,
+    /// Why the last probe failed
+    pub error: Option<String>,
+    /// Probes in a row that failed
+    pub consecutive_failures: u32,
+    pub last_probe_at: Option<DateTime<Utc>>,
+    pub last_reachable_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    device_type: Option<String>,
+    #[serde(skip)]
+    next_probe: Instant,
+}
+
+/// A device whose probe is due
+#[derive(Debug, Clone)]
+struct ProbeTarget {
+    device_id: String,
+    hostname: String,
+    port: u16,
+    device_type: Option<String>,
+}
+
+/// What a probe found: the time to connect and where from, or why it could not
+type ProbeOutcome = Result<(Duration, Option<String>), String>;
+
+/// The reachability of the inventory devices, kept current by probing them
+///
+/// A probe opens a TCP connection to the device's address and port, from the
+/// source `ssh.outbound` selects for it, and closes it at once. Each device
+/// is probed every `reachability.interval_seconds`, give or take the jitter,
+/// so a large inventory is probed steadily rather than in bursts.
+pub struct Reachability {
+    devices: Mutex<HashMap<String, DeviceReachability>>,
+}
+
+impl Reachability {
+    pub fn new() -> Self {
+        Self { devices: Mutex::default() }
+    }
+
+    /// Takes in the inventory's devices, returning those whose probe is due
+    ///
+    /// New devices are first probed at a random point within the interval;
+    /// those whose address changed are probed at once; removed ones are forgotten.
+    fn sync(&self, inventory: &[Device], settings: &ReachabilitySettings, now: Instant) -> Vec<ProbeTarget> {
+        let interval = interval(settings);
+        let mut devices = self.lock();
+        devices.retain(|device_id, _| inventory.iter().any(|device| &device.id == device_id));
+        for device in inventory {
+            let entry = devices.entry(device.id.clone()).or_insert_with(|| DeviceReachability {
+                device_id: device.id.clone(),
+                name: device.name.clone(),
+                hostname: device.hostname.clone(),
+                port: device.port,
+                tags: Vec::new(),
+                state: ReachState::Unknown,
+                latency_ms: None,
+                source_address: None,
+                error: None,
+                consecutive_failures: 0,
+                last_probe_at: None,
+                last_reachable_at: None,
+                device_type: None,
+                next_probe: now + interval.mul_f64(random_fraction()),
+            });
+            if entry.hostname != device.hostname || entry.port != device.port {
+                entry.hostname = device.hostname.clone();
+                entry.port = device.port;
+                entry.state = ReachState::Unknown;
+                entry.next_probe = now;
+            }
+            entry.name = device.name.clone();
+            entry.tags = device.tags.clone();
+            entry.device_type = device.device_type.clone();
+        }
+
+        devices.values()
+            .filter(|device| device.next_probe <= now)
+            .map(|device| ProbeTarget {
+                device_id: device.device_id.clone(),
+                hostname: device.hostname.clone(),
+                port: device.port,
+                device_type: device.device_type.clone(),
+            })
+            .collect()
+    }
+
+    /// Records a probe's outcome and schedules the device's next one
+    fn record(&self, target: &ProbeTarget, outcome: ProbeOutcome, settings: &ReachabilitySettings, now: Instant) {
+        let mut devices = self.lock();
+        let Some(device) = devices.get_mut(&target.device_id) else {
+            return;
+        };
+        // The address changed while the probe was in flight
+        if device.hostname != target.hostname || device.port != target.port {
+            return;
+        }
+        let previous = device.state;
+        device.last_probe_at = Some(Utc::now());
+        device.next_probe = next_probe(now, interval(settings), settings.jitter_percent);
+        match outcome {
+            Ok((latency, source_address)) => {
+                device.state = ReachState::Reachable;
+                device.latency_ms = Some(latency.as_millis() as u64);
+                device.source_address = source_address;
+                device.error = None;
+                device.consecutive_failures = 0;
+                device.last_reachable_at = device.last_probe_at;
+                if previous == ReachState::Unreachable {
+                    info!("Device {} ({}:{}) is reachable again", device.name, device.hostname, device.port);
+                }
+            }
+            Err(e) => {
+                if previous != ReachState::Unreachable {
+                    warn!("Device {} ({}:{}) is unreachable: {}", device.name, device.hostname, device.port, e);
+                }
+                device.state = ReachState::Unreachable;
+                device.error = Some(e);
+                device.consecutive_failures += 1;
+            }
+        }
+    }
+
+    /// The devices, by name
+    pub fn devices(&self) -> Vec<DeviceReachability> {
+        let mut devices: Vec<DeviceReachability> = self.lock().values().cloned().collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, DeviceReachability>> {
+        self.devices.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn interval(settings: &ReachabilitySettings) -> Duration {
+    Duration::from_secs(settings.interval_seconds.max(10))
+}
+
+/// The time of a device's next probe: the interval from now, moved by up to the jitter either way
+fn next_probe(now: Instant, interval: Duration, jitter_percent: u8) -> Instant {
+    let spread = interval.mul_f64(f64::from(jitter_percent.min(100)) / 100.0);
+    now + interval - spread + spread.mul_f64(2.0 * random_fraction())
+}
+
+/// A random number from 0 to 1
+fn random_fraction() -> f64 {
+    let mut bytes = [0u8; 4];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0.5;
+    }
+    f64::from(u32::from_ne_bytes(bytes)) / f64::from(u32::MAX)
+}
+
+/// Starts probing the inventory devices
+pub fn start(state: AppState, reachability: Arc<Reachability>) {
+    let tasks = state.tasks.clone();
+    tasks.spawn("reachability", RestartPolicy::OnPanic, move |task| {
+        let (state, reachability) = (state.clone(), reachability.clone());
+        async move {
+            loop {
+                probe_due(&state, &reachability).await;
+                task.ran();
+                tokio::time::sleep(SCHEDULE_TICK).await;
+            }
+        }
+    });
+}
+
+/// Probes the devices that are due, a few at a time
+async fn probe_due(state: &AppState, reachability: &Reachability) {
+    let Some(inventory) = &state.inventory else {
+        return;
+    };
+    // Read on every pass, as the settings may be reloaded
+    let settings = state.policy.settings();
+    let (outbound, settings) = (&settings.ssh.outbound, &settings.reachability);
+    let devices = match inventory.list(settings.tag.as_deref()) {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Reachability probes skipped: {}", e);
+            return;
+        }
+    };
+    let due = reachability.sync(&devices, settings, Instant::now());
+    let timeout = Duration::from_millis(settings.timeout_ms.max(100));
+    futures::stream::iter(due)
+        .for_each_concurrent(settings.max_concurrent.max(1), |target| async move {
+            let outcome = probe(outbound, &target, timeout).await;
+            reachability.record(&target, outcome, settings, Instant::now());
+        })
+        .await;
+}
+
+/// Opens and closes a connection to the device
+async fn probe(outbound: &OutboundSettings, target: &ProbeTarget, timeout: Duration) -> ProbeOutcome {
+    let (outbound, target) = (outbound.clone(), target.clone());
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let (_stream, source) = source::connect(&outbound, &target.hostname, target.port, target.device_type.as_deref(), Some(timeout))
+            .map_err(|e| e.to_string())?;
+        Ok((started.elapsed(), source.address))
+    })
+    .await
+    .map_err(|e| format!("Probe task failed: {}", e))?
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReachabilityQuery {
+    /// Only the device with this ID or name
+    pub device: Option<String>,
+    pub tag: Option<String>,
+    pub state: Option<ReachState>,
+}
+
+/// The reachability and latency of the inventory devices from this gateway
+pub async fn reachability_handler(State(state): State<AppState>, Query(query): Query<ReachabilityQuery>) -> Response {
+    let Some(reachability) = &state.reachability else {
+        return (StatusCode::CONFLICT, Json(json!({
+            "error": "reachability_disabled",
+            "message": "Reachability probes are not enabled on this instance",
+        }))).into_response();
+    };
+    let devices: Vec<DeviceReachability> = reachability.devices().into_iter()
+        .filter(|device| query.device.as_ref().is_none_or(|wanted| &device.device_id == wanted || &device.name == wanted))
+        .filter(|device| query.tag.as_ref().is_none_or(|tag| device.tags.contains(tag)))
+        .filter(|device| query.state.is_none_or(|state| device.state == state))
+        .collect();
+    let count = |state: ReachState| devices.iter().filter(|device| device.state == state).count();
+    Json(json!({
+        "node_id": state.node.id,
+        "interval_seconds": interval(&state.policy.settings().reachability).as_secs(),
+        "summary": {
+            "reachable": count(ReachState::Reachable),
+            "unreachable": count(ReachState::Unreachable),
+            "unknown": count(ReachState::Unknown),
+        },
+        "devices": devices,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, hostname: &str) -> Device {
+        Device {
+            id: id.to_string(),
+            name: format!("{}-name", id),
+            hostname: hostname.to_string(),
+            port: 22,
+            device_type: None,
+            credentials_ref: None,
+            tags: vec!["core".to_string()],
+            lab_twin: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_probes_are_spread_over_the_interval() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(60);
+        for _ in 0..100 {
+            let next = next_probe(now, interval, 20).duration_since(now);
+            assert!(next >= Duration::from_secs(48) && next <= Duration::from_secs(72), "{:?}", next);
+        }
+        assert_eq!(next_probe(now, interval, 0), now + interval);
+    }
+
+    #[test]
+    fn test_sync_and_record() {
+        let reachability = Reachability::new();
+        let settings = ReachabilitySettings::default();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(61);
+
+        // First probes fall within the interval
+        assert!(reachability.sync(&[device("r1", "10.0.0.1"), device("r2", "10.0.0.2")], &settings, now).len() <= 2);
+        let due = reachability.sync(&[device("r1", "10.0.0.1"), device("r2", "10.0.0.2")], &settings, later);
+        assert_eq!(due.len(), 2);
+
+        let r1 = due.iter().find(|target| target.device_id == "r1").unwrap();
+        reachability.record(r1, Ok((Duration::from_millis(12), None)), &settings, later);
+        let r2 = due.iter().find(|target| target.device_id == "r2").unwrap();
+        reachability.record(r2, Err("Connection refused".to_string()), &settings, later);
+        let devices = reachability.devices();
+        assert_eq!((devices[0].state, devices[0].latency_ms), (ReachState::Reachable, Some(12)));
+        assert_eq!((devices[1].state, devices[1].consecutive_failures), (ReachState::Unreachable, 1));
+
+        // A device moved to a new address is probed at once; a removed one is forgotten
+        let due = reachability.sync(&[device("r1", "10.0.0.9")], &settings, later);
+        assert_eq!(due.len(), 1);
+        let devices = reachability.devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].state, ReachState::Unknown);
+        // The outcome of a probe of the old address is dropped
+        reachability.record(r1, Ok((Duration::from_millis(12), None)), &settings, later);
+        assert_eq!(reachability.devices()[0].state, ReachState::Unknown);
+    }
+}
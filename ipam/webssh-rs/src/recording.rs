@@ -0,0 +1,357 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info};
+
+use crate::settings::{RecordingSettings, Settings};
+use crate::ssh::{ConnectionInfo, TerminalSize};
+use crate::AppState;
+
+/// A recorder shared between the WebSocket tasks of a session and the registry
+pub type SharedRecorder = Arc<Mutex<AsciicastRecorder>>;
+
+/// Metadata written next to each recording so it can be listed without parsing the cast
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingMetadata {
+    pub recording_id: String,
+    pub session_id: String,
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub includes_input: bool,
+    #[serde(default)]
+    pub size_bytes: u64,
+}
+
+/// Writes terminal events to an asciicast v2 file
+///
+/// The file starts with a JSON header line followed by one `[time, code, data]`
+/// event per line, where code is "o" for output, "i" for input and "r" for resize.
+pub struct AsciicastRecorder {
+    writer: BufWriter<File>,
+    metadata: RecordingMetadata,
+    metadata_path: PathBuf,
+    started: Instant,
+    record_input: bool,
+    // Trailing bytes of an incomplete UTF-8 sequence, kept until the next chunk
+    pending_output: Vec<u8>,
+    finished: bool,
+}
+
+impl AsciicastRecorder {
+    /// Creates the cast file and its metadata in the configured directory
+    pub fn start(
+        settings: &RecordingSettings,
+        mut metadata: RecordingMetadata,
+        cols: u32,
+        rows: u32,
+        term: &str,
+    ) -> std::io::Result<Self> {
+        let directory = PathBuf::from(&settings.directory);
+        fs::create_dir_all(&directory)?;
+
+        metadata.includes_input = settings.record_input;
+        let cast_path = directory.join(format!("{}.cast", metadata.recording_id));
+        let metadata_path = directory.join(format!("{}.json", metadata.recording_id));
+
+        let mut writer = BufWriter::new(File::create(&cast_path)?);
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": metadata.started_at.timestamp(),
+            "title": format!("{}@{}", metadata.ssh_username, metadata.device_id),
+            "env": { "TERM": term },
+        });
+        writeln!(writer, "{}", header)?;
+        writer.flush()?;
+
+        fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+        info!("Recording session {} to {}", metadata.session_id, cast_path.display());
+
+        Ok(Self {
+            writer,
+            metadata,
+            metadata_path,
+            started: Instant::now(),
+            record_input: settings.record_input,
+            pending_output: Vec::new(),
+            finished: false,
+        })
+    }
+
+    pub fn recording_id(&self) -> &str {
+        &self.metadata.recording_id
+    }
+
+    /// Records terminal output sent to the client
+    pub fn record_output(&mut self, data: &[u8]) {
+        self.pending_output.extend_from_slice(data);
+        let complete = complete_utf8_prefix(&self.pending_output);
+        if complete == 0 {
+            return;
+        }
+        let text = String::from_utf8_lossy(&self.pending_output[..complete]).into_owned();
+        self.pending_output.drain(..complete);
+        self.write_event("o", &text);
+    }
+
+    /// Records input typed by the user, if input recording is enabled
+    pub fn record_input(&mut self, data: &[u8]) {
+        if self.record_input {
+            let text = String::from_utf8_lossy(data).into_owned();
+            self.write_event("i", &text);
+        }
+    }
+
+    /// Records a terminal resize
+    pub fn record_resize(&mut self, cols: u32, rows: u32) {
+        self.write_event("r", &format!("{}x{}", cols, rows));
+    }
+
+    fn write_event(&mut self, code: &str, data: &str) {
+        if self.finished {
+            return;
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let event = json!([(elapsed * 1_000_000.0).round() / 1_000_000.0, code, data]);
+        if let Err(e) = writeln!(self.writer, "{}", event).and_then(|_| self.writer.flush()) {
+            error!("Failed to write recording {}: {}", self.metadata.recording_id, e);
+        }
+    }
+
+    /// Flushes the cast file and stamps the end time in the metadata
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        if !self.pending_output.is_empty() {
+            let text = String::from_utf8_lossy(&self.pending_output).into_owned();
+            self.pending_output.clear();
+            self.write_event("o", &text);
+        }
+        self.finished = true;
+
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush recording {}: {}", self.metadata.recording_id, e);
+        }
+        self.metadata.ended_at = Some(Utc::now());
+        self.metadata.size_bytes = self.writer.get_ref().metadata().map(|m| m.len()).unwrap_or(0);
+        match serde_json::to_string_pretty(&self.metadata) {
+            Ok(contents) => {
+                if let Err(e) = fs::write(&self.metadata_path, contents) {
+                    error!("Failed to update recording metadata {}: {}", self.metadata_path.display(), e);
+                }
+            }
+            Err(e) => error!("Failed to serialize recording metadata: {}", e),
+        }
+        info!("Finished recording {} for session {}", self.metadata.recording_id, self.metadata.session_id);
+    }
+}
+
+impl Drop for AsciicastRecorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Starts recording a newly created session, logging rather than failing on errors
+///
+/// The header gives the terminal the shell was opened with, or the configured default.
+pub fn start_session_recording(
+    settings: &Settings,
+    session_id: &str,
+    portal_user_id: &str,
+    device_id: &str,
+    ssh_username: &str,
+    connection: &ConnectionInfo,
+) -> Option<SharedRecorder> {
+    let metadata = RecordingMetadata {
+        recording_id: uuid::Uuid::new_v4().to_string(),
+        session_id: session_id.to_string(),
+        portal_user_id: portal_user_id.to_string(),
+        device_id: device_id.to_string(),
+        ssh_username: ssh_username.to_string(),
+        started_at: Utc::now(),
+        ended_at: None,
+        includes_input: settings.recording.record_input,
+        size_bytes: 0,
+    };
+
+    let terminal = &settings.ssh.terminal;
+    let size = connection.terminal_size.unwrap_or(TerminalSize { cols: terminal.default_cols, rows: terminal.default_rows });
+    match AsciicastRecorder::start(
+        &settings.recording,
+        metadata,
+        size.cols,
+        size.rows,
+        connection.terminal_type.as_deref().unwrap_or(&terminal.standard_terminal_type),
+    ) {
+        Ok(recorder) => Some(Arc::new(Mutex::new(recorder))),
+        Err(e) => {
+            error!("Failed to start recording for session {}: {}", session_id, e);
+            None
+        }
+    }
+}
+
+/// Applies an event to the session recorder, if the session is being recorded
+pub fn record(recorder: &Option<SharedRecorder>, event: impl FnOnce(&mut AsciicastRecorder)) {
+    if let Some(recorder) = recorder {
+        if let Ok(mut recorder) = recorder.lock() {
+            event(&mut recorder);
+        }
+    }
+}
+
+/// Returns the length of the data without a truncated UTF-8 sequence at its end
+///
+/// Invalid bytes elsewhere are left in place and replaced when converted.
+fn complete_utf8_prefix(data: &[u8]) -> usize {
+    let tail_start = data.len().saturating_sub(3);
+    (tail_start..data.len())
+        .find(|&i| matches!(std::str::from_utf8(&data[i..]), Err(e) if e.valid_up_to() == 0 && e.error_len().is_none()))
+        .unwrap_or(data.len())
+}
+
+fn is_valid_recording_id(recording_id: &str) -> bool {
+    !recording_id.is_empty() && recording_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn load_metadata(path: &FsPath) -> Option<RecordingMetadata> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn not_found(recording_id: &str) -> Response {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "error": "recording_not_found",
+        "message": format!("Recording '{}' not found", recording_id),
+    }))).into_response()
+}
+
+/// Handler for listing all recordings, newest first
+pub async fn list_handler(State(state): State<AppState>) -> Response {
+    let directory = PathBuf::from(&state.settings.recording.directory);
+    let entries = match fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(_) => return Json(Vec::<RecordingMetadata>::new()).into_response(),
+    };
+
+    let mut recordings: Vec<RecordingMetadata> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| load_metadata(&path))
+        .map(|mut metadata| {
+            // Recordings still in progress have no final size yet
+            if metadata.ended_at.is_none() {
+                let cast_path = directory.join(format!("{}.cast", metadata.recording_id));
+                metadata.size_bytes = fs::metadata(cast_path).map(|m| m.len()).unwrap_or(0);
+            }
+            metadata
+        })
+        .collect();
+    recordings.sort_by_key(|metadata| std::cmp::Reverse(metadata.started_at));
+
+    Json(recordings).into_response()
+}
+
+/// Handler for downloading a recording as an asciicast v2 file
+pub async fn download_handler(
+    Path(recording_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    if !is_valid_recording_id(&recording_id) {
+        return not_found(&recording_id);
+    }
+
+    let cast_path = PathBuf::from(&state.settings.recording.directory).join(format!("{}.cast", recording_id));
+    let file = match tokio::fs::File::open(&cast_path).await {
+        Ok(file) => file,
+        Err(_) => return not_found(&recording_id),
+    };
+    info!("Serving recording {}", recording_id);
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-asciicast".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.cast\"", recording_id)),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_utf8_prefix_holds_back_split_sequence() {
+        let euro = "€".as_bytes();
+        let mut data = b"abc".to_vec();
+        data.extend_from_slice(&euro[..2]);
+
+        assert_eq!(complete_utf8_prefix(b"plain ascii"), 11);
+        assert_eq!(complete_utf8_prefix(&data), 3);
+        assert_eq!(complete_utf8_prefix(&euro[..1]), 0);
+    }
+
+    #[test]
+    fn test_recording_writes_asciicast_events() {
+        let directory = std::env::temp_dir().join(format!("webssh-recording-{}", uuid::Uuid::new_v4()));
+        let settings = RecordingSettings {
+            enabled: true,
+            directory: directory.to_string_lossy().into_owned(),
+            record_input: false,
+        };
+        let metadata = RecordingMetadata {
+            recording_id: "test-recording".to_string(),
+            session_id: "session".to_string(),
+            portal_user_id: "user".to_string(),
+            device_id: "router1".to_string(),
+            ssh_username: "admin".to_string(),
+            started_at: Utc::now(),
+            ended_at: None,
+            includes_input: false,
+            size_bytes: 0,
+        };
+
+        let mut recorder = AsciicastRecorder::start(&settings, metadata, 80, 24, "xterm").unwrap();
+        let euro = "€".as_bytes();
+        recorder.record_output(&euro[..1]);
+        recorder.record_output(&euro[1..]);
+        recorder.record_input(b"secret");
+        recorder.record_resize(120, 40);
+        recorder.finish();
+
+        let cast = fs::read_to_string(directory.join("test-recording.cast")).unwrap();
+        let lines: Vec<serde_json::Value> = cast.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "€");
+        assert_eq!(lines[2][1], "r");
+        assert_eq!(lines[2][2], "120x40");
+
+        let metadata = load_metadata(&directory.join("test-recording.json")).unwrap();
+        assert!(metadata.ended_at.is_some());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
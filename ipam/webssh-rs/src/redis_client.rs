@@ -0,0 +1,213 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// Largest bulk string read
+const MAX_BULK_BYTES: usize = 16 * 1024 * 1024;
+
+/// A reply to a Redis command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Nil,
+    Status(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    /// An error returned by the server, e.g. "WRONGTYPE ..."
+    Error(String),
+}
+
+impl Reply {
+    /// The text of a bulk or status reply
+    pub fn text(&self) -> Option<String> {
+        match self {
+            Reply::Bulk(data) => Some(String::from_utf8_lossy(data).into_owned()),
+            Reply::Status(status) => Some(status.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Client of a Redis server, over one connection kept open between commands
+///
+/// Commands are sent one at a time; a command that fails on a broken
+/// connection is retried once on a new one.
+pub struct RedisClient {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    database: u32,
+    connection: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RedisClient {
+    /// Parses a `redis://[[user]:password@]host[:port][/database]` URL
+    pub fn new(url: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid Redis URL '{}'", url);
+        let rest = url.strip_prefix("redis://").ok_or_else(|| format!("Redis URL '{}' must start with redis://", url))?;
+        let (authority, database) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, 0),
+            Some((authority, database)) => (authority, database.parse().map_err(|_| invalid())?),
+            None => (rest, 0),
+        };
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, authority),
+        };
+        let (username, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+            Some(Some((username, password))) => {
+                let username = Some(username.to_string()).filter(|username| !username.is_empty());
+                let password = urlencoding::decode(password).map_err(|_| invalid())?.into_owned();
+                (username, Some(password))
+            }
+            Some(None) => return Err(invalid()),
+            None => (None, None),
+        };
+        // An IPv6 address is bracketed, so its colons are not taken for the port
+        let port_separator = match address.rfind(']') {
+            Some(end) => address[end..].find(':').map(|index| end + index),
+            None => address.rfind(':'),
+        };
+        let (host, port) = match port_separator {
+            Some(index) => (&address[..index], address[index + 1..].parse::<u16>().map_err(|_| invalid())?),
+            None => (address, 6379),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { host: host.to_string(), port, username, password, database, connection: Mutex::new(None) })
+    }
+
+    /// Runs a command, e.g. `["SET", key, value, "EX", "30"]`
+    ///
+    /// Server errors come back as `Reply::Error`; only I/O failures are `Err`.
+    pub async fn command(&self, args: &[&str]) -> std::io::Result<Reply> {
+        let mut connection = self.connection.lock().await;
+        for attempt in 0..2 {
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let Some(stream) = connection.as_mut() else {
+                continue;
+            };
+            match exchange(stream, args).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => {
+                    *connection = None;
+                    if attempt == 1 {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Err(Error::new(ErrorKind::NotConnected, "Redis connection lost"))
+    }
+
+    async fn connect(&self) -> std::io::Result<BufStream<TcpStream>> {
+        let mut stream = BufStream::new(TcpStream::connect((self.host.as_str(), self.port)).await?);
+        if let Some(password) = &self.password {
+            let reply = match &self.username {
+                Some(username) => exchange(&mut stream, &["AUTH", username, password]).await?,
+                None => exchange(&mut stream, &["AUTH", password]).await?,
+            };
+            if let Reply::Error(e) = reply {
+                return Err(Error::new(ErrorKind::PermissionDenied, format!("Redis authentication failed: {}", e)));
+            }
+        }
+        if self.database != 0 {
+            if let Reply::Error(e) = exchange(&mut stream, &["SELECT", &self.database.to_string()]).await? {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Cannot select Redis database {}: {}", self.database, e)));
+            }
+        }
+        Ok(stream)
+    }
+}
+
+/// Writes a command and reads its reply
+async fn exchange(stream: &mut BufStream<TcpStream>, args: &[&str]) -> std::io::Result<Reply> {
+    stream.write_all(&encode(args)).await?;
+    stream.flush().await?;
+    read_reply(stream).await
+}
+
+/// Encodes a command as a RESP array of bulk strings
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg.as_bytes());
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+async fn read_line(stream: &mut BufStream<TcpStream>) -> std::io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Redis closed the connection"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads one reply; the items of an array are read in turn, hence the boxed future
+fn read_reply(stream: &mut BufStream<TcpStream>) -> Pin<Box<dyn Future<Output = std::io::Result<Reply>> + Send + '_>> {
+    Box::pin(async move {
+        let line = read_line(stream).await?;
+        let malformed = || Error::new(ErrorKind::InvalidData, format!("Malformed Redis reply '{}'", line));
+        let (kind, value) = line.split_at_checked(1).ok_or_else(malformed)?;
+        match kind {
+            "+" => Ok(Reply::Status(value.to_string())),
+            "-" => Ok(Reply::Error(value.to_string())),
+            ":" => value.parse().map(Reply::Integer).map_err(|_| malformed()),
+            "$" => {
+                let length: i64 = value.parse().map_err(|_| malformed())?;
+                if length < 0 {
+                    return Ok(Reply::Nil);
+                }
+                let length = length as usize;
+                if length > MAX_BULK_BYTES {
+                    return Err(malformed());
+                }
+                let mut data = vec![0; length + 2];
+                stream.read_exact(&mut data).await?;
+                data.truncate(length);
+                Ok(Reply::Bulk(data))
+            }
+            "*" => {
+                let count: i64 = value.parse().map_err(|_| malformed())?;
+                if count < 0 {
+                    return Ok(Reply::Nil);
+                }
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    items.push(read_reply(stream).await?);
+                }
+                Ok(Reply::Array(items))
+            }
+            _ => Err(malformed()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url_and_encode() {
+        let client = RedisClient::new("redis://:p%40ss@redis.internal:6380/2").unwrap();
+        assert_eq!((client.host.as_str(), client.port, client.database), ("redis.internal", 6380, 2));
+        assert_eq!((client.username, client.password.as_deref()), (None, Some("p@ss")));
+        let client = RedisClient::new("redis://[::1]").unwrap();
+        assert_eq!((client.host.as_str(), client.port), ("::1", 6379));
+        assert!(RedisClient::new("rediss://redis.internal").is_err());
+        assert!(RedisClient::new("redis://redis.internal:port").is_err());
+
+        assert_eq!(encode(&["GET", "a:b"]), b"*2\r\n$3\r\nGET\r\n$3\r\na:b\r\n");
+    }
+}
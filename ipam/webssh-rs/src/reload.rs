@@ -0,0 +1,199 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::settings::Settings;
+use crate::tasks::RestartPolicy;
+use crate::AppState;
+
+/// Settings applied while running, as dotted paths; a change anywhere below one is applied
+///
+/// Each is read afresh where it is used, so a change takes effect for the
+/// next connection, cleanup pass or request. Sessions already open keep the
+/// timeouts and algorithms they were connected with.
+const RELOADABLE: &[&str] = &[
+    "ssh.connection",
+    "ssh.crypto",
+    "session_cleanup",
+    "session_lifetime",
+    "command_policy",
+    "authorization",
+    "rate_limit",
+    "exec",
+    "reconnect.grace_seconds",
+];
+
+/// The settings a reload found changed
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ReloadReport {
+    /// Now in force
+    pub applied: Vec<String>,
+    /// Changed in the file, but only read at startup; the gateway keeps the old value until restarted
+    pub requires_restart: Vec<String>,
+}
+
+impl ReloadReport {
+    /// Compares the settings in force with those read from the file
+    pub fn compare(current: &Settings, loaded: &Settings) -> Result<Self, String> {
+        let mut changed = Vec::new();
+        changed_paths(&to_value(current)?, &to_value(loaded)?, "", &mut changed);
+        let (applied, requires_restart) = changed.into_iter().partition(|path| is_reloadable(path));
+        Ok(Self { applied, requires_restart })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.requires_restart.is_empty()
+    }
+}
+
+/// Copies the applied paths of a report from the loaded settings
+pub fn apply(settings: &Settings, loaded: &Settings, report: &ReloadReport) -> Result<Settings, String> {
+    let mut target = to_value(settings)?;
+    let source = to_value(loaded)?;
+    for path in &report.applied {
+        let pointer = format!("/{}", path.replace('.', "/"));
+        if let (Some(slot), Some(value)) = (target.pointer_mut(&pointer), source.pointer(&pointer)) {
+            *slot = value.clone();
+        }
+    }
+    serde_json::from_value(target).map_err(|e| e.to_string())
+}
+
+fn to_value(settings: &Settings) -> Result<Value, String> {
+    serde_json::to_value(settings).map_err(|e| e.to_string())
+}
+
+fn is_reloadable(path: &str) -> bool {
+    RELOADABLE.iter().any(|prefix| {
+        path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// Collects the dotted paths of the leaves that differ; arrays are compared whole
+fn changed_paths(old: &Value, new: &Value, path: &str, changed: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys: Vec<&String> = old.keys().chain(new.keys().filter(|key| !old.contains_key(*key))).collect();
+            keys.sort();
+            for key in keys {
+                let child = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                changed_paths(old.get(key).unwrap_or(&Value::Null), new.get(key).unwrap_or(&Value::Null), &child, changed);
+            }
+        }
+        (old, new) if old != new => changed.push(path.to_string()),
+        _ => {}
+    }
+}
+
+/// Reads the settings file again and applies the settings that can change while running
+///
+/// The command line's overrides are applied to the file as at startup. A
+/// file that cannot be read or parsed changes nothing.
+pub fn reload(state: &AppState) -> Result<ReloadReport, String> {
+    let loaded = state.settings_source.reload()?;
+    let report = state.policy.reload(&loaded)?;
+    let file = state.settings_source.config.display();
+    if report.is_empty() {
+        info!("Reloaded {}: no changes", file);
+        return Ok(report);
+    }
+    if !report.applied.is_empty() {
+        info!("Reloaded {}: applied {}", file, report.applied.join(", "));
+    }
+    if !report.requires_restart.is_empty() {
+        warn!("Reloaded {}: changes to {} take effect after a restart", file, report.requires_restart.join(", "));
+    }
+    Ok(report)
+}
+
+/// Reloads the settings whenever the settings file changes
+pub fn watch(state: AppState, interval_seconds: u64) {
+    if interval_seconds == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(interval_seconds);
+    let path = state.settings_source.config.clone();
+    info!("Reloading {} on change, checking every {:?}", path.display(), interval);
+
+    let tasks = state.tasks.clone();
+    tasks.spawn("settings_reload", RestartPolicy::OnPanic, move |task| {
+        let state = state.clone();
+        let path = path.clone();
+        async move {
+            let mut last_modified = modified(&path);
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let current = modified(&path);
+                if current != last_modified {
+                    last_modified = current;
+                    if let Err(e) = reload(&state) {
+                        error!("Failed to reload settings, keeping the current ones: {}", e);
+                    }
+                }
+                task.ran();
+            }
+        }
+    });
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reads the settings file again and reports what changed
+pub async fn reload_handler(State(state): State<AppState>) -> Response {
+    match reload(&state) {
+        Ok(report) => Json(json!({
+            "success": true,
+            "applied": report.applied,
+            "requires_restart": report.requires_restart,
+        })).into_response(),
+        Err(e) => {
+            warn!("Settings reload refused: {}", e);
+            (StatusCode::BAD_REQUEST, Json(json!({
+                "error": "invalid_settings",
+                "message": e,
+            }))).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_reloadable_changes_are_applied() {
+        let current = Settings::default();
+        let mut loaded = Settings::default();
+        loaded.ssh.connection.timeout_seconds = 5;
+        loaded.ssh.crypto.kex_algorithms = "curve25519-sha256".to_string();
+        loaded.rate_limit.per_source = 3;
+        loaded.reconnect.grace_seconds = 10;
+        loaded.reconnect.buffer_bytes = 1024;
+        loaded.server.port = 9999;
+
+        let report = ReloadReport::compare(&current, &loaded).unwrap();
+        assert_eq!(report.applied, vec![
+            "rate_limit.per_source", "reconnect.grace_seconds", "ssh.connection.timeout_seconds", "ssh.crypto.kex_algorithms",
+        ]);
+        assert_eq!(report.requires_restart, vec!["reconnect.buffer_bytes", "server.port"]);
+
+        let reloaded = apply(&current, &loaded, &report).unwrap();
+        assert_eq!(reloaded.ssh.connection.timeout_seconds, 5);
+        assert_eq!(reloaded.rate_limit.per_source, 3);
+        assert_eq!(reloaded.reconnect.grace_seconds, 10);
+        assert_eq!(reloaded.reconnect.buffer_bytes, current.reconnect.buffer_bytes);
+        assert_eq!(reloaded.server.port, current.server.port);
+        assert!(ReloadReport::compare(&reloaded, &reloaded).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,534 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, Instrument};
+
+use crate::audit::SharedAudit;
+use crate::coalesce::{self, InputStats, InputStatsSnapshot};
+use crate::faults::{self, FaultPath, FaultSlot};
+use crate::password_change::PasswordChange;
+use crate::recording::SharedRecorder;
+use crate::scrollback::Spill;
+use crate::ssh::{Shell, ShellBackend};
+use crate::terminal::TerminalWatch;
+use crate::transform::{self, OutputPipeline};
+
+/// Smallest buffer allowed, so live output always fits
+const MIN_BUFFER_BYTES: usize = 4096;
+
+/// Most spilled output replayed to a WebSocket at once
+const REPLAY_CHUNK_BYTES: usize = 256 * 1024;
+
+/// The most recent output of a shell, addressed by absolute byte offsets
+///
+/// Offsets count every byte the shell has produced, so a client that knows
+/// how much it has received can ask for exactly what it missed. With a
+/// spill, bytes evicted from memory move to disk rather than being dropped.
+pub struct OutputBuffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+    // Offset of the first byte still held in memory
+    start: u64,
+    spill: Option<Spill>,
+    // The screen the output draws, for snapshots sent to viewers that fall behind
+    screen: Option<vt100::Parser>,
+}
+
+/// Output read back from the buffer
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub data: Vec<u8>,
+    /// Offset of the first returned byte
+    pub start: u64,
+    /// Bytes asked for that have already been dropped from the buffer
+    pub skipped: u64,
+}
+
+impl Replay {
+    /// Offset following the returned data
+    pub fn next(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+impl OutputBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self { data: VecDeque::new(), capacity: capacity.max(MIN_BUFFER_BYTES), start: 0, spill: None, screen: None }
+    }
+
+    /// Keeps output evicted from memory in the given spill
+    pub fn with_spill(mut self, spill: Spill) -> Self {
+        self.spill = Some(spill);
+        self
+    }
+
+    /// Tracks the screen the output draws, starting at the given size
+    pub fn with_screen(mut self, rows: u16, cols: u16) -> Self {
+        self.screen = Some(vt100::Parser::new(rows, cols, 0));
+        self
+    }
+
+    /// Appends output, moving the oldest bytes beyond the capacity to the spill or dropping them
+    pub fn push(&mut self, data: &[u8]) {
+        if let Some(screen) = self.screen.as_mut() {
+            screen.process(data);
+        }
+        self.data.extend(data);
+        let excess = self.data.len().saturating_sub(self.capacity);
+        if excess > 0 {
+            self.spill_front(excess);
+            self.data.drain(..excess);
+            self.start += excess as u64;
+        }
+    }
+
+    fn spill_front(&mut self, len: usize) {
+        let Some(spill) = self.spill.as_mut() else {
+            return;
+        };
+        let (front, back) = self.data.as_slices();
+        let from_front = len.min(front.len());
+        let written = spill.append(self.start, &front[..from_front])
+            .and_then(|_| spill.append(self.start + from_front as u64, &back[..len - from_front]));
+        if let Err(e) = written {
+            error!("Failed to spill scrollback to disk, keeping only the recent output: {}", e);
+            self.spill = None;
+        }
+    }
+
+    /// Offset of the oldest byte held in memory
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Offset of the oldest byte held, in memory or on disk
+    pub fn oldest(&self) -> u64 {
+        self.spill.as_ref().map(Spill::start).unwrap_or(self.start)
+    }
+
+    /// Offset following the newest byte
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Follows a resize of the terminal
+    pub fn resize_screen(&mut self, rows: u16, cols: u16) {
+        if let Some(screen) = self.screen.as_mut() {
+            screen.set_size(rows, cols);
+        }
+    }
+
+    /// Renders the screen as escape sequences that redraw it from scratch
+    ///
+    /// # Returns
+    /// * `Option<(u64, Vec<u8>)>` - The offset the screen is drawn up to and its rendering,
+    ///   or `None` if the screen is not tracked
+    pub fn snapshot(&self) -> Option<(u64, Vec<u8>)> {
+        self.screen.as_ref().map(|screen| (self.end(), screen.screen().state_formatted()))
+    }
+
+    /// Reads the text of a rectangle of the screen, one string per row, trailing blanks trimmed
+    ///
+    /// The rectangle is clipped to the screen, so a row or column past its edge reads nothing.
+    ///
+    /// # Returns
+    /// * `Option<(u64, Vec<String>)>` - The offset the screen is drawn up to and the rows,
+    ///   or `None` if the screen is not tracked
+    pub fn screen_rows(&self, top: u16, left: u16, rows: u16, cols: u16) -> Option<(u64, Vec<String>)> {
+        let screen = self.screen.as_ref()?.screen();
+        let text = screen.rows(left, cols)
+            .skip(top.into())
+            .take(rows.into())
+            .map(|row| row.trim_end().to_string())
+            .collect();
+        Some((self.end(), text))
+    }
+
+    /// Whether a full-screen application has the terminal on its alternate screen
+    pub fn fullscreen(&self) -> bool {
+        self.screen.as_ref().is_some_and(|screen| screen.screen().alternate_screen())
+    }
+
+    /// Reads everything from the given offset onwards
+    ///
+    /// Spilled output is returned a chunk at a time, so a client resuming
+    /// far back keeps reading until `next()` reaches `end()`.
+    pub fn read_from(&self, offset: u64) -> Replay {
+        self.read(offset, self.capacity.max(REPLAY_CHUNK_BYTES))
+    }
+
+    /// Reads up to `limit` bytes from the given offset, from disk and memory
+    pub fn read(&self, offset: u64, limit: usize) -> Replay {
+        let oldest = self.oldest();
+        let start = offset.clamp(oldest, self.end());
+        let mut data = Vec::new();
+
+        if let Some(spill) = self.spill.as_ref().filter(|_| start < self.start) {
+            let wanted = ((self.start - start) as usize).min(limit);
+            match spill.read(start, wanted) {
+                Ok(spilled) => data = spilled,
+                Err(e) => {
+                    // Continue from memory rather than fail the whole read
+                    error!("Failed to read spilled scrollback: {}", e);
+                    return self.read(self.start, limit);
+                }
+            }
+        }
+        if data.len() < limit && start + data.len() as u64 >= self.start {
+            let memory_start = (start + data.len() as u64).max(self.start);
+            let skip = (memory_start - self.start) as usize;
+            data.extend(self.data.range(skip..).take(limit - data.len()));
+        }
+
+        Replay { data, start, skipped: oldest.saturating_sub(offset) }
+    }
+}
+
+/// When a session's shells last had input from a user and output from the device
+///
+/// Looking a session up does not count; only traffic does, so a session
+/// polled by the status APIs still goes idle.
+#[derive(Debug)]
+pub struct ShellActivity {
+    // Milliseconds since the epoch
+    last_input: AtomicI64,
+    last_output: AtomicI64,
+}
+
+impl Default for ShellActivity {
+    fn default() -> Self {
+        let now = Utc::now().timestamp_millis();
+        Self { last_input: AtomicI64::new(now), last_output: AtomicI64::new(now) }
+    }
+}
+
+impl ShellActivity {
+    /// Notes input from a user, or a WebSocket attaching to the shell
+    pub fn input(&self) {
+        self.last_input.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn output(&self) {
+        self.last_output.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn last_input(&self) -> DateTime<Utc> {
+        from_millis(self.last_input.load(Ordering::Relaxed))
+    }
+
+    pub fn last_output(&self) -> DateTime<Utc> {
+        from_millis(self.last_output.load(Ordering::Relaxed))
+    }
+
+    /// The last sign of use: input, and output too if it counts as activity
+    pub fn last_active(&self, output_counts: bool) -> DateTime<Utc> {
+        if output_counts {
+            self.last_input().max(self.last_output())
+        } else {
+            self.last_input()
+        }
+    }
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+/// A shell whose output outlives the WebSockets attached to it
+///
+/// The I/O pump runs on its own task and output goes to an [`OutputBuffer`],
+/// so a client that drops its WebSocket can reattach and have missed output
+/// replayed. Attached WebSockets are woken through a watch on the end offset.
+pub struct ShellStream {
+    input_tx: mpsc::Sender<Bytes>,
+    resize_tx: mpsc::Sender<(u32, u32)>,
+    buffer: Arc<Mutex<OutputBuffer>>,
+    offsets: watch::Receiver<u64>,
+    recorder: Option<SharedRecorder>,
+    audit: Option<SharedAudit>,
+    shutdown: CancellationToken,
+    // None if input coalescing is disabled
+    input_stats: Option<Arc<InputStats>>,
+    activity: Arc<ShellActivity>,
+    faults: FaultSlot,
+    password_change: Option<Arc<PasswordChange>>,
+}
+
+/// What looks at a shell's output as it arrives, besides the buffer and the recording
+#[derive(Default)]
+pub struct OutputWatches {
+    pub terminal: Option<TerminalWatch>,
+    pub password_change: Option<Arc<PasswordChange>>,
+    /// The session's output stages, with where the watches and the recording sit among them
+    pub pipeline: OutputPipeline,
+}
+
+impl ShellStream {
+    /// Starts pumping the shell's I/O into the given buffer
+    ///
+    /// Output is recorded here rather than per WebSocket, so nothing is lost
+    /// from the recording while no client is attached.
+    pub fn start(
+        mut session: Shell,
+        buffer: OutputBuffer,
+        recorder: Option<SharedRecorder>,
+        audit: Option<SharedAudit>,
+        watches: OutputWatches,
+        activity: Arc<ShellActivity>,
+        session_id: &str,
+    ) -> Arc<Self> {
+        let (input_tx, mut input_rx) = mpsc::channel::<Bytes>(32);
+        let coalescing = session.handle().target().settings.input_coalescing.clone();
+        let input_stats = coalescing.enabled.then(|| Arc::new(InputStats::default()));
+        if let Some(stats) = &input_stats {
+            input_rx = coalesce::start(input_rx, &coalescing, stats.clone(), session_id);
+        }
+        let faults = FaultSlot::default();
+        #[cfg(feature = "fault-injection")]
+        {
+            input_rx = faults::relay(input_rx, faults.clone());
+        }
+        let (output_tx, mut output_rx) = mpsc::channel::<Bytes>(32);
+        let (resize_tx, mut resize_rx) = mpsc::channel::<(u32, u32)>(8);
+        let (shell_resize_tx, shell_resize_rx) = mpsc::channel::<(u32, u32)>(8);
+        session.set_resize_channel(shell_resize_rx);
+        let shutdown = session.shutdown_token();
+
+        let pump_session_id = session_id.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = session.run_io(input_rx, output_tx).await {
+                error!("SSH I/O error for session {}: {}", pump_session_id, e);
+            }
+        }.in_current_span());
+
+        let buffer = Arc::new(Mutex::new(buffer));
+        let (offset_tx, offsets) = watch::channel(0);
+        let output_buffer = buffer.clone();
+        let output_session_id = session_id.to_string();
+        let output_activity = activity.clone();
+        let output_faults = faults.clone();
+        let OutputWatches { terminal, password_change, pipeline } = watches;
+        let mut stages = pipeline.assemble(recorder.clone(), terminal, password_change.clone());
+        tokio::spawn(async move {
+            while let Some(data) = output_rx.recv().await {
+                if faults::inject(&output_faults, FaultPath::Ssh).await {
+                    continue;
+                }
+                output_activity.output();
+                let data = transform::run(&mut stages, data);
+                if data.is_empty() {
+                    continue;
+                }
+                if let Ok(mut buffer) = output_buffer.lock() {
+                    buffer.push(&data);
+                    offset_tx.send_replace(buffer.end());
+                }
+            }
+            debug!("[Session {}] Shell output ended", output_session_id);
+        }.in_current_span());
+
+        // Resizes pass through here so the tracked screen keeps the terminal's size
+        let resize_buffer = buffer.clone();
+        tokio::spawn(async move {
+            while let Some((rows, cols)) = resize_rx.recv().await {
+                if let Ok(mut buffer) = resize_buffer.lock() {
+                    buffer.resize_screen(rows.min(u16::MAX.into()) as u16, cols.min(u16::MAX.into()) as u16);
+                }
+                if shell_resize_tx.send((rows, cols)).await.is_err() {
+                    break;
+                }
+            }
+        }.in_current_span());
+
+        Arc::new(Self { input_tx, resize_tx, buffer, offsets, recorder, audit, shutdown, input_stats, activity, faults, password_change })
+    }
+
+    pub fn input_sender(&self) -> mpsc::Sender<Bytes> {
+        self.input_tx.clone()
+    }
+
+    /// How input was coalesced into channel writes; `None` if coalescing is disabled
+    pub fn input_stats(&self) -> Option<InputStatsSnapshot> {
+        self.input_stats.as_ref().map(|stats| stats.snapshot())
+    }
+
+    /// The activity of the session the shell belongs to, for input to be noted
+    pub fn activity(&self) -> Arc<ShellActivity> {
+        self.activity.clone()
+    }
+
+    /// The password change the device demanded of the shell's user, while watched for
+    pub fn password_change(&self) -> Option<Arc<PasswordChange>> {
+        self.password_change.clone()
+    }
+
+    /// The faults injected into the shell's traffic, in builds with fault injection
+    pub fn faults(&self) -> FaultSlot {
+        self.faults.clone()
+    }
+
+    pub fn resize_sender(&self) -> mpsc::Sender<(u32, u32)> {
+        self.resize_tx.clone()
+    }
+
+    pub fn recorder(&self) -> Option<SharedRecorder> {
+        self.recorder.clone()
+    }
+
+    /// The command audit of this shell, kept apart from other shells of the session
+    pub fn audit(&self) -> Option<SharedAudit> {
+        self.audit.clone()
+    }
+
+    /// Watches the end offset; closed once the shell has ended and all output is buffered
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.offsets.clone()
+    }
+
+    /// Offset of the output replayed to a newly attached WebSocket
+    pub fn start_offset(&self) -> u64 {
+        self.buffer.lock().map(|buffer| buffer.start()).unwrap_or(0)
+    }
+
+    /// Offset of the oldest output still held, including what was spilled to disk
+    pub fn oldest_offset(&self) -> u64 {
+        self.buffer.lock().map(|buffer| buffer.oldest()).unwrap_or(0)
+    }
+
+    /// Offset following the newest output
+    pub fn end_offset(&self) -> u64 {
+        self.buffer.lock().map(|buffer| buffer.end()).unwrap_or(0)
+    }
+
+    /// Whether the shell's screen is tracked, so snapshots can be taken
+    pub fn tracks_screen(&self) -> bool {
+        self.buffer.lock().is_ok_and(|buffer| buffer.screen.is_some())
+    }
+
+    /// Whether a full-screen application is drawing the shell's screen, if it is tracked
+    pub fn in_fullscreen(&self) -> bool {
+        self.buffer.lock().is_ok_and(|buffer| buffer.fullscreen())
+    }
+
+    /// Reads the text of a rectangle of the shell's screen, if it is tracked
+    pub fn screen_rows(&self, top: u16, left: u16, rows: u16, cols: u16) -> Option<(u64, Vec<String>)> {
+        self.buffer.lock().ok().and_then(|buffer| buffer.screen_rows(top, left, rows, cols))
+    }
+
+    /// Renders the shell's screen as of the newest output
+    pub fn snapshot(&self) -> Option<(u64, Vec<u8>)> {
+        self.buffer.lock().ok().and_then(|buffer| buffer.snapshot())
+    }
+
+    /// Reads buffered output from the given offset onwards
+    pub fn read_from(&self, offset: u64) -> Replay {
+        match self.buffer.lock() {
+            Ok(buffer) => buffer.read_from(offset),
+            Err(_) => Replay { data: Vec::new(), start: offset, skipped: 0 },
+        }
+    }
+
+    /// Reads up to `limit` bytes of output, including spilled output, from the given offset
+    pub fn read(&self, offset: u64, limit: usize) -> Replay {
+        match self.buffer.lock() {
+            Ok(buffer) => buffer.read(offset, limit),
+            Err(_) => Replay { data: Vec::new(), start: offset, skipped: 0 },
+        }
+    }
+
+    /// Gets the token cancelled when the shell shuts down
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Stops the I/O pump, which then closes the shell
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_from_offset() {
+        let mut buffer = OutputBuffer::new(MIN_BUFFER_BYTES);
+        buffer.push(b"hello ");
+        buffer.push(b"world");
+
+        let replay = buffer.read_from(6);
+        assert_eq!(replay.data, b"world");
+        assert_eq!((replay.start, replay.skipped, replay.next()), (6, 0, 11));
+
+        // Offsets past the end are clamped rather than rejected
+        let replay = buffer.read_from(100);
+        assert!(replay.data.is_empty());
+        assert_eq!(replay.next(), 11);
+    }
+
+    #[test]
+    fn test_replay_reports_dropped_output() {
+        let mut buffer = OutputBuffer::new(MIN_BUFFER_BYTES);
+        buffer.push(&vec![b'a'; MIN_BUFFER_BYTES]);
+        buffer.push(b"tail");
+
+        assert_eq!(buffer.start(), 4);
+        assert_eq!(buffer.end(), MIN_BUFFER_BYTES as u64 + 4);
+
+        let replay = buffer.read_from(0);
+        assert_eq!(replay.skipped, 4);
+        assert_eq!(replay.start, 4);
+        assert!(replay.data.ends_with(b"tail"));
+    }
+
+    #[test]
+    fn test_spilled_output_stays_readable() {
+        let dir = std::env::temp_dir().join(format!("webssh-replay-{}", uuid::Uuid::new_v4()));
+        let spill = Spill::create(dir, 4096, 1024 * 1024).unwrap();
+        let mut buffer = OutputBuffer::new(MIN_BUFFER_BYTES).with_spill(spill);
+        let output: Vec<u8> = (0..3 * MIN_BUFFER_BYTES as u32).map(|i| (i % 251) as u8).collect();
+        for chunk in output.chunks(1000) {
+            buffer.push(chunk);
+        }
+
+        assert_eq!(buffer.start(), 2 * MIN_BUFFER_BYTES as u64);
+        assert_eq!(buffer.oldest(), 0);
+        let replay = buffer.read(0, usize::MAX);
+        assert_eq!((replay.skipped, replay.next()), (0, buffer.end()));
+        assert_eq!(replay.data, output);
+
+        // Reads spanning disk and memory are limited, so replay is paged
+        let replay = buffer.read(2 * MIN_BUFFER_BYTES as u64 - 10, 20);
+        assert_eq!(replay.data, &output[2 * MIN_BUFFER_BYTES - 10..2 * MIN_BUFFER_BYTES + 10]);
+    }
+
+    #[test]
+    fn test_snapshot_redraws_the_screen() {
+        assert!(OutputBuffer::new(MIN_BUFFER_BYTES).snapshot().is_none());
+
+        let mut buffer = OutputBuffer::new(MIN_BUFFER_BYTES).with_screen(4, 20);
+        buffer.push(b"line one\r\nline two\x1b[1;6HONE");
+        buffer.resize_screen(4, 10);
+        let (offset, rendering) = buffer.snapshot().unwrap();
+        assert_eq!(offset, buffer.end());
+
+        // Drawing the snapshot on a blank terminal gives the same screen
+        let mut viewer = vt100::Parser::new(4, 10, 0);
+        viewer.process(&rendering);
+        assert_eq!(viewer.screen().contents(), "line ONE\nline two");
+
+        // Full-screen applications draw on the alternate screen
+        assert!(!buffer.fullscreen());
+        buffer.push(b"\x1b[?1049h\x1b[2J");
+        assert!(buffer.fullscreen());
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::session::SessionRegistry;
+use crate::store::{EndReason, RunRecord, SqliteStore};
+
+/// How often the run's metrics are saved, and so how late an unclean stop is noticed
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Days of earlier runs summarized in the startup report
+const REPORT_DAYS: i64 = 7;
+
+/// This run's entry in the run history kept next to the session history
+///
+/// A run that shuts down cleanly records how it was asked to stop. One that
+/// does not is found at the next startup with no end time: it crashed, was
+/// killed or lost its host somewhere after its last heartbeat.
+pub struct RunTracker {
+    store: Arc<SqliteStore>,
+    run_id: i64,
+    started_at: DateTime<Utc>,
+    // Earlier runs within the retention period, newest first
+    previous: Vec<RunRecord>,
+}
+
+impl RunTracker {
+    /// Records the start of this run and notes panics from now on
+    pub fn start(store: Arc<SqliteStore>, retention: chrono::Duration) -> Option<Self> {
+        let mut runs = match store.start_run(env!("CARGO_PKG_VERSION"), retention) {
+            Ok(runs) if !runs.is_empty() => runs,
+            Ok(_) => return None,
+            Err(e) => {
+                error!("Failed to record this run: {}; run history will not be kept", e);
+                return None;
+            }
+        };
+        let current = runs.remove(0);
+
+        let hook_store = store.clone();
+        let run_id = current.run_id;
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            let payload = panic_info.payload();
+            let message = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            let message = match panic_info.location() {
+                Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+                None => message.to_string(),
+            };
+            hook_store.record_panic(run_id, &message);
+            default_hook(panic_info);
+        }));
+
+        Some(Self { store, run_id, started_at: current.started_at, previous: runs })
+    }
+
+    /// Logs how earlier runs ended, and the sessions lost when the last one stopped uncleanly
+    ///
+    /// Call after the registry has recovered its history, which ends the sessions left open.
+    pub fn report(&self, registry: &SessionRegistry) {
+        info!("Starting run {}", self.run_id);
+        for line in summarize(&self.previous, Utc::now()) {
+            info!("{}", line);
+        }
+
+        let lost: Vec<_> = registry.session_history(None).into_iter()
+            .filter(|record| record.end_reason == Some(EndReason::ServiceRestart)
+                && record.ended_at.is_some_and(|ended_at| ended_at >= self.started_at))
+            .collect();
+        if !lost.is_empty() {
+            warn!("{} sessions were lost when the previous run stopped uncleanly:", lost.len());
+            for record in lost {
+                warn!("  {} ({} to {} as {}, created {})", record.session_id, record.portal_user_id,
+                      record.device_id, record.ssh_username, format_time(record.created_at));
+            }
+        }
+    }
+
+    /// Saves the run's metrics every heartbeat
+    pub fn watch(self: &Arc<Self>, registry: Arc<Mutex<SessionRegistry>>) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                tracker.save(&registry).await;
+            }
+        });
+    }
+
+    /// Records a clean shutdown, with the run's final metrics
+    pub async fn finish(&self, registry: &Mutex<SessionRegistry>, shutdown: &str) {
+        self.save(registry).await;
+        match self.store.end_run(self.run_id, shutdown) {
+            Ok(()) => info!("Run {} ended ({})", self.run_id, shutdown),
+            Err(e) => error!("Failed to record the end of run {}: {}", self.run_id, e),
+        }
+    }
+
+    async fn save(&self, registry: &Mutex<SessionRegistry>) {
+        let (peak_sessions, sessions_created) = registry.lock().await.run_metrics();
+        if let Err(e) = self.store.update_run(self.run_id, peak_sessions, sessions_created) {
+            warn!("Failed to save run metrics: {}", e);
+        }
+    }
+}
+
+/// The startup report on earlier runs, newest first
+fn summarize(previous: &[RunRecord], now: DateTime<Utc>) -> Vec<String> {
+    let Some(last) = previous.first() else {
+        return vec!["No earlier runs recorded".to_string()];
+    };
+
+    let mut lines = vec![
+        format!("Previous run {} (version {}) started {}, ran for {}: {}",
+                last.run_id, last.version, format_time(last.started_at),
+                format_duration(last.ended_at.unwrap_or(last.last_seen_at) - last.started_at), describe_exit(last)),
+        format!("Previous run peaked at {} sessions and created {}", last.peak_sessions, last.sessions_created),
+    ];
+
+    let recent: Vec<&RunRecord> = previous.iter()
+        .filter(|run| run.started_at >= now - chrono::Duration::days(REPORT_DAYS))
+        .collect();
+    let unclean: Vec<&&RunRecord> = recent.iter().filter(|run| run.ended_at.is_none()).collect();
+    lines.push(format!("Last {} days: {} restarts, {} of them after an unclean stop",
+                       REPORT_DAYS, recent.len(), unclean.len()));
+    for run in unclean.iter().filter(|run| run.last_panic.is_some()) {
+        lines.push(format!("  run {} last seen {}: {}", run.run_id, format_time(run.last_seen_at), describe_exit(run)));
+    }
+    lines
+}
+
+/// How a run ended, as far as can be told
+fn describe_exit(run: &RunRecord) -> String {
+    match (&run.ended_at, &run.last_panic) {
+        (Some(_), _) => format!("clean shutdown ({})", run.shutdown.as_deref().unwrap_or("unknown")),
+        (None, Some(panic)) => format!("crashed after a panic: {}", panic),
+        (None, None) => format!("stopped uncleanly (killed, out of memory or host failure) after {}", format_time(run.last_seen_at)),
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn format_duration(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes();
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, minutes) => format!("{}m", minutes),
+        (0, hours, minutes) => format!("{}h {}m", hours, minutes),
+        (days, hours, _) => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(run_id: i64, started_at: DateTime<Utc>) -> RunRecord {
+        RunRecord {
+            run_id,
+            version: "0.1.0".to_string(),
+            started_at,
+            last_seen_at: started_at + chrono::Duration::minutes(90),
+            ended_at: None,
+            shutdown: None,
+            last_panic: None,
+            peak_sessions: 12,
+            sessions_created: 40,
+        }
+    }
+
+    #[test]
+    fn test_summary_tells_crashes_from_deploys() {
+        let now = Utc::now();
+        let mut deploy = run(3, now - chrono::Duration::hours(2));
+        deploy.ended_at = Some(deploy.last_seen_at);
+        deploy.shutdown = Some("SIGTERM".to_string());
+        let mut crash = run(2, now - chrono::Duration::days(1));
+        crash.last_panic = Some("index out of bounds at src/main.rs:10".to_string());
+        let killed = run(1, now - chrono::Duration::days(2));
+        let expired = run(0, now - chrono::Duration::days(30));
+
+        let lines = summarize(&[deploy, crash, killed, expired], now);
+        assert!(lines[0].contains("ran for 1h 30m: clean shutdown (SIGTERM)"));
+        assert_eq!(lines[1], "Previous run peaked at 12 sessions and created 40");
+        assert_eq!(lines[2], "Last 7 days: 3 restarts, 2 of them after an unclean stop");
+        assert!(lines[3].contains("crashed after a panic: index out of bounds"));
+        assert_eq!(lines.len(), 4);
+
+        assert_eq!(summarize(&[], now), vec!["No earlier runs recorded"]);
+    }
+}
@@ -0,0 +1,332 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{sink::SinkExt, stream::StreamExt};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+use crate::replay::ShellStream;
+use crate::settings::ScreenSubscriptionSettings;
+use crate::AppState;
+
+/// Compiled size of a field pattern's program; the regex crate matches in linear time
+const PATTERN_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// A rectangle of the screen, from its top left corner; rows and columns past the edge are clipped
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct Region {
+    pub top: u16,
+    pub left: u16,
+    /// Rows from `top`; to the bottom of the screen when left out
+    pub rows: Option<u16>,
+    /// Columns from `left`; to the right edge when left out
+    pub cols: Option<u16>,
+}
+
+/// A value scraped from the region's text
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldSpec {
+    pub name: String,
+    /// Regular expression; the value is its first group, or the whole match if it has none
+    pub pattern: String,
+    /// Report every match as a list rather than the first one
+    #[serde(default)]
+    pub all: bool,
+}
+
+/// What a consumer wants to follow of the screen, sent as the WebSocket's first message
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Subscription {
+    pub region: Region,
+    pub fields: Vec<FieldSpec>,
+    /// Least time between two updates
+    pub interval_ms: Option<u64>,
+    /// Send the region's lines; by default only when no fields are asked for
+    pub lines: Option<bool>,
+}
+
+/// A subscription in force, with what its consumer was last sent
+struct Feed {
+    region: Region,
+    fields: Vec<(String, Regex, bool)>,
+    lines: bool,
+    interval: Duration,
+    // None until the first update, which carries everything
+    sent_lines: Option<Vec<String>>,
+    sent_fields: Map<String, Value>,
+}
+
+impl Feed {
+    /// Checks a subscription against the limits and compiles its patterns
+    fn new(subscription: Subscription, settings: &ScreenSubscriptionSettings) -> Result<Self, String> {
+        if subscription.fields.len() > settings.max_fields {
+            return Err(format!("at most {} fields may be scraped", settings.max_fields));
+        }
+        let mut fields: Vec<(String, Regex, bool)> = Vec::with_capacity(subscription.fields.len());
+        for field in subscription.fields {
+            if field.name.is_empty() || fields.iter().any(|(name, ..)| *name == field.name) {
+                return Err(format!("field names must be given and unique: '{}'", field.name));
+            }
+            if field.pattern.len() > settings.max_pattern_bytes {
+                return Err(format!("the pattern of field '{}' is longer than {} bytes", field.name, settings.max_pattern_bytes));
+            }
+            let pattern = RegexBuilder::new(&field.pattern)
+                .multi_line(true)
+                .size_limit(PATTERN_SIZE_LIMIT)
+                .build()
+                .map_err(|e| format!("invalid pattern for field '{}': {}", field.name, e))?;
+            fields.push((field.name, pattern, field.all));
+        }
+        let interval_ms = subscription.interval_ms.unwrap_or(settings.default_interval_ms).max(settings.min_interval_ms);
+        Ok(Self {
+            region: subscription.region,
+            lines: subscription.lines.unwrap_or(fields.is_empty()),
+            fields,
+            interval: Duration::from_millis(interval_ms),
+            sent_lines: None,
+            sent_fields: Map::new(),
+        })
+    }
+
+    /// Reads the region from the shell's screen
+    fn read(&self, stream: &ShellStream) -> Option<(u64, Vec<String>)> {
+        let Region { top, left, rows, cols } = self.region;
+        stream.screen_rows(top, left, rows.unwrap_or(u16::MAX), cols.unwrap_or(u16::MAX))
+    }
+
+    /// Builds the update for the region's current text, if anything the consumer follows has changed
+    ///
+    /// Only lines and fields that differ from those last sent are included; lines are
+    /// keyed by their row within the region.
+    fn update(&mut self, offset: u64, rows: Vec<String>) -> Option<Value> {
+        let mut update = Map::new();
+
+        let fields = self.scrape(&rows.join("\n"));
+        let changed: Map<String, Value> = fields.iter()
+            .filter(|(name, value)| self.sent_fields.get(*name) != Some(*value) || self.sent_lines.is_none())
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        if !changed.is_empty() {
+            update.insert("fields".to_string(), Value::Object(changed));
+        }
+        self.sent_fields = fields;
+
+        if self.lines {
+            let sent = self.sent_lines.as_deref().unwrap_or_default();
+            let changed: Map<String, Value> = rows.iter().enumerate()
+                .filter(|(row, line)| self.sent_lines.is_none() || sent.get(*row) != Some(*line))
+                .map(|(row, line)| (row.to_string(), Value::String(line.clone())))
+                .collect();
+            if !changed.is_empty() {
+                update.insert("lines".to_string(), Value::Object(changed));
+            }
+            if self.sent_lines.is_none() || sent.len() != rows.len() {
+                update.insert("rows".to_string(), json!(rows.len()));
+            }
+        }
+        let first = self.sent_lines.is_none();
+        self.sent_lines = Some(rows);
+
+        if update.is_empty() && !first {
+            return None;
+        }
+        update.insert("type".to_string(), json!("screen"));
+        update.insert("offset".to_string(), json!(offset));
+        Some(Value::Object(update))
+    }
+
+    /// The value of every field in the text; null for a field that does not match
+    fn scrape(&self, text: &str) -> Map<String, Value> {
+        self.fields.iter()
+            .map(|(name, pattern, all)| {
+                let value = |captures: regex::Captures| {
+                    captures.get(1).or_else(|| captures.get(0))
+                        .map_or(Value::Null, |found| Value::String(found.as_str().to_string()))
+                };
+                let found = if *all {
+                    Value::Array(pattern.captures_iter(text).map(value).collect())
+                } else {
+                    pattern.captures(text).map_or(Value::Null, value)
+                };
+                (name.clone(), found)
+            })
+            .collect()
+    }
+}
+
+/// Upgrades a WebSocket that follows a region or scraped fields of a session's screen
+///
+/// The consumer sends a [`Subscription`] and gets compact updates, at most one per
+/// interval and only when something it follows has changed, until the shell ends.
+pub async fn subscribe_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let settings = state.settings.screen_subscriptions.clone();
+    if !settings.enabled {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "error": "screen_subscriptions_disabled",
+            "message": "Screen subscriptions are not enabled on this instance",
+        }))).into_response();
+    }
+    let session_id = session_id.trim().to_string();
+    let mut registry = state.session_registry.lock().await;
+    if registry.get_session(&session_id).is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "error": "session_not_found",
+            "message": format!("Session '{}' not found", session_id),
+        }))).into_response();
+    }
+    let stream = registry.stream(&session_id, &state.scrollback).filter(|stream| stream.tracks_screen());
+    drop(registry);
+    let Some(stream) = stream else {
+        return (StatusCode::CONFLICT, Json(json!({
+            "error": "screen_not_tracked",
+            "message": "The session's screen is not tracked, e.g. because it is in pass-through mode",
+        }))).into_response();
+    };
+
+    info!("Screen subscription to session {}", session_id);
+    ws.on_upgrade(move |socket| follow(socket, stream, settings, session_id))
+}
+
+/// Sends the consumer updates of its subscription until either side ends
+async fn follow(socket: WebSocket, stream: Arc<ShellStream>, settings: ScreenSubscriptionSettings, session_id: String) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut offsets = stream.subscribe();
+    let mut feed: Option<Feed> = None;
+    // Set when the screen has changed since the last update
+    let mut pending = false;
+    let mut next_update = Instant::now();
+
+    loop {
+        tokio::select! {
+            message = receiver.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                // A new subscription replaces the old one and is answered with the full region
+                let subscribed = serde_json::from_str::<Subscription>(&text)
+                    .map_err(|e| format!("invalid subscription: {}", e))
+                    .and_then(|subscription| Feed::new(subscription, &settings));
+                let reply = match subscribed {
+                    Ok(subscribed) => {
+                        let reply = json!({ "type": "subscribed", "interval_ms": subscribed.interval.as_millis() as u64 });
+                        feed = Some(subscribed);
+                        pending = true;
+                        next_update = Instant::now();
+                        reply
+                    }
+                    Err(message) => json!({ "type": "error", "message": message }),
+                };
+                if sender.send(Message::Text(reply.to_string())).await.is_err() {
+                    break;
+                }
+            }
+            changed = offsets.changed(), if feed.is_some() && !pending => {
+                if changed.is_err() {
+                    // The shell has ended and all its output is on the screen
+                    if let Some(update) = feed.as_mut().and_then(|feed| feed.read(&stream).and_then(|(offset, rows)| feed.update(offset, rows))) {
+                        let _ = sender.send(Message::Text(update.to_string())).await;
+                    }
+                    let _ = sender.send(Message::Text(json!({ "type": "ended" }).to_string())).await;
+                    break;
+                }
+                pending = true;
+            }
+            _ = tokio::time::sleep_until(next_update), if pending => {
+                pending = false;
+                let Some(feed) = feed.as_mut() else {
+                    continue;
+                };
+                next_update = Instant::now() + feed.interval;
+                let update = feed.read(&stream).and_then(|(offset, rows)| feed.update(offset, rows));
+                if let Some(update) = update {
+                    if sender.send(Message::Text(update.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let _ = sender.close().await;
+    debug!("Screen subscription to session {} ended", session_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(subscription: Value) -> Feed {
+        Feed::new(serde_json::from_value(subscription).unwrap(), &ScreenSubscriptionSettings::default()).unwrap()
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_updates_carry_only_changes() {
+        let mut feed = feed(json!({ "region": { "top": 1, "rows": 3 } }));
+        assert!(feed.lines);
+
+        let first = feed.update(10, lines("a\nb\nc")).unwrap();
+        assert_eq!(first, json!({ "type": "screen", "offset": 10, "rows": 3, "lines": { "0": "a", "1": "b", "2": "c" } }));
+
+        assert!(feed.update(12, lines("a\nb\nc")).is_none());
+        let update = feed.update(14, lines("a\nB\nc")).unwrap();
+        assert_eq!(update, json!({ "type": "screen", "offset": 14, "lines": { "1": "B" } }));
+    }
+
+    #[test]
+    fn test_fields_are_scraped() {
+        let mut feed = feed(json!({
+            "fields": [
+                { "name": "input_rate", "pattern": r"input rate (\d+) bits/sec" },
+                { "name": "interfaces", "pattern": r"^(\S+) is up", "all": true },
+                { "name": "crc", "pattern": r"\d+ CRC" },
+            ],
+            "interval_ms": 10,
+        }));
+        assert!(!feed.lines);
+        assert_eq!(feed.interval, Duration::from_millis(200));
+
+        let screen = lines("Gi0/1 is up, line protocol is up\n  5 minute input rate 1000 bits/sec\nGi0/2 is up");
+        let first = feed.update(1, screen).unwrap();
+        assert_eq!(first["fields"], json!({ "input_rate": "1000", "interfaces": ["Gi0/1", "Gi0/2"], "crc": null }));
+
+        let screen = lines("Gi0/1 is up, line protocol is up\n  5 minute input rate 2000 bits/sec\nGi0/2 is up\n  0 CRC");
+        let update = feed.update(2, screen).unwrap();
+        assert_eq!(update["fields"], json!({ "input_rate": "2000", "crc": "0 CRC" }));
+        assert!(update.get("lines").is_none());
+    }
+
+    #[test]
+    fn test_limits() {
+        let settings = ScreenSubscriptionSettings { max_fields: 1, ..Default::default() };
+        let two = serde_json::from_value(json!({ "fields": [
+            { "name": "a", "pattern": "a" },
+            { "name": "b", "pattern": "b" },
+        ] })).unwrap();
+        assert!(Feed::new(two, &settings).is_err());
+        let invalid = serde_json::from_value(json!({ "fields": [{ "name": "a", "pattern": "(" }] })).unwrap();
+        assert!(Feed::new(invalid, &settings).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use tracing::{error, info, warn};
+
+use crate::replay::OutputBuffer;
+use crate::settings::Settings;
+use crate::ssh::TerminalSize;
+use crate::AppState;
+
+/// Default and largest amount of output returned by one scrollback request
+const DEFAULT_READ_BYTES: usize = 1024 * 1024;
+const MAX_READ_BYTES: usize = 16 * 1024 * 1024;
+
+/// Output evicted from a session's memory buffer, kept in files on disk
+///
+/// The output is appended to fixed-size segment files; the index maps the
+/// offset each segment starts at to its file, so any offset is one seek away.
+/// Whole segments are deleted from the front once the spill exceeds its limit.
+/// The session's directory is removed when the spill is dropped.
+pub struct Spill {
+    dir: PathBuf,
+    segments: VecDeque<Segment>,
+    // Open for appending: the last segment
+    file: Option<File>,
+    segment_bytes: u64,
+    max_bytes: u64,
+    // Offset following the last spilled byte
+    end: u64,
+}
+
+struct Segment {
+    start: u64,
+    len: u64,
+    path: PathBuf,
+}
+
+impl Spill {
+    pub(crate) fn create(dir: PathBuf, segment_bytes: u64, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            segments: VecDeque::new(),
+            file: None,
+            segment_bytes: segment_bytes.max(4096),
+            max_bytes: max_bytes.max(segment_bytes),
+            end: 0,
+        })
+    }
+
+    /// Offset of the oldest byte still on disk
+    pub fn start(&self) -> u64 {
+        self.segments.front().map(|segment| segment.start).unwrap_or(self.end)
+    }
+
+    /// Appends output that starts at `offset`, which must follow what is already spilled
+    pub fn append(&mut self, offset: u64, mut data: &[u8]) -> io::Result<()> {
+        if self.segments.is_empty() {
+            self.end = offset;
+        } else if offset != self.end {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "spilled output is not contiguous"));
+        }
+
+        while !data.is_empty() {
+            let full = self.segments.back().map(|segment| segment.len >= self.segment_bytes).unwrap_or(true);
+            if full || self.file.is_none() {
+                let path = self.dir.join(format!("{:020}.out", self.end));
+                self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+                self.segments.push_back(Segment { start: self.end, len: 0, path });
+            }
+            let (Some(segment), Some(file)) = (self.segments.back_mut(), self.file.as_mut()) else {
+                break;
+            };
+            let room = (self.segment_bytes - segment.len).min(data.len() as u64) as usize;
+            file.write_all(&data[..room])?;
+            segment.len += room as u64;
+            self.end += room as u64;
+            data = &data[room..];
+        }
+
+        while self.end - self.start() > self.max_bytes && self.segments.len() > 1 {
+            if let Some(segment) = self.segments.pop_front() {
+                let _ = fs::remove_file(&segment.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads up to `limit` bytes starting at `offset`, clamped to what is on disk
+    pub fn read(&self, offset: u64, limit: usize) -> io::Result<Vec<u8>> {
+        let offset = offset.clamp(self.start(), self.end);
+        let mut data = Vec::with_capacity(limit.min((self.end - offset) as usize));
+        let first = self.segments.partition_point(|segment| segment.start + segment.len <= offset);
+
+        for segment in self.segments.iter().skip(first) {
+            if data.len() >= limit {
+                break;
+            }
+            let position = (offset + data.len() as u64).saturating_sub(segment.start);
+            let wanted = (segment.len - position).min((limit - data.len()) as u64);
+            let mut file = File::open(&segment.path)?;
+            file.seek(SeekFrom::Start(position))?;
+            file.take(wanted).read_to_end(&mut data)?;
+        }
+        Ok(data)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        self.file = None;
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove scrollback spill {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Creates the output buffers of sessions, with a disk tier when enabled
+pub struct ScrollbackStore {
+    buffer_bytes: usize,
+    // Directory of this instance's spills; None if spilling is disabled
+    root: Option<PathBuf>,
+    segment_bytes: u64,
+    max_bytes: u64,
+    // Size of the screen tracked for summarized and held-back viewers and screen subscriptions
+    // when the shell's is not known; None if none of them is enabled
+    screen: Option<(u16, u16)>,
+}
+
+impl ScrollbackStore {
+    /// Prepares the spill directory, clearing spills left by an earlier run of this node
+    pub fn new(settings: &Settings, node_id: &str) -> Self {
+        let scrollback = &settings.scrollback;
+        let root = scrollback.spill_enabled.then(|| {
+            scrollback.directory.as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| std::env::temp_dir().join("webssh-scrollback"))
+                .join(sanitize(node_id))
+        });
+        let root = root.and_then(|root| {
+            let _ = fs::remove_dir_all(&root);
+            match fs::create_dir_all(&root) {
+                Ok(()) => {
+                    info!("Spilling scrollback to {}", root.display());
+                    Some(root)
+                }
+                Err(e) => {
+                    error!("Cannot create scrollback directory {}: {}; scrollback stays in memory", root.display(), e);
+                    None
+                }
+            }
+        });
+
+        Self {
+            buffer_bytes: settings.reconnect.buffer_bytes,
+            root,
+            segment_bytes: scrollback.segment_bytes,
+            max_bytes: scrollback.max_bytes,
+            screen: (settings.slow_consumers.summarize || settings.flow_control.coalesce_fullscreen || settings.screen_subscriptions.enabled).then(|| {
+                let terminal = &settings.ssh.terminal;
+                (terminal.default_rows.min(u16::MAX.into()) as u16, terminal.default_cols.min(u16::MAX.into()) as u16)
+            }),
+        }
+    }
+
+    /// Buffer for a session's shared shell, which keeps its history on disk
+    ///
+    /// `size` is that of the shell's terminal, so the tracked screen starts out matching it.
+    pub fn session_buffer(&self, session_id: &str, size: Option<TerminalSize>) -> OutputBuffer {
+        let buffer = self.buffer(size);
+        let Some(root) = &self.root else {
+            return buffer;
+        };
+        let dir = root.join(format!("{}-{}", sanitize(session_id), uuid::Uuid::new_v4().simple()));
+        match Spill::create(dir, self.segment_bytes, self.max_bytes) {
+            Ok(spill) => buffer.with_spill(spill),
+            Err(e) => {
+                error!("Cannot create scrollback spill for session {}: {}", session_id, e);
+                buffer
+            }
+        }
+    }
+
+    /// Buffer for a shell opened for a single WebSocket, which has no history
+    pub fn exclusive_buffer(&self, size: Option<TerminalSize>) -> OutputBuffer {
+        self.buffer(size)
+    }
+
+    /// Buffer for the shell of a session in pass-through mode
+    ///
+    /// Only the recent output is kept, for a client reattaching; no screen is
+    /// tracked and nothing is spilled to disk.
+    pub fn passthrough_buffer(&self) -> OutputBuffer {
+        OutputBuffer::new(self.buffer_bytes)
+    }
+
+    fn buffer(&self, size: Option<TerminalSize>) -> OutputBuffer {
+        let buffer = OutputBuffer::new(self.buffer_bytes);
+        let size = size.map(|size| (size.rows.min(u16::MAX.into()) as u16, size.cols.min(u16::MAX.into()) as u16));
+        match self.screen {
+            Some(default) => {
+                let (rows, cols) = size.unwrap_or(default);
+                buffer.with_screen(rows, cols)
+            }
+            None => buffer,
+        }
+    }
+}
+
+/// Keeps IDs usable as file names that cannot leave the spill directory
+fn sanitize(id: &str) -> String {
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScrollbackQuery {
+    /// Offset to read from; defaults to the oldest output still held
+    pub from: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Reads a session's output history from an offset
+///
+/// The output is returned as raw bytes; headers give the offsets needed to
+/// page through it, continuing from `X-Scrollback-Next`.
+pub async fn scrollback_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<ScrollbackQuery>,
+) -> Response {
+    let session_id = session_id.trim();
+    let stream = match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => session_info.stream.clone(),
+        None => {
+            return (StatusCode::NOT_FOUND, Json(json!({
+                "error": "session_not_found",
+                "message": format!("Session '{}' not found", session_id),
+            }))).into_response();
+        }
+    };
+    let Some(stream) = stream else {
+        return (StatusCode::CONFLICT, Json(json!({
+            "error": "shell_not_started",
+            "message": "The session's shell has not been attached yet, so it has no output",
+        }))).into_response();
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_READ_BYTES).clamp(1, MAX_READ_BYTES);
+    let (replay, oldest, end) = tokio::task::spawn_blocking(move || {
+        let oldest = stream.oldest_offset();
+        let replay = stream.read(query.from.unwrap_or(oldest), limit);
+        (replay, oldest, stream.end_offset())
+    }).await.unwrap_or_else(|_| (Default::default(), 0, 0));
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+        (HeaderName::from_static("x-scrollback-start"), replay.start.to_string()),
+        (HeaderName::from_static("x-scrollback-next"), replay.next().to_string()),
+        (HeaderName::from_static("x-scrollback-oldest"), oldest.to_string()),
+        (HeaderName::from_static("x-scrollback-end"), end.to_string()),
+        (HeaderName::from_static("x-scrollback-skipped"), replay.skipped.to_string()),
+    ];
+    (headers, replay.data).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_segments_and_limit() {
+        let dir = std::env::temp_dir().join(format!("webssh-spill-{}", uuid::Uuid::new_v4()));
+        let mut spill = Spill::create(dir.clone(), 4096, 8192).unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        spill.append(100, &data[..5000]).unwrap();
+        spill.append(5100, &data[5000..]).unwrap();
+        assert!(spill.append(1, b"gap").is_err());
+
+        // Three segments were written and the first dropped to stay within 8 KiB
+        assert_eq!(spill.segments.len(), 2);
+        assert_eq!(spill.start(), 4196);
+        assert_eq!(spill.read(4196, usize::MAX).unwrap(), &data[4096..]);
+        assert_eq!(spill.read(8000, 300).unwrap(), &data[7900..8200]);
+        assert_eq!(spill.read(0, 10).unwrap(), &data[4096..4106]);
+
+        drop(spill);
+        assert!(!dir.exists());
+    }
+}
@@ -1,7 +1,41 @@
-use crate::ssh::SSHSession;
+use crate::alerts::Alerts;
+use crate::audit::{AuditContext, CommandAudit};
+use crate::capture::CaptureSlot;
+use crate::command_policy::SharedApprovals;
+use crate::cluster::SessionDirectory;
+use crate::error_code::ErrorCode;
+use crate::file_server::DeviceAccess;
+use crate::forward::ForwardRegistry;
+use crate::presence::PresenceBoard;
+use crate::protocol::PerformanceStats;
+use crate::interactive_auth::AuthExchange;
+use crate::keepalive::{ConnectionHealth, HealthState};
+use crate::lifetime::SessionLifetime;
+use crate::log_context;
+use crate::recording::SharedRecorder;
+use crate::replay::{OutputWatches, ShellActivity, ShellStream};
+use crate::scrollback::ScrollbackStore;
+use crate::settings::{LimitPolicy, SessionCleanupSettings, SessionLimitSettings, TimelineSettings};
+use crate::share::{ShareGrant, ShareLinks};
+use crate::ssh::{ChannelKind, ChannelLease, ConnectionTarget, SessionHandle, SharedConnection, Shell};
+use crate::store::{EndReason, SessionEvent, SessionRecord, SessionStore, StoreError};
+use crate::password_change::PasswordChange;
+use crate::terminal::TerminalWatch;
+use crate::timeline::{self, SessionTimeline, TimelineEntry};
+use crate::transform::OutputPipeline;
+use crate::webhooks::Webhooks;
+use crate::ws_token::WsTokens;
+use crate::device_lock::DeviceLocks;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
 use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
-use tracing::{error, info};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn, Span};
 use uuid::Uuid;
 
 /// Represents a session in the registry
@@ -9,8 +43,222 @@ pub struct SessionInfo {
     pub portal_user_id: String,
     pub device_id: String,
     pub ssh_username: String,
-    pub ssh_session: SSHSession,
+    pub ssh_session: SessionHandle,
+    pub created_at: chrono::DateTime<Utc>,
+    // The shell opened at connect time, until the first WebSocket starts its stream
+    pub shell: Option<Shell>,
+    // The shell's I/O and replay buffer, once a WebSocket has attached
+    pub stream: Option<Arc<ShellStream>>,
+    // The WebSocket currently attached to the stream: its number, detach token and what it is told when let go
+    attachment: Option<(u64, CancellationToken, DetachNotice)>,
+    attach_count: u64,
+    // When the last WebSocket went away, while none is attached
+    pub detached_at: Option<Instant>,
+    // When the session was last looked up, e.g. by the status APIs
     pub last_activity: Instant,
+    // Traffic through the session's shells, which decides when it is idle
+    pub activity: Arc<ShellActivity>,
+    // The idle period the clients were last warned of, by when it began
+    idle_warned_for: Option<chrono::DateTime<Utc>>,
+    // Out-of-band notifications (e.g. transfer progress) for attached WebSockets
+    pub notifications: broadcast::Sender<serde_json::Value>,
+    // Asciicast recorder, when session recording is enabled
+    pub recorder: Option<SharedRecorder>,
+    // Who typed commands are attributed to, when command auditing is enabled
+    pub audit: Option<AuditContext>,
+    // The device's access to the file server, while enabled for this session
+    pub file_access: Option<DeviceAccess>,
+    // Port forwards opened over the session
+    pub forwards: ForwardRegistry,
+    // When the watchdog found the session's I/O stuck and forced it down
+    pub degraded_since: Option<chrono::DateTime<Utc>>,
+    // How the connection's keepalives are going, as last checked by the keepalive monitor
+    pub health: ConnectionHealth,
+    // WebSocket frame capture, while one is running
+    pub capture: CaptureSlot,
+    // Links letting others watch the session
+    pub shares: ShareLinks,
+    // Read-only WebSockets watching alongside the attached one
+    pub viewers: usize,
+    // Who is attached to the session's shell or watching it
+    pub presence: PresenceBoard,
+    // Traffic of all the WebSockets the session has had
+    pub stats: Arc<Mutex<PerformanceStats>>,
+    // Looks for a device that does not handle its terminal type, until the shell's I/O starts
+    pub terminal_watch: Option<TerminalWatch>,
+    // Guides the user through a password change the device demands after login
+    pub password_change: Option<Arc<PasswordChange>>,
+    // The stages the shell's output passes through, until the shell's I/O starts
+    pub output_pipeline: Option<OutputPipeline>,
+    // Output goes to the WebSockets uninspected and unrecorded, with no screen tracked
+    pub passthrough: bool,
+    // The session's lifetime limit, once its class has been decided
+    pub lifetime: Option<SessionLifetime>,
+    // Roles of the user who opened the session, from their token, for the command policy
+    pub roles: Vec<String>,
+    // Commands the guardrail refused that were sent for approval
+    pub command_approvals: SharedApprovals,
+    // The device type given at connect, or else the one recognised from the device
+    pub device_type: Option<String>,
+    // The span the session's log events are logged in, carrying its ID, portal user and device
+    pub span: Span,
+    // What happened to the session, in order, if timelines are enabled
+    pub timeline: Option<SessionTimeline>,
+    // The shell's entry in its connection's channel accounting, over SSH
+    _shell_channel: Option<ChannelLease>,
+}
+
+impl SessionInfo {
+    /// Gets the ID of the recording linked to this session, if any
+    pub fn recording_id(&self) -> Option<String> {
+        self.recorder.as_ref()
+            .and_then(|recorder| recorder.lock().ok().map(|recorder| recorder.recording_id().to_string()))
+    }
+
+    /// Gets the traffic of the session's WebSockets so far
+    pub fn traffic(&self) -> PerformanceStats {
+        self.stats.lock().map(|stats| stats.clone()).unwrap_or_default()
+    }
+}
+
+/// A session idle for longer than the cleanup allows, as listed to operators
+#[derive(Debug, Clone, Serialize)]
+pub struct StaleSession {
+    pub session_id: String,
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+    pub idle_seconds: u64,
+    /// A WebSocket is attached to the session's shell
+    pub attached: bool,
+    pub viewers: usize,
+}
+
+/// How long a session has gone without traffic
+fn idle_for(session_info: &SessionInfo, output_counts: bool, now: chrono::DateTime<Utc>) -> Duration {
+    (now - session_info.activity.last_active(output_counts)).to_std().unwrap_or_default()
+}
+
+/// The outcome of a pass of the idle session cleanup
+#[derive(Debug, Default)]
+pub struct IdleCheck {
+    pub removed: usize,
+    /// When the next idle warning or removal is due, if any session is open
+    pub next_due: Option<chrono::DateTime<Utc>>,
+}
+
+/// What a WebSocket is sent before it closes, when it is let go for a reason its client should know
+pub type DetachNotice = Arc<OnceLock<serde_json::Value>>;
+
+/// A WebSocket's hold on a shell
+pub struct Attachment {
+    pub stream: Arc<ShellStream>,
+    /// Cancelled when the WebSocket should let go: the shell has shut down,
+    /// a resuming client has taken its place, or the session was transferred
+    pub detach: CancellationToken,
+    pub notice: DetachNotice,
+    // Number of the attachment to the session's stream; None for a shell of its own or a viewer
+    id: Option<u64>,
+    read_only: bool,
+}
+
+impl Attachment {
+    /// Attaches to a shell opened for this WebSocket alone
+    pub fn exclusive(stream: Arc<ShellStream>) -> Self {
+        let detach = stream.shutdown_token();
+        Self { stream, detach, notice: DetachNotice::default(), id: None, read_only: false }
+    }
+
+    /// Whether the shell is the session's, and so outlives the WebSocket
+    pub fn is_shared(&self) -> bool {
+        self.id.is_some()
+    }
+
+    /// Whether the WebSocket only watches the output, sending no input
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/// A connection waiting for the user to answer keyboard-interactive prompts
+pub struct PendingAuth {
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+    pub roles: Vec<String>,
+    // The session takes the device's configuration lock once connected
+    pub locks_device: bool,
+    // Taken by the WebSocket that relays the prompts
+    pub exchange: Option<AuthExchange>,
+}
+
+/// How long the failure of a keyboard-interactive connection is kept for clients polling its status
+const FAILED_CONNECT_RETENTION: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Where a session stands, as reported to clients polling until they can attach
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleState {
+    /// Waiting for keyboard-interactive answers
+    Authenticating,
+    /// Connected, waiting for its first WebSocket
+    Ready,
+    Attached,
+    /// Its WebSocket went away; a client may reconnect
+    Detached,
+    /// The watchdog found its I/O stuck and is closing it, or its keepalives are failing
+    Degraded,
+    /// The connection failed before the session was created
+    Failed,
+    Ended,
+    NotFound,
+}
+
+/// How far the connection behind a session has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectPhase {
+    /// The device's prompts wait for a WebSocket to relay them
+    AwaitingWebsocket,
+    /// The prompts are being answered over the WebSocket
+    Prompting,
+    /// Logged in, with a shell open
+    Established,
+}
+
+/// Why a connection failed, or a live session is in trouble
+#[derive(Debug, Clone, Serialize)]
+pub struct LastError {
+    pub error_code: Option<ErrorCode>,
+    pub message: String,
+    pub at: chrono::DateTime<Utc>,
+}
+
+/// A session's state, with what a polling client should do next
+#[derive(Debug, Clone, Serialize)]
+pub struct Lifecycle {
+    pub state: LifecycleState,
+    pub phase: Option<ConnectPhase>,
+    pub last_error: Option<LastError>,
+    /// When to ask again; absent once polling cannot change anything
+    pub retry_after_seconds: Option<u64>,
+}
+
+/// A new session refused by the session limits
+#[derive(Debug, Error)]
+pub enum SessionLimitExceeded {
+    #[error("Portal user already has the maximum of {0} sessions")]
+    User(usize),
+    #[error("Device already has the maximum of {0} sessions")]
+    Device(usize),
+    #[error("The server already has the maximum of {0} sessions")]
+    Total(usize),
+}
+
+impl SessionLimitExceeded {
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode::SessionLimitExceeded
+    }
 }
 
 /// Session registry that manages all active SSH sessions
@@ -18,6 +266,12 @@ pub struct SessionRegistry {
     // Map of session_id -> SessionInfo
     pub(crate) sessions: HashMap<String, SessionInfo>,
     
+    // Map of session_id -> connection still authenticating
+    pending_auth: HashMap<String, PendingAuth>,
+    
+    // Map of session_id -> why a keyboard-interactive connection failed, kept for a while
+    failed_connects: HashMap<String, LastError>,
+    
     // Map of portal_user_id -> Set of session_ids
     portal_user_sessions: HashMap<String, HashSet<String>>,
     
@@ -26,6 +280,41 @@ pub struct SessionRegistry {
     
     // Map of (portal_user_id, device_id, ssh_username) -> session_id
     composite_key_sessions: HashMap<(String, String, String), String>,
+    
+    // Map of session_id -> lifecycle record, for live and ended sessions
+    history: HashMap<String, SessionRecord>,
+    
+    // Durable copy of the history, if storage is enabled
+    store: Option<Arc<dyn SessionStore>>,
+    
+    // Map of session_id -> its timeline, for live sessions, and for ended ones while no store keeps them
+    timelines: HashMap<String, SessionTimeline>,
+    timeline_settings: TimelineSettings,
+    
+    // Where other instances look up which instance holds a session, if shared through Redis
+    directory: Option<Arc<SessionDirectory>>,
+    
+    // Where lifecycle events are posted, if webhooks are enabled
+    webhooks: Option<Arc<Webhooks>>,
+    
+    // Where abnormal session ends and reconnects are counted towards the alert thresholds
+    alerts: Option<Arc<Alerts>>,
+    
+    // Single-use tokens for WebSockets attaching to sessions
+    pub(crate) ws_tokens: WsTokens,
+    
+    // Map of device_id -> the session holding the device's configuration lock
+    pub(crate) device_locks: DeviceLocks,
+    
+    // How long ended sessions are kept in the history
+    retention: chrono::Duration,
+    
+    // Caps on open sessions, enforced when sessions are added
+    limits: SessionLimitSettings,
+    
+    // Most sessions open at once, and sessions created, since startup
+    peak_sessions: usize,
+    sessions_created: u64,
 }
 
 impl SessionRegistry {
@@ -33,40 +322,216 @@ impl SessionRegistry {
     pub fn new() -> Self {
         Self {
             sessions: HashMap::new(),
+            pending_auth: HashMap::new(),
+            failed_connects: HashMap::new(),
             portal_user_sessions: HashMap::new(),
             device_sessions: HashMap::new(),
             composite_key_sessions: HashMap::new(),
+            history: HashMap::new(),
+            store: None,
+            timelines: HashMap::new(),
+            timeline_settings: TimelineSettings::default(),
+            directory: None,
+            webhooks: None,
+            alerts: None,
+            ws_tokens: WsTokens::default(),
+            device_locks: DeviceLocks::default(),
+            retention: chrono::Duration::days(30),
+            limits: SessionLimitSettings::default(),
+            peak_sessions: 0,
+            sessions_created: 0,
+        }
+    }
+
+    /// Enforces caps on the sessions open per portal user, per device and in total
+    pub fn with_limits(mut self, limits: SessionLimitSettings) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Keeps each session's events in order, if enabled
+    pub fn with_timeline(mut self, settings: TimelineSettings) -> Self {
+        self.timeline_settings = settings;
+        self
+    }
+
+    /// Creates a registry that persists session lifecycle events
+    ///
+    /// History from previous runs is loaded for the status APIs; sessions that
+    /// were still open when the service stopped are marked as ended.
+    pub fn with_store(store: Arc<dyn SessionStore>, retention: chrono::Duration) -> Self {
+        let mut registry = Self::new();
+        match store.recover(retention) {
+            Ok(records) => {
+                info!("Loaded {} sessions from history", records.len());
+                registry.history = records.into_iter()
+                    .map(|record| (record.session_id.clone(), record))
+                    .collect();
+            }
+            Err(e) => error!("Failed to load session history: {}", e),
+        }
+        registry.store = Some(store);
+        registry.retention = retention;
+        registry
+    }
+
+    /// Registers the sessions of this instance for the other instances to find
+    pub fn set_directory(&mut self, directory: Arc<SessionDirectory>) {
+        self.directory = Some(directory);
+    }
+
+    /// Posts the lifecycle events of this instance's sessions to the webhooks
+    pub fn set_webhooks(&mut self, webhooks: Arc<Webhooks>) {
+        self.webhooks = Some(webhooks);
+    }
+
+    /// Counts abnormal session ends and reconnects towards the alert thresholds
+    pub fn set_alerts(&mut self, alerts: Arc<Alerts>) {
+        self.alerts = Some(alerts);
+    }
+
+    /// Writes a lifecycle event to the store; failures are logged, never fatal
+    fn persist(&self, event: SessionEvent) {
+        if let Some(directory) = &self.directory {
+            directory.record(&event);
+        }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.record(&event, self.history.get(event.session_id()));
+        }
+        if let (Some(alerts), SessionEvent::Ended { reason, .. }) = (&self.alerts, &event) {
+            alerts.session_ended(*reason);
+        }
+        if let Some(store) = &self.store {
+            if let Err(e) = store.record(event) {
+                warn!("Failed to persist session event: {}", e);
+            }
         }
     }
     
     /// Adds a new session to the registry
+    ///
+    /// A session beyond the session limits is closed and refused, unless an
+    /// idle session can be evicted to make room for it.
     pub fn add_session(
         &mut self,
         portal_user_id: &str,
         device_id: &str,
         ssh_username: &str,
-        ssh_session: SSHSession,
-    ) -> String {
-        // Generate a unique session ID
-        let session_id = format!(
+        ssh_session: Shell,
+    ) -> Result<String, SessionLimitExceeded> {
+        let session_id = Self::new_session_id(portal_user_id, device_id, ssh_username);
+        self.insert_session(&session_id, portal_user_id, device_id, ssh_username, ssh_session)?;
+        Ok(session_id)
+    }
+
+    /// Generates a unique session ID
+    pub fn new_session_id(portal_user_id: &str, device_id: &str, ssh_username: &str) -> String {
+        // A device given as a scoped address, e.g. `fe80::1%mgmt0`, would otherwise need escaping in URLs
+        format!(
             "portal-{}-device-{}-ssh-{}-{}",
             portal_user_id,
-            device_id,
+            device_id.replace('%', "-"),
             ssh_username,
             Uuid::new_v4()
-        );
+        )
+    }
+
+    /// Adds a session under an ID chosen in advance, e.g. after interactive authentication
+    pub fn insert_session(
+        &mut self,
+        session_id: &str,
+        portal_user_id: &str,
+        device_id: &str,
+        ssh_username: &str,
+        mut ssh_session: Shell,
+    ) -> Result<(), SessionLimitExceeded> {
+        if let Err(e) = self.make_room(portal_user_id, device_id) {
+            warn!("Refusing session {} for portal user {}, device {}: {}", session_id, portal_user_id, device_id, e);
+            if let Err(e) = ssh_session.close() {
+                error!("Error closing refused SSH connection for session {}: {}", session_id, e);
+            }
+            return Err(e);
+        }
+        let session_id = session_id.to_string();
+        
+        // Record the session before the handle moves into the session info
+        let target = ssh_session.handle().target().clone();
+        let device_type = target.device_type.clone()
+            .or_else(|| ssh_session.handle().connection_info().device_type.map(|kind| kind.as_str().to_string()));
+        let created_at = Utc::now();
+        let record = SessionRecord {
+            session_id: session_id.clone(),
+            portal_user_id: portal_user_id.to_string(),
+            device_id: device_id.to_string(),
+            ssh_username: ssh_username.to_string(),
+            hostname: target.hostname,
+            port: target.port,
+            created_at,
+            last_attached_at: None,
+            ended_at: None,
+            end_reason: None,
+        };
+        let timeline = self.timeline_settings.enabled.then(|| {
+            let timeline = SessionTimeline::new(&session_id, &self.timeline_settings, self.store.clone());
+            timeline.record("created", json!({
+                "portal_user_id": record.portal_user_id,
+                "device_id": record.device_id,
+                "ssh_username": record.ssh_username,
+                "hostname": record.hostname,
+                "port": record.port,
+            }));
+            self.timelines.insert(session_id.clone(), timeline.clone());
+            timeline
+        });
+        self.persist(SessionEvent::Created(&record));
+        self.history.insert(session_id.clone(), record);
         
         // Create session info
+        let shell_channel = ssh_session.handle().connection()
+            .map(|connection| connection.lease(ChannelKind::Shell, portal_user_id));
         let session_info = SessionInfo {
             portal_user_id: portal_user_id.to_string(),
             device_id: device_id.to_string(),
             ssh_username: ssh_username.to_string(),
-            ssh_session,
+            ssh_session: ssh_session.handle(),
+            created_at,
+            shell: Some(ssh_session),
+            stream: None,
+            attachment: None,
+            attach_count: 0,
+            detached_at: None,
             last_activity: Instant::now(),
+            activity: Arc::default(),
+            idle_warned_for: None,
+            notifications: broadcast::channel(64).0,
+            recorder: None,
+            audit: None,
+            file_access: None,
+            forwards: ForwardRegistry::default(),
+            degraded_since: None,
+            health: ConnectionHealth::default(),
+            capture: CaptureSlot::default(),
+            shares: ShareLinks::default(),
+            viewers: 0,
+            presence: PresenceBoard::default(),
+            stats: Arc::default(),
+            terminal_watch: None,
+            password_change: None,
+            output_pipeline: None,
+            passthrough: false,
+            lifetime: None,
+            roles: Vec::new(),
+            command_approvals: SharedApprovals::default(),
+            device_type,
+            span: log_context::session_span(Some(&session_id), portal_user_id, device_id),
+            timeline,
+            _shell_channel: shell_channel,
         };
         
         // Add to sessions map
         self.sessions.insert(session_id.clone(), session_info);
+        self.sessions_created += 1;
+        self.peak_sessions = self.peak_sessions.max(self.sessions.len());
         
         // Add to portal user sessions map
         self.portal_user_sessions
@@ -90,10 +555,301 @@ impl SessionRegistry {
         
         info!("Added new session {} for portal user {}, device {}, SSH user {}", 
               session_id, portal_user_id, device_id, ssh_username);
-        
-        session_id
+        Ok(())
+    }
+
+    /// Checks, before connecting, whether a new session could be admitted
+    ///
+    /// Nothing is evicted here; `add_session` makes the final decision.
+    pub fn check_limits(&self, portal_user_id: &str, device_id: &str) -> Result<(), SessionLimitExceeded> {
+        for (exceeded, session_ids) in self.exceeded_limits(portal_user_id, device_id) {
+            if self.limits.on_limit == LimitPolicy::Reject || self.oldest_idle(&session_ids).is_none() {
+                return Err(exceeded);
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the sessions a new one would count against below their caps
+    fn make_room(&mut self, portal_user_id: &str, device_id: &str) -> Result<(), SessionLimitExceeded> {
+        while let Some((exceeded, session_ids)) = self.exceeded_limits(portal_user_id, device_id).into_iter().next() {
+            let evict = match self.limits.on_limit {
+                LimitPolicy::EvictOldest => self.oldest_idle(&session_ids),
+                LimitPolicy::Reject => None,
+            };
+            let Some(session_id) = evict else {
+                return Err(exceeded);
+            };
+            info!("Evicting idle session {}: {}", session_id, exceeded);
+            self.remove_session(&session_id, EndReason::Evicted);
+        }
+        Ok(())
+    }
+
+    /// Caps that are already full for a new session, with the sessions counted against each
+    fn exceeded_limits(&self, portal_user_id: &str, device_id: &str) -> Vec<(SessionLimitExceeded, Vec<String>)> {
+        let user_sessions = self.get_portal_user_sessions(portal_user_id);
+        let device_sessions = self.device_sessions.get(device_id)
+            .map(|session_ids| session_ids.iter().cloned().collect())
+            .unwrap_or_default();
+        let limits = &self.limits;
+        [
+            (limits.max_per_user, SessionLimitExceeded::User(limits.max_per_user), user_sessions),
+            (limits.max_per_device, SessionLimitExceeded::Device(limits.max_per_device), device_sessions),
+            (limits.max_total, SessionLimitExceeded::Total(limits.max_total), self.get_all_sessions()),
+        ]
+        .into_iter()
+        .filter(|(limit, _, session_ids)| *limit > 0 && session_ids.len() >= *limit)
+        .map(|(_, exceeded, session_ids)| (exceeded, session_ids))
+        .collect()
+    }
+
+    /// The least recently used of the sessions that no WebSocket is attached to
+    fn oldest_idle(&self, session_ids: &[String]) -> Option<String> {
+        session_ids.iter()
+            .filter_map(|session_id| self.sessions.get(session_id).map(|session_info| (session_id, session_info)))
+            .filter(|(_, session_info)| session_info.attachment.is_none())
+            .min_by_key(|(_, session_info)| session_info.last_activity)
+            .map(|(session_id, _)| session_id.clone())
+    }
+
+    /// Registers a connection that is waiting for keyboard-interactive answers
+    pub fn add_pending_auth(&mut self, session_id: &str, pending: PendingAuth) {
+        info!("Session {} is waiting for keyboard-interactive authentication", session_id);
+        self.pending_auth.insert(session_id.to_string(), pending);
+    }
+
+    /// Gets a connection that is still authenticating
+    pub fn get_pending_auth(&mut self, session_id: &str) -> Option<&mut PendingAuth> {
+        self.pending_auth.get_mut(session_id)
+    }
+
+    /// Removes a connection from the pending state once authentication has finished
+    pub fn remove_pending_auth(&mut self, session_id: &str) -> Option<PendingAuth> {
+        self.pending_auth.remove(session_id)
+    }
+
+    /// Keeps why a connection failed, for clients polling the status of its session ID
+    pub fn record_failed_connect(&mut self, session_id: &str, error_code: ErrorCode, message: String) {
+        let now = Utc::now();
+        self.failed_connects.retain(|_, failure| now - failure.at < FAILED_CONNECT_RETENTION);
+        self.failed_connects.insert(session_id.to_string(), LastError { error_code: Some(error_code), message, at: now });
+    }
+
+    /// Where a session stands, whether it is live, still connecting, failed or ended
+    pub fn lifecycle(&self, session_id: &str) -> Lifecycle {
+        let lifecycle = |state, phase, last_error, retry_after_seconds| Lifecycle { state, phase, last_error, retry_after_seconds };
+        if let Some(pending) = self.pending_auth.get(session_id) {
+            let phase = if pending.exchange.is_some() { ConnectPhase::AwaitingWebsocket } else { ConnectPhase::Prompting };
+            return lifecycle(LifecycleState::Authenticating, Some(phase), None, Some(1));
+        }
+        if let Some(session_info) = self.sessions.get(session_id) {
+            let established = Some(ConnectPhase::Established);
+            return match session_info.degraded_since {
+                Some(at) => lifecycle(LifecycleState::Degraded, established, Some(LastError {
+                    error_code: None,
+                    message: "The connection to the device stopped responding and is being closed".to_string(),
+                    at,
+                }), None),
+                None if session_info.health.state != HealthState::Healthy => lifecycle(LifecycleState::Degraded, established, Some(LastError {
+                    error_code: None,
+                    message: "The device is not answering keepalives".to_string(),
+                    at: session_info.health.since.unwrap_or_else(Utc::now),
+                }), None),
+                None if session_info.attachment.is_some() => lifecycle(LifecycleState::Attached, established, None, None),
+                None if session_info.detached_at.is_some() => lifecycle(LifecycleState::Detached, established, None, None),
+                None => lifecycle(LifecycleState::Ready, established, None, None),
+            };
+        }
+        if let Some(failure) = self.failed_connects.get(session_id).filter(|failure| Utc::now() - failure.at < FAILED_CONNECT_RETENTION) {
+            return lifecycle(LifecycleState::Failed, None, Some(failure.clone()), None);
+        }
+        if self.history.get(session_id).is_some_and(|record| record.ended_at.is_some()) {
+            return lifecycle(LifecycleState::Ended, None, None, None);
+        }
+        // It may still be on its way, e.g. from another instance
+        lifecycle(LifecycleState::NotFound, None, None, Some(2))
     }
     
+    /// Attaches a WebSocket to the session's shell, starting its I/O on first use
+    ///
+    /// A resuming client displaces a WebSocket still attached, as that one is
+    /// most likely dead. Otherwise an attached shell is left alone and `None`
+    /// is returned, so the caller can open another.
+    pub fn attach(&mut self, session_id: &str, resume: bool, scrollback: &ScrollbackStore) -> Option<Attachment> {
+        let session_info = self.get_session(session_id)?;
+        if let Some((_, detach, _)) = &session_info.attachment {
+            if !resume {
+                return None;
+            }
+            info!("Session {} resumed by a new WebSocket, detaching the previous one", session_id);
+            detach.cancel();
+        }
+
+        let stream = Self::start_stream(session_info, session_id, scrollback)?;
+
+        let reconnected = session_info.attach_count > 0;
+        session_info.attach_count += 1;
+        let detach = stream.shutdown_token().child_token();
+        let notice = DetachNotice::default();
+        session_info.attachment = Some((session_info.attach_count, detach.clone(), notice.clone()));
+        session_info.detached_at = None;
+        session_info.activity.input();
+        let id = session_info.attach_count;
+
+        let now = Utc::now();
+        if let Some(record) = self.history.get_mut(session_id) {
+            record.last_attached_at = Some(now);
+        }
+        self.persist(SessionEvent::Attached { session_id, at: now });
+        timeline::note(self.timelines.get(session_id), if reconnected { "reconnected" } else { "attached" }, json!({ "resumed": resume }));
+        if let (true, Some(alerts)) = (reconnected, &self.alerts) {
+            alerts.reconnected();
+        }
+        Some(Attachment { stream, detach, notice, id: Some(id), read_only: false })
+    }
+
+    /// Attaches a read-only WebSocket to the session's shell, next to the one attached
+    ///
+    /// Viewers see the same output but cannot type, resize or hold the session open.
+    pub fn observe(&mut self, session_id: &str, scrollback: &ScrollbackStore) -> Option<Attachment> {
+        let session_info = self.get_session(session_id)?;
+        let stream = Self::start_stream(session_info, session_id, scrollback)?;
+        session_info.viewers += 1;
+        let _ = session_info.notifications.send(json!({ "type": "viewers", "count": session_info.viewers }));
+        info!("Viewer joined session {} ({} watching)", session_id, session_info.viewers);
+
+        let detach = stream.shutdown_token().child_token();
+        Some(Attachment { stream, detach, notice: DetachNotice::default(), id: None, read_only: true })
+    }
+
+    /// Notes that a viewer's WebSocket has gone
+    pub fn stop_observing(&mut self, session_id: &str) {
+        if let Some(session_info) = self.sessions.get_mut(session_id) {
+            session_info.viewers = session_info.viewers.saturating_sub(1);
+            let _ = session_info.notifications.send(json!({ "type": "viewers", "count": session_info.viewers }));
+            info!("Viewer left session {} ({} watching)", session_id, session_info.viewers);
+        }
+    }
+
+    /// Sends an event to the WebSockets of every session
+    ///
+    /// # Returns
+    /// * `usize` - The number of sessions with a WebSocket to send it to
+    pub fn notify_all(&self, event: serde_json::Value) -> usize {
+        self.sessions.values()
+            .filter(|session_info| session_info.notifications.send(event.clone()).is_ok())
+            .count()
+    }
+
+    /// Finds the session a share link token grants access to
+    ///
+    /// # Returns
+    /// * `Option<(String, ShareGrant)>` - The session ID and what the link allows
+    pub fn find_share(&self, token: &str) -> Option<(String, ShareGrant)> {
+        self.sessions.iter().find_map(|(session_id, session_info)| {
+            session_info.shares.find(token).map(|grant| (session_id.clone(), grant))
+        })
+    }
+
+    /// Gets the session's shell stream, starting its I/O if no WebSocket has yet
+    pub fn stream(&mut self, session_id: &str, scrollback: &ScrollbackStore) -> Option<Arc<ShellStream>> {
+        let session_info = self.get_session(session_id)?;
+        Self::start_stream(session_info, session_id, scrollback)
+    }
+
+    /// Gets the session's shell stream, starting its I/O on first use
+    fn start_stream(session_info: &mut SessionInfo, session_id: &str, scrollback: &ScrollbackStore) -> Option<Arc<ShellStream>> {
+        if let Some(stream) = &session_info.stream {
+            return Some(stream.clone());
+        }
+        let shell = session_info.shell.take()?;
+        let buffer = if session_info.passthrough {
+            scrollback.passthrough_buffer()
+        } else {
+            scrollback.session_buffer(session_id, session_info.ssh_session.connection_info().terminal_size)
+        };
+        let audit = session_info.audit.clone().map(CommandAudit::new);
+        let watches = OutputWatches {
+            terminal: session_info.terminal_watch.take(),
+            password_change: session_info.password_change.clone(),
+            pipeline: session_info.output_pipeline.take().unwrap_or_default(),
+        };
+        let stream = session_info.span.in_scope(|| {
+            ShellStream::start(shell, buffer, session_info.recorder.clone(), audit, watches, session_info.activity.clone(), session_id)
+        });
+        session_info.stream = Some(stream.clone());
+        Some(stream)
+    }
+
+    /// Detaches a WebSocket, leaving the shell running for the client to resume
+    ///
+    /// # Returns
+    /// * `bool` - true if the WebSocket was still attached, false if it had been displaced
+    pub fn detach(&mut self, session_id: &str, attachment: &Attachment) -> bool {
+        match self.sessions.get_mut(session_id) {
+            Some(session_info) if attachment.id.is_some()
+                && session_info.attachment.as_ref().map(|(id, ..)| *id) == attachment.id => {
+                session_info.attachment = None;
+                session_info.detached_at = Some(Instant::now());
+                timeline::note(session_info.timeline.as_ref(), "detached", serde_json::Value::Null);
+                self.persist(SessionEvent::Detached { session_id, at: Utc::now() });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether another WebSocket has taken the session from this one, or it was transferred
+    pub fn is_displaced(&self, session_id: &str, attachment: &Attachment) -> bool {
+        self.sessions.get(session_id).is_some_and(|session_info| {
+            attachment.id.is_some() && session_info.attachment.as_ref().map(|(id, ..)| *id) != attachment.id
+        })
+    }
+
+    /// Lets go of the session's WebSocket, so one on another tab or machine can attach
+    ///
+    /// The shell, its scrollback and whatever runs in it carry on. The
+    /// WebSocket let go is sent `notice` before it closes. A session that has
+    /// had a WebSocket is then detached afresh, to expire as such unless
+    /// another attaches in time.
+    ///
+    /// # Returns
+    /// * `Option<bool>` - Whether a WebSocket was attached, or `None` if there is no such session
+    pub fn transfer(&mut self, session_id: &str, notice: serde_json::Value) -> Option<bool> {
+        let session_info = self.sessions.get_mut(session_id)?;
+        let attached = session_info.attachment.take();
+        let had_websocket = attached.is_some() || session_info.detached_at.is_some();
+        if let Some((_, detach, notice_slot)) = attached.as_ref() {
+            let _ = notice_slot.set(notice);
+            detach.cancel();
+        }
+        if had_websocket {
+            session_info.detached_at = Some(Instant::now());
+        }
+        if attached.is_some() {
+            self.persist(SessionEvent::Detached { session_id, at: Utc::now() });
+        }
+        Some(attached.is_some())
+    }
+
+    /// Removes a session if no WebSocket has attached within the grace period
+    ///
+    /// # Returns
+    /// * `bool` - true if the session was removed
+    pub fn expire_detached(&mut self, session_id: &str, grace: Duration) -> bool {
+        let expired = self.sessions.get(session_id).is_some_and(|session_info| {
+            session_info.attachment.is_none()
+                && session_info.detached_at.is_some_and(|detached_at| detached_at.elapsed() >= grace)
+        });
+        expired && self.remove_session(session_id, EndReason::ReconnectTimeout)
+    }
+    
+    /// Whether a session is open, or still connecting, on this instance
+    pub fn is_local(&self, session_id: &str) -> bool {
+        self.sessions.contains_key(session_id) || self.pending_auth.contains_key(session_id)
+    }
+
     /// Gets a list of all session IDs in the registry
     pub fn get_all_sessions(&self) -> Vec<String> {
         self.sessions.keys().cloned().collect()
@@ -135,6 +891,17 @@ impl SessionRegistry {
         None
     }
     
+    /// Finds a live session's connection that logs in to the target's device
+    /// the same way, to open a channel on instead of connecting again
+    pub fn shared_connection(&self, target: &ConnectionTarget) -> Option<SharedConnection> {
+        self.sessions.values()
+            .filter(|info| info.degraded_since.is_none())
+            .filter(|info| info.ssh_session.target().same_login(target))
+            .filter_map(|info| info.ssh_session.connection())
+            .find(|connection| connection.is_open())
+            .cloned()
+    }
+    
     /// Gets all sessions for a portal user
     pub fn get_portal_user_sessions(&self, portal_user_id: &str) -> Vec<String> {
         if let Some(session_ids) = self.portal_user_sessions.get(portal_user_id) {
@@ -155,13 +922,42 @@ impl SessionRegistry {
     }
     
     /// Removes a session from the registry and closes the SSH connection
-    pub fn remove_session(&mut self, session_id: &str) -> bool {
+    ///
+    /// The session stays in the history, ended for the given reason.
+    pub fn remove_session(&mut self, session_id: &str, reason: EndReason) -> bool {
+        self.ws_tokens.forget(session_id);
+        for lock in self.device_locks.release_session(session_id) {
+            info!("Lock on device {} released as session {} ended", lock.device_id, session_id);
+        }
         if let Some(mut session_info) = self.sessions.remove(session_id) {
+            let now = Utc::now();
+            if let Some(record) = self.history.get_mut(session_id) {
+                record.ended_at = Some(now);
+                record.end_reason = Some(reason);
+            }
+            self.persist(SessionEvent::Ended { session_id, at: now, reason });
+            timeline::note(session_info.timeline.as_ref(), "ended", json!({ "reason": reason }));
+            // The store has the timeline of an ended session; without one it is kept with the history
+            if self.store.is_some() {
+                self.timelines.remove(session_id);
+            }
+            
             // Close the SSH session first
             info!("Closing SSH connection for session {}", session_id);
-            match session_info.ssh_session.close() {
-                Ok(_) => info!("Successfully closed SSH connection for session {}", session_id),
-                Err(e) => error!("Error closing SSH connection for session {}: {}", session_id, e),
+            session_info.ssh_session.shutdown();
+            session_info.forwards.close_all();
+            if let Some(mut shell) = session_info.shell.take() {
+                match shell.close() {
+                    Ok(_) => info!("Successfully closed SSH connection for session {}", session_id),
+                    Err(e) => error!("Error closing SSH connection for session {}: {}", session_id, e),
+                }
+            }
+            
+            // Finalize the recording so its metadata carries the end time
+            if let Some(recorder) = &session_info.recorder {
+                if let Ok(mut recorder) = recorder.lock() {
+                    recorder.finish();
+                }
             }
             
             // Remove from portal user sessions map
@@ -188,7 +984,7 @@ impl SessionRegistry {
             );
             self.composite_key_sessions.remove(&composite_key);
             
-            info!("Removed session {} from registry", session_id);
+            info!("Removed session {} from registry ({})", session_id, reason.as_str());
             true
         } else {
             info!("Session {} not found in registry", session_id);
@@ -196,25 +992,85 @@ impl SessionRegistry {
         }
     }
     
-    /// Cleans up stale sessions
-    pub fn cleanup_stale_sessions(&mut self, max_idle_time: Duration) -> usize {
-        let now = Instant::now();
-        let stale_session_ids: Vec<String> = self.sessions
+    /// Lists the sessions idle for longer than `max_idle_time`, longest idle first
+    ///
+    /// Only traffic counts as activity: input, and device output if `output_counts`.
+    pub fn stale_sessions(&self, max_idle_time: Duration, output_counts: bool) -> Vec<StaleSession> {
+        let now = Utc::now();
+        let mut stale: Vec<StaleSession> = self.sessions
             .iter()
-            .filter(|(_, session_info)| now.duration_since(session_info.last_activity) > max_idle_time)
-            .map(|(session_id, _)| session_id.clone())
+            .map(|(session_id, session_info)| (session_id, session_info, idle_for(session_info, output_counts, now)))
+            .filter(|(_, _, idle)| *idle > max_idle_time)
+            .map(|(session_id, session_info, idle)| StaleSession {
+                session_id: session_id.clone(),
+                portal_user_id: session_info.portal_user_id.clone(),
+                device_id: session_info.device_id.clone(),
+                ssh_username: session_info.ssh_username.clone(),
+                idle_seconds: idle.as_secs(),
+                attached: session_info.attachment.is_some(),
+                viewers: session_info.viewers,
+            })
             .collect();
-        
-        let count = stale_session_ids.len();
-        for session_id in stale_session_ids {
-            self.remove_session(&session_id);
+        stale.sort_by_key(|session| std::cmp::Reverse(session.idle_seconds));
+        stale
+    }
+
+    /// Removes the sessions idle past the idle timeout, warning their clients beforehand
+    ///
+    /// Clients are warned once per idle period, `warning_seconds` before the
+    /// session is removed; any new input starts the period over.
+    ///
+    /// # Returns
+    /// * `IdleCheck` - The sessions removed, and when the next warning or removal is due
+    pub fn cleanup_stale_sessions(&mut self, cleanup: &SessionCleanupSettings) -> IdleCheck {
+        let now = Utc::now();
+        let mut check = IdleCheck::default();
+        let mut stale = Vec::new();
+        if cleanup.idle_timeout_seconds > 0 {
+            let timeout = chrono::Duration::seconds(cleanup.idle_timeout_seconds.min(i64::MAX as u64 / 1000) as i64);
+            let warning = chrono::Duration::seconds(cleanup.warning_seconds.min(cleanup.idle_timeout_seconds) as i64);
+            for (session_id, session_info) in self.sessions.iter_mut() {
+                let last_active = session_info.activity.last_active(cleanup.output_counts_as_activity);
+                let deadline = last_active + timeout;
+                let warned = warning.is_zero() || session_info.idle_warned_for == Some(last_active);
+                let next = if now >= deadline {
+                    stale.push(session_id.clone());
+                    continue;
+                } else if warned {
+                    deadline
+                } else if now >= deadline - warning {
+                    let remaining = (deadline - now).num_seconds().max(1);
+                    info!("Session {} is idle, disconnecting in {} seconds", session_id, remaining);
+                    let _ = session_info.notifications.send(json!({
+                        "type": "session_idle",
+                        "message": format!("Session idle, disconnecting in {} seconds", remaining),
+                        "remaining_seconds": remaining,
+                    }));
+                    session_info.idle_warned_for = Some(last_active);
+                    deadline
+                } else {
+                    deadline - warning
+                };
+                check.next_due = Some(check.next_due.map_or(next, |due: chrono::DateTime<Utc>| due.min(next)));
+            }
         }
         
-        if count > 0 {
-            info!("Cleaned up {} stale sessions", count);
+        for session_id in &stale {
+            if self.remove_session(session_id, EndReason::Idle) {
+                check.removed += 1;
+            }
         }
         
-        count
+        // Forget ended sessions past the retention period; the store prunes itself at startup
+        let cutoff = Utc::now() - self.retention;
+        self.history.retain(|_, record| match record.ended_at {
+            Some(ended_at) => ended_at >= cutoff,
+            None => true,
+        });
+        let history = &self.history;
+        self.timelines.retain(|session_id, _| history.contains_key(session_id));
+        
+        check
     }
     
     /// Gets the total number of active sessions
@@ -222,6 +1078,18 @@ impl SessionRegistry {
         self.sessions.len()
     }
     
+    /// Gets the most sessions open at once and the number of sessions created since startup
+    pub fn run_metrics(&self) -> (u64, u64) {
+        (self.peak_sessions as u64, self.sessions_created)
+    }
+    
+    /// Closes every session, e.g. when the service shuts down
+    pub fn remove_all(&mut self, reason: EndReason) {
+        for session_id in self.get_all_sessions() {
+            self.remove_session(&session_id, reason);
+        }
+    }
+    
     /// Gets the number of portal users with active sessions
     pub fn total_portal_users(&self) -> usize {
         self.portal_user_sessions.len()
@@ -232,6 +1100,38 @@ impl SessionRegistry {
         self.device_sessions.len()
     }
     
+    /// Gets the lifecycle record of a live or ended session
+    pub fn history(&self, session_id: &str) -> Option<&SessionRecord> {
+        self.history.get(session_id)
+    }
+
+    /// The latest `limit` events of a session's timeline, oldest first
+    ///
+    /// Those of a session no longer in memory are read from the store.
+    pub fn timeline(&self, session_id: &str, limit: usize) -> Result<Vec<TimelineEntry>, StoreError> {
+        if let Some(timeline) = self.timelines.get(session_id) {
+            let entries = timeline.entries();
+            return Ok(entries[entries.len().saturating_sub(limit)..].to_vec());
+        }
+        match &self.store {
+            Some(store) => store.timeline(session_id, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+    
+    /// Gets the lifecycle records of all known sessions, newest first, optionally for one portal user
+    pub fn session_history(&self, portal_user_id: Option<&str>) -> Vec<SessionRecord> {
+        let mut records: Vec<SessionRecord> = self.history.values()
+            .filter(|record| match portal_user_id {
+                Some(id) => record.portal_user_id == id,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.created_at));
+        records
+    }
+    
     /// Gets all portal user IDs
     pub fn get_all_portal_user_ids(&self) -> Vec<String> {
         self.portal_user_sessions.keys().cloned().collect()
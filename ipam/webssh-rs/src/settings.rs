@@ -1,22 +1,1934 @@
 use serde::{Deserialize, Serialize};
 use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Read;
 use std::path::Path;
 use tracing::{error, info};
 
+/// The settings file, read from the working directory
+pub const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub ssh: SSHSettings,
+    pub server: ServerSettings,
+    #[serde(default)]
+    pub api_keys: ApiKeySettings,
+    #[serde(default)]
+    pub recording: RecordingSettings,
+    #[serde(default)]
+    pub jwt: JwtSettings,
+    #[serde(default)]
+    pub reconnect: ReconnectSettings,
+    #[serde(default)]
+    pub file_server: FileServerSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub cluster: ClusterSettings,
+    #[serde(default)]
+    pub inventory: InventorySettings,
+    #[serde(default)]
+    pub credentials: CredentialSettings,
+    #[serde(default)]
+    pub credential_policy: CredentialPolicySettings,
+    #[serde(default)]
+    pub forwarding: ForwardingSettings,
+    #[serde(default)]
+    pub watchdog: WatchdogSettings,
+    #[serde(default)]
+    pub policy_bundles: PolicyBundleSettings,
+    #[serde(default)]
+    pub scrollback: ScrollbackSettings,
+    #[serde(default)]
+    pub session_limits: SessionLimitSettings,
+    #[serde(default)]
+    pub session_cleanup: SessionCleanupSettings,
+    #[serde(default)]
+    pub session_lifetime: SessionLifetimeSettings,
+    #[serde(default)]
+    pub command_policy: CommandPolicySettings,
+    #[serde(default)]
+    pub authorization: AuthorizationSettings,
+    #[serde(default)]
+    pub capture: CaptureSettings,
+    #[serde(default)]
+    pub sharing: SharingSettings,
+    #[serde(default)]
+    pub audit: AuditSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default)]
+    pub ssh_ca: SshCaSettings,
+    #[serde(default)]
+    pub exec: ExecSettings,
+    #[serde(default)]
+    pub client_stats: ClientStatsSettings,
+    #[serde(default)]
+    pub slow_consumers: SlowConsumerSettings,
+    #[serde(default)]
+    pub flow_control: FlowControlSettings,
+    #[serde(default)]
+    pub presence: PresenceSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub lab: LabSettings,
+    #[serde(default)]
+    pub telnet: TelnetSettings,
+    #[serde(default)]
+    pub parsing: ParsingSettings,
+    #[serde(default)]
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub password_change: PasswordChangeSettings,
+    #[serde(default)]
+    pub maintenance: MaintenanceSettings,
+    #[serde(default)]
+    pub ws_tokens: WsTokenSettings,
+    #[serde(default)]
+    pub output_pipeline: OutputPipelineSettings,
+    #[serde(default)]
+    pub device_locks: DeviceLockSettings,
+    #[serde(default)]
+    pub config_backup: ConfigBackupSettings,
+    #[serde(default)]
+    pub passthrough: PassthroughSettings,
+    #[serde(default)]
+    pub binary_protocol: BinaryProtocolSettings,
+    #[serde(default)]
+    pub background_tasks: BackgroundTaskSettings,
+    #[serde(default)]
+    pub alerts: AlertSettings,
+    #[serde(default)]
+    pub logging: LoggingSettings,
+    #[serde(default)]
+    pub reachability: ReachabilitySettings,
+    #[serde(default)]
+    pub websocket: WebSocketSettings,
+    #[serde(default)]
+    pub screen_subscriptions: ScreenSubscriptionSettings,
+    #[serde(default)]
+    pub timeline: TimelineSettings,
+    #[serde(default)]
+    pub host_keys: HostKeySettings,
+    #[serde(default)]
+    pub bulk_exec: BulkExecSettings,
+    #[serde(default)]
+    pub export: ExportSettings,
+    /// Per device type overrides, keyed by the lowercase device type
+    #[serde(default)]
+    pub profiles: HashMap<String, DeviceProfile>,
+}
+
+/// Connection behaviour specific to a kind of device
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceProfile {
+    /// SSH transport compression; falls back to `ssh.connection.compress`
+    pub compression: Option<CompressionMode>,
+    /// TERM for the device's shells, e.g. `vt100` or `dumb` for devices that mis-render xterm
+    pub terminal_type: Option<String>,
+    /// Output stages in place of `output_pipeline.stages`
+    pub output_stages: Option<Vec<String>>,
+    /// Accommodations in session setup for old devices that misbehave with the defaults
+    pub quirks: DeviceQuirks,
+}
+
+/// Accommodations for old devices, each turned on separately in their profile
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceQuirks {
+    /// Open the shell channel with a tiny window and packet size, for devices whose buffers overflow at the defaults
+    pub small_window: bool,
+    /// Sign with ssh-rsa even when the server's EXT_INFO lists rsa-sha2 algorithms it does not verify
+    pub ignore_ext_info: bool,
+    /// Request the PTY without terminal modes, for devices that reject the modes they do not know
+    pub no_pty_modes: bool,
+    /// Send no environment variables, for devices that drop the channel on an env request
+    pub no_environment: bool,
+}
+
+/// SSH transport compression policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionMode {
+    On,
+    Off,
+    /// Compress only when the measured round-trip time indicates a slow link
+    Auto,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerSettings {
+    pub address: String,
+    pub port: u16,
+    pub tls_enabled: bool,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    /// Identifier of this instance; defaults to `WEBSSH_NODE_ID` or the hostname
+    #[serde(default)]
+    pub node_id: Option<String>,
+    /// Response header carrying the node ID for load balancer session affinity
+    #[serde(default = "default_node_header")]
+    pub node_header: String,
+    /// Seconds between checks of the certificate and key files for changes; 0 disables reloading
+    #[serde(default)]
+    pub tls_reload_seconds: u64,
+    /// Seconds between checks of settings.json for changes; 0 disables reloading on change
+    #[serde(default = "default_settings_reload_seconds")]
+    pub settings_reload_seconds: u64,
+    /// A separate listener for the control API
+    #[serde(default)]
+    pub control_plane: ControlPlaneSettings,
+}
+
+fn default_node_header() -> String {
+    "X-Session-Node".to_string()
+}
+
+fn default_settings_reload_seconds() -> u64 {
+    5
+}
+
+/// A listener of its own for the `/api/*` routes, so firewalls can keep them to the backend network
+///
+/// The main listener then serves the terminal page, the WebSockets and
+/// `/connect`, plus the session status and terminate routes the page calls.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlPlaneSettings {
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+    /// Refuse requests without an API key, including those with a valid JWT
+    pub require_api_key: bool,
+}
+
+impl Default for ControlPlaneSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 8889,
+            require_api_key: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiKeySettings {
+    /// Require an API key with the right scope on the HTTP API
+    pub enabled: bool,
+    /// JSON file holding the hashed keys
+    pub store_file: String,
+}
+
+impl Default for ApiKeySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            store_file: "api_keys.json".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingSettings {
+    /// Record terminal output of every session in asciicast v2 format
+    pub enabled: bool,
+    /// Directory where recordings and their metadata are written
+    pub directory: String,
+    /// Also record what users type (may capture passwords typed at prompts)
+    pub record_input: bool,
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "recordings".to_string(),
+            record_input: false,
+        }
+    }
+}
+
+/// WebSocket frame captures, started per session through the admin API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureSettings {
+    /// Directory where capture files are written
+    pub directory: String,
+    /// Size at which a capture stops writing frames
+    pub max_bytes: u64,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            directory: "captures".to_string(),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// CORS, proxy and request size rules of the HTTP API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub cors: CorsSettings,
+    /// Addresses or CIDR ranges of reverse proxies whose forwarded header is believed
+    pub trusted_proxies: Vec<String>,
+    /// Header carrying the client address, as set by the trusted proxies
+    pub forwarded_header: String,
+    /// Largest JSON request body accepted
+    pub max_body_bytes: usize,
+    /// Largest SFTP upload accepted; 0 means no limit
+    pub max_upload_bytes: usize,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            cors: CorsSettings::default(),
+            trusted_proxies: Vec::new(),
+            forwarded_header: "X-Forwarded-For".to_string(),
+            max_body_bytes: 2 * 1024 * 1024,
+            max_upload_bytes: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Origins allowed to call the API from a browser, e.g. `https://ipam.example.com`,
+    /// or `"*"` for any; none allows only same-origin pages
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache a preflight response
+    pub max_age_seconds: u64,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "DELETE".to_string()],
+            allowed_headers: vec!["content-type".to_string(), "authorization".to_string(), "x-api-key".to_string()],
+            max_age_seconds: 600,
+        }
+    }
+}
+
+/// Short-lived SSH user certificates requested from a CA at connect time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshCaSettings {
+    /// Allow `auth_type: "certificate"` connections
+    pub enabled: bool,
+    /// Signing endpoint of the CA, e.g. `https://vault:8200/v1/ssh-client-signer/sign/webssh`
+    pub url: String,
+    /// File holding the token sent to the CA; read on every request so it can be rotated
+    pub token_file: Option<String>,
+    /// PEM bundle of the roots trusted for an HTTPS CA; defaults to the system bundle
+    pub ca_file: Option<String>,
+    /// Lifetime requested for each certificate
+    pub ttl_seconds: u64,
+    pub timeout_seconds: u64,
+    /// Principals requested for devices in no group; `{user}` is the SSO identity
+    /// and `{username}` the SSH username of the connect request
+    pub default_principals: Vec<String>,
+    /// Principal mapping by device group; the first group the device belongs to applies
+    pub groups: Vec<PrincipalGroup>,
+}
+
+impl Default for SshCaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            token_file: None,
+            ca_file: None,
+            ttl_seconds: 300,
+            timeout_seconds: 10,
+            default_principals: vec!["{user}".to_string()],
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// One-off commands run over REST, without a terminal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecSettings {
+    pub enabled: bool,
+    /// Time allowed for all of a request's commands when it gives none
+    pub default_timeout_seconds: u64,
+    pub max_timeout_seconds: u64,
+    /// Output kept per command, stdout and stderr together, when the request gives no limit
+    pub default_max_output_bytes: usize,
+    pub max_output_bytes: usize,
+    /// Commands one request may run
+    pub max_commands: usize,
+    /// Run commands over the connection of a live session logged in to the
+    /// same device with the same credentials, rather than connecting again
+    pub share_connections: bool,
+}
+
+impl Default for ExecSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default_timeout_seconds: 30,
+            max_timeout_seconds: 600,
+            default_max_output_bytes: 1024 * 1024,
+            max_output_bytes: 16 * 1024 * 1024,
+            max_commands: 50,
+            share_connections: true,
+        }
+    }
+}
+
+/// Commands run on many devices at once, each device as `/api/exec` would run them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BulkExecSettings {
+    pub enabled: bool,
+    /// Devices a job runs on at once when the request gives no concurrency
+    pub default_concurrency: usize,
+    pub max_concurrency: usize,
+    /// Devices one job may run on
+    pub max_devices: usize,
+    /// How long a finished job's results can still be fetched
+    pub retention_seconds: u64,
+}
+
+impl Default for BulkExecSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default_concurrency: 10,
+            max_concurrency: 50,
+            max_devices: 1000,
+            retention_seconds: 3600,
+        }
+    }
+}
+
+/// Session files for terminal programs, opening inventory devices through `webssh-rs attach`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportSettings {
+    pub enabled: bool,
+    /// The gateway's URL as engineers' machines reach it; defaults to the address the export was requested at
+    pub gateway_url: Option<String>,
+    /// How the exported sessions run the gateway's binary, e.g. a full path
+    pub attach_command: String,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gateway_url: None,
+            attach_command: "webssh-rs".to_string(),
+        }
+    }
+}
+
+/// Interactive sessions to legacy devices over telnet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelnetSettings {
+    /// Accept `"protocol": "telnet"` in connect requests; credentials then cross the network in cleartext
+    pub enabled: bool,
+}
+
+impl Default for TelnetSettings {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Parsing of command output into rows with TextFSM templates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParsingSettings {
+    pub enabled: bool,
+    /// Directory holding an ntc-templates style `index` and the templates it names;
+    /// tried before the bundled templates
+    pub templates_dir: String,
+}
+
+impl Default for ParsingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            templates_dir: "templates".to_string(),
+        }
+    }
+}
+
+/// Draining the instance for maintenance; turned on and off through `/api/admin/maintenance`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceSettings {
+    /// Start in maintenance mode
+    pub enabled: bool,
+    /// What connect requests are refused with, unless the request turning maintenance on gives another
+    pub message: String,
+    /// Shown to attached clients when started in maintenance mode
+    pub banner: Option<String>,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            message: "The gateway is under maintenance; try again later".to_string(),
+            banner: None,
+        }
+    }
+}
+
+/// Thresholds of the gateway's alerts, each counted over the last minute; 0 turns one off
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertSettings {
+    pub enabled: bool,
+    /// Sessions ending because their I/O stalled or their connection was lost
+    pub abnormal_terminations_per_minute: u32,
+    /// WebSockets resuming sessions
+    pub reconnects_per_minute: u32,
+    /// Failed connections to any one device
+    pub handshake_failures_per_device_per_minute: u32,
+    /// How long an alert is held off after it was raised, for the same device
+    pub cooldown_seconds: u64,
+    /// Post alerts to the webhooks as `alert` events, besides logging them
+    pub webhook: bool,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            abnormal_terminations_per_minute: 10,
+            reconnects_per_minute: 50,
+            handshake_failures_per_device_per_minute: 5,
+            cooldown_seconds: 300,
+            webhook: true,
+        }
+    }
+}
+
+/// The gateway's log output, set up once at startup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingSettings {
+    /// The most verbose level logged, e.g. `info` or `debug`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Compact,
+        }
+    }
+}
+
+/// Probes of the inventory devices, for `/api/reachability`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReachabilitySettings {
+    pub enabled: bool,
+    /// Time between probes of each device
+    pub interval_seconds: u64,
+    /// Each device's next probe is moved by up to this share of the interval,
+    /// earlier or later, so the devices are not probed all at once
+    pub jitter_percent: u8,
+    /// Time allowed for a device to accept the connection
+    pub timeout_ms: u64,
+    /// Probes in flight at once
+    pub max_concurrent: usize,
+    /// Probe only the devices with this inventory tag; all of them when unset
+    pub tag: Option<String>,
+}
+
+impl Default for ReachabilitySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_seconds: 60,
+            jitter_percent: 20,
+            timeout_ms: 3000,
+            max_concurrent: 16,
+            tag: None,
+        }
+    }
+}
+
+/// How terminal output is framed and compressed on the WebSocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketSettings {
+    /// Output arriving within this long of the last frame waits and goes out
+    /// with it in one frame; 0 sends every chunk as it arrives
+    pub batch_interval_ms: u64,
+    /// Largest output frame
+    pub max_batch_bytes: usize,
+    /// Accept the browser's `permessage-deflate` offer
+    pub permessage_deflate: bool,
+    /// Messages smaller than this are sent uncompressed
+    pub deflate_threshold_bytes: usize,
+    /// 0 (none) to 9 (best)
+    pub deflate_level: u32,
+    /// Keep the compression window across messages, so output repeating
+    /// earlier frames compresses to little
+    pub deflate_context_takeover: bool,
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        Self {
+            batch_interval_ms: 5,
+            max_batch_bytes: 65536,
+            permessage_deflate: true,
+            deflate_threshold_bytes: 128,
+            deflate_level: 1,
+            deflate_context_takeover: true,
+        }
+    }
+}
+
+/// Subscriptions to a region or scraped fields of a session's screen, for dashboards
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScreenSubscriptionSettings {
+    /// Allow subscriptions; tracks the screen of every session
+    pub enabled: bool,
+    /// Least time between two updates, when the subscription does not ask for more
+    pub default_interval_ms: u64,
+    /// Least time between two updates a subscription may ask for
+    pub min_interval_ms: u64,
+    /// Most fields one subscription may scrape
+    pub max_fields: usize,
+    /// Longest field pattern
+    pub max_pattern_bytes: usize,
+}
+
+impl Default for ScreenSubscriptionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default_interval_ms: 1000,
+            min_interval_ms: 200,
+            max_fields: 32,
+            max_pattern_bytes: 1024,
+        }
+    }
+}
+
+/// Each session's events in order, for `/api/session/:session_id/timeline`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimelineSettings {
+    pub enabled: bool,
+    /// Most events kept in memory and returned for one session; the store keeps them all
+    pub max_events: usize,
+    /// Add the command lines typed, as rebuilt from the keystrokes
+    pub commands: bool,
+}
+
+impl Default for TimelineSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_events: 1000,
+            commands: true,
+        }
+    }
+}
+
+/// Trust on first use for devices' SSH host keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostKeySettings {
+    /// Hold connections to a device whose host key is not pinned until the user confirms it,
+    /// and refuse a device whose key has changed
+    pub enabled: bool,
+    /// JSON file holding the pinned keys
+    pub pins_file: String,
+}
+
+impl Default for HostKeySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pins_file: "host_keys.json".to_string(),
+        }
+    }
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One short line per event, for reading
+    Compact,
+    /// Multi-line events with their fields, for debugging
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+/// Single-use tokens that WebSockets present to attach to a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WsTokenSettings {
+    /// Refuse WebSockets to `/ws/:session_id` without a token; off only for older clients
+    pub required: bool,
+    /// How long a token may wait to be used
+    pub ttl_seconds: u64,
+    /// How long a transferred session waits for a WebSocket, and its token to be used
+    pub transfer_ttl_seconds: u64,
+}
+
+impl Default for WsTokenSettings {
+    fn default() -> Self {
+        Self {
+            required: true,
+            ttl_seconds: 60,
+            transfer_ttl_seconds: 300,
+        }
+    }
+}
+
+/// Advisory locks on devices only one operator should configure at a time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceLockSettings {
+    pub enabled: bool,
+    /// Inventory tag of the devices whose sessions take the lock
+    pub tag: String,
+    /// What connects by other users to a locked device get
+    pub policy: DeviceLockPolicy,
+}
+
+impl Default for DeviceLockSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tag: "config-locked".to_string(),
+            policy: DeviceLockPolicy::Warn,
+        }
+    }
+}
+
+/// How a connect to a device locked by another user is handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceLockPolicy {
+    /// Connect, with a warning naming the holder
+    #[default]
+    Warn,
+    /// Refuse with `DEVICE_LOCKED`
+    Block,
+}
+
+/// Scheduled backups of the running configuration of inventory devices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigBackupSettings {
+    pub enabled: bool,
+    /// Time between backups of every device
+    pub interval_seconds: u64,
+    /// Directory the configurations are kept in, one subdirectory per device
+    pub directory: String,
+    /// Compress the stored configurations
+    pub gzip: bool,
+    /// Back up only the devices with this inventory tag; all of them when unset
+    pub tag: Option<String>,
+    /// Command printing the configuration, by lowercase device type; devices of other types are skipped
+    pub commands: HashMap<String, String>,
+    /// Time allowed for one device's command
+    pub timeout_seconds: u64,
+    /// Largest configuration kept; a longer one fails its backup
+    pub max_output_bytes: usize,
+    /// Versions kept per device, oldest removed first; 0 keeps them all
+    pub keep_versions: usize,
+}
+
+impl Default for ConfigBackupSettings {
+    fn default() -> Self {
+        let commands = [
+            ("cisco", "show running-config"),
+            ("cisco-ios", "show running-config"),
+            ("cisco-xr", "show running-config"),
+            ("cisco_ios", "show running-config"),
+            ("cisco_xe", "show running-config"),
+            ("cisco_nxos", "show running-config"),
+            ("arista-eos", "show running-config"),
+            ("arista_eos", "show running-config"),
+            ("junos", "show configuration | display set"),
+            ("juniper", "show configuration | display set"),
+        ];
+        Self {
+            enabled: false,
+            interval_seconds: 86400,
+            directory: "config_backups".to_string(),
+            gzip: true,
+            tag: None,
+            commands: commands.iter()
+                .map(|(device_type, command)| (device_type.to_string(), command.to_string()))
+                .collect(),
+            timeout_seconds: 120,
+            max_output_bytes: 16 * 1024 * 1024,
+            keep_versions: 100,
+        }
+    }
+}
+
+/// Sessions whose output goes to the WebSocket uninspected, for trusted automation
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PassthroughSettings {
+    /// Accept `"passthrough": true` in connect requests; such sessions are neither recorded nor watched
+    pub enabled: bool,
+    /// JWT roles allowed to ask for it; any caller allowed to connect when empty
+    pub roles: Vec<String>,
+}
+
+/// How frames of the binary WebSocket protocol are compressed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BinaryProtocolSettings {
+    /// Offer `binary-v1-zstd` to clients that ask for it; they get `binary-v1` otherwise
+    pub zstd: bool,
+    /// Messages larger than this are compressed, when that makes them smaller
+    pub compression_threshold_bytes: usize,
+    /// 0 (none) to 9 (best)
+    pub gzip_level: u32,
+    /// 1 to 22; negative levels trade size for speed
+    pub zstd_level: i32,
+}
+
+impl Default for BinaryProtocolSettings {
+    fn default() -> Self {
+        Self {
+            zstd: true,
+            compression_threshold_bytes: 1024,
+            gzip_level: 1,
+            zstd_level: 3,
+        }
+    }
+}
+
+/// The stages a shell's output passes through before it reaches the clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputPipelineSettings {
+    /// Stage names in order: `recorder`, `watchers`, `mask`, or stages registered at startup
+    pub stages: Vec<String>,
+    /// What the `mask` stage replaces
+    pub mask_rules: Vec<MaskRule>,
+}
+
+impl Default for OutputPipelineSettings {
+    fn default() -> Self {
+        Self {
+            stages: vec!["recorder".to_string(), "watchers".to_string()],
+            mask_rules: Vec::new(),
+        }
+    }
+}
+
+/// Output the `mask` stage replaces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaskRule {
+    /// Regular expression, matched against the raw output
+    pub pattern: String,
+    /// What each match becomes; `$1` and `${name}` refer to groups
+    #[serde(default = "default_mask")]
+    pub replacement: String,
+}
+
+fn default_mask() -> String {
+    "***".to_string()
+}
+
+/// Helping users through a device's demand for a new password right after login
+///
+/// Patterns are regular expressions matched ignoring case.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordChangeSettings {
+    pub enabled: bool,
+    /// Output that gives away an expired password the device wants changed
+    pub patterns: Vec<String>,
+    /// How much of a shell's output is looked at for them
+    pub scan_bytes: usize,
+    /// The end of output asking for a password, answered with a secret frame
+    pub prompt_pattern: String,
+    /// Output showing the device took the new password
+    pub success_patterns: Vec<String>,
+    /// Output showing the device refused the new password
+    pub failure_patterns: Vec<String>,
+    /// The end of output showing the device's prompt, once the new password is confirmed
+    pub shell_prompt_pattern: String,
+    /// Longest the user is waited for before the flow is abandoned
+    pub timeout_seconds: u64,
+    /// Store the new password where a session's `vault:` credential_ref points
+    pub write_back: bool,
+}
+
+impl Default for PasswordChangeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            patterns: vec![
+                r"password (has )?expired".to_string(),
+                r"(must|required to) change (your )?password".to_string(),
+                r"password change (is )?required".to_string(),
+                r"change your password (now|immediately)".to_string(),
+            ],
+            scan_bytes: 8192,
+            prompt_pattern: r"(password|passcode)[^\r\n:]{0,40}:\s*$".to_string(),
+            success_patterns: vec![
+                r"password (was |has been )?(successfully )?(changed|updated)".to_string(),
+                r"authentication tokens updated successfully".to_string(),
+            ],
+            failure_patterns: vec![
+                r"do not match".to_string(),
+                r"mismatch".to_string(),
+                r"bad password".to_string(),
+                r"too (short|simple|similar)".to_string(),
+                r"authentication token manipulation error".to_string(),
+                r"password unchanged".to_string(),
+            ],
+            shell_prompt_pattern: r"[>#$%]\s*$".to_string(),
+            timeout_seconds: 300,
+            write_back: false,
+        }
+    }
+}
+
+/// Posting session lifecycle and security events to other systems, e.g. the IPAM portal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub enabled: bool,
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Attempts at delivering an event to an endpoint before it is dropped
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled after each failed attempt up to `max_backoff_ms`
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub timeout_seconds: u64,
+    /// Events waiting for an endpoint, beyond which new ones are dropped
+    pub queue_size: usize,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoints: Vec::new(),
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            timeout_seconds: 10,
+            queue_size: 1000,
+        }
+    }
+}
+
+/// Where events are posted
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature sent in `X-Webhook-Signature`; events go unsigned without it
+    pub secret: Option<String>,
+    /// Events posted to the endpoint; empty for all
+    pub events: Vec<WebhookEventType>,
+    /// PEM bundle of the roots trusted for an HTTPS endpoint; defaults to the system bundle
+    pub ca_file: Option<String>,
+}
+
+/// What an event posted to webhooks reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventType {
+    SessionCreated,
+    /// A WebSocket attached to the session's shell, at first or on resuming
+    SessionAttached,
+    /// The session ended, for any reason
+    SessionTerminated,
+    /// A device refused the credentials of a connect request
+    AuthFailed,
+    /// The command policy refused a command line
+    CommandBlocked,
+    /// Abnormal session ends, reconnects or failed connections to a device passed their threshold
+    Alert,
+}
+
+/// What a WebSocket that cannot keep up with its session's output is sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlowConsumerSettings {
+    /// Send a client that falls behind periodic snapshots of the screen instead of the full output
+    pub summarize: bool,
+    /// Output a client may fall behind by before it is summarized; falling behind the
+    /// oldest buffered output summarizes it regardless
+    pub backlog_bytes: u64,
+    /// A summarized client that keeps up returns to the full output once less than
+    /// this much is produced between snapshots
+    pub resume_bytes: u64,
+    pub snapshot_interval_ms: u64,
+}
+
+impl Default for SlowConsumerSettings {
+    fn default() -> Self {
+        Self {
+            summarize: false,
+            backlog_bytes: 1024 * 1024,
+            resume_bytes: 64 * 1024,
+            snapshot_interval_ms: 1000,
+        }
+    }
+}
+
+/// Holding output back from a WebSocket whose client is slow to take it
+///
+/// Output keeps going to the session's buffer meanwhile, so the shell is
+/// never held up by a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlowControlSettings {
+    pub enabled: bool,
+    /// Bytes queued for a WebSocket but not yet written at which it is sent no more output
+    pub high_watermark_bytes: usize,
+    /// Bytes the queue drains to before output is sent again
+    pub low_watermark_bytes: usize,
+    /// Send a held-back client a snapshot of the screen in place of the updates a
+    /// full-screen application drew meanwhile; tracks the screen of every session
+    pub coalesce_fullscreen: bool,
+}
+
+impl Default for FlowControlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            high_watermark_bytes: 512 * 1024,
+            low_watermark_bytes: 128 * 1024,
+            coalesce_fullscreen: false,
+        }
+    }
+}
+
+/// Who is watching or driving a shared session, as told to its clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresenceSettings {
+    pub enabled: bool,
+    /// A participant sending nothing for this long is shown idle
+    pub idle_seconds: u64,
+    /// Tell the others while the writer is typing
+    pub typing_indicator: bool,
+    /// How long after a keystroke the writer is still shown typing
+    pub typing_timeout_ms: u64,
+}
+
+impl Default for PresenceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_seconds: 300,
+            typing_indicator: true,
+            typing_timeout_ms: 2000,
+        }
+    }
+}
+
+/// Cloning sessions to the lab twins of their devices, to rehearse changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LabSettings {
+    pub enabled: bool,
+    /// Pause between commands replayed in a dry run, for the device to take each one
+    pub replay_delay_ms: u64,
+    /// Most commands a dry run replays
+    pub max_replay_commands: usize,
+}
+
+impl Default for LabSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            replay_delay_ms: 500,
+            max_replay_commands: 500,
+        }
+    }
+}
+
+/// Limits on connect attempts, against password guessing through the gateway
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    pub window_seconds: u64,
+    /// Connect requests a source address may make per window
+    pub per_source: u32,
+    /// Connect requests a target device may receive per window, from all sources
+    pub per_target: u32,
+    /// Failed logins to a device in a row before it is locked out
+    pub lockout_after: u32,
+    /// First lockout; each further failure doubles it
+    pub lockout_base_seconds: u64,
+    pub lockout_max_seconds: u64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_seconds: 60,
+            per_source: 30,
+            per_target: 20,
+            lockout_after: 5,
+            lockout_base_seconds: 30,
+            lockout_max_seconds: 3600,
+        }
+    }
+}
+
+/// Periodic `stats` messages telling the client how its session's traffic flows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientStatsSettings {
+    pub enabled: bool,
+    pub interval_seconds: u64,
+    /// How often clients are pinged to measure the round trip; 0 turns pings off
+    pub ping_interval_seconds: u64,
+}
+
+impl Default for ClientStatsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_seconds: 2,
+            ping_interval_seconds: 10,
+        }
+    }
+}
+
+/// Devices whose certificates carry the same principals
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrincipalGroup {
+    pub name: String,
+    /// Addresses or CIDR ranges, hostnames, or `*.suffix` patterns; empty matches any host
+    pub hosts: Vec<String>,
+    /// Device types, as in `profiles`; empty matches any type
+    pub device_types: Vec<String>,
+    pub principals: Vec<String>,
+}
+
+/// Log of the command lines typed in sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditSettings {
+    /// Reconstruct typed command lines and append them to the audit log
+    pub enabled: bool,
+    /// JSON Lines file the commands are appended to
+    pub path: String,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "audit.jsonl".to_string(),
+        }
+    }
+}
+
+/// Read-only share links to a session's shell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharingSettings {
+    pub enabled: bool,
+    /// Lifetime of a link when the request gives none
+    pub default_ttl_seconds: u64,
+    pub max_ttl_seconds: u64,
+    /// Read-only WebSockets per session, however they joined
+    pub max_viewers: usize,
+    #[serde(default)]
+    pub embed: EmbedSettings,
+}
+
+impl Default for SharingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default_ttl_seconds: 3600,
+            max_ttl_seconds: 86400,
+            max_viewers: 10,
+            embed: EmbedSettings::default(),
+        }
+    }
+}
+
+/// Short-lived read-only tokens for pages of other tools to embed a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmbedSettings {
+    pub enabled: bool,
+    /// Origins of the pages that may embed sessions, e.g. `https://noc.example.com`
+    pub allowed_origins: Vec<String>,
+    /// Lifetime of a token when the request gives none
+    pub default_ttl_seconds: u64,
+    pub max_ttl_seconds: u64,
+}
+
+impl Default for EmbedSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allowed_origins: Vec::new(),
+            default_ttl_seconds: 300,
+            max_ttl_seconds: 3600,
+        }
+    }
+}
+
+/// How long a session survives without a WebSocket, for clients to resume it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectSettings {
+    /// Seconds a session is kept after its WebSocket drops; 0 closes it at once
+    pub grace_seconds: u64,
+    /// Output kept per session for replay to a reconnecting client
+    pub buffer_bytes: usize,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        Self {
+            grace_seconds: 60,
+            buffer_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Session output older than the reconnect buffer, kept on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScrollbackSettings {
+    /// Spill output evicted from the reconnect buffer to disk instead of dropping it
+    pub spill_enabled: bool,
+    /// Directory for the spill files; defaults to the system temporary directory
+    pub directory: Option<String>,
+    /// Disk kept per session; the oldest output is dropped beyond it
+    pub max_bytes: u64,
+    /// Size of each spill file
+    pub segment_bytes: u64,
+}
+
+impl Default for ScrollbackSettings {
+    fn default() -> Self {
+        Self {
+            spill_enabled: true,
+            directory: None,
+            max_bytes: 256 * 1024 * 1024,
+            segment_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Caps on concurrently open sessions; 0 leaves a cap off
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionLimitSettings {
+    pub max_per_user: usize,
+    pub max_per_device: usize,
+    pub max_total: usize,
+    /// What happens to a new session that would exceed a cap
+    pub on_limit: LimitPolicy,
+}
+
+/// Removal of sessions left idle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionCleanupSettings {
+    /// How long a session may go without traffic before it is removed; 0 keeps idle sessions
+    pub idle_timeout_seconds: u64,
+    /// How often idle sessions are looked for, besides when a warning or removal is due
+    pub interval_seconds: u64,
+    /// How long before removing an idle session its clients are warned; 0 for no warning
+    pub warning_seconds: u64,
+    /// Device output keeps a session active too, not only input from its users
+    pub output_counts_as_activity: bool,
+}
+
+impl Default for SessionCleanupSettings {
+    fn default() -> Self {
+        Self {
+            idle_timeout_seconds: 3600,
+            interval_seconds: 300,
+            warning_seconds: 60,
+            output_counts_as_activity: false,
+        }
+    }
+}
+
+/// Absolute limits on how long a session may stay open, however active it is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionLifetimeSettings {
+    pub enabled: bool,
+    /// How often sessions are checked against their limits
+    pub check_interval_seconds: u64,
+    /// Seconds before the end at which attached clients are warned
+    pub warn_before_seconds: Vec<u64>,
+    /// Class of the sessions that match no other class; none if it is not in `classes`
+    pub default_class: String,
+    /// Lifetime classes by name
+    pub classes: HashMap<String, LifetimeClass>,
+}
+
+impl Default for SessionLifetimeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            check_interval_seconds: 15,
+            warn_before_seconds: vec![900, 300, 60],
+            default_class: "standard".to_string(),
+            classes: HashMap::from([
+                ("standard".to_string(), LifetimeClass {
+                    max_lifetime_seconds: 8 * 3600,
+                    max_extensions: 2,
+                    ..LifetimeClass::default()
+                }),
+                ("privileged".to_string(), LifetimeClass {
+                    max_lifetime_seconds: 3600,
+                    usernames: vec!["root".to_string(), "admin".to_string()],
+                    extension_seconds: 1800,
+                    max_extensions: 1,
+                    require_approval: true,
+                }),
+            ]),
+        }
+    }
+}
+
+/// How long the sessions of a class may stay open, and how they may be extended
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Settings {
-    pub ssh: SSHSettings,
-    pub server: ServerSettings,
+#[serde(default)]
+pub struct LifetimeClass {
+    /// 0 for no limit
+    pub max_lifetime_seconds: u64,
+    /// SSH usernames whose sessions are in this class, matched case-insensitively;
+    /// a session matching several classes gets the shortest lifetime
+    pub usernames: Vec<String>,
+    /// Longest extension a single request may add
+    pub extension_seconds: u64,
+    /// Extensions a session may be granted; 0 allows none
+    pub max_extensions: u32,
+    /// Extensions wait for an administrator to approve them
+    pub require_approval: bool,
+}
+
+impl Default for LifetimeClass {
+    fn default() -> Self {
+        Self {
+            max_lifetime_seconds: 0,
+            usernames: Vec::new(),
+            extension_seconds: 3600,
+            max_extensions: 1,
+            require_approval: false,
+        }
+    }
 }
 
+/// Commands refused before they reach a device, by device type and user role
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerSettings {
+#[serde(default)]
+pub struct CommandPolicySettings {
+    pub enabled: bool,
+    /// Refuse lines edited with history recall or completion, whose text the gateway cannot know
+    pub block_uncertain: bool,
+    pub rules: Vec<CommandRule>,
+    #[serde(default)]
+    pub guardrail: GuardrailSettings,
+}
+
+impl Default for CommandPolicySettings {
+    fn default() -> Self {
+        let deny = |name: &str, device_types: &[&str], commands: &[&str]| CommandRule {
+            name: name.to_string(),
+            action: RuleAction::Deny,
+            commands: commands.iter().map(|command| command.to_string()).collect(),
+            device_types: device_types.iter().map(|device_type| device_type.to_string()).collect(),
+            roles: Vec::new(),
+            except_roles: vec!["network-admin".to_string()],
+        };
+        Self {
+            enabled: false,
+            block_uncertain: false,
+            rules: vec![
+                deny("network-destructive", &["cisco_ios", "cisco_xe", "cisco_nxos", "arista_eos"], &[
+                    "rel[[oad]]",
+                    "er[[ase]] (startup-config|nvram:|flash:)",
+                    "wr[[ite]] er[[ase]]",
+                    "fo[[rmat]]",
+                ]),
+                deny("linux-destructive", &["linux"], &[
+                    r"(sudo )?rm -[a-z]*r[a-z]* /\*?",
+                    "(sudo )?(reboot|shutdown|halt|poweroff)",
+                    r"(sudo )?mkfs(\.\w+)?",
+                ]),
+            ],
+            guardrail: GuardrailSettings::default(),
+        }
+    }
+}
+
+/// Holding users of restricted roles, e.g. new hires, to an allow-list of commands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuardrailSettings {
+    /// Roles held to the allow-list, matched case-insensitively; empty for none
+    pub roles: Vec<String>,
+    /// Patterns of the commands they may send, as in `CommandRule::commands`;
+    /// allow rules that apply to them add to these
+    pub commands: Vec<String>,
+    /// Shown in the terminal under a refused command
+    pub message: String,
+    /// Let a refused command be sent for approval, after which it may be entered once
+    pub approval: bool,
+    /// How long a request waits for a decision, and an approved command for its use
+    pub approval_ttl_seconds: u64,
+}
+
+impl Default for GuardrailSettings {
+    fn default() -> Self {
+        Self {
+            roles: Vec::new(),
+            commands: vec![
+                "sh[[ow]]".to_string(),
+                "ping".to_string(),
+                "tr[[aceroute]]".to_string(),
+                "term[[inal]] (len[[gth]]|wid[[th]])".to_string(),
+                "ex[[it]]".to_string(),
+            ],
+            message: "Your role may only run allow-listed commands. Ask a senior engineer if you need more.".to_string(),
+            approval: false,
+            approval_ttl_seconds: 900,
+        }
+    }
+}
+
+/// Commands a rule allows or refuses, and whom it applies to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandRule {
+    pub name: String,
+    pub action: RuleAction,
+    /// Patterns matched against the start of the command line, case-insensitively,
+    /// with `[[...]]` marking the optional rest of an abbreviated word, e.g. `rel[[oad]]`
+    pub commands: Vec<String>,
+    /// Device types the rule applies to; empty for all
+    pub device_types: Vec<String>,
+    /// Roles the rule applies to; empty for everyone
+    pub roles: Vec<String>,
+    /// Roles the rule does not apply to
+    pub except_roles: Vec<String>,
+}
+
+/// What a rule does with the commands it matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Refuse the commands
+    #[default]
+    Deny,
+    /// Refuse everything else; a command must match one of the allow rules that apply
+    Allow,
+}
+
+/// Roles and device ACLs deciding what users authenticated by JWT may do
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthorizationSettings {
+    pub enabled: bool,
+    /// Token roles granting each role, matched case-insensitively; a user with
+    /// several gets the highest
+    pub admin_roles: Vec<String>,
+    pub operator_roles: Vec<String>,
+    pub viewer_roles: Vec<String>,
+    /// Role of users whose token carries none of the above; none refuses them everything
+    pub default_role: Option<Role>,
+    /// Devices operators may connect to; admins may connect to any
+    pub acls: Vec<DeviceAcl>,
+}
+
+impl Default for AuthorizationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            admin_roles: vec!["admin".to_string()],
+            operator_roles: vec!["operator".to_string()],
+            viewer_roles: vec!["viewer".to_string()],
+            default_role: None,
+            acls: Vec::new(),
+        }
+    }
+}
+
+/// What a user may do, from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Watch sessions shared with them
+    Viewer,
+    /// Connect to the devices an ACL grants them, and use their own sessions
+    Operator,
+    /// Connect to any device, watch any session and terminate others' sessions
+    Admin,
+}
+
+/// Devices a group of operators may connect to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceAcl {
+    pub name: String,
+    /// Token subjects the entry is for; with `roles` empty too, every operator
+    pub subjects: Vec<String>,
+    /// Token roles the entry is for, matched case-insensitively
+    pub roles: Vec<String>,
+    /// Addresses, CIDR ranges, hostnames or `*.suffix` patterns of the devices
+    pub hosts: Vec<String>,
+    /// Inventory tags of the devices; with `hosts` empty too, every device
+    pub tags: Vec<String>,
+}
+
+/// Handling of a new session beyond a session cap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitPolicy {
+    /// Refuse the new session
+    #[default]
+    Reject,
+    /// Close the least recently used session without a WebSocket to make room,
+    /// refusing the new one if every session is attached
+    EvictOldest,
+}
+
+/// Embedded TFTP/HTTP server devices pull images from, e.g. `copy tftp://host/image.bin flash:`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileServerSettings {
+    /// Run the TFTP and HTTP listeners
+    pub enabled: bool,
+    /// Staging area served to devices; nothing outside it is reachable
+    pub directory: String,
     pub address: String,
-    pub port: u16,
-    pub tls_enabled: bool,
-    pub cert_file: Option<String>,
-    pub key_file: Option<String>,
+    /// Devices usually only speak TFTP on port 69, which needs CAP_NET_BIND_SERVICE
+    pub tftp_port: u16,
+    pub http_port: u16,
+    /// Seconds to wait for a TFTP acknowledgement before retransmitting
+    pub tftp_timeout_seconds: u64,
+    /// Devices always allowed to fetch files, in addition to those granted per session
+    pub acl: Vec<FileServerAcl>,
+}
+
+/// Lets devices at the given addresses fetch files from the staging area
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileServerAcl {
+    /// An IP address or CIDR block, e.g. "10.20.0.0/16"
+    pub source: String,
+    /// Limits the device to files under this path of the staging area
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+}
+
+impl Default for FileServerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "staging".to_string(),
+            address: "0.0.0.0".to_string(),
+            tftp_port: 69,
+            http_port: 8069,
+            tftp_timeout_seconds: 5,
+            acl: Vec::new(),
+        }
+    }
+}
+
+/// Where session lifecycle history is kept across restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub enabled: bool,
+    /// SQLite database file
+    pub path: String,
+    /// Days of history kept; older sessions are pruned at startup
+    pub retention_days: u32,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "sessions.db".to_string(),
+            retention_days: 30,
+        }
+    }
+}
+
+/// Where instances behind one load balancer find each other's sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterSettings {
+    pub registry: RegistryBackend,
+    /// `redis://[[user]:password@]host[:port][/database]`
+    pub redis_url: String,
+    /// Prefix of every key written to Redis, so clusters can share a server
+    pub key_prefix: String,
+    /// Base URL this instance is reached at directly, e.g. `https://webssh-2.example.com:8888`;
+    /// requests for its sessions landing elsewhere are redirected there
+    pub advertise_url: Option<String>,
+    /// Seconds an instance counts as alive after its last heartbeat
+    pub node_ttl_seconds: u64,
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        Self {
+            registry: RegistryBackend::Memory,
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            key_prefix: "webssh".to_string(),
+            advertise_url: None,
+            node_ttl_seconds: 30,
+        }
+    }
+}
+
+/// Where the session registry lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryBackend {
+    /// In this instance only
+    Memory,
+    /// Also in Redis, shared by all instances
+    Redis,
+}
+
+/// Registered devices, which connect requests can name instead of giving an address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InventorySettings {
+    pub enabled: bool,
+    /// SQLite database file
+    pub path: String,
+    /// Directory `file:` credential references are read from
+    pub credentials_dir: String,
+}
+
+impl Default for InventorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "inventory.db".to_string(),
+            credentials_dir: "credentials".to_string(),
+        }
+    }
+}
+
+/// Supervision of the background tasks: session cleanup, lifetime checks, the watchdog, settings reloading and configuration backups
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackgroundTaskSettings {
+    /// How long a task that panicked waits before it is started again
+    pub restart_delay_seconds: u64,
+    /// Times a task is started again after panicking before it is left failed
+    pub max_restarts: u32,
+}
+
+impl Default for BackgroundTaskSettings {
+    fn default() -> Self {
+        Self {
+            restart_delay_seconds: 5,
+            max_restarts: 10,
+        }
+    }
+}
+
+/// Credential providers a connect request's `credential_ref` can name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CredentialSettings {
+    /// Accept `credential_ref` on connect requests
+    pub enabled: bool,
+    /// Prefix of the environment variables `env:` references may read
+    pub env_prefix: String,
+    /// Directory `file:` references are read from
+    pub dir: String,
+    /// How long credentials read from Vault are reused; 0 reads them on every connection
+    pub cache_seconds: u64,
+    pub vault: VaultSettings,
+    pub keyring: KeyringSettings,
+}
+
+impl Default for CredentialSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            env_prefix: "WEBSSH_CREDENTIALS_".to_string(),
+            dir: "credentials".to_string(),
+            cache_seconds: 300,
+            vault: VaultSettings::default(),
+            keyring: KeyringSettings::default(),
+        }
+    }
+}
+
+/// HashiCorp Vault KV version 2 store read by `vault:` references
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VaultSettings {
+    pub enabled: bool,
+    /// Address of Vault, e.g. `https://vault.example.com:8200`
+    pub url: String,
+    /// File holding the Vault token, read on every request so it can be rotated;
+    /// `VAULT_TOKEN` is used when unset
+    pub token_file: Option<String>,
+    /// Vault Enterprise namespace, sent as `X-Vault-Namespace`
+    pub namespace: Option<String>,
+    /// PEM bundle of the roots trusted for an HTTPS Vault; defaults to the system bundle
+    pub ca_file: Option<String>,
+    pub timeout_seconds: u64,
+    /// Secret paths references may read, as prefixes such as `secret/network/`; empty allows any
+    pub allowed_paths: Vec<String>,
+    /// Renew the token's lease before it expires
+    pub renew_token: bool,
+}
+
+impl Default for VaultSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            token_file: None,
+            namespace: None,
+            ca_file: None,
+            timeout_seconds: 10,
+            allowed_paths: Vec::new(),
+            renew_token: true,
+        }
+    }
+}
+
+/// The OS keyring read by `keyring:` references, for a gateway run by one operator on their workstation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyringSettings {
+    /// Needs a build with the `os-keyring` feature
+    pub enabled: bool,
+    /// Service the entries are stored under; references name the entry's user
+    pub service: String,
+}
+
+impl Default for KeyringSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            service: "webssh-rs".to_string(),
+        }
+    }
+}
+
+/// SSH port forwarding through live sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardingSettings {
+    pub enabled: bool,
+    /// Address local forwards listen on; anything wider exposes the device network
+    pub bind_address: String,
+    /// Remote forwards make the gateway open connections on the device's behalf
+    pub allow_remote: bool,
+    pub max_per_session: usize,
+    #[serde(default)]
+    pub socks: SocksSettings,
+}
+
+/// SOCKS5 proxies over a session, whose clients choose where each connection goes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocksSettings {
+    pub enabled: bool,
+    /// Addresses or CIDR ranges, hostnames, or `*.suffix` patterns clients may
+    /// connect to; none are allowed while the list is empty
+    pub allowed_destinations: Vec<String>,
+    /// Ports clients may connect to; empty allows any
+    pub allowed_ports: Vec<u16>,
+    /// Connections open at once through one proxy
+    pub max_connections: usize,
+    /// Time a client has to send its request after connecting
+    pub handshake_timeout_seconds: u64,
+}
+
+impl Default for SocksSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allowed_destinations: Vec::new(),
+            allowed_ports: Vec::new(),
+            max_connections: 64,
+            handshake_timeout_seconds: 10,
+        }
+    }
+}
+
+impl Default for ForwardingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: "127.0.0.1".to_string(),
+            allow_remote: false,
+            max_per_session: 8,
+            socks: SocksSettings::default(),
+        }
+    }
+}
+
+/// Detection of session I/O loops that have stopped making progress
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchdogSettings {
+    pub enabled: bool,
+    pub check_interval_seconds: u64,
+    /// Seconds without progress before a loop counts as stuck; raised to twice
+    /// the keepalive period, as idle loops only wake for keepalives
+    pub stall_seconds: u64,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            check_interval_seconds: 15,
+            stall_seconds: 120,
+        }
+    }
+}
+
+/// Export and import of the policy configuration between instances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyBundleSettings {
+    /// File holding the last imported bundle, which overrides the policy sections of this file
+    pub file: String,
+    /// Shared secret bundles are signed with; export and import are refused without it
+    pub signing_key: Option<String>,
+}
+
+impl Default for PolicyBundleSettings {
+    fn default() -> Self {
+        Self {
+            file: "policy.json".to_string(),
+            signing_key: None,
+        }
+    }
+}
+
+/// Credential hygiene enforced on connect requests before any connection is made
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CredentialPolicySettings {
+    pub enabled: bool,
+    /// Smallest RSA private key accepted
+    pub min_rsa_bits: u32,
+    /// DSA keys are limited to 1024 bits and refused unless allowed
+    pub allow_dsa: bool,
+    /// Refuse requests with passwords or keys in the query string, rather than warn
+    pub reject_query_credentials: bool,
+    /// Refuse known default credentials, rather than warn
+    pub reject_default_credentials: bool,
+    pub default_credentials: Vec<DefaultCredential>,
+}
+
+/// A vendor default username and password pair
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DefaultCredential {
+    pub username: String,
+    pub password: String,
+}
+
+impl Default for CredentialPolicySettings {
+    fn default() -> Self {
+        let default_credentials = [
+            ("admin", "admin"),
+            ("admin", "password"),
+            ("admin", ""),
+            ("root", "root"),
+            ("root", "toor"),
+            ("cisco", "cisco"),
+            ("ubnt", "ubnt"),
+        ];
+        Self {
+            enabled: true,
+            min_rsa_bits: 2048,
+            allow_dsa: false,
+            reject_query_credentials: true,
+            reject_default_credentials: false,
+            default_credentials: default_credentials.iter()
+                .map(|(username, password)| DefaultCredential { username: username.to_string(), password: password.to_string() })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JwtSettings {
+    /// Require a valid JWT on the REST API and WebSocket endpoints
+    pub enabled: bool,
+    /// "HS256" (shared secret) or "RS256" (public key)
+    pub algorithm: String,
+    /// Shared secret for HS256; falls back to the WEBSSH_JWT_SECRET environment variable
+    pub secret: Option<String>,
+    /// PEM encoded RSA public key for RS256
+    pub public_key_file: Option<String>,
+    /// Expected `iss` claim, if any
+    pub issuer: Option<String>,
+    /// Expected `aud` claim, if any
+    pub audience: Option<String>,
+    /// Allowed clock skew when checking expiry
+    pub leeway_seconds: u64,
+}
+
+impl Default for JwtSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithm: "HS256".to_string(),
+            secret: None,
+            public_key_file: None,
+            issuer: None,
+            audience: None,
+            leeway_seconds: 30,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,6 +1936,165 @@ pub struct SSHSettings {
     pub connection: ConnectionSettings,
     pub crypto: CryptoSettings,
     pub terminal: TerminalSettings,
+    #[serde(default)]
+    pub jump_host: JumpHostSettings,
+    #[serde(default)]
+    pub agent: AgentSettings,
+    #[serde(default)]
+    pub pkcs11: Pkcs11Settings,
+    #[serde(default)]
+    pub gssapi: GssapiSettings,
+    #[serde(default)]
+    pub outbound: OutboundSettings,
+    #[serde(default)]
+    pub input_coalescing: InputCoalescingSettings,
+    #[serde(default)]
+    pub detection: DeviceDetectionSettings,
+}
+
+/// Recognising the kind of device from its SSH banner and first prompt when
+/// the connect request gives no `device_type`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceDetectionSettings {
+    pub enabled: bool,
+    /// Longest the shell's first output is waited for before the session starts
+    pub prompt_wait_ms: u64,
+}
+
+impl Default for DeviceDetectionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            prompt_wait_ms: 2000,
+        }
+    }
+}
+
+/// Merging of bursts of small shell input writes into larger channel writes
+///
+/// A write arriving alone, such as a keystroke, goes straight to the device;
+/// only writes queued behind others are held back, for at most `max_latency_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputCoalescingSettings {
+    pub enabled: bool,
+    /// Size at which a batch is written without waiting for more input
+    pub max_bytes: usize,
+    /// Longest a queued write is held back for more to join it
+    pub max_latency_ms: u64,
+}
+
+impl Default for InputCoalescingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_bytes: 16 * 1024,
+            max_latency_ms: 5,
+        }
+    }
+}
+
+/// Source address and interface of connections to devices, for multi-homed gateways
+///
+/// Unset, the operating system picks them from the routing table.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundSettings {
+    /// Local address connections are made from
+    pub source_address: Option<String>,
+    /// Interface or VRF device sockets are bound to (`SO_BINDTODEVICE`, Linux only)
+    pub interface: Option<String>,
+    /// Sources by device group; the first group the device belongs to applies
+    pub groups: Vec<SourceGroup>,
+}
+
+/// Devices reached from the same source
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SourceGroup {
+    pub name: String,
+    /// Addresses or CIDR ranges, hostnames, or `*.suffix` patterns; empty matches any host
+    pub hosts: Vec<String>,
+    /// Device types, as in `profiles`; empty matches any type
+    pub device_types: Vec<String>,
+    /// Overrides `source_address` for the group
+    pub source_address: Option<String>,
+    /// Overrides `interface` for the group
+    pub interface: Option<String>,
+}
+
+/// Authentication with the ssh-agent of the gateway host (`"auth_type": "agent"`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentSettings {
+    /// Off by default: any API client could log in with the gateway's own keys
+    pub enabled: bool,
+    /// Identities that may be used, by comment or `SHA256:` fingerprint; empty allows all
+    pub allowed_identities: Vec<String>,
+}
+
+/// Authentication with keys held in an HSM or smartcard (`"auth_type": "pkcs11"`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pkcs11Settings {
+    pub enabled: bool,
+    /// PKCS#11 modules that may be loaded, by the name connect requests give, e.g.
+    /// `"yubihsm": "/usr/lib/x86_64-linux-gnu/pkcs11/yubihsm_pkcs11.so"`
+    pub modules: BTreeMap<String, String>,
+}
+
+/// Kerberos authentication (`"auth_type": "gssapi"`), through the host's OpenSSH client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GssapiSettings {
+    pub enabled: bool,
+    /// The OpenSSH client, by path or looked up in PATH
+    pub ssh_binary: String,
+    /// Credential cache holding the gateway's tickets, e.g. `FILE:/var/lib/webssh/krb5cc`;
+    /// the Kerberos default (`KRB5CCNAME`) when unset
+    pub credential_cache: Option<String>,
+    /// Client keytab from which tickets are obtained and renewed, e.g. `/etc/webssh/webssh.keytab`
+    pub keytab: Option<String>,
+    /// Forward the tickets to the device, for onward Kerberos logins from it
+    pub delegate_credentials: bool,
+    /// Host keys of the devices, learnt on first connection; not checked when unset
+    pub known_hosts_file: Option<String>,
+    /// Further `-o` options for the client, e.g. `KexAlgorithms=+diffie-hellman-group14-sha1`
+    pub options: Vec<String>,
+}
+
+impl Default for GssapiSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ssh_binary: "ssh".to_string(),
+            credential_cache: None,
+            keytab: None,
+            delegate_credentials: false,
+            known_hosts_file: None,
+            options: Vec::new(),
+        }
+    }
+}
+
+/// Timeouts for the bastion hop of tunnelled connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JumpHostSettings {
+    /// Session timeout for the bastion handshake and authentication
+    pub timeout_seconds: u64,
+    /// Time allowed for the bastion to open the channel to the device
+    pub tunnel_timeout_seconds: u64,
+}
+
+impl Default for JumpHostSettings {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: 30,
+            tunnel_timeout_seconds: 15,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +2105,74 @@ pub struct ConnectionSettings {
     pub channel_timeout_seconds: u64,
     pub keepalive_seconds: u64,
     pub compress: bool,
+    /// RTT at or above which `auto` compression turns compression on
+    #[serde(default = "default_compression_auto_rtt_ms")]
+    pub compression_auto_rtt_ms: u64,
+    /// How long to wait for the user to answer keyboard-interactive prompts
+    #[serde(default = "default_auth_prompt_timeout_seconds")]
+    pub auth_prompt_timeout_seconds: u64,
+    /// Ask the user for the device's password over the WebSocket when a connect
+    /// request brings no credentials, so they never pass through the portal
+    #[serde(default)]
+    pub prompt_for_password: bool,
+    /// Keepalives in a row that may fail to send before the session is closed as lost
+    #[serde(default = "default_keepalive_max_failures")]
+    pub keepalive_max_failures: u32,
+    /// Keepalive intervals in a row that may go by without a word from the
+    /// device before the session is closed as lost
+    #[serde(default = "default_keepalive_max_missed_replies")]
+    pub keepalive_max_missed_replies: u32,
+    #[serde(default)]
+    pub slow_link: SlowLinkSettings,
+}
+
+/// Relaxing the timeouts and keepalive interval of a session whose link is
+/// measured slow when it connects, such as a satellite site
+///
+/// Each value is multiplied by `factor`, up to the bounds, and never lowered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlowLinkSettings {
+    pub enabled: bool,
+    /// TCP connect time, as an RTT estimate, at or above which a link is slow
+    pub rtt_threshold_ms: u64,
+    /// SSH handshake time at or above which a link is slow; the key exchange
+    /// moves a few kilobytes, so this catches links with little throughput
+    pub handshake_threshold_ms: u64,
+    pub factor: u64,
+    /// Bound of the relaxed read, write, command and channel timeouts
+    pub max_timeout_seconds: u64,
+    /// Bound of the relaxed keepalive interval
+    pub max_keepalive_seconds: u64,
+}
+
+impl Default for SlowLinkSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            rtt_threshold_ms: 400,
+            handshake_threshold_ms: 4000,
+            factor: 3,
+            max_timeout_seconds: 300,
+            max_keepalive_seconds: 90,
+        }
+    }
+}
+
+fn default_auth_prompt_timeout_seconds() -> u64 {
+    120
+}
+
+fn default_keepalive_max_failures() -> u32 {
+    3
+}
+
+fn default_keepalive_max_missed_replies() -> u32 {
+    3
+}
+
+fn default_compression_auto_rtt_ms() -> u64 {
+    50
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,28 +2192,93 @@ pub struct TerminalSettings {
     pub fallback_terminal_type: String,
     pub default_cols: u32,
     pub default_rows: u32,
+    /// Environment variables a connect request may set on the shell, by name or
+    /// by prefix ending in `*` (e.g. "LC_*")
+    #[serde(default = "default_allowed_environment")]
+    pub allowed_environment: Vec<String>,
+    #[serde(default)]
+    pub downgrade: TerminalDowngradeSettings,
+    /// PTY modes requested with every shell, by termios name, e.g. `{"ECHO": false}`
+    #[serde(default)]
+    pub pty_modes: BTreeMap<String, PtyModeValue>,
+    /// Size of the terminal in pixels, for applications that draw by it; 0 if not known
+    #[serde(default)]
+    pub width_px: u32,
+    #[serde(default)]
+    pub height_px: u32,
 }
 
-impl Settings {
-    pub fn load() -> Self {
-        let config_path = Path::new("settings.json");
-        if config_path.exists() {
-            match Self::load_from_file(config_path) {
-                Ok(settings) => {
-                    info!("Loaded settings from settings.json");
-                    return settings;
-                }
-                Err(e) => {
-                    error!("Failed to load settings from file: {}", e);
-                }
-            }
+/// Value of a PTY mode: a flag, or a number for characters (e.g. VINTR 3) and line speeds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PtyModeValue {
+    Flag(bool),
+    Number(u32),
+}
+
+fn default_allowed_environment() -> Vec<String> {
+    vec!["LANG".to_string(), "LC_*".to_string(), "TZ".to_string()]
+}
+
+/// Stepping a device down to a simpler terminal type when its output shows it
+/// does not handle the one it was given
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerminalDowngradeSettings {
+    pub enabled: bool,
+    /// Terminal types from richest to simplest; a device is moved to the next one
+    pub order: Vec<String>,
+    /// Output that gives away a device not handling its terminal type, matched ignoring case
+    pub patterns: Vec<String>,
+    /// How much of a shell's output is looked at
+    pub scan_bytes: usize,
+}
+
+impl Default for TerminalDowngradeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            order: vec!["xterm-256color".to_string(), "xterm".to_string(), "vt100".to_string(), "dumb".to_string()],
+            patterns: vec![
+                "terminal is not fully functional".to_string(),
+                "unknown terminal type".to_string(),
+                "terminal type unknown".to_string(),
+                "not a known terminal".to_string(),
+                "^[[".to_string(),
+            ],
+            scan_bytes: 8192,
         }
+    }
+}
+
+impl Settings {
+    /// Looks up the profile for a device type, ignoring case
+    pub fn profile(&self, device_type: Option<&str>) -> Option<&DeviceProfile> {
+        device_type.and_then(|device_type| self.profiles.get(&device_type.to_lowercase()))
+    }
 
-        info!("Using default settings");
-        Self::default()
+    /// Resolves the compression mode: request, then device profile, then the global flag
+    pub fn compression_mode(&self, requested: Option<CompressionMode>, device_type: Option<&str>) -> CompressionMode {
+        requested
+            .or_else(|| self.profile(device_type).and_then(|profile| profile.compression))
+            .unwrap_or(if self.ssh.connection.compress { CompressionMode::On } else { CompressionMode::Off })
     }
 
-    fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Reads the settings file, or takes the defaults when there is none or it is unreadable
+    ///
+    /// Nothing is logged here, as the log output is itself set by the settings;
+    /// the origin is returned for logging once it is set up.
+    pub fn load(path: &Path) -> (Self, SettingsOrigin) {
+        if !path.exists() {
+            return (Self::default(), SettingsOrigin::Defaults);
+        }
+        match Self::load_from_file(path) {
+            Ok(settings) => (settings, SettingsOrigin::File),
+            Err(e) => (Self::default(), SettingsOrigin::Unreadable(e.to_string())),
+        }
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
         let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
@@ -83,6 +2287,26 @@ impl Settings {
     }
 }
 
+/// Where the settings in force came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsOrigin {
+    File,
+    /// There was no settings file
+    Defaults,
+    /// The settings file could not be read or parsed, so the defaults are in force
+    Unreadable(String),
+}
+
+impl SettingsOrigin {
+    pub fn log(&self, path: &Path) {
+        match self {
+            SettingsOrigin::File => info!("Loaded settings from {}", path.display()),
+            SettingsOrigin::Defaults => info!("No {}; using default settings", path.display()),
+            SettingsOrigin::Unreadable(e) => error!("Failed to load settings from {}: {}; using default settings", path.display(), e),
+        }
+    }
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Settings {
@@ -94,6 +2318,12 @@ impl Default for Settings {
                     channel_timeout_seconds: 120,
                     keepalive_seconds: 30,
                     compress: false,
+                    compression_auto_rtt_ms: default_compression_auto_rtt_ms(),
+                    auth_prompt_timeout_seconds: default_auth_prompt_timeout_seconds(),
+                    prompt_for_password: false,
+                    keepalive_max_failures: default_keepalive_max_failures(),
+                    keepalive_max_missed_replies: default_keepalive_max_missed_replies(),
+                    slow_link: SlowLinkSettings::default(),
                 },
                 crypto: CryptoSettings {
                     kex_algorithms: "curve25519-sha256,curve25519-sha256@libssh.org,ecdh-sha2-nistp256,ecdh-sha2-nistp384,ecdh-sha2-nistp521,diffie-hellman-group-exchange-sha256,diffie-hellman-group16-sha512,diffie-hellman-group18-sha512,diffie-hellman-group14-sha256,diffie-hellman-group14-sha1,diffie-hellman-group1-sha1".to_string(),
@@ -109,7 +2339,19 @@ impl Default for Settings {
                     fallback_terminal_type: "dumb".to_string(),
                     default_cols: 80,
                     default_rows: 24,
+                    allowed_environment: default_allowed_environment(),
+                    downgrade: TerminalDowngradeSettings::default(),
+                    pty_modes: BTreeMap::new(),
+                    width_px: 0,
+                    height_px: 0,
                 },
+                jump_host: JumpHostSettings::default(),
+                agent: AgentSettings::default(),
+                pkcs11: Pkcs11Settings::default(),
+                gssapi: GssapiSettings::default(),
+                outbound: OutboundSettings::default(),
+                input_coalescing: InputCoalescingSettings::default(),
+                detection: DeviceDetectionSettings::default(),
             },
             server: ServerSettings {
                 address: "127.0.0.1".to_string(),
@@ -117,7 +2359,83 @@ impl Default for Settings {
                 tls_enabled: false,
                 cert_file: None,
                 key_file: None,
+                node_id: None,
+                node_header: default_node_header(),
+                tls_reload_seconds: 0,
+                settings_reload_seconds: default_settings_reload_seconds(),
+                control_plane: ControlPlaneSettings::default(),
             },
+            api_keys: ApiKeySettings::default(),
+            recording: RecordingSettings::default(),
+            jwt: JwtSettings::default(),
+            reconnect: ReconnectSettings::default(),
+            file_server: FileServerSettings::default(),
+            storage: StorageSettings::default(),
+            cluster: ClusterSettings::default(),
+            inventory: InventorySettings::default(),
+            credentials: CredentialSettings::default(),
+            credential_policy: CredentialPolicySettings::default(),
+            forwarding: ForwardingSettings::default(),
+            watchdog: WatchdogSettings::default(),
+            policy_bundles: PolicyBundleSettings::default(),
+            scrollback: ScrollbackSettings::default(),
+            session_limits: SessionLimitSettings::default(),
+            session_cleanup: SessionCleanupSettings::default(),
+            session_lifetime: SessionLifetimeSettings::default(),
+            command_policy: CommandPolicySettings::default(),
+            authorization: AuthorizationSettings::default(),
+            capture: CaptureSettings::default(),
+            sharing: SharingSettings::default(),
+            audit: AuditSettings::default(),
+            http: HttpSettings::default(),
+            ssh_ca: SshCaSettings::default(),
+            exec: ExecSettings::default(),
+            client_stats: ClientStatsSettings::default(),
+            slow_consumers: SlowConsumerSettings::default(),
+            flow_control: FlowControlSettings::default(),
+            presence: PresenceSettings::default(),
+            rate_limit: RateLimitSettings::default(),
+            lab: LabSettings::default(),
+            telnet: TelnetSettings::default(),
+            parsing: ParsingSettings::default(),
+            webhooks: WebhookSettings::default(),
+            password_change: PasswordChangeSettings::default(),
+            maintenance: MaintenanceSettings::default(),
+            ws_tokens: WsTokenSettings::default(),
+            output_pipeline: OutputPipelineSettings::default(),
+            device_locks: DeviceLockSettings::default(),
+            config_backup: ConfigBackupSettings::default(),
+            passthrough: PassthroughSettings::default(),
+            binary_protocol: BinaryProtocolSettings::default(),
+            background_tasks: BackgroundTaskSettings::default(),
+            alerts: AlertSettings::default(),
+            logging: LoggingSettings::default(),
+            reachability: ReachabilitySettings::default(),
+            websocket: WebSocketSettings::default(),
+            screen_subscriptions: ScreenSubscriptionSettings::default(),
+            timeline: TimelineSettings::default(),
+            host_keys: HostKeySettings::default(),
+            bulk_exec: BulkExecSettings::default(),
+            export: ExportSettings::default(),
+            profiles: HashMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_mode_precedence() {
+        let mut settings = Settings::default();
+        settings.profiles.insert("cisco".to_string(), DeviceProfile { compression: Some(CompressionMode::Auto), terminal_type: None, output_stages: None, quirks: DeviceQuirks::default() });
+
+        assert_eq!(settings.compression_mode(None, None), CompressionMode::Off);
+        assert_eq!(settings.compression_mode(None, Some("Cisco")), CompressionMode::Auto);
+        assert_eq!(settings.compression_mode(Some(CompressionMode::On), Some("cisco")), CompressionMode::On);
+
+        settings.ssh.connection.compress = true;
+        assert_eq!(settings.compression_mode(None, Some("linux")), CompressionMode::On);
+    }
+}
@@ -0,0 +1,852 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ssh2::{OpenFlags, OpenType, Session, Sftp};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path as RemotePath, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+
+use crate::exec;
+use crate::ssh::{error::SSHError, ChannelKind, SharedConnection};
+use crate::AppState;
+
+/// Size of the chunks read from and written to the remote file
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Minimum number of bytes between two progress notifications
+const PROGRESS_INTERVAL: u64 = 256 * 1024;
+
+/// Time the device may take over any one step of a transfer before it is abandoned
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a step the connection is not ready for is tried again
+///
+/// The session's shell reads the same socket, so data for the transfer may
+/// already be buffered by libssh2 without the socket turning readable again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// libssh2's LIBSSH2_ERROR_FILE, with which reading a directory ends
+const ERROR_FILE: i32 = -16;
+
+/// A single entry of a remote directory listing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SftpEntry {
+    pub name: String,
+    pub path: String,
+    pub is_dir: bool,
+    pub size: Option<u64>,
+    pub permissions: Option<u32>,
+    pub modified: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SftpPathQuery {
+    path: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SftpListResponse {
+    path: String,
+    entries: Vec<SftpEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SftpUploadResponse {
+    success: bool,
+    path: String,
+    bytes_written: u64,
+    protocol: TransferProtocol,
+}
+
+/// How a file was transferred: SFTP, or SCP on devices without the SFTP subsystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferProtocol {
+    Sftp,
+    Scp,
+}
+
+impl TransferProtocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            TransferProtocol::Sftp => "sftp",
+            TransferProtocol::Scp => "scp",
+        }
+    }
+}
+
+/// A remote file opened for reading
+enum RemoteReader {
+    Sftp(ssh2::File),
+    /// The SCP channel, limited to the file: the status byte after it is not part of the file
+    Scp(std::io::Take<ssh2::Channel>),
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            RemoteReader::Sftp(file) => file.read(buf),
+            RemoteReader::Scp(channel) => channel.read(buf),
+        }
+    }
+}
+
+impl RemoteReader {
+    fn protocol(&self) -> TransferProtocol {
+        match self {
+            RemoteReader::Sftp(_) => TransferProtocol::Sftp,
+            RemoteReader::Scp(_) => TransferProtocol::Scp,
+        }
+    }
+
+    /// Closes an SCP channel the way the device expects once the file is read
+    fn finish(self) {
+        if let RemoteReader::Scp(channel) = self {
+            let mut channel = channel.into_inner();
+            let _ = retry(|| channel.send_eof());
+            let _ = retry(|| channel.wait_eof());
+            let _ = retry(|| channel.close());
+            let _ = retry(|| channel.wait_close());
+        }
+    }
+}
+
+/// Runs a libssh2 call on the session's non-blocking connection until it gets an answer
+fn retry<T>(call: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, SSHError> {
+    exec::retry(Instant::now() + STEP_TIMEOUT, call)
+}
+
+/// Waits before trying a step again, unless the device has run out of time
+fn wait(deadline: Instant) -> io::Result<()> {
+    if Instant::now() >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the device"));
+    }
+    std::thread::sleep(POLL_INTERVAL);
+    Ok(())
+}
+
+/// Reads from a remote file or channel, waiting while the connection has nothing for it
+fn read_some(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    loop {
+        match reader.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait(deadline)?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+/// Writes all of `data` to a remote file or channel, waiting while the connection is busy
+fn write_all(writer: &mut impl Write, mut data: &[u8]) -> io::Result<()> {
+    let mut deadline = Instant::now() + STEP_TIMEOUT;
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data = &data[n..];
+                deadline = Instant::now() + STEP_TIMEOUT;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait(deadline)?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Starts the SFTP subsystem on the session's connection
+///
+/// Dropping the SFTP handle and its files closes them with the connection
+/// briefly in blocking mode; ssh2 holds the session's lock throughout and
+/// restores the mode, so the shell's I/O never sees it.
+fn open_sftp(session: &Session) -> Result<Sftp, SSHError> {
+    retry(|| session.sftp())
+}
+
+/// Opens a remote file for reading over SFTP, or over SCP if the device has no SFTP subsystem
+///
+/// # Returns
+/// * `Result<(RemoteReader, Option<u64>), SSHError>` - The file and its size, if known
+fn open_for_read(session: &Session, path: &str) -> Result<(RemoteReader, Option<u64>), SSHError> {
+    match open_sftp(session) {
+        Ok(sftp) => {
+            let mut file = retry(|| sftp.open(RemotePath::new(path)))?;
+            let total_bytes = retry(|| file.stat()).ok().and_then(|stat| stat.size);
+            Ok((RemoteReader::Sftp(file), total_bytes))
+        }
+        Err(e) => {
+            info!("SFTP unavailable ({}), downloading {} over SCP", e, path);
+            let (channel, stat) = retry(|| session.scp_recv(RemotePath::new(path)))?;
+            Ok((RemoteReader::Scp(channel.take(stat.size())), Some(stat.size())))
+        }
+    }
+}
+
+/// Uploads a file over SCP, for devices without the SFTP subsystem
+///
+/// SCP announces the size before the data, so it is taken from the
+/// request's Content-Length; without one, the body is spooled to a
+/// temporary file first.
+fn scp_upload(
+    session: &Session,
+    path: &str,
+    content_length: Option<u64>,
+    chunks: &mut mpsc::Receiver<UploadChunk>,
+    progress: &mut ProgressReporter,
+) -> Result<u64, SSHError> {
+    let mut spool = None;
+    let size = match content_length {
+        Some(size) => size,
+        None => {
+            let spool_path = std::env::temp_dir().join(format!("webssh-scp-{}", uuid::Uuid::new_v4()));
+            let mut file = std::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&spool_path)?;
+            // Unlinked at once: the spool goes away with the handle, however the upload ends
+            std::fs::remove_file(&spool_path)?;
+            while let Some(chunk) = next_chunk(chunks)? {
+                file.write_all(&chunk)?;
+            }
+            let size = file.seek(SeekFrom::End(0))?;
+            file.rewind()?;
+            spool = Some(file);
+            size
+        }
+    };
+
+    let mut channel = retry(|| session.scp_send(RemotePath::new(path), 0o644, size, None))?;
+    let mut written = 0u64;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let chunk = match spool.as_mut() {
+            Some(file) => match file.read(&mut buf)? {
+                0 => break,
+                n => Bytes::copy_from_slice(&buf[..n]),
+            },
+            None => match next_chunk(chunks)? {
+                Some(chunk) => chunk,
+                None => break,
+            },
+        };
+        if written + chunk.len() as u64 > size {
+            return Err(length_mismatch(size));
+        }
+        write_all(&mut channel, &chunk)?;
+        written += chunk.len() as u64;
+        progress.update(written);
+    }
+    if written != size {
+        return Err(length_mismatch(size));
+    }
+    retry(|| channel.send_eof())?;
+    retry(|| channel.wait_eof())?;
+    retry(|| channel.close())?;
+    retry(|| channel.wait_close())?;
+    Ok(written)
+}
+
+/// Writes an upload's chunks to the remote file as they arrive
+fn write_chunks(
+    file: &mut impl Write,
+    chunks: &mut mpsc::Receiver<UploadChunk>,
+    progress: &mut ProgressReporter,
+) -> Result<u64, SSHError> {
+    let mut written = 0u64;
+    while let Some(chunk) = next_chunk(chunks)? {
+        write_all(file, &chunk)?;
+        written += chunk.len() as u64;
+        progress.update(written);
+    }
+    Ok(written)
+}
+
+/// A chunk of an upload's body, or the news that the body failed before its end
+type UploadChunk = Result<Bytes, Interrupted>;
+
+/// The upload's request body failed
+#[derive(Debug)]
+struct Interrupted;
+
+/// The next chunk of an upload, or `None` at the end of its body
+fn next_chunk(chunks: &mut mpsc::Receiver<UploadChunk>) -> Result<Option<Bytes>, SSHError> {
+    chunks.blocking_recv().transpose().map_err(|Interrupted| SSHError::Connection(std::io::Error::new(
+        std::io::ErrorKind::Interrupted,
+        "The upload body ended before it was complete",
+    )))
+}
+
+fn length_mismatch(size: u64) -> SSHError {
+    SSHError::Connection(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Upload body does not match its Content-Length of {} bytes", size),
+    ))
+}
+
+/// Direction of a file transfer, as reported in progress notifications
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        }
+    }
+}
+
+/// Publishes transfer progress to the WebSocket attached to the session
+struct ProgressReporter {
+    notifications: broadcast::Sender<serde_json::Value>,
+    transfer_id: String,
+    direction: Direction,
+    protocol: TransferProtocol,
+    path: String,
+    total_bytes: Option<u64>,
+    transferred: u64,
+    last_reported: u64,
+}
+
+impl ProgressReporter {
+    fn new(
+        notifications: broadcast::Sender<serde_json::Value>,
+        direction: Direction,
+        protocol: TransferProtocol,
+        path: &str,
+        total_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            notifications,
+            transfer_id: uuid::Uuid::new_v4().to_string(),
+            direction,
+            protocol,
+            path: path.to_string(),
+            total_bytes,
+            transferred: 0,
+            last_reported: 0,
+        }
+    }
+
+    fn update(&mut self, bytes_transferred: u64) {
+        self.transferred = bytes_transferred;
+        if bytes_transferred - self.last_reported >= PROGRESS_INTERVAL {
+            self.send(bytes_transferred, false);
+        }
+    }
+
+    fn finish(&mut self, bytes_transferred: u64) {
+        self.send(bytes_transferred, true);
+    }
+
+    /// Reports how a transfer ended; a failed one is never reported complete
+    fn end(&mut self, result: &Result<u64, SSHError>) {
+        match result {
+            Ok(bytes_transferred) => self.finish(*bytes_transferred),
+            Err(e) => {
+                let mut event = self.event(self.transferred, false);
+                event["error"] = json!(e.to_string());
+                let _ = self.notifications.send(event);
+            }
+        }
+    }
+
+    fn send(&mut self, bytes_transferred: u64, complete: bool) {
+        self.last_reported = bytes_transferred;
+        // Nobody listening just means no WebSocket is attached right now
+        let _ = self.notifications.send(self.event(bytes_transferred, complete));
+    }
+
+    fn event(&self, bytes_transferred: u64, complete: bool) -> serde_json::Value {
+        json!({
+            "type": "sftp_progress",
+            "transfer_id": self.transfer_id,
+            "direction": self.direction.as_str(),
+            "protocol": self.protocol.as_str(),
+            "path": self.path,
+            "bytes_transferred": bytes_transferred,
+            "total_bytes": self.total_bytes,
+            "complete": complete,
+        })
+    }
+}
+
+/// Lists the entries of a remote directory, directories first
+pub fn list_directory(session: &Session, path: &str) -> Result<Vec<SftpEntry>, SSHError> {
+    let sftp = open_sftp(session)?;
+    let mut dir = retry(|| sftp.opendir(RemotePath::new(path)))?;
+    let mut entries = Vec::new();
+    loop {
+        match retry(|| dir.readdir()) {
+            Ok((name, stat)) => {
+                if name != RemotePath::new(".") && name != RemotePath::new("..") {
+                    entries.push(entry_from_stat(RemotePath::new(path).join(name), stat));
+                }
+            }
+            Err(SSHError::Ssh(e)) if e.code() == ssh2::ErrorCode::Session(ERROR_FILE) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+fn entry_from_stat(path: PathBuf, stat: ssh2::FileStat) -> SftpEntry {
+    SftpEntry {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: path.to_string_lossy().into_owned(),
+        is_dir: stat.is_dir(),
+        size: stat.size,
+        permissions: stat.perm,
+        modified: stat.mtime,
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn transfer_error(e: SSHError) -> Response {
+    let status = match e {
+        SSHError::Ssh(ref err) if matches!(err.code(), ssh2::ErrorCode::SFTP(2)) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_response(status, "sftp_error", e.to_string())
+}
+
+/// What a transfer needs of a live session
+struct TransferContext {
+    /// The session's SSH connection, which the transfer opens its channel on
+    connection: SharedConnection,
+    /// The portal user the session belongs to, as the channel's owner
+    owner: String,
+    notifications: broadcast::Sender<serde_json::Value>,
+}
+
+impl TransferContext {
+    /// Records the transfer's channel on the connection until the lease is dropped
+    fn lease(&self) -> crate::ssh::ChannelLease {
+        self.connection.lease(ChannelKind::Sftp, &self.owner)
+    }
+}
+
+/// Looks up the SSH connection and notification channel of a live session
+async fn session_context(state: &AppState, session_id: &str) -> Result<TransferContext, Response> {
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id.trim()) else {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            "session_not_found",
+            format!("Session '{}' not found", session_id.trim()),
+        ));
+    };
+    match session_info.ssh_session.connection().filter(|connection| connection.is_open()) {
+        Some(connection) => Ok(TransferContext {
+            connection: connection.clone(),
+            owner: session_info.portal_user_id.clone(),
+            notifications: session_info.notifications.clone(),
+        }),
+        None => Err(error_response(
+            StatusCode::CONFLICT,
+            "no_ssh_connection",
+            format!("Session '{}' has no SSH connection to transfer files over", session_id.trim()),
+        )),
+    }
+}
+
+fn required_path(query: &SftpPathQuery) -> Option<String> {
+    query.path.as_deref()
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+}
+
+fn missing_path() -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        "missing_path",
+        "The 'path' query parameter is required".to_string(),
+    )
+}
+
+/// Handler for listing a remote directory over SFTP
+pub async fn list_handler(
+    Path(session_id): Path<String>,
+    Query(query): Query<SftpPathQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let context = match session_context(&state, &session_id).await {
+        Ok(context) => context,
+        Err(response) => return response,
+    };
+    let path = required_path(&query).unwrap_or_else(|| ".".to_string());
+    info!("SFTP list of {} for session {}", path, session_id);
+
+    let list_path = path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let _lease = context.lease();
+        list_directory(context.connection.session(), &list_path)
+    }).await;
+
+    match result {
+        Ok(Ok(entries)) => Json(SftpListResponse { path, entries }).into_response(),
+        Ok(Err(e)) => {
+            error!("SFTP list of {} failed for session {}: {}", path, session_id, e);
+            transfer_error(e)
+        }
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "sftp_error", e.to_string()),
+    }
+}
+
+/// Handler for downloading a remote file; the body is streamed as it is read
+pub async fn download_handler(
+    Path(session_id): Path<String>,
+    Query(query): Query<SftpPathQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(path) = required_path(&query) else {
+        return missing_path();
+    };
+    let context = match session_context(&state, &session_id).await {
+        Ok(context) => context,
+        Err(response) => return response,
+    };
+    info!("SFTP download of {} for session {}", path, session_id);
+
+    let (opened_tx, opened_rx) = oneshot::channel::<Result<(Option<u64>, TransferProtocol), SSHError>>();
+    let (chunk_tx, chunk_rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(8);
+
+    let remote_path = path.clone();
+    tokio::task::spawn_blocking(move || {
+        let _lease = context.lease();
+        let (mut file, total_bytes) = match open_for_read(context.connection.session(), &remote_path) {
+            Ok(opened) => opened,
+            Err(e) => {
+                let _ = opened_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let protocol = file.protocol();
+        if opened_tx.send(Ok((total_bytes, protocol))).is_err() {
+            return;
+        }
+
+        let mut progress = ProgressReporter::new(context.notifications, Direction::Download, protocol, &remote_path, total_bytes);
+        let mut transferred = 0u64;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            match read_some(&mut file, &mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    transferred += n as u64;
+                    if chunk_tx.blocking_send(Ok(Bytes::copy_from_slice(&buf[..n]))).is_err() {
+                        debug!("SFTP download of {} cancelled by client", remote_path);
+                        return;
+                    }
+                    progress.update(transferred);
+                }
+                Err(e) => {
+                    error!("SFTP read of {} failed: {}", remote_path, e);
+                    let _ = chunk_tx.blocking_send(Err(e));
+                    return;
+                }
+            }
+        }
+        file.finish();
+        progress.finish(transferred);
+        info!("{} download of {} completed ({} bytes)", protocol.as_str().to_uppercase(), remote_path, transferred);
+    });
+
+    let (total_bytes, protocol) = match opened_rx.await {
+        Ok(Ok(opened)) => opened,
+        Ok(Err(e)) => {
+            error!("SFTP download of {} failed for session {}: {}", path, session_id, e);
+            return transfer_error(e);
+        }
+        Err(_) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "sftp_error",
+                "Transfer task ended unexpectedly".to_string(),
+            );
+        }
+    };
+
+    let body = Body::from_stream(stream::unfold(chunk_rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    let file_name = RemotePath::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name.replace('"', ""))),
+            (header::HeaderName::from_static("x-transfer-protocol"), protocol.as_str().to_string()),
+        ],
+        body,
+    ).into_response();
+    if let Some(total_bytes) = total_bytes {
+        response.headers_mut().insert(header::CONTENT_LENGTH, total_bytes.into());
+    }
+    response
+}
+
+/// Handler for uploading a file; the request body is written as it arrives
+pub async fn upload_handler(
+    Path(session_id): Path<String>,
+    Query(query): Query<SftpPathQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let Some(path) = required_path(&query) else {
+        return missing_path();
+    };
+    let context = match session_context(&state, &session_id).await {
+        Ok(context) => context,
+        Err(response) => return response,
+    };
+    info!("SFTP upload to {} for session {}", path, session_id);
+
+    let content_length = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<UploadChunk>(8);
+
+    let remote_path = path.clone();
+    let writer = tokio::task::spawn_blocking(move || -> Result<(u64, TransferProtocol), SSHError> {
+        let _lease = context.lease();
+        let session = context.connection.session();
+        let sftp = match open_sftp(session) {
+            Ok(sftp) => sftp,
+            Err(e) => {
+                info!("SFTP unavailable ({}), uploading {} over SCP", e, remote_path);
+                let mut progress = ProgressReporter::new(context.notifications, Direction::Upload, TransferProtocol::Scp, &remote_path, content_length);
+                // A device may keep what an interrupted SCP upload sent it; SCP cannot remove it
+                let written = scp_upload(session, &remote_path, content_length, &mut chunk_rx, &mut progress);
+                progress.end(&written);
+                return Ok((written?, TransferProtocol::Scp));
+            }
+        };
+        let mut file = retry(|| sftp.open_mode(
+            RemotePath::new(&remote_path),
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            0o644,
+            OpenType::File,
+        ))?;
+
+        let mut progress = ProgressReporter::new(context.notifications, Direction::Upload, TransferProtocol::Sftp, &remote_path, None);
+        let written = write_chunks(&mut file, &mut chunk_rx, &mut progress);
+        if written.is_err() {
+            // A partial file is not left behind looking like a finished upload
+            drop(file);
+            if let Err(e) = retry(|| sftp.unlink(RemotePath::new(&remote_path))) {
+                warn!("Partial upload {} could not be removed: {}", remote_path, e);
+            }
+        }
+        progress.end(&written);
+        Ok((written?, TransferProtocol::Sftp))
+    });
+
+    let mut body_stream = body.into_data_stream();
+    let mut interrupted = None;
+    while let Some(chunk) = body_stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            error!("SFTP upload body for {} failed: {}", path, e);
+            interrupted = Some(e.to_string());
+            Interrupted
+        });
+        // The writer may have stopped early; its result carries the reason
+        if chunk_tx.send(chunk).await.is_err() || interrupted.is_some() {
+            break;
+        }
+    }
+    drop(chunk_tx);
+
+    // The writer has cleaned up after an interrupted body by the time it ends
+    let written = writer.await;
+    if let Some(reason) = interrupted {
+        return error_response(StatusCode::BAD_REQUEST, "upload_interrupted", reason);
+    }
+    match written {
+        Ok(Ok((bytes_written, protocol))) => {
+            info!("{} upload to {} completed ({} bytes)", protocol.as_str().to_uppercase(), path, bytes_written);
+            Json(SftpUploadResponse { success: true, path, bytes_written, protocol }).into_response()
+        }
+        Ok(Err(e)) => {
+            error!("SFTP upload to {} failed for session {}: {}", path, session_id, e);
+            transfer_error(e)
+        }
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "sftp_error", e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use crate::tests::send;
+    use axum::http::Request;
+
+    fn query(path: Option<&str>) -> SftpPathQuery {
+        SftpPathQuery { path: path.map(str::to_string) }
+    }
+
+    /// A remote file that is not ready for every other call, and takes at most 3 bytes at a time
+    struct Flaky {
+        ready: bool,
+        data: Vec<u8>,
+    }
+
+    impl Flaky {
+        fn poll(&mut self) -> io::Result<()> {
+            self.ready = !self.ready;
+            if self.ready { Ok(()) } else { Err(io::ErrorKind::WouldBlock.into()) }
+        }
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.poll()?;
+            let n = buf.len().min(self.data.len()).min(3);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.poll()?;
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_required_path() {
+        assert_eq!(required_path(&query(None)), None);
+        assert_eq!(required_path(&query(Some("  "))), None);
+        assert_eq!(required_path(&query(Some(" /var/log/messages "))), Some("/var/log/messages".to_string()));
+    }
+
+    #[test]
+    fn test_entry_from_stat() {
+        let stat = |perm| ssh2::FileStat { size: Some(512), uid: None, gid: None, perm: Some(perm), atime: None, mtime: Some(1700000000) };
+        let dir = entry_from_stat(PathBuf::from("/var/log"), stat(0o040755));
+        assert_eq!((dir.name.as_str(), dir.path.as_str(), dir.is_dir), ("log", "/var/log", true));
+        let file = entry_from_stat(PathBuf::from("flash:/config.txt"), stat(0o100644));
+        assert_eq!((file.name.as_str(), file.is_dir, file.size, file.modified), ("config.txt", false, Some(512), Some(1700000000)));
+    }
+
+    #[test]
+    fn test_transfer_error_status() {
+        let missing = SSHError::Ssh(ssh2::Error::new(ssh2::ErrorCode::SFTP(2), "No such file"));
+        assert_eq!(transfer_error(missing).status(), StatusCode::NOT_FOUND);
+        let denied = SSHError::Ssh(ssh2::Error::new(ssh2::ErrorCode::SFTP(3), "Permission denied"));
+        assert_eq!(transfer_error(denied).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_reads_and_writes_wait_for_the_connection() {
+        let mut remote = Flaky { ready: false, data: Vec::new() };
+        write_all(&mut remote, b"show version").unwrap();
+        assert_eq!(remote.data, b"show version");
+
+        let mut buf = [0u8; 8];
+        assert_eq!(read_some(&mut remote, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"sho");
+    }
+
+    async fn error_of(response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["error"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_interrupted_upload_is_never_reported_complete() {
+        let (notifications, mut events) = broadcast::channel(16);
+        let (chunk_tx, mut chunk_rx) = mpsc::channel(8);
+        chunk_tx.try_send(Ok(Bytes::from_static(b"abc"))).unwrap();
+        chunk_tx.try_send(Err(Interrupted)).unwrap();
+        drop(chunk_tx);
+
+        let mut progress = ProgressReporter::new(notifications, Direction::Upload, TransferProtocol::Sftp, "/tmp/a", None);
+        let mut file = Vec::new();
+        let written = write_chunks(&mut file, &mut chunk_rx, &mut progress);
+        progress.end(&written);
+        assert!(written.is_err());
+        assert_eq!(file, b"abc");
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event["complete"], false);
+        assert_eq!(event["bytes_transferred"], 3);
+        assert!(event["error"].as_str().unwrap().contains("ended before it was complete"));
+        assert!(events.try_recv().is_err());
+
+        // A body that ends normally is
+        let (notifications, mut events) = broadcast::channel(16);
+        let (chunk_tx, mut chunk_rx) = mpsc::channel(8);
+        chunk_tx.try_send(Ok(Bytes::from_static(b"abc"))).unwrap();
+        drop(chunk_tx);
+        let mut progress = ProgressReporter::new(notifications, Direction::Upload, TransferProtocol::Sftp, "/tmp/a", None);
+        let written = write_chunks(&mut Vec::new(), &mut chunk_rx, &mut progress);
+        progress.end(&written);
+        assert_eq!(written.unwrap(), 3);
+        assert_eq!(events.try_recv().unwrap()["complete"], true);
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let (app, _) = crate::router(crate::test_state(Settings::default()));
+
+        for (method, uri) in [
+            ("GET", "/api/session/missing/sftp/list"),
+            ("GET", "/api/session/missing/sftp/download?path=/tmp/a"),
+            ("POST", "/api/session/missing/sftp/upload?path=/tmp/a"),
+        ] {
+            let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+            let error = error_of(send(&app, request).await).await;
+            assert_eq!(error, (StatusCode::NOT_FOUND, "session_not_found".to_string()), "{}", uri);
+        }
+
+        for (method, uri) in [
+            ("GET", "/api/session/missing/sftp/download"),
+            ("POST", "/api/session/missing/sftp/upload?path=%20"),
+        ] {
+            let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+            let error = error_of(send(&app, request).await).await;
+            assert_eq!(error, (StatusCode::BAD_REQUEST, "missing_path".to_string()), "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::AppState;
+
+/// What a share link lets its holder do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareRole {
+    /// Watch the session's output without sending input
+    #[default]
+    Viewer,
+}
+
+/// How a link is meant to be used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareKind {
+    /// Handed to a person
+    #[default]
+    Link,
+    /// Embedded in a page of another tool, e.g. a NOC wallboard; usable only from its origins
+    Embed,
+}
+
+/// A link granting access to a session's shell to whoever holds its token
+pub struct ShareLink {
+    kind: ShareKind,
+    role: ShareRole,
+    /// Origins of the pages whose WebSockets may use the link; empty for any
+    allowed_origins: Vec<String>,
+    // Only the hash is kept; the token is shown once, when the link is created
+    token_hash: String,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    // Cancelled when the link is revoked, detaching the viewers who joined through it
+    revoked: CancellationToken,
+}
+
+/// State of a share link, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct ShareStatus {
+    pub id: String,
+    pub kind: ShareKind,
+    pub role: ShareRole,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_origins: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// What an unexpired link lets its holder do
+pub struct ShareGrant {
+    pub role: ShareRole,
+    pub allowed_origins: Vec<String>,
+    /// Cancelled when the link is revoked
+    pub revoked: CancellationToken,
+}
+
+impl ShareGrant {
+    /// Whether a WebSocket opened by a page of this origin may use the link
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        self.allowed_origins.is_empty()
+            || origin.is_some_and(|origin| self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)))
+    }
+}
+
+/// The share links of a session, revoked together with it
+#[derive(Default)]
+pub struct ShareLinks {
+    links: HashMap<String, ShareLink>,
+}
+
+impl ShareLinks {
+    /// Creates a link, returning its state and token
+    pub fn create(&mut self, role: ShareRole, ttl: chrono::Duration) -> (ShareStatus, String) {
+        self.insert(ShareKind::Link, role, Vec::new(), ttl)
+    }
+
+    /// Creates a read-only link for pages of the given origins to embed
+    pub fn create_embed(&mut self, allowed_origins: Vec<String>, ttl: chrono::Duration) -> (ShareStatus, String) {
+        self.insert(ShareKind::Embed, ShareRole::Viewer, allowed_origins, ttl)
+    }
+
+    fn insert(
+        &mut self,
+        kind: ShareKind,
+        role: ShareRole,
+        allowed_origins: Vec<String>,
+        ttl: chrono::Duration,
+    ) -> (ShareStatus, String) {
+        let now = Utc::now();
+        self.links.retain(|_, link| link.expires_at > now);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let prefix = match kind {
+            ShareKind::Link => "wss",
+            ShareKind::Embed => "wse",
+        };
+        let token = format!("{}_{}{}", prefix, uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let link = ShareLink {
+            kind,
+            role,
+            allowed_origins,
+            token_hash: hash_token(&token),
+            created_at: now,
+            expires_at: now + ttl,
+            revoked: CancellationToken::new(),
+        };
+        let status = link.status(&id);
+        self.links.insert(id, link);
+        (status, token)
+    }
+
+    /// Finds the unexpired link a token belongs to
+    pub fn find(&self, token: &str) -> Option<ShareGrant> {
+        let token_hash = hash_token(token);
+        self.links.values()
+            .find(|link| link.token_hash == token_hash && link.expires_at > Utc::now())
+            .map(|link| ShareGrant {
+                role: link.role,
+                allowed_origins: link.allowed_origins.clone(),
+                revoked: link.revoked.clone(),
+            })
+    }
+
+    /// Revokes a link, detaching the viewers who joined through it
+    ///
+    /// # Returns
+    /// * `bool` - true if the link existed
+    pub fn revoke(&mut self, id: &str) -> bool {
+        match self.links.remove(id) {
+            Some(link) => {
+                link.revoked.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gets the unexpired links, oldest first
+    pub fn list(&self) -> Vec<ShareStatus> {
+        let now = Utc::now();
+        let mut links: Vec<ShareStatus> = self.links.iter()
+            .filter(|(_, link)| link.expires_at > now)
+            .map(|(id, link)| link.status(id))
+            .collect();
+        links.sort_by_key(|link| link.created_at);
+        links
+    }
+}
+
+impl ShareLink {
+    fn status(&self, id: &str) -> ShareStatus {
+        ShareStatus {
+            id: id.to_string(),
+            kind: self.kind,
+            role: self.role,
+            allowed_origins: self.allowed_origins.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Body of a request to create a share link
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ShareRequest {
+    pub role: ShareRole,
+    /// Lifetime of the link; defaults to `sharing.default_ttl_seconds`
+    pub ttl_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareResponse {
+    #[serde(flatten)]
+    share: ShareStatus,
+    /// Shown only here; the link cannot be recovered later
+    token: String,
+    websocket_url: String,
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+fn session_not_found(session_id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id))
+}
+
+/// Creates a link for others to watch the session
+pub async fn create_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    request: Option<Json<ShareRequest>>,
+) -> Response {
+    let settings = &state.settings.sharing;
+    if !settings.enabled {
+        return error_response(StatusCode::CONFLICT, "sharing_disabled",
+                              "Session sharing is not enabled on this instance".to_string());
+    }
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let ttl_seconds = request.ttl_seconds.unwrap_or(settings.default_ttl_seconds);
+    if ttl_seconds == 0 || ttl_seconds > settings.max_ttl_seconds {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_ttl",
+                              format!("ttl_seconds must be between 1 and {}", settings.max_ttl_seconds));
+    }
+
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        return session_not_found(session_id);
+    };
+    let (share, token) = session_info.shares.create(request.role, chrono::Duration::seconds(ttl_seconds as i64));
+    info!("Share link {} created for session {}, expiring {}", share.id, session_id, share.expires_at);
+
+    let websocket_url = crate::share_url(&state.settings, &token);
+    (StatusCode::CREATED, Json(ShareResponse { share, token, websocket_url })).into_response()
+}
+
+/// Lists the session's share links and how many viewers are watching
+pub async fn list_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    match state.session_registry.lock().await.get_session(session_id) {
+        Some(session_info) => Json(json!({
+            "shares": session_info.shares.list(),
+            "viewers": session_info.viewers,
+        })).into_response(),
+        None => session_not_found(session_id),
+    }
+}
+
+/// Body of a request to mint an embed token
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EmbedRequest {
+    /// Origins of the embedding pages; defaults to all of `sharing.embed.allowed_origins`
+    pub origins: Vec<String>,
+    /// Lifetime of the token; defaults to `sharing.embed.default_ttl_seconds`
+    pub ttl_seconds: Option<u64>,
+}
+
+/// Mints a token for a page of another tool to embed a read-only view of the session
+pub async fn embed_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    request: Option<Json<EmbedRequest>>,
+) -> Response {
+    let settings = &state.settings.sharing.embed;
+    if !settings.enabled || settings.allowed_origins.is_empty() {
+        return error_response(StatusCode::CONFLICT, "embed_disabled",
+                              "Embedding is not enabled on this instance".to_string());
+    }
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let ttl_seconds = request.ttl_seconds.unwrap_or(settings.default_ttl_seconds);
+    if ttl_seconds == 0 || ttl_seconds > settings.max_ttl_seconds {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_ttl",
+                              format!("ttl_seconds must be between 1 and {}", settings.max_ttl_seconds));
+    }
+    let origins = if request.origins.is_empty() {
+        settings.allowed_origins.clone()
+    } else {
+        request.origins
+    };
+    if let Some(origin) = origins.iter().find(|origin| {
+        !settings.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }) {
+        return error_response(StatusCode::FORBIDDEN, "origin_not_allowed",
+                              format!("Origin '{}' is not among sharing.embed.allowed_origins", origin));
+    }
+
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        return session_not_found(session_id);
+    };
+    let (share, token) = session_info.shares.create_embed(origins, chrono::Duration::seconds(ttl_seconds as i64));
+    info!("Embed token {} created for session {}, for {}, expiring {}",
+          share.id, session_id, share.allowed_origins.join(", "), share.expires_at);
+
+    let websocket_url = crate::share_url(&state.settings, &token);
+    (StatusCode::CREATED, Json(ShareResponse { share, token, websocket_url })).into_response()
+}
+
+/// Revokes one of the session's share links
+pub async fn revoke_handler(
+    State(state): State<AppState>,
+    Path((session_id, share_id)): Path<(String, String)>,
+) -> Response {
+    let session_id = session_id.trim();
+    let mut registry = state.session_registry.lock().await;
+    let Some(session_info) = registry.get_session(session_id) else {
+        return session_not_found(session_id);
+    };
+    if session_info.shares.revoke(&share_id) {
+        info!("Share link {} revoked for session {}", share_id, session_id);
+        Json(json!({ "success": true, "message": format!("Share link '{}' revoked", share_id) })).into_response()
+    } else {
+        error_response(StatusCode::NOT_FOUND, "share_not_found", format!("Share link '{}' not found", share_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_links_expire_and_revoke() {
+        let mut links = ShareLinks::default();
+        let (share, token) = links.create(ShareRole::Viewer, chrono::Duration::minutes(5));
+        let (_, expired_token) = links.create(ShareRole::Viewer, chrono::Duration::seconds(-1));
+
+        let grant = links.find(&token).unwrap();
+        assert_eq!(grant.role, ShareRole::Viewer);
+        assert!(grant.allows_origin(None));
+        let revoked = grant.revoked;
+        assert!(links.find(&expired_token).is_none());
+        assert!(links.find("wss_guess").is_none());
+        assert_eq!(links.list().len(), 1);
+
+        assert!(links.revoke(&share.id));
+        assert!(revoked.is_cancelled());
+        assert!(links.find(&token).is_none());
+        assert!(!links.revoke(&share.id));
+
+        // Embed tokens are only good from the pages they were minted for
+        let (embed, token) = links.create_embed(vec!["https://noc.example.com".to_string()], chrono::Duration::minutes(5));
+        assert_eq!(embed.kind, ShareKind::Embed);
+        assert!(token.starts_with("wse_"));
+        let grant = links.find(&token).unwrap();
+        assert!(grant.allows_origin(Some("https://NOC.example.com")));
+        assert!(!grant.allows_origin(Some("https://evil.example.com")));
+        assert!(!grant.allows_origin(None));
+    }
+}
@@ -0,0 +1,374 @@
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::faults::{FaultPath, FaultProfile};
+use crate::http_client::{Connection, HttpClient};
+
+const CLI_USAGE: &str = "usage: webssh-rs soak --url URL --request FILE [--sessions N] [--duration 4h]
+       [--interval 30s] [--reconnect-every 10m] [--report-every 1m] [--input TEXT]
+       [--header 'Name: value']... [--ca-file FILE]
+       [--latency-ms MS] [--jitter-ms MS] [--drop-rate RATE] [--fault-path ssh|websocket]";
+
+/// States in which a session will not come back
+const GONE: [&str; 3] = ["ended", "failed", "not_found"];
+
+/// How a synthetic session is run
+struct SoakOptions {
+    client: HttpClient,
+    /// Body of the `/api/connect` request opening each session
+    request: String,
+    sessions: usize,
+    duration: Duration,
+    /// Between keepalives: an `input` message, if any, and a `ping`
+    interval: Duration,
+    /// Between deliberate WebSocket drops, each followed by a resume
+    reconnect_every: Option<Duration>,
+    report_every: Duration,
+    input: String,
+    headers: Vec<(String, String)>,
+    faults: Option<FaultProfile>,
+}
+
+/// What happened to the synthetic sessions, across all of them
+#[derive(Debug, Default, Clone, Serialize)]
+struct SoakReport {
+    elapsed_seconds: u64,
+    sessions_opened: u64,
+    connect_failures: u64,
+    /// Deliberate drops and unexpected disconnects that were resumed
+    reconnects: u64,
+    reconnect_failures: u64,
+    unexpected_disconnects: u64,
+    /// Sessions the gateway ended before the soak did, by end reason
+    sessions_lost: BTreeMap<String, u64>,
+    keepalives_sent: u64,
+    pongs_received: u64,
+    output_bytes: u64,
+    last_error: Option<String>,
+}
+
+type SharedReport = Arc<Mutex<SoakReport>>;
+
+fn update(report: &SharedReport, change: impl FnOnce(&mut SoakReport)) {
+    if let Ok(mut report) = report.lock() {
+        change(&mut report);
+    }
+}
+
+/// Why a WebSocket stopped being driven
+enum Outcome {
+    /// The soak is over
+    Finished,
+    /// Time for a deliberate drop
+    Reconnect,
+    /// The WebSocket closed or failed on its own
+    Disconnected(String),
+}
+
+/// Reads a duration such as `90`, `30s`, `10m` or `4h`
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}'", value);
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 3600,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_options(args: &[String]) -> Result<SoakOptions, String> {
+    let option = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1));
+    let number = |name: &str| {
+        option(name).map(|value| value.parse::<u64>().map_err(|_| format!("Invalid {} '{}'", name, value))).transpose()
+    };
+    let duration = |name: &str, default: &str| parse_duration(option(name).map(String::as_str).unwrap_or(default));
+
+    let url = option("--url").ok_or(CLI_USAGE)?;
+    let client = HttpClient::new(url.trim_end_matches('/'), option("--ca-file").map(String::as_str), "gateway")?;
+    let path = option("--request").ok_or(CLI_USAGE)?;
+    let request = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    serde_json::from_str::<Value>(&request).map_err(|e| format!("Malformed connect request in {}: {}", path, e))?;
+
+    let headers = args.windows(2)
+        .filter(|pair| pair[0] == "--header")
+        .map(|pair| match pair[1].split_once(':') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("Invalid header '{}'", pair[1])),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let paths = match option("--fault-path").map(String::as_str) {
+        None => Vec::new(),
+        Some("ssh") => vec![FaultPath::Ssh],
+        Some("websocket") => vec![FaultPath::Websocket],
+        Some(path) => return Err(format!("Invalid --fault-path '{}'", path)),
+    };
+    let faults = FaultProfile {
+        latency_ms: number("--latency-ms")?.unwrap_or(0),
+        jitter_ms: number("--jitter-ms")?.unwrap_or(0),
+        drop_rate: option("--drop-rate")
+            .map(|rate| rate.parse::<f64>().map_err(|_| format!("Invalid --drop-rate '{}'", rate)))
+            .transpose()?
+            .unwrap_or(0.0),
+        paths,
+    };
+    faults.validate()?;
+
+    Ok(SoakOptions {
+        client,
+        request,
+        sessions: number("--sessions")?.unwrap_or(1).max(1) as usize,
+        duration: duration("--duration", "1h")?,
+        interval: duration("--interval", "30s")?.max(Duration::from_secs(1)),
+        reconnect_every: option("--reconnect-every").map(|value| parse_duration(value)).transpose()?
+            .filter(|every| !every.is_zero()),
+        report_every: duration("--report-every", "1m")?.max(Duration::from_secs(1)),
+        input: option("--input").cloned().unwrap_or_else(|| "\r".to_string()),
+        headers,
+        faults: (faults.latency_ms > 0 || faults.jitter_ms > 0 || faults.drop_rate > 0.0).then_some(faults),
+    })
+}
+
+impl SoakOptions {
+    async fn call(&self, method: &str, path: &str, body: Option<&str>) -> Result<(u16, Value), String> {
+        let headers: Vec<(&str, &str)> = self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        let path = format!("{}{}", self.client.endpoint.path.trim_end_matches('/'), path);
+        let (status, body) = self.client.request(method, &path, &headers, body).await.map_err(|e| e.to_string())?;
+        Ok((status, serde_json::from_slice(&body).unwrap_or(Value::Null)))
+    }
+
+    /// Opens a session, returning its ID
+    async fn open(&self) -> Result<String, String> {
+        let (status, response) = self.call("POST", "/api/connect", Some(&self.request)).await?;
+        match response["session_id"].as_str() {
+            Some(session_id) if status == 200 => Ok(session_id.to_string()),
+            _ => Err(format!("connect returned {}: {}", status, response["message"].as_str().unwrap_or("no details"))),
+        }
+    }
+
+    /// The lifecycle state of a session and, once it has ended, why
+    async fn state(&self, session_id: &str) -> Result<(String, Option<String>), String> {
+        let (_, response) = self.call("GET", &format!("/api/session/{}/status", session_id), None).await?;
+        let state = response["state"].as_str().unwrap_or("unknown").to_string();
+        let end_reason = response["history"]["end_reason"].as_str().map(str::to_string);
+        Ok((state, end_reason))
+    }
+
+    async fn attach(&self, session_id: &str, resume: Option<u64>) -> Result<WebSocketStream<Box<dyn Connection>>, String> {
+        // Every WebSocket spends a token of its own
+        let (status, response) = self.call("POST", &format!("/api/session/{}/ws-token", session_id), None).await?;
+        let token = match response["ws_token"].as_str() {
+            Some(token) if status == 201 => token.to_string(),
+            _ => return Err(format!("ws-token returned {}: {}", status, response["message"].as_str().unwrap_or("no details"))),
+        };
+        let mut url = self.client.endpoint.websocket_url(&format!("/ws/{}?ws_token={}", session_id, token));
+        if let Some(offset) = resume {
+            url.push_str(&format!("&resume={}", offset));
+        }
+        let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+        for (name, value) in &self.headers {
+            let name: tokio_tungstenite::tungstenite::http::HeaderName = name.parse().map_err(|_| format!("Invalid header '{}'", name))?;
+            let value = HeaderValue::from_str(value).map_err(|_| format!("Invalid value of header '{}'", name))?;
+            request.headers_mut().insert(name, value);
+        }
+        let connection = self.client.connect().await.map_err(|e| e.to_string())?;
+        let (socket, _) = tokio_tungstenite::client_async(request, connection).await.map_err(|e| e.to_string())?;
+        Ok(socket)
+    }
+}
+
+/// Exchanges keepalives and output on a WebSocket, following the output offset
+async fn drive(
+    socket: &mut WebSocketStream<Box<dyn Connection>>,
+    options: &SoakOptions,
+    offset: &mut Option<u64>,
+    report: &SharedReport,
+    deadline: Instant,
+) -> Outcome {
+    let mut keepalive = tokio::time::interval_at(Instant::now() + options.interval, options.interval);
+    let reconnect_at = options.reconnect_every.map(|every| Instant::now() + every);
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Binary(data))) => {
+                    *offset = offset.map(|offset| offset + data.len() as u64);
+                    update(report, |report| report.output_bytes += data.len() as u64);
+                }
+                Some(Ok(Message::Text(text))) => {
+                    let event: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+                    match event["type"].as_str() {
+                        Some("output_offset") => *offset = event["offset"].as_u64(),
+                        Some("pong") => update(report, |report| report.pongs_received += 1),
+                        Some("error") => {
+                            let message = event["message"].as_str().unwrap_or("").to_string();
+                            update(report, |report| report.last_error = Some(message));
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = frame.map(|frame| frame.reason.to_string()).filter(|reason| !reason.is_empty());
+                    return Outcome::Disconnected(reason.unwrap_or_else(|| "closed by the gateway".to_string()));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Outcome::Disconnected(e.to_string()),
+                None => return Outcome::Disconnected("connection lost".to_string()),
+            },
+            _ = keepalive.tick() => {
+                let mut sent = Ok(());
+                if !options.input.is_empty() {
+                    sent = socket.send(Message::Text(json!({ "type": "input", "data": options.input }).to_string())).await;
+                }
+                if sent.is_ok() {
+                    sent = socket.send(Message::Text(json!({ "type": "ping" }).to_string())).await;
+                }
+                if let Err(e) = sent {
+                    return Outcome::Disconnected(e.to_string());
+                }
+                update(report, |report| report.keepalives_sent += 1);
+            }
+            _ = tokio::time::sleep_until(reconnect_at.unwrap_or(deadline)), if reconnect_at.is_some() => return Outcome::Reconnect,
+            _ = tokio::time::sleep_until(deadline) => return Outcome::Finished,
+        }
+    }
+}
+
+/// Keeps one synthetic session open until the deadline, opening another whenever it is lost
+async fn run_session(options: Arc<SoakOptions>, report: SharedReport, deadline: Instant) {
+    while Instant::now() < deadline {
+        let session_id = match options.open().await {
+            Ok(session_id) => session_id,
+            Err(e) => {
+                update(&report, |report| {
+                    report.connect_failures += 1;
+                    report.last_error = Some(e);
+                });
+                tokio::time::sleep(options.interval).await;
+                continue;
+            }
+        };
+        update(&report, |report| report.sessions_opened += 1);
+
+        let mut offset = None;
+        let mut attached_before = false;
+        loop {
+            let mut socket = match options.attach(&session_id, offset).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    update(&report, |report| {
+                        report.reconnect_failures += 1;
+                        report.last_error = Some(format!("[{}] {}", session_id, e));
+                    });
+                    match options.state(&session_id).await {
+                        Ok((state, end_reason)) if GONE.contains(&state.as_str()) => {
+                            let reason = end_reason.unwrap_or(state);
+                            update(&report, |report| *report.sessions_lost.entry(reason).or_default() += 1);
+                            break;
+                        }
+                        _ if Instant::now() >= deadline => return,
+                        _ => {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    }
+                }
+            };
+            if attached_before {
+                update(&report, |report| report.reconnects += 1);
+            } else if let Some(faults) = &options.faults {
+                // The shell exists from the first attach on, so faults can be set now
+                let body = serde_json::to_string(faults).unwrap_or_default();
+                match options.call("PUT", &format!("/api/session/{}/faults", session_id), Some(&body)).await {
+                    Ok((200, _)) => {}
+                    Ok((status, response)) => update(&report, |report| {
+                        report.last_error = Some(format!("[{}] setting faults returned {}: {}", session_id, status, response["message"]));
+                    }),
+                    Err(e) => update(&report, |report| report.last_error = Some(e)),
+                }
+            }
+            attached_before = true;
+
+            match drive(&mut socket, &options, &mut offset, &report, deadline).await {
+                Outcome::Finished => {
+                    let _ = socket.close(None).await;
+                    let _ = options.call("POST", &format!("/api/session/{}/terminate", session_id), None).await;
+                    return;
+                }
+                // Dropped without a close frame, as a lost connection would be
+                Outcome::Reconnect => drop(socket),
+                Outcome::Disconnected(reason) => update(&report, |report| {
+                    report.unexpected_disconnects += 1;
+                    report.last_error = Some(format!("[{}] {}", session_id, reason));
+                }),
+            }
+        }
+    }
+}
+
+fn print_report(report: &SharedReport, started: Instant) {
+    if let Ok(mut report) = report.lock() {
+        report.elapsed_seconds = started.elapsed().as_secs();
+        println!("{}", serde_json::to_string(&*report).unwrap_or_default());
+    }
+}
+
+/// Runs `webssh-rs soak ...`: keeps synthetic sessions open through a gateway
+///
+/// Each session is kept busy with keepalives, dropped and resumed on
+/// schedule, and replaced if the gateway ends it. A report line is printed
+/// periodically and at the end; the run fails if any session was lost.
+pub async fn run_cli(args: &[String]) -> Result<(), String> {
+    let options = Arc::new(parse_options(args)?);
+    let report = SharedReport::default();
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    println!("Soaking {} session(s) through {}:{} for {} s",
+             options.sessions, options.client.endpoint.host, options.client.endpoint.port, options.duration.as_secs());
+
+    let sessions: Vec<_> = (0..options.sessions)
+        .map(|_| tokio::spawn(run_session(options.clone(), report.clone(), deadline)))
+        .collect();
+    let mut reports = tokio::time::interval_at(started + options.report_every, options.report_every);
+    let all_done = futures::future::join_all(sessions);
+    tokio::pin!(all_done);
+    loop {
+        tokio::select! {
+            _ = &mut all_done => break,
+            _ = reports.tick() => print_report(&report, started),
+        }
+    }
+    print_report(&report, started);
+
+    let lost: u64 = report.lock().map(|report| report.sessions_lost.values().sum()).unwrap_or(0);
+    match lost {
+        0 => Ok(()),
+        lost => Err(format!("{} session(s) were ended by the gateway during the soak", lost)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("4h").unwrap(), Duration::from_secs(4 * 3600));
+        assert!(parse_duration("4d").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+}
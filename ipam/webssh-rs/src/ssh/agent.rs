@@ -0,0 +1,85 @@
+use sha2::{Digest, Sha256};
+use ssh2::Session;
+use tracing::{debug, info, warn};
+
+use crate::settings::AgentSettings;
+use super::error::SSHError;
+
+/// Authenticates with an identity held by the ssh-agent of the gateway host
+///
+/// The agent is found through `SSH_AUTH_SOCK`. Identities are tried in the
+/// agent's order, skipping those `ssh.agent.allowed_identities` does not list.
+pub fn userauth(session: &Session, username: &str, settings: &AgentSettings) -> Result<(), SSHError> {
+    if !settings.enabled {
+        return Err(SSHError::Agent("agent authentication is not enabled on this instance".into()));
+    }
+    let mut agent = session.agent()
+        .map_err(|e| SSHError::Agent(format!("failed to create an agent handle: {}", e)))?;
+    agent.connect()
+        .map_err(|e| SSHError::Agent(format!("failed to connect to the ssh-agent: {}", e)))?;
+    let identities = agent.list_identities()
+        .and_then(|_| agent.identities())
+        .map_err(|e| SSHError::Agent(format!("failed to list the ssh-agent's identities: {}", e)));
+
+    let result = identities.and_then(|identities| {
+        let permitted: Vec<_> = identities.iter()
+            .filter(|identity| {
+                let fingerprint = fingerprint(identity.blob());
+                let allowed = permitted(settings, identity.comment(), &fingerprint);
+                if !allowed {
+                    debug!("Skipping agent identity {} ({}): not allowed", fingerprint, identity.comment());
+                }
+                allowed
+            })
+            .collect();
+        if permitted.is_empty() {
+            return Err(SSHError::Agent("the ssh-agent holds no permitted identity".into()));
+        }
+        for identity in permitted {
+            match agent.userauth(username, identity) {
+                Ok(()) => {
+                    info!("Authenticated {} with agent identity {}", username, fingerprint(identity.blob()));
+                    return Ok(());
+                }
+                Err(e) => debug!("Agent identity {} refused: {}", fingerprint(identity.blob()), e),
+            }
+        }
+        Err(SSHError::Authentication("Agent authentication failed: no identity was accepted".into()))
+    });
+
+    if let Err(e) = agent.disconnect() {
+        warn!("Failed to disconnect from the ssh-agent: {}", e);
+    }
+    result
+}
+
+/// Formats a public key's fingerprint as OpenSSH does, e.g. `SHA256:H+TE7...`
+pub fn fingerprint(blob: &[u8]) -> String {
+    format!("SHA256:{}", base64::encode_config(Sha256::digest(blob), base64::STANDARD_NO_PAD))
+}
+
+/// Whether an identity may be used; an empty allow list permits all of them
+fn permitted(settings: &AgentSettings, comment: &str, fingerprint: &str) -> bool {
+    settings.allowed_identities.is_empty()
+        || settings.allowed_identities.iter().any(|allowed| allowed == fingerprint || allowed == comment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identities_allowed_by_fingerprint_or_comment() {
+        let blob = base64::decode("AAAAC3NzaC1lZDI1NTE5AAAAIHfym6d7T/M0IsFVmkbu6MHhDZPt39z4h+dH+dVcb6tD").unwrap();
+        let fingerprint = fingerprint(&blob);
+        assert_eq!(fingerprint, "SHA256:H+TE7BOY0ThfviWgNZeZtR3+Nt3gwD4D8rdlqD3VlTQ");
+
+        let mut settings = AgentSettings { enabled: true, allowed_identities: Vec::new() };
+        assert!(permitted(&settings, "deploy@gateway", &fingerprint));
+        settings.allowed_identities = vec!["deploy@gateway".to_string()];
+        assert!(permitted(&settings, "deploy@gateway", &fingerprint));
+        assert!(!permitted(&settings, "root@gateway", "SHA256:other"));
+        settings.allowed_identities = vec![fingerprint.clone()];
+        assert!(permitted(&settings, "", &fingerprint));
+    }
+}
@@ -0,0 +1,152 @@
+use bytes::Bytes;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::error::SSHError;
+use super::heartbeat::Heartbeat;
+use super::session::{SSHSession, SessionHandle};
+use super::target::{ConnectionTarget, Protocol};
+use super::gssapi::GssapiSession;
+use super::telnet::TelnetSession;
+
+/// An interactive shell whose I/O can be pumped to and from a WebSocket
+///
+/// The WebSocket side only deals in channels, so backends are free to drive
+/// the connection however suits them: on the Tokio reactor, or on a dedicated thread.
+pub trait ShellBackend: Send + 'static {
+    /// Pumps data until the shell closes, the WebSocket goes away or shutdown is signalled,
+    /// then closes the shell
+    ///
+    /// # Arguments
+    /// * `input_rx` - A receiver for data from the WebSocket
+    /// * `output_tx` - A sender for data to the WebSocket
+    fn run_io(
+        self,
+        input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> impl Future<Output = Result<(), SSHError>> + Send;
+}
+
+/// libssh2 in non-blocking mode, woken by socket readiness from the Tokio reactor
+#[cfg(feature = "reactor-io")]
+impl ShellBackend for SSHSession {
+    async fn run_io(
+        mut self,
+        input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), SSHError> {
+        let result = self.run_reactor_io(input_rx, output_tx).await;
+        let _ = self.close();
+        result
+    }
+}
+
+/// Fallback: libssh2 polled from a blocking thread per session
+#[cfg(not(feature = "reactor-io"))]
+impl ShellBackend for SSHSession {
+    async fn run_io(
+        mut self,
+        input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), SSHError> {
+        // The thread logs in the session's span, as the task calling it does
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let result = self.start_io(input_rx, output_tx);
+            let _ = self.close();
+            result
+        })
+            .await
+            .map_err(|e| SSHError::Connection(std::io::Error::other(e)))?
+    }
+}
+
+/// An interactive shell on a device, over whichever protocol reaches it
+pub enum Shell {
+    Ssh(SSHSession),
+    Telnet(TelnetSession),
+    /// SSH logged in to with Kerberos by the OpenSSH client
+    Gssapi(GssapiSession),
+}
+
+impl Shell {
+    /// Connects to the target over its protocol and opens the shell
+    ///
+    /// # Returns
+    /// * `Result<Shell, SSHError>` - A new shell or an error
+    pub fn open(target: ConnectionTarget) -> Result<Self, SSHError> {
+        match target.protocol {
+            Protocol::Ssh if target.gssapi => GssapiSession::open(target).map(Shell::Gssapi),
+            Protocol::Ssh => SSHSession::open(target).map(Shell::Ssh),
+            Protocol::Telnet => TelnetSession::open(target).map(Shell::Telnet),
+        }
+    }
+
+    /// Gets a handle to this shell's session for the session registry
+    pub fn handle(&self) -> SessionHandle {
+        match self {
+            Shell::Ssh(session) => session.handle(),
+            Shell::Telnet(session) => session.handle(),
+            Shell::Gssapi(session) => session.handle(),
+        }
+    }
+
+    /// Sets the channel for receiving terminal resize events, as (rows, cols)
+    pub fn set_resize_channel(&mut self, resize_rx: mpsc::Receiver<(u32, u32)>) {
+        match self {
+            Shell::Ssh(session) => session.set_resize_channel(resize_rx),
+            Shell::Telnet(session) => session.set_resize_channel(resize_rx),
+            Shell::Gssapi(session) => session.set_resize_channel(resize_rx),
+        }
+    }
+
+    /// Gets the token cancelled when the shell shuts down
+    pub fn shutdown_token(&self) -> CancellationToken {
+        match self {
+            Shell::Ssh(session) => session.shutdown_token(),
+            Shell::Telnet(session) => session.shutdown_token(),
+            Shell::Gssapi(session) => session.shutdown_token(),
+        }
+    }
+
+    /// Closes the shell and its connection
+    pub fn close(&mut self) -> Result<(), SSHError> {
+        match self {
+            Shell::Ssh(session) => session.close(),
+            Shell::Telnet(session) => session.close(),
+            Shell::Gssapi(session) => session.close(),
+        }
+    }
+
+    /// Makes this shell one of a session's, shut down together with it
+    pub(super) fn join(&mut self, shutdown: CancellationToken, shells: &Arc<Mutex<Vec<Heartbeat>>>) {
+        match self {
+            Shell::Ssh(session) => session.join(shutdown, shells),
+            Shell::Telnet(session) => session.join(shutdown, shells),
+            Shell::Gssapi(session) => session.join(shutdown, shells),
+        }
+    }
+}
+
+impl From<SSHSession> for Shell {
+    fn from(session: SSHSession) -> Self {
+        Shell::Ssh(session)
+    }
+}
+
+impl ShellBackend for Shell {
+    async fn run_io(
+        self,
+        input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), SSHError> {
+        match self {
+            Shell::Ssh(session) => session.run_io(input_rx, output_tx).await,
+            Shell::Telnet(session) => session.run_io(input_rx, output_tx).await,
+            Shell::Gssapi(session) => session.run_io(input_rx, output_tx).await,
+        }
+    }
+}
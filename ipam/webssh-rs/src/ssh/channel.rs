@@ -1,16 +1,18 @@
 use ssh2::Session;
 use tracing::{debug, error};
 
-use crate::settings::SSHSettings;
+use crate::settings::{DeviceQuirks, SSHSettings};
+use crate::terminal::encode_pty_modes;
 use super::error::SSHError;
+use super::quirks;
 
 /// Sets up a standard SSH session channel with default terminal settings
 /// 
 /// This is the primary approach for most SSH servers and works with standard
 /// Linux/Unix systems.
-pub fn setup_standard_session(session: &mut Session, settings: &SSHSettings) -> Result<ssh2::Channel, SSHError> {
+pub fn setup_standard_session(session: &mut Session, settings: &SSHSettings, environment: &[(String, String)], quirks: &DeviceQuirks) -> Result<(ssh2::Channel, String), SSHError> {
     debug!("Creating SSH channel for standard session");
-    let mut channel = match session.channel_session() {
+    let mut channel = match quirks::channel_session(session, quirks) {
         Ok(channel) => {
             debug!("SSH session channel opened successfully");
             channel
@@ -20,14 +22,11 @@ pub fn setup_standard_session(session: &mut Session, settings: &SSHSettings) ->
             return Err(e.into());
         }
     };
+    set_environment(&mut channel, environment);
     
     // Request PTY with standard terminal type
     debug!("Requesting PTY with standard terminal type");
-    match channel.request_pty(
-        &settings.terminal.standard_terminal_type, 
-        None, 
-        Some((settings.terminal.default_cols, settings.terminal.default_rows, 0, 0))
-    ) {
+    match request_pty(&mut channel, &settings.terminal.standard_terminal_type, settings) {
         Ok(_) => debug!("PTY requested successfully"),
         Err(e) => {
             error!("Failed to request PTY: {}", e);
@@ -40,7 +39,7 @@ pub fn setup_standard_session(session: &mut Session, settings: &SSHSettings) ->
     match channel.shell() {
         Ok(_) => {
             debug!("Shell started successfully");
-            Ok(channel)
+            Ok((channel, settings.terminal.standard_terminal_type.clone()))
         },
         Err(e) => {
             error!("Failed to start shell: {}", e);
@@ -53,9 +52,9 @@ pub fn setup_standard_session(session: &mut Session, settings: &SSHSettings) ->
 /// 
 /// This approach attempts to execute bash as the shell, which is
 /// specific to Linux systems.
-pub fn setup_linux_session(session: &mut Session, settings: &SSHSettings) -> Result<ssh2::Channel, SSHError> {
+pub fn setup_linux_session(session: &mut Session, settings: &SSHSettings, environment: &[(String, String)], quirks: &DeviceQuirks) -> Result<(ssh2::Channel, String), SSHError> {
     debug!("Creating SSH channel for Linux session");
-    let mut channel = match session.channel_session() {
+    let mut channel = match quirks::channel_session(session, quirks) {
         Ok(channel) => {
             debug!("SSH session channel opened successfully");
             channel
@@ -65,24 +64,23 @@ pub fn setup_linux_session(session: &mut Session, settings: &SSHSettings) -> Res
             return Err(e.into());
         }
     };
+    set_environment(&mut channel, environment);
     
     // For Linux devices, we'll use the Linux terminal type from settings
     debug!("Requesting PTY for Linux device");
-    match channel.request_pty(
-        &settings.terminal.linux_terminal_type, 
-        None, 
-        Some((settings.terminal.default_cols, settings.terminal.default_rows, 0, 0))
-    ) {
-        Ok(_) => debug!("PTY requested successfully"),
+    let terminal_type = match request_pty(&mut channel, &settings.terminal.linux_terminal_type, settings) {
+        Ok(_) => {
+            debug!("PTY requested successfully");
+            &settings.terminal.linux_terminal_type
+        },
         Err(e) => {
             error!("Failed to request PTY: {}", e);
             // Try with a simpler terminal type as fallback
-            match channel.request_pty(
-                &settings.terminal.fallback_terminal_type, 
-                None, 
-                Some((settings.terminal.default_cols, settings.terminal.default_rows, 0, 0))
-            ) {
-                Ok(_) => debug!("Dumb PTY requested successfully"),
+            match request_pty(&mut channel, &settings.terminal.fallback_terminal_type, settings) {
+                Ok(_) => {
+                    debug!("Dumb PTY requested successfully");
+                    &settings.terminal.fallback_terminal_type
+                },
                 Err(e2) => {
                     error!("Failed to request dumb PTY: {}", e2);
                     // Don't try more fallbacks - if PTY fails, it's likely a protocol issue
@@ -90,14 +88,14 @@ pub fn setup_linux_session(session: &mut Session, settings: &SSHSettings) -> Res
                 }
             }
         }
-    }
+    };
     
     // Try executing bash command - this is the key test for Linux devices
     debug!("Executing bash command for Linux device");
     match channel.exec("bash") {
         Ok(_) => {
             debug!("Bash command executed successfully - confirmed Linux device");
-            Ok(channel)
+            Ok((channel, terminal_type.clone()))
         },
         Err(e) => {
             error!("Failed to execute bash command: {}", e);
@@ -110,9 +108,9 @@ pub fn setup_linux_session(session: &mut Session, settings: &SSHSettings) -> Res
 /// 
 /// Cisco devices often have different terminal requirements and behaviors
 /// compared to standard Linux/Unix systems.
-pub fn setup_cisco_session(session: &mut Session, settings: &SSHSettings) -> Result<ssh2::Channel, SSHError> {
+pub fn setup_cisco_session(session: &mut Session, settings: &SSHSettings, environment: &[(String, String)], quirks: &DeviceQuirks) -> Result<(ssh2::Channel, String), SSHError> {
     debug!("Creating SSH channel for Cisco session");
-    let mut channel = match session.channel_session() {
+    let mut channel = match quirks::channel_session(session, quirks) {
         Ok(channel) => {
             debug!("SSH session channel opened successfully");
             channel
@@ -122,14 +120,11 @@ pub fn setup_cisco_session(session: &mut Session, settings: &SSHSettings) -> Res
             return Err(e.into());
         }
     };
+    set_environment(&mut channel, environment);
     
     // For Cisco devices, we'll use the standard terminal type from settings
     debug!("Requesting PTY for Cisco device");
-    match channel.request_pty(
-        &settings.terminal.standard_terminal_type, 
-        None, 
-        Some((settings.terminal.default_cols, settings.terminal.default_rows, 0, 0))
-    ) {
+    match request_pty(&mut channel, &settings.terminal.standard_terminal_type, settings) {
         Ok(_) => debug!("PTY requested successfully"),
         Err(e) => {
             error!("Failed to request PTY: {}", e);
@@ -142,7 +137,7 @@ pub fn setup_cisco_session(session: &mut Session, settings: &SSHSettings) -> Res
     match channel.shell() {
         Ok(_) => {
             debug!("Shell started successfully");
-            Ok(channel)
+            Ok((channel, settings.terminal.standard_terminal_type.clone()))
         },
         Err(e) => {
             error!("Failed to start shell: {}", e);
@@ -150,3 +145,26 @@ pub fn setup_cisco_session(session: &mut Session, settings: &SSHSettings) -> Res
         }
     }
 }
+
+/// Sets the requested environment variables on a channel before its shell starts
+///
+/// Servers take only the variables their configuration accepts (e.g. OpenSSH's
+/// `AcceptEnv`), so a refused variable is logged and the shell opened without it.
+fn set_environment(channel: &mut ssh2::Channel, environment: &[(String, String)]) {
+    for (name, value) in environment {
+        match channel.setenv(name, value) {
+            Ok(()) => debug!("Set environment variable {}", name),
+            Err(e) => debug!("Server refused environment variable {}: {}", name, e),
+        }
+    }
+}
+
+/// Requests a PTY with the configured modes and the terminal's size in characters and pixels
+fn request_pty(channel: &mut ssh2::Channel, terminal_type: &str, settings: &SSHSettings) -> Result<(), ssh2::Error> {
+    let terminal = &settings.terminal;
+    channel.request_pty(
+        terminal_type,
+        encode_pty_modes(&terminal.pty_modes),
+        Some((terminal.default_cols, terminal.default_rows, terminal.width_px, terminal.height_px)),
+    )
+}
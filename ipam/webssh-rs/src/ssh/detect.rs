@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+/// Kinds of device a connection can be recognised as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceKind {
+    CiscoIos,
+    CiscoXr,
+    Junos,
+    Linux,
+    AristaEos,
+}
+
+impl DeviceKind {
+    /// The device type the kind is known by, as given in connect requests and rules
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceKind::CiscoIos => "cisco-ios",
+            DeviceKind::CiscoXr => "cisco-xr",
+            DeviceKind::Junos => "junos",
+            DeviceKind::Linux => "linux",
+            DeviceKind::AristaEos => "arista-eos",
+        }
+    }
+
+    /// Whether the device is a network OS, whose shell is started without trying a Unix one
+    pub fn is_network_device(self) -> bool {
+        self != DeviceKind::Linux
+    }
+}
+
+/// Guesses the kind of device from its SSH identification string, e.g. "SSH-2.0-Cisco-1.25"
+///
+/// Junos, EOS and most Linux servers all run OpenSSH, so only a few
+/// identification strings give the device away.
+pub fn from_banner(banner: &str) -> Option<DeviceKind> {
+    let banner = banner.to_lowercase();
+    if banner.contains("cisco") {
+        Some(DeviceKind::CiscoIos)
+    } else if ["ubuntu", "debian", "raspbian", "dropbear"].iter().any(|name| banner.contains(name)) {
+        Some(DeviceKind::Linux)
+    } else {
+        None
+    }
+}
+
+/// Recognises a device from the first output of its shell, which has to end in a prompt
+///
+/// The prompt tells the family; the banner or message of the day before it
+/// tells apart the ones whose prompts look alike.
+pub fn from_output(output: &[u8]) -> Option<DeviceKind> {
+    let text = String::from_utf8_lossy(output);
+    let prompt = text.lines().map(str::trim).rfind(|line| !line.is_empty())?;
+    let lower = text.to_lowercase();
+    match from_prompt(prompt)? {
+        DeviceKind::CiscoIos if lower.contains("arista") => Some(DeviceKind::AristaEos),
+        DeviceKind::CiscoIos if lower.contains("ios xr") || lower.contains("ios-xr") => Some(DeviceKind::CiscoXr),
+        kind => Some(kind),
+    }
+}
+
+fn from_prompt(prompt: &str) -> Option<DeviceKind> {
+    // e.g. "RP/0/RSP0/CPU0:router#"
+    if (prompt.starts_with("RP/") || prompt.starts_with("LC/")) && prompt.contains(":") && prompt.ends_with('#') {
+        return Some(DeviceKind::CiscoXr);
+    }
+    // e.g. "admin@host:~$", "[admin@host ~]$", "root@host:~#"
+    if prompt.ends_with('$') || (prompt.ends_with('#') && (prompt.contains(':') || prompt.starts_with('['))) {
+        return Some(DeviceKind::Linux);
+    }
+    let name = prompt.strip_suffix('>').or_else(|| prompt.strip_suffix('#'))?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "@._-()/".contains(c)) {
+        return None;
+    }
+    // Junos prompts carry the user, e.g. "admin@router>"; IOS and EOS ones only the hostname
+    if name.contains('@') {
+        Some(DeviceKind::Junos)
+    } else {
+        Some(DeviceKind::CiscoIos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recognise_devices() {
+        assert_eq!(from_banner("SSH-2.0-Cisco-1.25"), Some(DeviceKind::CiscoIos));
+        assert_eq!(from_banner("SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.6"), Some(DeviceKind::Linux));
+        assert_eq!(from_banner("SSH-2.0-OpenSSH_7.5"), None);
+
+        assert_eq!(from_output(b"\r\nUser Access Verification\r\n\r\nRouter#"), Some(DeviceKind::CiscoIos));
+        assert_eq!(from_output(b"\r\nRouter(config)# "), Some(DeviceKind::CiscoIos));
+        assert_eq!(from_output(b"Last login: Mon\r\n\r\nRP/0/RSP0/CPU0:pe1#"), Some(DeviceKind::CiscoXr));
+        assert_eq!(from_output(b"--- JUNOS 21.4R3 built 2023-01-01\r\nadmin@mx1> "), Some(DeviceKind::Junos));
+        assert_eq!(from_output(b"Arista Networks EOS shell\r\n\r\nleaf1>"), Some(DeviceKind::AristaEos));
+        assert_eq!(from_output(b"Welcome to Ubuntu 22.04\r\n\x1b[01;32madmin@web1\x1b[00m:~$ "), Some(DeviceKind::Linux));
+        assert_eq!(from_output(b"[root@db1 ~]# "), Some(DeviceKind::Linux));
+
+        // No prompt yet
+        assert_eq!(from_output(b"Welcome to the lab\r\nAuthorized use only\r\n"), None);
+        assert_eq!(from_output(b""), None);
+    }
+}
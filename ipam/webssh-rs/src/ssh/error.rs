@@ -1,5 +1,8 @@
 use thiserror::Error;
 
+use crate::error_code::ErrorCode;
+use crate::host_keys::PresentedKey;
+
 /// Custom error types for SSH operations
 #[derive(Error, Debug)]
 pub enum SSHError {
@@ -14,4 +17,81 @@ pub enum SSHError {
     /// Authentication-specific errors
     #[error("SSH authentication error: {0}")]
     Authentication(String),
+
+    /// The private key is malformed or in a format that is not supported
+    #[error("Invalid private key: {0}")]
+    PrivateKey(String),
+
+    /// The private key is encrypted and no passphrase was given
+    #[error("Private key is encrypted; a passphrase is required")]
+    PassphraseRequired,
+
+    /// The device is connected to over a protocol that does not offer the operation
+    #[error("Not available for {0} devices")]
+    Unsupported(String),
+
+    /// The gateway's ssh-agent cannot be used to authenticate
+    #[error("SSH agent unavailable: {0}")]
+    Agent(String),
+
+    /// The HSM or smartcard holding the key cannot be used
+    #[error("PKCS#11 token unavailable: {0}")]
+    Pkcs11(String),
+
+    /// Kerberos authentication is disabled, or the OpenSSH client or GSSAPI library cannot be used
+    #[error("GSSAPI unavailable: {0}")]
+    Gssapi(String),
+
+    /// The gateway holds no Kerberos ticket, and has no keytab to get one from
+    #[error("No Kerberos ticket: {0}")]
+    TicketMissing(String),
+
+    /// The gateway's Kerberos ticket has expired
+    #[error("Kerberos ticket expired: {0}")]
+    TicketExpired(String),
+
+    /// The device's host key is not pinned, and the user has not confirmed it
+    #[error("The host key of {} is not trusted yet: {} {}; confirm its fingerprint to connect", .0.presenter(), .0.key_type, .0.fingerprint)]
+    HostKeyUnconfirmed(PresentedKey),
+
+    /// The device presented another host key than the one pinned for it
+    #[error("Host key changed: {} presented {} {}, but {} is pinned", .presented.presenter(), .presented.key_type, .presented.fingerprint, .pinned)]
+    HostKeyChanged { presented: PresentedKey, pinned: String },
+}
+
+impl SSHError {
+    /// Error code reported to API clients
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            SSHError::Authentication(_) => ErrorCode::AuthFailed,
+            SSHError::PrivateKey(_) => ErrorCode::InvalidPrivateKey,
+            SSHError::PassphraseRequired => ErrorCode::PassphraseRequired,
+            SSHError::Connection(_) => ErrorCode::ConnectionFailed,
+            SSHError::Ssh(_) => ErrorCode::UnknownError,
+            SSHError::Unsupported(_) => ErrorCode::UnsupportedProtocol,
+            SSHError::Agent(_) => ErrorCode::AgentUnavailable,
+            SSHError::Pkcs11(_) => ErrorCode::HsmUnavailable,
+            SSHError::Gssapi(_) => ErrorCode::GssapiUnavailable,
+            SSHError::TicketMissing(_) => ErrorCode::KerberosTicketMissing,
+            SSHError::TicketExpired(_) => ErrorCode::KerberosTicketExpired,
+            SSHError::HostKeyUnconfirmed(_) => ErrorCode::HostkeyConfirmationRequired,
+            SSHError::HostKeyChanged { .. } => ErrorCode::HostKeyChanged,
+        }
+    }
+
+    /// Marks a refused host key as the jump host's rather than the device's
+    pub fn at_jump_host(mut self, hostname: &str, port: u16) -> Self {
+        if let SSHError::HostKeyUnconfirmed(presented) | SSHError::HostKeyChanged { presented, .. } = &mut self {
+            presented.jump_host = Some(format!("{}:{}", hostname, port));
+        }
+        self
+    }
+
+    /// The host key the device presented, if the connection was refused over it
+    pub fn host_key(&self) -> Option<&PresentedKey> {
+        match self {
+            SSHError::HostKeyUnconfirmed(presented) | SSHError::HostKeyChanged { presented, .. } => Some(presented),
+            _ => None,
+        }
+    }
 }
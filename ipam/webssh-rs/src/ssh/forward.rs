@@ -0,0 +1,385 @@
+use serde::{Deserialize, Serialize};
+use ssh2::{Channel, ErrorCode, Listener, Session};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use super::error::SSHError;
+use super::socks::{self, Destination, DestinationFilter};
+use super::target::ConnectionTarget;
+use super::tunnel::write_all;
+use crate::settings::SocksSettings;
+
+/// How long the pump sleeps when no connection has anything to move
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// libssh2's LIBSSH2_ERROR_EAGAIN, returned by non-blocking calls with nothing to do
+const ERROR_EAGAIN: i32 = -37;
+
+/// Which side of the SSH connection listens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardDirection {
+    /// The gateway listens; connections are opened from the device (direct-tcpip)
+    Local,
+    /// The device listens; connections are opened from the gateway (tcpip-forward)
+    Remote,
+    /// The gateway listens as a SOCKS5 proxy; each connection is opened from
+    /// the device to where the client asks (direct-tcpip)
+    Dynamic,
+}
+
+/// What a forward listens on and where its connections go
+#[derive(Debug, Clone)]
+pub struct ForwardSpec {
+    pub direction: ForwardDirection,
+    pub bind_host: String,
+    /// Port to listen on; 0 lets the listening side choose
+    pub bind_port: u16,
+    /// Empty for dynamic forwards
+    pub target_host: String,
+    pub target_port: u16,
+    /// Dynamic forwards only: the destinations clients may reach
+    pub socks: Option<SocksSettings>,
+}
+
+/// Traffic through a forward, updated by its pump thread
+#[derive(Debug, Default)]
+pub struct ForwardStats {
+    pub connections: AtomicU64,
+    pub bytes_to_device: AtomicU64,
+    pub bytes_from_device: AtomicU64,
+}
+
+enum Source {
+    Local(TcpListener),
+    Remote(Listener),
+    Socks(SocksProxy),
+}
+
+/// A SOCKS listener and the requests of clients that have finished their handshake
+///
+/// Handshakes run on threads of their own, so a slow client holds up no
+/// other connection; the pump opens the channels, as it owns the session.
+struct SocksProxy {
+    listener: TcpListener,
+    filter: Arc<DestinationFilter>,
+    settings: SocksSettings,
+    requests_tx: mpsc::Sender<SocksRequest>,
+    requests: mpsc::Receiver<SocksRequest>,
+}
+
+struct SocksRequest {
+    stream: TcpStream,
+    peer: SocketAddr,
+    destination: Destination,
+}
+
+/// A forwarded TCP connection and the SSH channel carrying it
+struct Connection {
+    channel: Channel,
+    stream: TcpStream,
+}
+
+/// Opens a port forward on a connection of its own to the device
+///
+/// The forward runs on a pump thread until `shutdown` is cancelled or the
+/// SSH connection drops; the pump cancels `shutdown` itself when it stops,
+/// so the token also tells whether the forward is still running.
+///
+/// # Returns
+/// * `Result<u16, SSHError>` - The port actually listened on, or an error
+pub fn open(
+    target: &ConnectionTarget,
+    spec: &ForwardSpec,
+    stats: Arc<ForwardStats>,
+    shutdown: CancellationToken,
+) -> Result<u16, SSHError> {
+    let filter = match &spec.socks {
+        Some(socks) => Some(Arc::new(DestinationFilter::new(socks).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?)),
+        None => None,
+    };
+    let session = target.connect()?;
+
+    let (source, bound_port) = match spec.direction {
+        ForwardDirection::Local | ForwardDirection::Dynamic => {
+            let listener = TcpListener::bind((spec.bind_host.as_str(), spec.bind_port))?;
+            listener.set_nonblocking(true)?;
+            let port = listener.local_addr()?.port();
+            match (filter, &spec.socks) {
+                (Some(filter), Some(settings)) => {
+                    let (requests_tx, requests) = mpsc::channel();
+                    let proxy = SocksProxy { listener, filter, settings: settings.clone(), requests_tx, requests };
+                    (Source::Socks(proxy), port)
+                }
+                _ => (Source::Local(listener), port),
+            }
+        }
+        ForwardDirection::Remote => {
+            let (listener, port) = session.channel_forward_listen(spec.bind_port, Some(&spec.bind_host), None)?;
+            (Source::Remote(listener), port)
+        }
+    };
+    let label = match spec.direction {
+        ForwardDirection::Dynamic => format!("{}:{} (SOCKS)", spec.bind_host, bound_port),
+        _ => format!("{}:{} -> {}:{}", spec.bind_host, bound_port, spec.target_host, spec.target_port),
+    };
+    info!("Forwarding {:?} {} for {}", spec.direction, label, target.hostname);
+
+    let spec = spec.clone();
+    let keepalive_interval = Duration::from_secs(target.settings.connection.keepalive_seconds.max(1));
+    let connect_timeout = Duration::from_secs(target.settings.connection.timeout_seconds.max(1));
+    std::thread::spawn(move || {
+        session.set_blocking(false);
+        let mut pump = Pump { session, spec, stats, connections: Vec::new(), connect_timeout, label: label.clone() };
+        pump.run(source, &shutdown, keepalive_interval, &label);
+        shutdown.cancel();
+        info!("Forward {} closed", label);
+    });
+
+    Ok(bound_port)
+}
+
+struct Pump {
+    session: Session,
+    spec: ForwardSpec,
+    stats: Arc<ForwardStats>,
+    connections: Vec<Connection>,
+    connect_timeout: Duration,
+    label: String,
+}
+
+impl Pump {
+    /// Accepts connections and moves their data until shut down or the SSH connection fails
+    fn run(&mut self, mut source: Source, shutdown: &CancellationToken, keepalive_interval: Duration, label: &str) {
+        let mut buf = [0u8; 16384];
+        let mut last_keepalive = Instant::now();
+
+        while !shutdown.is_cancelled() {
+            let accepted = match &mut source {
+                Source::Local(listener) => self.accept_local(listener),
+                Source::Remote(listener) => self.accept_remote(listener),
+                Source::Socks(proxy) => self.accept_socks(proxy),
+            };
+            let mut busy = match accepted {
+                Ok(busy) => busy,
+                Err(e) => {
+                    error!("Forward {}: listener failed: {}", label, e);
+                    break;
+                }
+            };
+
+            let (session, stats) = (&self.session, &self.stats);
+            self.connections.retain_mut(|connection| {
+                let open = connection.pump(session, &mut buf, stats, &mut busy);
+                if !open {
+                    connection.close();
+                }
+                open
+            });
+
+            if last_keepalive.elapsed() >= keepalive_interval {
+                if let Err(e) = self.session.keepalive_send() {
+                    error!("Forward {}: SSH connection lost: {}", label, e);
+                    break;
+                }
+                last_keepalive = Instant::now();
+            }
+            if !busy {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        for connection in &mut self.connections {
+            connection.close();
+        }
+        // Dropping the listener cancels the device's tcpip-forward
+        self.session.set_blocking(true);
+        drop(source);
+        let _ = self.session.disconnect(None, "Port forward closed", None);
+    }
+
+    /// Takes a connection to the gateway's listener and opens a channel from the device to the target
+    fn accept_local(&mut self, listener: &TcpListener) -> Result<bool, SSHError> {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Opening the channel is rare enough to do in blocking mode
+        self.session.set_blocking(true);
+        let channel = self.session.channel_direct_tcpip(
+            &self.spec.target_host,
+            self.spec.target_port,
+            Some((&peer.ip().to_string(), peer.port())),
+        );
+        self.session.set_blocking(false);
+        match channel {
+            Ok(channel) => {
+                debug!("Forwarding connection from {} to {}:{}", peer, self.spec.target_host, self.spec.target_port);
+                self.add(channel, stream)?;
+            }
+            Err(e) => error!("Device could not open a channel to {}:{}: {}", self.spec.target_host, self.spec.target_port, e),
+        }
+        Ok(true)
+    }
+
+    /// Takes a connection forwarded by the device and connects it to the target
+    fn accept_remote(&mut self, listener: &mut Listener) -> Result<bool, SSHError> {
+        let mut channel = match listener.accept() {
+            Ok(channel) => channel,
+            Err(e) if e.code() == ErrorCode::Session(ERROR_EAGAIN) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        let address = super::host::resolve(&self.spec.target_host, self.spec.target_port)
+            .ok()
+            .and_then(|addresses| addresses.into_iter().next());
+        match address.map(|address| TcpStream::connect_timeout(&address, self.connect_timeout)) {
+            Some(Ok(stream)) => {
+                debug!("Forwarding connection from the device to {}:{}", self.spec.target_host, self.spec.target_port);
+                self.add(channel, stream)?;
+            }
+            _ => {
+                error!("Could not connect to {}:{} for a forwarded connection", self.spec.target_host, self.spec.target_port);
+                let _ = channel.close();
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes SOCKS clients and opens channels from the device for those whose handshake is done
+    fn accept_socks(&mut self, proxy: &mut SocksProxy) -> Result<bool, SSHError> {
+        let mut busy = false;
+        match proxy.listener.accept() {
+            Ok((stream, peer)) => {
+                busy = true;
+                if self.connections.len() >= proxy.settings.max_connections {
+                    warn!("SOCKS proxy {}: refusing {}, {} connections are open", self.label, peer, self.connections.len());
+                } else {
+                    self.start_handshake(proxy, stream, peer);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Ok(SocksRequest { mut stream, peer, destination }) = proxy.requests.try_recv() {
+            busy = true;
+            self.session.set_blocking(true);
+            let channel = self.session.channel_direct_tcpip(
+                &destination.host,
+                destination.port,
+                Some((&peer.ip().to_string(), peer.port())),
+            );
+            self.session.set_blocking(false);
+            match channel {
+                Ok(channel) => {
+                    debug!("SOCKS proxy {}: connecting {} to {}:{}", self.label, peer, destination.host, destination.port);
+                    if stream.write_all(&socks::reply(socks::SUCCEEDED)).is_ok() {
+                        self.add(channel, stream)?;
+                    }
+                }
+                Err(e) => {
+                    error!("Device could not open a channel to {}:{}: {}", destination.host, destination.port, e);
+                    let _ = stream.write_all(&socks::reply(socks::HOST_UNREACHABLE));
+                }
+            }
+        }
+        Ok(busy)
+    }
+
+    /// Reads a SOCKS client's request on a thread of its own, refusing destinations not allowed
+    fn start_handshake(&self, proxy: &SocksProxy, mut stream: TcpStream, peer: SocketAddr) {
+        let filter = proxy.filter.clone();
+        let requests_tx = proxy.requests_tx.clone();
+        let timeout = Duration::from_secs(proxy.settings.handshake_timeout_seconds.max(1));
+        let label = self.label.clone();
+        std::thread::spawn(move || {
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_read_timeout(Some(timeout));
+            match socks::handshake(&mut stream) {
+                Ok(destination) if filter.allows(&destination) => {
+                    let _ = stream.set_read_timeout(None);
+                    let _ = requests_tx.send(SocksRequest { stream, peer, destination });
+                }
+                Ok(destination) => {
+                    warn!("SOCKS proxy {}: {} may not connect to {}:{}", label, peer, destination.host, destination.port);
+                    let _ = stream.write_all(&socks::reply(socks::NOT_ALLOWED));
+                }
+                Err(e) => debug!("SOCKS proxy {}: handshake with {} failed: {}", label, peer, e),
+            }
+        });
+    }
+
+    fn add(&mut self, channel: Channel, stream: TcpStream) -> Result<(), SSHError> {
+        stream.set_nonblocking(true)?;
+        let _ = stream.set_nodelay(true);
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        self.connections.push(Connection { channel, stream });
+        Ok(())
+    }
+}
+
+impl Connection {
+    /// Moves whatever is ready in either direction
+    ///
+    /// # Returns
+    /// * `bool` - false once either side has closed
+    fn pump(&mut self, session: &Session, buf: &mut [u8], stats: &ForwardStats, busy: &mut bool) -> bool {
+        // Device -> TCP peer
+        match self.channel.read(buf) {
+            Ok(0) if self.channel.eof() => return false,
+            Ok(0) => {}
+            Ok(n) => {
+                if write_stream(&mut self.stream, &buf[..n]).is_err() {
+                    return false;
+                }
+                stats.bytes_from_device.fetch_add(n as u64, Ordering::Relaxed);
+                *busy = true;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => return false,
+        }
+
+        // TCP peer -> device
+        match self.stream.read(buf) {
+            Ok(0) => return false,
+            Ok(n) => {
+                if write_all(session, &mut self.channel, &buf[..n]).is_err() {
+                    return false;
+                }
+                stats.bytes_to_device.fetch_add(n as u64, Ordering::Relaxed);
+                *busy = true;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => return false,
+        }
+        true
+    }
+
+    fn close(&mut self) {
+        let _ = self.channel.send_eof();
+        let _ = self.channel.close();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Writes all data to a non-blocking socket, retrying while it would block
+fn write_stream(stream: &mut TcpStream, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,805 @@
+use bytes::Bytes;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::ptr;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::settings::{CompressionMode, GssapiSettings};
+use super::backend::ShellBackend;
+use super::error::SSHError;
+use super::heartbeat::Heartbeat;
+use super::session::SessionHandle;
+use super::source::{self, SourceInfo};
+use super::target::{CompressionInfo, ConnectionInfo, ConnectionTarget, TerminalSize};
+use super::telnet::recv_resize;
+
+// GSSAPI (RFC 2744), as far as checking the gateway's credentials goes
+type OmUint32 = u32;
+type GssName = *mut c_void;
+type GssCred = *mut c_void;
+
+#[repr(C)]
+struct GssBuffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+/// One entry of a credential store (MIT `gss_acquire_cred_from`)
+#[repr(C)]
+struct KeyValueElement {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+#[repr(C)]
+struct KeyValueSet {
+    count: OmUint32,
+    elements: *const KeyValueElement,
+}
+
+type AcquireCredFrom = unsafe extern "C" fn(*mut OmUint32, GssName, OmUint32, *const c_void, c_int, *const KeyValueSet, *mut GssCred, *mut *mut c_void, *mut OmUint32) -> OmUint32;
+type InquireCred = unsafe extern "C" fn(*mut OmUint32, GssCred, *mut GssName, *mut OmUint32, *mut c_int, *mut *mut c_void) -> OmUint32;
+type DisplayName = unsafe extern "C" fn(*mut OmUint32, GssName, *mut GssBuffer, *mut *mut c_void) -> OmUint32;
+type DisplayStatus = unsafe extern "C" fn(*mut OmUint32, OmUint32, c_int, *const c_void, *mut OmUint32, *mut GssBuffer) -> OmUint32;
+type ReleaseBuffer = unsafe extern "C" fn(*mut OmUint32, *mut GssBuffer) -> OmUint32;
+type ReleaseName = unsafe extern "C" fn(*mut OmUint32, *mut GssName) -> OmUint32;
+type ReleaseCred = unsafe extern "C" fn(*mut OmUint32, *mut GssCred) -> OmUint32;
+
+const GSS_C_INITIATE: c_int = 1;
+const GSS_C_GSS_CODE: c_int = 1;
+const GSS_C_MECH_CODE: c_int = 2;
+const GSS_C_INDEFINITE: OmUint32 = 0xffff_ffff;
+const GSS_C_ROUTINE_ERROR_MASK: OmUint32 = 0x00ff_0000;
+const GSS_S_NO_CRED: OmUint32 = 7 << 16;
+const GSS_S_CREDENTIALS_EXPIRED: OmUint32 = 11 << 16;
+
+/// The MIT Kerberos GSSAPI library, which the OpenSSH client uses too
+const LIBRARY: &str = "libgssapi_krb5.so.2";
+
+/// Longest the OpenSSH client is given to exit once asked to
+const EXIT_GRACE: Duration = Duration::from_secs(2);
+
+struct Library {
+    acquire_cred_from: AcquireCredFrom,
+    inquire_cred: InquireCred,
+    display_name: DisplayName,
+    display_status: DisplayStatus,
+    release_buffer: ReleaseBuffer,
+    release_name: ReleaseName,
+    release_cred: ReleaseCred,
+}
+
+/// Loaded on first use; never unloaded
+static GSSAPI: OnceLock<Result<Library, String>> = OnceLock::new();
+
+impl Library {
+    fn get() -> Result<&'static Library, SSHError> {
+        GSSAPI.get_or_init(Library::load).as_ref().map_err(|e| SSHError::Gssapi(e.clone()))
+    }
+
+    fn load() -> Result<Library, String> {
+        let name = CString::new(LIBRARY).map_err(|e| e.to_string())?;
+        // SAFETY: dlopen and dlsym are given NUL-terminated strings; each symbol is
+        // transmuted to the signature RFC 2744 and the MIT extensions define for it
+        unsafe {
+            let library = libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if library.is_null() {
+                return Err(format!("cannot load {}; is MIT Kerberos installed?", LIBRARY));
+            }
+            let symbol = |name: &CStr| {
+                let symbol = libc::dlsym(library, name.as_ptr());
+                if symbol.is_null() {
+                    Err(format!("{} has no {}", LIBRARY, name.to_string_lossy()))
+                } else {
+                    Ok(symbol)
+                }
+            };
+            Ok(Library {
+                acquire_cred_from: std::mem::transmute::<*mut c_void, AcquireCredFrom>(symbol(c"gss_acquire_cred_from")?),
+                inquire_cred: std::mem::transmute::<*mut c_void, InquireCred>(symbol(c"gss_inquire_cred")?),
+                display_name: std::mem::transmute::<*mut c_void, DisplayName>(symbol(c"gss_display_name")?),
+                display_status: std::mem::transmute::<*mut c_void, DisplayStatus>(symbol(c"gss_display_status")?),
+                release_buffer: std::mem::transmute::<*mut c_void, ReleaseBuffer>(symbol(c"gss_release_buffer")?),
+                release_name: std::mem::transmute::<*mut c_void, ReleaseName>(symbol(c"gss_release_name")?),
+                release_cred: std::mem::transmute::<*mut c_void, ReleaseCred>(symbol(c"gss_release_cred")?),
+            })
+        }
+    }
+
+    /// Takes the text out of a buffer the library allocated, and frees it
+    fn take_buffer(&self, buffer: &mut GssBuffer) -> String {
+        let text = if buffer.value.is_null() {
+            String::new()
+        } else {
+            // SAFETY: the library filled in `length` bytes at `value`
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.value as *const u8, buffer.length) };
+            String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()
+        };
+        let mut minor = 0;
+        // SAFETY: the buffer was allocated by the library
+        unsafe { (self.release_buffer)(&mut minor, buffer) };
+        text
+    }
+
+    /// The library's messages for a failed call, Kerberos's own last as it says most
+    fn status_message(&self, major: OmUint32, minor: OmUint32) -> String {
+        let mut messages = Vec::new();
+        for (code, kind) in [(major, GSS_C_GSS_CODE), (minor, GSS_C_MECH_CODE)] {
+            if code == 0 {
+                continue;
+            }
+            let mut context = 0;
+            loop {
+                let mut buffer = GssBuffer { length: 0, value: ptr::null_mut() };
+                let mut status = 0;
+                // SAFETY: the buffer and context are written by the call
+                let result = unsafe { (self.display_status)(&mut status, code, kind, ptr::null(), &mut context, &mut buffer) };
+                if result & GSS_C_ROUTINE_ERROR_MASK != 0 {
+                    break;
+                }
+                messages.push(self.take_buffer(&mut buffer));
+                if context == 0 {
+                    break;
+                }
+            }
+        }
+        messages.retain(|message| !message.is_empty());
+        messages.join(": ")
+    }
+
+    fn error(&self, major: OmUint32, minor: OmUint32) -> SSHError {
+        let message = self.status_message(major, minor);
+        match major & GSS_C_ROUTINE_ERROR_MASK {
+            GSS_S_NO_CRED => SSHError::TicketMissing(message),
+            GSS_S_CREDENTIALS_EXPIRED => SSHError::TicketExpired(message),
+            _ => SSHError::Gssapi(message),
+        }
+    }
+}
+
+/// The gateway's Kerberos credentials, as SSH logins will present them
+#[derive(Debug, Clone)]
+pub struct KerberosCredentials {
+    /// e.g. `webssh@EXAMPLE.COM`
+    pub principal: String,
+    /// Time left on the ticket; None when tickets are renewed from the keytab
+    pub lifetime: Option<Duration>,
+}
+
+/// Checks that the gateway holds a ticket it can log in with, or a keytab to get one from
+///
+/// # Returns
+/// * `Result<KerberosCredentials, SSHError>` - The credentials, or `TicketMissing`
+///   or `TicketExpired` when there is no ticket that can be used
+pub fn credentials(settings: &GssapiSettings) -> Result<KerberosCredentials, SSHError> {
+    let library = Library::get()?;
+    let mut store = Vec::new();
+    for (key, value) in [("ccache", &settings.credential_cache), ("client_keytab", &settings.keytab)] {
+        if let Some(value) = value {
+            let value = CString::new(value.as_str()).map_err(|_| SSHError::Gssapi(format!("invalid {} {}", key, value)))?;
+            store.push((CString::new(key).unwrap_or_default(), value));
+        }
+    }
+    let elements: Vec<_> = store.iter()
+        .map(|(key, value)| KeyValueElement { key: key.as_ptr(), value: value.as_ptr() })
+        .collect();
+    let store = KeyValueSet { count: elements.len() as OmUint32, elements: elements.as_ptr() };
+
+    let mut minor = 0;
+    let mut credential: GssCred = ptr::null_mut();
+    // SAFETY: the store's strings outlive the call; the credential is released below
+    let major = unsafe {
+        (library.acquire_cred_from)(&mut minor, ptr::null_mut(), GSS_C_INDEFINITE, ptr::null(), GSS_C_INITIATE,
+                                    if elements.is_empty() { ptr::null() } else { &store },
+                                    &mut credential, ptr::null_mut(), ptr::null_mut())
+    };
+    if major & GSS_C_ROUTINE_ERROR_MASK != 0 {
+        return Err(library.error(major, minor));
+    }
+
+    let mut name: GssName = ptr::null_mut();
+    let mut lifetime = 0;
+    // SAFETY: the credential was acquired above; the name is released below
+    let major = unsafe { (library.inquire_cred)(&mut minor, credential, &mut name, &mut lifetime, ptr::null_mut(), ptr::null_mut()) };
+    let result = if major & GSS_C_ROUTINE_ERROR_MASK != 0 {
+        Err(library.error(major, minor))
+    } else {
+        let mut buffer = GssBuffer { length: 0, value: ptr::null_mut() };
+        // SAFETY: the name was returned by gss_inquire_cred
+        let principal = unsafe {
+            (library.display_name)(&mut minor, name, &mut buffer, ptr::null_mut());
+            (library.release_name)(&mut minor, &mut name);
+            library.take_buffer(&mut buffer)
+        };
+        // A keytab gets a new ticket whenever it is needed
+        match (lifetime, &settings.keytab) {
+            (_, Some(_)) | (GSS_C_INDEFINITE, None) => Ok(KerberosCredentials { principal, lifetime: None }),
+            (0, None) => Err(SSHError::TicketExpired(format!("the ticket of {} has expired", principal))),
+            (seconds, None) => Ok(KerberosCredentials { principal, lifetime: Some(Duration::from_secs(seconds as u64)) }),
+        }
+    };
+    // SAFETY: the credential was acquired above and is not used after this
+    unsafe { (library.release_cred)(&mut minor, &mut credential) };
+    result
+}
+
+/// Checks the settings, so a missing OpenSSH client is reported at startup rather than on connect
+pub fn validate(settings: &GssapiSettings) -> Result<(), String> {
+    if !settings.enabled {
+        return Ok(());
+    }
+    let binary = std::path::Path::new(&settings.ssh_binary);
+    let found = if binary.components().count() > 1 {
+        binary.is_file()
+    } else {
+        std::env::var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(binary).is_file()))
+    };
+    if !found {
+        return Err(format!("the OpenSSH client '{}' was not found", settings.ssh_binary));
+    }
+    if let Some(keytab) = &settings.keytab {
+        let path = keytab.strip_prefix("FILE:").unwrap_or(keytab);
+        if !std::path::Path::new(path).is_file() {
+            return Err(format!("keytab {} does not exist", keytab));
+        }
+    }
+    if let Some(option) = settings.options.iter().find(|option| !option.contains('=')) {
+        return Err(format!("option '{}' is not in the form Name=value", option));
+    }
+    Ok(())
+}
+
+/// An interactive shell on a device logged in to with the gateway's Kerberos credentials
+///
+/// libssh2 has no GSSAPI authentication, so the host's OpenSSH client makes the
+/// connection, run in a pseudo-terminal of its own. The gateway only sees the
+/// terminal, so such sessions offer a shell and nothing else: no exec, file
+/// transfers or forwarding.
+pub struct GssapiSession {
+    // Our end of the client's pseudo-terminal
+    pty: OwnedFd,
+    client: Child,
+    resize_rx: Option<mpsc::Receiver<(u32, u32)>>,
+    // Cancelled to stop the I/O pump and the WebSocket attached to it
+    shutdown: CancellationToken,
+    target: ConnectionTarget,
+    connection_info: ConnectionInfo,
+    // Progress of this shell's I/O loop
+    heartbeat: Heartbeat,
+    // Heartbeats of every shell opened for the session, for the watchdog
+    shells: Arc<Mutex<Vec<Heartbeat>>>,
+}
+
+impl GssapiSession {
+    /// Checks the gateway's tickets, runs the OpenSSH client and waits for it to log in
+    ///
+    /// # Arguments
+    /// * `target` - The connection parameters for the device
+    ///
+    /// # Returns
+    /// * `Result<Self, SSHError>` - A new GssapiSession or an error
+    pub fn open(target: ConnectionTarget) -> Result<Self, SSHError> {
+        let gssapi = &target.settings.gssapi;
+        if !gssapi.enabled {
+            return Err(SSHError::Gssapi("Kerberos authentication is not enabled on this instance".to_string()));
+        }
+        if target.jump_host.is_some() {
+            return Err(SSHError::Gssapi("Kerberos logins cannot go through a jump host".to_string()));
+        }
+        let credentials = credentials(gssapi)?;
+        match credentials.lifetime {
+            Some(lifetime) => info!("Connecting to {}:{} as {} with the Kerberos ticket of {}, valid for {} more minutes",
+                                    target.hostname, target.port, target.username, credentials.principal, lifetime.as_secs() / 60),
+            None => info!("Connecting to {}:{} as {} with the Kerberos credentials of {}",
+                          target.hostname, target.port, target.username, credentials.principal),
+        }
+
+        let source = source::choose(&target.settings.outbound, &target.hostname, target.device_type.as_deref())?;
+        let terminal = target.shell_settings().terminal;
+        let (pty, terminal_end) = open_pty(window_size(terminal.default_cols, terminal.default_rows, terminal.width_px, terminal.height_px))?;
+
+        let mut command = Command::new(&gssapi.ssh_binary);
+        command.args(client_args(&target, &source))
+            .env("TERM", &terminal.standard_terminal_type)
+            .stdin(Stdio::from(terminal_end.try_clone()?))
+            .stdout(Stdio::from(terminal_end))
+            .stderr(Stdio::piped());
+        if let Some(cache) = &gssapi.credential_cache {
+            command.env("KRB5CCNAME", cache);
+        }
+        if let Some(keytab) = &gssapi.keytab {
+            command.env("KRB5_CLIENT_KTNAME", keytab);
+        }
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                // The pseudo-terminal becomes the client's controlling terminal, so it gets SIGWINCH on resizes
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let started = Instant::now();
+        let mut client = command.spawn()
+            .map_err(|e| SSHError::Gssapi(format!("cannot run {}: {}", gssapi.ssh_binary, e)))?;
+        // Our copies of the terminal end must be closed, or the client exiting goes unnoticed
+        drop(command);
+
+        let Some(log) = client.stderr.take() else {
+            stop(&mut client);
+            return Err(SSHError::Gssapi("the OpenSSH client's log cannot be read".to_string()));
+        };
+        let (login_tx, login_rx) = std_mpsc::channel();
+        let hostname = target.hostname.clone();
+        std::thread::spawn(move || follow_log(log, login_tx, started, &hostname));
+
+        let timeout = Duration::from_secs(target.settings.connection.timeout_seconds.max(1) * 2);
+        let tcp_connect_ms = match login_rx.recv_timeout(timeout) {
+            Ok(Ok(tcp_connect_ms)) => tcp_connect_ms,
+            Ok(Err(e)) => {
+                stop(&mut client);
+                return Err(e);
+            }
+            Err(_) => {
+                stop(&mut client);
+                return Err(SSHError::Connection(Error::new(ErrorKind::TimedOut,
+                    format!("not logged in to {} within {} s", target.hostname, timeout.as_secs()))));
+            }
+        };
+        info!("Logged in to {}:{} with Kerberos in {} ms", target.hostname, target.port, started.elapsed().as_millis());
+
+        let heartbeat = Heartbeat::new(pty.as_raw_fd());
+        Ok(Self {
+            pty,
+            client,
+            resize_rx: None,
+            shutdown: CancellationToken::new(),
+            connection_info: ConnectionInfo {
+                tcp_connect_ms: tcp_connect_ms.unwrap_or_default(),
+                compression: CompressionInfo {
+                    mode: target.compression,
+                    requested: target.compression == CompressionMode::On,
+                    client_to_server: None,
+                    server_to_client: None,
+                },
+                terminal_type: Some(terminal.standard_terminal_type.clone()),
+                terminal_size: Some(TerminalSize { cols: terminal.default_cols, rows: terminal.default_rows }),
+                source,
+                slow_link: None,
+                device_type: None,
+                quirks: Vec::new(),
+            },
+            target,
+            shells: Arc::new(Mutex::new(vec![heartbeat.clone()])),
+            heartbeat,
+        })
+    }
+
+    /// Gets a handle to this session for the session registry
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle::new(
+            self.target.clone(),
+            self.connection_info.clone(),
+            self.shutdown.clone(),
+            self.shells.clone(),
+            None,
+        )
+    }
+
+    /// Makes this shell one of a session's, shut down together with it
+    pub(super) fn join(&mut self, shutdown: CancellationToken, shells: &Arc<Mutex<Vec<Heartbeat>>>) {
+        self.shutdown = shutdown;
+        if let Ok(mut shells) = shells.lock() {
+            shells.push(self.heartbeat.clone());
+        }
+        self.shells = shells.clone();
+    }
+
+    /// Sets the channel for receiving terminal resize events, as (rows, cols)
+    pub fn set_resize_channel(&mut self, resize_rx: mpsc::Receiver<(u32, u32)>) {
+        self.resize_rx = Some(resize_rx);
+    }
+
+    /// Gets the token cancelled when the session shuts down, for tasks serving it
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Ends the OpenSSH client and signals the I/O pump to stop
+    pub fn close(&mut self) -> Result<(), SSHError> {
+        info!("Closing Kerberos SSH connection to {}:{}", self.target.hostname, self.target.port);
+        self.shutdown.cancel();
+        stop(&mut self.client);
+        Ok(())
+    }
+
+    /// Pumps I/O between the client's terminal and the WebSocket on the Tokio reactor
+    async fn pump(&mut self, mut input_rx: mpsc::Receiver<Bytes>, output_tx: mpsc::Sender<Bytes>) -> Result<(), SSHError> {
+        info!("Starting Kerberos SSH I/O handling");
+
+        // A second descriptor, so the session's own stays valid for the watchdog until it is dropped
+        let pty = self.pty.try_clone()?;
+        set_nonblocking(&pty)?;
+        let pty = AsyncFd::new(pty)?;
+        let mut resize_rx = self.resize_rx.take();
+        let shutdown = self.shutdown.clone();
+        // The client sends the keepalives; the loop only wakes to show it is not stuck
+        let keepalive_period = Duration::from_secs(self.connection_info.keepalive_seconds(&self.target.settings).max(1));
+        let mut wake = tokio::time::interval(keepalive_period);
+        let mut buf = [0u8; 4096];
+
+        loop {
+            self.heartbeat.beat();
+
+            tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("Shutdown signalled, stopping I/O handling");
+                    break;
+                }
+                // Output waits in the terminal while the shell's output queue is full
+                read = read_pty(&pty, &mut buf), if output_tx.capacity() > 0 => {
+                    // The terminal reads EIO once the client has exited
+                    let n = match read {
+                        Ok(n) => n,
+                        Err(e) if e.raw_os_error() == Some(libc::EIO) => 0,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if n == 0 {
+                        info!("Kerberos SSH connection to {}:{} closed", self.target.hostname, self.target.port);
+                        shutdown.cancel();
+                        let _ = output_tx.send(Bytes::from_static(b"\r\n[SSH connection closed]\r\n")).await;
+                        break;
+                    }
+                    self.heartbeat.heard();
+                    if output_tx.send(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                        error!("Failed to send SSH output to WebSocket");
+                        break;
+                    }
+                }
+                data = input_rx.recv() => {
+                    let Some(data) = data else {
+                        debug!("WebSocket input closed, stopping I/O handling");
+                        break;
+                    };
+                    write_pty(&pty, &data).await?;
+                }
+                Some((rows, cols)) = recv_resize(&mut resize_rx) => {
+                    debug!("Processing resize command: {}x{}", cols, rows);
+                    let (width_px, height_px) = self.target.pixel_size(cols, rows);
+                    if let Err(e) = set_window_size(&self.pty, window_size(cols, rows, width_px, height_px)) {
+                        warn!("Failed to resize the terminal of {}:{}: {}", self.target.hostname, self.target.port, e);
+                    }
+                }
+                permit = output_tx.reserve(), if output_tx.capacity() == 0 => {
+                    if permit.is_err() {
+                        break;
+                    }
+                }
+                _ = wake.tick() => {}
+            }
+        }
+
+        info!("Kerberos SSH I/O handling completed");
+        Ok(())
+    }
+}
+
+impl ShellBackend for GssapiSession {
+    async fn run_io(
+        mut self,
+        input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), SSHError> {
+        let result = self.pump(input_rx, output_tx).await;
+        let _ = self.close();
+        result
+    }
+}
+
+impl Drop for GssapiSession {
+    fn drop(&mut self) {
+        // The terminal closes right after this; the watchdog must not touch it from now on
+        self.heartbeat.finish();
+        stop(&mut self.client);
+    }
+}
+
+/// The OpenSSH client's arguments for a login to the target with GSSAPI alone
+///
+/// Escapes are off, so `~.` typed in the terminal reaches the device.
+fn client_args(target: &ConnectionTarget, source: &SourceInfo) -> Vec<String> {
+    let connection = &target.settings.connection;
+    let gssapi = &target.settings.gssapi;
+    let yes_no = |yes: bool| if yes { "yes" } else { "no" };
+    let mut options = vec![
+        "BatchMode=yes".to_string(),
+        "PreferredAuthentications=gssapi-with-mic".to_string(),
+        "GSSAPIAuthentication=yes".to_string(),
+        format!("GSSAPIDelegateCredentials={}", yes_no(gssapi.delegate_credentials)),
+        format!("ConnectTimeout={}", connection.timeout_seconds),
+        format!("ServerAliveInterval={}", connection.keepalive_seconds),
+        format!("Compression={}", yes_no(target.compression == CompressionMode::On)),
+    ];
+    match &gssapi.known_hosts_file {
+        Some(file) => options.extend(["StrictHostKeyChecking=accept-new".to_string(), format!("UserKnownHostsFile={}", file)]),
+        None => options.extend(["StrictHostKeyChecking=no".to_string(), "UserKnownHostsFile=/dev/null".to_string()]),
+    }
+    for (name, value) in &target.environment {
+        if value.contains(['"', '\r', '\n']) {
+            warn!("Not passing {} to {}: its value cannot be quoted for the OpenSSH client", name, target.hostname);
+            continue;
+        }
+        options.push(format!("SetEnv={}=\"{}\"", name, value));
+    }
+    options.extend(gssapi.options.iter().cloned());
+
+    let mut args = vec!["-tt".to_string(), "-v".to_string(), "-e".to_string(), "none".to_string()];
+    args.extend(["-p".to_string(), target.port.to_string(), "-l".to_string(), target.username.clone()]);
+    for option in options {
+        args.extend(["-o".to_string(), option]);
+    }
+    if let Some(address) = &source.address {
+        args.extend(["-b".to_string(), address.clone()]);
+    }
+    if let Some(interface) = &source.interface {
+        args.extend(["-B".to_string(), interface.clone()]);
+    }
+    // Nothing after this is taken for an option, whatever the hostname
+    args.extend(["--".to_string(), target.hostname.clone()]);
+    args
+}
+
+/// What the client's log says about its login so far
+#[derive(Debug, Default)]
+struct LoginLog {
+    // Time from starting the client to the TCP connection, if it got that far
+    tcp_connect_ms: Option<u64>,
+    ticket_missing: bool,
+    ticket_expired: bool,
+    // The last line that was not debugging output, e.g. "Permission denied (gssapi-with-mic)."
+    last_error: Option<String>,
+}
+
+impl LoginLog {
+    /// Reads one line of the log, returning true once the client has logged in
+    fn line(&mut self, line: &str, elapsed: Duration) -> bool {
+        if line.contains("Authenticated to ") || line.contains("Authentication succeeded") {
+            return true;
+        }
+        if line.starts_with("debug1: Connection established") {
+            self.tcp_connect_ms = Some(elapsed.as_millis() as u64);
+        }
+        if line.contains("No Kerberos credentials available") || line.contains("No credentials cache found") {
+            self.ticket_missing = true;
+        }
+        if line.contains("Ticket expired") || line.contains("Credentials expired") {
+            self.ticket_expired = true;
+        }
+        if !line.starts_with("debug") && !line.trim().is_empty() {
+            self.last_error = Some(line.trim().to_string());
+        }
+        false
+    }
+
+    /// Why the client exited without logging in
+    fn failure(self) -> SSHError {
+        let message = self.last_error.unwrap_or_else(|| "the OpenSSH client exited".to_string());
+        if self.ticket_expired {
+            SSHError::TicketExpired(message)
+        } else if self.ticket_missing {
+            SSHError::TicketMissing(message)
+        } else if message.contains("Permission denied") {
+            SSHError::Authentication(format!("Kerberos authentication failed: {}", message))
+        } else {
+            SSHError::Connection(Error::other(message))
+        }
+    }
+}
+
+/// Reads the client's log, reporting whether it logged in, then logs the rest
+fn follow_log(log: ChildStderr, login_tx: std_mpsc::Sender<Result<Option<u64>, SSHError>>, started: Instant, hostname: &str) {
+    let mut login = Some(LoginLog::default());
+    for line in BufReader::new(log).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        match login.as_mut() {
+            Some(log) => {
+                trace!("[ssh {}] {}", hostname, line);
+                if log.line(&line, started.elapsed()) {
+                    let _ = login_tx.send(Ok(log.tcp_connect_ms));
+                    login = None;
+                }
+            }
+            None if line.starts_with("debug") => trace!("[ssh {}] {}", hostname, line),
+            None => debug!("[ssh {}] {}", hostname, line),
+        }
+    }
+    if let Some(log) = login {
+        let _ = login_tx.send(Err(log.failure()));
+    }
+}
+
+/// Opens a pseudo-terminal of the given size, returning our end and the client's
+fn open_pty(size: libc::winsize) -> io::Result<(OwnedFd, OwnedFd)> {
+    let (mut ours, mut theirs) = (-1, -1);
+    // SAFETY: openpty writes the two descriptors, which are then owned here
+    unsafe {
+        if libc::openpty(&mut ours, &mut theirs, ptr::null_mut(), ptr::null(), &size) < 0 {
+            return Err(Error::last_os_error());
+        }
+        let pair = (OwnedFd::from_raw_fd(ours), OwnedFd::from_raw_fd(theirs));
+        for fd in [ours, theirs] {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        Ok(pair)
+    }
+}
+
+fn window_size(cols: u32, rows: u32, width_px: u32, height_px: u32) -> libc::winsize {
+    let clamp = |value: u32| value.min(u16::MAX as u32) as u16;
+    libc::winsize { ws_row: clamp(rows), ws_col: clamp(cols), ws_xpixel: clamp(width_px), ws_ypixel: clamp(height_px) }
+}
+
+/// Resizes the terminal; the kernel signals the client, which tells the device
+fn set_window_size(pty: &OwnedFd, size: libc::winsize) -> io::Result<()> {
+    // SAFETY: TIOCSWINSZ reads a winsize
+    if unsafe { libc::ioctl(pty.as_raw_fd(), libc::TIOCSWINSZ, &size) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_nonblocking(fd: &OwnedFd) -> io::Result<()> {
+    // SAFETY: plain fcntl calls on a descriptor we own
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+async fn read_pty(pty: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = pty.readable().await?;
+        // SAFETY: read(2) into a buffer of the given length
+        let read = guard.try_io(|fd| match unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } {
+            n if n < 0 => Err(Error::last_os_error()),
+            n => Ok(n as usize),
+        });
+        if let Ok(result) = read {
+            return result;
+        }
+    }
+}
+
+async fn write_pty(pty: &AsyncFd<OwnedFd>, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let mut guard = pty.writable().await?;
+        // SAFETY: write(2) from a buffer of the given length
+        let written = guard.try_io(|fd| match unsafe { libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) } {
+            n if n < 0 => Err(Error::last_os_error()),
+            n => Ok(n as usize),
+        });
+        if let Ok(written) = written {
+            data = &data[written?..];
+        }
+    }
+    Ok(())
+}
+
+/// Asks the client to exit, killing it if it does not, and reaps it
+fn stop(client: &mut Child) {
+    if !matches!(client.try_wait(), Ok(None)) {
+        return;
+    }
+    // SAFETY: the child has not been reaped, so its pid is still its own
+    unsafe { libc::kill(client.id() as libc::pid_t, libc::SIGTERM) };
+    let deadline = Instant::now() + EXIT_GRACE;
+    while Instant::now() < deadline {
+        if !matches!(client.try_wait(), Ok(None)) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let _ = client.kill();
+    let _ = client.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use crate::ssh::target::Protocol;
+
+    fn target() -> ConnectionTarget {
+        let mut settings = Settings::default().ssh;
+        settings.gssapi.enabled = true;
+        settings.gssapi.known_hosts_file = Some("/var/lib/webssh/known_hosts".to_string());
+        ConnectionTarget {
+            hostname: "-oProxyCommand=evil".to_string(),
+            port: 2222,
+            username: "netops".to_string(),
+            password: None,
+            private_key: None,
+            private_key_passphrase: None,
+            certificate: None,
+            device_type: None,
+            compression: CompressionMode::Off,
+            jump_host: None,
+            keyboard_interactive: false,
+            agent: false,
+            pkcs11: None,
+            gssapi: true,
+            terminal_type: None,
+            terminal_size: None,
+            environment: vec![("LANG".to_string(), "en_US.UTF-8".to_string()), ("TZ".to_string(), "a\"b".to_string())],
+            pty: Default::default(),
+            host_key_check: None,
+            quirks: Default::default(),
+            protocol: Protocol::Ssh,
+            settings,
+        }
+    }
+
+    #[test]
+    fn test_client_args() {
+        let source = SourceInfo { address: Some("10.0.0.250".to_string()), interface: None, group: None };
+        let args = client_args(&target(), &source);
+        let options: Vec<_> = args.windows(2).filter(|pair| pair[0] == "-o").map(|pair| pair[1].as_str()).collect();
+        assert!(options.contains(&"PreferredAuthentications=gssapi-with-mic"));
+        assert!(options.contains(&"BatchMode=yes"));
+        assert!(options.contains(&"StrictHostKeyChecking=accept-new"));
+        assert!(options.contains(&"SetEnv=LANG=\"en_US.UTF-8\""));
+        assert!(!options.iter().any(|option| option.starts_with("SetEnv=TZ")));
+        assert!(args.windows(2).any(|pair| pair == ["-b", "10.0.0.250"]));
+        assert!(args.windows(2).any(|pair| pair == ["-e", "none"]));
+        // The hostname cannot pass for an option
+        assert_eq!(&args[args.len() - 2..], ["--", "-oProxyCommand=evil"]);
+    }
+
+    #[test]
+    fn test_login_log() {
+        let mut log = LoginLog::default();
+        assert!(!log.line("debug1: Connecting to router1 [10.0.0.1] port 22.", Duration::from_millis(1)));
+        assert!(!log.line("debug1: Connection established.", Duration::from_millis(12)));
+        assert!(log.line("Authenticated to router1 ([10.0.0.1]:22) using \"gssapi-with-mic\".", Duration::from_millis(90)));
+        assert_eq!(log.tcp_connect_ms, Some(12));
+
+        let mut log = LoginLog::default();
+        log.line("debug1: Unspecified GSS failure.  Minor code may provide more information", Duration::ZERO);
+        log.line("Ticket expired", Duration::ZERO);
+        log.line("netops@router1: Permission denied (gssapi-with-mic).", Duration::ZERO);
+        assert!(matches!(log.failure(), SSHError::TicketExpired(message) if message.contains("Permission denied")));
+
+        let mut log = LoginLog::default();
+        log.line("netops@router1: Permission denied (gssapi-with-mic,keyboard-interactive).", Duration::ZERO);
+        assert!(matches!(log.failure(), SSHError::Authentication(_)));
+
+        let mut log = LoginLog::default();
+        log.line("ssh: connect to host router1 port 22: Connection refused", Duration::ZERO);
+        assert!(matches!(log.failure(), SSHError::Connection(_)));
+    }
+
+    #[test]
+    fn test_credentials_without_ticket() {
+        let settings = GssapiSettings {
+            enabled: true,
+            credential_cache: Some("FILE:/nonexistent/krb5cc_webssh".to_string()),
+            ..GssapiSettings::default()
+        };
+        // Hosts without MIT Kerberos cannot check at all
+        assert!(matches!(credentials(&settings), Err(SSHError::TicketMissing(_)) | Err(SSHError::Gssapi(_))));
+    }
+}
@@ -0,0 +1,196 @@
+use std::mem::ManuallyDrop;
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Marks `last_beat` before the I/O loop has started
+const NOT_STARTED: u64 = u64::MAX;
+
+/// Progress marker of a shell's I/O loop, checked by the watchdog
+///
+/// The loop beats on every iteration. A loop that stops beating is stuck,
+/// most likely in a libssh2 call on a wedged connection, and can be freed
+/// by shutting down the socket underneath it from another thread.
+///
+/// The loop also counts its keepalives here: those that failed to send, and
+/// the intervals that went by without a word from the device, for the
+/// keepalive monitor to tell a degraded or dead connection.
+#[derive(Clone)]
+pub struct Heartbeat(Arc<Inner>);
+
+struct Inner {
+    epoch: Instant,
+    // Milliseconds since `epoch` of the last beat
+    last_beat: AtomicU64,
+    finished: AtomicBool,
+    // Keepalives in a row that failed to send
+    keepalive_failures: AtomicU32,
+    // Keepalive intervals in a row that ended without anything from the device
+    missed_replies: AtomicU32,
+    // Whether anything came from the device since the last keepalive
+    heard: AtomicBool,
+    // The session socket; owned by the session, never closed from here
+    fd: RawFd,
+}
+
+impl Heartbeat {
+    pub(super) fn new(fd: RawFd) -> Self {
+        Self(Arc::new(Inner {
+            epoch: Instant::now(),
+            last_beat: AtomicU64::new(NOT_STARTED),
+            finished: AtomicBool::new(false),
+            keepalive_failures: AtomicU32::new(0),
+            missed_replies: AtomicU32::new(0),
+            heard: AtomicBool::new(true),
+            fd,
+        }))
+    }
+
+    /// Records that the I/O loop has made progress
+    pub fn beat(&self) {
+        let now = self.0.epoch.elapsed().as_millis() as u64;
+        self.0.last_beat.store(now, Ordering::Relaxed);
+    }
+
+    /// Records that something came from the device: output, or the reply to a keepalive
+    pub fn heard(&self) {
+        self.0.heard.store(true, Ordering::Relaxed);
+        self.0.missed_replies.store(0, Ordering::Relaxed);
+    }
+
+    /// Records that a keepalive is due, counting the last interval as missed if the device said nothing in it
+    pub fn keepalive_due(&self) {
+        if !self.0.heard.swap(false, Ordering::Relaxed) {
+            self.0.missed_replies.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records whether a keepalive went out
+    pub fn keepalive_sent(&self, sent: bool) {
+        if sent {
+            self.0.keepalive_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.0.keepalive_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The keepalive counts of the connection, for the keepalive monitor
+    pub fn keepalive(&self) -> KeepaliveCounts {
+        KeepaliveCounts {
+            failures: self.0.keepalive_failures.load(Ordering::Relaxed),
+            missed_replies: self.0.missed_replies.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Records that the I/O loop has ended and the session is closed
+    pub fn finish(&self) {
+        self.0.finished.store(true, Ordering::Release);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.finished.load(Ordering::Acquire)
+    }
+
+    /// How long the I/O loop has gone without progress
+    ///
+    /// # Returns
+    /// * `Option<Duration>` - None if the loop has not started or has ended
+    pub fn stalled_for(&self) -> Option<Duration> {
+        let last_beat = self.0.last_beat.load(Ordering::Relaxed);
+        if last_beat == NOT_STARTED || self.is_finished() {
+            return None;
+        }
+        Some(self.0.epoch.elapsed().saturating_sub(Duration::from_millis(last_beat)))
+    }
+
+    /// Shuts down the session socket so a stuck libssh2 call fails and the loop can end
+    ///
+    /// The descriptor is left open, so it cannot be reused while the session
+    /// still owns it; shutdown(2) applies to jump host tunnels' Unix sockets too.
+    ///
+    /// # Returns
+    /// * `bool` - true if the socket was shut down
+    pub fn force_close(&self) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        // SAFETY: the session keeps the descriptor open until the loop has finished,
+        // and ManuallyDrop keeps this wrapper from closing it
+        let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(self.0.fd) });
+        socket.shutdown(Shutdown::Both).is_ok()
+    }
+}
+
+/// How a connection's keepalives have gone lately
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct KeepaliveCounts {
+    /// Keepalives in a row that failed to send
+    pub failures: u32,
+    /// Keepalive intervals in a row without anything from the device
+    pub missed_replies: u32,
+}
+
+impl KeepaliveCounts {
+    /// The worse of two connections' counts
+    pub fn worst(self, other: Self) -> Self {
+        Self {
+            failures: self.failures.max(other.failures),
+            missed_replies: self.missed_replies.max(other.missed_replies),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_force_close_frees_a_blocked_reader() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_server, _) = listener.accept().unwrap();
+
+        let heartbeat = Heartbeat::new(client.as_raw_fd());
+        assert_eq!(heartbeat.stalled_for(), None);
+        heartbeat.beat();
+        assert!(heartbeat.stalled_for().unwrap() < Duration::from_secs(1));
+
+        // A read with no data would block forever; the shutdown ends it
+        let closer = heartbeat.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(closer.force_close());
+        });
+        assert_eq!(client.read(&mut [0u8; 16]).unwrap(), 0);
+
+        heartbeat.finish();
+        assert_eq!(heartbeat.stalled_for(), None);
+        assert!(!heartbeat.force_close());
+    }
+
+    #[test]
+    fn test_keepalive_counts() {
+        let heartbeat = Heartbeat::new(-1);
+        // The first interval starts out heard
+        heartbeat.keepalive_due();
+        heartbeat.keepalive_sent(true);
+        assert_eq!(heartbeat.keepalive(), KeepaliveCounts::default());
+
+        heartbeat.keepalive_due();
+        heartbeat.keepalive_sent(false);
+        heartbeat.keepalive_due();
+        heartbeat.keepalive_sent(false);
+        assert_eq!(heartbeat.keepalive(), KeepaliveCounts { failures: 2, missed_replies: 2 });
+
+        heartbeat.heard();
+        heartbeat.keepalive_due();
+        heartbeat.keepalive_sent(true);
+        assert_eq!(heartbeat.keepalive(), KeepaliveCounts::default());
+    }
+}
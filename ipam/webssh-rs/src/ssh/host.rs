@@ -0,0 +1,116 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+
+/// Puts a device hostname in the form it is connected and matched by
+///
+/// Brackets around an IPv6 address are dropped, as is the URI encoding of a
+/// zone separator (`fe80::1%25mgmt0`), leaving e.g. `fe80::1%mgmt0`.
+pub fn normalize(host: &str) -> String {
+    let host = host.trim();
+    let unbracketed = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if !unbracketed.contains(':') {
+        return host.to_string();
+    }
+    match unbracketed.split_once("%25") {
+        Some((address, zone)) if !zone.is_empty() && address.parse::<Ipv6Addr>().is_ok() => format!("{}%{}", address, zone),
+        _ => unbracketed.to_string(),
+    }
+}
+
+/// Splits an IPv6 address scoped to an interface, e.g. `fe80::1%mgmt0`, into its address and zone
+pub fn scoped_ipv6(host: &str) -> Option<(Ipv6Addr, &str)> {
+    let (address, zone) = host.split_once('%')?;
+    Some((address.parse().ok()?, zone))
+}
+
+/// The address a hostname is written as, without any zone; None for a name
+pub fn ip(host: &str) -> Option<IpAddr> {
+    match scoped_ipv6(host) {
+        Some((address, _)) => Some(IpAddr::V6(address)),
+        None => host.parse().ok(),
+    }
+}
+
+/// Checks a normalized hostname; a zone is only accepted on a link-local IPv6 address
+pub fn validate(host: &str) -> Result<(), String> {
+    if host.is_empty() {
+        return Err("hostname is required".to_string());
+    }
+    if !host.contains('%') {
+        return Ok(());
+    }
+    let Some((address, zone)) = scoped_ipv6(host) else {
+        return Err(format!("'{}' is not an IPv6 address with an interface scope", host));
+    };
+    if zone.is_empty() || !zone.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(format!("'{}' is not a valid interface name", zone));
+    }
+    if !address.is_unicast_link_local() {
+        return Err(format!("Interface scopes only apply to link-local addresses, not {}", address));
+    }
+    Ok(())
+}
+
+/// Resolves a device to the addresses to connect to, with the interface of a scoped address
+pub fn resolve(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    match scoped_ipv6(host) {
+        Some((address, zone)) => Ok(vec![SocketAddr::V6(SocketAddrV6::new(address, port, 0, scope_id(zone)?))]),
+        None => Ok((host, port).to_socket_addrs()?.collect()),
+    }
+}
+
+/// Resolves a device without blocking the runtime
+pub async fn lookup(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    match scoped_ipv6(host) {
+        Some(_) => resolve(host, port),
+        None => Ok(tokio::net::lookup_host((host, port)).await?.collect()),
+    }
+}
+
+/// The index of the interface a zone names, by name or number
+fn scope_id(zone: &str) -> std::io::Result<u32> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+    interface_index(zone)
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> std::io::Result<u32> {
+    let name = std::ffi::CString::new(name).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid interface name"))?;
+    // SAFETY: the name is a valid NUL-terminated string for the duration of the call
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(Error::new(ErrorKind::NotFound, format!("No interface named {}", name.to_string_lossy()))),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> std::io::Result<u32> {
+    Err(Error::new(ErrorKind::Unsupported, format!("Interface {} must be given by number on this platform", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scoped_addresses() {
+        assert_eq!(normalize(" [fe80::1%25mgmt0] "), "fe80::1%mgmt0");
+        assert_eq!(normalize("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(normalize("core-1.example.com"), "core-1.example.com");
+
+        assert_eq!(scoped_ipv6("fe80::1%mgmt0"), Some(("fe80::1".parse().unwrap(), "mgmt0")));
+        assert_eq!(ip("fe80::1%mgmt0"), Some("fe80::1".parse().unwrap()));
+        assert!(validate("fe80::1%mgmt0").is_ok());
+        assert!(validate("fe80::1%2").is_ok());
+        assert!(validate("2001:db8::1%mgmt0").is_err());
+        assert!(validate("10.0.0.1%mgmt0").is_err());
+        assert!(validate("fe80::1%").is_err());
+
+        let address = resolve("fe80::1%1", 22).unwrap();
+        assert_eq!(address, vec!["[fe80::1%1]:22".parse().unwrap()]);
+        assert_eq!(resolve("fe80::1%lo", 22).is_ok(), cfg!(target_os = "linux"));
+        assert!(resolve("fe80::1%no-such-interface0", 22).is_err());
+    }
+}
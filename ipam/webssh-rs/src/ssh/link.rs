@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::settings::ConnectionSettings;
+
+/// Timeouts relaxed for a link measured slow, recorded for session metadata
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowLinkAdaptation {
+    /// TCP connect time the link was judged on
+    pub rtt_ms: u64,
+    /// SSH handshake time the link was judged on; telnet has none
+    pub handshake_ms: Option<u64>,
+    pub read_timeout_seconds: u64,
+    pub write_timeout_seconds: u64,
+    pub timeout_seconds: u64,
+    pub channel_timeout_seconds: u64,
+    pub keepalive_seconds: u64,
+}
+
+impl SlowLinkAdaptation {
+    /// Works out the relaxed timeouts for a link, or `None` if it is not slow
+    ///
+    /// # Arguments
+    /// * `connection` - The configured timeouts and slow-link thresholds
+    /// * `rtt` - The TCP connect time
+    /// * `handshake` - The SSH handshake time, once it is known
+    pub fn assess(connection: &ConnectionSettings, rtt: Duration, handshake: Option<Duration>) -> Option<Self> {
+        let slow_link = &connection.slow_link;
+        let rtt_ms = rtt.as_millis() as u64;
+        let handshake_ms = handshake.map(|handshake| handshake.as_millis() as u64);
+        let slow = rtt_ms >= slow_link.rtt_threshold_ms
+            || handshake_ms.is_some_and(|handshake_ms| handshake_ms >= slow_link.handshake_threshold_ms);
+        if !slow_link.enabled || !slow {
+            return None;
+        }
+
+        let relax = |seconds: u64, bound: u64| seconds.saturating_mul(slow_link.factor.max(1)).min(bound).max(seconds);
+        Some(Self {
+            rtt_ms,
+            handshake_ms,
+            read_timeout_seconds: relax(connection.read_timeout_seconds, slow_link.max_timeout_seconds),
+            write_timeout_seconds: relax(connection.write_timeout_seconds, slow_link.max_timeout_seconds),
+            timeout_seconds: relax(connection.timeout_seconds, slow_link.max_timeout_seconds),
+            channel_timeout_seconds: relax(connection.channel_timeout_seconds, slow_link.max_timeout_seconds),
+            keepalive_seconds: relax(connection.keepalive_seconds, slow_link.max_keepalive_seconds),
+        })
+    }
+
+    /// Puts the relaxed timeouts in place of the configured ones
+    pub fn apply(&self, connection: &mut ConnectionSettings) {
+        connection.read_timeout_seconds = self.read_timeout_seconds;
+        connection.write_timeout_seconds = self.write_timeout_seconds;
+        connection.timeout_seconds = self.timeout_seconds;
+        connection.channel_timeout_seconds = self.channel_timeout_seconds;
+        connection.keepalive_seconds = self.keepalive_seconds;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_assess_relaxes_within_bounds() {
+        let connection = Settings::default().ssh.connection;
+        assert_eq!(SlowLinkAdaptation::assess(&connection, Duration::from_millis(20), Some(Duration::from_millis(300))), None);
+
+        let adaptation = SlowLinkAdaptation::assess(&connection, Duration::from_millis(650), None).unwrap();
+        assert_eq!((adaptation.rtt_ms, adaptation.handshake_ms), (650, None));
+        assert_eq!((adaptation.read_timeout_seconds, adaptation.timeout_seconds), (90, 180));
+        // 120 s channel timeout and 30 s keepalive tripled, then held to the bounds
+        assert_eq!((adaptation.channel_timeout_seconds, adaptation.keepalive_seconds), (300, 90));
+
+        // A fast RTT but a slow key exchange: little throughput
+        assert!(SlowLinkAdaptation::assess(&connection, Duration::from_millis(20), Some(Duration::from_secs(5))).is_some());
+
+        let mut relaxed = connection.clone();
+        relaxed.channel_timeout_seconds = 600;
+        assert_eq!(SlowLinkAdaptation::assess(&relaxed, Duration::from_secs(1), None).unwrap().channel_timeout_seconds, 600);
+
+        let mut disabled = connection;
+        disabled.slow_link.enabled = false;
+        assert_eq!(SlowLinkAdaptation::assess(&disabled, Duration::from_secs(1), None), None);
+    }
+}
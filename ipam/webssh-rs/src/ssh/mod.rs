@@ -1,7 +1,30 @@
 // Re-export the main components for use by other modules
+pub mod agent;
+pub mod pkcs11;
+pub mod gssapi;
+pub mod backend;
 pub mod error;
 pub mod channel;
 pub mod session;
+pub mod target;
+pub mod tunnel;
+pub mod forward;
+pub mod heartbeat;
+pub mod detect;
+pub mod keys;
+pub mod link;
+pub mod pool;
+#[cfg(feature = "reactor-io")]
+pub mod reactor;
+pub mod quirks;
+pub mod rekey;
+pub mod telnet;
+pub mod source;
+pub mod host;
+pub mod socks;
 
 // Re-export the SSHSession for use by other modules
-pub use session::SSHSession;
+pub use backend::{Shell, ShellBackend};
+pub use pool::{ChannelKind, ChannelLease, ChannelOwner, SharedConnection};
+pub use session::{SSHSession, SessionHandle};
+pub use target::{ConnectionInfo, ConnectionTarget, JumpHost, Protocol, PtyRequest, TerminalSize};
@@ -0,0 +1,669 @@
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_uchar, c_ulong, c_void, CString};
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::ptr;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use tracing::{debug, info, warn};
+
+use crate::settings::Pkcs11Settings;
+use super::agent::fingerprint;
+use super::error::SSHError;
+
+/// A key pair in an HSM or smartcard, as named by a connect request's `pkcs11` field
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pkcs11Key {
+    /// One of `ssh.pkcs11.modules`; may be left out when only one is configured
+    pub module: Option<String>,
+    /// Label of the token; the first token present when left out
+    pub token: Option<String>,
+    /// Label of the key pair
+    pub label: Option<String>,
+    /// `CKA_ID` of the key pair, in hex, in place of the label
+    pub id: Option<String>,
+}
+
+impl fmt::Display for Pkcs11Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.label, &self.id) {
+            (Some(label), _) => write!(f, "key '{}'", label)?,
+            (None, Some(id)) => write!(f, "key {}", id)?,
+            (None, None) => f.write_str("key")?,
+        }
+        if let Some(token) = &self.token {
+            write!(f, " on token '{}'", token)?;
+        }
+        Ok(())
+    }
+}
+
+/// A key in a token and the PIN to log in to the token with, once the user has given it
+#[derive(Clone, PartialEq, Eq)]
+pub struct Pkcs11Login {
+    pub key: Pkcs11Key,
+    pub pin: Option<String>,
+}
+
+impl fmt::Debug for Pkcs11Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Login")
+            .field("key", &self.key)
+            .field("pin", &self.pin.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Authenticates with a key that never leaves its HSM or smartcard
+///
+/// The module must be one of `ssh.pkcs11.modules`; it is loaded once and kept.
+/// The token is logged in to with the user's PIN for this authentication
+/// only. Authentications through one module take turns, so a token is never
+/// left logged in for one whose PIN was not checked.
+pub fn userauth(session: &Session, username: &str, login: &Pkcs11Login, settings: &Pkcs11Settings) -> Result<(), SSHError> {
+    if !settings.enabled {
+        return Err(SSHError::Pkcs11("PKCS#11 authentication is not enabled on this instance".into()));
+    }
+    let path = module_path(&login.key, settings)?;
+    let pin = login.pin.as_deref().filter(|pin| !pin.is_empty())
+        .ok_or_else(|| SSHError::Authentication("The token's PIN is required".into()))?;
+    let module = Module::load(path)?;
+    let _turn = module.turn.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let slot = module.find_slot(login.key.token.as_deref())?;
+    let mut token = TokenSession::open(&module, slot)?;
+    token.login(pin)?;
+    let key = token.find_key(&login.key)?;
+    info!("Authenticating {} with {} {} from PKCS#11 {}", username, key.kind.algorithm(), fingerprint(&key.public_blob), login.key);
+
+    let signer = Signer { token: &token, key: &key, error: Mutex::new(None) };
+    let user = CString::new(username).map_err(|_| SSHError::Authentication("Invalid username".into()))?;
+    let rc = {
+        let mut raw = session.raw();
+        let mut context = &signer as *const Signer as *mut c_void;
+        // SAFETY: the session is locked for the call; the callback only reads the signer, which outlives it
+        unsafe {
+            libssh2_userauth_publickey(&mut *raw as *mut _ as *mut c_void, user.as_ptr(),
+                                       key.public_blob.as_ptr(), key.public_blob.len(), sign_callback, &mut context)
+        }
+    };
+    if rc == 0 && session.authenticated() {
+        return Ok(());
+    }
+    let reason = match signer.error.into_inner().unwrap_or_else(PoisonError::into_inner) {
+        Some(e) => e,
+        None => ssh2::Error::last_session_error(session).map(|e| e.to_string()).unwrap_or_else(|| format!("error {}", rc)),
+    };
+    Err(SSHError::Authentication(format!("PKCS#11 key authentication failed: {}", reason)))
+}
+
+/// The path of the module a key names, if it is allowed
+fn module_path<'s>(key: &Pkcs11Key, settings: &'s Pkcs11Settings) -> Result<&'s str, SSHError> {
+    let name = match &key.module {
+        Some(name) => name.as_str(),
+        None if settings.modules.len() == 1 => settings.modules.keys().next().map(String::as_str).unwrap_or_default(),
+        None => return Err(SSHError::Pkcs11("name the PKCS#11 module in pkcs11.module".into())),
+    };
+    settings.modules.get(name).map(String::as_str)
+        .ok_or_else(|| SSHError::Pkcs11(format!("'{}' is not one of the allowed PKCS#11 modules", name)))
+}
+
+type CkRv = c_ulong;
+
+const CKR_OK: CkRv = 0;
+const CKR_PIN_INCORRECT: CkRv = 0xa0;
+const CKR_PIN_LOCKED: CkRv = 0xa4;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_OS_LOCKING_OK: c_ulong = 1 << 1;
+const CKF_SERIAL_SESSION: c_ulong = 1 << 2;
+const CKU_USER: c_ulong = 1;
+const CKO_PUBLIC_KEY: c_ulong = 2;
+const CKO_PRIVATE_KEY: c_ulong = 3;
+const CKK_RSA: c_ulong = 0;
+const CKK_EC: c_ulong = 3;
+const CKA_CLASS: c_ulong = 0;
+const CKA_LABEL: c_ulong = 3;
+const CKA_KEY_TYPE: c_ulong = 0x100;
+const CKA_ID: c_ulong = 0x102;
+const CKA_MODULUS: c_ulong = 0x120;
+const CKA_PUBLIC_EXPONENT: c_ulong = 0x122;
+const CKA_EC_PARAMS: c_ulong = 0x180;
+const CKA_EC_POINT: c_ulong = 0x181;
+const CKM_RSA_PKCS: c_ulong = 1;
+const CKM_ECDSA: c_ulong = 0x1041;
+
+#[repr(C)]
+struct CkVersion {
+    major: u8,
+    minor: u8,
+}
+
+#[repr(C)]
+struct CkAttribute {
+    kind: c_ulong,
+    value: *mut c_void,
+    len: c_ulong,
+}
+
+#[repr(C)]
+struct CkMechanism {
+    mechanism: c_ulong,
+    parameter: *mut c_void,
+    len: c_ulong,
+}
+
+#[repr(C)]
+struct CkInitializeArgs {
+    mutex_functions: [*mut c_void; 4],
+    flags: c_ulong,
+    reserved: *mut c_void,
+}
+
+#[repr(C)]
+struct CkTokenInfo {
+    label: [u8; 32],
+    manufacturer_id: [u8; 32],
+    model: [u8; 16],
+    serial_number: [u8; 16],
+    flags: c_ulong,
+    counts: [c_ulong; 10],
+    hardware_version: CkVersion,
+    firmware_version: CkVersion,
+    utc_time: [u8; 16],
+}
+
+/// The start of `CK_FUNCTION_LIST`, up to `C_Sign`; unused entries are left opaque
+#[repr(C)]
+struct FunctionList {
+    version: CkVersion,
+    initialize: unsafe extern "C" fn(*mut CkInitializeArgs) -> CkRv,
+    _finalize_to_get_function_list: [*const c_void; 3],
+    get_slot_list: unsafe extern "C" fn(u8, *mut c_ulong, *mut c_ulong) -> CkRv,
+    _get_slot_info: *const c_void,
+    get_token_info: unsafe extern "C" fn(c_ulong, *mut CkTokenInfo) -> CkRv,
+    _get_mechanism_list_to_set_pin: [*const c_void; 5],
+    open_session: unsafe extern "C" fn(c_ulong, c_ulong, *mut c_void, *mut c_void, *mut c_ulong) -> CkRv,
+    close_session: unsafe extern "C" fn(c_ulong) -> CkRv,
+    _close_all_sessions_to_set_operation_state: [*const c_void; 4],
+    login: unsafe extern "C" fn(c_ulong, c_ulong, *const u8, c_ulong) -> CkRv,
+    logout: unsafe extern "C" fn(c_ulong) -> CkRv,
+    _create_object_to_get_object_size: [*const c_void; 4],
+    get_attribute_value: unsafe extern "C" fn(c_ulong, c_ulong, *mut CkAttribute, c_ulong) -> CkRv,
+    _set_attribute_value: *const c_void,
+    find_objects_init: unsafe extern "C" fn(c_ulong, *mut CkAttribute, c_ulong) -> CkRv,
+    find_objects: unsafe extern "C" fn(c_ulong, *mut c_ulong, c_ulong, *mut c_ulong) -> CkRv,
+    find_objects_final: unsafe extern "C" fn(c_ulong) -> CkRv,
+    _encrypt_init_to_digest_final: [*const c_void; 13],
+    sign_init: unsafe extern "C" fn(c_ulong, *mut CkMechanism, c_ulong) -> CkRv,
+    sign: unsafe extern "C" fn(c_ulong, *const u8, c_ulong, *mut u8, *mut c_ulong) -> CkRv,
+}
+
+type SignCallback = unsafe extern "C" fn(*mut c_void, *mut *mut c_uchar, *mut usize, *const c_uchar, usize, *mut *mut c_void) -> c_int;
+
+extern "C" {
+    // In the libssh2 that libssh2-sys builds, but not declared by it
+    fn libssh2_userauth_publickey(session: *mut c_void, username: *const c_char, pubkeydata: *const c_uchar,
+                                  pubkeydata_len: usize, sign_callback: SignCallback, abstract_: *mut *mut c_void) -> c_int;
+}
+
+fn check(rv: CkRv, what: &str) -> Result<(), SSHError> {
+    match rv {
+        CKR_OK => Ok(()),
+        rv => Err(SSHError::Pkcs11(format!("{} failed (CKR 0x{:x})", what, rv))),
+    }
+}
+
+/// A loaded PKCS#11 module
+struct Module {
+    functions: &'static FunctionList,
+    // Held for an authentication from opening a session to logging out
+    turn: Mutex<()>,
+}
+
+// SAFETY: the module is initialized with CKF_OS_LOCKING_OK, so it may be called from any thread
+unsafe impl Send for Module {}
+unsafe impl Sync for Module {}
+
+/// Modules loaded, by path; never unloaded, as they may keep threads and state of their own
+static MODULES: OnceLock<Mutex<HashMap<String, Arc<Module>>>> = OnceLock::new();
+
+impl Module {
+    fn load(path: &str) -> Result<Arc<Module>, SSHError> {
+        let mut modules = MODULES.get_or_init(Mutex::default).lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(module) = modules.get(path) {
+            return Ok(module.clone());
+        }
+
+        let c_path = CString::new(path).map_err(|_| SSHError::Pkcs11(format!("invalid module path {}", path)))?;
+        // SAFETY: dlopen and dlsym are given NUL-terminated strings; C_GetFunctionList has the signature PKCS#11 defines
+        let functions = unsafe {
+            let library = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if library.is_null() {
+                return Err(SSHError::Pkcs11(format!("cannot load {}", path)));
+            }
+            let symbol = libc::dlsym(library, c"C_GetFunctionList".as_ptr());
+            if symbol.is_null() {
+                return Err(SSHError::Pkcs11(format!("{} is not a PKCS#11 module", path)));
+            }
+            let get_function_list: unsafe extern "C" fn(*mut *const FunctionList) -> CkRv = std::mem::transmute(symbol);
+            let mut functions: *const FunctionList = ptr::null();
+            check(get_function_list(&mut functions), "C_GetFunctionList")?;
+            if functions.is_null() {
+                return Err(SSHError::Pkcs11(format!("{} has no function list", path)));
+            }
+            &*functions
+        };
+        let mut args = CkInitializeArgs { mutex_functions: [ptr::null_mut(); 4], flags: CKF_OS_LOCKING_OK, reserved: ptr::null_mut() };
+        // SAFETY: the arguments are valid for the call
+        match unsafe { (functions.initialize)(&mut args) } {
+            CKR_OK | CKR_CRYPTOKI_ALREADY_INITIALIZED => {}
+            rv => check(rv, "C_Initialize")?,
+        }
+        info!("Loaded PKCS#11 module {} (Cryptoki {}.{})", path, functions.version.major, functions.version.minor);
+
+        let module = Arc::new(Module { functions, turn: Mutex::new(()) });
+        modules.insert(path.to_string(), module.clone());
+        Ok(module)
+    }
+
+    /// The slot of the token with the label, or of the first token present
+    fn find_slot(&self, label: Option<&str>) -> Result<c_ulong, SSHError> {
+        let mut count: c_ulong = 0;
+        // SAFETY: a null list asks for the count only
+        check(unsafe { (self.functions.get_slot_list)(1, ptr::null_mut(), &mut count) }, "C_GetSlotList")?;
+        let mut slots = vec![0; count as usize];
+        // SAFETY: the list has room for `count` slots
+        check(unsafe { (self.functions.get_slot_list)(1, slots.as_mut_ptr(), &mut count) }, "C_GetSlotList")?;
+        slots.truncate(count as usize);
+
+        let Some(label) = label else {
+            return slots.first().copied().ok_or_else(|| SSHError::Pkcs11("no token is present".into()));
+        };
+        for slot in slots {
+            // SAFETY: CK_TOKEN_INFO is plain data, and zeroes are a valid value of it
+            let mut info: CkTokenInfo = unsafe { std::mem::zeroed() };
+            // SAFETY: the token info is written in place
+            if unsafe { (self.functions.get_token_info)(slot, &mut info) } == CKR_OK && padded(&info.label) == label {
+                return Ok(slot);
+            }
+        }
+        Err(SSHError::Pkcs11(format!("no token labelled '{}' is present", label)))
+    }
+}
+
+/// A blank-padded PKCS#11 string
+fn padded(field: &[u8]) -> &str {
+    std::str::from_utf8(field).unwrap_or_default().trim_end_matches([' ', '\0'])
+}
+
+/// A session with a token, logged out and closed when dropped
+struct TokenSession<'m> {
+    module: &'m Module,
+    handle: c_ulong,
+    logged_in: bool,
+}
+
+/// The type of a key pair
+enum KeyKind {
+    Rsa,
+    /// The curve's SSH name, e.g. `nistp256`
+    Ecdsa(&'static str),
+}
+
+impl KeyKind {
+    fn algorithm(&self) -> &'static str {
+        match self {
+            KeyKind::Rsa => "ssh-rsa",
+            KeyKind::Ecdsa("nistp256") => "ecdsa-sha2-nistp256",
+            KeyKind::Ecdsa("nistp384") => "ecdsa-sha2-nistp384",
+            KeyKind::Ecdsa(_) => "ecdsa-sha2-nistp521",
+        }
+    }
+}
+
+/// A private key found in a token, with its public key in SSH wire format
+struct TokenKey {
+    handle: c_ulong,
+    kind: KeyKind,
+    public_blob: Vec<u8>,
+}
+
+impl<'m> TokenSession<'m> {
+    fn open(module: &'m Module, slot: c_ulong) -> Result<Self, SSHError> {
+        let mut handle = 0;
+        // SAFETY: no notification callback is registered
+        check(unsafe { (module.functions.open_session)(slot, CKF_SERIAL_SESSION, ptr::null_mut(), ptr::null_mut(), &mut handle) },
+              "C_OpenSession")?;
+        Ok(Self { module, handle, logged_in: false })
+    }
+
+    fn login(&mut self, pin: &str) -> Result<(), SSHError> {
+        let functions = self.module.functions;
+        // SAFETY: the PIN is passed with its length
+        let mut rv = unsafe { (functions.login)(self.handle, CKU_USER, pin.as_ptr(), pin.len() as c_ulong) };
+        if rv == CKR_USER_ALREADY_LOGGED_IN {
+            // Left logged in by another application; log in again so the PIN is checked
+            warn!("PKCS#11 token was already logged in; logging in again");
+            // SAFETY: the session is open
+            unsafe {
+                (functions.logout)(self.handle);
+                rv = (functions.login)(self.handle, CKU_USER, pin.as_ptr(), pin.len() as c_ulong);
+            }
+        }
+        match rv {
+            CKR_OK => {
+                self.logged_in = true;
+                Ok(())
+            }
+            CKR_PIN_INCORRECT => Err(SSHError::Authentication("The token refused the PIN".into())),
+            CKR_PIN_LOCKED => Err(SSHError::Authentication("The token's PIN is locked".into())),
+            rv => check(rv, "C_Login"),
+        }
+    }
+
+    /// Finds the private key by label or ID, and its public key
+    fn find_key(&self, key: &Pkcs11Key) -> Result<TokenKey, SSHError> {
+        let id = match &key.id {
+            Some(id) => Some(decode_hex(id).ok_or_else(|| SSHError::Pkcs11(format!("key ID '{}' is not hex", id)))?),
+            None if key.label.is_none() => return Err(SSHError::Pkcs11("name the key by pkcs11.label or pkcs11.id".into())),
+            None => None,
+        };
+        let select = |class: c_ulong| {
+            let mut template = vec![(CKA_CLASS, class.to_ne_bytes().to_vec())];
+            match (&id, &key.label) {
+                (Some(id), _) => template.push((CKA_ID, id.clone())),
+                (None, Some(label)) => template.push((CKA_LABEL, label.as_bytes().to_vec())),
+                (None, None) => {}
+            }
+            template
+        };
+
+        let private = self.find_object(select(CKO_PRIVATE_KEY))?
+            .ok_or_else(|| SSHError::Pkcs11(format!("no private {} in the token", key)))?;
+        let key_type = self.attribute(private, CKA_KEY_TYPE)?;
+        let public = self.find_object(select(CKO_PUBLIC_KEY))?;
+        // The public parts are read from the public key object, or failing one from the private key
+        let source = public.unwrap_or(private);
+
+        match c_ulong::from_ne_bytes(key_type.as_slice().try_into().unwrap_or_default()) {
+            CKK_RSA => {
+                let exponent = self.attribute(source, CKA_PUBLIC_EXPONENT)?;
+                let modulus = self.attribute(source, CKA_MODULUS)?;
+                let mut blob = Vec::new();
+                put_string(&mut blob, b"ssh-rsa");
+                put_mpint(&mut blob, &exponent);
+                put_mpint(&mut blob, &modulus);
+                Ok(TokenKey { handle: private, kind: KeyKind::Rsa, public_blob: blob })
+            }
+            CKK_EC => {
+                let curve = curve_name(&self.attribute(source, CKA_EC_PARAMS)?)
+                    .ok_or_else(|| SSHError::Pkcs11("the key's curve is not one of P-256, P-384 or P-521".into()))?;
+                let point = public.ok_or_else(|| SSHError::Pkcs11(format!("no public {} in the token", key)))
+                    .and_then(|public| self.attribute(public, CKA_EC_POINT))?;
+                let kind = KeyKind::Ecdsa(curve);
+                let mut blob = Vec::new();
+                put_string(&mut blob, kind.algorithm().as_bytes());
+                put_string(&mut blob, curve.as_bytes());
+                put_string(&mut blob, unwrap_octet_string(&point));
+                Ok(TokenKey { handle: private, kind, public_blob: blob })
+            }
+            _ => Err(SSHError::Pkcs11("only RSA and ECDSA keys are supported".into())),
+        }
+    }
+
+    fn find_object(&self, template: Vec<(c_ulong, Vec<u8>)>) -> Result<Option<c_ulong>, SSHError> {
+        let mut values = template;
+        let mut attributes: Vec<CkAttribute> = values.iter_mut()
+            .map(|(kind, value)| CkAttribute { kind: *kind, value: value.as_mut_ptr() as *mut c_void, len: value.len() as c_ulong })
+            .collect();
+        let functions = self.module.functions;
+        let (mut object, mut found) = (0, 0);
+        // SAFETY: the template's values outlive the search
+        unsafe {
+            check((functions.find_objects_init)(self.handle, attributes.as_mut_ptr(), attributes.len() as c_ulong), "C_FindObjectsInit")?;
+            let rv = (functions.find_objects)(self.handle, &mut object, 1, &mut found);
+            (functions.find_objects_final)(self.handle);
+            check(rv, "C_FindObjects")?;
+        }
+        Ok((found > 0).then_some(object))
+    }
+
+    fn attribute(&self, object: c_ulong, kind: c_ulong) -> Result<Vec<u8>, SSHError> {
+        let functions = self.module.functions;
+        let mut attribute = CkAttribute { kind, value: ptr::null_mut(), len: 0 };
+        // SAFETY: a null value asks for the length only, then the value is written into a buffer of that length
+        unsafe {
+            check((functions.get_attribute_value)(self.handle, object, &mut attribute, 1), "C_GetAttributeValue")?;
+            let mut value = vec![0u8; attribute.len as usize];
+            attribute.value = value.as_mut_ptr() as *mut c_void;
+            check((functions.get_attribute_value)(self.handle, object, &mut attribute, 1), "C_GetAttributeValue")?;
+            value.truncate(attribute.len as usize);
+            Ok(value)
+        }
+    }
+
+    fn sign(&self, key: &TokenKey, mechanism: c_ulong, input: &[u8]) -> Result<Vec<u8>, SSHError> {
+        let functions = self.module.functions;
+        let mut mechanism = CkMechanism { mechanism, parameter: ptr::null_mut(), len: 0 };
+        let mut len: c_ulong = 0;
+        // SAFETY: a null output asks for the signature's length, then it is written into a buffer of that length
+        unsafe {
+            check((functions.sign_init)(self.handle, &mut mechanism, key.handle), "C_SignInit")?;
+            check((functions.sign)(self.handle, input.as_ptr(), input.len() as c_ulong, ptr::null_mut(), &mut len), "C_Sign")?;
+            let mut signature = vec![0u8; len as usize];
+            check((functions.sign)(self.handle, input.as_ptr(), input.len() as c_ulong, signature.as_mut_ptr(), &mut len), "C_Sign")?;
+            signature.truncate(len as usize);
+            Ok(signature)
+        }
+    }
+}
+
+impl Drop for TokenSession<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the session is open until here
+        unsafe {
+            if self.logged_in {
+                (self.module.functions.logout)(self.handle);
+            }
+            (self.module.functions.close_session)(self.handle);
+        }
+    }
+}
+
+/// Signs the authentication request for libssh2 with the token's key
+struct Signer<'a> {
+    token: &'a TokenSession<'a>,
+    key: &'a TokenKey,
+    // Why signing failed, for the error returned
+    error: Mutex<Option<String>>,
+}
+
+impl Signer<'_> {
+    /// The signature blob of the request, without the algorithm name libssh2 puts before it
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, SSHError> {
+        let algorithm = signed_algorithm(data)
+            .ok_or_else(|| SSHError::Pkcs11("malformed authentication request".into()))?;
+        debug!("Signing the authentication request with {} in the token", algorithm);
+        match self.key.kind {
+            KeyKind::Rsa => {
+                let (hash, prefix) = rsa_digest_info(algorithm)
+                    .ok_or_else(|| SSHError::Pkcs11(format!("cannot sign {} with an RSA key", algorithm)))?;
+                let mut digest_info = prefix.to_vec();
+                digest_info.extend_from_slice(digest::digest(hash, data).as_ref());
+                self.token.sign(self.key, CKM_RSA_PKCS, &digest_info)
+            }
+            KeyKind::Ecdsa(curve) => {
+                let hash = match curve {
+                    "nistp256" => &digest::SHA256,
+                    "nistp384" => &digest::SHA384,
+                    _ => &digest::SHA512,
+                };
+                let signature = self.token.sign(self.key, CKM_ECDSA, digest::digest(hash, data).as_ref())?;
+                // The token gives r and s as halves of equal length; SSH wants them as mpints
+                let (r, s) = signature.split_at(signature.len() / 2);
+                let mut blob = Vec::new();
+                put_mpint(&mut blob, r);
+                put_mpint(&mut blob, s);
+                Ok(blob)
+            }
+        }
+    }
+}
+
+/// Called by libssh2 with the data to sign; `abstract_` points at the `Signer`
+unsafe extern "C" fn sign_callback(_session: *mut c_void, sig: *mut *mut c_uchar, sig_len: *mut usize,
+                                   data: *const c_uchar, data_len: usize, abstract_: *mut *mut c_void) -> c_int {
+    let signer = &*(*abstract_ as *const Signer);
+    let data = std::slice::from_raw_parts(data, data_len);
+    let signature = match std::panic::catch_unwind(AssertUnwindSafe(|| signer.sign(data))) {
+        Ok(Ok(signature)) => signature,
+        Ok(Err(e)) => {
+            *signer.error.lock().unwrap_or_else(PoisonError::into_inner) = Some(e.to_string());
+            return -1;
+        }
+        Err(_) => return -1,
+    };
+    // libssh2 frees the signature with its allocator, which is malloc's
+    let buffer = libc::malloc(signature.len()) as *mut c_uchar;
+    if buffer.is_null() {
+        return -1;
+    }
+    ptr::copy_nonoverlapping(signature.as_ptr(), buffer, signature.len());
+    *sig = buffer;
+    *sig_len = signature.len();
+    0
+}
+
+/// The public key algorithm named in the data of a `publickey` authentication request
+fn signed_algorithm(data: &[u8]) -> Option<&str> {
+    // Session ID, SSH_MSG_USERAUTH_REQUEST, user name, service, "publickey", TRUE, algorithm
+    let (_session_id, rest) = take_string(data)?;
+    let rest = rest.strip_prefix(&[50])?;
+    let (_user, rest) = take_string(rest)?;
+    let (_service, rest) = take_string(rest)?;
+    let (method, rest) = take_string(rest)?;
+    if method != b"publickey" {
+        return None;
+    }
+    let (algorithm, _) = take_string(rest.strip_prefix(&[1])?)?;
+    std::str::from_utf8(algorithm).ok()
+}
+
+/// Splits an SSH string off the front of the data
+fn take_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let value = data.get(4..4usize.checked_add(len)?)?;
+    Some((value, &data[4 + len..]))
+}
+
+/// The hash and DER `DigestInfo` prefix of an RSA signature algorithm
+fn rsa_digest_info(algorithm: &str) -> Option<(&'static digest::Algorithm, &'static [u8])> {
+    match algorithm {
+        "rsa-sha2-256" => Some((&digest::SHA256, &[0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20])),
+        "rsa-sha2-512" => Some((&digest::SHA512, &[0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05, 0x00, 0x04, 0x40])),
+        "ssh-rsa" => Some((&digest::SHA1_FOR_LEGACY_USE_ONLY, &[0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04, 0x14])),
+        _ => None,
+    }
+}
+
+/// The SSH name of the curve in DER-encoded `CKA_EC_PARAMS`
+fn curve_name(params: &[u8]) -> Option<&'static str> {
+    match params {
+        [0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07] => Some("nistp256"),
+        [0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22] => Some("nistp384"),
+        [0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x23] => Some("nistp521"),
+        _ => None,
+    }
+}
+
+/// The point of `CKA_EC_POINT`, which most tokens wrap in a DER OCTET STRING
+fn unwrap_octet_string(value: &[u8]) -> &[u8] {
+    let (len, header) = match value {
+        [0x04, len, ..] if *len < 0x80 => (*len as usize, 2),
+        [0x04, 0x81, len, ..] => (*len as usize, 3),
+        _ => return value,
+    };
+    if value.len() == header + len { &value[header..] } else { value }
+}
+
+fn put_string(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Writes an unsigned big-endian integer as an SSH mpint
+fn put_mpint(out: &mut Vec<u8>, value: &[u8]) {
+    let start = value.iter().position(|byte| *byte != 0).unwrap_or(value.len());
+    let value = &value[start..];
+    if value.first().is_some_and(|byte| byte & 0x80 != 0) {
+        out.extend_from_slice(&(value.len() as u32 + 1).to_be_bytes());
+        out.push(0);
+        out.extend_from_slice(value);
+    } else {
+        put_string(out, value);
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x").replace(':', "");
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &[u8], algorithm: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_string(&mut data, &[7; 32]);
+        data.push(50);
+        put_string(&mut data, b"admin");
+        put_string(&mut data, b"ssh-connection");
+        put_string(&mut data, method);
+        data.push(1);
+        put_string(&mut data, algorithm);
+        put_string(&mut data, b"key blob");
+        data
+    }
+
+    #[test]
+    fn test_signed_algorithm() {
+        assert_eq!(signed_algorithm(&request(b"publickey", b"rsa-sha2-256")), Some("rsa-sha2-256"));
+        assert_eq!(signed_algorithm(&request(b"password", b"rsa-sha2-256")), None);
+        assert_eq!(signed_algorithm(&[0, 0, 0, 0xff, 1]), None);
+    }
+
+    #[test]
+    fn test_mpint_and_point_encoding() {
+        let mut out = Vec::new();
+        put_mpint(&mut out, &[0, 0, 0x01, 0x00, 0x01]);
+        put_mpint(&mut out, &[0x80, 0x01]);
+        assert_eq!(out, [0, 0, 0, 3, 1, 0, 1, 0, 0, 0, 3, 0, 0x80, 1]);
+
+        assert_eq!(unwrap_octet_string(&[0x04, 0x03, 0x04, 0xaa, 0xbb]), &[0x04, 0xaa, 0xbb]);
+        assert_eq!(unwrap_octet_string(&[0x04, 0xaa, 0xbb]), &[0x04, 0xaa, 0xbb]);
+        assert_eq!(curve_name(&[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22]), Some("nistp384"));
+    }
+
+    #[test]
+    fn test_module_and_key_selection() {
+        let mut settings = Pkcs11Settings { enabled: true, ..Pkcs11Settings::default() };
+        settings.modules.insert("yubihsm".into(), "/usr/lib/yubihsm_pkcs11.so".into());
+        assert_eq!(module_path(&Pkcs11Key::default(), &settings).unwrap(), "/usr/lib/yubihsm_pkcs11.so");
+
+        settings.modules.insert("softhsm".into(), "/usr/lib/softhsm/libsofthsm2.so".into());
+        assert!(module_path(&Pkcs11Key::default(), &settings).is_err());
+        let key = Pkcs11Key { module: Some("other".into()), ..Pkcs11Key::default() };
+        assert!(module_path(&key, &settings).is_err());
+
+        assert_eq!(decode_hex("01:ab"), Some(vec![0x01, 0xab]));
+        assert_eq!(decode_hex("abc"), None);
+    }
+}
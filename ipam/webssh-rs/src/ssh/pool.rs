@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+use super::rekey::KeyExchangeLog;
+
+/// What a channel on a shared connection is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    /// The interactive shell the connection was opened for
+    Shell,
+    /// A command run through the exec API
+    Exec,
+    /// A file transfer through the SFTP API, over SFTP or SCP
+    Sftp,
+}
+
+/// A channel open on a shared connection and who it belongs to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelOwner {
+    pub id: u64,
+    pub kind: ChannelKind,
+    /// The portal user the channel was opened for
+    pub owner: String,
+    pub opened_at: DateTime<Utc>,
+}
+
+/// An SSH connection that other users may open channels on
+///
+/// The connection is opened for an interactive shell and lives as long as
+/// that shell does; exec requests to the same device with the same login
+/// borrow it rather than taking another vty line.
+#[derive(Clone)]
+pub struct SharedConnection {
+    session: Session,
+    // Cancelled when the shell the connection was opened for shuts down
+    closed: CancellationToken,
+    channels: Arc<Mutex<Vec<ChannelOwner>>>,
+    next_id: Arc<AtomicU64>,
+    // Key re-exchanges the shell's I/O loop has seen
+    key_exchanges: KeyExchangeLog,
+}
+
+impl SharedConnection {
+    pub(super) fn new(session: Session, closed: CancellationToken) -> Self {
+        Self {
+            session,
+            closed,
+            channels: Arc::new(Mutex::new(Vec::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            key_exchanges: KeyExchangeLog::default(),
+        }
+    }
+
+    /// Gets the connection, always in non-blocking mode
+    ///
+    /// The shell's I/O is pumped over the same connection, so its blocking
+    /// mode and timeout must not be changed.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Gets the log of the connection's key re-exchanges
+    pub fn key_exchanges(&self) -> &KeyExchangeLog {
+        &self.key_exchanges
+    }
+
+    /// Whether the shell the connection was opened for is still running
+    pub fn is_open(&self) -> bool {
+        !self.closed.is_cancelled()
+    }
+
+    /// Records a channel opened on the connection until the lease is dropped
+    pub fn lease(&self, kind: ChannelKind, owner: &str) -> ChannelLease {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut channels) = self.channels.lock() {
+            channels.push(ChannelOwner {
+                id,
+                kind,
+                owner: owner.to_string(),
+                opened_at: Utc::now(),
+            });
+        }
+        ChannelLease {
+            channels: self.channels.clone(),
+            id,
+        }
+    }
+
+    /// Gets the channels open on the connection, oldest first
+    pub fn channels(&self) -> Vec<ChannelOwner> {
+        self.channels.lock().map(|channels| channels.clone()).unwrap_or_default()
+    }
+}
+
+/// A channel's place in its connection's accounting, released on drop
+pub struct ChannelLease {
+    channels: Arc<Mutex<Vec<ChannelOwner>>>,
+    id: u64,
+}
+
+impl Drop for ChannelLease {
+    fn drop(&mut self) {
+        if let Ok(mut channels) = self.channels.lock() {
+            channels.retain(|channel| channel.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leases_account_for_channels() {
+        let closed = CancellationToken::new();
+        let connection = SharedConnection::new(Session::new().unwrap(), closed.clone());
+        let shell = connection.lease(ChannelKind::Shell, "alice");
+        let exec = connection.clone().lease(ChannelKind::Exec, "automation");
+        let owners: Vec<_> = connection.channels().into_iter().map(|channel| (channel.kind, channel.owner)).collect();
+        assert_eq!(owners, vec![(ChannelKind::Shell, "alice".to_string()), (ChannelKind::Exec, "automation".to_string())]);
+
+        drop(exec);
+        assert_eq!(connection.channels().len(), 1);
+        drop(shell);
+        assert!(connection.channels().is_empty());
+
+        assert!(connection.is_open());
+        closed.cancel();
+        assert!(!connection.is_open());
+    }
+}
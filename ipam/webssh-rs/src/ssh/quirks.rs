@@ -0,0 +1,61 @@
+//! Accommodations in session setup for old devices, as turned on in their profile
+//!
+//! libssh2 always offers `ext-info-c` and `kex-strict-c-v00@openssh.com` in
+//! its key exchange, so neither can be left out for a device; strict key
+//! exchange only takes effect with servers that offer it too.
+
+use ssh2::{Channel, MethodType, Session};
+use tracing::debug;
+
+use crate::settings::DeviceQuirks;
+
+/// Receive window of a shell channel opened with the `small_window` quirk
+const SMALL_WINDOW_SIZE: u32 = 16 * 1024;
+
+/// Largest packet a device may send on a shell channel opened with the `small_window` quirk
+const SMALL_PACKET_SIZE: u32 = 4 * 1024;
+
+/// Names of the quirks turned on, as published in the session's connection facts
+pub fn names(quirks: &DeviceQuirks) -> Vec<String> {
+    [
+        (quirks.small_window, "small_window"),
+        (quirks.ignore_ext_info, "ignore_ext_info"),
+        (quirks.no_pty_modes, "no_pty_modes"),
+        (quirks.no_environment, "no_environment"),
+    ]
+    .into_iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, name)| name.to_string())
+    .collect()
+}
+
+/// Applies the quirks that must be in place before the handshake
+pub fn prepare(session: &Session, quirks: &DeviceQuirks) -> Result<(), ssh2::Error> {
+    if quirks.ignore_ext_info {
+        // With ssh-rsa the only preference, the rsa-sha2 algorithms listed in the server's EXT_INFO are never chosen
+        debug!("Quirk ignore_ext_info: signing RSA keys with ssh-rsa");
+        session.method_pref(MethodType::SignAlgo, "ssh-rsa")?;
+    }
+    Ok(())
+}
+
+/// Opens a session channel for a shell, with a tiny window if the device needs one
+pub fn channel_session(session: &Session, quirks: &DeviceQuirks) -> Result<Channel, ssh2::Error> {
+    if quirks.small_window {
+        debug!("Quirk small_window: opening the channel with a {} byte window", SMALL_WINDOW_SIZE);
+        return session.channel_open("session", SMALL_WINDOW_SIZE, SMALL_PACKET_SIZE, None);
+    }
+    session.channel_session()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_list_the_quirks_turned_on() {
+        assert!(names(&DeviceQuirks::default()).is_empty());
+        let quirks = DeviceQuirks { small_window: true, no_environment: true, ..DeviceQuirks::default() };
+        assert_eq!(names(&quirks), ["small_window", "no_environment"]);
+    }
+}
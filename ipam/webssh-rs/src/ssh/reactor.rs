@@ -0,0 +1,364 @@
+use bytes::Bytes;
+use ssh2::{BlockDirections, Channel, Session};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use super::error::SSHError;
+use super::heartbeat::Heartbeat;
+use super::rekey::{self, RekeyWatch};
+use super::session::SSHSession;
+
+/// What the reactor needs of a shell's channel
+///
+/// Implemented by the SSH channel of a live session; the reactor's read,
+/// write and end-of-file paths only see it through this trait.
+pub(super) trait ShellChannel: Read + Write {
+    /// Whether the device has closed its side of the channel
+    fn eof(&self) -> bool;
+
+    /// Which way the connection is blocked, after a call that would block
+    fn block_directions(&self) -> BlockDirections;
+
+    /// Whether a write that would block is held up by a key re-exchange
+    fn exchanging_keys(&self) -> bool;
+}
+
+/// A shell's SSH channel together with the session it is open on
+pub(super) struct SshChannel<'a> {
+    pub session: &'a Session,
+    pub channel: &'a mut Channel,
+}
+
+impl Read for SshChannel<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.channel.read(buf)
+    }
+}
+
+impl Write for SshChannel<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.channel.write(buf)
+    }
+
+    // Channel::flush is not called: in libssh2 it discards unread incoming data
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ShellChannel for SshChannel<'_> {
+    fn eof(&self) -> bool {
+        self.channel.eof()
+    }
+
+    fn block_directions(&self) -> BlockDirections {
+        self.session.block_directions()
+    }
+
+    fn exchanging_keys(&self) -> bool {
+        rekey::exchanging_keys(self.session, Some(self.channel))
+    }
+}
+
+/// The session socket, registered with the reactor without taking ownership of it
+pub(super) struct SocketFd(pub RawFd);
+
+impl AsRawFd for SocketFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Reads everything the channel has available and forwards it to the WebSocket
+///
+/// Reading stops while the output queue is full, so a slow consumer never
+/// blocks the loop; unread data stays in the channel window, which holds
+/// the device back.
+///
+/// # Returns
+/// * `Result<bool, SSHError>` - false once the channel or the WebSocket has closed
+pub(super) async fn drain_output(
+    channel: &mut impl ShellChannel,
+    buf: &mut [u8],
+    output_tx: &mpsc::Sender<Bytes>,
+    heartbeat: &Heartbeat,
+    shutdown: &CancellationToken,
+    rekey: &mut RekeyWatch,
+) -> Result<bool, SSHError> {
+    loop {
+        if output_tx.capacity() == 0 {
+            return Ok(true);
+        }
+        match channel.read(buf) {
+            Ok(0) => {
+                if channel.eof() {
+                    info!("SSH channel EOF detected");
+                    shutdown.cancel();
+                    let closure_message = "\r\n[SSH connection closed]\r\n";
+                    let _ = output_tx.send(Bytes::from(closure_message.as_bytes().to_vec())).await;
+                    return Ok(false);
+                }
+                return Ok(true);
+            }
+            Ok(n) => {
+                debug!("Read {} bytes from SSH", n);
+                heartbeat.heard();
+                rekey.transferred(n);
+                let cleaned_data = SSHSession::clean_control_sequences(&buf[..n]);
+                if !cleaned_data.is_empty() && output_tx.send(Bytes::from(cleaned_data)).await.is_err() {
+                    error!("Failed to send SSH output to WebSocket");
+                    return Ok(false);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(e) => {
+                error!("SSH read error: {}", e);
+                return Err(SSHError::Connection(e));
+            }
+        }
+    }
+}
+
+/// Writes WebSocket input to the channel, waiting on the socket whenever libssh2 would block
+///
+/// A key re-exchange holds writes up until the device has answered it.
+///
+/// # Returns
+/// * `Result<bool, SSHError>` - false if the channel has closed
+pub(super) async fn write_input(
+    channel: &mut impl ShellChannel,
+    socket: &AsyncFd<SocketFd>,
+    data: &[u8],
+    rekey: &mut RekeyWatch,
+) -> Result<bool, SSHError> {
+    let mut written = 0;
+    while written < data.len() {
+        match channel.write(&data[written..]) {
+            Ok(n) => {
+                rekey.resumed();
+                rekey.transferred(n);
+                written += n;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                rekey.blocked(channel.exchanging_keys());
+                wait_for_socket(channel.block_directions(), socket).await?;
+            }
+            Err(e) => {
+                let is_channel_closed = e.kind() == io::ErrorKind::BrokenPipe ||
+                                       e.kind() == io::ErrorKind::ConnectionReset ||
+                                       e.to_string().contains("closed");
+                if is_channel_closed {
+                    error!("SSH channel closed unexpectedly: {}", e);
+                    return Ok(false);
+                }
+                error!("SSH write error: {}", e);
+                return Err(SSHError::Connection(e));
+            }
+        }
+    }
+    debug!("Wrote {} bytes to SSH", data.len());
+    Ok(true)
+}
+
+/// Waits until the socket is ready in the direction libssh2 is blocked on
+pub(super) async fn wait_for_socket(directions: BlockDirections, socket: &AsyncFd<SocketFd>) -> Result<(), SSHError> {
+    match directions {
+        BlockDirections::Inbound => socket.readable().await?.clear_ready(),
+        BlockDirections::Outbound => socket.writable().await?.clear_ready(),
+        _ => tokio::select! {
+            ready = socket.readable() => ready?.clear_ready(),
+            ready = socket.writable() => ready?.clear_ready(),
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::rekey::KeyExchangeLog;
+    use std::collections::VecDeque;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    /// A channel replaying scripted reads and taking writes in small pieces
+    #[derive(Default)]
+    struct FakeChannel {
+        reads: VecDeque<io::Result<Vec<u8>>>,
+        eof: bool,
+        written: Vec<u8>,
+        // Writes still to be refused as would-block, and whether on the device rather than the socket
+        stalls: usize,
+        inbound: bool,
+        closed: bool,
+    }
+
+    impl Read for FakeChannel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(data)) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Some(Err(e)) => Err(e),
+                None if self.eof => Ok(0),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for FakeChannel {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if self.stalls > 0 {
+                self.stalls -= 1;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(4);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ShellChannel for FakeChannel {
+        fn eof(&self) -> bool {
+            self.eof
+        }
+
+        fn block_directions(&self) -> BlockDirections {
+            if self.inbound { BlockDirections::Inbound } else { BlockDirections::Outbound }
+        }
+
+        fn exchanging_keys(&self) -> bool {
+            false
+        }
+    }
+
+    /// What the I/O loop passes to the reactor's steps, around the gateway's end of a socket pair
+    struct Reactor {
+        heartbeat: Heartbeat,
+        shutdown: CancellationToken,
+        rekey: RekeyWatch,
+        // The device's end of the socket
+        peer: UnixStream,
+        socket: AsyncFd<SocketFd>,
+        // Owns the descriptor registered above; declared after it, so closed after it
+        _local: UnixStream,
+    }
+
+    fn reactor() -> Reactor {
+        let (local, peer) = UnixStream::pair().unwrap();
+        local.set_nonblocking(true).unwrap();
+        Reactor {
+            heartbeat: Heartbeat::new(local.as_raw_fd()),
+            shutdown: CancellationToken::new(),
+            rekey: RekeyWatch::new(KeyExchangeLog::default()),
+            peer,
+            socket: AsyncFd::new(SocketFd(local.as_raw_fd())).unwrap(),
+            _local: local,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_forwards_output_until_it_would_block() {
+        let mut r = reactor();
+        let mut channel = FakeChannel::default();
+        channel.reads.extend([Ok(b"Router>".to_vec()), Ok(b" show".to_vec())]);
+        let (output_tx, mut output_rx) = mpsc::channel(8);
+
+        let mut buf = [0u8; 64];
+        assert!(drain_output(&mut channel, &mut buf, &output_tx, &r.heartbeat, &r.shutdown, &mut r.rekey).await.unwrap());
+        assert_eq!(output_rx.recv().await.unwrap(), Bytes::from_static(b"Router>"));
+        assert_eq!(output_rx.recv().await.unwrap(), Bytes::from_static(b" show"));
+        assert!(output_rx.try_recv().is_err());
+        assert!(!r.shutdown.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_drain_stops_while_the_output_queue_is_full() {
+        let mut r = reactor();
+        let mut channel = FakeChannel::default();
+        channel.reads.extend([Ok(b"one".to_vec()), Ok(b"two".to_vec())]);
+        let (output_tx, mut output_rx) = mpsc::channel(1);
+
+        let mut buf = [0u8; 64];
+        assert!(drain_output(&mut channel, &mut buf, &output_tx, &r.heartbeat, &r.shutdown, &mut r.rekey).await.unwrap());
+        // The second read waits in the channel until the WebSocket catches up
+        assert_eq!(channel.reads.len(), 1);
+        assert_eq!(output_rx.recv().await.unwrap(), Bytes::from_static(b"one"));
+    }
+
+    #[tokio::test]
+    async fn test_drain_reports_eof_and_shuts_down() {
+        let mut r = reactor();
+        let mut channel = FakeChannel { eof: true, ..Default::default() };
+        channel.reads.push_back(Ok(b"logout".to_vec()));
+        let (output_tx, mut output_rx) = mpsc::channel(8);
+
+        let mut buf = [0u8; 64];
+        assert!(!drain_output(&mut channel, &mut buf, &output_tx, &r.heartbeat, &r.shutdown, &mut r.rekey).await.unwrap());
+        assert_eq!(output_rx.recv().await.unwrap(), Bytes::from_static(b"logout"));
+        assert_eq!(output_rx.recv().await.unwrap(), Bytes::from_static(b"\r\n[SSH connection closed]\r\n"));
+        assert!(r.shutdown.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_drain_fails_on_read_errors() {
+        let mut r = reactor();
+        let mut channel = FakeChannel::default();
+        channel.reads.push_back(Err(io::ErrorKind::ConnectionReset.into()));
+        let (output_tx, _output_rx) = mpsc::channel(8);
+
+        let mut buf = [0u8; 64];
+        let result = drain_output(&mut channel, &mut buf, &output_tx, &r.heartbeat, &r.shutdown, &mut r.rekey).await;
+        assert!(matches!(result, Err(SSHError::Connection(_))));
+    }
+
+    #[tokio::test]
+    async fn test_write_waits_for_the_socket_when_blocked() {
+        let mut r = reactor();
+        let mut channel = FakeChannel { stalls: 1, ..Default::default() };
+
+        let written = tokio::time::timeout(
+            Duration::from_secs(5),
+            write_input(&mut channel, &r.socket, b"show running-config\n", &mut r.rekey),
+        ).await.unwrap();
+        assert!(written.unwrap());
+        assert_eq!(channel.written, b"show running-config\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_blocked_inbound_waits_for_the_device() {
+        let mut r = reactor();
+        let mut channel = FakeChannel { stalls: 1, inbound: true, ..Default::default() };
+
+        {
+            let write = write_input(&mut channel, &r.socket, b"exit\n", &mut r.rekey);
+            tokio::pin!(write);
+            // Nothing from the device yet, e.g. its half of a key re-exchange
+            assert!(tokio::time::timeout(Duration::from_millis(100), &mut write).await.is_err());
+
+            (&r.peer).write_all(b"x").unwrap();
+            let written = tokio::time::timeout(Duration::from_secs(5), write).await.unwrap();
+            assert!(written.unwrap());
+        }
+        assert_eq!(channel.written, b"exit\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_to_a_closed_channel() {
+        let mut r = reactor();
+        let mut channel = FakeChannel { closed: true, ..Default::default() };
+        assert!(!write_input(&mut channel, &r.socket, b"exit\n", &mut r.rekey).await.unwrap());
+    }
+}
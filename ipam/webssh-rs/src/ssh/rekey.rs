@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use ssh2::{BlockDirections, Channel, Session};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::info;
+
+/// Key exchanges kept per connection
+const MAX_KEY_EXCHANGES: usize = 16;
+
+/// A key re-exchange seen on a connection
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyExchange {
+    /// When the I/O loop was first held up by it
+    pub at: DateTime<Utc>,
+    /// How long writes and keepalives were held up
+    pub stalled_ms: u64,
+    /// Traffic since the previous key exchange, or since the connection was opened
+    pub bytes_before: u64,
+    pub seconds_before: u64,
+}
+
+/// The key re-exchanges of a connection, for the session stats
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KeyExchangeHistory {
+    pub count: u64,
+    /// The latest ones, oldest first
+    pub recent: Vec<KeyExchange>,
+}
+
+/// Where a connection's I/O loop records its key re-exchanges
+#[derive(Clone, Default)]
+pub struct KeyExchangeLog(Arc<Mutex<KeyExchangeHistory>>);
+
+impl KeyExchangeLog {
+    pub fn history(&self) -> KeyExchangeHistory {
+        self.0.lock().map(|history| history.clone()).unwrap_or_default()
+    }
+
+    fn push(&self, exchange: KeyExchange) {
+        if let Ok(mut history) = self.0.lock() {
+            history.count += 1;
+            if history.recent.len() == MAX_KEY_EXCHANGES {
+                history.recent.remove(0);
+            }
+            history.recent.push(exchange);
+        }
+    }
+}
+
+/// Whether a write that would block is held up by a key re-exchange
+///
+/// libssh2 sends nothing while keys are being exchanged and reads the
+/// device's replies first, so the write waits on inbound data. Outside a
+/// key exchange, a write only waits on inbound data for a window adjustment
+/// when the channel window is used up.
+pub fn exchanging_keys(session: &Session, channel: Option<&Channel>) -> bool {
+    session.block_directions() == BlockDirections::Inbound
+        && channel.is_none_or(|channel| channel.write_window().remaining > 0)
+}
+
+/// Follows the key re-exchanges the device starts during a session
+///
+/// libssh2 takes part in a re-exchange the device starts but cannot start
+/// one itself. While one runs, reads and writes return WouldBlock; the I/O
+/// loop waits it out and notes it here.
+pub struct RekeyWatch {
+    log: KeyExchangeLog,
+    since: Instant,
+    bytes: u64,
+    stalled: Option<(Instant, DateTime<Utc>)>,
+}
+
+impl RekeyWatch {
+    pub fn new(log: KeyExchangeLog) -> Self {
+        Self { log, since: Instant::now(), bytes: 0, stalled: None }
+    }
+
+    /// Counts traffic through the connection
+    pub fn transferred(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    /// Notes a write or keepalive that would block
+    ///
+    /// # Arguments
+    /// * `exchanging_keys` - Whether it was held up by a key exchange, see [`exchanging_keys`]
+    pub fn blocked(&mut self, exchanging_keys: bool) {
+        if exchanging_keys && self.stalled.is_none() {
+            self.stalled = Some((Instant::now(), Utc::now()));
+        }
+    }
+
+    /// Notes a write or keepalive that went through, ending a key exchange in progress
+    pub fn resumed(&mut self) {
+        let Some((stalled_since, at)) = self.stalled.take() else {
+            return;
+        };
+        let exchange = KeyExchange {
+            at,
+            stalled_ms: stalled_since.elapsed().as_millis() as u64,
+            bytes_before: self.bytes,
+            seconds_before: stalled_since.duration_since(self.since).as_secs(),
+        };
+        info!("Key re-exchange after {} bytes in {} s held writes up for {} ms",
+              exchange.bytes_before, exchange.seconds_before, exchange.stalled_ms);
+        self.log.push(exchange);
+        self.since = stalled_since;
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_exchanges_are_logged_once_resumed() {
+        let log = KeyExchangeLog::default();
+        let mut watch = RekeyWatch::new(log.clone());
+        watch.transferred(4096);
+        // Blocked on a full socket or channel window: not a key exchange
+        watch.blocked(false);
+        watch.resumed();
+        assert_eq!(log.history().count, 0);
+
+        watch.blocked(true);
+        watch.transferred(100);
+        watch.blocked(true);
+        watch.resumed();
+        watch.resumed();
+        let history = log.history();
+        assert_eq!(history.count, 1);
+        assert_eq!(history.recent[0].bytes_before, 4196);
+
+        for _ in 0..MAX_KEY_EXCHANGES + 4 {
+            watch.blocked(true);
+            watch.resumed();
+        }
+        let history = log.history();
+        assert_eq!((history.count, history.recent.len()), (MAX_KEY_EXCHANGES as u64 + 5, MAX_KEY_EXCHANGES));
+        assert_eq!(history.recent.last().map(|exchange| exchange.bytes_before), Some(0));
+    }
+}
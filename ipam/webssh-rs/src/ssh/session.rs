@@ -1,54 +1,120 @@
-use ssh2::Session;
-use std::{io::{Read, Write}, net::TcpStream};
+use ssh2::{KeyboardInteractivePrompt, Session};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use bytes::Bytes;
-use tracing::{error, info, debug};
-use std::time::Duration;
+use tracing::{error, info, debug, warn};
 
-use crate::settings::SSHSettings;
+use super::backend::Shell;
+use super::detect;
 use super::error::SSHError;
+use super::heartbeat::Heartbeat;
+use super::pool::SharedConnection;
+use super::rekey::{self, RekeyWatch};
+use super::target::{ConnectionInfo, ConnectionTarget, TerminalSize};
 use super::channel::{setup_standard_session, setup_linux_session, setup_cisco_session};
 
+/// Most of a greeting read while recognising the device
+const MAX_GREETING_BYTES: usize = 64 * 1024;
+
 /// Represents an active SSH session with a remote server
 ///
 /// This struct manages the SSH connection, authentication, and I/O operations
 /// between the web client and the SSH server.
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
-
 pub struct SSHSession {
     session: Session,
     channel: ssh2::Channel,
     resize_rx: Option<mpsc::Receiver<(u32, u32)>>,
-    // Thread-safe flag to signal shutdown
-    shutdown_flag: Arc<AtomicBool>,
-    settings: SSHSettings,
-    // Store connection parameters for cloning
-    hostname: String,
-    port: u16,
-    username: String,
-    password: Option<String>,
-    private_key: Option<String>,
-    device_type: Option<String>,
+    // Cancelled to stop the I/O pump and the WebSocket attached to it
+    shutdown: CancellationToken,
+    // Connection parameters, kept for further shells and auxiliary connections
+    target: ConnectionTarget,
+    // RTT estimate and negotiated compression
+    connection_info: ConnectionInfo,
+    // Output read while recognising the device, sent ahead of the rest
+    greeting: Vec<u8>,
+    // Progress of this shell's I/O loop
+    heartbeat: Heartbeat,
+    // Heartbeats of every shell opened for the session, for the watchdog
+    shells: Arc<Mutex<Vec<Heartbeat>>>,
+    // The connection, as lent to exec requests while the shell runs
+    connection: SharedConnection,
 }
 
-// Implement Clone for SSHSession
-impl Clone for SSHSession {
-    fn clone(&self) -> Self {
-        // Create a new session with the same parameters, but share the shutdown flag
-        let mut cloned = SSHSession::new(
-            &self.hostname,
-            self.port,
-            &self.username,
-            self.password.as_deref(),
-            self.private_key.as_deref(),
-            self.device_type.as_deref(),
-            &self.settings,
-        ).expect("Failed to clone SSH session");
-        
-        // Share the same shutdown flag so both instances can be shut down together
-        cloned.shutdown_flag = self.shutdown_flag.clone();
-        
-        cloned
+/// A lightweight reference to an SSH session, kept by the session registry
+///
+/// The shell itself is owned by the task pumping its I/O; the handle can
+/// signal it to shut down and open further shells to the same target.
+#[derive(Clone)]
+pub struct SessionHandle {
+    target: ConnectionTarget,
+    connection_info: ConnectionInfo,
+    shutdown: CancellationToken,
+    shells: Arc<Mutex<Vec<Heartbeat>>>,
+    // Telnet sessions have no connection to share
+    connection: Option<SharedConnection>,
+}
+
+impl SessionHandle {
+    pub(super) fn new(
+        target: ConnectionTarget,
+        connection_info: ConnectionInfo,
+        shutdown: CancellationToken,
+        shells: Arc<Mutex<Vec<Heartbeat>>>,
+        connection: Option<SharedConnection>,
+    ) -> Self {
+        Self { target, connection_info, shutdown, shells, connection }
+    }
+
+    /// Gets the connection parameters the session was opened with
+    pub fn target(&self) -> &ConnectionTarget {
+        &self.target
+    }
+
+    /// Gets what was measured and negotiated when the session connected
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.connection_info
+    }
+
+    /// Signals every shell of the session to stop its I/O and close
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Gets the token cancelled when the session shuts down
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Gets the session's SSH connection, for opening further channels on it
+    pub fn connection(&self) -> Option<&SharedConnection> {
+        self.connection.as_ref()
+    }
+
+    /// Opens another shell to the same target, shut down together with the session
+    ///
+    /// The shell can also be shut down on its own without affecting the session.
+    ///
+    /// # Returns
+    /// * `Result<Shell, SSHError>` - A new shell or an error
+    pub fn open_shell(&self) -> Result<Shell, SSHError> {
+        let mut shell = Shell::open(self.target.clone())?;
+        shell.join(self.shutdown.child_token(), &self.shells);
+        Ok(shell)
+    }
+
+    /// Gets the heartbeats of the session's shells whose I/O has not ended
+    pub fn heartbeats(&self) -> Vec<Heartbeat> {
+        match self.shells.lock() {
+            Ok(mut shells) => {
+                shells.retain(|heartbeat| !heartbeat.is_finished());
+                shells.clone()
+            }
+            Err(_) => Vec::new(),
+        }
     }
 }
 
@@ -61,11 +127,11 @@ impl SSHSession {
     /// # Returns
     /// * `Result<(), SSHError>` - Success or an error
     pub fn close(&mut self) -> Result<(), SSHError> {
-        info!("Closing SSH session to {}:{} for user {}", self.hostname, self.port, self.username);
+        info!("Closing SSH session to {}:{} for user {}", self.target.hostname, self.target.port, self.target.username);
         
-        // Signal the I/O handling thread to stop by setting the shutdown flag
-        info!("Setting shutdown flag to signal I/O handling thread to stop");
-        self.shutdown_flag.store(true, Ordering::SeqCst);
+        // Signal the I/O pump and the WebSocket to stop
+        info!("Cancelling shutdown token to signal I/O handling to stop");
+        self.shutdown.cancel();
         
         // Close the channel first
         match self.channel.close() {
@@ -94,265 +160,46 @@ impl SSHSession {
             Err(e) => error!("Error disconnecting SSH session: {}", e),
         }
         
-        info!("SSH session to {}:{} for user {} closed", self.hostname, self.port, self.username);
+        info!("SSH session to {}:{} for user {} closed", self.target.hostname, self.target.port, self.target.username);
         Ok(())
     }
     
-    /// Creates a new SSH session with the specified connection parameters
+    /// Connects to the given target and opens the interactive shell channel
     ///
     /// # Arguments
-    /// * `hostname` - The hostname or IP address of the SSH server
-    /// * `port` - The port number of the SSH server (typically 22)
-    /// * `username` - The username for authentication
-    /// * `password` - Optional password for authentication
-    /// * `private_key` - Optional private key for authentication (in PEM format)
-    /// * `device_type_hint` - Optional hint about the device type (e.g., "cisco", "linux")
-    /// * `settings` - SSH settings from the application configuration
+    /// * `target` - The connection parameters for the device
     ///
     /// # Returns
     /// * `Result<Self, SSHError>` - A new SSHSession or an error
-    pub fn new(
-        hostname: &str,
-        port: u16,
-        username: &str,
-        password: Option<&str>,
-        private_key: Option<&str>,
-        device_type_hint: Option<&str>,
-        settings: &SSHSettings,
-    ) -> Result<Self, SSHError> {
-        info!("Connecting to SSH server {}:{}", hostname, port);
-        
-        // Create TCP connection with timeout
-        let tcp = TcpStream::connect((hostname, port))?;
-        tcp.set_read_timeout(Some(Duration::from_secs(settings.connection.read_timeout_seconds)))?;
-        tcp.set_write_timeout(Some(Duration::from_secs(settings.connection.write_timeout_seconds)))?;
-        debug!("TCP connection established");
-
-        // Create and configure SSH session
-        let mut session = Session::new()
-            .map_err(|_| SSHError::Connection(
-                std::io::Error::new(std::io::ErrorKind::Other, "Failed to create SSH session")
-            ))?;
-
-        session.set_tcp_stream(tcp);
-        session.set_timeout((settings.connection.timeout_seconds * 1000) as u32); // Convert seconds to milliseconds
-        session.set_compress(settings.connection.compress);
-        
-        // Configure SSH algorithms from settings
-        session.method_pref(
-            ssh2::MethodType::Kex,
-            &settings.crypto.kex_algorithms
-        )?;
-        session.method_pref(
-            ssh2::MethodType::HostKey,
-            &settings.crypto.host_key_algorithms
-        )?;
-        session.method_pref(
-            ssh2::MethodType::CryptCs,
-            &settings.crypto.encryption_client_to_server
-        )?;
-        session.method_pref(
-            ssh2::MethodType::CryptSc,
-            &settings.crypto.encryption_server_to_client
-        )?;
-        session.method_pref(
-            ssh2::MethodType::MacCs,
-            &settings.crypto.mac_client_to_server
-        )?;
-        session.method_pref(
-            ssh2::MethodType::MacSc,
-            &settings.crypto.mac_server_to_client
-        )?;
-
-        debug!("Starting SSH handshake");
-        
-        // Log available methods before handshake
-        debug!("Configured KEX methods: {}", settings.crypto.kex_algorithms);
-        debug!("Configured host key methods: {}", settings.crypto.host_key_algorithms);
-        debug!("Configured client->server encryption methods: {}", settings.crypto.encryption_client_to_server);
-        debug!("Configured server->client encryption methods: {}", settings.crypto.encryption_server_to_client);
-        
-        // Implement retry mechanism for handshake with banner issues
-        let mut retry_count = 0;
-        let max_retries = 3;
-        
-        loop {
-            match session.handshake() {
-                Ok(_) => {
-                    if retry_count > 0 {
-                        debug!("SSH handshake completed successfully after {} retries", retry_count);
-                    } else {
-                        debug!("SSH handshake completed successfully");
-                    }
-                    break;
-                },
-                Err(e) => {
-                    // Check if this is a banner-related error
-                    let is_banner_error = e.code() == ssh2::ErrorCode::Session(-13) && 
-                                          e.message().contains("banner");
-                    
-                    if is_banner_error && retry_count < max_retries {
-                        retry_count += 1;
-                        error!("SSH handshake failed due to banner issue (attempt {}/{}): {}", 
-                               retry_count, max_retries, e);
-                        
-                        // For Cisco XR devices that have banner issues, we'll retry after a short delay
-                        debug!("Retrying handshake after banner issue...");
-                        std::thread::sleep(std::time::Duration::from_millis(500));
-                        
-                        // Create a new session for the retry
-                        drop(session);
-                        session = Session::new()
-                            .map_err(|_| SSHError::Connection(
-                                std::io::Error::new(std::io::ErrorKind::Other, "Failed to create SSH session")
-                            ))?;
-                        
-                        // Reconnect TCP
-                        let tcp = TcpStream::connect((hostname, port))?;
-                        tcp.set_read_timeout(Some(Duration::from_secs(settings.connection.read_timeout_seconds)))?;
-                        tcp.set_write_timeout(Some(Duration::from_secs(settings.connection.write_timeout_seconds)))?;
-                        
-                        session.set_tcp_stream(tcp);
-                        session.set_timeout((settings.connection.timeout_seconds * 1000) as u32);
-                        session.set_compress(settings.connection.compress);
-                        
-                        // Reconfigure SSH algorithms
-                        session.method_pref(ssh2::MethodType::Kex, &settings.crypto.kex_algorithms)?;
-                        session.method_pref(ssh2::MethodType::HostKey, &settings.crypto.host_key_algorithms)?;
-                        session.method_pref(ssh2::MethodType::CryptCs, &settings.crypto.encryption_client_to_server)?;
-                        session.method_pref(ssh2::MethodType::CryptSc, &settings.crypto.encryption_server_to_client)?;
-                        session.method_pref(ssh2::MethodType::MacCs, &settings.crypto.mac_client_to_server)?;
-                        session.method_pref(ssh2::MethodType::MacSc, &settings.crypto.mac_server_to_client)?;
-                        
-                        continue;
-                    } else {
-                        // For non-banner errors or if we've exhausted retries
-                        if is_banner_error {
-                            error!("SSH handshake failed due to banner issue after {} retries: {}", max_retries, e);
-                        } else {
-                            error!("SSH handshake failed: {}", e);
-                        }
-                        error!("This could be due to incompatible encryption algorithms or network issues");
-                        return Err(e.into());
-                    }
-                }
-            }
+    pub fn open(mut target: ConnectionTarget) -> Result<Self, SSHError> {
+        let connected = target.connect_with_info()?;
+        // A token's PIN is for this login only; later connections must ask again
+        if let Some(login) = target.pkcs11.as_mut() {
+            login.pin = None;
         }
+        Self::open_shell_channel(target, connected)
+    }
 
-        // Configure session
-        session.set_blocking(true);
-        session.set_keepalive(true, settings.connection.keepalive_seconds as u32);
-
-        // Authenticate with retry mechanism
-        if let Some(password) = password {
-            info!("Authenticating with password for user {}", username);
-            
-            // Implement retry for password authentication
-            let mut auth_retry_count = 0;
-            let max_auth_retries = 3;
-            let mut auth_success = false;
-            
-            while auth_retry_count < max_auth_retries && !auth_success {
-                match session.userauth_password(username, password) {
-                    Ok(_) => {
-                        auth_success = true;
-                        if auth_retry_count > 0 {
-                            debug!("Password authentication succeeded after {} retries", auth_retry_count);
-                        } else {
-                            debug!("Password authentication succeeded");
-                        }
-                    },
-                    Err(e) => {
-                        auth_retry_count += 1;
-                        error!("Password authentication failed (attempt {}/{}): {}", 
-                               auth_retry_count, max_auth_retries, e);
-                        
-                        if auth_retry_count < max_auth_retries {
-                            debug!("Retrying password authentication after failure...");
-                            std::thread::sleep(std::time::Duration::from_millis(500));
-                            
-                            // For certain authentication errors, we may need to recreate the session
-                            if e.code() == ssh2::ErrorCode::Session(-43) { // Waiting for password response
-                                debug!("Recreating SSH session after authentication error");
-                                
-                                // Create a new session for the retry
-                                drop(session);
-                                session = Session::new()
-                                    .map_err(|_| SSHError::Connection(
-                                        std::io::Error::new(std::io::ErrorKind::Other, "Failed to create SSH session")
-                                    ))?;
-                                
-                                // Reconnect TCP
-                                let tcp = TcpStream::connect((hostname, port))?;
-                                tcp.set_read_timeout(Some(Duration::from_secs(settings.connection.read_timeout_seconds)))?;
-                                tcp.set_write_timeout(Some(Duration::from_secs(settings.connection.write_timeout_seconds)))?;
-                                
-                                session.set_tcp_stream(tcp);
-                                session.set_timeout((settings.connection.timeout_seconds * 1000) as u32);
-                                session.set_compress(settings.connection.compress);
-                                session.set_blocking(true);
-                                session.set_keepalive(true, settings.connection.keepalive_seconds as u32);
-                                
-                                // Reconfigure SSH algorithms
-                                session.method_pref(ssh2::MethodType::Kex, &settings.crypto.kex_algorithms)?;
-                                session.method_pref(ssh2::MethodType::HostKey, &settings.crypto.host_key_algorithms)?;
-                                session.method_pref(ssh2::MethodType::CryptCs, &settings.crypto.encryption_client_to_server)?;
-                                session.method_pref(ssh2::MethodType::CryptSc, &settings.crypto.encryption_server_to_client)?;
-                                session.method_pref(ssh2::MethodType::MacCs, &settings.crypto.mac_client_to_server)?;
-                                session.method_pref(ssh2::MethodType::MacSc, &settings.crypto.mac_server_to_client)?;
-                                
-                                // Perform handshake again
-                                debug!("Performing handshake after session recreation");
-                                match session.handshake() {
-                                    Ok(_) => debug!("SSH handshake completed successfully after session recreation"),
-                                    Err(handshake_err) => {
-                                        error!("SSH handshake failed after session recreation: {}", handshake_err);
-                                        return Err(handshake_err.into());
-                                    }
-                                }
-                            }
-                            continue;
-                        } else {
-                            return Err(SSHError::Authentication(format!("Password authentication failed after {} attempts: {}", max_auth_retries, e)));
-                        }
-                    }
-                }
-            }
-            
-            if !auth_success {
-                return Err(SSHError::Authentication(format!("Password authentication failed after {} attempts", max_auth_retries)));
-            }
-        } else if let Some(key_data) = private_key {
-            info!("Authenticating with private key for user {}", username);
-            
-            // Try to parse the private key
-            debug!("Parsing private key");
-            
-            // First, check if the key is in PEM format
-            if key_data.contains("-----BEGIN") {
-                debug!("Key appears to be in PEM format");
-                
-                // Try to load the private key
-                match session.userauth_pubkey_memory(username, None, key_data, None) {
-                    Ok(_) => debug!("Private key authentication successful"),
-                    Err(e) => {
-                        error!("Private key authentication failed: {}", e);
-                        return Err(SSHError::Authentication(format!("Private key authentication failed: {}", e)));
-                    }
-                }
-            } else {
-                // If not in PEM format, it might be in OpenSSH format or another format
-                error!("Unsupported private key format. Please provide a PEM formatted private key");
-                return Err(SSHError::Authentication("Unsupported private key format. Please provide a PEM formatted private key".into()));
-            }
-        } else {
-            return Err(SSHError::Authentication("No authentication method provided".into()));
-        }
+    /// Connects to the given target using keyboard-interactive authentication
+    /// and opens the interactive shell channel
+    ///
+    /// # Arguments
+    /// * `target` - The connection parameters for the device
+    /// * `prompter` - Answers the server's authentication prompts
+    ///
+    /// # Returns
+    /// * `Result<Self, SSHError>` - A new SSHSession or an error
+    pub fn open_interactive(target: ConnectionTarget, prompter: &mut dyn KeyboardInteractivePrompt) -> Result<Self, SSHError> {
+        let connected = target.connect_interactive(prompter)?;
+        Self::open_shell_channel(target, connected)
+    }
 
-        if !session.authenticated() {
-            return Err(SSHError::Authentication("Authentication failed".into()));
+    fn open_shell_channel(target: ConnectionTarget, (mut session, mut connection_info): (Session, ConnectionInfo)) -> Result<Self, SSHError> {
+        let mut settings = target.shell_settings();
+        if let Some(adaptation) = &connection_info.slow_link {
+            adaptation.apply(&mut settings.connection);
         }
-        debug!("Authentication successful");
+        let settings = &settings;
 
         // Create a simple channel
         info!("Creating SSH channel");
@@ -361,40 +208,51 @@ impl SSHSession {
         session.set_timeout((settings.connection.channel_timeout_seconds * 1000) as u32);
         
         // Get device type hint if provided
-        let device_type_hint = device_type_hint.map(|hint| hint.to_lowercase());
-        let is_cisco_hint = device_type_hint.as_ref().is_some_and(|hint|
+        let is_cisco_hint = target.device_type.as_ref().is_some_and(|hint|
             hint == "cisco" || hint == "router" || hint == "switch");
+        // Without a hint, the device is recognised from its banner and first prompt
+        let detect = target.device_type.is_none() && settings.detection.enabled;
+        let banner_kind = if detect { session.banner().and_then(detect::from_banner) } else { None };
+        // Some old devices drop the connection on an env request they do not know
+        let environment: &[(String, String)] = if target.quirks.no_environment { &[] } else { &target.environment };
         
         // Set up the channel based on device type with fallback mechanism
-        let mut channel = if is_cisco_hint {
+        let (mut channel, terminal_type) = if is_cisco_hint {
             debug!("Using Cisco approach based on user hint");
-            setup_cisco_session(&mut session, settings)?
+            setup_cisco_session(&mut session, settings, environment, &target.quirks)?
+        } else if let Some(kind) = banner_kind.filter(|kind| kind.is_network_device()) {
+            debug!("Using Cisco approach for a device recognised as {} from its banner", kind.as_str());
+            setup_cisco_session(&mut session, settings, environment, &target.quirks)?
         } else {
             // Try standard approach first (similar to electerm)
             debug!("Trying standard approach first");
-            match setup_standard_session(&mut session, settings) {
-                Ok(channel) => {
+            match setup_standard_session(&mut session, settings, environment, &target.quirks) {
+                Ok(opened) => {
                     debug!("Standard approach succeeded");
-                    channel
+                    opened
                 },
                 Err(e) => {
                     debug!("Standard approach failed: {}. Trying Linux approach", e);
                     // If standard approach fails, try Linux approach
-                    match setup_linux_session(&mut session, settings) {
-                        Ok(channel) => {
+                    match setup_linux_session(&mut session, settings, environment, &target.quirks) {
+                        Ok(opened) => {
                             debug!("Linux approach succeeded");
-                            channel
+                            opened
                         },
                         Err(e) => {
                             debug!("Linux approach failed: {}. Trying Cisco approach as final fallback", e);
                             // If Linux approach fails, try Cisco approach as final fallback
-                            setup_cisco_session(&mut session, settings)?
+                            setup_cisco_session(&mut session, settings, environment, &target.quirks)?
                         }
                     }
                 }
             }
         };
         
+        info!("Opened shell with terminal type {} at {}x{}", terminal_type, settings.terminal.default_cols, settings.terminal.default_rows);
+        connection_info.terminal_type = Some(terminal_type);
+        connection_info.terminal_size = Some(TerminalSize { cols: settings.terminal.default_cols, rows: settings.terminal.default_rows });
+
         // Ensure channel is ready with a flush
         debug!("Flushing channel");
         if let Err(e) = channel.flush() {
@@ -408,22 +266,55 @@ impl SSHSession {
         session.set_blocking(false);
         debug!("SSH session setup completed");
 
+        let greeting = if detect {
+            let greeting = read_greeting(&mut channel, Duration::from_millis(settings.detection.prompt_wait_ms));
+            connection_info.device_type = detect::from_output(&greeting).or(banner_kind);
+            match connection_info.device_type {
+                Some(kind) => info!("Recognised {} as {}", target.hostname, kind.as_str()),
+                None => debug!("Could not recognise the kind of device {}", target.hostname),
+            }
+            greeting
+        } else {
+            Vec::new()
+        };
+
+        let heartbeat = Heartbeat::new(session.as_raw_fd());
+        let shutdown = CancellationToken::new();
         Ok(Self {
+            connection: SharedConnection::new(session.clone(), shutdown.clone()),
             session,
             channel,
             resize_rx: None,
-            shutdown_flag: Arc::new(AtomicBool::new(false)),
-            settings: settings.clone(),
-            // Store parameters for cloning
-            hostname: hostname.to_string(),
-            port,
-            username: username.to_string(),
-            password: password.map(String::from),
-            private_key: private_key.map(String::from),
-            device_type: device_type_hint,
+            shutdown,
+            target,
+            connection_info,
+            greeting,
+            shells: Arc::new(Mutex::new(vec![heartbeat.clone()])),
+            heartbeat,
         })
     }
 
+    /// Gets a handle to this session for the session registry
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle::new(
+            self.target.clone(),
+            self.connection_info.clone(),
+            self.shutdown.clone(),
+            self.shells.clone(),
+            Some(self.connection.clone()),
+        )
+    }
+
+    /// Makes this shell one of a session's, shut down together with it
+    pub(super) fn join(&mut self, shutdown: CancellationToken, shells: &Arc<Mutex<Vec<Heartbeat>>>) {
+        self.shutdown = shutdown;
+        self.connection = SharedConnection::new(self.session.clone(), self.shutdown.clone());
+        if let Ok(mut shells) = shells.lock() {
+            shells.push(self.heartbeat.clone());
+        }
+        self.shells = shells.clone();
+    }
+
     /// Sets the channel for receiving terminal resize events
     ///
     /// # Arguments
@@ -443,6 +334,7 @@ impl SSHSession {
     ///
     /// # Returns
     /// * `Result<(), SSHError>` - Success or an error
+    #[cfg(not(feature = "reactor-io"))]
     pub fn resize_pty(&mut self, rows: u32, cols: u32) -> Result<(), SSHError> {
         debug!("Resizing PTY to {}x{}", cols, rows);
         
@@ -452,7 +344,8 @@ impl SSHSession {
         
         // Request PTY size change - this is the only thing we really need to do
         // The SSH server will handle sending SIGWINCH to the processes
-        self.channel.request_pty_size(cols, rows, None, None)?;
+        let (width_px, height_px) = self.target.pixel_size(cols, rows);
+        self.channel.request_pty_size(cols, rows, Some(width_px), Some(height_px))?;
         
         // We don't need to send any special escape sequences
         // Those were causing disconnection issues with some SSH servers
@@ -477,17 +370,17 @@ impl SSHSession {
     /// * `output_tx` - A sender for data to the WebSocket
     ///
     /// # Returns
-    /// Gets a clone of the shutdown flag for use in other threads
+    /// Gets the token cancelled when the session shuts down, for tasks serving it
     ///
     /// # Returns
-    /// * `Arc<AtomicBool>` - A clone of the shutdown flag
-    #[allow(dead_code)]
-    pub fn get_shutdown_flag(&self) -> Arc<AtomicBool> {
-        self.shutdown_flag.clone()
+    /// * `CancellationToken` - A clone of the session's shutdown token
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
     }
     
+    #[cfg(not(feature = "reactor-io"))]
     pub fn start_io(
-        mut self,
+        &mut self,
         mut input_rx: mpsc::Receiver<Bytes>,
         output_tx: mpsc::Sender<Bytes>,
     ) -> Result<(), SSHError> {
@@ -496,27 +389,34 @@ impl SSHSession {
         // Buffer for reading from SSH
         let mut buf = [0u8; 4096];
         let mut last_keepalive = std::time::Instant::now();
+        // Output the WebSocket side had no room for; the channel is not read until it is taken
+        // The greeting read while recognising the device goes first
+        let mut pending: Option<Bytes> = Some(std::mem::take(&mut self.greeting))
+            .filter(|greeting| !greeting.is_empty())
+            .map(|greeting| Bytes::from(Self::clean_control_sequences(&greeting)));
+        
+        // Input the channel had no room for, and how much of it was written
+        let mut unwritten: Option<(Bytes, usize)> = None;
+        let mut rekey = RekeyWatch::new(self.connection.key_exchanges().clone());
         
         // Take ownership of the resize channel if it exists
         let mut resize_rx = self.resize_rx.take();
         
-        // Get a clone of the shutdown flag for this thread
-        let shutdown_flag = self.shutdown_flag.clone();
+        // Get a clone of the shutdown token for this thread
+        let shutdown = self.shutdown.clone();
         
         loop {
-            // Check if the shutdown flag has been set
-            if shutdown_flag.load(Ordering::SeqCst) {
-                info!("Shutdown flag set, stopping I/O handling");
+            self.heartbeat.beat();
+            
+            // Check if shutdown has been signalled
+            if shutdown.is_cancelled() {
+                info!("Shutdown signalled, stopping I/O handling");
                 break;
             }
             
             // Send keepalive based on settings
-            if last_keepalive.elapsed() >= std::time::Duration::from_secs(self.settings.connection.keepalive_seconds) {
-                debug!("Sending keepalive");
-                if let Err(e) = self.session.keepalive_send() {
-                    error!("Failed to send keepalive: {}", e);
-                    break;
-                }
+            if last_keepalive.elapsed() >= std::time::Duration::from_secs(self.connection_info.keepalive_seconds(&self.target.settings)) {
+                self.send_keepalive(&mut rekey);
                 last_keepalive = std::time::Instant::now();
             }
             
@@ -530,25 +430,49 @@ impl SSHSession {
                 }
             }
 
-            // Read from SSH with timeout
-            match self.channel.read(&mut buf) {
+            if let Some(data) = pending.take() {
+                match output_tx.try_send(data) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(data)) => pending = Some(data),
+                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                        error!("Failed to send SSH output to WebSocket");
+                        break;
+                    }
+                }
+            }
+
+            // libssh2 takes in keepalive replies unseen, so look for them on the socket first
+            if socket_readable(self.session.as_raw_fd()) {
+                self.heartbeat.heard();
+            }
+
+            // Read from SSH with timeout, unless output is still waiting for room
+            let read = match pending {
+                Some(_) => Err(std::io::ErrorKind::WouldBlock.into()),
+                None => self.channel.read(&mut buf),
+            };
+            match read {
                 Ok(n) => {
                     if n > 0 {
                         debug!("Read {} bytes from SSH", n);
+                        self.heartbeat.heard();
+                        rekey.transferred(n);
                         // Clean control sequences from the output
                         let cleaned_data = Self::clean_control_sequences(&buf[..n]);
                         if !cleaned_data.is_empty() {
-                            let data = Bytes::from(cleaned_data);
-                            if output_tx.blocking_send(data).is_err() {
-                                error!("Failed to send SSH output to WebSocket");
-                                break;
+                            match output_tx.try_send(Bytes::from(cleaned_data)) {
+                                Ok(()) => debug!("Sent {} bytes to WebSocket", n),
+                                Err(mpsc::error::TrySendError::Full(data)) => pending = Some(data),
+                                Err(mpsc::error::TrySendError::Closed(_)) => {
+                                    error!("Failed to send SSH output to WebSocket");
+                                    break;
+                                }
                             }
-                            debug!("Sent {} bytes to WebSocket", n);
                         }
                     } else if self.channel.eof() {
                         info!("SSH channel EOF detected");
-                        // Set shutdown flag to ensure all tasks terminate cleanly
-                        shutdown_flag.store(true, Ordering::SeqCst);
+                        // Signal shutdown to ensure all tasks terminate cleanly
+                        shutdown.cancel();
                         
                         // Send a final message to indicate connection closure
                         let closure_message = "\r\n[SSH connection closed]\r\n";
@@ -566,21 +490,31 @@ impl SSHSession {
                 }
             }
 
-            // Process any pending input
-            while let Ok(data) = input_rx.try_recv() {
-                debug!("Received {} bytes from WebSocket", data.len());
-                match self.channel.write_all(&data) {
-                    Ok(_) => {
-                        if let Err(e) = self.channel.flush() {
-                            if e.kind() != std::io::ErrorKind::WouldBlock {
-                                error!("Failed to flush SSH channel: {}", e);
-                                return Err(SSHError::Connection(e));
-                            }
+            // Process any pending input, the rest of a write that would have blocked first
+            // Channel::flush is not called: in libssh2 it discards unread incoming data
+            while let Some((data, written)) = unwritten.take().or_else(|| input_rx.try_recv().ok().map(|data| (data, 0))) {
+                if written == 0 {
+                    debug!("Received {} bytes from WebSocket", data.len());
+                }
+                match self.channel.write(&data[written..]) {
+                    Ok(n) if n > 0 => {
+                        rekey.resumed();
+                        rekey.transferred(n);
+                        if written + n < data.len() {
+                            unwritten = Some((data, written + n));
+                        } else {
+                            debug!("Wrote {} bytes to SSH", data.len());
                         }
-                        debug!("Wrote {} bytes to SSH", data.len());
+                    }
+                    Ok(_) => {
+                        unwritten = Some((data, written));
+                        break;
                     }
                     Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        // Would block, try again next iteration
+                        // Held up by a key re-exchange, the channel window or a full
+                        // socket: the rest is written next iteration
+                        rekey.blocked(rekey::exchanging_keys(&self.session, Some(&self.channel)));
+                        unwritten = Some((data, written));
                         break;
                     }
                     Err(e) => {
@@ -591,8 +525,8 @@ impl SSHSession {
                             
                         if is_channel_closed {
                             error!("SSH channel closed unexpectedly: {}", e);
-                            // Set shutdown flag to true to terminate all tasks
-                            shutdown_flag.store(true, Ordering::SeqCst);
+                            // Signal shutdown to terminate all tasks
+                            shutdown.cancel();
                             break;
                         } else {
                             error!("SSH write error: {}", e);
@@ -610,6 +544,133 @@ impl SSHSession {
         Ok(())
     }
     
+    /// Pumps I/O between the SSH channel and the WebSocket on the Tokio reactor
+    ///
+    /// Instead of polling from a dedicated thread, the task sleeps until the
+    /// session socket becomes ready, input arrives, the PTY is resized, a
+    /// keepalive is due or shutdown is signalled. libssh2 stays in non-blocking
+    /// mode and `block_directions` tells which readiness to wait for.
+    ///
+    /// # Arguments
+    /// * `input_rx` - A receiver for data from the WebSocket
+    /// * `output_tx` - A sender for data to the WebSocket
+    ///
+    /// # Returns
+    /// * `Result<(), SSHError>` - Success or an error
+    #[cfg(feature = "reactor-io")]
+    pub async fn run_reactor_io(
+        &mut self,
+        mut input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), SSHError> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::unix::AsyncFd;
+        use super::reactor::{self, SocketFd, SshChannel};
+
+        info!("Starting SSH I/O handling on the reactor");
+
+        let socket = AsyncFd::new(SocketFd(self.session.as_raw_fd()))?;
+        let mut resize_rx = self.resize_rx.take();
+        let shutdown = self.shutdown.clone();
+        let keepalive_period = std::time::Duration::from_secs(self.connection_info.keepalive_seconds(&self.target.settings).max(1));
+        let mut keepalive = tokio::time::interval_at(tokio::time::Instant::now() + keepalive_period, keepalive_period);
+        let mut buf = [0u8; 4096];
+        let mut rekey = RekeyWatch::new(self.connection.key_exchanges().clone());
+
+        // The greeting read while recognising the device goes first
+        let greeting = std::mem::take(&mut self.greeting);
+        if !greeting.is_empty() && output_tx.send(Bytes::from(Self::clean_control_sequences(&greeting))).await.is_err() {
+            error!("Failed to send SSH output to WebSocket");
+            return Ok(());
+        }
+
+        loop {
+            // Wakes at least once per keepalive period, so a silent heartbeat means a stuck loop
+            self.heartbeat.beat();
+            
+            // libssh2 may have buffered channel data while writing, so drain before waiting
+            let mut channel = SshChannel { session: &self.session, channel: &mut self.channel };
+            if !reactor::drain_output(&mut channel, &mut buf, &output_tx, &self.heartbeat, &self.shutdown, &mut rekey).await? {
+                break;
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("Shutdown signalled, stopping I/O handling");
+                    break;
+                }
+                ready = socket.readable() => {
+                    ready?.clear_ready();
+                    self.heartbeat.heard();
+                }
+                data = input_rx.recv() => {
+                    let Some(data) = data else {
+                        debug!("WebSocket input closed, stopping I/O handling");
+                        break;
+                    };
+                    debug!("Received {} bytes from WebSocket", data.len());
+                    let mut channel = SshChannel { session: &self.session, channel: &mut self.channel };
+                    if !reactor::write_input(&mut channel, &socket, &data, &mut rekey).await? {
+                        shutdown.cancel();
+                        break;
+                    }
+                }
+                Some((rows, cols)) = recv_resize(&mut resize_rx) => {
+                    debug!("Processing resize command: {}x{}", cols, rows);
+                    let (rows, cols) = (rows.max(24), cols.max(80));
+                    let (width_px, height_px) = self.target.pixel_size(cols, rows);
+                    loop {
+                        match self.channel.request_pty_size(cols, rows, Some(width_px), Some(height_px)) {
+                            Err(e) if e.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                                reactor::wait_for_socket(self.session.block_directions(), &socket).await?;
+                            }
+                            Err(e) => {
+                                error!("Failed to resize PTY: {}", e);
+                                break;
+                            }
+                            Ok(_) => break,
+                        }
+                    }
+                }
+                // Reading resumes once the output queue has room again
+                permit = output_tx.reserve(), if output_tx.capacity() == 0 => {
+                    if permit.is_err() {
+                        break;
+                    }
+                }
+                _ = keepalive.tick() => {
+                    self.send_keepalive(&mut rekey);
+                }
+            }
+        }
+
+        info!("SSH I/O handling completed");
+        Ok(())
+    }
+
+    /// Sends a keepalive, counting it on the heartbeat for the keepalive monitor
+    ///
+    /// A keepalive that fails is not retried until the next is due; the
+    /// monitor closes the session once too many have failed in a row.
+    fn send_keepalive(&mut self, rekey: &mut RekeyWatch) {
+        debug!("Sending keepalive");
+        self.heartbeat.keepalive_due();
+        match self.session.keepalive_send() {
+            Ok(_) => {
+                rekey.resumed();
+                self.heartbeat.keepalive_sent(true);
+            }
+            // Held up by a key re-exchange or a full socket; libssh2 sends it once it can
+            Err(e) if e.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                rekey.blocked(rekey::exchanging_keys(&self.session, None));
+            }
+            Err(e) => {
+                warn!("Failed to send keepalive to {}:{}: {}", self.target.hostname, self.target.port, e);
+                self.heartbeat.keepalive_sent(false);
+            }
+        }
+    }
+
     /// Processes terminal output to handle ANSI escape sequences properly
     ///
     /// This function preserves all ANSI escape sequences that are needed for proper
@@ -621,9 +682,64 @@ impl SSHSession {
     ///
     /// # Returns
     /// * `Vec<u8>` - The processed output
-    fn clean_control_sequences(input: &[u8]) -> Vec<u8> {
+    pub(super) fn clean_control_sequences(input: &[u8]) -> Vec<u8> {
         // For SSH terminal output, we simply pass through all data unchanged
         // This ensures proper display of banners, prompts, and commands like 'top'
         input.to_vec()
     }
 }
+
+/// Reads what the device sends before anyone types: its banner and first prompt
+///
+/// Stops at the first prompt recognised, at end of file, or after `wait`; the
+/// channel is non-blocking.
+fn read_greeting(channel: &mut ssh2::Channel, wait: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + wait;
+    let mut greeting = Vec::new();
+    let mut buf = [0u8; 4096];
+    while Instant::now() < deadline && greeting.len() < MAX_GREETING_BYTES {
+        match channel.read(&mut buf) {
+            Ok(0) if channel.eof() => break,
+            Ok(n) if n > 0 => {
+                greeting.extend_from_slice(&buf[..n]);
+                if detect::from_output(&greeting).is_some() {
+                    break;
+                }
+            }
+            Ok(_) => std::thread::sleep(Duration::from_millis(20)),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(20)),
+            Err(e) => {
+                debug!("Stopped reading the greeting: {}", e);
+                break;
+            }
+        }
+    }
+    greeting
+}
+
+impl Drop for SSHSession {
+    fn drop(&mut self) {
+        // The socket closes right after this; the watchdog must not touch it from now on
+        self.heartbeat.finish();
+    }
+}
+
+/// Whether the device has sent anything not yet read from the socket
+#[cfg(not(feature = "reactor-io"))]
+fn socket_readable(fd: std::os::unix::io::RawFd) -> bool {
+    let mut poll_fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // SAFETY: a single valid pollfd, polled without waiting
+    unsafe { libc::poll(&mut poll_fd, 1, 0) > 0 && poll_fd.revents & libc::POLLIN != 0 }
+}
+
+/// libssh2's "would block" error code
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// Receives the next resize, or never resolves when no resize channel is set
+#[cfg(feature = "reactor-io")]
+async fn recv_resize(resize_rx: &mut Option<mpsc::Receiver<(u32, u32)>>) -> Option<(u32, u32)> {
+    match resize_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::source::HostMatcher;
+use crate::settings::SocksSettings;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes of RFC 1928
+pub const SUCCEEDED: u8 = 0x00;
+pub const NOT_ALLOWED: u8 = 0x02;
+pub const HOST_UNREACHABLE: u8 = 0x04;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Where a SOCKS client asked to connect
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    /// An address, or a hostname for the device to resolve
+    pub host: String,
+    pub port: u16,
+}
+
+/// The destinations a SOCKS proxy may connect to
+pub struct DestinationFilter {
+    hosts: Option<HostMatcher>,
+    ports: Vec<u16>,
+}
+
+impl DestinationFilter {
+    /// Builds the filter from `forwarding.socks`; no allowed destinations allows none
+    pub fn new(settings: &SocksSettings) -> Result<Self, String> {
+        let hosts = if settings.allowed_destinations.is_empty() {
+            None
+        } else {
+            Some(HostMatcher::new(&settings.allowed_destinations, "forwarding.socks.allowed_destinations")?)
+        };
+        Ok(Self { hosts, ports: settings.allowed_ports.clone() })
+    }
+
+    pub fn allows(&self, destination: &Destination) -> bool {
+        self.hosts.as_ref().is_some_and(|hosts| hosts.matches(&destination.host))
+            && (self.ports.is_empty() || self.ports.contains(&destination.port))
+    }
+}
+
+/// Reads a client's greeting and CONNECT request
+///
+/// Only connections without authentication and the CONNECT command are
+/// offered; anything else is answered with the matching error and refused.
+/// The reply to the CONNECT request is left to the caller, once it knows
+/// whether the connection could be made.
+pub fn handshake<S: Read + Write>(stream: &mut S) -> std::io::Result<Destination> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("SOCKS version {} is not supported", header[0])));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD])?;
+        return Err(Error::new(ErrorKind::PermissionDenied, "client requires authentication"));
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION])?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request)?;
+    if request[0] != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, "malformed SOCKS request"));
+    }
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets)?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets)?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length)?;
+            let mut name = vec![0u8; length[0] as usize];
+            stream.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| Error::new(ErrorKind::InvalidData, "hostname is not UTF-8"))?
+        }
+        _ => {
+            stream.write_all(&reply(ADDRESS_NOT_SUPPORTED))?;
+            return Err(Error::new(ErrorKind::InvalidData, "address type is not supported"));
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port)?;
+    if request[1] != CONNECT {
+        stream.write_all(&reply(COMMAND_NOT_SUPPORTED))?;
+        return Err(Error::new(ErrorKind::Unsupported, format!("SOCKS command {} is not supported", request[1])));
+    }
+    // A bracketed IPv6 literal sent as a hostname is taken as the address
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    Ok(Destination { host, port: u16::from_be_bytes(port) })
+}
+
+/// The reply to a CONNECT request; the bound address is not known through the device, so it is zero
+pub fn reply(code: u8) -> [u8; 10] {
+    [VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A client's bytes to read, and what was written back
+    struct Exchange {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Exchange {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Exchange {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn exchange(input: &[u8]) -> Exchange {
+        Exchange { input: Cursor::new(input.to_vec()), output: Vec::new() }
+    }
+
+    #[test]
+    fn test_handshake_and_filter() {
+        let mut client = exchange(b"\x05\x01\x00\x05\x01\x00\x03\x0bcore-1.corp\x00\x16");
+        let destination = handshake(&mut client).unwrap();
+        assert_eq!(destination, Destination { host: "core-1.corp".to_string(), port: 22 });
+        assert_eq!(client.output, vec![5, 0]);
+
+        let mut client = exchange(b"\x05\x01\x00\x05\x01\x00\x01\x0a\x00\x00\x05\x01\xbb");
+        assert_eq!(handshake(&mut client).unwrap(), Destination { host: "10.0.0.5".to_string(), port: 443 });
+
+        // Only unauthenticated CONNECT requests are served
+        let mut client = exchange(b"\x05\x01\x02");
+        assert!(handshake(&mut client).is_err());
+        assert_eq!(client.output, vec![5, 0xff]);
+        let mut client = exchange(b"\x05\x01\x00\x05\x03\x00\x01\x0a\x00\x00\x05\x00\x35");
+        assert!(handshake(&mut client).is_err());
+        assert_eq!(client.output[3], COMMAND_NOT_SUPPORTED);
+
+        let settings = SocksSettings {
+            allowed_destinations: vec!["10.0.0.0/24".to_string(), "*.corp".to_string()],
+            allowed_ports: vec![22, 443],
+            ..SocksSettings::default()
+        };
+        let filter = DestinationFilter::new(&settings).unwrap();
+        assert!(filter.allows(&Destination { host: "10.0.0.5".to_string(), port: 443 }));
+        assert!(filter.allows(&Destination { host: "Core-1.CORP".to_string(), port: 22 }));
+        assert!(!filter.allows(&Destination { host: "10.0.1.5".to_string(), port: 443 }));
+        assert!(!filter.allows(&Destination { host: "10.0.0.5".to_string(), port: 80 }));
+        assert!(!DestinationFilter::new(&SocksSettings::default()).unwrap()
+            .allows(&Destination { host: "10.0.0.5".to_string(), port: 443 }));
+    }
+}
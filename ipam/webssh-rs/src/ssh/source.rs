@@ -0,0 +1,239 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+use tracing::debug;
+
+use super::host;
+use crate::settings::{OutboundSettings, SourceGroup};
+
+/// Where an outbound connection to a device was made from, recorded for session metadata
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SourceInfo {
+    /// Local address of the connection
+    pub address: Option<String>,
+    /// Interface or VRF the socket was bound to
+    pub interface: Option<String>,
+    /// The `ssh.outbound.groups` entry that chose the source, if any
+    pub group: Option<String>,
+}
+
+/// Hosts given as addresses or CIDR ranges, hostnames, or `*.suffix` patterns
+pub struct HostMatcher {
+    networks: Vec<IpNet>,
+    hostnames: Vec<String>,
+    any_host: bool,
+}
+
+impl HostMatcher {
+    /// Parses host patterns; an empty list matches any host
+    ///
+    /// # Arguments
+    /// * `hosts` - The patterns
+    /// * `group` - What the patterns belong to, for error messages
+    pub fn new(hosts: &[String], group: &str) -> Result<Self, String> {
+        let mut networks = Vec::new();
+        let mut hostnames = Vec::new();
+        for host in hosts {
+            match host.parse::<IpNet>().or_else(|_| host.parse::<IpAddr>().map(IpNet::from)) {
+                Ok(network) => networks.push(network),
+                Err(_) if host.contains('/') => {
+                    return Err(format!("Invalid CIDR range '{}' in {}", host, group));
+                }
+                Err(_) => hostnames.push(host.to_lowercase()),
+            }
+        }
+        Ok(Self { networks, hostnames, any_host: hosts.is_empty() })
+    }
+
+    pub fn matches(&self, hostname: &str) -> bool {
+        let hostname = hostname.to_lowercase();
+        self.any_host
+            || host::ip(&hostname).is_some_and(|ip| self.networks.iter().any(|network| network.contains(&ip)))
+            || self.hostnames.iter().any(|pattern| match pattern.strip_prefix('*') {
+                Some(suffix) => hostname.ends_with(suffix),
+                None => *pattern == hostname,
+            })
+    }
+}
+
+/// The source address and interface chosen for a device
+#[derive(Debug, Default, PartialEq)]
+struct Source {
+    address: Option<IpAddr>,
+    interface: Option<String>,
+    group: Option<String>,
+}
+
+/// Checks the outbound settings, so mistakes are reported at startup rather than on connect
+pub fn validate(settings: &OutboundSettings) -> Result<(), String> {
+    parse_address(settings.source_address.as_deref(), "ssh.outbound.source_address")?;
+    for group in &settings.groups {
+        HostMatcher::new(&group.hosts, &format!("outbound group '{}'", group.name))?;
+        parse_address(group.source_address.as_deref(), &format!("outbound group '{}'", group.name))?;
+    }
+    if !cfg!(target_os = "linux") && (settings.interface.is_some() || settings.groups.iter().any(|group| group.interface.is_some())) {
+        return Err("Binding outbound connections to an interface is only supported on Linux".to_string());
+    }
+    Ok(())
+}
+
+fn parse_address(address: Option<&str>, context: &str) -> Result<Option<IpAddr>, String> {
+    match address.map(str::trim).filter(|address| !address.is_empty()) {
+        Some(address) => address.parse().map(Some)
+            .map_err(|_| format!("Invalid source address '{}' in {}", address, context)),
+        None => Ok(None),
+    }
+}
+
+/// The source for a device: that of the first group it belongs to, or the global one
+///
+/// A group setting only an address or only an interface takes the other from
+/// the global settings.
+fn select(settings: &OutboundSettings, hostname: &str, device_type: Option<&str>) -> Result<Source, String> {
+    let group = settings.groups.iter().find(|group| group_matches(group, hostname, device_type));
+    let address = group.and_then(|group| group.source_address.as_deref()).or(settings.source_address.as_deref());
+    let interface = group.and_then(|group| group.interface.clone()).or(settings.interface.clone());
+    Ok(Source {
+        address: parse_address(address, "ssh.outbound")?,
+        interface: interface.filter(|interface| !interface.is_empty()),
+        group: group.map(|group| group.name.clone()),
+    })
+}
+
+/// The source chosen for a device, for connections made by another program, e.g. `ssh -b`
+pub fn choose(settings: &OutboundSettings, hostname: &str, device_type: Option<&str>) -> std::io::Result<SourceInfo> {
+    let source = select(settings, hostname, device_type).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(SourceInfo {
+        address: source.address.map(|address| address.to_string()),
+        interface: source.interface,
+        group: source.group,
+    })
+}
+
+fn group_matches(group: &SourceGroup, hostname: &str, device_type: Option<&str>) -> bool {
+    let hosts_match = HostMatcher::new(&group.hosts, &group.name).is_ok_and(|hosts| hosts.matches(hostname));
+    let type_matches = group.device_types.is_empty()
+        || device_type.is_some_and(|device_type| group.device_types.iter().any(|wanted| wanted.eq_ignore_ascii_case(device_type)));
+    hosts_match && type_matches
+}
+
+/// Opens a TCP connection to a device from the source configured for it
+///
+/// Each resolved address is tried in turn; with a source address, only those
+/// of its family are. A link-local address is reached through the interface
+/// its zone names, e.g. `fe80::1%mgmt0`.
+///
+/// # Arguments
+/// * `settings` - The outbound settings
+/// * `hostname`, `port` - The device
+/// * `device_type` - The device's type, for groups matching on it
+/// * `timeout` - Limit on each connection attempt, if any
+///
+/// # Returns
+/// * `std::io::Result<(TcpStream, SourceInfo)>` - The connection, and where it was made from
+pub fn connect(
+    settings: &OutboundSettings,
+    hostname: &str,
+    port: u16,
+    device_type: Option<&str>,
+    timeout: Option<Duration>,
+) -> std::io::Result<(TcpStream, SourceInfo)> {
+    let source = select(settings, hostname, device_type).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut last_error = None;
+    for address in host::resolve(hostname, port)? {
+        if source.address.is_some_and(|source| source.is_ipv4() != address.is_ipv4()) {
+            continue;
+        }
+        match connect_from(&source, address, timeout) {
+            Ok(stream) => {
+                let info = SourceInfo {
+                    address: stream.local_addr().ok().map(|local| local.ip().to_string()),
+                    interface: source.interface.clone(),
+                    group: source.group.clone(),
+                };
+                debug!("Connected to {} from {:?}", address, info);
+                return Ok((stream, info));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| match source.address {
+        Some(source) => Error::new(ErrorKind::AddrNotAvailable,
+                                   format!("{} has no address reachable from source {}", hostname, source)),
+        None => Error::new(ErrorKind::NotFound, format!("No address for {}", hostname)),
+    }))
+}
+
+fn connect_from(source: &Source, address: SocketAddr, timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(interface) = &source.interface {
+        bind_device(&socket, interface)?;
+    }
+    if let Some(source) = source.address {
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+    }
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&address.into(), timeout)?,
+        None => socket.connect(&address.into())?,
+    }
+    Ok(socket.into())
+}
+
+/// Binds the socket to an interface or VRF device (`SO_BINDTODEVICE`)
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+        .map_err(|e| Error::new(e.kind(), format!("Cannot bind to interface {}: {}", interface, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, interface: &str) -> std::io::Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, format!("Cannot bind to interface {} on this platform", interface)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_is_chosen_by_group() {
+        let settings = OutboundSettings {
+            source_address: Some("192.0.2.10".to_string()),
+            interface: None,
+            groups: vec![
+                SourceGroup {
+                    name: "oob".to_string(),
+                    hosts: vec!["10.99.0.0/16".to_string(), "*.oob.example.com".to_string()],
+                    device_types: Vec::new(),
+                    source_address: None,
+                    interface: Some("vrf-mgmt".to_string()),
+                },
+                SourceGroup {
+                    name: "ups".to_string(),
+                    hosts: Vec::new(),
+                    device_types: vec!["ups".to_string()],
+                    source_address: Some("192.0.2.20".to_string()),
+                    interface: None,
+                },
+            ],
+        };
+        assert!(validate(&settings).is_ok());
+
+        let oob = select(&settings, "Core-1.OOB.example.com", None).unwrap();
+        assert_eq!(oob, Source {
+            address: Some("192.0.2.10".parse().unwrap()),
+            interface: Some("vrf-mgmt".to_string()),
+            group: Some("oob".to_string()),
+        });
+        assert_eq!(select(&settings, "10.99.4.1", Some("ups")).unwrap().group.as_deref(), Some("oob"));
+        assert_eq!(select(&settings, "10.1.4.1", Some("UPS")).unwrap().address, Some("192.0.2.20".parse().unwrap()));
+        assert_eq!(select(&settings, "10.1.4.1", None).unwrap().group, None);
+
+        let mut broken = settings.clone();
+        broken.groups[0].hosts.push("10.0.0.0/33".to_string());
+        assert!(validate(&broken).is_err());
+    }
+}
@@ -0,0 +1,758 @@
+use serde::{Deserialize, Serialize};
+use socket2::Socket;
+use ssh2::{KeyboardInteractivePrompt, Prompt, Session};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::{error, info, debug, warn};
+
+use crate::settings::{CompressionMode, DeviceQuirks, PtyModeValue, SSHSettings};
+use crate::host_keys::HostKeyCheck;
+use super::agent;
+use super::pkcs11::{self, Pkcs11Login};
+use super::quirks;
+use super::detect::DeviceKind;
+use super::error::SSHError;
+use super::keys::PrivateKey;
+use super::link::SlowLinkAdaptation;
+use super::source::{self, SourceInfo};
+use super::tunnel;
+
+/// How the terminal reaches the device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Ssh,
+    /// Cleartext, for legacy devices without SSH; only interactive shells are available
+    Telnet,
+}
+
+impl Protocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Protocol::Ssh => 22,
+            Protocol::Telnet => 23,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Ssh => "ssh",
+            Protocol::Telnet => "telnet",
+        }
+    }
+}
+
+/// Size of a terminal in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalSize {
+    pub cols: u32,
+    pub rows: u32,
+}
+
+/// PTY modes and pixel size a connect request asks for, over those of the settings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PtyRequest {
+    /// Modes by termios name, already checked to be ones SSH can set
+    pub modes: BTreeMap<String, PtyModeValue>,
+    pub width_px: Option<u32>,
+    pub height_px: Option<u32>,
+}
+
+/// Facts about an established connection, recorded for session metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    /// Time taken to establish the TCP connection, used as an RTT estimate
+    pub tcp_connect_ms: u64,
+    pub compression: CompressionInfo,
+    /// TERM the shell's terminal was opened with, once it is open
+    pub terminal_type: Option<String>,
+    /// Size the shell's terminal was opened with, once it is open
+    #[serde(default)]
+    pub terminal_size: Option<TerminalSize>,
+    /// Where the connection was made from; empty when tunnelled through a jump host
+    #[serde(default)]
+    pub source: SourceInfo,
+    /// Timeouts relaxed because the link was measured slow
+    #[serde(default)]
+    pub slow_link: Option<SlowLinkAdaptation>,
+    /// Kind of device recognised from the banner and first prompt, when no type was given
+    #[serde(default)]
+    pub device_type: Option<DeviceKind>,
+    /// Quirks of the device's profile applied in setting up the session
+    #[serde(default)]
+    pub quirks: Vec<String>,
+}
+
+impl ConnectionInfo {
+    /// The keepalive interval of the connection: the configured one, or as relaxed for a slow link
+    pub fn keepalive_seconds(&self, settings: &SSHSettings) -> u64 {
+        self.slow_link.as_ref().map_or(settings.connection.keepalive_seconds, |adaptation| adaptation.keepalive_seconds)
+    }
+}
+
+/// Requested and negotiated SSH transport compression
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionInfo {
+    pub mode: CompressionMode,
+    /// Whether compression was offered to the server
+    pub requested: bool,
+    /// Negotiated methods, e.g. "zlib@openssh.com" or "none"
+    pub client_to_server: Option<String>,
+    pub server_to_client: Option<String>,
+}
+
+/// Everything needed to open and authenticate an SSH connection to a device
+///
+/// An interactive `SSHSession` keeps its target so that auxiliary connections
+/// (file transfers, clones) can be established with the same parameters
+/// without touching the channel owned by the I/O thread.
+#[derive(Debug, Clone)]
+pub struct ConnectionTarget {
+    pub hostname: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    pub private_key: Option<String>,
+    /// Passphrase of an encrypted private key
+    pub private_key_passphrase: Option<String>,
+    /// OpenSSH certificate signed for `private_key`, presented in place of its public key
+    pub certificate: Option<String>,
+    pub device_type: Option<String>,
+    pub compression: CompressionMode,
+    /// Bastion the connection is tunnelled through, if the device is not directly reachable
+    pub jump_host: Option<Box<ConnectionTarget>>,
+    /// The device requires keyboard-interactive authentication (e.g. OTP challenges),
+    /// so it can only be connected to with a prompter
+    pub keyboard_interactive: bool,
+    /// Authenticate with the gateway host's ssh-agent rather than a password or key
+    pub agent: bool,
+    /// Authenticate with a key held in an HSM or smartcard, once the user has given its PIN
+    pub pkcs11: Option<Pkcs11Login>,
+    /// Authenticate with the gateway's Kerberos ticket, through the host's OpenSSH client
+    pub gssapi: bool,
+    /// TERM for the shell's terminal in place of the configured ones, from the
+    /// connect request, the device profile or an earlier downgrade
+    pub terminal_type: Option<String>,
+    /// Size of the shell's terminal in place of the configured default, so the
+    /// first screen is drawn at the size of the client's window
+    pub terminal_size: Option<TerminalSize>,
+    /// Environment variables set on the shell channel before it starts, e.g. LANG
+    pub environment: Vec<(String, String)>,
+    /// PTY modes and pixel size for the shell's terminal
+    pub pty: PtyRequest,
+    /// Holds the connection until the device's host key is pinned or confirmed
+    pub host_key_check: Option<HostKeyCheck>,
+    /// Accommodations for an old device, from its profile
+    pub quirks: DeviceQuirks,
+    pub protocol: Protocol,
+    pub settings: SSHSettings,
+}
+
+/// Lets a `dyn KeyboardInteractivePrompt` be passed where ssh2 expects a sized prompter
+struct DynPrompter<'p> {
+    inner: &'p mut dyn KeyboardInteractivePrompt,
+    // Whether the user answered the last prompts, rather than letting them time out
+    answered: bool,
+}
+
+impl KeyboardInteractivePrompt for DynPrompter<'_> {
+    fn prompt<'a>(&mut self, username: &str, instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        let answers = self.inner.prompt(username, instructions, prompts);
+        if !prompts.is_empty() {
+            self.answered = !answers.is_empty();
+        }
+        answers
+    }
+}
+
+/// Times the user is prompted before authentication is given up, as OpenSSH does
+const PROMPT_ATTEMPTS: u32 = 3;
+
+/// Outcome of checking credentials against a device
+#[derive(Debug)]
+pub struct CredentialCheck {
+    /// Authentication methods the server advertises for the user
+    pub auth_methods: Vec<String>,
+    /// The server's identification string, e.g. "SSH-2.0-OpenSSH_9.6"
+    pub server_banner: Option<String>,
+    pub result: Result<(), SSHError>,
+}
+
+/// A bastion as given in a connect request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JumpHost {
+    pub hostname: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    pub private_key: Option<String>,
+    pub private_key_passphrase: Option<String>,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+impl JumpHost {
+    /// Builds the connection parameters for the bastion hop, applying the jump host timeouts
+    pub fn to_target(&self, settings: &SSHSettings) -> ConnectionTarget {
+        let mut settings = settings.clone();
+        settings.connection.timeout_seconds = settings.jump_host.timeout_seconds;
+        ConnectionTarget {
+            hostname: self.hostname.clone(),
+            port: self.port,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            private_key: self.private_key.clone(),
+            private_key_passphrase: self.private_key_passphrase.clone(),
+            certificate: None,
+            device_type: None,
+            compression: CompressionMode::Off,
+            jump_host: None,
+            keyboard_interactive: false,
+            agent: false,
+            pkcs11: None,
+            gssapi: false,
+            terminal_type: None,
+            terminal_size: None,
+            environment: Vec::new(),
+            pty: PtyRequest::default(),
+            host_key_check: None,
+            quirks: DeviceQuirks::default(),
+            protocol: Protocol::Ssh,
+            settings,
+        }
+    }
+}
+
+impl ConnectionTarget {
+    /// Whether both targets log in to the same device as the same user with
+    /// the same credentials, so a connection made for one can serve the other
+    ///
+    /// Keyboard-interactive logins never match: their answers (e.g. one-time
+    /// codes) cannot be compared.
+    pub fn same_login(&self, other: &ConnectionTarget) -> bool {
+        !self.keyboard_interactive && !other.keyboard_interactive
+            && self.protocol == other.protocol
+            && self.hostname.eq_ignore_ascii_case(&other.hostname)
+            && self.port == other.port
+            && self.username == other.username
+            && self.agent == other.agent
+            && self.pkcs11 == other.pkcs11
+            && self.gssapi == other.gssapi
+            && self.password == other.password
+            && self.private_key == other.private_key
+            && self.certificate == other.certificate
+            && self.jump_host.as_ref().map(|jump| (&jump.hostname, jump.port, &jump.username))
+                == other.jump_host.as_ref().map(|jump| (&jump.hostname, jump.port, &jump.username))
+    }
+
+    /// The settings a shell is opened with, its terminal type and size overridden if the target says so
+    pub fn shell_settings(&self) -> SSHSettings {
+        let mut settings = self.settings.clone();
+        if let Some(terminal_type) = &self.terminal_type {
+            settings.terminal.standard_terminal_type = terminal_type.clone();
+            settings.terminal.linux_terminal_type = terminal_type.clone();
+        }
+        if let Some(size) = self.terminal_size {
+            settings.terminal.default_cols = size.cols;
+            settings.terminal.default_rows = size.rows;
+        }
+        settings.terminal.pty_modes.extend(self.pty.modes.iter().map(|(name, value)| (name.clone(), *value)));
+        if self.quirks.no_pty_modes {
+            settings.terminal.pty_modes.clear();
+        }
+        settings.terminal.width_px = self.pty.width_px.unwrap_or(settings.terminal.width_px);
+        settings.terminal.height_px = self.pty.height_px.unwrap_or(settings.terminal.height_px);
+        settings
+    }
+
+    /// The terminal's size in pixels once resized to the given size in characters
+    ///
+    /// The shell's terminal opens at a known size in pixels, if at all; each
+    /// character keeps the pixel size it had then. (0, 0) when not known.
+    pub fn pixel_size(&self, cols: u32, rows: u32) -> (u32, u32) {
+        let terminal = &self.settings.terminal;
+        let (initial_cols, initial_rows) = self.terminal_size
+            .map_or((terminal.default_cols, terminal.default_rows), |size| (size.cols, size.rows));
+        let width_px = self.pty.width_px.unwrap_or(terminal.width_px);
+        let height_px = self.pty.height_px.unwrap_or(terminal.height_px);
+        (width_px * cols / initial_cols.max(1), height_px * rows / initial_rows.max(1))
+    }
+
+    /// Opens a TCP connection, performs the SSH handshake and authenticates
+    ///
+    /// The returned session is in blocking mode with the general session
+    /// timeout applied; callers adjust timeouts and blocking mode as needed.
+    ///
+    /// # Returns
+    /// * `Result<Session, SSHError>` - An authenticated SSH session or an error
+    pub fn connect(&self) -> Result<Session, SSHError> {
+        self.connect_with_info().map(|(session, _)| session)
+    }
+
+    /// Like `connect`, but also reports what was measured and negotiated
+    ///
+    /// # Returns
+    /// * `Result<(Session, ConnectionInfo), SSHError>` - An authenticated SSH session and its connection facts
+    pub fn connect_with_info(&self) -> Result<(Session, ConnectionInfo), SSHError> {
+        if self.keyboard_interactive {
+            return Err(SSHError::Authentication(
+                "Device requires keyboard-interactive authentication; connect again to answer its prompts".into()
+            ));
+        }
+        self.establish(None)
+    }
+
+    /// Like `connect_with_info`, but authenticates with keyboard-interactive,
+    /// passing the server's prompts to `prompter`
+    ///
+    /// A configured password is tried first, for servers that ask for both a
+    /// password and a challenge response.
+    ///
+    /// # Returns
+    /// * `Result<(Session, ConnectionInfo), SSHError>` - An authenticated SSH session and its connection facts
+    pub fn connect_interactive(&self, prompter: &mut dyn KeyboardInteractivePrompt) -> Result<(Session, ConnectionInfo), SSHError> {
+        self.establish(Some(prompter))
+    }
+
+    /// Connects and completes the SSH handshake, without authenticating
+    ///
+    /// A link slow to connect or to complete the key exchange has its session
+    /// timeout and keepalive interval relaxed.
+    ///
+    /// # Returns
+    /// * `Result<(Session, bool, Duration, SourceInfo, Option<SlowLinkAdaptation>), SSHError>` - The session,
+    ///   whether compression was requested, the TCP connect time, where the connection was made from
+    ///   and the timeouts relaxed for a slow link
+    fn handshake(&self) -> Result<(Session, bool, Duration, SourceInfo, Option<SlowLinkAdaptation>), SSHError> {
+        info!("Connecting to SSH server {}:{}", self.hostname, self.port);
+        
+        let (mut session, compress, rtt, source) = self.open_transport(None)?;
+        
+        debug!("Starting SSH handshake");
+        
+        // Log available methods before handshake
+        debug!("Configured KEX methods: {}", self.settings.crypto.kex_algorithms);
+        debug!("Configured host key methods: {}", self.settings.crypto.host_key_algorithms);
+        debug!("Configured client->server encryption methods: {}", self.settings.crypto.encryption_client_to_server);
+        debug!("Configured server->client encryption methods: {}", self.settings.crypto.encryption_server_to_client);
+        
+        // Implement retry mechanism for handshake with banner issues
+        let mut retry_count = 0;
+        let max_retries = 3;
+        let mut handshake_started;
+        
+        loop {
+            handshake_started = Instant::now();
+            match session.handshake() {
+                Ok(_) => {
+                    if retry_count > 0 {
+                        debug!("SSH handshake completed successfully after {} retries", retry_count);
+                    } else {
+                        debug!("SSH handshake completed successfully");
+                    }
+                    break;
+                },
+                Err(e) => {
+                    // Check if this is a banner-related error
+                    let is_banner_error = e.code() == ssh2::ErrorCode::Session(-13) && 
+                                          e.message().contains("banner");
+                    
+                    if is_banner_error && retry_count < max_retries {
+                        retry_count += 1;
+                        error!("SSH handshake failed due to banner issue (attempt {}/{}): {}", 
+                               retry_count, max_retries, e);
+                        
+                        // For Cisco XR devices that have banner issues, we'll retry after a short delay
+                        debug!("Retrying handshake after banner issue...");
+                        std::thread::sleep(std::time::Duration::from_millis(500));
+                        
+                        // Create a new session for the retry
+                        drop(session);
+                        session = self.open_transport(Some(compress))?.0;
+                        
+                        continue;
+                    } else {
+                        // For non-banner errors or if we've exhausted retries
+                        if is_banner_error {
+                            error!("SSH handshake failed due to banner issue after {} retries: {}", max_retries, e);
+                        } else {
+                            error!("SSH handshake failed: {}", e);
+                        }
+                        error!("This could be due to incompatible encryption algorithms or network issues");
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+
+        if let Some(check) = &self.host_key_check {
+            check.verify(&self.hostname, self.port, &session)?;
+        }
+
+        let handshake_time = handshake_started.elapsed();
+        debug!("SSH handshake took {} ms", handshake_time.as_millis());
+        let slow_link = SlowLinkAdaptation::assess(&self.settings.connection, rtt, Some(handshake_time));
+        let keepalive_seconds = match &slow_link {
+            Some(adaptation) => {
+                info!("Slow link to {}:{} (TCP connect {} ms, handshake {} ms): timeouts relaxed to {} s, keepalive to {} s",
+                      self.hostname, self.port, adaptation.rtt_ms, handshake_time.as_millis(),
+                      adaptation.timeout_seconds, adaptation.keepalive_seconds);
+                session.set_timeout((adaptation.timeout_seconds * 1000) as u32);
+                adaptation.keepalive_seconds
+            }
+            None => self.settings.connection.keepalive_seconds,
+        };
+
+        // Configure session
+        session.set_blocking(true);
+        session.set_keepalive(true, keepalive_seconds as u32);
+
+        Ok((session, compress, rtt, source, slow_link))
+    }
+
+    /// Connects, records the advertised authentication methods and tries the
+    /// credentials once, then disconnects
+    ///
+    /// Unlike `connect`, a failed attempt is not retried, so routine checks
+    /// do not lock out accounts.
+    ///
+    /// # Returns
+    /// * `Result<CredentialCheck, SSHError>` - The check, or an error if the device could not be reached
+    pub fn check_credentials(&self) -> Result<CredentialCheck, SSHError> {
+        let (session, ..) = self.handshake()?;
+        session.set_blocking(true);
+
+        let auth_methods = session.auth_methods(&self.username)
+            .map(|methods| methods.split(',').map(String::from).collect())
+            .unwrap_or_default();
+        let server_banner = session.banner().map(String::from);
+        debug!("Server advertises authentication methods {:?} for {}", auth_methods, self.username);
+
+        let attempt = if session.authenticated() {
+            Ok(())
+        } else if self.agent {
+            agent::userauth(&session, &self.username, &self.settings.agent)
+        } else if let Some(login) = &self.pkcs11 {
+            pkcs11::userauth(&session, &self.username, login, &self.settings.pkcs11)
+        } else if let Some(password) = self.password.as_deref() {
+            session.userauth_password(&self.username, password)
+                .map_err(|e| SSHError::Authentication(format!("Authentication failed: {}", e)))
+        } else if let Some(key_data) = self.private_key.as_deref() {
+            self.userauth_private_key(&session, key_data)
+        } else {
+            return Ok(CredentialCheck {
+                auth_methods,
+                server_banner,
+                result: Err(SSHError::Authentication("No authentication method provided".into())),
+            });
+        };
+        let result = match attempt {
+            Ok(_) if session.authenticated() => Ok(()),
+            Ok(_) => Err(SSHError::Authentication("Authentication failed".into())),
+            Err(e) => Err(e),
+        };
+
+        let _ = session.disconnect(None, "Credential check complete", None);
+        Ok(CredentialCheck { auth_methods, server_banner, result })
+    }
+
+    fn establish(&self, prompter: Option<&mut dyn KeyboardInteractivePrompt>) -> Result<(Session, ConnectionInfo), SSHError> {
+        let (mut session, compress, rtt, source, slow_link) = self.handshake()?;
+        let mut connection = self.settings.connection.clone();
+        if let Some(adaptation) = &slow_link {
+            adaptation.apply(&mut connection);
+        }
+
+        // Authenticate with retry mechanism
+        if let Some(prompter) = prompter {
+            info!("Authenticating with keyboard-interactive for user {}", self.username);
+            if let Some(password) = self.password.as_deref() {
+                if let Err(e) = session.userauth_password(&self.username, password) {
+                    debug!("Password step before keyboard-interactive did not authenticate: {}", e);
+                }
+            }
+            if !session.authenticated() {
+                self.userauth_prompted(&session, prompter)?;
+            }
+        } else if self.agent {
+            info!("Authenticating with the gateway's ssh-agent for user {}", self.username);
+            agent::userauth(&session, &self.username, &self.settings.agent)?;
+        } else if let Some(login) = &self.pkcs11 {
+            info!("Authenticating with a PKCS#11 {} for user {}", login.key, self.username);
+            pkcs11::userauth(&session, &self.username, login, &self.settings.pkcs11)?;
+        } else if let Some(password) = self.password.as_deref() {
+            info!("Authenticating with password for user {}", self.username);
+            
+            // Implement retry for password authentication
+            let mut auth_retry_count = 0;
+            let max_auth_retries = 3;
+            let mut auth_success = false;
+            
+            while auth_retry_count < max_auth_retries && !auth_success {
+                match session.userauth_password(&self.username, password) {
+                    Ok(_) => {
+                        auth_success = true;
+                        if auth_retry_count > 0 {
+                            debug!("Password authentication succeeded after {} retries", auth_retry_count);
+                        } else {
+                            debug!("Password authentication succeeded");
+                        }
+                    },
+                    Err(e) => {
+                        auth_retry_count += 1;
+                        error!("Password authentication failed (attempt {}/{}): {}", 
+                               auth_retry_count, max_auth_retries, e);
+                        
+                        if auth_retry_count < max_auth_retries {
+                            debug!("Retrying password authentication after failure...");
+                            std::thread::sleep(std::time::Duration::from_millis(500));
+                            
+                            // For certain authentication errors, we may need to recreate the session
+                            if e.code() == ssh2::ErrorCode::Session(-43) { // Waiting for password response
+                                debug!("Recreating SSH session after authentication error");
+                                
+                                // Create a new session for the retry
+                                drop(session);
+                                session = self.open_transport(Some(compress))?.0;
+                                session.set_blocking(true);
+                                session.set_timeout((connection.timeout_seconds * 1000) as u32);
+                                session.set_keepalive(true, connection.keepalive_seconds as u32);
+                                
+                                // Perform handshake again
+                                debug!("Performing handshake after session recreation");
+                                match session.handshake() {
+                                    Ok(_) => {
+                                        debug!("SSH handshake completed successfully after session recreation");
+                                        if let Some(check) = &self.host_key_check {
+                                            check.verify(&self.hostname, self.port, &session)?;
+                                        }
+                                    }
+                                    Err(handshake_err) => {
+                                        error!("SSH handshake failed after session recreation: {}", handshake_err);
+                                        return Err(handshake_err.into());
+                                    }
+                                }
+                            }
+                            continue;
+                        } else {
+                            return Err(SSHError::Authentication(format!("Password authentication failed after {} attempts: {}", max_auth_retries, e)));
+                        }
+                    }
+                }
+            }
+            
+            if !auth_success {
+                return Err(SSHError::Authentication(format!("Password authentication failed after {} attempts", max_auth_retries)));
+            }
+        } else if let Some(key_data) = self.private_key.as_deref() {
+            info!("Authenticating with private key for user {}", self.username);
+            self.userauth_private_key(&session, key_data)?;
+            debug!("Private key authentication successful");
+        } else {
+            return Err(SSHError::Authentication("No authentication method provided".into()));
+        }
+
+        if !session.authenticated() {
+            return Err(SSHError::Authentication("Authentication failed".into()));
+        }
+        debug!("Authentication successful");
+
+        let compression = CompressionInfo {
+            mode: self.compression,
+            requested: compress,
+            client_to_server: session.methods(ssh2::MethodType::CompCs).map(String::from),
+            server_to_client: session.methods(ssh2::MethodType::CompSc).map(String::from),
+        };
+        debug!("Negotiated compression: {:?}", compression);
+        let info = ConnectionInfo {
+            tcp_connect_ms: rtt.as_millis() as u64,
+            compression,
+            terminal_type: None,
+            terminal_size: None,
+            source,
+            slow_link,
+            device_type: None,
+            quirks: quirks::names(&self.quirks),
+        };
+        if !info.quirks.is_empty() {
+            info!("Applying quirks to {}:{}: {}", self.hostname, self.port, info.quirks.join(", "));
+        }
+
+        Ok((session, info))
+    }
+
+    /// Authenticates with the user's answers to prompts relayed to them
+    ///
+    /// The server's keyboard-interactive prompts are relayed as they are. A
+    /// server that only takes passwords, when none was given, has the password
+    /// asked for with a prompt of the gateway's own. A wrong answer is asked
+    /// again, up to `PROMPT_ATTEMPTS` times, unless the user gave none.
+    fn userauth_prompted(&self, session: &Session, prompter: &mut dyn KeyboardInteractivePrompt) -> Result<(), SSHError> {
+        let methods = session.auth_methods(&self.username).unwrap_or_default().to_string();
+        if session.authenticated() {
+            return Ok(());
+        }
+        let offers = |method: &str| methods.split(',').any(|offered| offered == method);
+        let ask_password = self.password.is_none() && offers("password") && !offers("keyboard-interactive");
+        let kind = if ask_password { "Password" } else { "Keyboard-interactive" };
+        let mut prompter = DynPrompter { inner: prompter, answered: false };
+        let mut attempt = 1;
+        loop {
+            prompter.answered = false;
+            let result = if ask_password {
+                let prompt = Prompt { text: format!("{}@{}'s password: ", self.username, self.hostname).into(), echo: false };
+                let answer = prompter.prompt(&self.username, "", &[prompt]).into_iter().next();
+                match answer.filter(|_| prompter.answered) {
+                    Some(password) => session.userauth_password(&self.username, &password),
+                    None => return Err(SSHError::Authentication("No password was given".into())),
+                }
+            } else {
+                session.userauth_keyboard_interactive(&self.username, &mut prompter)
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if prompter.answered && attempt < PROMPT_ATTEMPTS => {
+                    warn!("{} authentication failed (attempt {}/{}): {}", kind, attempt, PROMPT_ATTEMPTS, e);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("{} authentication failed: {}", kind, e);
+                    return Err(SSHError::Authentication(format!("{} authentication failed: {}", kind, e)));
+                }
+            }
+        }
+    }
+
+    /// Authenticates with a private key in any format libssh2 can read
+    ///
+    /// The key is rewritten in its canonical PEM layout first; encrypted keys
+    /// are decrypted by libssh2 with `private_key_passphrase`.
+    fn userauth_private_key(&self, session: &Session, key_data: &str) -> Result<(), SSHError> {
+        let key = PrivateKey::parse(key_data).map_err(|e| {
+            error!("Unusable private key for user {}: {}", self.username, e);
+            SSHError::PrivateKey(e)
+        })?;
+        let passphrase = self.private_key_passphrase.as_deref().filter(|passphrase| !passphrase.is_empty());
+        let encrypted = key.is_encrypted();
+        if encrypted && passphrase.is_none() {
+            return Err(SSHError::PassphraseRequired);
+        }
+        debug!("Using {} key{}{}", key.label, if encrypted { " (encrypted)" } else { "" },
+               if self.certificate.is_some() { " with a certificate" } else { "" });
+
+        session.userauth_pubkey_memory(&self.username, self.certificate.as_deref(), &key.to_pem(), passphrase)
+            .map_err(|e| {
+                error!("Private key authentication failed: {}", e);
+                SSHError::Authentication(format!("Private key authentication failed: {}", e))
+            })
+    }
+
+    /// Connects TCP and creates an SSH session configured from settings, ready for the handshake
+    ///
+    /// When `compress` is not given it is derived from the compression mode; in auto
+    /// mode the TCP connect time serves as an RTT estimate, compressing slow links only.
+    /// With a jump host the stream is a tunnel through the bastion instead of a direct TCP connection.
+    ///
+    /// # Returns
+    /// * `Result<(Session, bool, Duration, SourceInfo), SSHError>` - The session, whether compression
+    ///   was requested, the TCP connect time and where the connection was made from
+    fn open_transport(&self, compress: Option<bool>) -> Result<(Session, bool, Duration, SourceInfo), SSHError> {
+        // File transfers, forwards and exec need SSH; a telnet device has only its shell
+        if self.protocol != Protocol::Ssh {
+            return Err(SSHError::Unsupported(self.protocol.as_str().to_string()));
+        }
+        // Kerberos logins are made by the OpenSSH client, which only offers the shell
+        if self.gssapi {
+            return Err(SSHError::Unsupported("Kerberos-authenticated".to_string()));
+        }
+
+        // Create and configure SSH session
+        let mut session = Session::new()
+            .map_err(|_| SSHError::Connection(
+                std::io::Error::other("Failed to create SSH session")
+            ))?;
+
+        let connect_started = Instant::now();
+        let (socket, source) = match &self.jump_host {
+            Some(jump_host) => (Socket::from(tunnel::open(jump_host, &self.hostname, self.port)?), SourceInfo::default()),
+            None => {
+                let (tcp, source) = source::connect(&self.settings.outbound, &self.hostname, self.port,
+                                                    self.device_type.as_deref(), None)?;
+                (Socket::from(tcp), source)
+            }
+        };
+        let rtt = connect_started.elapsed();
+        debug!("TCP connection established in {} ms", rtt.as_millis());
+
+        // A link already slow to connect gets its relaxed timeouts for the handshake too
+        let mut connection = self.settings.connection.clone();
+        if let Some(adaptation) = SlowLinkAdaptation::assess(&connection, rtt, None) {
+            adaptation.apply(&mut connection);
+        }
+        socket.set_read_timeout(Some(Duration::from_secs(connection.read_timeout_seconds)))?;
+        socket.set_write_timeout(Some(Duration::from_secs(connection.write_timeout_seconds)))?;
+        session.set_tcp_stream(socket);
+
+        let compress = compress.unwrap_or_else(|| match self.compression {
+            CompressionMode::On => true,
+            CompressionMode::Off => false,
+            CompressionMode::Auto => {
+                let slow_link = rtt.as_millis() as u64 >= self.settings.connection.compression_auto_rtt_ms;
+                debug!("Auto compression: RTT estimate {} ms, compress = {}", rtt.as_millis(), slow_link);
+                slow_link
+            }
+        });
+
+        session.set_timeout((connection.timeout_seconds * 1000) as u32); // Convert seconds to milliseconds
+        session.set_compress(compress);
+        
+        // Configure SSH algorithms from settings
+        session.method_pref(ssh2::MethodType::Kex, &self.settings.crypto.kex_algorithms)?;
+        session.method_pref(ssh2::MethodType::HostKey, &self.settings.crypto.host_key_algorithms)?;
+        session.method_pref(ssh2::MethodType::CryptCs, &self.settings.crypto.encryption_client_to_server)?;
+        session.method_pref(ssh2::MethodType::CryptSc, &self.settings.crypto.encryption_server_to_client)?;
+        session.method_pref(ssh2::MethodType::MacCs, &self.settings.crypto.mac_client_to_server)?;
+        session.method_pref(ssh2::MethodType::MacSc, &self.settings.crypto.mac_server_to_client)?;
+        quirks::prepare(&session, &self.quirks)?;
+
+        Ok((session, compress, rtt, source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers every prompt with the same text, or lets them time out
+    struct Answers(Option<&'static str>);
+
+    impl KeyboardInteractivePrompt for Answers {
+        fn prompt<'a>(&mut self, _username: &str, _instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+            match self.0 {
+                Some(answer) => prompts.iter().map(|_| answer.to_string()).collect(),
+                None => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_prompter_notes_unanswered_prompts() {
+        let password = [Prompt { text: "Password: ".into(), echo: false }];
+        let mut user = Answers(Some("secret"));
+        let mut prompter = DynPrompter { inner: &mut user, answered: false };
+        assert_eq!(prompter.prompt("admin", "", &password), ["secret"]);
+        assert!(prompter.answered);
+        // An informational round asks nothing, so says nothing of the user
+        prompter.prompt("admin", "Welcome", &[]);
+        assert!(prompter.answered);
+
+        let mut away = Answers(None);
+        let mut prompter = DynPrompter { inner: &mut away, answered: true };
+        assert!(prompter.prompt("admin", "", &password).is_empty());
+        assert!(!prompter.answered);
+    }
+}
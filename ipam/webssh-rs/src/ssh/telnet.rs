@@ -0,0 +1,544 @@
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::settings::CompressionMode;
+use super::backend::ShellBackend;
+use super::error::SSHError;
+use super::heartbeat::Heartbeat;
+use super::session::SessionHandle;
+use super::link::SlowLinkAdaptation;
+use super::source;
+use super::target::{CompressionInfo, ConnectionInfo, ConnectionTarget, TerminalSize};
+
+// Telnet commands (RFC 854)
+const SE: u8 = 240;
+const NOP: u8 = 241;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+// Telnet options
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TERMINAL_TYPE: u8 = 24;
+const NAWS: u8 = 31;
+
+// TERMINAL-TYPE subnegotiation (RFC 1091)
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+
+/// Longest subnegotiation kept; anything longer is not one we answer
+const MAX_SUBNEGOTIATION: usize = 256;
+
+/// An interactive telnet connection to a device
+///
+/// Telnet has no channels or authentication of its own: the device prompts
+/// for a login in the terminal. When the request gives credentials, the
+/// first login and password prompts are answered with them.
+pub struct TelnetSession {
+    stream: TcpStream,
+    codec: TelnetCodec,
+    login: LoginScript,
+    resize_rx: Option<mpsc::Receiver<(u32, u32)>>,
+    // Cancelled to stop the I/O pump and the WebSocket attached to it
+    shutdown: CancellationToken,
+    target: ConnectionTarget,
+    connection_info: ConnectionInfo,
+    // Progress of this shell's I/O loop
+    heartbeat: Heartbeat,
+    // Heartbeats of every shell opened for the session, for the watchdog
+    shells: Arc<Mutex<Vec<Heartbeat>>>,
+}
+
+impl TelnetSession {
+    /// Connects to the target and offers the options the terminal needs
+    ///
+    /// # Arguments
+    /// * `target` - The connection parameters for the device
+    ///
+    /// # Returns
+    /// * `Result<Self, SSHError>` - A new TelnetSession or an error
+    pub fn open(target: ConnectionTarget) -> Result<Self, SSHError> {
+        if target.jump_host.is_some() {
+            return Err(SSHError::Unsupported("Telnet connections cannot go through a jump host".to_string()));
+        }
+        let connection = &target.settings.connection;
+
+        info!("Opening telnet connection to {}:{}", target.hostname, target.port);
+        let connect_started = Instant::now();
+        let (mut stream, source) = source::connect(&target.settings.outbound, &target.hostname, target.port,
+                                                   target.device_type.as_deref(),
+                                                   Some(Duration::from_secs(connection.timeout_seconds)))?;
+        let rtt = connect_started.elapsed();
+        debug!("TCP connection established in {} ms", rtt.as_millis());
+        let slow_link = SlowLinkAdaptation::assess(connection, rtt, None);
+        if let Some(adaptation) = &slow_link {
+            info!("Slow link to {}:{} (TCP connect {} ms): timeouts relaxed to {} s, keepalive to {} s",
+                  target.hostname, target.port, adaptation.rtt_ms, adaptation.timeout_seconds, adaptation.keepalive_seconds);
+        }
+        let write_timeout = slow_link.as_ref().map_or(connection.write_timeout_seconds, |adaptation| adaptation.write_timeout_seconds);
+        stream.set_write_timeout(Some(Duration::from_secs(write_timeout)))?;
+        stream.set_nodelay(true)?;
+
+        let terminal = target.shell_settings().terminal;
+        let mut codec = TelnetCodec::new(&terminal.standard_terminal_type, terminal.default_cols as u16, terminal.default_rows as u16);
+        stream.write_all(&codec.offer())?;
+
+        let heartbeat = Heartbeat::new(stream.as_raw_fd());
+        Ok(Self {
+            stream,
+            codec,
+            login: LoginScript::new(&target.username, target.password.as_deref()),
+            resize_rx: None,
+            shutdown: CancellationToken::new(),
+            connection_info: ConnectionInfo {
+                tcp_connect_ms: rtt.as_millis() as u64,
+                compression: CompressionInfo {
+                    mode: CompressionMode::Off,
+                    requested: false,
+                    client_to_server: None,
+                    server_to_client: None,
+                },
+                terminal_type: Some(terminal.standard_terminal_type.clone()),
+                terminal_size: Some(TerminalSize { cols: terminal.default_cols, rows: terminal.default_rows }),
+                source,
+                slow_link,
+                device_type: None,
+                quirks: Vec::new(),
+            },
+            target,
+            shells: Arc::new(Mutex::new(vec![heartbeat.clone()])),
+            heartbeat,
+        })
+    }
+
+    /// Gets a handle to this session for the session registry
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle::new(
+            self.target.clone(),
+            self.connection_info.clone(),
+            self.shutdown.clone(),
+            self.shells.clone(),
+            None,
+        )
+    }
+
+    /// Makes this shell one of a session's, shut down together with it
+    pub(super) fn join(&mut self, shutdown: CancellationToken, shells: &Arc<Mutex<Vec<Heartbeat>>>) {
+        self.shutdown = shutdown;
+        if let Ok(mut shells) = shells.lock() {
+            shells.push(self.heartbeat.clone());
+        }
+        self.shells = shells.clone();
+    }
+
+    /// Sets the channel for receiving terminal resize events, as (rows, cols)
+    pub fn set_resize_channel(&mut self, resize_rx: mpsc::Receiver<(u32, u32)>) {
+        self.resize_rx = Some(resize_rx);
+    }
+
+    /// Gets the token cancelled when the session shuts down, for tasks serving it
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Closes the connection and signals the I/O pump to stop
+    pub fn close(&mut self) -> Result<(), SSHError> {
+        info!("Closing telnet connection to {}:{}", self.target.hostname, self.target.port);
+        self.shutdown.cancel();
+        if let Err(e) = self.stream.shutdown(Shutdown::Both) {
+            debug!("Error shutting down telnet connection: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Pumps I/O between the connection and the WebSocket on the Tokio reactor
+    ///
+    /// Option negotiation is answered as it arrives; only terminal data reaches the WebSocket.
+    async fn pump(&mut self, mut input_rx: mpsc::Receiver<Bytes>, output_tx: mpsc::Sender<Bytes>) -> Result<(), SSHError> {
+        info!("Starting telnet I/O handling");
+
+        // A second descriptor for the same socket: the session keeps its own
+        // for closing, and the watchdog can still shut it down from outside
+        let stream = self.stream.try_clone()?;
+        stream.set_nonblocking(true)?;
+        let mut stream = tokio::net::TcpStream::from_std(stream)?;
+        let mut resize_rx = self.resize_rx.take();
+        let shutdown = self.shutdown.clone();
+        let keepalive_period = Duration::from_secs(self.connection_info.keepalive_seconds(&self.target.settings).max(1));
+        let mut keepalive = tokio::time::interval_at(tokio::time::Instant::now() + keepalive_period, keepalive_period);
+        let mut buf = [0u8; 4096];
+
+        loop {
+            // Wakes at least once per keepalive period, so a silent heartbeat means a stuck loop
+            self.heartbeat.beat();
+
+            tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("Shutdown signalled, stopping I/O handling");
+                    break;
+                }
+                // Output waits in the socket while the shell's output queue is full
+                read = stream.read(&mut buf), if output_tx.capacity() > 0 => {
+                    let n = read?;
+                    if n == 0 {
+                        info!("Telnet connection closed by the device");
+                        shutdown.cancel();
+                        let _ = output_tx.send(Bytes::from_static(b"\r\n[Telnet connection closed]\r\n")).await;
+                        break;
+                    }
+                    self.heartbeat.heard();
+                    let (data, replies) = self.codec.decode(&buf[..n]);
+                    if !replies.is_empty() {
+                        stream.write_all(&replies).await?;
+                    }
+                    if let Some(answer) = self.login.answer(&data) {
+                        stream.write_all(&self.codec.encode(answer.as_bytes())).await?;
+                    }
+                    if !data.is_empty() && output_tx.send(Bytes::from(data)).await.is_err() {
+                        error!("Failed to send telnet output to WebSocket");
+                        break;
+                    }
+                }
+                data = input_rx.recv() => {
+                    let Some(data) = data else {
+                        debug!("WebSocket input closed, stopping I/O handling");
+                        break;
+                    };
+                    stream.write_all(&self.codec.encode(&data)).await?;
+                }
+                Some((rows, cols)) = recv_resize(&mut resize_rx) => {
+                    debug!("Processing resize command: {}x{}", cols, rows);
+                    let report = self.codec.resize(cols.min(u16::MAX as u32) as u16, rows.min(u16::MAX as u32) as u16);
+                    if !report.is_empty() {
+                        stream.write_all(&report).await?;
+                    }
+                }
+                permit = output_tx.reserve(), if output_tx.capacity() == 0 => {
+                    if permit.is_err() {
+                        break;
+                    }
+                }
+                // NOP asks for no reply, so only keepalives that fail to send are counted
+                _ = keepalive.tick() => {
+                    let sent = stream.write_all(&[IAC, NOP]).await;
+                    self.heartbeat.keepalive_sent(sent.is_ok());
+                    if let Err(e) = sent {
+                        warn!("Failed to send keepalive to {}:{}: {}", self.target.hostname, self.target.port, e);
+                    }
+                }
+            }
+        }
+
+        info!("Telnet I/O handling completed");
+        Ok(())
+    }
+}
+
+impl ShellBackend for TelnetSession {
+    async fn run_io(
+        mut self,
+        input_rx: mpsc::Receiver<Bytes>,
+        output_tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), SSHError> {
+        let result = self.pump(input_rx, output_tx).await;
+        let _ = self.close();
+        result
+    }
+}
+
+impl Drop for TelnetSession {
+    fn drop(&mut self) {
+        // The socket closes right after this; the watchdog must not touch it from now on
+        self.heartbeat.finish();
+    }
+}
+
+/// Receives the next resize, or never resolves when no resize channel is set
+pub(super) async fn recv_resize(resize_rx: &mut Option<mpsc::Receiver<(u32, u32)>>) -> Option<(u32, u32)> {
+    match resize_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CodecState {
+    Data,
+    Iac,
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Telnet framing and option negotiation, separated from the connection
+///
+/// The device may echo and suppress go-ahead; we report the window size
+/// (NAWS, RFC 1073) and terminal type. Every other option is refused.
+pub struct TelnetCodec {
+    state: CodecState,
+    subnegotiation: Vec<u8>,
+    // Options enabled on our side and on the device's
+    local: HashSet<u8>,
+    remote: HashSet<u8>,
+    // The last byte of data was a CR, so a NUL after it is padding
+    after_cr: bool,
+    terminal_type: String,
+    cols: u16,
+    rows: u16,
+}
+
+impl TelnetCodec {
+    pub fn new(terminal_type: &str, cols: u16, rows: u16) -> Self {
+        Self {
+            state: CodecState::Data,
+            subnegotiation: Vec::new(),
+            local: HashSet::new(),
+            remote: HashSet::new(),
+            after_cr: false,
+            terminal_type: terminal_type.to_string(),
+            cols,
+            rows,
+        }
+    }
+
+    /// The options offered as soon as the connection opens
+    pub fn offer(&mut self) -> Vec<u8> {
+        self.local.extend([NAWS, TERMINAL_TYPE]);
+        self.remote.extend([SUPPRESS_GO_AHEAD, ECHO]);
+        vec![
+            IAC, WILL, NAWS,
+            IAC, WILL, TERMINAL_TYPE,
+            IAC, DO, SUPPRESS_GO_AHEAD,
+            IAC, DO, ECHO,
+        ]
+    }
+
+    /// Splits bytes from the device into terminal data and the replies owed to it
+    ///
+    /// # Returns
+    /// * `(Vec<u8>, Vec<u8>)` - Data for the terminal, and bytes to send back to the device
+    pub fn decode(&mut self, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(input.len());
+        let mut replies = Vec::new();
+        for &byte in input {
+            self.state = match self.state {
+                CodecState::Data => match byte {
+                    IAC => CodecState::Iac,
+                    // CR NUL is a bare carriage return
+                    0 if self.after_cr => {
+                        self.after_cr = false;
+                        CodecState::Data
+                    }
+                    _ => {
+                        self.after_cr = byte == b'\r';
+                        data.push(byte);
+                        CodecState::Data
+                    }
+                },
+                CodecState::Iac => match byte {
+                    IAC => {
+                        self.after_cr = false;
+                        data.push(IAC);
+                        CodecState::Data
+                    }
+                    WILL | WONT | DO | DONT => CodecState::Negotiate(byte),
+                    SB => {
+                        self.subnegotiation.clear();
+                        CodecState::Subnegotiation
+                    }
+                    // NOP, GA and the like carry nothing for the terminal
+                    _ => CodecState::Data,
+                },
+                CodecState::Negotiate(command) => {
+                    self.negotiate(command, byte, &mut replies);
+                    CodecState::Data
+                }
+                CodecState::Subnegotiation => {
+                    if byte == IAC {
+                        CodecState::SubnegotiationIac
+                    } else {
+                        self.push_subnegotiation(byte);
+                        CodecState::Subnegotiation
+                    }
+                }
+                CodecState::SubnegotiationIac => match byte {
+                    SE => {
+                        self.subnegotiate(&mut replies);
+                        CodecState::Data
+                    }
+                    IAC => {
+                        self.push_subnegotiation(IAC);
+                        CodecState::Subnegotiation
+                    }
+                    _ => CodecState::Data,
+                },
+            };
+        }
+        (data, replies)
+    }
+
+    /// Frames terminal input for the device
+    ///
+    /// IAC bytes are doubled, and a carriage return not followed by a line
+    /// feed is sent as CR NUL, as the network virtual terminal requires.
+    pub fn encode(&self, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len() + 1);
+        for (i, &byte) in input.iter().enumerate() {
+            output.push(byte);
+            match byte {
+                IAC => output.push(IAC),
+                b'\r' if input.get(i + 1) != Some(&b'\n') => output.push(0),
+                _ => {}
+            }
+        }
+        output
+    }
+
+    /// Records a new window size, returning the report owed to the device
+    pub fn resize(&mut self, cols: u16, rows: u16) -> Vec<u8> {
+        self.cols = cols;
+        self.rows = rows;
+        self.window_report()
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8, replies: &mut Vec<u8>) {
+        // Only changes of state are answered, so negotiation cannot loop
+        match command {
+            DO if matches!(option, NAWS | TERMINAL_TYPE) => {
+                if self.local.insert(option) {
+                    replies.extend_from_slice(&[IAC, WILL, option]);
+                }
+                if option == NAWS {
+                    replies.extend(self.window_report());
+                }
+            }
+            DO => replies.extend_from_slice(&[IAC, WONT, option]),
+            DONT if self.local.remove(&option) => replies.extend_from_slice(&[IAC, WONT, option]),
+            WILL if matches!(option, ECHO | SUPPRESS_GO_AHEAD) && self.remote.insert(option) => {
+                replies.extend_from_slice(&[IAC, DO, option]);
+            }
+            WILL if !matches!(option, ECHO | SUPPRESS_GO_AHEAD) => replies.extend_from_slice(&[IAC, DONT, option]),
+            WONT if self.remote.remove(&option) => replies.extend_from_slice(&[IAC, DONT, option]),
+            _ => {}
+        }
+    }
+
+    fn push_subnegotiation(&mut self, byte: u8) {
+        if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+            self.subnegotiation.push(byte);
+        }
+    }
+
+    fn subnegotiate(&mut self, replies: &mut Vec<u8>) {
+        if self.subnegotiation == [TERMINAL_TYPE, TERMINAL_TYPE_SEND] && self.local.contains(&TERMINAL_TYPE) {
+            replies.extend_from_slice(&[IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_IS]);
+            replies.extend(self.terminal_type.bytes().filter(|&byte| byte != IAC));
+            replies.extend_from_slice(&[IAC, SE]);
+        }
+    }
+
+    /// The window size subnegotiation, once the device has agreed to NAWS
+    fn window_report(&self) -> Vec<u8> {
+        if !self.local.contains(&NAWS) {
+            return Vec::new();
+        }
+        let mut report = vec![IAC, SB, NAWS];
+        for byte in self.cols.to_be_bytes().into_iter().chain(self.rows.to_be_bytes()) {
+            report.push(byte);
+            if byte == IAC {
+                report.push(IAC);
+            }
+        }
+        report.extend_from_slice(&[IAC, SE]);
+        report
+    }
+}
+
+/// Answers the device's first login and password prompts with the request's credentials
+struct LoginScript {
+    username: Option<String>,
+    password: Option<String>,
+    // The end of the output so far, where a prompt would be
+    tail: Vec<u8>,
+}
+
+/// Output kept to recognise a prompt
+const PROMPT_TAIL: usize = 64;
+
+impl LoginScript {
+    fn new(username: &str, password: Option<&str>) -> Self {
+        Self {
+            username: Some(username.to_string()).filter(|username| !username.is_empty()),
+            password: password.map(str::to_string),
+            tail: Vec::new(),
+        }
+    }
+
+    /// The line to send if the output ends at a prompt still to be answered
+    fn answer(&mut self, output: &[u8]) -> Option<String> {
+        if self.username.is_none() && self.password.is_none() {
+            return None;
+        }
+        self.tail.extend_from_slice(output);
+        if self.tail.len() > PROMPT_TAIL {
+            self.tail.drain(..self.tail.len() - PROMPT_TAIL);
+        }
+        let text = String::from_utf8_lossy(&self.tail).trim_end().to_ascii_lowercase();
+        let answer = if text.ends_with("password:") {
+            // The login is over once the password is given
+            self.username = None;
+            self.password.take()
+        } else if text.ends_with("login:") || text.ends_with("username:") {
+            self.username.take()
+        } else {
+            None
+        };
+        if answer.is_some() {
+            self.tail.clear();
+        }
+        answer.map(|line| line + "\r")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation_and_login() {
+        let mut codec = TelnetCodec::new("xterm-256color", 132, 40);
+        codec.offer();
+
+        // Agreed options are not confirmed again; NAWS is answered with the window
+        let (data, replies) = codec.decode(&[IAC, DO, NAWS, IAC, WILL, ECHO, IAC, DO, 39, b'h', b'i', IAC, IAC]);
+        assert_eq!(data, vec![b'h', b'i', IAC]);
+        assert_eq!(replies, vec![IAC, SB, NAWS, 0, 132, 0, 40, IAC, SE, IAC, WONT, 39]);
+
+        let (_, replies) = codec.decode(&[IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_SEND, IAC, SE]);
+        assert_eq!(&replies[..4], &[IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_IS]);
+        assert_eq!(&replies[4..replies.len() - 2], b"xterm-256color");
+
+        assert_eq!(codec.resize(255, 24), vec![IAC, SB, NAWS, 0, 255, 255, 0, 24, IAC, SE]);
+        assert_eq!(codec.encode(b"ls\r"), b"ls\r\0".to_vec());
+        assert_eq!(codec.encode(&[IAC]), vec![IAC, IAC]);
+
+        let mut login = LoginScript::new("admin", Some("secret"));
+        assert_eq!(login.answer(b"\r\nUser Access Verification\r\n\r\nUser"), None);
+        assert_eq!(login.answer(b"name: ").as_deref(), Some("admin\r"));
+        assert_eq!(login.answer(b"Password: ").as_deref(), Some("secret\r"));
+        assert_eq!(login.answer(b"\r\nUsername: "), None);
+    }
+}
@@ -0,0 +1,339 @@
+use ssh2::{BlockDirections, Channel, Session};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
+
+use super::error::SSHError;
+use super::target::ConnectionTarget;
+
+/// Opens a direct-tcpip channel through a bastion to the given host
+///
+/// libssh2 needs a real socket for the target session, so the channel is
+/// bridged to one end of a socket pair by a pump thread; the other end is
+/// returned for the target session to use. The bastion connection lives as
+/// long as the tunnel.
+///
+/// # Arguments
+/// * `jump_host` - The bastion to connect through
+/// * `hostname` - The target host, resolved by the bastion
+/// * `port` - The target port
+///
+/// # Returns
+/// * `Result<UnixStream, SSHError>` - The local end of the tunnel or an error
+pub fn open(jump_host: &ConnectionTarget, hostname: &str, port: u16) -> Result<UnixStream, SSHError> {
+    info!("Opening tunnel to {}:{} through jump host {}:{}", hostname, port, jump_host.hostname, jump_host.port);
+    // The bastion's host key is checked against its own pin, as the device's is
+    let bastion = jump_host.connect().map_err(|e| e.at_jump_host(&jump_host.hostname, jump_host.port))?;
+
+    bastion.set_timeout((jump_host.settings.jump_host.tunnel_timeout_seconds * 1000) as u32);
+    let channel = bastion.channel_direct_tcpip(hostname, port, None)
+        .inspect_err(|e| error!("Jump host {} could not open a channel to {}:{}: {}", jump_host.hostname, hostname, port, e))?;
+    debug!("Direct-tcpip channel to {}:{} opened", hostname, port);
+
+    let (local, remote) = UnixStream::pair()?;
+    remote.set_nonblocking(true)?;
+    bastion.set_blocking(false);
+
+    let keepalive_interval = Duration::from_secs(jump_host.settings.connection.keepalive_seconds.max(1));
+    let label = format!("{}:{} via {}", hostname, port, jump_host.hostname);
+    std::thread::spawn(move || pump(Bastion { session: bastion, channel }, remote, keepalive_interval, label));
+
+    Ok(local)
+}
+
+/// The bastion end of a tunnel, as the pump sees it
+trait BastionChannel: Read + Write {
+    /// Whether the target has closed its side of the channel
+    fn eof(&self) -> bool;
+
+    /// What to wait for on the bastion socket before the channel can go on
+    fn events(&self) -> libc::pollfd;
+
+    /// Sends a keepalive over the bastion connection
+    fn keepalive(&mut self);
+
+    /// Closes the channel and the bastion connection
+    fn close(&mut self);
+}
+
+/// A direct-tcpip channel and the bastion connection it is open on
+struct Bastion {
+    session: Session,
+    channel: Channel,
+}
+
+impl Read for Bastion {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.channel.read(buf)
+    }
+}
+
+impl Write for Bastion {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.channel.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BastionChannel for Bastion {
+    fn eof(&self) -> bool {
+        self.channel.eof()
+    }
+
+    fn events(&self) -> libc::pollfd {
+        socket_events(&self.session)
+    }
+
+    fn keepalive(&mut self) {
+        let _ = self.session.keepalive_send();
+    }
+
+    fn close(&mut self) {
+        let _ = self.channel.close();
+        let _ = self.session.disconnect(None, "Tunnel closed", None);
+    }
+}
+
+/// Copies bytes between the bastion channel and the local socket until either side closes
+///
+/// The thread sleeps in `poll` until either socket has something for it or
+/// a keepalive is due. It owns the bastion connection, so libssh2 only
+/// reads from its socket here: once the channel would block, nothing is
+/// left buffered and the socket says when there is more.
+fn pump(mut bastion: impl BastionChannel, mut local: UnixStream, keepalive_interval: Duration, label: String) {
+    let mut buf = [0u8; 16384];
+    let mut last_keepalive = Instant::now();
+
+    'pump: loop {
+        // Bastion -> target session
+        loop {
+            match bastion.read(&mut buf) {
+                Ok(0) if bastion.eof() => {
+                    debug!("Tunnel {}: remote end closed", label);
+                    break 'pump;
+                }
+                Ok(0) => break,
+                Ok(n) => {
+                    if let Err(e) = write_waiting(&mut local, |local| pollfd(local.as_raw_fd(), libc::POLLOUT), &buf[..n]) {
+                        debug!("Tunnel {}: local end closed: {}", label, e);
+                        break 'pump;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Tunnel {}: read from jump host failed: {}", label, e);
+                    break 'pump;
+                }
+            }
+        }
+
+        // Target session -> bastion
+        let mut sent = false;
+        loop {
+            match local.read(&mut buf) {
+                Ok(0) => {
+                    debug!("Tunnel {}: local end closed", label);
+                    break 'pump;
+                }
+                Ok(n) => {
+                    if let Err(e) = write_waiting(&mut bastion, |bastion| bastion.events(), &buf[..n]) {
+                        error!("Tunnel {}: write to jump host failed: {}", label, e);
+                        break 'pump;
+                    }
+                    sent = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Tunnel {}: local read failed: {}", label, e);
+                    break 'pump;
+                }
+            }
+        }
+
+        // A write may have read what the bastion sent meanwhile into libssh2's buffers
+        if sent {
+            continue;
+        }
+
+        let until_keepalive = keepalive_interval.saturating_sub(last_keepalive.elapsed());
+        if until_keepalive.is_zero() {
+            bastion.keepalive();
+            last_keepalive = Instant::now();
+            continue;
+        }
+        let mut ready = [bastion.events(), pollfd(local.as_raw_fd(), libc::POLLIN)];
+        if let Err(e) = wait(&mut ready, Some(until_keepalive)) {
+            error!("Tunnel {}: waiting for data failed: {}", label, e);
+            break;
+        }
+    }
+
+    bastion.close();
+    info!("Tunnel {} closed", label);
+}
+
+/// Writes all data to a non-blocking channel, waiting on the connection's socket while libssh2 would block
+pub(super) fn write_all(session: &Session, channel: &mut Channel, data: &[u8]) -> io::Result<()> {
+    write_waiting(channel, |_| socket_events(session), data)
+}
+
+/// Writes all data to a non-blocking writer, sleeping until `events` is ready whenever it would block
+fn write_waiting<W: Write>(writer: &mut W, events: impl Fn(&W) -> libc::pollfd, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => wait(&mut [events(writer)], None)?,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// What to wait for on an SSH connection's socket: data from the server, and
+/// room to send when libssh2 is held up writing
+fn socket_events(session: &Session) -> libc::pollfd {
+    let events = match session.block_directions() {
+        BlockDirections::Outbound | BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+        _ => libc::POLLIN,
+    };
+    pollfd(session.as_raw_fd(), events)
+}
+
+fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd { fd, events, revents: 0 }
+}
+
+/// Sleeps until one of `fds` is ready or `timeout` has passed; without a timeout, until one is ready
+fn wait(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> io::Result<()> {
+    // Rounded up, so a keepalive a fraction of a millisecond away is not polled for in a loop
+    let timeout_ms = timeout.map_or(-1, |timeout| timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32);
+    // SAFETY: `fds` is a valid slice of pollfds for the length given
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Shutdown;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Stands in for the bastion: the channel is a socket whose other end plays the target device
+    struct FakeBastion {
+        stream: UnixStream,
+        eof: bool,
+        // Whether the last write would have blocked, as libssh2's block directions say
+        write_blocked: bool,
+        keepalives: Arc<AtomicUsize>,
+    }
+
+    impl Read for FakeBastion {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.stream.read(buf)?;
+            self.eof = n == 0;
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeBastion {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written = self.stream.write(buf);
+            self.write_blocked = written.as_ref().is_err_and(|e| e.kind() == ErrorKind::WouldBlock);
+            written
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BastionChannel for FakeBastion {
+        fn eof(&self) -> bool {
+            self.eof
+        }
+
+        fn events(&self) -> libc::pollfd {
+            pollfd(self.stream.as_raw_fd(), if self.write_blocked { libc::POLLIN | libc::POLLOUT } else { libc::POLLIN })
+        }
+
+        fn keepalive(&mut self) {
+            self.keepalives.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn close(&mut self) {
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Starts a pump; returns the device's end, the target session's end, the keepalive count and the pump thread
+    fn tunnel(keepalive_interval: Duration) -> (UnixStream, UnixStream, Arc<AtomicUsize>, thread::JoinHandle<()>) {
+        let (device, channel) = UnixStream::pair().unwrap();
+        let (session, local) = UnixStream::pair().unwrap();
+        channel.set_nonblocking(true).unwrap();
+        local.set_nonblocking(true).unwrap();
+        for end in [&device, &session] {
+            end.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        }
+        let keepalives = Arc::new(AtomicUsize::new(0));
+        let bastion = FakeBastion { stream: channel, eof: false, write_blocked: false, keepalives: keepalives.clone() };
+        let pump = thread::spawn(move || pump(bastion, local, keepalive_interval, "test".to_string()));
+        (device, session, keepalives, pump)
+    }
+
+    #[test]
+    fn test_tunnel_forwards_both_ways_and_closes_with_the_device() {
+        let (mut device, mut session, _, pump) = tunnel(Duration::from_secs(60));
+
+        device.write_all(b"SSH-2.0-Cisco-1.25\r\n").unwrap();
+        let mut banner = [0u8; 20];
+        session.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"SSH-2.0-Cisco-1.25\r\n");
+
+        // More than the sockets buffer, so the pump has to wait for the device to read
+        let large: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let mut writer = session.try_clone().unwrap();
+        let sent = large.clone();
+        let writer = thread::spawn(move || writer.write_all(&sent).unwrap());
+        let mut received = vec![0u8; large.len()];
+        device.read_exact(&mut received).unwrap();
+        assert!(received == large);
+        writer.join().unwrap();
+
+        drop(device);
+        assert_eq!(session.read(&mut banner).unwrap(), 0);
+        pump.join().unwrap();
+    }
+
+    #[test]
+    fn test_tunnel_closes_the_channel_with_the_session() {
+        let (mut device, session, _, pump) = tunnel(Duration::from_secs(60));
+
+        drop(session);
+        pump.join().unwrap();
+        assert_eq!(device.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_idle_tunnel_sends_keepalives() {
+        let (device, _session, keepalives, pump) = tunnel(Duration::from_millis(20));
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(keepalives.load(Ordering::Relaxed) >= 3);
+        drop(device);
+        pump.join().unwrap();
+    }
+}
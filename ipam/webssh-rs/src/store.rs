@@ -0,0 +1,444 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Mutex;
+use thiserror::Error;
+
+use crate::timeline::TimelineEntry;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Storage unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Why a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// The WebSocket closed and no reconnection was allowed
+    ClientDisconnected,
+    /// The device closed the shell
+    ShellClosed,
+    /// Terminated through the API
+    Terminated,
+    /// Removed by the stale session cleanup
+    Idle,
+    /// The client did not reconnect within the grace period
+    ReconnectTimeout,
+    /// Still open when the service stopped
+    ServiceRestart,
+    /// Closed by the watchdog after its I/O stopped making progress
+    Stalled,
+    /// Closed to make room for a new session under the session limits
+    Evicted,
+    /// Removed by an operator purging idle sessions ahead of the cleanup
+    Purged,
+    /// Open for longer than its lifetime class allows
+    LifetimeExceeded,
+    /// Closed by the keepalive monitor after the device stopped answering keepalives
+    ConnectionLost,
+}
+
+impl EndReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EndReason::ClientDisconnected => "client_disconnected",
+            EndReason::ShellClosed => "shell_closed",
+            EndReason::Terminated => "terminated",
+            EndReason::Idle => "idle",
+            EndReason::ReconnectTimeout => "reconnect_timeout",
+            EndReason::ServiceRestart => "service_restart",
+            EndReason::Stalled => "stalled",
+            EndReason::Evicted => "evicted",
+            EndReason::Purged => "purged",
+            EndReason::LifetimeExceeded => "lifetime_exceeded",
+            EndReason::ConnectionLost => "connection_lost",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [
+            EndReason::ClientDisconnected,
+            EndReason::ShellClosed,
+            EndReason::Terminated,
+            EndReason::Idle,
+            EndReason::ReconnectTimeout,
+            EndReason::ServiceRestart,
+            EndReason::Stalled,
+            EndReason::Evicted,
+            EndReason::Purged,
+            EndReason::LifetimeExceeded,
+            EndReason::ConnectionLost,
+        ].into_iter().find(|reason| reason.as_str() == value)
+    }
+}
+
+/// What is kept about a session once it has been created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub portal_user_id: String,
+    pub device_id: String,
+    pub ssh_username: String,
+    pub hostname: String,
+    pub port: u16,
+    pub created_at: DateTime<Utc>,
+    pub last_attached_at: Option<DateTime<Utc>>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub end_reason: Option<EndReason>,
+}
+
+/// One run of the gateway process, from startup to shutdown
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    pub run_id: i64,
+    pub version: String,
+    pub started_at: DateTime<Utc>,
+    /// Last heartbeat, the best guess at when a run that did not shut down cleanly stopped
+    pub last_seen_at: DateTime<Utc>,
+    /// Set by a clean shutdown only
+    pub ended_at: Option<DateTime<Utc>>,
+    /// What asked the run to stop, e.g. "SIGTERM"
+    pub shutdown: Option<String>,
+    /// Message of the last panic, which may be what brought the run down
+    pub last_panic: Option<String>,
+    pub peak_sessions: u64,
+    pub sessions_created: u64,
+}
+
+/// A step in a session's lifecycle
+#[derive(Debug)]
+pub enum SessionEvent<'a> {
+    Created(&'a SessionRecord),
+    Attached { session_id: &'a str, at: DateTime<Utc> },
+    Detached { session_id: &'a str, at: DateTime<Utc> },
+    Ended { session_id: &'a str, at: DateTime<Utc>, reason: EndReason },
+}
+
+impl SessionEvent<'_> {
+    pub fn session_id(&self) -> &str {
+        match self {
+            SessionEvent::Created(record) => &record.session_id,
+            SessionEvent::Attached { session_id, .. }
+            | SessionEvent::Detached { session_id, .. }
+            | SessionEvent::Ended { session_id, .. } => session_id,
+        }
+    }
+}
+
+/// Durable storage for session lifecycle events
+///
+/// Live sessions cannot survive a restart, but their metadata and the reason
+/// they ended can, for auditing and for the status APIs.
+pub trait SessionStore: Send + Sync {
+    fn record(&self, event: SessionEvent) -> Result<(), StoreError>;
+
+    /// Ends the sessions a previous run left open and returns the history
+    /// of sessions created within the retention period
+    fn recover(&self, retention: chrono::Duration) -> Result<Vec<SessionRecord>, StoreError>;
+
+    /// Adds an event to a session's timeline
+    fn record_timeline(&self, session_id: &str, entry: &TimelineEntry) -> Result<(), StoreError>;
+
+    /// The latest `limit` events of a session's timeline, oldest first
+    fn timeline(&self, session_id: &str, limit: usize) -> Result<Vec<TimelineEntry>, StoreError>;
+}
+
+/// Session history in an SQLite database
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database, e.g. "sessions.db" or ":memory:"
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        if let Some(parent) = path.as_ref().parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| StoreError::Unavailable(e.to_string()))?;
+        }
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                session_id TEXT PRIMARY KEY,
+                portal_user_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                ssh_username TEXT NOT NULL,
+                hostname TEXT NOT NULL,
+                port INTEGER NOT NULL,
+                created_at TEXT NOT NULL,
+                last_attached_at TEXT,
+                ended_at TEXT,
+                end_reason TEXT
+            );
+            CREATE TABLE IF NOT EXISTS session_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session_id TEXT NOT NULL,
+                event TEXT NOT NULL,
+                reason TEXT,
+                at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS session_events_session ON session_events (session_id);
+            CREATE TABLE IF NOT EXISTS session_timeline (
+                session_id TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                at TEXT NOT NULL,
+                event TEXT NOT NULL,
+                detail TEXT,
+                PRIMARY KEY (session_id, seq)
+            );
+            CREATE TABLE IF NOT EXISTS runs (
+                run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                version TEXT NOT NULL,
+                started_at TEXT NOT NULL,
+                last_seen_at TEXT NOT NULL,
+                ended_at TEXT,
+                shutdown TEXT,
+                last_panic TEXT,
+                peak_sessions INTEGER NOT NULL DEFAULT 0,
+                sessions_created INTEGER NOT NULL DEFAULT 0
+            );",
+        )?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>, StoreError> {
+        self.connection.lock().map_err(|_| StoreError::Unavailable("connection lock poisoned".to_string()))
+    }
+
+    /// Records the start of a run and returns the runs started within the
+    /// retention period, newest first, this one included
+    pub fn start_run(&self, version: &str, retention: chrono::Duration) -> Result<Vec<RunRecord>, StoreError> {
+        let connection = self.connection()?;
+        let now = Utc::now();
+        connection.execute("DELETE FROM runs WHERE started_at < ?1", params![now - retention])?;
+        connection.execute(
+            "INSERT INTO runs (version, started_at, last_seen_at) VALUES (?1, ?2, ?2)",
+            params![version, now],
+        )?;
+        let runs = connection
+            .prepare(
+                "SELECT run_id, version, started_at, last_seen_at, ended_at, shutdown, last_panic,
+                        peak_sessions, sessions_created
+                 FROM runs ORDER BY run_id DESC",
+            )?
+            .query_map([], |row| {
+                Ok(RunRecord {
+                    run_id: row.get(0)?,
+                    version: row.get(1)?,
+                    started_at: row.get(2)?,
+                    last_seen_at: row.get(3)?,
+                    ended_at: row.get(4)?,
+                    shutdown: row.get(5)?,
+                    last_panic: row.get(6)?,
+                    peak_sessions: row.get(7)?,
+                    sessions_created: row.get(8)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(runs)
+    }
+
+    /// Records that a run is still alive, with its metrics so far
+    pub fn update_run(&self, run_id: i64, peak_sessions: u64, sessions_created: u64) -> Result<(), StoreError> {
+        self.connection()?.execute(
+            "UPDATE runs SET last_seen_at = ?2, peak_sessions = ?3, sessions_created = ?4 WHERE run_id = ?1",
+            params![run_id, Utc::now(), peak_sessions, sessions_created],
+        )?;
+        Ok(())
+    }
+
+    /// Records a panic, from the panic hook
+    ///
+    /// The connection is only tried, never waited for: the panicking thread may hold it.
+    pub fn record_panic(&self, run_id: i64, message: &str) {
+        if let Ok(connection) = self.connection.try_lock() {
+            let _ = connection.execute(
+                "UPDATE runs SET last_panic = ?2, last_seen_at = ?3 WHERE run_id = ?1",
+                params![run_id, message, Utc::now()],
+            );
+        }
+    }
+
+    /// Records a clean shutdown
+    pub fn end_run(&self, run_id: i64, shutdown: &str) -> Result<(), StoreError> {
+        let now = Utc::now();
+        self.connection()?.execute(
+            "UPDATE runs SET ended_at = ?2, last_seen_at = ?2, shutdown = ?3 WHERE run_id = ?1",
+            params![run_id, now, shutdown],
+        )?;
+        Ok(())
+    }
+}
+
+fn add_event(connection: &Connection, session_id: &str, event: &str, reason: Option<&str>, at: DateTime<Utc>) -> Result<(), StoreError> {
+    connection.execute(
+        "INSERT INTO session_events (session_id, event, reason, at) VALUES (?1, ?2, ?3, ?4)",
+        params![session_id, event, reason, at],
+    )?;
+    Ok(())
+}
+
+impl SessionStore for SqliteStore {
+    fn record(&self, event: SessionEvent) -> Result<(), StoreError> {
+        let connection = self.connection()?;
+        match event {
+            SessionEvent::Created(record) => {
+                connection.execute(
+                    "INSERT OR REPLACE INTO sessions
+                        (session_id, portal_user_id, device_id, ssh_username, hostname, port, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![record.session_id, record.portal_user_id, record.device_id, record.ssh_username,
+                            record.hostname, record.port, record.created_at],
+                )?;
+                add_event(&connection, &record.session_id, "created", None, record.created_at)
+            }
+            SessionEvent::Attached { session_id, at } => {
+                connection.execute(
+                    "UPDATE sessions SET last_attached_at = ?2 WHERE session_id = ?1",
+                    params![session_id, at],
+                )?;
+                add_event(&connection, session_id, "attached", None, at)
+            }
+            SessionEvent::Detached { session_id, at } => add_event(&connection, session_id, "detached", None, at),
+            SessionEvent::Ended { session_id, at, reason } => {
+                connection.execute(
+                    "UPDATE sessions SET ended_at = ?2, end_reason = ?3 WHERE session_id = ?1",
+                    params![session_id, at, reason.as_str()],
+                )?;
+                add_event(&connection, session_id, "ended", Some(reason.as_str()), at)
+            }
+        }
+    }
+
+    fn recover(&self, retention: chrono::Duration) -> Result<Vec<SessionRecord>, StoreError> {
+        let mut connection = self.connection()?;
+        let now = Utc::now();
+        let transaction = connection.transaction()?;
+
+        let orphaned: Vec<String> = transaction
+            .prepare("SELECT session_id FROM sessions WHERE ended_at IS NULL")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for session_id in &orphaned {
+            transaction.execute(
+                "UPDATE sessions SET ended_at = ?2, end_reason = ?3 WHERE session_id = ?1",
+                params![session_id, now, EndReason::ServiceRestart.as_str()],
+            )?;
+            add_event(&transaction, session_id, "ended", Some(EndReason::ServiceRestart.as_str()), now)?;
+        }
+
+        let cutoff = now - retention;
+        transaction.execute(
+            "DELETE FROM session_events WHERE session_id IN (SELECT session_id FROM sessions WHERE created_at < ?1)",
+            params![cutoff],
+        )?;
+        transaction.execute(
+            "DELETE FROM session_timeline WHERE session_id IN (SELECT session_id FROM sessions WHERE created_at < ?1)",
+            params![cutoff],
+        )?;
+        transaction.execute("DELETE FROM sessions WHERE created_at < ?1", params![cutoff])?;
+
+        let records = transaction
+            .prepare(
+                "SELECT session_id, portal_user_id, device_id, ssh_username, hostname, port,
+                        created_at, last_attached_at, ended_at, end_reason
+                 FROM sessions ORDER BY created_at",
+            )?
+            .query_map([], |row| {
+                Ok(SessionRecord {
+                    session_id: row.get(0)?,
+                    portal_user_id: row.get(1)?,
+                    device_id: row.get(2)?,
+                    ssh_username: row.get(3)?,
+                    hostname: row.get(4)?,
+                    port: row.get(5)?,
+                    created_at: row.get(6)?,
+                    last_attached_at: row.get(7)?,
+                    ended_at: row.get(8)?,
+                    end_reason: row.get::<_, Option<String>>(9)?.as_deref().and_then(EndReason::parse),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        transaction.commit()?;
+        Ok(records)
+    }
+
+    fn record_timeline(&self, session_id: &str, entry: &TimelineEntry) -> Result<(), StoreError> {
+        let detail = (!entry.detail.is_null()).then(|| entry.detail.to_string());
+        self.connection()?.execute(
+            "INSERT OR REPLACE INTO session_timeline (session_id, seq, at, event, detail) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![session_id, entry.seq, entry.at, entry.event, detail],
+        )?;
+        Ok(())
+    }
+
+    fn timeline(&self, session_id: &str, limit: usize) -> Result<Vec<TimelineEntry>, StoreError> {
+        let connection = self.connection()?;
+        let mut entries = connection
+            .prepare(
+                "SELECT seq, at, event, detail FROM session_timeline
+                 WHERE session_id = ?1 ORDER BY seq DESC LIMIT ?2",
+            )?
+            .query_map(params![session_id, limit], |row| {
+                Ok(TimelineEntry {
+                    seq: row.get(0)?,
+                    at: row.get(1)?,
+                    event: row.get(2)?,
+                    detail: row.get::<_, Option<String>>(3)?
+                        .and_then(|detail| serde_json::from_str(&detail).ok())
+                        .unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(session_id: &str) -> SessionRecord {
+        SessionRecord {
+            session_id: session_id.to_string(),
+            portal_user_id: "alice".to_string(),
+            device_id: "router1".to_string(),
+            ssh_username: "admin".to_string(),
+            hostname: "192.0.2.1".to_string(),
+            port: 22,
+            created_at: Utc::now(),
+            last_attached_at: None,
+            ended_at: None,
+            end_reason: None,
+        }
+    }
+
+    #[test]
+    fn test_recover_ends_orphaned_sessions() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.record(SessionEvent::Created(&record("open"))).unwrap();
+        store.record(SessionEvent::Created(&record("closed"))).unwrap();
+        store.record(SessionEvent::Attached { session_id: "closed", at: Utc::now() }).unwrap();
+        store.record(SessionEvent::Ended { session_id: "closed", at: Utc::now(), reason: EndReason::Terminated }).unwrap();
+
+        let history = store.recover(chrono::Duration::days(1)).unwrap();
+        let reason = |id: &str| history.iter().find(|record| record.session_id == id).and_then(|record| record.end_reason);
+        assert_eq!(history.len(), 2);
+        assert_eq!(reason("open"), Some(EndReason::ServiceRestart));
+        assert_eq!(reason("closed"), Some(EndReason::Terminated));
+
+        let connection = store.connection().unwrap();
+        let events: Vec<String> = connection
+            .prepare("SELECT event FROM session_events WHERE session_id = 'closed' ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(events, ["created", "attached", "ended"]);
+    }
+}
@@ -0,0 +1,283 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::any::Any;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::settings::BackgroundTaskSettings;
+use crate::AppState;
+
+/// What is done when a background task panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Left failed
+    Never,
+    /// Started again after `background_tasks.restart_delay_seconds`, up to `max_restarts` times
+    OnPanic,
+}
+
+/// Where a background task is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Panicked, and waiting to be started again
+    Restarting,
+    /// Panicked, and not to be started again
+    Failed,
+    /// Returned of its own accord
+    Finished,
+    /// Stopped by the shutdown
+    Stopped,
+}
+
+/// A background task, as reported by `/api/admin/tasks`
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub state: TaskState,
+    pub restart_policy: RestartPolicy,
+    pub started_at: DateTime<Utc>,
+    /// When the task last finished a pass of its work
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub runs: u64,
+    pub restarts: u32,
+    /// What the task last panicked with
+    pub last_panic: Option<String>,
+    pub last_panic_at: Option<DateTime<Utc>>,
+}
+
+/// A running task's handle on its status
+#[derive(Clone)]
+pub struct TaskContext {
+    status: Arc<Mutex<TaskStatus>>,
+}
+
+impl TaskContext {
+    /// Notes that the task finished a pass of its work
+    pub fn ran(&self) {
+        let mut status = lock(&self.status);
+        status.last_run_at = Some(Utc::now());
+        status.runs += 1;
+    }
+}
+
+/// Runs the gateway's background loops: session cleanup, lifetime checks, the watchdog, settings reloading and configuration backups
+///
+/// Each task is spawned by name. A task that panics is logged and, by its
+/// restart policy, started afresh after a delay; one that returns is left
+/// finished. At shutdown every task is stopped and waited for.
+pub struct TaskSupervisor {
+    settings: BackgroundTaskSettings,
+    shutdown: CancellationToken,
+    tasks: Mutex<Vec<Arc<Mutex<TaskStatus>>>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl TaskSupervisor {
+    pub fn new(settings: &BackgroundTaskSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            shutdown: CancellationToken::new(),
+            tasks: Mutex::new(Vec::new()),
+            handles: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Starts a task; `run` is called again for each restart
+    pub fn spawn<F, Fut>(&self, name: &'static str, restart_policy: RestartPolicy, run: F)
+    where
+        F: Fn(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let status = Arc::new(Mutex::new(TaskStatus {
+            name,
+            state: TaskState::Running,
+            restart_policy,
+            started_at: Utc::now(),
+            last_run_at: None,
+            runs: 0,
+            restarts: 0,
+            last_panic: None,
+            last_panic_at: None,
+        }));
+        lock(&self.tasks).push(status.clone());
+
+        let shutdown = self.shutdown.clone();
+        let delay = Duration::from_secs(self.settings.restart_delay_seconds);
+        let max_restarts = self.settings.max_restarts;
+        let handle = tokio::spawn(async move {
+            let context = TaskContext { status: status.clone() };
+            loop {
+                let mut task = tokio::spawn(run(context.clone()));
+                let outcome = tokio::select! {
+                    outcome = &mut task => outcome,
+                    _ = shutdown.cancelled() => {
+                        task.abort();
+                        let _ = task.await;
+                        lock(&status).state = TaskState::Stopped;
+                        return;
+                    }
+                };
+                let panic = match outcome {
+                    Ok(()) => {
+                        info!("Background task {} finished", name);
+                        lock(&status).state = TaskState::Finished;
+                        return;
+                    }
+                    Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                    Err(_) => {
+                        lock(&status).state = TaskState::Stopped;
+                        return;
+                    }
+                };
+
+                let restarts = {
+                    let mut status = lock(&status);
+                    status.last_panic = Some(panic.clone());
+                    status.last_panic_at = Some(Utc::now());
+                    if restart_policy == RestartPolicy::Never || status.restarts >= max_restarts {
+                        status.state = TaskState::Failed;
+                        None
+                    } else {
+                        status.state = TaskState::Restarting;
+                        Some(status.restarts)
+                    }
+                };
+                let Some(restarts) = restarts else {
+                    error!("Background task {} panicked and will not be restarted: {}", name, panic);
+                    return;
+                };
+                warn!("Background task {} panicked, restarting in {:?} ({} of {}): {}",
+                      name, delay, restarts + 1, max_restarts, panic);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => {
+                        lock(&status).state = TaskState::Stopped;
+                        return;
+                    }
+                }
+                let mut status = lock(&status);
+                status.restarts += 1;
+                status.state = TaskState::Running;
+            }
+        });
+        lock(&self.handles).push(handle);
+    }
+
+    /// Every task started, in the order they were
+    pub fn report(&self) -> Vec<TaskStatus> {
+        lock(&self.tasks).iter().map(|status| lock(status).clone()).collect()
+    }
+
+    /// Stops every task, waiting for each to end
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        let handles = std::mem::take(&mut *lock(&self.handles));
+        for handle in handles {
+            let _ = handle.await;
+        }
+        info!("Background tasks stopped");
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The message a task panicked with, if it was a string
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map(|message| message.to_string())
+            .unwrap_or_else(|| "panicked with a non-string payload".to_string()),
+    }
+}
+
+/// Lists the background tasks with their state, last run and panics
+pub async fn list_handler(State(state): State<AppState>) -> Response {
+    Json(json!({ "tasks": state.tasks.report() })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn supervisor(max_restarts: u32) -> TaskSupervisor {
+        TaskSupervisor::new(&BackgroundTaskSettings { restart_delay_seconds: 0, max_restarts })
+    }
+
+    /// Waits for the first task to be left failed
+    async fn failed(supervisor: &TaskSupervisor) -> TaskStatus {
+        for _ in 0..500 {
+            let status = supervisor.report().remove(0);
+            if status.state == TaskState::Failed {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("task did not fail");
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_is_restarted_until_the_limit() {
+        let supervisor = supervisor(2);
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counted = attempts.clone();
+        supervisor.spawn("flaky", RestartPolicy::OnPanic, move |task| {
+            let attempts = counted.clone();
+            async move {
+                task.ran();
+                attempts.fetch_add(1, Ordering::SeqCst);
+                panic!("pass failed");
+            }
+        });
+
+        let status = failed(&supervisor).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.runs, 3);
+        assert_eq!(status.last_panic.as_deref(), Some("pass failed"));
+    }
+
+    #[tokio::test]
+    async fn test_task_without_restart_fails_on_first_panic() {
+        let supervisor = supervisor(5);
+        supervisor.spawn("once", RestartPolicy::Never, |_| async { panic!("{}", "formatted".to_string()) });
+
+        let status = failed(&supervisor).await;
+        assert_eq!(status.restarts, 0);
+        assert_eq!(status.last_panic.as_deref(), Some("formatted"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_running_tasks() {
+        let supervisor = supervisor(5);
+        supervisor.spawn("loop", RestartPolicy::OnPanic, |task| async move {
+            loop {
+                task.ran();
+                tokio::time::sleep(Duration::from_millis(40)).await;
+            }
+        });
+        supervisor.spawn("done", RestartPolicy::OnPanic, |_| async {});
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        supervisor.shutdown().await;
+        let report = supervisor.report();
+        assert_eq!(report[0].state, TaskState::Stopped);
+        assert!(report[0].runs >= 2);
+        assert_eq!(report[1].state, TaskState::Finished);
+    }
+}
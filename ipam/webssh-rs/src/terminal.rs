@@ -0,0 +1,392 @@
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::settings::{PtyModeValue, TerminalDowngradeSettings, TerminalSettings};
+use crate::ssh::{PtyRequest, TerminalSize};
+
+/// Smallest terminal a shell is given, as for resizes
+const MIN_COLS: u32 = 80;
+const MIN_ROWS: u32 = 24;
+/// Largest terminal a connect request may ask for, in either direction
+const MAX_DIMENSION: u32 = 1000;
+/// Longest environment variable value passed on to a device
+const MAX_ENV_VALUE: usize = 1024;
+/// Largest terminal size in pixels a connect request may ask for, in either direction
+const MAX_PIXELS: u32 = 65535;
+
+/// What a PTY mode's value is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PtyModeKind {
+    Flag,
+    /// A control character, 255 for none
+    Character,
+    /// A line speed in bits per second
+    Speed,
+}
+
+/// The PTY modes SSH can set (RFC 4254 section 8), by termios name, with their opcodes
+const PTY_MODES: &[(&str, u8, PtyModeKind)] = &[
+    ("VINTR", 1, PtyModeKind::Character),
+    ("VQUIT", 2, PtyModeKind::Character),
+    ("VERASE", 3, PtyModeKind::Character),
+    ("VKILL", 4, PtyModeKind::Character),
+    ("VEOF", 5, PtyModeKind::Character),
+    ("VEOL", 6, PtyModeKind::Character),
+    ("VEOL2", 7, PtyModeKind::Character),
+    ("VSTART", 8, PtyModeKind::Character),
+    ("VSTOP", 9, PtyModeKind::Character),
+    ("VSUSP", 10, PtyModeKind::Character),
+    ("VDSUSP", 11, PtyModeKind::Character),
+    ("VREPRINT", 12, PtyModeKind::Character),
+    ("VWERASE", 13, PtyModeKind::Character),
+    ("VLNEXT", 14, PtyModeKind::Character),
+    ("VFLUSH", 15, PtyModeKind::Character),
+    ("VSWTCH", 16, PtyModeKind::Character),
+    ("VSTATUS", 17, PtyModeKind::Character),
+    ("VDISCARD", 18, PtyModeKind::Character),
+    ("IGNPAR", 30, PtyModeKind::Flag),
+    ("PARMRK", 31, PtyModeKind::Flag),
+    ("INPCK", 32, PtyModeKind::Flag),
+    ("ISTRIP", 33, PtyModeKind::Flag),
+    ("INLCR", 34, PtyModeKind::Flag),
+    ("IGNCR", 35, PtyModeKind::Flag),
+    ("ICRNL", 36, PtyModeKind::Flag),
+    ("IUCLC", 37, PtyModeKind::Flag),
+    ("IXON", 38, PtyModeKind::Flag),
+    ("IXANY", 39, PtyModeKind::Flag),
+    ("IXOFF", 40, PtyModeKind::Flag),
+    ("IMAXBEL", 41, PtyModeKind::Flag),
+    ("IUTF8", 42, PtyModeKind::Flag),
+    ("ISIG", 50, PtyModeKind::Flag),
+    ("ICANON", 51, PtyModeKind::Flag),
+    ("XCASE", 52, PtyModeKind::Flag),
+    ("ECHO", 53, PtyModeKind::Flag),
+    ("ECHOE", 54, PtyModeKind::Flag),
+    ("ECHOK", 55, PtyModeKind::Flag),
+    ("ECHONL", 56, PtyModeKind::Flag),
+    ("NOFLSH", 57, PtyModeKind::Flag),
+    ("TOSTOP", 58, PtyModeKind::Flag),
+    ("IEXTEN", 59, PtyModeKind::Flag),
+    ("ECHOCTL", 60, PtyModeKind::Flag),
+    ("ECHOKE", 61, PtyModeKind::Flag),
+    ("PENDIN", 62, PtyModeKind::Flag),
+    ("OPOST", 70, PtyModeKind::Flag),
+    ("OLCUC", 71, PtyModeKind::Flag),
+    ("ONLCR", 72, PtyModeKind::Flag),
+    ("OCRNL", 73, PtyModeKind::Flag),
+    ("ONOCR", 74, PtyModeKind::Flag),
+    ("ONLRET", 75, PtyModeKind::Flag),
+    ("CS7", 90, PtyModeKind::Flag),
+    ("CS8", 91, PtyModeKind::Flag),
+    ("PARENB", 92, PtyModeKind::Flag),
+    ("PARODD", 93, PtyModeKind::Flag),
+    ("TTY_OP_ISPEED", 128, PtyModeKind::Speed),
+    ("TTY_OP_OSPEED", 129, PtyModeKind::Speed),
+];
+
+/// Terminal types devices were found not to handle, and what they get instead
+///
+/// A PTY's TERM cannot be changed once the shell is open, so a downgrade
+/// applies to the next shell opened to the device. Downgrades are kept in
+/// memory and forgotten on restart.
+pub struct TerminalTypes {
+    settings: TerminalDowngradeSettings,
+    // host:port -> terminal type to open shells with
+    downgraded: Mutex<HashMap<String, String>>,
+}
+
+impl TerminalTypes {
+    pub fn new(settings: TerminalDowngradeSettings) -> Self {
+        Self { settings, downgraded: Mutex::new(HashMap::new()) }
+    }
+
+    /// The terminal type a device was downgraded to, if any
+    pub fn downgraded(&self, hostname: &str, port: u16) -> Option<String> {
+        self.downgraded.lock().unwrap().get(&device_key(hostname, port)).cloned()
+    }
+
+    /// Starts watching a new shell's output for signs that its terminal type is not understood
+    ///
+    /// # Returns
+    /// * `Option<TerminalWatch>` - The watch, or `None` if downgrades are disabled or the
+    ///   terminal type cannot be stepped down
+    pub fn watch(
+        self: &Arc<Self>,
+        hostname: &str,
+        port: u16,
+        terminal_type: &str,
+        notifications: broadcast::Sender<serde_json::Value>,
+    ) -> Option<TerminalWatch> {
+        if !self.settings.enabled || self.next(terminal_type).is_none() {
+            return None;
+        }
+        Some(TerminalWatch {
+            types: self.clone(),
+            device: device_key(hostname, port),
+            terminal_type: terminal_type.to_string(),
+            notifications,
+            seen: String::new(),
+            done: false,
+        })
+    }
+
+    /// The terminal type following one in `ssh.terminal.downgrade.order`
+    fn next(&self, terminal_type: &str) -> Option<&str> {
+        let order = &self.settings.order;
+        let position = order.iter().position(|listed| listed.eq_ignore_ascii_case(terminal_type))?;
+        order.get(position + 1).map(String::as_str)
+    }
+}
+
+/// Scans the start of a shell's output for complaints about its terminal type
+pub struct TerminalWatch {
+    types: Arc<TerminalTypes>,
+    device: String,
+    terminal_type: String,
+    notifications: broadcast::Sender<serde_json::Value>,
+    // Output seen so far, lowercased, up to `scan_bytes`
+    seen: String,
+    done: bool,
+}
+
+impl TerminalWatch {
+    /// Looks at more of the shell's output, downgrading the device on the first match
+    pub fn scan(&mut self, data: &[u8]) {
+        if self.done {
+            return;
+        }
+        let settings = &self.types.settings;
+        let room = settings.scan_bytes.saturating_sub(self.seen.len());
+        self.seen.push_str(&String::from_utf8_lossy(&data[..data.len().min(room)]).to_lowercase());
+        let matched = settings.patterns.iter()
+            .find(|pattern| !pattern.is_empty() && self.seen.contains(&pattern.to_lowercase()));
+        if let Some(pattern) = matched {
+            self.done = true;
+            self.downgrade(pattern);
+        } else if self.seen.len() >= settings.scan_bytes {
+            self.done = true;
+        }
+    }
+
+    fn downgrade(&self, pattern: &str) {
+        let Some(next) = self.types.next(&self.terminal_type) else {
+            return;
+        };
+        warn!("Device {} does not handle terminal type {} (output matched \"{}\"); its next shells get {}",
+              self.device, self.terminal_type, pattern, next);
+        self.types.downgraded.lock().unwrap().insert(self.device.clone(), next.to_string());
+        let _ = self.notifications.send(json!({
+            "type": "terminal_downgraded",
+            "terminal_type": self.terminal_type,
+            "next_terminal_type": next,
+        }));
+    }
+}
+
+/// The terminal a connect request asks for, checked against the settings
+#[derive(Debug, Default)]
+pub struct RequestedTerminal {
+    pub terminal_type: Option<String>,
+    pub size: Option<TerminalSize>,
+    pub environment: Vec<(String, String)>,
+    pub pty: PtyRequest,
+    /// What was left out of the request and why, for the connect response
+    pub warnings: Vec<String>,
+}
+
+impl RequestedTerminal {
+    /// Checks a request's `term`, `cols`, `rows` and `env`
+    ///
+    /// Nothing here is worth refusing a connection over: a TERM that is not a
+    /// plain name and variables the settings do not allow are left out with a
+    /// warning, and the size is kept within the bounds resizes are.
+    pub fn check(
+        settings: &TerminalSettings,
+        term: Option<&str>,
+        cols: Option<u32>,
+        rows: Option<u32>,
+        env: &BTreeMap<String, String>,
+    ) -> Self {
+        let mut requested = Self::default();
+        match term {
+            Some(term) if valid_terminal_type(term) => requested.terminal_type = Some(term.to_string()),
+            Some(term) => requested.warnings.push(format!("Terminal type '{}' is not valid; using the default", term)),
+            None => {}
+        }
+        if cols.is_some() || rows.is_some() {
+            requested.size = Some(TerminalSize {
+                cols: cols.unwrap_or(settings.default_cols).clamp(MIN_COLS, MAX_DIMENSION),
+                rows: rows.unwrap_or(settings.default_rows).clamp(MIN_ROWS, MAX_DIMENSION),
+            });
+        }
+        for (name, value) in env {
+            if !valid_env_name(name) {
+                requested.warnings.push(format!("Environment variable '{}' is not a valid name", name));
+            } else if !settings.allowed_environment.iter().any(|allowed| env_allowed(allowed, name)) {
+                requested.warnings.push(format!("Environment variable {} is not allowed", name));
+            } else if value.len() > MAX_ENV_VALUE || value.chars().any(char::is_control) {
+                requested.warnings.push(format!("Environment variable {} has an invalid value", name));
+            } else {
+                requested.environment.push((name.clone(), value.clone()));
+            }
+        }
+        requested
+    }
+
+    /// Checks a request's `pty_modes`, `width_px` and `height_px`
+    ///
+    /// Modes SSH cannot set or with values of the wrong kind are left out with a warning.
+    pub fn with_pty(
+        mut self,
+        modes: &BTreeMap<String, PtyModeValue>,
+        width_px: Option<u32>,
+        height_px: Option<u32>,
+    ) -> Self {
+        for (name, value) in modes {
+            match pty_mode(name, *value) {
+                Ok(_) => {
+                    self.pty.modes.insert(name.to_ascii_uppercase(), *value);
+                }
+                Err(e) => self.warnings.push(e),
+            }
+        }
+        self.pty.width_px = width_px.map(|width| width.min(MAX_PIXELS));
+        self.pty.height_px = height_px.map(|height| height.min(MAX_PIXELS));
+        self
+    }
+}
+
+/// The opcode and encoded value of a PTY mode, by its termios name
+fn pty_mode(name: &str, value: PtyModeValue) -> Result<(u8, u32), String> {
+    let name = name.to_ascii_uppercase();
+    let Some(&(_, opcode, kind)) = PTY_MODES.iter().find(|(mode, ..)| *mode == name) else {
+        return Err(match name.as_str() {
+            "VMIN" | "VTIME" => format!("PTY mode {} cannot be set over SSH; turn ICANON off for unbuffered input", name),
+            _ => format!("PTY mode {} is not one SSH can set", name),
+        });
+    };
+    match (kind, value) {
+        (PtyModeKind::Flag, PtyModeValue::Flag(flag)) => Ok((opcode, flag.into())),
+        (PtyModeKind::Flag, PtyModeValue::Number(number @ (0 | 1))) => Ok((opcode, number)),
+        (PtyModeKind::Character, PtyModeValue::Number(number @ 0..=255)) => Ok((opcode, number)),
+        (PtyModeKind::Speed, PtyModeValue::Number(number)) => Ok((opcode, number)),
+        (PtyModeKind::Flag, _) => Err(format!("PTY mode {} takes true or false", name)),
+        (PtyModeKind::Character, _) => Err(format!("PTY mode {} takes a character code from 0 to 255", name)),
+        (PtyModeKind::Speed, _) => Err(format!("PTY mode {} takes a speed in bits per second", name)),
+    }
+}
+
+/// Encodes PTY modes for a PTY request, leaving out any SSH cannot set
+///
+/// # Returns
+/// * `Option<ssh2::PtyModes>` - The modes, or `None` to request the server's defaults
+pub fn encode_pty_modes(modes: &BTreeMap<String, PtyModeValue>) -> Option<ssh2::PtyModes> {
+    if modes.is_empty() {
+        return None;
+    }
+    let mut encoded = ssh2::PtyModes::new();
+    for (opcode, value) in modes.iter().filter_map(|(name, value)| pty_mode(name, *value).ok()) {
+        encoded.set_u32(opcode, value);
+    }
+    Some(encoded)
+}
+
+/// Checks the PTY modes of the settings, so a typo is reported at startup rather than ignored
+pub fn validate(settings: &TerminalSettings) -> Result<(), String> {
+    settings.pty_modes.iter()
+        .try_for_each(|(name, value)| pty_mode(name, *value).map(drop))
+}
+
+/// A terminfo name, e.g. "xterm-256color" or "vt100"
+fn valid_terminal_type(term: &str) -> bool {
+    !term.is_empty() && term.len() <= 64
+        && term.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+}
+
+fn valid_env_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whether an `allowed_environment` entry, a name or a prefix ending in `*`, admits a variable
+fn env_allowed(allowed: &str, name: &str) -> bool {
+    match allowed.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => allowed == name,
+    }
+}
+
+fn device_key(hostname: &str, port: u16) -> String {
+    format!("{}:{}", hostname.to_lowercase(), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_device_is_downgraded_on_complaint() {
+        let settings = Settings::default();
+        let types = Arc::new(TerminalTypes::new(settings.ssh.terminal.downgrade.clone()));
+        let (notifications, mut rx) = broadcast::channel(4);
+
+        assert!(types.watch("core-1", 22, "dumb", notifications.clone()).is_none());
+        let mut watch = types.watch("core-1", 22, "xterm", notifications).unwrap();
+        watch.scan(b"Welcome\r\nWARNING: terminal is not ");
+        assert_eq!(types.downgraded("CORE-1", 22), None);
+        watch.scan(b"fully functional\r\n");
+        assert_eq!(types.downgraded("CORE-1", 22).as_deref(), Some("vt100"));
+        assert_eq!(rx.try_recv().unwrap()["next_terminal_type"], "vt100");
+    }
+
+    #[test]
+    fn test_requested_terminal_is_checked() {
+        let settings = Settings::default().ssh.terminal;
+        let env: BTreeMap<String, String> = [
+            ("LANG", "en_US.UTF-8"),
+            ("LC_ALL", "C"),
+            ("PATH", "/tmp"),
+            ("1BAD", "x"),
+            ("TZ", "UTC\nPATH=/tmp"),
+        ].into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+
+        let requested = RequestedTerminal::check(&settings, Some("xterm-256color"), Some(200), Some(10), &env);
+        assert_eq!(requested.terminal_type.as_deref(), Some("xterm-256color"));
+        assert_eq!(requested.size, Some(TerminalSize { cols: 200, rows: MIN_ROWS }));
+        assert_eq!(requested.environment, vec![
+            ("LANG".to_string(), "en_US.UTF-8".to_string()),
+            ("LC_ALL".to_string(), "C".to_string()),
+        ]);
+        assert_eq!(requested.warnings.len(), 3);
+
+        let requested = RequestedTerminal::check(&settings, Some("xterm; reboot"), None, Some(50), &BTreeMap::new());
+        assert_eq!(requested.terminal_type, None);
+        assert_eq!(requested.size, Some(TerminalSize { cols: settings.default_cols, rows: 50 }));
+        assert_eq!(requested.warnings.len(), 1);
+        assert!(RequestedTerminal::check(&settings, None, None, None, &BTreeMap::new()).size.is_none());
+    }
+
+    #[test]
+    fn test_pty_modes_are_checked() {
+        let modes: BTreeMap<String, PtyModeValue> = [
+            ("echo", PtyModeValue::Flag(false)),
+            ("VINTR", PtyModeValue::Number(3)),
+            ("VMIN", PtyModeValue::Number(1)),
+            ("ICANON", PtyModeValue::Number(7)),
+        ].into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+        let requested = RequestedTerminal::default().with_pty(&modes, Some(1280), Some(100_000));
+        assert_eq!(requested.pty.modes.keys().collect::<Vec<_>>(), ["ECHO", "VINTR"]);
+        assert_eq!(requested.warnings.len(), 2);
+        assert!(requested.warnings.iter().any(|warning| warning.contains("ICANON off")));
+        assert_eq!((requested.pty.width_px, requested.pty.height_px), (Some(1280), Some(MAX_PIXELS)));
+
+        // Opcode and big-endian value of each mode, then TTY_OP_END
+        let encoded = encode_pty_modes(&requested.pty.modes).unwrap().finish();
+        assert_eq!(encoded, [53, 0, 0, 0, 0, 1, 0, 0, 0, 3, 0]);
+        assert!(encode_pty_modes(&BTreeMap::new()).is_none());
+    }
+}
@@ -0,0 +1,387 @@
+use regex::Regex;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// A TextFSM template that failed to load or aborted a parse
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+    #[error("line {0}: invalid regex: {1}")]
+    Regex(usize, regex::Error),
+    /// An `Error` action matched the output
+    #[error("template rejected the output: {0}")]
+    Rejected(String),
+}
+
+#[derive(Debug, Default)]
+struct ValueOptions {
+    filldown: bool,
+    required: bool,
+    list: bool,
+    fillup: bool,
+}
+
+/// A `Value` line: a named field and the regex capturing it
+#[derive(Debug)]
+struct ValueDef {
+    name: String,
+    regex: String,
+    options: ValueOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineOp {
+    Next,
+    Continue,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordOp {
+    NoRecord,
+    Record,
+    Clear,
+    Clearall,
+}
+
+#[derive(Debug)]
+struct Rule {
+    regex: Regex,
+    line_op: LineOp,
+    record_op: RecordOp,
+    new_state: Option<String>,
+    // Message of an `Error` action
+    message: Option<String>,
+}
+
+/// A parsed TextFSM template, as used by ntc-templates
+///
+/// The template declares `Value`s, then states of `^regex -> Action` rules.
+/// Output is read line by line from the `Start` state; `Record` actions turn
+/// the values captured so far into a row.
+#[derive(Debug)]
+pub struct Template {
+    values: Vec<ValueDef>,
+    states: HashMap<String, Vec<Rule>>,
+}
+
+/// A value while the output is read: one capture, or several for `List` values
+#[derive(Debug, Clone)]
+enum Cell {
+    Empty,
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Cell {
+    fn is_empty(&self) -> bool {
+        match self {
+            Cell::Empty => true,
+            Cell::One(_) => false,
+            Cell::Many(items) => items.is_empty(),
+        }
+    }
+
+    fn to_json(&self, list: bool) -> JsonValue {
+        match self {
+            Cell::One(value) => JsonValue::String(value.clone()),
+            Cell::Many(items) => JsonValue::from(items.clone()),
+            Cell::Empty if list => JsonValue::Array(Vec::new()),
+            Cell::Empty => JsonValue::String(String::new()),
+        }
+    }
+}
+
+impl Template {
+    /// Parses a template's text
+    pub fn parse(text: &str) -> Result<Self, TemplateError> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+        let mut values = Vec::new();
+
+        // Values come first, up to the first blank line after them
+        for (number, line) in lines.by_ref() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            if line.trim().is_empty() {
+                if values.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            values.push(parse_value(number, line)?);
+        }
+
+        let mut states: HashMap<String, Vec<Rule>> = HashMap::new();
+        let mut current: Option<String> = None;
+        for (number, line) in lines {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            if !line.starts_with(char::is_whitespace) {
+                let name = line.trim().to_string();
+                if states.contains_key(&name) {
+                    return Err(TemplateError::Syntax(number, format!("state {} is defined twice", name)));
+                }
+                states.insert(name.clone(), Vec::new());
+                current = Some(name);
+                continue;
+            }
+            let Some(state) = current.as_ref() else {
+                return Err(TemplateError::Syntax(number, "rule outside of a state".to_string()));
+            };
+            let rule = parse_rule(number, line.trim(), &values)?;
+            if let Some(rules) = states.get_mut(state) {
+                rules.push(rule);
+            }
+        }
+
+        if !states.contains_key("Start") {
+            return Err(TemplateError::Syntax(0, "no Start state".to_string()));
+        }
+        for rules in states.values() {
+            for rule in rules {
+                if let Some(state) = &rule.new_state {
+                    if state != "End" && state != "EOF" && !states.contains_key(state) {
+                        return Err(TemplateError::Syntax(0, format!("unknown state {}", state)));
+                    }
+                }
+            }
+        }
+        Ok(Self { values, states })
+    }
+
+    /// Reads command output into rows, keyed by the lowercase value names
+    pub fn run(&self, output: &str) -> Result<Vec<Map<String, JsonValue>>, TemplateError> {
+        let mut state = "Start";
+        let mut record = vec![Cell::Empty; self.values.len()];
+        let mut rows: Vec<Vec<Cell>> = Vec::new();
+
+        'lines: for line in output.lines() {
+            let rules = self.states.get(state).map(Vec::as_slice).unwrap_or_default();
+            for rule in rules {
+                let Some(captures) = rule.regex.captures(line) else {
+                    continue;
+                };
+                for (index, value) in self.values.iter().enumerate() {
+                    let Some(capture) = captures.name(&value.name) else {
+                        continue;
+                    };
+                    let captured = capture.as_str().to_string();
+                    if value.options.fillup {
+                        // Earlier rows missing the value take it, up to the last that has one
+                        for row in rows.iter_mut().rev() {
+                            if !row[index].is_empty() {
+                                break;
+                            }
+                            row[index] = Cell::One(captured.clone());
+                        }
+                    }
+                    record[index] = match (&mut record[index], value.options.list) {
+                        (Cell::Many(items), true) => {
+                            items.push(captured);
+                            continue;
+                        }
+                        (_, true) => Cell::Many(vec![captured]),
+                        (_, false) => Cell::One(captured),
+                    };
+                }
+
+                if rule.line_op == LineOp::Error {
+                    let message = rule.message.clone().unwrap_or_else(|| format!("state {}, line {:?}", state, line));
+                    return Err(TemplateError::Rejected(message));
+                }
+                match rule.record_op {
+                    RecordOp::NoRecord => {}
+                    RecordOp::Record => self.record(&mut record, &mut rows),
+                    RecordOp::Clear => self.clear(&mut record, false),
+                    RecordOp::Clearall => self.clear(&mut record, true),
+                }
+                if let Some(new_state) = &rule.new_state {
+                    if new_state == "End" {
+                        return Ok(self.rows(rows));
+                    }
+                    state = new_state;
+                }
+                if rule.line_op == LineOp::Next {
+                    continue 'lines;
+                }
+            }
+        }
+
+        // Reaching the end of the output records what is left, unless the template has an EOF state
+        if !self.states.contains_key("EOF") {
+            self.record(&mut record, &mut rows);
+        }
+        Ok(self.rows(rows))
+    }
+
+    fn record(&self, record: &mut [Cell], rows: &mut Vec<Vec<Cell>>) {
+        let missing_required = self.values.iter().zip(record.iter())
+            .any(|(value, cell)| value.options.required && cell.is_empty());
+        if !missing_required && !record.iter().all(Cell::is_empty) {
+            rows.push(record.to_vec());
+        }
+        self.clear(record, false);
+    }
+
+    fn clear(&self, record: &mut [Cell], all: bool) {
+        for (value, cell) in self.values.iter().zip(record.iter_mut()) {
+            if all || !value.options.filldown {
+                *cell = Cell::Empty;
+            }
+        }
+    }
+
+    fn rows(&self, rows: Vec<Vec<Cell>>) -> Vec<Map<String, JsonValue>> {
+        rows.into_iter()
+            .map(|row| self.values.iter().zip(row.iter())
+                .map(|(value, cell)| (value.name.to_lowercase(), cell.to_json(value.options.list)))
+                .collect())
+            .collect()
+    }
+}
+
+/// Parses `Value [Option,...] Name (regex)`
+fn parse_value(number: usize, line: &str) -> Result<ValueDef, TemplateError> {
+    let syntax = |message: &str| TemplateError::Syntax(number, message.to_string());
+    let rest = line.strip_prefix("Value ").ok_or_else(|| syntax("expected a Value line"))?.trim();
+    let (first, rest) = rest.split_once(char::is_whitespace).ok_or_else(|| syntax("expected a name and a regex"))?;
+    let rest = rest.trim_start();
+    let (options, name, regex) = if rest.starts_with('(') {
+        ("", first, rest)
+    } else {
+        let (name, regex) = rest.split_once(char::is_whitespace).ok_or_else(|| syntax("expected a regex"))?;
+        (first, name, regex.trim_start())
+    };
+    if !regex.starts_with('(') || !regex.ends_with(')') {
+        return Err(syntax("the regex must be enclosed in parentheses"));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(syntax("value names may only have letters, digits and underscores"));
+    }
+
+    let mut parsed = ValueOptions::default();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option {
+            "Filldown" => parsed.filldown = true,
+            "Required" => parsed.required = true,
+            "List" => parsed.list = true,
+            "Fillup" => parsed.fillup = true,
+            "Key" => {}
+            _ => return Err(syntax(&format!("unknown option {}", option))),
+        }
+    }
+    Ok(ValueDef { name: name.to_string(), regex: regex.to_string(), options: parsed })
+}
+
+/// Parses `^regex [-> Action]`, substituting `${Name}` with the value's regex
+fn parse_rule(number: usize, line: &str, values: &[ValueDef]) -> Result<Rule, TemplateError> {
+    let syntax = |message: String| TemplateError::Syntax(number, message);
+    if !line.starts_with('^') {
+        return Err(syntax("rules must start with ^".to_string()));
+    }
+    let (pattern, action) = match line.rsplit_once(" -> ") {
+        Some((pattern, action)) => (pattern.trim_end(), action.trim()),
+        None => (line, ""),
+    };
+
+    let mut regex = pattern.replace("$$", "\u{0}");
+    for value in values {
+        let group = format!("(?P<{}>{}", value.name, &value.regex[1..]);
+        regex = regex.replace(&format!("${{{}}}", value.name), &group);
+    }
+    if regex.contains("${") {
+        return Err(syntax(format!("unknown value in {}", pattern)));
+    }
+    let regex = Regex::new(&regex.replace('\u{0}', "$")).map_err(|e| TemplateError::Regex(number, e))?;
+
+    let mut rule = Rule { regex, line_op: LineOp::Next, record_op: RecordOp::NoRecord, new_state: None, message: None };
+    let (operation, argument) = match action.split_once(char::is_whitespace) {
+        Some((operation, argument)) => (operation, Some(argument.trim())),
+        None => (action, None),
+    };
+    let (line_op, record_op) = match operation.split_once('.') {
+        Some((line_op, record_op)) => (Some(line_op), Some(record_op)),
+        None if matches!(operation, "Next" | "Continue" | "Error") => (Some(operation), None),
+        None if matches!(operation, "NoRecord" | "Record" | "Clear" | "Clearall") => (None, Some(operation)),
+        // A bare state name
+        None => {
+            if !operation.is_empty() {
+                rule.new_state = Some(operation.to_string());
+            }
+            (None, None)
+        }
+    };
+    if let Some(line_op) = line_op {
+        rule.line_op = match line_op {
+            "Next" => LineOp::Next,
+            "Continue" => LineOp::Continue,
+            "Error" => LineOp::Error,
+            _ => return Err(syntax(format!("unknown line action {}", line_op))),
+        };
+    }
+    if let Some(record_op) = record_op {
+        rule.record_op = match record_op {
+            "NoRecord" => RecordOp::NoRecord,
+            "Record" => RecordOp::Record,
+            "Clear" => RecordOp::Clear,
+            "Clearall" => RecordOp::Clearall,
+            _ => return Err(syntax(format!("unknown record action {}", record_op))),
+        };
+    }
+    if let Some(argument) = argument {
+        if rule.line_op == LineOp::Error {
+            rule.message = Some(argument.trim_matches('"').to_string());
+        } else if rule.new_state.is_none() {
+            rule.new_state = Some(argument.to_string());
+        } else {
+            return Err(syntax(format!("unexpected {}", argument)));
+        }
+    }
+    if rule.line_op == LineOp::Continue && rule.new_state.is_some() {
+        return Err(syntax("Continue cannot change state".to_string()));
+    }
+    Ok(rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = r#"# Interfaces with their addresses
+Value Filldown DEVICE (\S+)
+Value Required INTERFACE (\S+)
+Value IP_ADDRESS (\S+)
+Value List FLAGS (\w+)
+
+Start
+  ^${DEVICE}# -> Continue
+  ^Interface -> Next
+  ^${INTERFACE}\s+${IP_ADDRESS}\s*$$ -> Record
+  ^\s+flag ${FLAGS}
+  ^${INTERFACE}\s+unassigned -> Record
+  ^% -> Error "command rejected"
+"#;
+
+    #[test]
+    fn test_template_records_rows() {
+        let template = Template::parse(TEMPLATE).unwrap();
+        let output = "core-1# show ip interface brief\nInterface IP-Address\nGi0/0 10.0.0.1\n  flag up\n  flag routed\nGi0/1 unassigned\n";
+        let rows = template.run(output).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["device"], "core-1");
+        assert_eq!(rows[0]["interface"], "Gi0/0");
+        assert_eq!(rows[0]["ip_address"], "10.0.0.1");
+        assert_eq!(rows[0]["flags"], serde_json::json!([]));
+        // List values gather until the next record; filldown values carry over
+        assert_eq!(rows[1]["device"], "core-1");
+        assert_eq!(rows[1]["interface"], "Gi0/1");
+        assert_eq!(rows[1]["flags"], serde_json::json!(["up", "routed"]));
+
+        assert!(matches!(template.run("% Invalid input"), Err(TemplateError::Rejected(message)) if message == "command rejected"));
+        assert!(Template::parse("Value X (\\S+)\n\nStart\n  ^${Y} -> Record\n").is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tracing::{debug, error, warn};
+
+use crate::file_server::{FileServer, FileServerError};
+
+// Opcodes (RFC 1350, RFC 2347)
+const OP_RRQ: u16 = 1;
+const OP_WRQ: u16 = 2;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+// Error codes
+const ERR_NOT_DEFINED: u16 = 0;
+const ERR_FILE_NOT_FOUND: u16 = 1;
+const ERR_ACCESS_VIOLATION: u16 = 2;
+const ERR_ILLEGAL_OPERATION: u16 = 4;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Block sizes a client may negotiate (RFC 2348)
+const BLOCK_SIZE_RANGE: std::ops::RangeInclusive<usize> = 8..=65464;
+/// Transmissions of a packet before the transfer is given up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// A read request, with the options the client asked for
+#[derive(Debug, PartialEq)]
+pub struct ReadRequest {
+    pub filename: String,
+    pub mode: String,
+    pub options: Vec<(String, String)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Read(ReadRequest),
+    Write,
+}
+
+/// Parses the first packet of a transfer
+///
+/// # Returns
+/// * `Option<Request>` - The request, or None if the packet is malformed or not a request
+pub fn parse_request(packet: &[u8]) -> Option<Request> {
+    let opcode = u16::from_be_bytes([*packet.first()?, *packet.get(1)?]);
+    let mut fields = packet[2..].split(|&b| b == 0).map(|field| String::from_utf8_lossy(field).into_owned());
+    match opcode {
+        OP_RRQ => {
+            let filename = fields.next().filter(|filename| !filename.is_empty())?;
+            let mode = fields.next()?.to_ascii_lowercase();
+            let mut options = Vec::new();
+            while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+                if !name.is_empty() {
+                    options.push((name.to_ascii_lowercase(), value));
+                }
+            }
+            Some(Request::Read(ReadRequest { filename, mode, options }))
+        }
+        OP_WRQ => Some(Request::Write),
+        _ => None,
+    }
+}
+
+fn data_packet(block: u16, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4 + data.len());
+    packet.extend_from_slice(&OP_DATA.to_be_bytes());
+    packet.extend_from_slice(&block.to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn error_packet(code: u16, message: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(5 + message.len());
+    packet.extend_from_slice(&OP_ERROR.to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    packet
+}
+
+fn oack_packet(options: &[(String, String)]) -> Vec<u8> {
+    let mut packet = OP_OACK.to_be_bytes().to_vec();
+    for (name, value) in options {
+        packet.extend_from_slice(name.as_bytes());
+        packet.push(0);
+        packet.extend_from_slice(value.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+/// Answers read requests on the TFTP port, each transfer on a socket of its own
+///
+/// Uploads are refused: devices only pull images from the staging area.
+pub async fn serve(socket: UdpSocket, server: Arc<FileServer>) {
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("TFTP receive failed: {}", e);
+                continue;
+            }
+        };
+
+        match parse_request(&buf[..len]) {
+            Some(Request::Read(request)) => {
+                debug!("TFTP read request from {} for {} ({})", peer, request.filename, request.mode);
+                tokio::spawn(send_file(server.clone(), peer, request));
+            }
+            Some(Request::Write) => {
+                warn!("TFTP upload from {} refused", peer);
+                let _ = socket.send_to(&error_packet(ERR_ACCESS_VIOLATION, "Uploads are not accepted"), peer).await;
+            }
+            None => {
+                let _ = socket.send_to(&error_packet(ERR_ILLEGAL_OPERATION, "Illegal TFTP operation"), peer).await;
+            }
+        }
+    }
+}
+
+/// Sends a file to the client, negotiating block size and transfer size
+async fn send_file(server: Arc<FileServer>, peer: SocketAddr, request: ReadRequest) {
+    let socket = match UdpSocket::bind((server.settings.address.as_str(), 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to open TFTP transfer socket for {}: {}", peer, e);
+            return;
+        }
+    };
+    if let Err(e) = socket.connect(peer).await {
+        error!("Failed to reach TFTP client {}: {}", peer, e);
+        return;
+    }
+
+    let mut grant = match server.authorize("TFTP", peer.ip(), &request.filename).await {
+        Ok(grant) => grant,
+        Err(e) => {
+            let code = match e {
+                FileServerError::NotFound => ERR_FILE_NOT_FOUND,
+                FileServerError::AccessDenied => ERR_ACCESS_VIOLATION,
+            };
+            let _ = socket.send(&error_packet(code, &e.to_string())).await;
+            return;
+        }
+    };
+    let mut file = match tokio::fs::File::open(&grant.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = socket.send(&error_packet(ERR_FILE_NOT_FOUND, "File not found")).await;
+            grant.transfer.finish(Err(e.to_string()));
+            return;
+        }
+    };
+
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut accepted = Vec::new();
+    for (name, value) in &request.options {
+        match name.as_str() {
+            "blksize" => {
+                if let Ok(requested) = value.parse::<usize>() {
+                    block_size = requested.clamp(*BLOCK_SIZE_RANGE.start(), *BLOCK_SIZE_RANGE.end());
+                    accepted.push((name.clone(), block_size.to_string()));
+                }
+            }
+            "tsize" => accepted.push((name.clone(), grant.size.to_string())),
+            _ => {}
+        }
+    }
+
+    let timeout = Duration::from_secs(server.settings.tftp_timeout_seconds.max(1));
+    if !accepted.is_empty() {
+        if let Err(e) = send_and_wait(&socket, &oack_packet(&accepted), 0, timeout).await {
+            grant.transfer.finish(Err(e));
+            return;
+        }
+    }
+
+    let mut block: u16 = 1;
+    let mut data = vec![0u8; block_size];
+    loop {
+        let len = match read_block(&mut file, &mut data).await {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = socket.send(&error_packet(ERR_NOT_DEFINED, "Read error")).await;
+                grant.transfer.finish(Err(e.to_string()));
+                return;
+            }
+        };
+        if let Err(e) = send_and_wait(&socket, &data_packet(block, &data[..len]), block, timeout).await {
+            grant.transfer.finish(Err(e));
+            return;
+        }
+        grant.transfer.add(len as u64);
+
+        // A short block ends the transfer; block numbers wrap for large images
+        if len < block_size {
+            break;
+        }
+        block = block.wrapping_add(1);
+    }
+    grant.transfer.finish(Ok(()));
+}
+
+/// Sends a packet until the client acknowledges the given block
+async fn send_and_wait(socket: &UdpSocket, packet: &[u8], block: u16, timeout: Duration) -> Result<(), String> {
+    let mut buf = [0u8; 516];
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(packet).await.map_err(|e| e.to_string())?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        // Duplicate acknowledgements of earlier blocks are ignored
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received.map_err(|e| e.to_string())?;
+            if len < 4 {
+                continue;
+            }
+            let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+            let number = u16::from_be_bytes([buf[2], buf[3]]);
+            match opcode {
+                OP_ACK if number == block => return Ok(()),
+                OP_ERROR => {
+                    let message = String::from_utf8_lossy(&buf[4..len]);
+                    return Err(format!("Client aborted: {}", message.trim_end_matches('\0')));
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(format!("No acknowledgement of block {} after {} attempts", block, MAX_ATTEMPTS))
+}
+
+/// Fills the buffer from the file, short only at the end of the file
+async fn read_block(file: &mut tokio::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_read_request_with_options() {
+        let packet = b"\x00\x01/images/ios.bin\x00octet\x00blksize\x001428\x00tsize\x000\x00";
+        assert_eq!(parse_request(packet), Some(Request::Read(ReadRequest {
+            filename: "/images/ios.bin".to_string(),
+            mode: "octet".to_string(),
+            options: vec![
+                ("blksize".to_string(), "1428".to_string()),
+                ("tsize".to_string(), "0".to_string()),
+            ],
+        })));
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_packets() {
+        assert_eq!(parse_request(b"\x00\x02image.bin\x00octet\x00"), Some(Request::Write));
+        assert_eq!(parse_request(b"\x00\x01"), None);
+        assert_eq!(parse_request(b"\x00\x01\x00octet\x00"), None);
+        assert_eq!(parse_request(b"\x00\x04\x00\x01"), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! A session's events in the order they happened, for reconstructing incidents
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::{error, warn, Instrument};
+
+use crate::audit::LineEditor;
+use crate::jwt::AuthenticatedUser;
+use crate::settings::TimelineSettings;
+use crate::store::SessionStore;
+use crate::AppState;
+
+/// Notifications too frequent or too slight to be kept, e.g. transfer progress
+const UNRECORDED_NOTIFICATIONS: &[&str] = &[
+    "sftp_progress", "file_server_transfer", "presence", "typing", "viewers", "banner", "secret_prompt",
+];
+
+/// Something that happened to a session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    /// Place in the session's timeline, counted from 1
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    /// What happened, e.g. `attached`, `resized` or `command`
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub detail: Value,
+}
+
+/// A session's timeline, shared by everything that adds to it
+#[derive(Clone)]
+pub struct SessionTimeline {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    session_id: String,
+    settings: TimelineSettings,
+    // Where every entry is also written, if storage is enabled
+    store: Option<Arc<dyn SessionStore>>,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    // The latest `max_events`, oldest first
+    recent: VecDeque<TimelineEntry>,
+    last_seq: u64,
+}
+
+impl SessionTimeline {
+    pub fn new(session_id: &str, settings: &TimelineSettings, store: Option<Arc<dyn SessionStore>>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                session_id: session_id.to_string(),
+                settings: settings.clone(),
+                store,
+                entries: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Adds an event, writing it to the store too if there is one
+    pub fn record(&self, event: &str, detail: Value) {
+        let Ok(mut entries) = self.inner.entries.lock() else {
+            return;
+        };
+        entries.last_seq += 1;
+        let entry = TimelineEntry { seq: entries.last_seq, at: Utc::now(), event: event.to_string(), detail };
+        if let Some(store) = &self.inner.store {
+            if let Err(e) = store.record_timeline(&self.inner.session_id, &entry) {
+                warn!("Failed to persist timeline event {} of session {}: {}", event, self.inner.session_id, e);
+            }
+        }
+        if entries.recent.len() >= self.inner.settings.max_events.max(1) {
+            entries.recent.pop_front();
+        }
+        entries.recent.push_back(entry);
+    }
+
+    /// The events kept in memory, oldest first
+    pub fn entries(&self) -> Vec<TimelineEntry> {
+        self.inner.entries.lock().map(|entries| entries.recent.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Follows a WebSocket's typed input for the command lines it completes,
+    /// if commands are kept
+    pub fn commands(&self) -> Option<CommandLines> {
+        self.inner.settings.commands.then(|| CommandLines { timeline: self.clone(), editor: LineEditor::default() })
+    }
+
+    /// Adds the session's notifications until it ends, e.g. a terminal type
+    /// downgrade, an idle warning or a lost connection
+    pub fn follow(&self, mut notifications: broadcast::Receiver<Value>) {
+        let timeline = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match notifications.recv().await {
+                    Ok(notification) => timeline.notified(notification),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        timeline.record("notifications_missed", json!({ "count": skipped }));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }.in_current_span());
+    }
+
+    fn notified(&self, mut notification: Value) {
+        let Some(kind) = notification.as_object_mut().and_then(|fields| fields.remove("type")) else {
+            return;
+        };
+        let Some(kind) = kind.as_str().filter(|kind| !UNRECORDED_NOTIFICATIONS.contains(kind)) else {
+            return;
+        };
+        self.record(kind, notification);
+    }
+}
+
+/// Command lines typed into one WebSocket, added to the session's timeline
+pub struct CommandLines {
+    timeline: SessionTimeline,
+    editor: LineEditor,
+}
+
+impl CommandLines {
+    /// Follows keystrokes sent to the device, adding each command line they complete
+    pub fn input(&mut self, data: &[u8]) {
+        for (command, uncertain) in self.editor.feed(&String::from_utf8_lossy(data)) {
+            let detail = match uncertain {
+                true => json!({ "command": command, "uncertain": true }),
+                false => json!({ "command": command }),
+            };
+            self.timeline.record("command", detail);
+        }
+    }
+}
+
+/// Adds an event to a session's timeline, if it has one
+pub fn note(timeline: Option<&SessionTimeline>, event: &str, detail: Value) {
+    if let Some(timeline) = timeline {
+        timeline.record(event, detail);
+    }
+}
+
+/// The answer of `GET /api/session/:session_id/timeline`
+#[derive(Debug, Serialize)]
+struct TimelineResponse {
+    session_id: String,
+    portal_user_id: String,
+    device_id: String,
+    created_at: DateTime<Utc>,
+    ended_at: Option<DateTime<Utc>>,
+    /// Earlier events no longer kept, beyond `timeline.max_events`
+    omitted: u64,
+    events: Vec<TimelineEntry>,
+}
+
+/// A live or ended session's events, oldest first
+pub async fn timeline_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    user: Option<axum::Extension<AuthenticatedUser>>,
+) -> Response {
+    if !state.settings.timeline.enabled {
+        return error_response(StatusCode::NOT_FOUND, "timeline_disabled", "Session timelines are not enabled".to_string());
+    }
+    let session_id = session_id.trim();
+    let registry = state.session_registry.lock().await;
+    // Authenticated users only ever see their own sessions
+    let record = registry.history(session_id).filter(|record| match &user {
+        Some(axum::Extension(user)) => user.owns(&record.portal_user_id),
+        None => true,
+    });
+    let Some(record) = record.cloned() else {
+        return error_response(StatusCode::NOT_FOUND, "session_not_found", format!("Session '{}' not found", session_id));
+    };
+    let events = match registry.timeline(session_id, state.settings.timeline.max_events) {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to read the timeline of session {}: {}", session_id, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "timeline_unavailable", e.to_string());
+        }
+    };
+    drop(registry);
+    Json(TimelineResponse {
+        session_id: record.session_id,
+        portal_user_id: record.portal_user_id,
+        device_id: record.device_id,
+        created_at: record.created_at,
+        ended_at: record.ended_at,
+        omitted: events.first().map_or(0, |entry| entry.seq - 1),
+        events,
+    }).into_response()
+}
+
+fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({
+        "error": error,
+        "message": message,
+    }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timeline_keeps_the_latest_events() {
+        let settings = TimelineSettings { max_events: 3, ..TimelineSettings::default() };
+        let timeline = SessionTimeline::new("s1", &settings, None);
+        timeline.record("created", Value::Null);
+        timeline.record("attached", Value::Null);
+
+        let (notifications, receiver) = broadcast::channel(8);
+        timeline.follow(receiver);
+        notifications.send(json!({ "type": "sftp_progress", "percent": 50 })).unwrap();
+        notifications.send(json!({ "type": "terminal_downgraded", "terminal_type": "vt100" })).unwrap();
+        drop(notifications);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let mut commands = timeline.commands().unwrap();
+        commands.input(b"show versoin\x7f\x7f\x7fion\r");
+
+        let entries = timeline.entries();
+        let events: Vec<&str> = entries.iter().map(|entry| entry.event.as_str()).collect();
+        assert_eq!(events, ["attached", "terminal_downgraded", "command"]);
+        assert_eq!(entries[0].seq, 2);
+        assert_eq!(entries[1].detail, json!({ "terminal_type": "vt100" }));
+        assert_eq!(entries[2].detail, json!({ "command": "show version" }));
+    }
+}
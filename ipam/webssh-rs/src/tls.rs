@@ -0,0 +1,67 @@
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+use crate::settings::ServerSettings;
+
+/// Loads the certificate chain and private key for serving HTTPS and WSS
+///
+/// # Returns
+/// * `Result<RustlsConfig, String>` - The TLS configuration, or a description of the problem
+pub async fn load(settings: &ServerSettings) -> Result<RustlsConfig, String> {
+    let (cert_file, key_file) = files(settings)?;
+
+    // Only the ring provider is compiled in; installing it twice is harmless
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    RustlsConfig::from_pem_file(&cert_file, &key_file).await
+        .map_err(|e| format!("Cannot load {} and {}: {}", cert_file.display(), key_file.display(), e))
+}
+
+/// Reloads the certificate and key whenever either file changes
+///
+/// New connections use the new certificate; established ones keep theirs.
+/// A pair that fails to load is logged and the previous one stays in use.
+pub fn watch(config: RustlsConfig, settings: &ServerSettings) {
+    if settings.tls_reload_seconds == 0 {
+        return;
+    }
+    let Ok((cert_file, key_file)) = files(settings) else {
+        return;
+    };
+    let interval = Duration::from_secs(settings.tls_reload_seconds);
+    info!("Reloading TLS certificate on change, checking every {:?}", interval);
+
+    tokio::spawn(async move {
+        let mut last_modified = (modified(&cert_file), modified(&key_file));
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let current = (modified(&cert_file), modified(&key_file));
+            if current == last_modified {
+                continue;
+            }
+            // Remember the change even if the reload fails; a certificate written
+            // before its key is picked up once the key changes as well
+            last_modified = current;
+
+            match config.reload_from_pem_file(&cert_file, &key_file).await {
+                Ok(()) => info!("Reloaded TLS certificate from {}", cert_file.display()),
+                Err(e) => error!("Failed to reload TLS certificate, keeping the current one: {}", e),
+            }
+        }
+    });
+}
+
+fn files(settings: &ServerSettings) -> Result<(PathBuf, PathBuf), String> {
+    let cert_file = settings.cert_file.as_deref()
+        .ok_or("server.cert_file is required when TLS is enabled")?;
+    let key_file = settings.key_file.as_deref()
+        .ok_or("server.key_file is required when TLS is enabled")?;
+    Ok((PathBuf::from(cert_file), PathBuf::from(key_file)))
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
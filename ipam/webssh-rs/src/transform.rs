@@ -0,0 +1,274 @@
+use bytes::Bytes;
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::recording::{record, SharedRecorder};
+use crate::password_change::PasswordChange;
+use crate::settings::{MaskRule, OutputPipelineSettings, Settings};
+use crate::terminal::TerminalWatch;
+
+/// Places the recording in the pipeline
+pub const RECORDER: &str = "recorder";
+/// Places the terminal type and password change watches in the pipeline
+pub const WATCHERS: &str = "watchers";
+/// Replaces what matches `output_pipeline.mask_rules`
+pub const MASK: &str = "mask";
+
+/// A step of a shell's output on its way to the clients
+///
+/// Each stream gets stages of its own, fed its output in order, so a stage
+/// may keep state between chunks.
+pub trait OutputStage: Send {
+    /// Passes a chunk of output on, changed or not; an empty chunk goes no further
+    fn process(&mut self, data: Bytes) -> Bytes;
+}
+
+/// What a stage is made for
+pub struct StageContext<'a> {
+    pub session_id: &'a str,
+    /// The device type given or recognised, if any
+    pub device_type: Option<&'a str>,
+}
+
+/// Makes a stage for each shell stream
+pub type StageFactory = Box<dyn Fn(&StageContext) -> Box<dyn OutputStage> + Send + Sync>;
+
+/// The output stages that sessions can use, by name
+///
+/// Stages are registered at startup; `output_pipeline.stages`, or a device
+/// profile's `output_stages`, lists the ones a session's output passes
+/// through. The recording and the watches are stages too, so the list also
+/// says whether they see output before or after it is changed. They always
+/// run: when a list leaves them out, they go first.
+pub struct OutputStages {
+    factories: HashMap<String, StageFactory>,
+}
+
+impl OutputStages {
+    /// Registers the built-in stages, checking their settings
+    pub fn new(settings: &OutputPipelineSettings) -> Result<Self, String> {
+        let mut stages = Self { factories: HashMap::new() };
+        let rules = Arc::new(compile_rules(&settings.mask_rules)?);
+        stages.register(MASK, Box::new(move |context| Box::new(MaskStage {
+            rules: rules.clone(),
+            session_id: context.session_id.to_string(),
+            masked: false,
+        })))?;
+        Ok(stages)
+    }
+
+    /// Makes a stage available under a name
+    pub fn register(&mut self, name: &str, factory: StageFactory) -> Result<(), String> {
+        if name == RECORDER || name == WATCHERS || self.factories.contains_key(name) {
+            return Err(format!("output stage '{}' is already registered", name));
+        }
+        self.factories.insert(name.to_string(), factory);
+        Ok(())
+    }
+
+    /// Checks that the configured pipelines name only registered stages, each once
+    pub fn validate(&self, settings: &Settings) -> Result<(), String> {
+        let profiles = settings.profiles.iter()
+            .filter_map(|(device_type, profile)| profile.output_stages.as_ref().map(|stages| (format!("profile '{}'", device_type), stages)));
+        for (owner, stages) in std::iter::once(("output_pipeline".to_string(), &settings.output_pipeline.stages)).chain(profiles) {
+            for (i, name) in stages.iter().enumerate() {
+                if name != RECORDER && name != WATCHERS && !self.factories.contains_key(name) {
+                    return Err(format!("{} names unknown output stage '{}'", owner, name));
+                }
+                if stages[..i].contains(name) {
+                    return Err(format!("{} names output stage '{}' twice", owner, name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lays out the output pipeline of a new session
+    pub fn pipeline(&self, settings: &Settings, context: &StageContext) -> OutputPipeline {
+        let names = settings.profile(context.device_type)
+            .and_then(|profile| profile.output_stages.as_ref())
+            .unwrap_or(&settings.output_pipeline.stages);
+        let mut steps = Vec::new();
+        if !names.iter().any(|name| name == RECORDER) {
+            steps.push(Step::Recorder);
+        }
+        if !names.iter().any(|name| name == WATCHERS) {
+            steps.push(Step::Watchers);
+        }
+        for name in names {
+            steps.push(match name.as_str() {
+                RECORDER => Step::Recorder,
+                WATCHERS => Step::Watchers,
+                name => match self.factories.get(name) {
+                    Some(factory) => Step::Custom(factory(context)),
+                    None => continue,
+                },
+            });
+        }
+        OutputPipeline { steps }
+    }
+}
+
+enum Step {
+    Recorder,
+    Watchers,
+    Custom(Box<dyn OutputStage>),
+}
+
+/// The order of a session's output stages, before its stream starts
+pub struct OutputPipeline {
+    steps: Vec<Step>,
+}
+
+impl Default for OutputPipeline {
+    /// Records the output, then watches it, unchanged
+    fn default() -> Self {
+        Self { steps: vec![Step::Recorder, Step::Watchers] }
+    }
+}
+
+impl OutputPipeline {
+    /// No stages at all, for a session in pass-through mode
+    pub fn passthrough() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    /// Puts the stream's recorder and watches in their places
+    pub fn assemble(
+        self,
+        recorder: Option<SharedRecorder>,
+        terminal: Option<TerminalWatch>,
+        password_change: Option<Arc<PasswordChange>>,
+    ) -> Vec<Box<dyn OutputStage>> {
+        let mut watches = Some(WatchStage { terminal, password_change });
+        self.steps.into_iter()
+            .filter_map(|step| match step {
+                Step::Recorder => recorder.clone().map(|recorder| Box::new(RecorderStage { recorder: Some(recorder) }) as Box<dyn OutputStage>),
+                Step::Watchers => watches.take().map(|watches| Box::new(watches) as Box<dyn OutputStage>),
+                Step::Custom(stage) => Some(stage),
+            })
+            .collect()
+    }
+}
+
+/// Runs a chunk of output through the stages
+pub fn run(stages: &mut [Box<dyn OutputStage>], mut data: Bytes) -> Bytes {
+    for stage in stages {
+        if data.is_empty() {
+            break;
+        }
+        data = stage.process(data);
+    }
+    data
+}
+
+struct RecorderStage {
+    recorder: Option<SharedRecorder>,
+}
+
+impl OutputStage for RecorderStage {
+    fn process(&mut self, data: Bytes) -> Bytes {
+        record(&self.recorder, |recorder| recorder.record_output(&data));
+        data
+    }
+}
+
+struct WatchStage {
+    terminal: Option<TerminalWatch>,
+    password_change: Option<Arc<PasswordChange>>,
+}
+
+impl OutputStage for WatchStage {
+    fn process(&mut self, data: Bytes) -> Bytes {
+        if let Some(terminal) = self.terminal.as_mut() {
+            terminal.scan(&data);
+        }
+        if let Some(password_change) = &self.password_change {
+            password_change.scan(&data);
+        }
+        data
+    }
+}
+
+fn compile_rules(rules: &[MaskRule]) -> Result<Vec<(Regex, Vec<u8>)>, String> {
+    rules.iter()
+        .map(|rule| Regex::new(&rule.pattern)
+            .map(|pattern| (pattern, rule.replacement.as_bytes().to_vec()))
+            .map_err(|e| format!("invalid mask pattern '{}': {}", rule.pattern, e)))
+        .collect()
+}
+
+/// Replaces sensitive text, e.g. customer addresses while sharing a screen
+///
+/// Patterns are matched within each chunk of output as read from the shell,
+/// so a value split across two reads is not masked.
+struct MaskStage {
+    rules: Arc<Vec<(Regex, Vec<u8>)>>,
+    session_id: String,
+    // Something has been masked, and logged once
+    masked: bool,
+}
+
+impl OutputStage for MaskStage {
+    fn process(&mut self, data: Bytes) -> Bytes {
+        let mut data = data;
+        for (pattern, replacement) in self.rules.iter() {
+            if pattern.is_match(&data) {
+                if !self.masked {
+                    debug!("Masking output of session {}", self.session_id);
+                    self.masked = true;
+                }
+                data = Bytes::from(pattern.replace_all(&data, replacement.as_slice()).into_owned());
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DeviceProfile;
+
+    struct Upper;
+
+    impl OutputStage for Upper {
+        fn process(&mut self, data: Bytes) -> Bytes {
+            Bytes::from(data.to_ascii_uppercase())
+        }
+    }
+
+    #[test]
+    fn test_stages_run_in_configured_order() {
+        let mut settings = Settings::default();
+        settings.output_pipeline.mask_rules = vec![MaskRule {
+            pattern: r"\b10\.\d+\.\d+\.\d+\b".to_string(),
+            replacement: "x.x.x.x".to_string(),
+        }];
+        settings.output_pipeline.stages = vec![MASK.to_string(), "upper".to_string()];
+        settings.profiles.insert("linux".to_string(), DeviceProfile {
+            output_stages: Some(vec!["upper".to_string(), MASK.to_string(), RECORDER.to_string()]),
+            ..Default::default()
+        });
+
+        let mut stages = OutputStages::new(&settings.output_pipeline).unwrap();
+        stages.register("upper", Box::new(|_| Box::new(Upper))).unwrap();
+        assert!(stages.register(RECORDER, Box::new(|_| Box::new(Upper))).is_err());
+        stages.validate(&settings).unwrap();
+
+        let context = StageContext { session_id: "s1", device_type: None };
+        let mut pipeline = stages.pipeline(&settings, &context).assemble(None, None, None);
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(run(&mut pipeline, Bytes::from_static(b"ping 10.1.2.3 ok")), &b"PING X.X.X.X OK"[..]);
+
+        // The profile's pipeline upper-cases first, so the replacement keeps its case
+        let context = StageContext { session_id: "s2", device_type: Some("Linux") };
+        let mut pipeline = stages.pipeline(&settings, &context).assemble(None, None, None);
+        assert_eq!(run(&mut pipeline, Bytes::from_static(b"ping 10.1.2.3 ok")), &b"PING x.x.x.x OK"[..]);
+
+        settings.output_pipeline.stages = vec![MASK.to_string(), "shout".to_string()];
+        assert_eq!(stages.validate(&settings).unwrap_err(), "output_pipeline names unknown output stage 'shout'");
+    }
+}
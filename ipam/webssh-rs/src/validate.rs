@@ -0,0 +1,100 @@
+use axum::{
+    extract::{RawQuery, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::error_code::ErrorCode;
+use crate::{connection_target, credential_policy, resolve_request, AppState, SSHCredentials};
+
+/// Result of a credential check
+#[derive(Debug, Serialize)]
+pub struct ValidationResponse {
+    pub success: bool,
+    pub message: String,
+    pub error_code: Option<ErrorCode>,
+    /// Authentication methods the device advertises for the user
+    pub auth_methods: Vec<String>,
+    pub server_banner: Option<String>,
+}
+
+/// Handler for checking device credentials without creating a session
+///
+/// Authenticates once and disconnects immediately; no shell is opened.
+pub async fn validate_credentials_handler(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+    Json(credentials): Json<SSHCredentials>,
+) -> Response {
+    let credentials = match resolve_request(&state, credentials).await {
+        Ok(credentials) => credentials,
+        Err(e) => return Json(ValidationResponse {
+            success: false,
+            message: e.to_string(),
+            error_code: Some(e.error_code()),
+            auth_methods: Vec::new(),
+            server_banner: None,
+        }).into_response(),
+    };
+    let policy_settings = state.policy.settings();
+    let portal_user_id = credentials.portal_user_id.as_deref().unwrap_or("anonymous");
+    let target = connection_target(&state, &credentials, &policy_settings, portal_user_id);
+    info!("Credential validation for {}@{}:{}", target.username, target.hostname, target.port);
+
+    // Credentials the gateway would refuse to connect with are reported as such
+    if let Err(violation) = credential_policy::check(&policy_settings.credential_policy, &target, query.as_deref()) {
+        warn!("Credential validation for {}@{} refused by credential policy: {}", target.username, target.hostname, violation);
+        return Json(ValidationResponse {
+            success: false,
+            message: violation.to_string(),
+            error_code: Some(violation.error_code()),
+            auth_methods: Vec::new(),
+            server_banner: None,
+        }).into_response();
+    }
+
+    let check = match tokio::task::spawn_blocking(move || target.check_credentials()).await {
+        Ok(check) => check,
+        Err(e) => {
+            error!("Credential validation task failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "error": "validation_failed",
+                "message": "Credential validation task failed",
+            }))).into_response();
+        }
+    };
+
+    let response = match check {
+        Ok(check) => match check.result {
+            Ok(()) => ValidationResponse {
+                success: true,
+                message: "Credentials are valid".to_string(),
+                error_code: None,
+                auth_methods: check.auth_methods,
+                server_banner: check.server_banner,
+            },
+            Err(e) => ValidationResponse {
+                success: false,
+                message: e.to_string(),
+                error_code: Some(e.error_code()),
+                auth_methods: check.auth_methods,
+                server_banner: check.server_banner,
+            },
+        },
+        Err(e) => ValidationResponse {
+            success: false,
+            message: format!("Failed to connect: {}", e),
+            error_code: Some(e.error_code()),
+            auth_methods: Vec::new(),
+            server_banner: None,
+        },
+    };
+    info!("Credential validation for {}@{}: {}", credentials.username, credentials.hostname,
+          if response.success { "valid" } else { "invalid" });
+
+    Json(response).into_response()
+}
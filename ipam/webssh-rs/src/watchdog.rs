@@ -0,0 +1,116 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::session::SessionRegistry;
+use crate::settings::Settings;
+use crate::store::EndReason;
+use crate::tasks::{RestartPolicy, TaskSupervisor};
+
+/// Counters of what the watchdog has found and done since startup
+#[derive(Debug, Default)]
+pub struct WatchdogMetrics {
+    stalls_detected: AtomicU64,
+    forced_teardowns: AtomicU64,
+    sessions_removed: AtomicU64,
+}
+
+/// Watchdog state, as reported by the status API
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WatchdogStatus {
+    pub degraded_sessions: usize,
+    pub stalls_detected: u64,
+    pub forced_teardowns: u64,
+    pub sessions_removed: u64,
+}
+
+impl WatchdogMetrics {
+    pub fn status(&self, degraded_sessions: usize) -> WatchdogStatus {
+        WatchdogStatus {
+            degraded_sessions,
+            stalls_detected: self.stalls_detected.load(Ordering::Relaxed),
+            forced_teardowns: self.forced_teardowns.load(Ordering::Relaxed),
+            sessions_removed: self.sessions_removed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Starts checking the I/O loops of all sessions for progress
+///
+/// A session whose loop has not beaten within the threshold is marked
+/// degraded, its socket is shut down from the watchdog to free the stuck
+/// libssh2 call, and the attached WebSocket is told. If it is still in the
+/// registry at the next check it is removed.
+pub fn start(tasks: &TaskSupervisor, registry: Arc<Mutex<SessionRegistry>>, settings: &Settings, metrics: Arc<WatchdogMetrics>) {
+    if !settings.watchdog.enabled {
+        return;
+    }
+    let keepalive = settings.ssh.connection.keepalive_seconds.max(1);
+    let threshold = Duration::from_secs(settings.watchdog.stall_seconds.max(2 * keepalive));
+    let check_interval = Duration::from_secs(settings.watchdog.check_interval_seconds.max(1));
+    info!("Session watchdog enabled (stall threshold {:?})", threshold);
+
+    tasks.spawn("watchdog", RestartPolicy::OnPanic, move |task| {
+        let (registry, metrics) = (registry.clone(), metrics.clone());
+        async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                check(&mut *registry.lock().await, threshold, &metrics);
+                task.ran();
+            }
+        }
+    });
+}
+
+fn check(registry: &mut SessionRegistry, threshold: Duration, metrics: &WatchdogMetrics) {
+    let mut removals = Vec::new();
+
+    for (session_id, session_info) in registry.sessions.iter_mut() {
+        // Teardown was forced at the last check; whatever is left goes now
+        if session_info.degraded_since.is_some() {
+            removals.push(session_id.clone());
+            continue;
+        }
+
+        // A shell on a slow link keeps alive, and so beats, less often
+        let keepalive = session_info.ssh_session.connection_info().keepalive_seconds(&session_info.ssh_session.target().settings);
+        let threshold = threshold.max(Duration::from_secs(2 * keepalive));
+        let stalled: Vec<_> = session_info.ssh_session.heartbeats().into_iter()
+            .filter_map(|heartbeat| heartbeat.stalled_for().map(|stalled_for| (heartbeat, stalled_for)))
+            .filter(|(_, stalled_for)| *stalled_for >= threshold)
+            .collect();
+        let Some(longest) = stalled.iter().map(|(_, stalled_for)| *stalled_for).max() else {
+            continue;
+        };
+
+        metrics.stalls_detected.fetch_add(1, Ordering::Relaxed);
+        error!("ALERT: session {} I/O has made no progress for {:?} (device {}); forcing teardown",
+               session_id, longest, session_info.device_id);
+        session_info.degraded_since = Some(Utc::now());
+        let _ = session_info.notifications.send(json!({
+            "type": "session_degraded",
+            "message": "The connection to the device stopped responding and is being closed",
+            "stalled_seconds": longest.as_secs(),
+        }));
+
+        session_info.ssh_session.shutdown();
+        for (heartbeat, _) in &stalled {
+            if heartbeat.force_close() {
+                metrics.forced_teardowns.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    for session_id in removals {
+        if registry.remove_session(&session_id, EndReason::Stalled) {
+            metrics.sessions_removed.fetch_add(1, Ordering::Relaxed);
+            info!("Watchdog removed degraded session {}", session_id);
+        }
+    }
+}
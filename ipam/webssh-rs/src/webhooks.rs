@@ -0,0 +1,206 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::http_client::HttpClient;
+use crate::settings::{WebhookEndpoint, WebhookEventType, WebhookSettings};
+use crate::store::{SessionEvent, SessionRecord};
+
+/// An event as posted to the endpoints
+#[derive(Debug, Serialize)]
+pub struct WebhookEvent {
+    /// Unique to the event, and the same across retries, so receivers can drop duplicates
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: WebhookEventType,
+    pub occurred_at: DateTime<Utc>,
+    /// The instance the event happened on
+    pub node_id: String,
+    pub data: Value,
+}
+
+/// An endpoint's queue of events to deliver
+struct Subscriber {
+    url: String,
+    events: Vec<WebhookEventType>,
+    queue: mpsc::Sender<Arc<WebhookEvent>>,
+}
+
+/// Posts events to the configured endpoints
+///
+/// Each endpoint is delivered to in order by a background task of its own,
+/// so a slow or unreachable endpoint delays neither the sessions nor the
+/// other endpoints. Failed deliveries are retried with exponential backoff;
+/// events beyond an endpoint's queue are dropped.
+pub struct Webhooks {
+    node_id: String,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Webhooks {
+    /// Checks the endpoints and starts delivering to them
+    pub fn start(settings: &WebhookSettings, node_id: &str) -> Result<Self, String> {
+        let mut subscribers = Vec::new();
+        for endpoint in &settings.endpoints {
+            let client = HttpClient::new(endpoint.url.trim(), endpoint.ca_file.as_deref(), "webhook")?;
+            let (queue, queue_rx) = mpsc::channel(settings.queue_size.max(1));
+            tokio::spawn(deliver(client, endpoint.clone(), settings.clone(), queue_rx));
+            subscribers.push(Subscriber { url: endpoint.url.clone(), events: endpoint.events.clone(), queue });
+        }
+        info!("Posting events to {} webhook endpoint(s)", subscribers.len());
+        Ok(Self { node_id: node_id.to_string(), subscribers })
+    }
+
+    /// Queues an event for the endpoints that want it
+    pub fn send(&self, event_type: WebhookEventType, data: Value) {
+        let event = Arc::new(WebhookEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            event_type,
+            occurred_at: Utc::now(),
+            node_id: self.node_id.clone(),
+            data,
+        });
+        for subscriber in &self.subscribers {
+            if !subscriber.events.is_empty() && !subscriber.events.contains(&event_type) {
+                continue;
+            }
+            if subscriber.queue.try_send(event.clone()).is_err() {
+                warn!("Webhook queue for {} is full; dropped {:?} event {}", subscriber.url, event_type, event.id);
+            }
+        }
+    }
+
+    /// Notes a lifecycle event of one of this instance's sessions
+    ///
+    /// # Arguments
+    /// * `record` - The session as kept in the history, reported with the event
+    pub fn record(&self, event: &SessionEvent, record: Option<&SessionRecord>) {
+        let event_type = match event {
+            SessionEvent::Created(_) => WebhookEventType::SessionCreated,
+            SessionEvent::Attached { .. } => WebhookEventType::SessionAttached,
+            SessionEvent::Ended { .. } => WebhookEventType::SessionTerminated,
+            SessionEvent::Detached { .. } => return,
+        };
+        let record = match event {
+            SessionEvent::Created(record) => Some(*record),
+            _ => record,
+        };
+        let mut data = record.and_then(|record| serde_json::to_value(record).ok())
+            .unwrap_or_else(|| json!({ "session_id": event.session_id() }));
+        if let SessionEvent::Ended { reason, .. } = event {
+            data["end_reason"] = json!(reason);
+        }
+        self.send(event_type, data);
+    }
+}
+
+/// Why a delivery failed
+enum Failure {
+    /// Worth trying again, e.g. the endpoint was unreachable or answered 503
+    Transient(String),
+    /// The endpoint will not take the event, e.g. it answered 400
+    Rejected(String),
+}
+
+/// Delivers an endpoint's events in order, retrying each with exponential backoff
+async fn deliver(
+    client: HttpClient,
+    endpoint: WebhookEndpoint,
+    settings: WebhookSettings,
+    mut queue: mpsc::Receiver<Arc<WebhookEvent>>,
+) {
+    let timeout = Duration::from_secs(settings.timeout_seconds.max(1));
+    let attempts = settings.max_attempts.max(1);
+    while let Some(event) = queue.recv().await {
+        let body = match serde_json::to_string(&*event) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Cannot serialize webhook event {}: {}", event.id, e);
+                continue;
+            }
+        };
+        for attempt in 1..=attempts {
+            match post(&client, &endpoint, &event, &body, timeout).await {
+                Ok(()) => {
+                    debug!("Delivered {:?} event {} to {}", event.event_type, event.id, endpoint.url);
+                    break;
+                }
+                Err(Failure::Rejected(reason)) => {
+                    warn!("Webhook {} rejected event {}: {}", endpoint.url, event.id, reason);
+                    break;
+                }
+                Err(Failure::Transient(reason)) if attempt < attempts => {
+                    let wait = backoff(&settings, attempt);
+                    warn!("Webhook {} failed for event {} (attempt {} of {}): {}; retrying in {} ms",
+                          endpoint.url, event.id, attempt, attempts, reason, wait.as_millis());
+                    tokio::time::sleep(wait).await;
+                }
+                Err(Failure::Transient(reason)) => {
+                    warn!("Webhook {} failed for event {} after {} attempts: {}; dropped", endpoint.url, event.id, attempts, reason);
+                }
+            }
+        }
+    }
+}
+
+async fn post(client: &HttpClient, endpoint: &WebhookEndpoint, event: &WebhookEvent, body: &str, timeout: Duration) -> Result<(), Failure> {
+    let timestamp = Utc::now().timestamp().to_string();
+    let event_type = json!(event.event_type);
+    let mut headers = vec![
+        ("User-Agent", concat!("webssh-rs/", env!("CARGO_PKG_VERSION")).to_string()),
+        ("X-Webhook-Id", event.id.clone()),
+        ("X-Webhook-Event", event_type.as_str().unwrap_or_default().to_string()),
+        ("X-Webhook-Timestamp", timestamp.clone()),
+    ];
+    if let Some(secret) = endpoint.secret.as_deref().filter(|secret| !secret.is_empty()) {
+        headers.push(("X-Webhook-Signature", format!("sha256={}", signature(secret, &timestamp, body))));
+    }
+    let headers: Vec<(&str, &str)> = headers.iter().map(|(name, value)| (*name, value.as_str())).collect();
+    let request = client.request("POST", &client.endpoint.path, &headers, Some(body));
+    match tokio::time::timeout(timeout, request).await {
+        Err(_) => Err(Failure::Transient(format!("no response within {} seconds", timeout.as_secs()))),
+        Ok(Err(e)) => Err(Failure::Transient(e.to_string())),
+        Ok(Ok((status, _))) if (200..300).contains(&status) => Ok(()),
+        Ok(Ok((status, _))) if status == 408 || status == 429 || status >= 500 => Err(Failure::Transient(format!("HTTP {}", status))),
+        Ok(Ok((status, _))) => Err(Failure::Rejected(format!("HTTP {}", status))),
+    }
+}
+
+/// HMAC-SHA256 of `<timestamp>.<body>`, hex-encoded, so a receiver can check
+/// both who sent the event and that it is not a replay of an old one
+fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Wait after a failed attempt: the initial backoff, doubled for each attempt before
+fn backoff(settings: &WebhookSettings, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+    Duration::from_millis(settings.initial_backoff_ms.saturating_mul(factor).min(settings.max_backoff_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_and_backoff() {
+        assert_eq!(
+            signature("It's a Secret to Everybody", "1700000000", r#"{"type":"session_created"}"#),
+            "c84184dd7540107f8e418d1c13b474034b025559fe74d520149b19d932f9ac38"
+        );
+        let settings = WebhookSettings::default();
+        let waits: Vec<u128> = (1..=8).map(|attempt| backoff(&settings, attempt).as_millis()).collect();
+        assert_eq!(waits, [1000, 2000, 4000, 8000, 16000, 32000, 60000, 60000]);
+        assert_eq!(backoff(&settings, 200).as_millis(), 60000);
+    }
+}
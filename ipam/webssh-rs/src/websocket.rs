@@ -3,8 +3,24 @@ use bytes::Bytes;
 use futures::{sink::SinkExt, stream::StreamExt};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::sync::mpsc;
-use tracing::{error, info, debug};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, debug, warn, Instrument};
+
+use crate::audit::{audit, audit_blocked, SharedAudit};
+use crate::capture::{capture, CaptureSlot, Direction};
+use crate::command_policy::CommandFilter;
+use crate::faults::{self, FaultPath};
+use crate::presence::Presence;
+use crate::protocol::{BinaryMessage, Framing, PerformanceStats};
+use crate::recording::record;
+use crate::replay::ShellStream;
+use crate::session::DetachNotice;
+use crate::settings::{BinaryProtocolSettings, FlowControlSettings, SlowConsumerSettings, WebSocketSettings};
+use crate::timeline::{self, SessionTimeline};
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
@@ -15,13 +31,66 @@ pub enum WSCommand {
     Input { data: String },
     #[serde(rename = "ping")]
     Ping,
+    /// The answer to a password prompt, kept out of the recording, the audit and the logs
+    #[serde(rename = "secret_input")]
+    SecretInput { data: String },
+    /// Keystrokes sent as a raw binary frame, in the legacy framing
+    #[serde(skip)]
+    Raw(Vec<u8>),
+}
+
+impl WSCommand {
+    /// Reads a command from a `binary-v1` frame
+    fn from_binary(data: &[u8]) -> Option<Self> {
+        match BinaryMessage::from_binary(data).ok()? {
+            BinaryMessage::TerminalInput { data } => Some(WSCommand::Input { data }),
+            BinaryMessage::Resize { cols, rows } => Some(WSCommand::Resize { rows: rows.into(), cols: cols.into() }),
+            BinaryMessage::Ping => Some(WSCommand::Ping),
+            BinaryMessage::Event { json } => serde_json::from_str(&json).ok(),
+            _ => None,
+        }
+    }
 }
 
 pub struct WebSocketHandler {
     socket: WebSocket,
-    ssh_input_tx: mpsc::Sender<Bytes>,
-    ssh_output_rx: mpsc::Receiver<Bytes>,
-    resize_tx: Option<mpsc::Sender<(u32, u32)>>,
+    stream: Arc<ShellStream>,
+    // Cancelled to let go of the shell; also cancelled here when the client goes away
+    detach: CancellationToken,
+    // Why the shell was let go, for the client, if it was let go for a reason to tell
+    detach_notice: Option<DetachNotice>,
+    // Output offset to resume from, rather than the oldest output buffered
+    resume_offset: Option<u64>,
+    notification_rx: Option<broadcast::Receiver<serde_json::Value>>,
+    // Frame capture of the session, written to while one is running
+    capture: CaptureSlot,
+    // Viewers see the output but their input and resizes are refused
+    read_only: bool,
+    framing: Framing,
+    // How often the client is sent `stats` messages, if at all
+    stats_interval: Option<Duration>,
+    // How often the client is pinged to measure the round trip, if at all
+    ping_interval: Option<Duration>,
+    // The session's traffic, counted alongside this WebSocket's
+    session_stats: Option<Arc<Mutex<PerformanceStats>>>,
+    // Switches the client to screen snapshots when it falls behind, if set
+    slow_consumers: Option<SlowConsumerSettings>,
+    // Holds output back while too much is queued for the client, if set
+    flow_control: Option<FlowControlSettings>,
+    // The client's place among the session's participants, if presence is tracked
+    presence: Option<Arc<Presence>>,
+    // Holds back command lines the command policy refuses
+    command_filter: Option<CommandFilter>,
+    // Where the session's resizes, commands and refused commands are added, if it has a timeline
+    timeline: Option<SessionTimeline>,
+    // The maintenance banner shown when the client attached, if any
+    banner: Option<serde_json::Value>,
+    // Output is sent on as it comes, without looking for full-screen applications
+    passthrough: bool,
+    // Output arriving this soon after the last frame waits to go out with more
+    batch_interval: Duration,
+    // Largest output frame, if smaller than a replay chunk
+    max_batch_bytes: Option<usize>,
     session_id: String,
     portal_user_id: String,
 }
@@ -29,23 +98,108 @@ pub struct WebSocketHandler {
 impl WebSocketHandler {
     pub fn new(
         socket: WebSocket,
-        ssh_input_tx: mpsc::Sender<Bytes>,
-        ssh_output_rx: mpsc::Receiver<Bytes>,
+        stream: Arc<ShellStream>,
+        detach: CancellationToken,
         session_id: String,
         portal_user_id: String,
     ) -> Self {
+        let framing = Framing::negotiated(&socket, &BinaryProtocolSettings::default());
         Self {
             socket,
-            ssh_input_tx,
-            ssh_output_rx,
-            resize_tx: None,
+            stream,
+            detach,
+            detach_notice: None,
+            resume_offset: None,
+            notification_rx: None,
+            capture: CaptureSlot::default(),
+            read_only: false,
+            framing,
+            stats_interval: None,
+            ping_interval: None,
+            session_stats: None,
+            slow_consumers: None,
+            flow_control: None,
+            presence: None,
+            command_filter: None,
+            timeline: None,
+            banner: None,
+            passthrough: false,
+            batch_interval: Duration::ZERO,
+            max_batch_bytes: None,
             session_id,
             portal_user_id,
         }
     }
-    
-    pub fn set_resize_channel(&mut self, resize_tx: mpsc::Sender<(u32, u32)>) {
-        self.resize_tx = Some(resize_tx);
+
+    /// Replays output from the given offset, as reported to the client before it disconnected
+    pub fn set_resume_offset(&mut self, offset: u64) {
+        self.resume_offset = Some(offset);
+    }
+
+    pub fn set_detach_notice(&mut self, notice: DetachNotice) {
+        self.detach_notice = Some(notice);
+    }
+
+    pub fn set_notification_channel(&mut self, notification_rx: broadcast::Receiver<serde_json::Value>) {
+        self.notification_rx = Some(notification_rx);
+    }
+
+    pub fn set_capture(&mut self, capture: CaptureSlot) {
+        self.capture = capture;
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn set_stats_interval(&mut self, interval: Duration) {
+        self.stats_interval = Some(interval);
+    }
+
+    pub fn set_ping_interval(&mut self, interval: Duration) {
+        self.ping_interval = Some(interval);
+    }
+
+    pub fn set_session_stats(&mut self, stats: Arc<Mutex<PerformanceStats>>) {
+        self.session_stats = Some(stats);
+    }
+
+    pub fn set_slow_consumers(&mut self, settings: SlowConsumerSettings) {
+        self.slow_consumers = Some(settings);
+    }
+
+    pub fn set_flow_control(&mut self, settings: FlowControlSettings) {
+        self.flow_control = Some(settings);
+    }
+
+    pub fn set_presence(&mut self, presence: Presence) {
+        self.presence = Some(Arc::new(presence));
+    }
+
+    pub fn set_command_filter(&mut self, command_filter: CommandFilter) {
+        self.command_filter = Some(command_filter);
+    }
+
+    pub fn set_timeline(&mut self, timeline: SessionTimeline) {
+        self.timeline = Some(timeline);
+    }
+
+    /// Compresses binary frames as configured rather than by the defaults
+    pub fn set_binary_protocol(&mut self, settings: &BinaryProtocolSettings) {
+        self.framing = Framing::negotiated(&self.socket, settings);
+    }
+
+    pub fn set_passthrough(&mut self) {
+        self.passthrough = true;
+    }
+
+    pub fn set_output_batching(&mut self, settings: &WebSocketSettings) {
+        self.batch_interval = Duration::from_millis(settings.batch_interval_ms);
+        self.max_batch_bytes = Some(settings.max_batch_bytes);
+    }
+
+    pub fn set_banner(&mut self, banner: serde_json::Value) {
+        self.banner = Some(banner);
     }
 
     pub async fn handle(mut self) {
@@ -54,138 +208,348 @@ impl WebSocketHandler {
         let (ws_sender, mut ws_receiver) = self.socket.split();
 
         // Create a channel for sending messages to the WebSocket
-        let (ws_msg_tx, mut ws_msg_rx) = mpsc::channel::<Message>(100);
+        let (ws_msg_tx, mut ws_msg_rx) = Outbox::channel(100);
         
         // Clone the sender for use in the receiver task
         let ws_msg_tx_clone = ws_msg_tx.clone();
 
+        if let Some(banner) = self.banner.take() {
+            let _ = ws_msg_tx.send(self.framing.event(banner)).await;
+        }
+        // A client attaching while the device waits for a password is shown the prompt again
+        if let Some(prompt) = self.stream.password_change().and_then(|password_change| password_change.pending_prompt()) {
+            let _ = ws_msg_tx.send(self.framing.event(prompt)).await;
+        }
+
+        // Traffic of this WebSocket, and the output offset queued for it so far
+        let stats = Traffic {
+            websocket: Arc::new(Mutex::new(PerformanceStats::default())),
+            session: self.session_stats.take(),
+            started: Instant::now(),
+        };
+        let queued_offset = Arc::new(AtomicU64::new(0));
+
         // Handle incoming WebSocket messages
-        let ssh_input_tx = self.ssh_input_tx.clone();
-        let resize_tx = self.resize_tx.clone();
+        let ssh_input_tx = self.stream.input_sender();
+        let resize_tx = self.stream.resize_sender();
+        let input_recorder = self.stream.recorder();
+        let input_audit = self.stream.audit();
+        let input_activity = self.stream.activity();
         let session_id = self.session_id.clone();
         let portal_user_id = self.portal_user_id.clone();
+        let receiver_detach = self.detach.clone();
+        let receiver_capture = self.capture.clone();
+        let read_only = self.read_only;
+        let framing = self.framing;
+        let receiver_stats = stats.clone();
+        let receiver_presence = self.presence.clone();
+        let mut command_filter = self.command_filter.take();
+        let receiver_faults = self.stream.faults();
+        let receiver_password_change = self.stream.password_change();
+        let receiver_timeline = self.timeline.clone();
+        let mut command_lines = self.timeline.as_ref().and_then(SessionTimeline::commands);
         
         // Spawn a task to handle incoming WebSocket messages
-        tokio::spawn(async move {
+        let receiver_task = tokio::spawn(async move {
             debug!("Starting WebSocket receiver task for session {} (portal user: {})",
                    session_id, portal_user_id);
             while let Some(Ok(msg)) = ws_receiver.next().await {
-                match msg {
+                if faults::inject(&receiver_faults, FaultPath::Websocket).await && !matches!(msg, Message::Close(_)) {
+                    continue;
+                }
+                capture(&receiver_capture, Direction::In, &msg);
+                let cmd = match msg {
                     Message::Text(text) => {
-                        debug!("[Session {}] Received text message: {}", session_id, text);
-                        if let Ok(cmd) = serde_json::from_str::<WSCommand>(&text) {
-                            match cmd {
-                                WSCommand::Input { data } => {
-                                    debug!("[Session {}] Processing input command: {} bytes",
-                                           session_id, data.len());
-                                    
-                                    match ssh_input_tx.send(Bytes::from(data)).await {
-                                        Ok(_) => {}, // Successfully sent data to SSH channel
-                                        Err(e) => {
-                                            // Check if this is a channel closed error
-                                            let error_msg = e.to_string();
-                                            let is_channel_closed = error_msg.contains("channel closed");
-                                            
-                                            error!("[Session {}] Failed to send SSH input: {}",
-                                                   session_id, e);
-                                            
-                                            // If channel is closed, send a notification to the client
-                                            if is_channel_closed {
-                                                debug!("[Session {}] SSH channel is closed, notifying client", session_id);
-                                                let _ = ws_msg_tx_clone.send(Message::Text(json!({
-                                                    "type": "error",
-                                                    "message": "SSH connection has been closed. Please reconnect."
-                                                }).to_string())).await;
-                                                
-                                                // Short delay to allow the message to be sent
-                                                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                                            }
-                                            
-                                            // Break the loop to close the WebSocket
-                                            break;
-                                        }
-                                    }
-                                }
-                                WSCommand::Resize { rows, cols } => {
-                                    debug!("[Session {}] Processing resize command: {}x{}",
-                                           session_id, cols, rows);
-                                    
-                                    // Validate terminal dimensions
-                                    let rows = std::cmp::max(rows, 24); // Minimum 24 rows
-                                    let cols = std::cmp::max(cols, 80); // Minimum 80 columns
-                                    
-                                    if let Some(ref resize_tx) = resize_tx {
-                                        debug!("[Session {}] Sending resize command with validated dimensions: {}x{}",
-                                               session_id, cols, rows);
-                                               
-                                        if let Err(e) = resize_tx.send((rows, cols)).await {
-                                            error!("[Session {}] Failed to send resize command: {}",
-                                                   session_id, e);
-                                        } else {
-                                            // Send acknowledgment to client that resize was processed
-                                            let _ = ws_msg_tx_clone.send(Message::Text(json!({
-                                                "type": "info",
-                                                "message": format!("Terminal resized to {}x{}", cols, rows)
-                                            }).to_string())).await;
-                                        }
-                                    } else {
-                                        debug!("[Session {}] Resize channel not set, ignoring resize command",
-                                               session_id);
-                                    }
-                                }
-                                WSCommand::Ping => {
-                                    // Handle ping message from client (used for connection health check)
-                                    debug!("[Session {}] Received ping from client", session_id);
+                        match serde_json::from_str::<WSCommand>(&text) {
+                            Ok(WSCommand::SecretInput { data }) => {
+                                debug!("[Session {}] Received an answer to a password prompt", session_id);
+                                WSCommand::SecretInput { data }
+                            }
+                            Ok(cmd) => {
+                                debug!("[Session {}] Received text message: {}", session_id, text);
+                                cmd
+                            }
+                            Err(_) => {
+                                error!("[Session {}] Failed to parse WebSocket command: {}",
+                                       session_id, text);
+                                continue;
+                            }
+                        }
+                    }
+                    Message::Binary(data) if framing == Framing::Json => WSCommand::Raw(data),
+                    Message::Binary(data) => match WSCommand::from_binary(&data) {
+                        Some(cmd) => cmd,
+                        None => {
+                            error!("[Session {}] Failed to decode binary message: {} bytes",
+                                   session_id, data.len());
+                            continue;
+                        }
+                    },
+                    Message::Close(_) => {
+                        info!("[Session {}] WebSocket close message received", session_id);
+                        break;
+                    }
+                    Message::Pong(data) => {
+                        receiver_stats.pong(&data);
+                        continue;
+                    }
+                    msg => {
+                        debug!("[Session {}] Received other message type: {:?}",
+                               session_id, msg);
+                        continue;
+                    }
+                };
+                if let Some(presence) = &receiver_presence {
+                    presence.active();
+                    if !read_only && matches!(cmd, WSCommand::Input { .. } | WSCommand::Raw(_)) {
+                        presence.typed();
+                    }
+                }
+                if let (Some(password_change), WSCommand::Input { .. } | WSCommand::Raw(_), false) = (&receiver_password_change, &cmd, read_only) {
+                    password_change.typed();
+                }
+                match cmd {
+                    WSCommand::Input { .. } | WSCommand::Raw(_) | WSCommand::Resize { .. } | WSCommand::SecretInput { .. } if read_only => {
+                        let _ = ws_msg_tx_clone.send(read_only_error(framing)).await;
+                    }
+                    WSCommand::Input { data } => {
+                        debug!("[Session {}] Processing input command: {} bytes",
+                               session_id, data.len());
+                        let data = enforce(&mut command_filter, &input_audit, &receiver_timeline, data.into_bytes(), &ws_msg_tx_clone, framing, &session_id).await;
+                        record(&input_recorder, |recorder| recorder.record_input(&data));
+                        input_activity.input();
+                        audit(&input_audit, &data);
+                        if let Some(command_lines) = &mut command_lines {
+                            command_lines.input(&data);
+                        }
+                        receiver_stats.received(data.len());
+                        
+                        match ssh_input_tx.send(Bytes::from(data)).await {
+                            Ok(_) => {}, // Successfully sent data to SSH channel
+                            Err(e) => {
+                                // Check if this is a channel closed error
+                                let error_msg = e.to_string();
+                                let is_channel_closed = error_msg.contains("channel closed");
+                                
+                                error!("[Session {}] Failed to send SSH input: {}",
+                                       session_id, e);
+                                
+                                // If channel is closed, send a notification to the client
+                                if is_channel_closed {
+                                    debug!("[Session {}] SSH channel is closed, notifying client", session_id);
+                                    let _ = ws_msg_tx_clone.send(framing.error(
+                                        "ssh_closed",
+                                        "SSH connection has been closed. Please reconnect.",
+                                    )).await;
                                     
-                                    // Send a pong response back to the client
-                                    let _ = ws_msg_tx_clone.send(Message::Text(json!({
-                                        "type": "pong"
-                                    }).to_string())).await;
+                                    // Short delay to allow the message to be sent
+                                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                                 }
+                                
+                                // Break the loop to close the WebSocket
+                                break;
                             }
-                        } else {
-                            error!("[Session {}] Failed to parse WebSocket command: {}",
-                                   session_id, text);
                         }
                     }
-                    Message::Binary(data) => {
+                    WSCommand::Raw(data) => {
                         debug!("[Session {}] Received binary message: {} bytes",
                                session_id, data.len());
+                        let data = enforce(&mut command_filter, &input_audit, &receiver_timeline, data, &ws_msg_tx_clone, framing, &session_id).await;
+                        record(&input_recorder, |recorder| recorder.record_input(&data));
+                        input_activity.input();
+                        audit(&input_audit, &data);
+                        if let Some(command_lines) = &mut command_lines {
+                            command_lines.input(&data);
+                        }
+                        receiver_stats.received(data.len());
                         if let Err(e) = ssh_input_tx.send(Bytes::from(data)).await {
                             error!("[Session {}] Failed to send SSH binary input: {}",
                                    session_id, e);
                             break;
                         }
                     }
-                    Message::Close(_) => {
-                        info!("[Session {}] WebSocket close message received", session_id);
-                        break;
+                    WSCommand::SecretInput { data } => {
+                        let answer = match &receiver_password_change {
+                            Some(password_change) => password_change.answer(&data),
+                            None => Err("No password prompt is waiting for an answer"),
+                        };
+                        match answer {
+                            Ok(keystrokes) => {
+                                input_activity.input();
+                                receiver_stats.received(keystrokes.len());
+                                if let Err(e) = ssh_input_tx.send(Bytes::from(keystrokes)).await {
+                                    error!("[Session {}] Failed to send the answer to a password prompt: {}",
+                                           session_id, e);
+                                    break;
+                                }
+                            }
+                            Err(message) => {
+                                let _ = ws_msg_tx_clone.send(framing.error("no_secret_prompt", message)).await;
+                            }
+                        }
                     }
-                    msg => {
-                        debug!("[Session {}] Received other message type: {:?}",
-                               session_id, msg);
+                    WSCommand::Resize { rows, cols } => {
+                        debug!("[Session {}] Processing resize command: {}x{}",
+                               session_id, cols, rows);
+                        
+                        // Validate terminal dimensions
+                        let rows = std::cmp::max(rows, 24); // Minimum 24 rows
+                        let cols = std::cmp::max(cols, 80); // Minimum 80 columns
+                        
+                        debug!("[Session {}] Sending resize command with validated dimensions: {}x{}",
+                               session_id, cols, rows);
+                               
+                        if let Err(e) = resize_tx.send((rows, cols)).await {
+                            error!("[Session {}] Failed to send resize command: {}",
+                                   session_id, e);
+                        } else {
+                            record(&input_recorder, |recorder| recorder.record_resize(cols, rows));
+                            timeline::note(receiver_timeline.as_ref(), "resized", json!({ "cols": cols, "rows": rows }));
+
+                            // Send acknowledgment to client that resize was processed
+                            let _ = ws_msg_tx_clone.send(framing.event(json!({
+                                "type": "info",
+                                "message": format!("Terminal resized to {}x{}", cols, rows)
+                            }))).await;
+                        }
+                    }
+                    WSCommand::Ping => {
+                        // Handle ping message from client (used for connection health check)
+                        debug!("[Session {}] Received ping from client", session_id);
+                        
+                        // Send a pong response back to the client
+                        let _ = ws_msg_tx_clone.send(framing.pong()).await;
                     }
                 }
             }
             debug!("[Session {}] WebSocket receiver task ended", session_id);
-        });
+            // The client is gone; stop forwarding output to it
+            receiver_detach.cancel();
+        }.in_current_span());
 
         // Spawn a task to forward messages from the channel to the WebSocket
         let session_id_clone = self.session_id.clone();
+        let sender_capture = self.capture.clone();
+        let sender_faults = self.stream.faults();
+        let sender_unsent = ws_msg_tx.unsent.clone();
         let sender_task = tokio::spawn(async move {
             debug!("[Session {}] Starting WebSocket sender task", session_id_clone);
             let mut ws_sender = ws_sender;
             
             while let Some(msg) = ws_msg_rx.recv().await {
-                if let Err(e) = ws_sender.send(msg).await {
+                let len = frame_len(&msg);
+                if faults::inject(&sender_faults, FaultPath::Websocket).await && !matches!(msg, Message::Close(_)) {
+                    sender_unsent.taken(len);
+                    continue;
+                }
+                capture(&sender_capture, Direction::Out, &msg);
+                let sent = ws_sender.send(msg).await;
+                sender_unsent.taken(len);
+                if let Err(e) = sent {
                     error!("[Session {}] Failed to send WebSocket message: {}", session_id_clone, e);
                     break;
                 }
             }
             
             debug!("[Session {}] WebSocket sender task ended", session_id_clone);
-        });
+        }.in_current_span());
         
+        // Forward session notifications (e.g. file transfer progress) to the WebSocket
+        let notification_task = self.notification_rx.take().map(|mut notification_rx| {
+            let notification_tx = ws_msg_tx.clone();
+            let session_id = self.session_id.clone();
+            let framing = self.framing;
+            tokio::spawn(async move {
+                loop {
+                    match notification_rx.recv().await {
+                        Ok(notification) => {
+                            if notification_tx.send(framing.event(notification)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!("[Session {}] Skipped {} notifications", session_id, skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            }.in_current_span())
+        });
+
+        // Notice when the client goes idle or stops typing
+        let presence_task = self.presence.clone().map(|presence| {
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(Duration::from_millis(500));
+                loop {
+                    ticker.tick().await;
+                    presence.tick();
+                }
+            })
+        });
+
+        // Ping the client now and then; its pongs measure the round trip
+        let ping_task = self.ping_interval.map(|interval| {
+            let ping_tx = ws_msg_tx.clone();
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    // Queued behind output, a ping would measure the queue rather than the link
+                    if ping_tx.capacity() < ping_tx.max_capacity() {
+                        continue;
+                    }
+                    if let Err(mpsc::error::TrySendError::Closed(_)) = ping_tx.try_send(stats.ping()) {
+                        break;
+                    }
+                }
+            })
+        });
+
+        // Report the session's traffic, so the client can tell a busy device from a stuck gateway
+        let stats_task = self.stats_interval.map(|interval| {
+            let stats_tx = ws_msg_tx.clone();
+            let stats = stats.clone();
+            let queued_offset = queued_offset.clone();
+            let stream = self.stream.clone();
+            let framing = self.framing;
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                ticker.tick().await;
+                let mut last_at = Instant::now();
+                let mut last_offset = stream.end_offset();
+                let mut last = PerformanceStats::default();
+                loop {
+                    ticker.tick().await;
+                    let Ok(current) = stats.websocket.lock().map(|stats| stats.clone()) else {
+                        break;
+                    };
+                    let offset = stream.end_offset();
+                    let elapsed = last_at.elapsed().as_secs_f64();
+                    let rate = |bytes: u64| (bytes as f64 / elapsed).round() as u64;
+                    let message = framing.event(json!({
+                        "type": "stats",
+                        "device_bytes_per_second": rate(offset.saturating_sub(last_offset)),
+                        "sent_bytes_per_second": rate(current.bytes_sent.saturating_sub(last.bytes_sent)),
+                        "received_bytes_per_second": rate(current.bytes_received.saturating_sub(last.bytes_received)),
+                        "queued_messages": stats_tx.max_capacity() - stats_tx.capacity(),
+                        "unsent_bytes": stats_tx.unsent_bytes(),
+                        "backlog_bytes": offset.saturating_sub(queued_offset.load(Ordering::Relaxed)),
+                        "compression_ratio": (f64::from(current.compression_ratio) * 100.0).round() / 100.0,
+                        "frame_compression_ratio": current.frame_compression_ratio().map(|ratio| (f64::from(ratio) * 100.0).round() / 100.0),
+                        "latency_ms": current.last_latency_ms.map(|latency| (f64::from(latency) * 10.0).round() / 10.0),
+                    }));
+                    // Dropped rather than queued behind output the client is slow to take
+                    if let Err(mpsc::error::TrySendError::Closed(_)) = stats_tx.try_send(message) {
+                        break;
+                    }
+                    last_at = Instant::now();
+                    last_offset = offset;
+                    last = current;
+                }
+            })
+        });
+
         // Forward SSH output to WebSocket with improved handling for terminal applications
         debug!("Starting SSH output forwarder for session {}", self.session_id);
         
@@ -193,52 +557,184 @@ impl WebSocketHandler {
         let mut saw_top_command = false;
         let mut saw_fullscreen_app = false;
         
-        while let Some(data) = self.ssh_output_rx.recv().await {
-            debug!("[Session {}] Received {} bytes from SSH", self.session_id, data.len());
-            
-            // Check for patterns in the output that indicate a full-screen application
-            // This helps us provide better handling for commands like 'top'
-            if !saw_fullscreen_app {
-                // Look for clear screen sequences or cursor positioning that indicate full-screen apps
-                if data.windows(3).any(|w| w == b"\x1b[H" || w == b"\x1b[2J") {
-                    saw_fullscreen_app = true;
-                    debug!("[Session {}] Detected full-screen application", self.session_id);
+        // Tell the client where the output it receives starts, so it can
+        // resume from the right offset after a reconnect
+        let mut offsets = self.stream.subscribe();
+        offsets.borrow_and_update();
+        let from = self.resume_offset.unwrap_or_else(|| self.stream.start_offset());
+        // At most one frame's worth of output is read at a time
+        let stream = self.stream.clone();
+        let max_batch_bytes = self.max_batch_bytes;
+        let read_output = |offset| match max_batch_bytes {
+            Some(limit) => stream.read(offset, limit),
+            None => stream.read_from(offset),
+        };
+        let mut replay = read_output(from);
+        queued_offset.store(replay.start, Ordering::Relaxed);
+        let _ = ws_msg_tx.send(self.framing.event(json!({
+            "type": "output_offset",
+            "offset": replay.start,
+        }))).await;
+        
+        // Set once the client has received all the output; until then it is replaying, not falling behind
+        let mut caught_up = false;
+        // When output was last queued, to gather output that follows closely into one frame
+        let mut last_sent: Option<Instant> = None;
+        let slow_consumers = self.slow_consumers.take().filter(|_| self.stream.tracks_screen());
+        let flow_control = self.flow_control.take();
+        
+        loop {
+            if let Some(policy) = flow_control.as_ref().filter(|policy| ws_msg_tx.unsent_bytes() >= policy.high_watermark_bytes) {
+                let held = HeldBack {
+                    stream: &self.stream,
+                    detach: &self.detach,
+                    framing: self.framing,
+                    policy,
+                    tx: &ws_msg_tx,
+                    stats: &stats,
+                    queued_offset: &queued_offset,
+                    session_id: &self.session_id,
+                };
+                match held.run(replay.start).await {
+                    Some(offset) => replay = read_output(offset),
+                    None => break,
                 }
             }
-            
-            // Check for 'top' command in the output
-            if !saw_top_command {
-                let data_str = String::from_utf8_lossy(&data);
-                if data_str.contains("top -") || data_str.contains("Tasks:") || data_str.contains("Cpu(s):") {
-                    saw_top_command = true;
-                    debug!("[Session {}] Detected 'top' command output", self.session_id);
+            if let Some(policy) = &slow_consumers {
+                let behind = self.stream.end_offset().saturating_sub(replay.start);
+                if replay.skipped > 0 || (caught_up && behind > policy.backlog_bytes) {
+                    let summarized = Summarized {
+                        stream: &self.stream,
+                        detach: &self.detach,
+                        framing: self.framing,
+                        policy,
+                        tx: &ws_msg_tx,
+                        stats: &stats,
+                        queued_offset: &queued_offset,
+                        session_id: &self.session_id,
+                    };
+                    match summarized.run(behind).await {
+                        Some(offset) => {
+                            replay = read_output(offset);
+                            continue;
+                        }
+                        None => break,
+                    }
                 }
             }
+            if replay.skipped > 0 {
+                let _ = ws_msg_tx.send(self.framing.event(json!({
+                    "type": "info",
+                    "message": format!("{} bytes of output were dropped before they could be sent", replay.skipped)
+                }))).await;
+            }
+            let next = replay.next();
+            let data = replay.data;
             
-            // Send the data to the WebSocket
-            if let Err(e) = ws_msg_tx.send(Message::Binary(data.to_vec())).await {
-                error!("[Session {}] Failed to queue WebSocket message: {}",
-                       self.session_id, e);
-                break;
-            } else {
-                debug!("[Session {}] Queued {} bytes to WebSocket", self.session_id, data.len());
+            if !data.is_empty() {
+                debug!("[Session {}] Received {} bytes from SSH", self.session_id, data.len());
+            
+                // Check for patterns in the output that indicate a full-screen application
+                // This helps us provide better handling for commands like 'top'
+                if !self.passthrough && !saw_fullscreen_app {
+                    // Look for clear screen sequences or cursor positioning that indicate full-screen apps
+                    if data.windows(3).any(|w| w == b"\x1b[H" || w == b"\x1b[2J") {
+                        saw_fullscreen_app = true;
+                        debug!("[Session {}] Detected full-screen application", self.session_id);
+                    }
+                }
+            
+                // Check for 'top' command in the output
+                if !self.passthrough && !saw_top_command {
+                    let data_str = String::from_utf8_lossy(&data);
+                    if data_str.contains("top -") || data_str.contains("Tasks:") || data_str.contains("Cpu(s):") {
+                        saw_top_command = true;
+                        debug!("[Session {}] Detected 'top' command output", self.session_id);
+                    }
+                }
+            
+                // Send the data to the WebSocket
+                let len = data.len();
+                let message = stats.output(self.framing, data);
+                if let Err(e) = ws_msg_tx.send(message).await {
+                    error!("[Session {}] Failed to queue WebSocket message: {}",
+                           self.session_id, e);
+                    break;
+                } else {
+                    debug!("[Session {}] Queued {} bytes to WebSocket", self.session_id, len);
                 
-                // For full-screen applications like 'top', send a refresh notification
-                // This helps the client know when to refresh the terminal display
-                if saw_fullscreen_app || saw_top_command {
-                    // Small delay to allow the data to be processed
-                    tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+                    // For full-screen applications like 'top', send a refresh notification
+                    // This helps the client know when to refresh the terminal display
+                    if saw_fullscreen_app || saw_top_command {
+                        // Small delay to allow the data to be processed
+                        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
                     
-                    // Send a notification to trigger a client-side refresh
-                    let _ = ws_msg_tx.send(Message::Text(json!({
-                        "type": "refresh",
-                        "fullscreen": saw_fullscreen_app
-                    }).to_string())).await;
+                        // Send a notification to trigger a client-side refresh
+                        let _ = ws_msg_tx.send(self.framing.event(json!({
+                            "type": "refresh",
+                            "fullscreen": saw_fullscreen_app
+                        }))).await;
+                    }
+                }
+            }
+            
+            queued_offset.store(next, Ordering::Relaxed);
+            
+            if next > replay.start {
+                last_sent = Some(Instant::now());
+            }
+            
+            // Spilled output is replayed in chunks; wait for more only once caught up.
+            // Output buffered before the shell ended is still delivered
+            if next >= *offsets.borrow_and_update() {
+                caught_up = true;
+                tokio::select! {
+                    biased;
+                    changed = offsets.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                    }
+                    _ = self.detach.cancelled() => {
+                        debug!("[Session {}] Detached from shell, closing WebSocket", self.session_id);
+                        break;
+                    }
+                }
+                // Output following closely on the last frame, e.g. from `cat` of a
+                // large file, is left to gather into one frame; a lone echo is not held
+                if let Some(due) = last_sent.map(|sent| sent + self.batch_interval).filter(|due| *due > Instant::now()) {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due.into()) => {}
+                        _ = self.detach.cancelled() => {}
+                    }
                 }
+                offsets.borrow_and_update();
+            } else if self.detach.is_cancelled() {
+                break;
             }
+            replay = read_output(next);
+        }
+        
+        // Tell the client why it was let go, e.g. that the session moved to another device
+        if let Some(notice) = self.detach_notice.as_ref().and_then(|notice| notice.get()) {
+            let _ = ws_msg_tx.send(self.framing.event(notice.clone())).await;
         }
         
-        // Close the message channel to signal the sender task to end
+        // Stop reading from the client, forwarding notifications and reporting stats, then close the message channel to
+        // signal the sender task to end
+        receiver_task.abort();
+        if let Some(notification_task) = notification_task {
+            notification_task.abort();
+        }
+        if let Some(stats_task) = stats_task {
+            stats_task.abort();
+        }
+        if let Some(presence_task) = presence_task {
+            presence_task.abort();
+        }
+        if let Some(ping_task) = ping_task {
+            ping_task.abort();
+        }
         drop(ws_msg_tx);
         
         // Wait for the sender task to complete
@@ -251,3 +747,279 @@ impl WebSocketHandler {
               self.session_id, self.portal_user_id);
     }
 }
+
+/// A client sent snapshots of the screen in place of output it cannot keep up with
+struct Summarized<'a> {
+    stream: &'a ShellStream,
+    detach: &'a CancellationToken,
+    framing: Framing,
+    policy: &'a SlowConsumerSettings,
+    tx: &'a Outbox,
+    stats: &'a Traffic,
+    queued_offset: &'a AtomicU64,
+    session_id: &'a str,
+}
+
+impl Summarized<'_> {
+    /// Sends snapshots until the client keeps up with them and the output slows down
+    ///
+    /// Each snapshot redraws the whole screen and is followed by the offset it
+    /// was taken at, so the client can resume from there after a reconnect.
+    ///
+    /// # Returns
+    /// * `Option<u64>` - The offset to send the full output from again, or `None` once detached
+    async fn run(&self, behind: u64) -> Option<u64> {
+        info!("[Session {}] Client is {} bytes behind; sending screen snapshots", self.session_id, behind);
+        self.event(json!({ "type": "summarized", "active": true })).await?;
+
+        let mut ticker = tokio::time::interval(Duration::from_millis(self.policy.snapshot_interval_ms.max(100)));
+        let mut last_offset = None;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = self.detach.cancelled() => return None,
+            }
+            // Earlier messages are still queued; a snapshot would only wait behind them
+            if self.tx.capacity() < self.tx.max_capacity() {
+                continue;
+            }
+            let (offset, screen) = self.stream.snapshot()?;
+            if last_offset.is_some_and(|last| offset - last <= self.policy.resume_bytes) {
+                info!("[Session {}] Client caught up; sending the full output again", self.session_id);
+                self.event(json!({ "type": "summarized", "active": false })).await?;
+                return last_offset;
+            }
+
+            let message = self.stats.output(self.framing, screen);
+            self.tx.send(message).await.ok()?;
+            self.event(json!({ "type": "output_offset", "offset": offset })).await?;
+            self.queued_offset.store(offset, Ordering::Relaxed);
+            last_offset = Some(offset);
+        }
+    }
+
+    async fn event(&self, event: serde_json::Value) -> Option<()> {
+        self.tx.send(self.framing.event(event)).await.ok()
+    }
+}
+
+/// A client held back from output while too much is queued for it
+struct HeldBack<'a> {
+    stream: &'a ShellStream,
+    detach: &'a CancellationToken,
+    framing: Framing,
+    policy: &'a FlowControlSettings,
+    tx: &'a Outbox,
+    stats: &'a Traffic,
+    queued_offset: &'a AtomicU64,
+    session_id: &'a str,
+}
+
+impl HeldBack<'_> {
+    /// Waits for the client to take most of what is queued for it
+    ///
+    /// The client is told when output stops and starts again. Updates a
+    /// full-screen application drew meanwhile are replaced with a snapshot
+    /// of its screen, if the policy allows.
+    ///
+    /// # Returns
+    /// * `Option<u64>` - The offset to send output from again, or `None` once detached
+    async fn run(&self, from: u64) -> Option<u64> {
+        let unsent = self.tx.unsent_bytes();
+        debug!("[Session {}] {} bytes queued for the client; holding output back", self.session_id, unsent);
+        self.event(json!({ "type": "flow_control", "paused": true, "unsent_bytes": unsent })).await?;
+        tokio::select! {
+            _ = self.tx.drained_to(self.policy.low_watermark_bytes) => {}
+            _ = self.detach.cancelled() => return None,
+        }
+
+        let mut from = from;
+        let mut coalesced = 0;
+        if self.policy.coalesce_fullscreen && self.stream.in_fullscreen() {
+            if let Some((offset, screen)) = self.stream.snapshot().filter(|(offset, _)| *offset > from) {
+                let message = self.stats.output(self.framing, screen);
+                self.tx.send(message).await.ok()?;
+                self.event(json!({ "type": "output_offset", "offset": offset })).await?;
+                self.queued_offset.store(offset, Ordering::Relaxed);
+                coalesced = offset - from;
+                from = offset;
+            }
+        }
+        debug!("[Session {}] Sending output again; {} bytes coalesced into a snapshot", self.session_id, coalesced);
+        self.event(json!({
+            "type": "flow_control",
+            "paused": false,
+            "unsent_bytes": self.tx.unsent_bytes(),
+            "coalesced_bytes": coalesced,
+        })).await?;
+        Some(from)
+    }
+
+    async fn event(&self, event: serde_json::Value) -> Option<()> {
+        self.tx.send(self.framing.event(event)).await.ok()
+    }
+}
+
+/// Holds back the command lines the policy refuses, telling the client why
+///
+/// # Returns
+/// * `Vec<u8>` - The input to send to the device
+async fn enforce(
+    command_filter: &mut Option<CommandFilter>,
+    audit: &Option<SharedAudit>,
+    timeline: &Option<SessionTimeline>,
+    data: Vec<u8>,
+    ws_msg_tx: &Outbox,
+    framing: Framing,
+    session_id: &str,
+) -> Vec<u8> {
+    let Some(command_filter) = command_filter else {
+        return data;
+    };
+    let filtered = command_filter.input(&data);
+    for blocked in filtered.blocked {
+        warn!("[Session {}] Command refused: {}", session_id, blocked.reason);
+        audit_blocked(audit, &blocked.command);
+        timeline::note(timeline.as_ref(), "command_blocked", json!({ "command": blocked.command, "reason": blocked.reason }));
+        let _ = ws_msg_tx.send(framing.output(format!("\r\n% {}\r\n", blocked.reason).into_bytes())).await;
+    }
+    for notice in filtered.notices {
+        let _ = ws_msg_tx.send(framing.output(format!("% {}\r\n", notice).into_bytes())).await;
+    }
+    filtered.forward
+}
+
+fn read_only_error(framing: Framing) -> Message {
+    framing.error("read_only", "This is a read-only view of the session")
+}
+
+/// The queue of messages to a WebSocket, counting the bytes waiting in it
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::Sender<Message>,
+    unsent: Arc<Unsent>,
+}
+
+/// Bytes queued for a WebSocket that its sender task has not yet taken
+#[derive(Default)]
+struct Unsent {
+    bytes: AtomicUsize,
+    drained: Notify,
+}
+
+impl Unsent {
+    /// Notes a message taken off the queue, once written or dropped
+    fn taken(&self, len: usize) {
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+        self.drained.notify_waiters();
+    }
+}
+
+impl Outbox {
+    fn channel(capacity: usize) -> (Self, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self { tx, unsent: Arc::default() }, rx)
+    }
+
+    async fn send(&self, message: Message) -> Result<(), mpsc::error::SendError<Message>> {
+        let len = frame_len(&message);
+        self.unsent.bytes.fetch_add(len, Ordering::Relaxed);
+        self.tx.send(message).await.inspect_err(|_| {
+            self.unsent.bytes.fetch_sub(len, Ordering::Relaxed);
+        })
+    }
+
+    fn try_send(&self, message: Message) -> Result<(), mpsc::error::TrySendError<Message>> {
+        let len = frame_len(&message);
+        self.unsent.bytes.fetch_add(len, Ordering::Relaxed);
+        self.tx.try_send(message).inspect_err(|_| {
+            self.unsent.bytes.fetch_sub(len, Ordering::Relaxed);
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.tx.capacity()
+    }
+
+    fn max_capacity(&self) -> usize {
+        self.tx.max_capacity()
+    }
+
+    fn unsent_bytes(&self) -> usize {
+        self.unsent.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Waits until no more than `bytes` are queued, or the WebSocket has closed
+    async fn drained_to(&self, bytes: usize) {
+        loop {
+            let drained = self.unsent.drained.notified();
+            if self.unsent_bytes() <= bytes {
+                return;
+            }
+            tokio::select! {
+                _ = drained => {}
+                _ = self.tx.closed() => return,
+            }
+        }
+    }
+}
+
+/// Counts a WebSocket's traffic, and the session's along with it
+#[derive(Clone)]
+struct Traffic {
+    websocket: Arc<Mutex<PerformanceStats>>,
+    session: Option<Arc<Mutex<PerformanceStats>>>,
+    // What ping payloads are measured from
+    started: Instant,
+}
+
+impl Traffic {
+    /// Frames terminal output, counting it as sent
+    fn output(&self, framing: Framing, data: Vec<u8>) -> Message {
+        let original_size = data.len();
+        let (message, compressed_from) = framing.measured_output(data);
+        let frame_size = frame_len(&message);
+        self.update(|stats| {
+            stats.record_sent(original_size, frame_size);
+            if let Some(uncompressed_size) = compressed_from {
+                stats.record_compression(uncompressed_size, frame_size);
+            }
+        });
+        message
+    }
+
+    fn received(&self, size: usize) {
+        self.update(|stats| stats.record_received(size));
+    }
+
+    /// A ping carrying the time it was sent
+    fn ping(&self) -> Message {
+        Message::Ping((self.started.elapsed().as_micros() as u64).to_be_bytes().to_vec())
+    }
+
+    /// Records the round trip of a ping the client answered
+    fn pong(&self, data: &[u8]) {
+        let Ok(sent) = <[u8; 8]>::try_from(data).map(u64::from_be_bytes) else {
+            return;
+        };
+        let round_trip = Duration::from_micros((self.started.elapsed().as_micros() as u64).saturating_sub(sent));
+        self.update(|stats| stats.record_latency(round_trip.as_secs_f32() * 1000.0));
+    }
+
+    fn update(&self, mut change: impl FnMut(&mut PerformanceStats)) {
+        for stats in std::iter::once(&self.websocket).chain(&self.session) {
+            if let Ok(mut stats) = stats.lock() {
+                change(&mut stats);
+            }
+        }
+    }
+}
+
+/// Size of a frame's payload on the wire
+fn frame_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) => data.len(),
+        _ => 0,
+    }
+}
@@ -0,0 +1,666 @@
+//! `permessage-deflate` (RFC 7692) for the WebSockets
+//!
+//! axum's WebSocket cannot negotiate extensions, so compression happens beneath
+//! it: [`DeflateAcceptor`] wraps each connection in a [`DeflateStream`], and
+//! [`negotiate`] answers the browser's offer on the `101` response and arms the
+//! stream. From then on the stream compresses the server's messages and inflates
+//! the client's, so the WebSocket above it only ever sees plain frames.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::{AddExtension, Next},
+    response::Response,
+    Extension,
+};
+use axum_server::accept::Accept;
+use bytes::{Buf, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::future::BoxFuture;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tower::Layer;
+use tracing::debug;
+
+use crate::settings::WebSocketSettings;
+
+/// Ends every sync-flushed message; left off on the wire
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Largest frame, and largest message once inflated, accepted from a client
+const MAX_MESSAGE_BYTES: usize = 16 << 20;
+
+/// Writes wait while this much is waiting to go out on the connection
+const MAX_PENDING_BYTES: usize = 256 << 10;
+
+/// Longest response head looked through for the end of the `101`
+const MAX_HEAD_BYTES: usize = 16 << 10;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+
+/// How the server compresses, from the `websocket` settings
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    level: u32,
+    threshold: usize,
+    context_takeover: bool,
+}
+
+/// The parameters agreed with a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Agreement {
+    /// The server resets its compression window after each message
+    pub server_no_context_takeover: bool,
+    /// The client resets its compression window after each message
+    pub client_no_context_takeover: bool,
+}
+
+impl Agreement {
+    /// The `Sec-WebSocket-Extensions` response header
+    pub fn header(&self) -> &'static str {
+        match (self.server_no_context_takeover, self.client_no_context_takeover) {
+            (false, false) => "permessage-deflate",
+            (true, false) => "permessage-deflate; server_no_context_takeover",
+            (false, true) => "permessage-deflate; client_no_context_takeover",
+            (true, true) => "permessage-deflate; server_no_context_takeover; client_no_context_takeover",
+        }
+    }
+}
+
+/// Picks the first `permessage-deflate` offer of a `Sec-WebSocket-Extensions`
+/// request header that can be accepted
+///
+/// Offers limiting the server's window below 15 bits are declined, as the
+/// compressor always uses the full window; any client window can be inflated.
+pub fn accept_offer(offers: &str, context_takeover: bool) -> Option<Agreement> {
+    'offers: for offer in offers.split(',') {
+        let mut params = offer.split(';').map(str::trim);
+        if !params.next().is_some_and(|name| name.eq_ignore_ascii_case("permessage-deflate")) {
+            continue;
+        }
+        let mut agreement = Agreement {
+            server_no_context_takeover: !context_takeover,
+            client_no_context_takeover: !context_takeover,
+        };
+        let mut seen = Vec::new();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            if seen.contains(&name) {
+                continue 'offers;
+            }
+            seen.push(name);
+            match (name, value) {
+                ("server_no_context_takeover", None) => agreement.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) | ("client_max_window_bits", None) => {}
+                ("server_max_window_bits", Some("15")) => {}
+                ("client_max_window_bits", Some(bits)) if bits.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits)) => {}
+                _ => continue 'offers,
+            }
+        }
+        return Some(agreement);
+    }
+    None
+}
+
+/// Checks the compression level and frame size, before any connection is accepted
+pub fn validate(settings: &WebSocketSettings) -> Result<(), String> {
+    if settings.deflate_level > 9 {
+        return Err(format!("websocket.deflate_level must be 0 to 9, not {}", settings.deflate_level));
+    }
+    if settings.max_batch_bytes == 0 {
+        return Err("websocket.max_batch_bytes must be above 0".to_string());
+    }
+    Ok(())
+}
+
+/// Arms its connection's [`DeflateStream`]; in the extensions of every request
+#[derive(Clone)]
+pub struct DeflateSwitch {
+    // None when `websocket.permessage_deflate` is off
+    config: Option<Config>,
+    agreement: Arc<OnceLock<Agreement>>,
+}
+
+/// Answers the client's `permessage-deflate` offer when a WebSocket is accepted
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let accepted = request.extensions().get::<DeflateSwitch>()
+        .and_then(|switch| switch.config.map(|config| (switch.clone(), config)))
+        .and_then(|(switch, config)| {
+            let offers = request.headers().get_all(header::SEC_WEBSOCKET_EXTENSIONS).iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            accept_offer(&offers, config.context_takeover).map(|agreement| (switch, agreement))
+        });
+
+    let mut response = next.run(request).await;
+    if let Some((switch, agreement)) = accepted {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS && switch.agreement.set(agreement).is_ok() {
+            response.headers_mut().insert(header::SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static(agreement.header()));
+        }
+    }
+    response
+}
+
+/// Wraps each connection accepted by `inner` in a [`DeflateStream`]
+#[derive(Clone)]
+pub struct DeflateAcceptor<A> {
+    inner: A,
+    config: Option<Config>,
+}
+
+impl<A> DeflateAcceptor<A> {
+    pub fn new(inner: A, settings: &WebSocketSettings) -> Self {
+        let config = settings.permessage_deflate.then_some(Config {
+            level: settings.deflate_level,
+            threshold: settings.deflate_threshold_bytes,
+            context_takeover: settings.deflate_context_takeover,
+        });
+        Self { inner, config }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for DeflateAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Future: Send + 'static,
+    A::Stream: Send + 'static,
+    A::Service: Send + 'static,
+{
+    type Stream = DeflateStream<A::Stream>;
+    type Service = AddExtension<A::Service, DeflateSwitch>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let accepting = self.inner.accept(stream, service);
+        let config = self.config;
+        Box::pin(async move {
+            let (stream, service) = accepting.await?;
+            let switch = DeflateSwitch { config, agreement: Arc::new(OnceLock::new()) };
+            let stream = DeflateStream { inner: stream, config, agreement: switch.agreement.clone(), mode: Mode::Http { head: Vec::new() } };
+            Ok((stream, Extension(switch).layer(service)))
+        })
+    }
+}
+
+/// A connection that compresses its WebSocket once [`negotiate`] arms it
+pub struct DeflateStream<S> {
+    inner: S,
+    config: Option<Config>,
+    agreement: Arc<OnceLock<Agreement>>,
+    mode: Mode,
+}
+
+enum Mode {
+    /// Plain HTTP; once armed, the response head is followed to the end of the `101`
+    Http { head: Vec<u8> },
+    WebSocket(Box<Codec>),
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let codec = match &mut this.mode {
+            Mode::Http { .. } => return Pin::new(&mut this.inner).poll_read(cx, buf),
+            Mode::WebSocket(codec) => codec,
+        };
+        loop {
+            if !codec.readable.is_empty() {
+                let len = codec.readable.len().min(buf.remaining());
+                buf.put_slice(&codec.readable[..len]);
+                codec.readable.advance(len);
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            codec.received.extend_from_slice(read.filled());
+            codec.decode()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match &mut this.mode {
+            Mode::Http { head } => {
+                let (Some(config), Some(agreement)) = (this.config, this.agreement.get().copied()) else {
+                    return Pin::new(&mut this.inner).poll_write(cx, buf);
+                };
+                if head.len() > MAX_HEAD_BYTES {
+                    return Pin::new(&mut this.inner).poll_write(cx, buf);
+                }
+                // Write up to the end of the head, to start compressing right after it
+                let seen = head.len();
+                head.extend_from_slice(buf);
+                let end = find(&head[seen.saturating_sub(3)..], b"\r\n\r\n")
+                    .map(|at| seen.saturating_sub(3) + at + 4 - seen);
+                head.truncate(seen);
+                let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..end.unwrap_or(buf.len())]))?;
+                head.extend_from_slice(&buf[..written]);
+                if end == Some(written) {
+                    if head.starts_with(b"HTTP/1.1 101") {
+                        debug!("Compressing the WebSocket with {}", agreement.header());
+                        this.mode = Mode::WebSocket(Box::new(Codec::new(config, agreement)));
+                    } else {
+                        head.clear();
+                    }
+                }
+                Poll::Ready(Ok(written))
+            }
+            Mode::WebSocket(codec) => {
+                if codec.pending.len() >= MAX_PENDING_BYTES {
+                    ready!(flush_pending(&mut this.inner, codec, cx))?;
+                }
+                codec.written.extend_from_slice(buf);
+                codec.encode()?;
+                // The rest goes out on flush if the connection is not ready for it
+                if let Poll::Ready(Err(e)) = flush_pending(&mut this.inner, codec, cx) {
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Mode::WebSocket(codec) = &mut this.mode {
+            ready!(flush_pending(&mut this.inner, codec, cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Mode::WebSocket(codec) = &mut this.mode {
+            ready!(flush_pending(&mut this.inner, codec, cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> Drop for DeflateStream<S> {
+    fn drop(&mut self) {
+        if let Mode::WebSocket(codec) = &self.mode {
+            debug!(
+                "WebSocket closed; {} bytes of messages were sent as {} and {} received as {}",
+                codec.message_bytes_out, codec.wire_bytes_out, codec.message_bytes_in, codec.wire_bytes_in,
+            );
+        }
+    }
+}
+
+fn flush_pending<S: AsyncWrite + Unpin>(inner: &mut S, codec: &mut Codec, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    while !codec.pending.is_empty() {
+        let written = ready!(Pin::new(&mut *inner).poll_write(cx, &codec.pending))?;
+        if written == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        codec.pending.advance(written);
+    }
+    Poll::Ready(Ok(()))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// The fixed part of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    payload_len: usize,
+    header_len: usize,
+}
+
+impl FrameHeader {
+    /// Parses the header at the start of `buf`; None until it is all there
+    fn parse(buf: &[u8]) -> io::Result<Option<Self>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (first, second) = (buf[0], buf[1]);
+        let (payload_len, mut header_len) = match second & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        let payload_len = usize::try_from(payload_len).ok()
+            .filter(|len| *len <= MAX_MESSAGE_BYTES)
+            .ok_or_else(|| invalid("WebSocket frame too large"))?;
+        let mask = if second & 0x80 != 0 {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mask = [buf[header_len], buf[header_len + 1], buf[header_len + 2], buf[header_len + 3]];
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Ok(Some(Self { fin: first & 0x80 != 0, rsv1: first & 0x40 != 0, opcode: first & 0x0f, mask, payload_len, header_len }))
+    }
+
+    fn is_data(&self) -> bool {
+        matches!(self.opcode, OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY)
+    }
+}
+
+/// Writes a frame with the given first byte, masking the payload if a mask is given
+fn put_frame(out: &mut BytesMut, first: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+    out.extend_from_slice(&[first]);
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.extend_from_slice(&[masked | len as u8]),
+        len @ 126..=0xffff => {
+            out.extend_from_slice(&[masked | 126]);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.extend_from_slice(&[masked | 127]);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            out.extend_from_slice(&mask);
+            out.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        }
+        None => out.extend_from_slice(payload),
+    }
+}
+
+fn unmask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Compression state of a WebSocket connection
+struct Codec {
+    config: Config,
+    agreement: Agreement,
+    compress: Compress,
+    decompress: Decompress,
+    // Frames from the WebSocket not yet complete
+    written: BytesMut,
+    // Frames waiting to go out on the connection
+    pending: BytesMut,
+    // Bytes from the connection not yet a complete frame
+    received: BytesMut,
+    // Frames for the WebSocket to read
+    readable: BytesMut,
+    // Set while the frames of a compressed message are arriving
+    inflating: bool,
+    // Bytes inflated so far of the message arriving
+    inflated: usize,
+    message_bytes_out: u64,
+    wire_bytes_out: u64,
+    message_bytes_in: u64,
+    wire_bytes_in: u64,
+}
+
+impl Codec {
+    fn new(config: Config, agreement: Agreement) -> Self {
+        Self {
+            config,
+            agreement,
+            compress: Compress::new(Compression::new(config.level), false),
+            decompress: Decompress::new(false),
+            written: BytesMut::new(),
+            pending: BytesMut::new(),
+            received: BytesMut::new(),
+            readable: BytesMut::new(),
+            inflating: false,
+            inflated: 0,
+            message_bytes_out: 0,
+            wire_bytes_out: 0,
+            message_bytes_in: 0,
+            wire_bytes_in: 0,
+        }
+    }
+
+    /// Moves the complete frames written by the WebSocket to `pending`,
+    /// compressing unfragmented messages above the threshold
+    fn encode(&mut self) -> io::Result<()> {
+        while let Some(frame) = FrameHeader::parse(&self.written)? {
+            let len = frame.header_len + frame.payload_len;
+            if self.written.len() < len {
+                break;
+            }
+            let raw = self.written.split_to(len);
+            let payload = &raw[frame.header_len..];
+            let compressible = frame.fin && !frame.rsv1 && frame.mask.is_none()
+                && matches!(frame.opcode, OPCODE_TEXT | OPCODE_BINARY)
+                && payload.len() >= self.config.threshold;
+            if !compressible {
+                self.pending.extend_from_slice(&raw);
+                continue;
+            }
+
+            let compressed = self.deflate(payload)?;
+            self.message_bytes_out += payload.len() as u64;
+            // Without context takeover nothing depends on this message, so it may go out as it is
+            if self.agreement.server_no_context_takeover && compressed.len() >= payload.len() {
+                self.wire_bytes_out += payload.len() as u64;
+                self.pending.extend_from_slice(&raw);
+            } else {
+                self.wire_bytes_out += compressed.len() as u64;
+                put_frame(&mut self.pending, 0x80 | 0x40 | frame.opcode, None, &compressed);
+            }
+        }
+        Ok(())
+    }
+
+    fn deflate(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            let before = self.compress.total_in();
+            self.compress.compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| invalid(e.to_string()))?;
+            consumed += (self.compress.total_in() - before) as usize;
+            // The flush is complete once it leaves room in the output
+            if consumed == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        if self.agreement.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    /// Moves the complete frames from the client to `readable`, inflating
+    /// compressed messages and clearing their RSV1 bit
+    fn decode(&mut self) -> io::Result<()> {
+        while let Some(frame) = FrameHeader::parse(&self.received)? {
+            let len = frame.header_len + frame.payload_len;
+            if self.received.len() < len {
+                break;
+            }
+            let raw = self.received.split_to(len);
+            let starts_compressed = frame.rsv1 && matches!(frame.opcode, OPCODE_TEXT | OPCODE_BINARY);
+            if !(starts_compressed || (self.inflating && frame.opcode == OPCODE_CONTINUATION)) {
+                // Control frames may arrive between the fragments of a compressed message
+                if frame.is_data() && frame.fin {
+                    self.inflating = false;
+                }
+                self.readable.extend_from_slice(&raw);
+                continue;
+            }
+
+            self.inflating = !frame.fin;
+            let mut payload = raw[frame.header_len..].to_vec();
+            if let Some(mask) = frame.mask {
+                unmask(&mut payload, mask);
+            }
+            if frame.fin {
+                payload.extend_from_slice(&TRAILER);
+            }
+            let mut inflated = Vec::with_capacity(payload.len() * 4);
+            let ended = self.inflate(&payload, &mut inflated)?;
+            self.wire_bytes_in += frame.payload_len as u64;
+            self.message_bytes_in += inflated.len() as u64;
+            self.inflated += inflated.len();
+            if self.inflated > MAX_MESSAGE_BYTES {
+                return Err(invalid("WebSocket message too large once inflated"));
+            }
+            put_frame(&mut self.readable, raw[0] & !0x40, frame.mask, &inflated);
+
+            if frame.fin {
+                self.inflated = 0;
+                if ended || self.agreement.client_no_context_takeover {
+                    self.decompress.reset(false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the client ended its deflate stream, which it then starts anew
+    fn inflate(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<bool> {
+        let mut consumed = 0;
+        loop {
+            let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self.decompress.decompress_vec(&input[consumed..], out, FlushDecompress::Sync)
+                .map_err(|e| invalid(e.to_string()))?;
+            consumed += (self.decompress.total_in() - before_in) as usize;
+            if status == Status::StreamEnd {
+                return Ok(true);
+            }
+            if out.len() > MAX_MESSAGE_BYTES {
+                return Err(invalid("WebSocket message too large once inflated"));
+            }
+            let progressed = self.decompress.total_in() != before_in || self.decompress.total_out() != before_out;
+            if (consumed == input.len() && out.len() < out.capacity()) || !progressed && out.len() < out.capacity() {
+                return Ok(false);
+            }
+            out.reserve(out.capacity().max(64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CONFIG: Config = Config { level: 1, threshold: 16, context_takeover: true };
+
+    #[test]
+    fn test_accept_offer() {
+        // Chrome and Firefox
+        let agreement = accept_offer("permessage-deflate; client_max_window_bits", true).unwrap();
+        assert_eq!(agreement.header(), "permessage-deflate");
+        // The client may ask the server to reset its window
+        let agreement = accept_offer("permessage-deflate; server_no_context_takeover", true).unwrap();
+        assert_eq!(agreement.header(), "permessage-deflate; server_no_context_takeover");
+        // A smaller server window cannot be honoured, so the next offer is taken
+        let agreement = accept_offer("permessage-deflate; server_max_window_bits=10, permessage-deflate; client_max_window_bits=12", false).unwrap();
+        assert_eq!(agreement.header(), "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+
+        assert!(accept_offer("x-webkit-deflate-frame", true).is_none());
+        assert!(accept_offer("permessage-deflate; unknown_param", true).is_none());
+        assert!(accept_offer("permessage-deflate; client_max_window_bits; client_max_window_bits", true).is_none());
+        assert!(accept_offer("", true).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_compresses_after_the_101() {
+        let (server, mut client) = tokio::io::duplex(1 << 20);
+        let agreement = Arc::new(OnceLock::new());
+        let mut stream = DeflateStream { inner: server, config: Some(CONFIG), agreement: agreement.clone(), mode: Mode::Http { head: Vec::new() } };
+        agreement.set(Agreement { server_no_context_takeover: false, client_no_context_takeover: false }).unwrap();
+
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\n\r\n";
+        let text = "show interfaces\r\n".repeat(100);
+        let mut frames = BytesMut::new();
+        put_frame(&mut frames, 0x80 | OPCODE_TEXT, None, text.as_bytes());
+        put_frame(&mut frames, 0x80 | 0x9, None, b"ping");
+        // The head and the first frame arrive in one write, split across the end of the head
+        let mut written = head.to_vec();
+        written.extend_from_slice(&frames);
+        stream.write_all(&written[..head.len() - 2]).await.unwrap();
+        stream.write_all(&written[head.len() - 2..]).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut received = vec![0u8; head.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, head);
+        let mut rest = vec![0u8; 4096];
+        let len = client.read(&mut rest).await.unwrap();
+        let frame = FrameHeader::parse(&rest[..len]).unwrap().unwrap();
+        assert!(frame.fin && frame.rsv1);
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        assert!(frame.payload_len < text.len() / 4);
+
+        let mut compressed = rest[frame.header_len..frame.header_len + frame.payload_len].to_vec();
+        compressed.extend_from_slice(&TRAILER);
+        let mut inflated = Vec::with_capacity(text.len() * 2);
+        Decompress::new(false).decompress_vec(&compressed, &mut inflated, FlushDecompress::Sync).unwrap();
+        assert_eq!(inflated, text.as_bytes());
+
+        // Control frames pass as they are
+        let ping = &rest[frame.header_len + frame.payload_len..len];
+        assert_eq!(ping, [0x89, 4, b'p', b'i', b'n', b'g']);
+    }
+
+    #[test]
+    fn test_inflates_fragmented_client_messages() {
+        let agreement = Agreement { server_no_context_takeover: false, client_no_context_takeover: false };
+        let mut codec = Codec::new(CONFIG, agreement);
+        let mut client = Compress::new(Compression::default(), false);
+        let mask = [1, 2, 3, 4];
+
+        for message in ["ls -la\r", "terminal input"] {
+            let mut compressed = Vec::with_capacity(256);
+            client.compress_vec(message.as_bytes(), &mut compressed, FlushCompress::Sync).unwrap();
+            assert!(compressed.ends_with(&TRAILER));
+            compressed.truncate(compressed.len() - TRAILER.len());
+            let (first, second) = compressed.split_at(compressed.len() / 2);
+            put_frame(&mut codec.received, 0x40 | OPCODE_TEXT, Some(mask), first);
+            put_frame(&mut codec.received, 0x80 | 0x9, Some(mask), b"");
+            put_frame(&mut codec.received, 0x80 | OPCODE_CONTINUATION, Some(mask), second);
+        }
+        codec.decode().unwrap();
+
+        let mut text = Vec::new();
+        while let Some(frame) = FrameHeader::parse(&codec.readable).unwrap() {
+            assert!(!frame.rsv1);
+            assert_eq!(frame.mask, Some(mask));
+            let mut payload = codec.readable[frame.header_len..frame.header_len + frame.payload_len].to_vec();
+            unmask(&mut payload, mask);
+            if frame.opcode != 0x9 {
+                text.extend_from_slice(&payload);
+            }
+            codec.readable.advance(frame.header_len + frame.payload_len);
+        }
+        assert_eq!(String::from_utf8(text).unwrap(), "ls -la\rterminal input");
+    }
+}
@@ -0,0 +1,246 @@
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Path, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::settings::WsTokenSettings;
+use crate::session::SessionRegistry;
+use crate::share::hash_token;
+use crate::AppState;
+
+/// A token waiting to be used
+struct WsToken {
+    session_id: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Why a WebSocket's token was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsTokenError {
+    Missing,
+    /// Unknown, expired, already used, or issued for another session
+    Invalid,
+}
+
+/// Single-use tokens for attaching WebSockets to sessions
+///
+/// Session IDs show up in logs, browser history and the terminal page's
+/// URL, so holding one is not enough to attach to the session's shell.
+/// Each connect response carries a token that lets one WebSocket attach to
+/// the new session within a short while; another is minted for every
+/// reconnect. Only the hashes of the tokens are kept.
+#[derive(Default)]
+pub struct WsTokens {
+    tokens: HashMap<String, WsToken>,
+}
+
+impl WsTokens {
+    /// Mints a token for one WebSocket to the session
+    pub fn issue(&mut self, session_id: &str, ttl: chrono::Duration) -> String {
+        let now = Utc::now();
+        self.tokens.retain(|_, token| token.expires_at > now);
+
+        let token = format!("wst_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        self.tokens.insert(hash_token(&token), WsToken {
+            session_id: session_id.to_string(),
+            expires_at: now + ttl,
+        });
+        token
+    }
+
+    /// Checks a token presented for the session, leaving it to be used up by [`Redemption::complete`]
+    pub fn check(&self, session_id: &str, token: Option<&str>) -> Result<Redemption, WsTokenError> {
+        let token = token.map(str::trim).filter(|token| !token.is_empty()).ok_or(WsTokenError::Missing)?;
+        let token_hash = hash_token(token);
+        match self.tokens.get(&token_hash) {
+            Some(issued) if issued.session_id == session_id && issued.expires_at > Utc::now() => {
+                Ok(Redemption { session_id: session_id.to_string(), token_hash })
+            }
+            _ => Err(WsTokenError::Invalid),
+        }
+    }
+
+    /// Uses up a checked token, unless another WebSocket already has
+    fn spend(&mut self, redemption: &Redemption) -> bool {
+        self.tokens.remove(&redemption.token_hash).is_some()
+    }
+
+    /// Drops the tokens of a session that has ended
+    pub fn forget(&mut self, session_id: &str) {
+        self.tokens.retain(|_, token| token.session_id != session_id);
+    }
+}
+
+/// A token that passed the check before its WebSocket's upgrade
+///
+/// The token is only used up once the upgrade has gone through, so a
+/// request that fails on the way, or whose handshake never completes, can
+/// be retried with it.
+pub struct Redemption {
+    session_id: String,
+    token_hash: String,
+}
+
+impl Redemption {
+    /// Uses up the token for the upgraded socket, closing it if another WebSocket got to the token first
+    pub async fn complete(self, registry: &Mutex<SessionRegistry>, socket: &mut WebSocket) -> bool {
+        let redeemed = registry.lock().await.ws_tokens.spend(&self);
+        if !redeemed {
+            warn!("WebSocket for session {} closed: its token was used by another WebSocket, or the session ended", self.session_id);
+            let _ = socket.send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "The WebSocket token is invalid, expired or already used".into(),
+            }))).await;
+        }
+        redeemed
+    }
+}
+
+/// How long a new token may wait to be used
+pub fn ttl(settings: &WsTokenSettings) -> chrono::Duration {
+    chrono::Duration::seconds(settings.ttl_seconds.max(1) as i64)
+}
+
+#[derive(Debug, Serialize)]
+pub struct WsTokenResponse {
+    pub session_id: String,
+    pub ws_token: String,
+    pub websocket_url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Mints a token for a WebSocket to reattach to the session, e.g. after a dropped connection
+pub async fn issue_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    let ttl = ttl(&state.settings.ws_tokens);
+    let mut registry = state.session_registry.lock().await;
+    if registry.get_session(session_id).is_none() && registry.get_pending_auth(session_id).is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "error": "session_not_found",
+            "message": format!("Session '{}' not found", session_id),
+        }))).into_response();
+    }
+    let ws_token = registry.ws_tokens.issue(session_id, ttl);
+    drop(registry);
+    debug!("WebSocket token issued for session {}", session_id);
+
+    (StatusCode::CREATED, Json(WsTokenResponse {
+        session_id: session_id.to_string(),
+        websocket_url: crate::websocket_url(&state.settings, session_id, &ws_token),
+        ws_token,
+        expires_at: Utc::now() + ttl,
+    })).into_response()
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransferResponse {
+    pub session_id: String,
+    pub ws_token: String,
+    pub websocket_url: String,
+    /// The terminal page, to open on the other machine
+    pub terminal_url: String,
+    /// The session is closed if no WebSocket has attached by then
+    pub expires_at: DateTime<Utc>,
+    /// Whether a WebSocket was attached and has been let go
+    pub detached: bool,
+}
+
+/// Moves a session to another tab or machine, keeping its shell
+///
+/// The attached WebSocket is told the session moved and closed; the SSH
+/// connection, the scrollback and whatever runs in the shell carry on. The
+/// token returned attaches the next WebSocket, which is sent the scrollback.
+pub async fn transfer_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let session_id = session_id.trim();
+    let window = std::time::Duration::from_secs(state.settings.ws_tokens.transfer_ttl_seconds.max(1));
+    let ttl = chrono::Duration::seconds(window.as_secs() as i64);
+    let notice = json!({
+        "type": "detached",
+        "reason": "transferred",
+        "message": "This session was moved to another tab or device",
+    });
+    let mut registry = state.session_registry.lock().await;
+    let Some(detached) = registry.transfer(session_id, notice) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "error": "session_not_found",
+            "message": format!("Session '{}' not found", session_id),
+        }))).into_response();
+    };
+    let ws_token = registry.ws_tokens.issue(session_id, ttl);
+    drop(registry);
+    info!("Session {} transferred{}; waiting up to {:?} for a WebSocket", session_id,
+          if detached { ", its WebSocket let go" } else { "" }, window);
+
+    // Gone unless a WebSocket attaches within the window, as after a dropped connection
+    let registry = state.session_registry.clone();
+    let expiring = session_id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(window).await;
+        if registry.lock().await.expire_detached(&expiring, window) {
+            info!("Session {} closed: no WebSocket attached within {:?} of its transfer", expiring, window);
+        }
+    });
+
+    (StatusCode::CREATED, Json(TransferResponse {
+        session_id: session_id.to_string(),
+        websocket_url: crate::websocket_url(&state.settings, session_id, &ws_token),
+        terminal_url: crate::terminal_url(&state.settings, session_id, &ws_token),
+        ws_token,
+        expires_at: Utc::now() + ttl,
+        detached,
+    })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redeem(tokens: &mut WsTokens, session_id: &str, token: Option<&str>) -> Result<bool, WsTokenError> {
+        let redemption = tokens.check(session_id, token)?;
+        Ok(tokens.spend(&redemption))
+    }
+
+    #[test]
+    fn test_tokens_are_single_use() {
+        let mut tokens = WsTokens::default();
+        let ttl = chrono::Duration::seconds(60);
+        let token = tokens.issue("s1", ttl);
+        assert!(token.starts_with("wst_"));
+
+        assert_eq!(redeem(&mut tokens, "s1", None).err(), Some(WsTokenError::Missing));
+        assert_eq!(redeem(&mut tokens, "s2", Some(&token)).err(), Some(WsTokenError::Invalid));
+        assert_eq!(redeem(&mut tokens, "s1", Some(&token)), Ok(true));
+        assert_eq!(redeem(&mut tokens, "s1", Some(&token)).err(), Some(WsTokenError::Invalid));
+
+        let expired = tokens.issue("s1", chrono::Duration::seconds(-1));
+        assert_eq!(redeem(&mut tokens, "s1", Some(&expired)).err(), Some(WsTokenError::Invalid));
+
+        // Of two WebSockets that checked the same token, only the first to upgrade uses it
+        let token = tokens.issue("s1", ttl);
+        let first = tokens.check("s1", Some(&token)).unwrap();
+        let second = tokens.check("s1", Some(&token)).unwrap();
+        assert!(tokens.spend(&first));
+        assert!(!tokens.spend(&second));
+
+        let token = tokens.issue("s1", ttl);
+        tokens.forget("s1");
+        assert_eq!(redeem(&mut tokens, "s1", Some(&token)).err(), Some(WsTokenError::Invalid));
+    }
+}
@@ -3,6 +3,8 @@ let term = null;
 let ws = null;
 let fitAddon = null;
 let currentSessionId = null;
+let outputPosition = null; // { sessionId, offset } of the next output byte, to resume after a dropped connection
+let wsToken = null; // Single-use token for the next WebSocket, from the connect response
 let isApiConnection = false;
 let rendererType = 'canvas'; // Track current renderer type
 
@@ -2221,6 +2223,7 @@ function initTerminal() {
         // Check URL parameters for API-initiated connection
         const urlParams = new URLSearchParams(window.location.search);
         let sessionId = urlParams.get('session_id');
+        wsToken = urlParams.get('ws_token');
         const hostname = urlParams.get('hostname');
         const username = urlParams.get('username');
         const deviceName = urlParams.get('device_name');
@@ -1,5 +1,24 @@
+// Get a single-use token for the next WebSocket: the one the page was opened
+// with, or else a fresh one for reconnecting
+async function takeWsToken(sessionId) {
+    if (wsToken) {
+        const token = wsToken;
+        wsToken = null;
+        return token;
+    }
+    try {
+        const response = await fetch(`/api/session/${encodeURIComponent(sessionId)}/ws-token`, { method: 'POST' });
+        if (response.ok) {
+            return (await response.json()).ws_token;
+        }
+    } catch (error) {
+        console.error('Failed to get a WebSocket token:', error);
+    }
+    return null;
+}
+
 // Connect to WebSocket
-function connectWebSocket(sessionId) {
+async function connectWebSocket(sessionId) {
     if (ws) {
         ws.close();
     }
@@ -7,7 +26,17 @@ function connectWebSocket(sessionId) {
     currentSessionId = sessionId;
     
     const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
-    const wsUrl = `${protocol}//${window.location.host}/ws/${sessionId}`;
+    const query = new URLSearchParams();
+    const token = await takeWsToken(sessionId);
+    if (token) {
+        query.set('ws_token', token);
+    }
+    
+    // Resume where the previous connection to this session left off
+    if (outputPosition && outputPosition.sessionId === sessionId) {
+        query.set('resume', outputPosition.offset);
+    }
+    const wsUrl = `${protocol}//${window.location.host}/ws/${sessionId}?${query.toString()}`;
     
     // Connecting to WebSocket silently
     
@@ -57,6 +86,10 @@ function connectWebSocket(sessionId) {
         let processingInput = false;
         
         term.onData(data => {
+            if (terminalAnswer) {
+                terminalAnswer(data);
+                return;
+            }
             if (ws && ws.readyState === WebSocket.OPEN) {
                 // Check for special local commands first
                 if (data === '\r' && inputQueue.length > 0) {
@@ -228,6 +261,9 @@ function connectWebSocket(sessionId) {
             console.log('Binary data received (ArrayBuffer)');
             const uint8Data = new Uint8Array(data);
             term.write(uint8Data);
+            if (outputPosition) {
+                outputPosition.offset += data.byteLength;
+            }
             
             // Force a refresh after receiving data to ensure display is updated
             // This helps with commands like 'top' that use cursor positioning
@@ -239,6 +275,9 @@ function connectWebSocket(sessionId) {
                 console.log('Binary data received (Blob)');
                 const uint8Data = new Uint8Array(reader.result);
                 term.write(uint8Data);
+                if (outputPosition) {
+                    outputPosition.offset += reader.result.byteLength;
+                }
                 
                 // Force a refresh after receiving data
                 term.refresh(0, term.rows - 1);
@@ -279,6 +318,59 @@ function connectWebSocket(sessionId) {
                         // Standard refresh
                         term.refresh(0, term.rows - 1);
                     }
+                } else if (jsonData.type === 'output_offset') {
+                    // Offset of the output that follows, counted from the start of the session
+                    outputPosition = { sessionId: sessionId, offset: jsonData.offset };
+                } else if (jsonData.type === 'auth_prompt') {
+                    // Keyboard-interactive challenge (e.g. OTP code) from the device
+                    if (jsonData.instructions) {
+                        term.write('\r\n' + jsonData.instructions + '\r\n');
+                    }
+                    if (jsonData.prompts.length > 0) {
+                        answerInTerminal(jsonData.prompts, responses => {
+                            if (ws) {
+                                ws.send(JSON.stringify({ type: 'auth_response', responses: responses }));
+                            }
+                        });
+                    }
+                } else if (jsonData.type === 'auth_success') {
+                    console.log('Keyboard-interactive authentication succeeded');
+                } else if (jsonData.type === 'auth_failed') {
+                    showError(jsonData.message);
+                } else if (jsonData.type === 'secret_prompt') {
+                    // Forced password change or token PIN: answered in a masked field, never through the terminal
+                    promptSecret(jsonData.prompt, secret => {
+                        if (ws) {
+                            ws.send(JSON.stringify({ type: 'secret_input', data: secret }));
+                        }
+                    });
+                } else if (jsonData.type === 'password_change') {
+                    if (jsonData.status === 'started') {
+                        term.write(`\r\n% ${jsonData.message}. Answer the prompts in the dialog.\r\n`);
+                    } else if (jsonData.status === 'failed' && jsonData.message) {
+                        term.write(`\r\n% Password not changed: ${jsonData.message}\r\n`);
+                    } else if (jsonData.status === 'stored') {
+                        term.write('\r\n% The new password was saved to the credential store.\r\n');
+                    } else if (jsonData.status === 'store_failed') {
+                        showError('The new password could not be saved: ' + jsonData.message);
+                    }
+                } else if (jsonData.type === 'banner') {
+                    // Maintenance notice from the gateway; no message takes it down
+                    showBanner(jsonData.message);
+                } else if (jsonData.type === 'command_approval') {
+                    // Decisions on commands the guardrail sent for approval
+                    const request = jsonData.request;
+                    if (request.status === 'approved') {
+                        term.write(`\r\n% Approved: ${request.command}. Enter it again to run it.\r\n`);
+                    } else if (request.status === 'denied') {
+                        term.write(`\r\n% Denied: ${request.command}\r\n`);
+                    }
+                } else if (jsonData.type === 'connection_degraded' || jsonData.type === 'connection_lost') {
+                    // The device has stopped answering keepalives
+                    term.write(`\r\n% ${jsonData.message}\r\n`);
+                } else if (jsonData.type === 'detached') {
+                    // Let go by the gateway, e.g. the session was moved to another device
+                    term.write(`\r\n% ${jsonData.message}\r\n`);
                 } else if (jsonData.type === 'info') {
                     // Display informational messages
                     console.log('Server info:', jsonData.message);
@@ -348,4 +440,94 @@ function checkSessionAndConnect(sessionId) {
         .catch(error => {
             showError('Failed to check session status: ' + error.message);
         });
-}
\ No newline at end of file
+}
+
+// Takes the keys typed while answering login prompts in the terminal
+let terminalAnswer = null;
+
+// Asks the login prompts in the terminal, one after the other, calling
+// onAnswers with the answers; those not to be echoed are not shown as typed
+function answerInTerminal(prompts, onAnswers) {
+    const answers = [];
+    let answer = '';
+    term.write('\r\n' + prompts[0].prompt);
+    terminalAnswer = data => {
+        for (const key of data) {
+            if (key === '\r' || key === '\n') {
+                term.write('\r\n');
+                answers.push(answer);
+                answer = '';
+                if (answers.length === prompts.length) {
+                    terminalAnswer = null;
+                    onAnswers(answers);
+                    return;
+                }
+                term.write(prompts[answers.length].prompt);
+            } else if (key === '\x7f' || key === '\b') {
+                if (answer.length > 0) {
+                    answer = answer.slice(0, -1);
+                    if (prompts[answers.length].echo) {
+                        term.write('\b \b');
+                    }
+                }
+            } else if (key >= ' ') {
+                answer += key;
+                if (prompts[answers.length].echo) {
+                    term.write(key);
+                }
+            }
+        }
+    };
+}
+
+// Asks for a secret in a masked field, calling onAnswer with it unless cancelled
+function promptSecret(text, onAnswer) {
+    const overlay = document.createElement('div');
+    overlay.style.cssText = 'position:fixed;inset:0;background:rgba(0,0,0,0.6);display:flex;align-items:center;justify-content:center;z-index:1000';
+    const form = document.createElement('form');
+    form.style.cssText = 'background:#1e1e1e;color:#ddd;padding:16px;border-radius:4px;font-family:monospace';
+    const label = document.createElement('label');
+    label.textContent = text;
+    const input = document.createElement('input');
+    input.type = 'password';
+    input.autocomplete = 'off';
+    input.style.cssText = 'display:block;margin-top:8px;width:280px';
+    form.append(label, input);
+    overlay.appendChild(form);
+    document.body.appendChild(overlay);
+    input.focus();
+
+    const close = () => {
+        overlay.remove();
+        term.focus();
+    };
+    form.onsubmit = event => {
+        event.preventDefault();
+        const secret = input.value;
+        close();
+        onAnswer(secret);
+    };
+    input.onkeydown = event => {
+        if (event.key === 'Escape') {
+            close();
+        }
+    };
+}
+
+// Shows a notice above the terminal, or removes it when message is empty
+function showBanner(message) {
+    let banner = document.getElementById('gateway-banner');
+    if (!message) {
+        if (banner) {
+            banner.remove();
+        }
+        return;
+    }
+    if (!banner) {
+        banner = document.createElement('div');
+        banner.id = 'gateway-banner';
+        banner.style.cssText = 'position:fixed;top:0;left:0;right:0;padding:6px 12px;background:#4a3b00;color:#ffd75f;font-family:monospace;z-index:999';
+        document.body.appendChild(banner);
+    }
+    banner.textContent = message;
+}